@@ -0,0 +1,104 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Optional integrity check of the kernel and modules.
+//!
+//! If there is a `manifest.sha256` next to the kernel (in the format
+//! `sha256sum` writes), every module it lists has to match its hash or we
+//! refuse to boot. The hashes we measure are passed on to the kernel
+//! (`Module::sha256`) which logs them.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use sha2::{Digest, Sha256};
+
+use crate::Module;
+
+/// Name of the manifest file.
+pub const MANIFEST: &str = "manifest.sha256";
+
+fn parse_hash(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut hash = [0u8; 32];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+fn to_hex(hash: &[u8; 32]) -> String {
+    let mut hex = String::with_capacity(64);
+    for byte in hash {
+        let _r = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+/// Parses the manifest, returns (module name, hash) pairs.
+fn parse_manifest(manifest: &str) -> Vec<(&str, [u8; 32])> {
+    manifest
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut parts = line.split_whitespace();
+            let hash = parts.next().and_then(parse_hash);
+            // `sha256sum -b` marks names with a `*`
+            let name = parts.next().map(|name| name.trim_start_matches('*'));
+            match (name, hash) {
+                (Some(name), Some(hash)) => (name, hash),
+                _ => panic!("Malformed line in {}: '{}'", MANIFEST, line),
+            }
+        })
+        .collect()
+}
+
+/// Measures all modules and checks them against the manifest.
+///
+/// Does nothing if there is no manifest, panics if a module doesn't match or
+/// a module in the manifest is missing.
+pub fn verify_modules(modules: &mut [(String, Module)]) {
+    let manifest = match modules.iter().find(|(name, _)| name == MANIFEST) {
+        // Safe: The bootloader address space maps the modules 1:1
+        Some((_, module)) => unsafe { module.as_pslice() },
+        None => {
+            debug!("No {}, modules are not verified", MANIFEST);
+            return;
+        }
+    };
+    let manifest = core::str::from_utf8(manifest)
+        .unwrap_or_else(|_e| panic!("{} is not a text file", MANIFEST));
+    let expected = parse_manifest(manifest);
+
+    let mut verified = 0;
+    for (name, module) in modules.iter_mut().filter(|(name, _)| name != MANIFEST) {
+        let measured: [u8; 32] = Sha256::digest(unsafe { module.as_pslice() }).into();
+        module.sha256 = Some(measured);
+
+        match expected.iter().find(|(n, _)| n == name) {
+            Some((_, hash)) if *hash == measured => verified += 1,
+            Some((_, hash)) => panic!(
+                "Integrity check failed: sha256 of '{}' is {} but {} expects {}",
+                name,
+                to_hex(&measured),
+                MANIFEST,
+                to_hex(hash)
+            ),
+            None => warn!("'{}' is not in {}, it is not verified", name, MANIFEST),
+        }
+    }
+
+    for (name, _hash) in expected.iter() {
+        if !modules.iter().any(|(n, _)| n == name) {
+            panic!(
+                "Integrity check failed: '{}' is in {} but wasn't found",
+                name, MANIFEST
+            );
+        }
+    }
+    info!("Verified {} modules against {}", verified, MANIFEST);
+}
@@ -0,0 +1,195 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Kernel address space layout randomization (KASLR).
+//!
+//! The kernel is linked as position independent code, we relocate it to run
+//! at `KERNEL_OFFSET` + the physical address of the memory we load it into
+//! (see `Kernel::allocate`). Instead of taking whatever memory the firmware
+//! hands out, we pick a random (2 MiB aligned) place in conventional memory
+//! so the addresses of the kernel change with every boot. The randomness
+//! comes from the firmware (`EFI_RNG_PROTOCOL`) or from RDRAND.
+//!
+//! The direct map of physical memory stays at `KERNEL_OFFSET`: driverkit
+//! (an external crate) computes the DMA addresses of its buffers (`IOBuf`,
+//! the default `DmaObject::paddr`) with its constant `KERNEL_BASE`, so the
+//! NIC drivers would hand out wrong addresses with a moved direct map.
+//!
+//! With `kaslr=off` on the command line the kernel is where the firmware
+//! allocates memory for it (which is usually the same place every boot).
+//! The kernel reads the same argument when it loads the next kernel with
+//! kexec (see `kexec::find_place` in the kernel).
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::x86_64::_rdrand64_step;
+use core::ffi::c_void;
+use core::{mem, ptr, slice};
+
+use uefi::prelude::*;
+use uefi::proto::Protocol;
+use uefi::table::boot::{AllocateType, MemoryType};
+use uefi::unsafe_guid;
+use uefi_services::system_table;
+use x86::bits64::paging::{PAddr, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+use x86::cpuid::CpuId;
+
+use bootloader_shared::{Kaslr, RandomSource};
+
+use crate::kernel::KERNEL_ELF;
+use crate::round_up;
+use crate::vspace::VSpace;
+
+/// We don't put the kernel below 16 MiB, the kernel and devices want low
+/// memory for other things (AP bootstrap code, legacy DMA).
+const MIN_ADDRESS: u64 = 16 * 1024 * 1024;
+
+/// How often we retry RDRAND if it's out of entropy.
+const RDRAND_RETRIES: usize = 16;
+
+/// `EFI_RNG_PROTOCOL` (uefi-rs doesn't have it yet), we only use `GetRNG`
+/// with the default algorithm.
+#[repr(C)]
+#[unsafe_guid("3152bca5-eade-433d-862e-c01cdc291f44")]
+#[derive(Protocol)]
+pub struct Rng {
+    get_info: usize,
+    get_rng: unsafe extern "efiapi" fn(
+        this: &mut Rng,
+        algorithm: *const c_void,
+        length: usize,
+        value: *mut u8,
+    ) -> Status,
+}
+
+/// Whether the command line turns KASLR off (`kaslr=off`).
+pub fn disabled(cmdline: &[u8]) -> bool {
+    core::str::from_utf8(cmdline)
+        .unwrap_or("")
+        .split_whitespace()
+        .any(|arg| arg == "kaslr=off")
+}
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand() -> Option<u64> {
+    let mut value = 0;
+    for _ in 0..RDRAND_RETRIES {
+        if _rdrand64_step(&mut value) == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// A random number and where it came from.
+fn random(st: &SystemTable<Boot>) -> Option<(u64, RandomSource)> {
+    if let Ok(rng) = st.boot_services().locate_protocol::<Rng>() {
+        let rng = rng.expect("Warnings encountered while opening RNG protocol");
+        let rng = unsafe { &mut *rng.get() };
+        let mut value = 0u64;
+        let status = unsafe {
+            (rng.get_rng)(
+                rng,
+                ptr::null(),
+                mem::size_of::<u64>(),
+                &mut value as *mut u64 as *mut u8,
+            )
+        };
+        if status == Status::SUCCESS {
+            return Some((value, RandomSource::UefiRng));
+        }
+        debug!("EFI_RNG_PROTOCOL failed: {:?}", status);
+    }
+
+    let has_rdrand = CpuId::new()
+        .get_feature_info()
+        .map_or(false, |f| f.has_rdrand());
+    if has_rdrand {
+        if let Some(value) = unsafe { rdrand() } {
+            return Some((value, RandomSource::Rdrand));
+        }
+    }
+    None
+}
+
+/// The places in conventional memory where `size` bytes aligned to `align`
+/// fit, as (first address, number of places) for every free region.
+fn places(st: &SystemTable<Boot>, size: u64, align: u64) -> Vec<(u64, u64)> {
+    let (mm_size, _no_descs) = crate::estimate_memory_map_size(st);
+    // `u64` so the memory descriptors are aligned
+    let mut words = vec![0u64; mm_size / mem::size_of::<u64>()];
+    let buffer = unsafe { slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, mm_size) };
+
+    let (_key, desc_iter) = st
+        .boot_services()
+        .memory_map(buffer)
+        .expect_success("Failed to retrieve UEFI memory map");
+    desc_iter
+        .filter(|entry| entry.ty == MemoryType::CONVENTIONAL)
+        .filter_map(|entry| {
+            let start = round_up!(entry.phys_start.max(MIN_ADDRESS), align);
+            let end = entry.phys_start + entry.page_count * BASE_PAGE_SIZE as u64;
+            (end >= start + size).then(|| (start, (end - start - size) / align + 1))
+        })
+        .collect()
+}
+
+/// Allocates `pages` for the kernel binary, aligned to `align`, at a random
+/// place (if `randomize` is set and we have randomness).
+///
+/// Returns the physical address of the memory and how we picked it.
+pub fn allocate_kernel(pages: usize, align: u64, randomize: bool) -> (PAddr, Kaslr) {
+    let anywhere = |kaslr| {
+        let paddr = VSpace::allocate_pages_aligned(pages, MemoryType(KERNEL_ELF), align);
+        (paddr, kaslr)
+    };
+    if !randomize {
+        return anywhere(Kaslr::Disabled);
+    }
+
+    let st = unsafe { system_table().as_ref() };
+    let (value, source) = match random(st) {
+        Some(random) => random,
+        None => {
+            warn!("KASLR unavailable: no EFI_RNG_PROTOCOL and no RDRAND");
+            return anywhere(Kaslr::Unavailable);
+        }
+    };
+
+    let align = align.max(LARGE_PAGE_SIZE as u64);
+    let size = (pages * BASE_PAGE_SIZE) as u64;
+    let places = places(st, size, align);
+    let slots: u64 = places.iter().map(|(_first, count)| count).sum();
+    if slots == 0 {
+        warn!("KASLR unavailable: no free memory region fits the kernel");
+        return anywhere(Kaslr::Unavailable);
+    }
+
+    let mut pick = value % slots;
+    for (first, count) in places {
+        if pick >= count {
+            pick -= count;
+            continue;
+        }
+
+        let base = first + pick * align;
+        return match st.boot_services().allocate_pages(
+            AllocateType::Address(base as usize),
+            MemoryType(KERNEL_ELF),
+            pages,
+        ) {
+            Ok(_) => {
+                unsafe {
+                    st.boot_services()
+                        .set_mem(base as *mut u8, size as usize, 0u8)
+                };
+                (PAddr::from(base), Kaslr::Randomized { source, slots })
+            }
+            Err(e) => {
+                warn!("Can't load the kernel at {:#x}: {:?}", base, e.status());
+                anywhere(Kaslr::Unavailable)
+            }
+        };
+    }
+    unreachable!("Picked a place out of range");
+}
@@ -4,6 +4,7 @@
 //! Implements the necessary functionality to load the ELF image in machine memory.
 use crate::alloc::vec::Vec;
 
+use bootloader_shared::Kaslr;
 use elfloader::{self, ElfLoaderErr};
 use x86::bits64::paging::*;
 
@@ -21,23 +22,9 @@ macro_rules! is_page_aligned {
     };
 }
 
-/// UEFI memory region type for ELF data allocation.
-pub const KERNEL_ELF: u32 = 0x80000001;
-
-/// UEFI memory region type for kernel page-tables.
-pub const KERNEL_PT: u32 = 0x80000002;
-
-/// UEFI memory region type for the kernel stack.
-pub const KERNEL_STACK: u32 = 0x80000003;
-
-/// UEFI memory region type for the memory map.
-pub const UEFI_MEMORY_MAP: u32 = 0x80000004;
-
-/// UEFI memory region type for arguments passed to the kernel.
-pub const KERNEL_ARGS: u32 = 0x80000005;
-
-/// UEFI memory region type for arguments passed to the kernel.
-pub const MODULE: u32 = 0x80000006;
+pub use bootloader_shared::{
+    KERNEL_ARGS, KERNEL_ELF, KERNEL_PT, KERNEL_STACK, MODULE, UEFI_MEMORY_MAP,
+};
 
 /// 512 GiB are that many bytes.
 pub const GIB_512: usize = 512 * 512 * 512 * 0x1000;
@@ -67,6 +54,9 @@ pub struct Kernel<'a> {
     pub offset: VAddr,
     pub mapping: Vec<(VAddr, usize, u64, MapAction)>,
     pub vspace: VSpace<'a>,
+    /// How we placed the kernel (set in `allocate`, unless it's
+    /// `Kaslr::Disabled`).
+    pub kaslr: Kaslr,
 }
 
 impl<'a> elfloader::ElfLoader for Kernel<'a> {
@@ -147,14 +137,18 @@ impl<'a> elfloader::ElfLoader for Kernel<'a> {
             is_page_aligned!(max_end),
             "max end is not aligned to page-size"
         );
-        let pbase = VSpace::allocate_pages_aligned(
+        let (pbase, kaslr) = crate::kaslr::allocate_kernel(
             ((max_end - min_base) >> BASE_PAGE_SHIFT) as usize,
-            uefi::table::boot::MemoryType(KERNEL_ELF),
             max_alignment,
+            self.kaslr != Kaslr::Disabled,
         );
+        self.kaslr = kaslr;
 
         self.offset = VAddr::from(KERNEL_OFFSET + pbase.as_usize());
-        info!("Kernel loaded at address: {:#x}", self.offset);
+        info!(
+            "Kernel loaded at address: {:#x} ({:?})",
+            self.offset, self.kaslr
+        );
 
         // Do the mappings:
         for (base, size, _alignment, action) in self.mapping.iter() {
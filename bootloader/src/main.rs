@@ -31,8 +31,8 @@
 //!  * The kernel address space we switch to is set-up as follows:
 //!    * All UEFI reported memory regions are 1:1 mapped phys <-> virt.
 //!    * All UEFI reported memory regions are 1:1 mapped to the 'kernel physical space' (which is above KERNEL_BASE).
-//!    * The kernel ELF binary is loaded somewhere in physical memory and relocated
-//!      for running in the kernel-space above KERNEL_BASE.
+//!    * The kernel ELF binary is loaded at a random place in physical memory (see `kaslr`)
+//!      and relocated for running in the kernel-space above KERNEL_BASE.
 //!  * A pointer to the KernelArgs struct is given as a first argument:
 //!    * The memory allocated for it (and everything within) is pointing to kernel space
 //!
@@ -41,7 +41,7 @@
 
 #![no_std]
 #![no_main]
-#![feature(llvm_asm, global_asm)]
+#![feature(llvm_asm, global_asm, abi_efiapi)]
 
 #[macro_use]
 extern crate log;
@@ -57,21 +57,26 @@ use core::{mem, slice};
 use uefi::prelude::*;
 use uefi::proto::console::gop::GraphicsOutput;
 use uefi::table::boot::{AllocateType, MemoryDescriptor, MemoryType};
-use uefi::table::cfg::{ACPI2_GUID, ACPI_GUID};
+use uefi::table::cfg::{ACPI2_GUID, ACPI_GUID, SMBIOS3_GUID, SMBIOS_GUID};
 
 use crate::alloc::vec::Vec;
 
 use x86::bits64::paging::*;
 use x86::controlregs;
 
+mod integrity;
+mod kaslr;
 mod kernel;
 mod modules;
+mod pxe;
+mod runtime;
 mod vspace;
 
 use kernel::*;
 use modules::*;
 use vspace::*;
 
+use bootloader_shared::splash::{BootStage, Splash};
 use bootloader_shared::*;
 
 #[macro_export]
@@ -223,6 +228,10 @@ fn map_physical_memory(st: &SystemTable<Boot>, kernel: &mut Kernel) {
                 || entry.ty == MemoryType(KERNEL_PT)
                 || entry.ty == MemoryType(MODULE)
                 || entry.ty == MemoryType(KERNEL_ARGS)
+                // The kernel calls the runtime services at this offset
+                // (see `runtime::set_virtual_address_map`):
+                || entry.ty == MemoryType::RUNTIME_SERVICES_CODE
+                || entry.ty == MemoryType::RUNTIME_SERVICES_DATA
             {
                 kernel.vspace.map_identity_with_offset(
                     PAddr::from(KERNEL_OFFSET as u64),
@@ -235,6 +244,29 @@ fn map_physical_memory(st: &SystemTable<Boot>, kernel: &mut Kernel) {
     }
 }
 
+/// Sets up the boot progress bar (if there is a framebuffer we can draw into).
+fn splash(st: &SystemTable<Boot>) -> Option<Splash<'static>> {
+    let gop = st
+        .boot_services()
+        .locate_protocol::<GraphicsOutput>()
+        .ok()?;
+    let gop = gop.expect("Warnings encountered while opening GOP");
+    let gop = unsafe { &mut *gop.get() };
+
+    let mut frame_buffer = gop.frame_buffer();
+    // The framebuffer stays valid (and identity mapped) until we leave the
+    // bootloader
+    let frame_buffer =
+        unsafe { slice::from_raw_parts_mut(frame_buffer.as_mut_ptr(), frame_buffer.size()) };
+    Splash::new(frame_buffer, &gop.current_mode_info())
+}
+
+fn progress(splash: &mut Option<Splash>, stage: BootStage) {
+    if let Some(splash) = splash {
+        splash.progress(stage);
+    }
+}
+
 /// Initialize the screen to the highest possible resolution.
 fn _setup_screen(st: &SystemTable<Boot>) {
     if let Ok(gop) = st.boot_services().locate_protocol::<GraphicsOutput>() {
@@ -287,7 +319,6 @@ fn assert_required_cpu_features() {
     let has_xsave = fi.as_ref().map_or(false, |f| f.has_xsave());
     let has_sse = fi.as_ref().map_or(false, |f| f.has_sse());
     let has_apic = fi.as_ref().map_or(false, |f| f.has_apic());
-    let has_x2apic = fi.as_ref().map_or(false, |f| f.has_x2apic());
     let has_tsc = fi.as_ref().map_or(false, |f| f.has_tsc());
     let has_pae = fi.as_ref().map_or(false, |f| f.has_pae());
     let has_pse = fi.as_ref().map_or(false, |f| f.has_pse());
@@ -317,7 +348,6 @@ fn assert_required_cpu_features() {
     assert!(has_fsgsbase);
     assert!(has_sse);
     assert!(has_apic);
-    assert!(has_x2apic); // If you fail here it probably means qemu wasn't running with KVM enabled...
     assert!(has_tsc);
     assert!(has_pae);
     assert!(has_pse);
@@ -347,8 +377,19 @@ pub extern "C" fn uefi_start(handle: uefi::Handle, mut st: SystemTable<Boot>) ->
     );
     info!("UEFI Bootloader starting...");
     check_revision(st.uefi_revision());
-
-    let modules = load_modules_on_all_sfs(&st, "\\");
+    let mut splash = splash(&st);
+    progress(&mut splash, BootStage::Bootloader);
+
+    let mut modules = load_modules_on_all_sfs(&st, "\\");
+    let mut module_source = ModuleSource::FileSystem;
+    if !modules.iter().any(|(name, _)| name == "kernel") {
+        info!("No kernel binary on the file systems, trying PXE/TFTP...");
+        if let Some(source) = pxe::load_modules(&st, &mut modules) {
+            module_source = source;
+        }
+    }
+    integrity::verify_modules(&mut modules);
+    progress(&mut splash, BootStage::ModulesLoaded);
 
     let (kernel_blob, cmdline_blob) = {
         let mut kernel_blob = None;
@@ -380,12 +421,18 @@ pub extern "C" fn uefi_start(handle: uefi::Handle, mut st: SystemTable<Boot>) ->
         offset: VAddr::from(0usize),
         mapping: Vec::new(),
         vspace: VSpace { pml4: pml4_table },
+        kaslr: if kaslr::disabled(cmdline_blob) {
+            Kaslr::Disabled
+        } else {
+            Kaslr::Unavailable
+        },
     };
 
     // Parse the ELF file and load it into the new address space
     let binary = elfloader::ElfBinary::new(kernel_blob).unwrap();
     trace!("Load the ELF binary into the address space");
     binary.load(&mut kernel).expect("Can't load the kernel");
+    progress(&mut splash, BootStage::KernelLoaded);
 
     // On big machines with the init stack tends to put big structures
     // on the stack so we reserve a fair amount of space:
@@ -483,7 +530,9 @@ pub extern "C" fn uefi_start(handle: uefi::Handle, mut st: SystemTable<Boot>) ->
         kernel_args.pml4 = PAddr::from(kernel.vspace.pml4 as *const _ as u64);
         kernel_args.stack = (stack_base + KERNEL_OFFSET, stack_size);
         kernel_args.kernel_elf_offset = kernel.offset;
+        kernel_args.kaslr = kernel.kaslr;
         kernel_args.modules = arrayvec::ArrayVec::new();
+        kernel_args.module_source = module_source;
         // Add modules to kernel args, ensure 'kernel' is first:
         for (name, module) in modules.iter() {
             if name == "kernel" {
@@ -500,8 +549,13 @@ pub extern "C" fn uefi_start(handle: uefi::Handle, mut st: SystemTable<Boot>) ->
                 kernel_args.acpi2_rsdp = PAddr::from(entry.address as u64);
             } else if entry.guid == ACPI_GUID {
                 kernel_args.acpi1_rsdp = PAddr::from(entry.address as u64);
+            } else if entry.guid == SMBIOS3_GUID {
+                kernel_args.smbios3_entry = PAddr::from(entry.address as u64);
+            } else if entry.guid == SMBIOS_GUID {
+                kernel_args.smbios_entry = PAddr::from(entry.address as u64);
             }
         }
+        let runtime_services = PAddr::from(st.runtime_services() as *const _ as u64);
 
         if let Ok(gop) = st.boot_services().locate_protocol::<GraphicsOutput>() {
             let gop = gop.expect("Warnings encountered while opening GOP");
@@ -510,7 +564,16 @@ pub extern "C" fn uefi_start(handle: uefi::Handle, mut st: SystemTable<Boot>) ->
             let mut frame_buffer = gop.frame_buffer();
             let frame_buf_ptr = frame_buffer.as_mut_ptr();
             let size = frame_buffer.size();
-            let _frame_buf_paddr = PAddr::from(frame_buf_ptr as u64);
+            let frame_buf_paddr = PAddr::from(frame_buf_ptr as u64);
+
+            // The framebuffer usually isn't in the memory map, so the kernel
+            // needs an extra mapping for it (to draw the progress bar)
+            kernel.vspace.map_identity_with_offset(
+                PAddr::from(KERNEL_OFFSET as u64),
+                frame_buf_paddr,
+                frame_buf_paddr + round_up!(size, BASE_PAGE_SIZE),
+                MapAction::ReadWriteKernel,
+            );
 
             kernel_args.frame_buffer = Some(core::slice::from_raw_parts_mut(
                 frame_buf_ptr.add(KERNEL_OFFSET),
@@ -536,6 +599,11 @@ pub extern "C" fn uefi_start(handle: uefi::Handle, mut st: SystemTable<Boot>) ->
 
         kernel_args.mm_iter.extend(mmiter);
 
+        // Relocate the runtime services into the kernel address space
+        if runtime::set_virtual_address_map(runtime_services, &mut kernel_args.mm_iter) {
+            kernel_args.runtime_services = runtime_services;
+        }
+
         // It's unclear from the spec if `exit_boot_services` already disables interrupts
         // so we we make sure they are disabled (otherwise we triple fault since
         // we don't have an IDT setup in the beginning)
@@ -0,0 +1,279 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Code to load the kernel and modules over the network (PXE/TFTP).
+//!
+//! If the machine was PXE booted, the firmware's PXE base code protocol is
+//! already started and configured (by DHCP), so we can fetch files from the
+//! TFTP server that handed us the bootloader. We use this if the kernel isn't
+//! on any of the SimpleFileSystems, it avoids rewriting disks between runs
+//! on rack-scale test deployments.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::{ptr, slice};
+
+use uefi::prelude::*;
+use uefi::proto::Protocol;
+use uefi::table::boot::MemoryType;
+use uefi::unsafe_guid;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+use crate::kernel::{paddr_to_kernel_vaddr, paddr_to_uefi_vaddr, MODULE};
+use crate::{allocate_pages, round_up, Module, ModuleSource};
+
+/// `EFI_PXE_BASE_CODE_TFTP_OPCODE`
+#[repr(u32)]
+#[allow(unused)]
+#[derive(Debug, Clone, Copy)]
+enum TftpOpcode {
+    First = 0,
+    GetFileSize = 1,
+    ReadFile = 2,
+}
+
+/// `EFI_IP_ADDRESS`
+#[repr(C, align(4))]
+#[derive(Clone, Copy)]
+struct IpAddress([u8; 16]);
+
+/// `EFI_PXE_BASE_CODE_MODE` (up to the fields we need).
+#[repr(C)]
+struct Mode {
+    started: bool,
+    ipv6_available: bool,
+    ipv6_supported: bool,
+    using_ipv6: bool,
+    bis_supported: bool,
+    bis_detected: bool,
+    auto_arp: bool,
+    send_guid: bool,
+    dhcp_discover_valid: bool,
+    dhcp_ack_received: bool,
+    proxy_offer_received: bool,
+    pxe_discover_valid: bool,
+    pxe_reply_received: bool,
+    pxe_bis_reply_received: bool,
+    icmp_error_received: bool,
+    tftp_error_received: bool,
+    make_callbacks: bool,
+    ttl: u8,
+    tos: u8,
+    station_ip: IpAddress,
+    subnet_mask: IpAddress,
+    dhcp_discover: [u8; 1472],
+    dhcp_ack: [u8; 1472],
+}
+
+/// `EFI_PXE_BASE_CODE_PROTOCOL` (uefi-rs doesn't have it yet), we only use
+/// `Mtftp`.
+#[repr(C)]
+#[unsafe_guid("03c4e603-ac28-11d3-9a2d-0090273fc14d")]
+#[derive(Protocol)]
+pub struct BaseCode {
+    revision: u64,
+    start: usize,
+    stop: usize,
+    dhcp: usize,
+    discover: usize,
+    mtftp: unsafe extern "efiapi" fn(
+        this: &mut BaseCode,
+        operation: TftpOpcode,
+        buffer: *mut c_void,
+        overwrite_file: bool,
+        buffer_size: &mut u64,
+        block_size: *const usize,
+        server_ip: &IpAddress,
+        filename: *const u8,
+        info: *const c_void,
+        dont_use_buffer: bool,
+    ) -> Status,
+    udp_write: usize,
+    udp_read: usize,
+    set_ip_filter: usize,
+    arp: usize,
+    set_parameters: usize,
+    set_station_ip: usize,
+    set_packets: usize,
+    mode: *const Mode,
+}
+
+impl BaseCode {
+    /// The TFTP server (`siaddr` in the BOOTP header of the DHCP ACK).
+    fn server(&self) -> Option<IpAddress> {
+        let mode = unsafe { &*self.mode };
+        if !mode.started || !mode.dhcp_ack_received || mode.using_ipv6 {
+            return None;
+        }
+
+        let mut server = IpAddress([0; 16]);
+        server.0[..4].copy_from_slice(&mode.dhcp_ack[20..24]);
+        Some(server)
+    }
+
+    fn tftp(
+        &mut self,
+        operation: TftpOpcode,
+        server: &IpAddress,
+        name: &str,
+        buffer: *mut u8,
+        size: &mut u64,
+    ) -> Status {
+        let mut filename: Vec<u8> = Vec::with_capacity(name.len() + 1);
+        filename.extend_from_slice(name.as_bytes());
+        filename.push(0);
+
+        unsafe {
+            (self.mtftp)(
+                self,
+                operation,
+                buffer as *mut c_void,
+                false,
+                size,
+                ptr::null(),
+                server,
+                filename.as_ptr(),
+                ptr::null(),
+                buffer.is_null(),
+            )
+        }
+    }
+}
+
+/// Fetch the binary `name` from the TFTP `server` and return a Module
+/// struct that can be passed to the kernel.
+fn load_binary_into_memory(
+    st: &SystemTable<Boot>,
+    pxe: &mut BaseCode,
+    server: &IpAddress,
+    name: &str,
+) -> Option<Module> {
+    let mut module_size = 0;
+    let status = pxe.tftp(
+        TftpOpcode::GetFileSize,
+        server,
+        name,
+        ptr::null_mut(),
+        &mut module_size,
+    );
+    if status != Status::SUCCESS {
+        debug!("Can't get size of {} from TFTP server: {:?}", name, status);
+        return None;
+    }
+    let module_size = module_size as usize;
+    debug!("Found {} binary with {} bytes", name, module_size);
+
+    let module_base_paddr = allocate_pages(
+        &st,
+        round_up!(module_size, BASE_PAGE_SIZE) / BASE_PAGE_SIZE,
+        MemoryType(MODULE),
+    );
+    let module_blob: &mut [u8] = unsafe {
+        slice::from_raw_parts_mut(
+            paddr_to_uefi_vaddr(module_base_paddr).as_mut_ptr::<u8>(),
+            module_size,
+        )
+    };
+
+    let mut read = module_size as u64;
+    let status = pxe.tftp(
+        TftpOpcode::ReadFile,
+        server,
+        name,
+        module_blob.as_mut_ptr(),
+        &mut read,
+    );
+    if status != Status::SUCCESS || read as usize != module_size {
+        error!(
+            "Can't read {} from TFTP server: {:?} ({} of {} bytes)",
+            name, status, read, module_size
+        );
+        return None;
+    }
+
+    Some(Module::new(
+        name,
+        paddr_to_kernel_vaddr(module_base_paddr),
+        module_base_paddr,
+        module_size,
+    ))
+}
+
+/// The binaries `init.toml` starts (`binary = "..."` lines).
+fn config_binaries(config: &[u8]) -> Vec<String> {
+    core::str::from_utf8(config)
+        .unwrap_or("")
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once('=')?;
+            let value = value.split('#').next()?.trim();
+            (key.trim() == "binary").then(|| value.trim_matches('"').into())
+        })
+        .collect()
+}
+
+/// The init binary named on the command-line (`init=...`).
+fn init_binary(cmdline: &[u8]) -> String {
+    core::str::from_utf8(cmdline)
+        .unwrap_or("")
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix("init="))
+        .map(|init| init.trim_matches('\'').trim_matches('"'))
+        .unwrap_or("init")
+        .into()
+}
+
+/// Loads the kernel, `cmdline.in`, the manifest, the root file-system
+/// archive and `init.toml` (if there are any) and the binaries to start
+/// (unless they're in `modules` already) from the TFTP server we were PXE
+/// booted from.
+///
+/// Returns where they came from or `None` if the machine wasn't PXE booted.
+pub fn load_modules(
+    st: &SystemTable<Boot>,
+    modules: &mut Vec<(String, Module)>,
+) -> Option<ModuleSource> {
+    let pxe = st.boot_services().locate_protocol::<BaseCode>().ok()?;
+    let pxe = pxe.expect("Warnings encountered while opening PXE base code");
+    let pxe = unsafe { &mut *pxe.get() };
+    let server = pxe.server()?;
+    info!(
+        "Loading modules from TFTP server {}.{}.{}.{}",
+        server.0[0], server.0[1], server.0[2], server.0[3]
+    );
+
+    let mut load = |name: &str, modules: &mut Vec<(String, Module)>| {
+        if modules.iter().any(|(n, _)| n == name) {
+            return;
+        }
+        if let Some(module) = load_binary_into_memory(st, pxe, &server, name) {
+            modules.push((name.into(), module));
+        }
+    };
+
+    load("kernel", modules);
+    load("cmdline.in", modules);
+    load(crate::integrity::MANIFEST, modules);
+    // Archive with the initial contents of the file-system and the
+    // processes to start (optional)
+    load("rootfs", modules);
+    load("init.toml", modules);
+    // The kernel starts what `init.toml` declares, `init=` otherwise
+    let binaries = match modules.iter().find(|(name, _)| name == "init.toml") {
+        Some((_, m)) => config_binaries(unsafe { m.as_pslice() }),
+        None => modules
+            .iter()
+            .find(|(name, _)| name == "cmdline.in")
+            .map(|(_, m)| init_binary(unsafe { m.as_pslice() }))
+            .into_iter()
+            .collect(),
+    };
+    for binary in binaries.iter() {
+        load(binary.as_str(), modules);
+    }
+
+    let mut ip = [0; 4];
+    ip.copy_from_slice(&server.0[..4]);
+    Some(ModuleSource::Tftp { server: ip })
+}
@@ -0,0 +1,63 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Prepares the UEFI runtime services for the kernel.
+//!
+//! The kernel calls the runtime services from its own address space, so we
+//! tell the firmware (with `SetVirtualAddressMap`) that all runtime regions
+//! are mapped at `KERNEL_OFFSET` (see `map_physical_memory`).
+
+use core::mem;
+
+use uefi::table::boot::{MemoryAttribute, MemoryDescriptor};
+use uefi::Status;
+use x86::bits64::paging::PAddr;
+
+use crate::kernel::KERNEL_OFFSET;
+
+/// `EFI_RUNTIME_SERVICES` (up to `SetVirtualAddressMap`, which uefi-rs
+/// doesn't expose yet).
+#[repr(C)]
+struct RuntimeServices {
+    header: [u8; 24],
+    get_time: usize,
+    set_time: usize,
+    get_wakeup_time: usize,
+    set_wakeup_time: usize,
+    set_virtual_address_map: unsafe extern "efiapi" fn(
+        map_size: usize,
+        desc_size: usize,
+        desc_version: u32,
+        virtual_map: *mut MemoryDescriptor,
+    ) -> Status,
+}
+
+/// Relocates the runtime services to `KERNEL_OFFSET`.
+///
+/// Has to be called after exiting boot services but before switching to the
+/// kernel address space (the firmware runs identity mapped until this
+/// returns). Returns false if the firmware refused the new map, the kernel
+/// can't use the runtime services in that case.
+///
+/// # Safety
+/// `runtime_services` has to point to the firmware's runtime services table
+/// and `map` has to be the final memory map.
+pub unsafe fn set_virtual_address_map(
+    runtime_services: PAddr,
+    map: &mut [MemoryDescriptor],
+) -> bool {
+    for desc in map.iter_mut() {
+        if desc.att.contains(MemoryAttribute::RUNTIME) {
+            desc.virt_start = desc.phys_start + KERNEL_OFFSET as u64;
+        }
+    }
+
+    let rt = &*(runtime_services.as_u64() as *const RuntimeServices);
+    let status = (rt.set_virtual_address_map)(
+        map.len() * mem::size_of::<MemoryDescriptor>(),
+        mem::size_of::<MemoryDescriptor>(),
+        MemoryDescriptor::VERSION,
+        map.as_mut_ptr(),
+    );
+    status == Status::SUCCESS
+}
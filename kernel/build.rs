@@ -37,4 +37,50 @@ fn main() {
         .expect("Could not determine git hash");
     let git_hash = String::from_utf8(output.stdout).expect("Could not parse the git hash");
     println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+
+    // Build information the kernel reports at boot, in panics and with
+    // `System::build_info` (see `src/build_info.rs`)
+    let dirty = Command::new("git")
+        .args(&["diff-index", "--quiet", "HEAD", "--"])
+        .status()
+        .map(|status| !status.success())
+        .unwrap_or(false);
+    println!(
+        "cargo:rustc-env=NRK_GIT_VERSION={}{}",
+        git_hash.trim(),
+        if dirty { "-dirty" } else { "" }
+    );
+    println!(
+        "cargo:rustc-env=NRK_BUILD={}",
+        env::var("PROFILE").unwrap_or_else(|_| String::from("unknown"))
+    );
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=NRK_FEATURES={}", features.join(","));
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    let rustc_version = Command::new(rustc)
+        .arg("-V")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .unwrap_or_else(|| String::from("rustc (unknown version)"));
+    println!("cargo:rustc-env=NRK_RUSTC={}", rustc_version.trim());
+
+    // Pick up new commits (and changes to the working tree)
+    if let Ok(output) = Command::new("git")
+        .args(&["rev-parse", "--git-dir"])
+        .output()
+    {
+        if let Ok(git_dir) = String::from_utf8(output.stdout) {
+            println!("cargo:rerun-if-changed={}/HEAD", git_dir.trim());
+            println!("cargo:rerun-if-changed={}/index", git_dir.trim());
+        }
+    }
 }
@@ -21,7 +21,7 @@ from time import sleep
 from plumbum import colors, local, SshMachine
 from plumbum.commands import ProcessExecutionError
 
-from plumbum.cmd import whoami, python3, cat, getent, whoami
+from plumbum.cmd import whoami, python3, cat, getent, whoami, cargo
 try:
     from plumbum.cmd import xargo
 except ImportError as e:
@@ -55,6 +55,7 @@ TARGET_PATH = (SCRIPT_PATH / '..').resolve() / 'target'
 KERNEL_PATH = SCRIPT_PATH
 LIBS_PATH = (SCRIPT_PATH / '..').resolve() / 'lib'
 USR_PATH = (SCRIPT_PATH / '..').resolve() / 'usr'
+SCRIPTS_PATH = (SCRIPT_PATH / '..').resolve() / 'scripts'
 
 UEFI_TARGET = "{}-uefi".format(ARCH)
 KERNEL_TARGET = "{}-nrk".format(ARCH)
@@ -82,8 +83,14 @@ parser.add_argument('-m', '--mods', nargs='+', default=['init'],
                     help='User-space modules to be included in build & deployment', required=False)
 parser.add_argument("--cmd", type=str,
                     help="Command line arguments passed to the kernel.")
+parser.add_argument("--rootfs", type=str, required=False, default=None,
+                    help="Directory to populate the kernel's file-system from at boot (deployed as an archive).")
+parser.add_argument("--init-config", type=str, required=False, default=None,
+                    help="init.toml that declares the processes the kernel starts (instead of init= on the command line).")
 parser.add_argument("--machine",
                     help='Which machine to run on (defaults to qemu)', required=False, default='qemu')
+parser.add_argument("--target", default='nrk', choices=["nrk", "unix"],
+                    help="Build the kernel for bare-metal/QEMU (nrk) or as a host process for development (unix).", required=False)
 
 # QEMU related arguments
 parser.add_argument("--qemu-nodes", type=int,
@@ -102,6 +109,12 @@ parser.add_argument("--qemu-large-pages", action="store_true", default=False,
                     help="Use large-pages on the host for guest memory", required=False)
 parser.add_argument("--qemu-settings", type=str,
                     help="Pass additional generic QEMU arguments.")
+parser.add_argument("--qemu-no-x2apic", action="store_true", default=False,
+                    help="Hide the x2APIC from the guest (NRK uses the xAPIC)", required=False)
+parser.add_argument("--qemu-no-tsc-deadline", action="store_true", default=False,
+                    help="Hide the TSC-deadline timer from the guest (NRK uses the APIC timer in one-shot mode)", required=False)
+parser.add_argument("--qemu-no-invtsc", action="store_true", default=False,
+                    help="Hide the invariant TSC from the guest (NRK keeps time with the HPET)", required=False)
 parser.add_argument("--qemu-monitor", action="store_true",
                     help="Launch the QEMU monitor (for qemu)")
 parser.add_argument("--pvrdma", action="store_true",
@@ -172,6 +185,42 @@ def build_kernel(args):
             xargo(*build_args)
 
 
+def build_kernel_unix(args):
+    "Builds the kernel as a process for the host (unix) platform"
+    log("Build kernel (unix)")
+    with local.cwd(KERNEL_PATH):
+        build_args = ['build', '--bin', 'nrk']
+        if args.no_kfeatures:
+            build_args += ["--no-default-features"]
+        for feature in args.kfeatures:
+            build_args += ['--features', feature]
+        build_args += CARGO_DEFAULT_ARGS
+        if args.verbose:
+            print("cd {}".format(KERNEL_PATH))
+            print("cargo " + " ".join(build_args))
+        cargo(*build_args)
+
+
+def run_unix(args):
+    """
+    Run the kernel as a process on the host.
+    Returns: A nrk exit error code.
+    """
+    profile = 'release' if args.release else 'debug'
+    kernel = TARGET_PATH / profile / 'nrk'
+    cmd = [str(kernel)]
+    if args.cmd:
+        cmd += args.cmd.split()
+    if args.verbose:
+        print(" ".join(cmd))
+
+    execution = subprocess.run(cmd)
+    nrk_exit_code = execution.returncode
+    print(NRK_EXIT_CODES.get(nrk_exit_code,
+                             "[FAIL] Kernel exited with unknown error status {}.".format(nrk_exit_code)))
+    return nrk_exit_code
+
+
 def build_user_libraries(args):
     "Builds nrk vibrio lib to provide runtime support for other rump based apps"
     log("Build user-space lib vibrio")
@@ -275,6 +324,16 @@ def deploy(args):
             for app in to_copy:
                 shutil.copy2(app, esp_path)
 
+    # Deploy the archive of the root file-system
+    if args.rootfs:
+        python3(SCRIPTS_PATH / 'mkrootfs.py', args.rootfs, esp_path / 'rootfs')
+        deployed.append('rootfs')
+
+    # Deploy the processes to start
+    if args.init_config:
+        shutil.copy2(args.init_config, esp_path / 'init.toml')
+        deployed.append('init.toml')
+
     # Write kernel cmd-line file in ESP dir
     with open(esp_path / 'boot.php', 'w') as boot_file:
         ipxe_script = """#!ipxe
@@ -303,8 +362,11 @@ def run_qemu(args):
     qemu_default_args = ['-no-reboot']
     # Setup KVM and required guest hardware features
     qemu_default_args += ['-enable-kvm']
+    x2apic = '-x2apic' if args.qemu_no_x2apic else '+x2apic'
+    tsc_deadline = '-tsc-deadline' if args.qemu_no_tsc_deadline else '+tsc-deadline'
+    invtsc = '-invtsc' if args.qemu_no_invtsc else '+invtsc'
     qemu_default_args += ['-cpu',
-                          'host,migratable=no,+invtsc,+tsc,+x2apic,+fsgsbase']
+                          'host,migratable=no,{},+tsc,{},{},+fsgsbase'.format(invtsc, x2apic, tsc_deadline)]
     # Use serial communication
     # '-nographic',
     qemu_default_args += ['-display', 'none', '-serial', 'stdio']
@@ -653,6 +715,14 @@ if __name__ == '__main__':
     "Execution pipeline for building and launching nrk"
     args = parser.parse_args()
 
+    if args.target == 'unix':
+        if args.release:
+            CARGO_DEFAULT_ARGS.append("--release")
+        build_kernel_unix(args)
+        if not args.norun:
+            sys.exit(run_unix(args))
+        sys.exit(0)
+
     user = whoami().strip()
     kvm_members = getent['group', 'kvm']().strip().split(":")[-1].split(',')
     if not user in kvm_members and not args.norun:
@@ -0,0 +1,97 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Interfaces every architecture (`arch/x86_64`, `arch/unix`, ...) has to
+//! provide to the generic parts of the kernel.
+//!
+//! Each architecture defines a `Platform` type (re-exported as
+//! `crate::arch::Platform`) which implements all traits in this module.
+//! Generic code should use these instead of reaching into arch specific
+//! modules.
+
+use alloc::sync::Arc;
+use core::sync::atomic::AtomicBool;
+
+use crate::error::KError;
+use crate::memory::vspace::MapAction;
+use crate::memory::PAddr;
+use crate::process::ResumeHandle;
+use crate::stack::Stack;
+
+/// Interrupt control for the current core.
+pub trait IrqControl {
+    /// Enable interrupts on the current core.
+    fn enable();
+
+    /// Disable interrupts on the current core.
+    fn disable();
+
+    /// Signal end-of-interrupt for the interrupt we're currently handling.
+    fn acknowledge();
+
+    /// Run `f` with interrupts enabled, returns true if an interrupt arrived
+    /// (it's delivered once the core leaves the kernel).
+    fn with_interrupts(f: fn()) -> bool;
+}
+
+/// The per-core timer.
+pub trait Timer {
+    /// Default for when to raise the next timer interrupt (in rdtsc ticks).
+    const DEFAULT_DEADLINE: u64;
+
+    /// Raise a timer interrupt on the current core `deadline` ticks from now.
+    fn set(deadline: u64);
+
+    /// The current time (in rdtsc ticks).
+    fn now() -> u64;
+
+    /// The current time (in rdtsc ticks) corrected for the offset of the
+    /// current core's counter, comparable with `global_now` of other cores.
+    fn global_now() -> u64;
+}
+
+/// Entering and leaving the kernel.
+pub trait ContextSwitch {
+    /// Handle to resume execution in a context that entered the kernel.
+    type Resumer: ResumeHandle;
+
+    /// Returns a handle to resume the context that trapped into the kernel
+    /// on the current core (e.g., by a system call or an interrupt).
+    fn resume_current() -> Self::Resumer;
+
+    /// Put the core to sleep (with interrupts enabled).
+    fn halt() -> !;
+}
+
+/// Manipulation of the kernel address space.
+pub trait KernelVSpace {
+    /// Map `size` bytes of physical memory starting at `base` to the same
+    /// address in the kernel address space of the current core.
+    fn map_identity(base: PAddr, size: usize, rights: MapAction) -> Result<(), KError>;
+}
+
+/// Starting additional cores.
+pub trait CoreBoot {
+    /// How the architecture identifies a core (e.g., the APIC id).
+    type CoreId;
+
+    /// Starts up the core identified by `core_id`, after initialization it
+    /// begins to execute `init_function` on `stack`. `initialized` is set by
+    /// the new core once it's up.
+    ///
+    /// # Safety
+    /// The new core executes arbitrary code on `stack`, the caller has to
+    /// make sure `stack` and `args` stay valid while the core runs.
+    unsafe fn boot_core<A>(
+        core_id: Self::CoreId,
+        init_function: fn(Arc<A>, &AtomicBool),
+        args: Arc<A>,
+        initialized: &AtomicBool,
+        stack: &dyn Stack,
+    ) -> Result<(), KError>;
+}
+
+/// All the functionality an architecture needs to provide.
+pub trait Arch: IrqControl + Timer + ContextSwitch + KernelVSpace + CoreBoot {}
+
+impl<T: IrqControl + Timer + ContextSwitch + KernelVSpace + CoreBoot> Arch for T {}
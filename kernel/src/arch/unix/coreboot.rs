@@ -0,0 +1,76 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Emulates booting additional cores by spawning host threads.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::ptr;
+use core::sync::atomic::AtomicBool;
+
+use crate::error::KError;
+use crate::memory::GlobalMemory;
+
+use super::kcb;
+
+/// Everything a new "core" needs to start.
+struct CoreStart<A> {
+    core_id: usize,
+    gmanager: Option<&'static GlobalMemory>,
+    init_function: fn(Arc<A>, &AtomicBool),
+    args: Arc<A>,
+    initialized: *const AtomicBool,
+}
+
+extern "C" fn core_entry<A>(arg: *mut libc::c_void) -> *mut libc::c_void {
+    // Safety: `arg` was created by `Box::into_raw` in `initialize`.
+    let start = unsafe { Box::from_raw(arg as *mut CoreStart<A>) };
+
+    // Every thread has its own (thread-local) KCB, set it up similar to what
+    // the BSP did so memory allocation works:
+    let kcb = kcb::get_kcb();
+    kcb.arch.id = start.core_id;
+    if let Some(gmanager) = start.gmanager {
+        kcb.set_global_memory(gmanager);
+    }
+
+    // Safety: The caller of `initialize` keeps `initialized` alive until the
+    // core signals it's up.
+    (start.init_function)(start.args, unsafe { &*start.initialized });
+    ptr::null_mut()
+}
+
+/// Starts up a new thread that acts as core `core_id` and begins
+/// executing `init_function`.
+///
+/// # Safety
+/// `initialized` has to stay valid until the new core has set it.
+pub unsafe fn initialize<A>(
+    core_id: usize,
+    init_function: fn(Arc<A>, &AtomicBool),
+    args: Arc<A>,
+    initialized: &AtomicBool,
+) -> Result<(), KError> {
+    let start = Box::into_raw(Box::new(CoreStart {
+        core_id,
+        gmanager: kcb::per_core().physical_memory.gmanager,
+        init_function,
+        args,
+        initialized: initialized as *const AtomicBool,
+    }));
+
+    let mut thread: libc::pthread_t = 0;
+    let r = libc::pthread_create(
+        &mut thread,
+        ptr::null(),
+        core_entry::<A>,
+        start as *mut libc::c_void,
+    );
+    if r != 0 {
+        drop(Box::from_raw(start));
+        return Err(KError::NotSupported);
+    }
+
+    libc::pthread_detach(thread);
+    Ok(())
+}
@@ -0,0 +1,33 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Jumping back out of a panicking subsystem (see `crate::domain`).
+//!
+//! Panics on unix go to the standard library, nothing jumps back: [`call`]
+//! just calls.
+
+/// Where [`resume`] returns to.
+#[derive(Debug, Default)]
+pub struct Context;
+
+/// Runs `f(arg)`, always returns `true`.
+///
+/// # Safety
+/// Same as on x86-64.
+pub unsafe fn call(_context: &mut Context, f: extern "C" fn(*mut u8), arg: *mut u8) -> bool {
+    f(arg);
+    true
+}
+
+/// Never called on unix.
+///
+/// # Safety
+/// Same as on x86-64.
+pub unsafe fn resume(_context: &Context) -> ! {
+    unreachable!("No panic domains on unix")
+}
+
+/// There are no NMIs on unix.
+pub fn in_nmi() -> bool {
+    false
+}
@@ -17,7 +17,7 @@ use crate::nr::KernelNode;
 use crate::nrproc::NrProcess;
 use crate::process::{Pid, MAX_PROCESSES};
 use crate::{
-    kcb::{ArchSpecificKcb, BootloaderArguments, Kcb},
+    kcb::{ArchSpecificKcb, BootloaderArguments, Kcb, LocalCore},
     memory::mcache::TCacheSp,
 };
 
@@ -31,7 +31,11 @@ static KERNEL_ARGS: KernelArgs = KernelArgs::new();
 static mut KCB: Kcb<ArchKcb> = {
     Kcb::new(
         &[],
-        BootloaderArguments::new("info", "init", "init", "init"),
+        BootloaderArguments {
+            init_args: "init",
+            app_args: "init",
+            ..BootloaderArguments::DEFAULT
+        },
         TCacheSp::new(0),
         ArchKcb::new(&KERNEL_ARGS),
         0,
@@ -46,6 +50,17 @@ pub fn get_kcb<'a>() -> &'a mut Kcb<ArchKcb> {
     unsafe { &mut KCB }
 }
 
+pub fn per_core<'a>() -> &'a Kcb<ArchKcb> {
+    unsafe { &KCB }
+}
+
+impl LocalCore {
+    /// Mutable access to the KCB of the current core.
+    pub fn kcb_mut(&mut self) -> &mut Kcb<ArchKcb> {
+        get_kcb()
+    }
+}
+
 /// Initialize the KCB in the system.
 ///
 /// Should be called during set-up. Afterwards we can use `get_kcb` safely.
@@ -61,6 +76,8 @@ pub struct ArchKcb {
     pub replica: Option<(Arc<Replica<'static, KernelNode>>, ReplicaToken)>,
     pub cnr_replica: Option<(Arc<MlnrReplica<'static, MlnrKernelNode>>, MlnrReplicaToken)>,
     pub current_executor: Option<Box<UnixThread>>,
+    /// Id of the "core" (the host thread that emulates it).
+    pub(crate) id: usize,
 }
 
 impl ArchKcb {
@@ -71,6 +88,7 @@ impl ArchKcb {
             replica: None,
             cnr_replica: None,
             current_executor: None,
+            id: 0,
         }
     }
 
@@ -84,19 +102,18 @@ impl ArchKcb {
     }
 
     pub fn id(&self) -> usize {
-        0
+        self.id
     }
 
     pub fn max_threads(&self) -> usize {
         0
     }
 
-    #[allow(clippy::boxed_local)]
     pub fn swap_current_executor(
         &mut self,
-        _current_executor: Box<UnixThread>,
+        new_executor: Box<UnixThread>,
     ) -> Option<Box<UnixThread>> {
-        None
+        self.current_executor.replace(new_executor)
     }
 
     pub fn has_executor(&self) -> bool {
@@ -118,7 +135,7 @@ impl ArchSpecificKcb for ArchKcb {
     fn install(&mut self) {}
 
     fn hwthread_id(&self) -> usize {
-        0
+        self.id
     }
 
     fn node(&self) -> usize {
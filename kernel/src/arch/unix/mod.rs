@@ -16,14 +16,20 @@ use crate::memory::{GlobalMemory, GrowBackend, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
 use crate::nr::{KernelNode, Op};
 use crate::{xmain, ExitReason};
 
+pub mod coreboot;
 pub mod debug;
+pub mod domain;
 pub mod irq;
 pub mod kcb;
 pub mod memory;
 pub mod process;
 pub mod timer;
+pub mod usercopy;
 pub mod vspace;
 
+#[path = "../traits.rs"]
+pub mod traits;
+
 pub use bootloader_shared::*;
 
 pub const MAX_NUMA_NODES: usize = 12;
@@ -37,6 +43,87 @@ pub fn advance_fs_replica() {
     unimplemented!("eager_advance_fs_replica not implemented for unix");
 }
 
+/// The unix (process-hosted) platform.
+pub struct Unix;
+
+/// The platform we're compiled for.
+pub type Platform = Unix;
+
+impl traits::IrqControl for Unix {
+    fn enable() {
+        irq::enable();
+    }
+
+    fn disable() {
+        irq::disable();
+    }
+
+    fn acknowledge() {}
+
+    fn with_interrupts(f: fn()) -> bool {
+        f();
+        false
+    }
+}
+
+impl traits::Timer for Unix {
+    const DEFAULT_DEADLINE: u64 = timer::DEFAULT_TIMER_DEADLINE;
+
+    fn set(deadline: u64) {
+        timer::set(deadline);
+    }
+
+    fn now() -> u64 {
+        unsafe { x86::time::rdtsc() }
+    }
+
+    fn global_now() -> u64 {
+        unsafe { x86::time::rdtsc() }
+    }
+}
+
+impl traits::ContextSwitch for Unix {
+    type Resumer = process::UnixResumeHandle;
+
+    fn resume_current() -> Self::Resumer {
+        process::UnixResumeHandle {}
+    }
+
+    fn halt() -> ! {
+        halt()
+    }
+}
+
+impl traits::KernelVSpace for Unix {
+    fn map_identity(
+        base: memory::PAddr,
+        size: usize,
+        rights: crate::memory::vspace::MapAction,
+    ) -> Result<(), crate::error::KError> {
+        kcb::per_core().arch.init_vspace().map_generic(
+            memory::VAddr::from(base.as_u64()),
+            (base, size),
+            rights,
+            true,
+        )
+    }
+}
+
+impl traits::CoreBoot for Unix {
+    type CoreId = usize;
+
+    unsafe fn boot_core<A>(
+        core_id: Self::CoreId,
+        init_function: fn(Arc<A>, &AtomicBool),
+        args: Arc<A>,
+        initialized: &AtomicBool,
+        _stack: &dyn crate::stack::Stack,
+    ) -> Result<(), crate::error::KError> {
+        // Threads come with their own stack
+        coreboot::initialize(core_id, init_function, args, initialized)
+    }
+}
+
 static INITIALIZED: AtomicBool = AtomicBool::new(false);
 
 #[ctor]
@@ -54,6 +141,8 @@ fn init_setup() {
 
     lazy_static::initialize(&rawtime::WALL_TIME_ANCHOR);
     lazy_static::initialize(&rawtime::BOOT_TIME_ANCHOR);
+    crate::features::report();
+    crate::timer_wheel::calibrate();
 
     // Allocate 32 MiB and add it to our heap
     let mut tc = TCacheSp::new(0);
@@ -10,6 +10,7 @@ use core::ops::{Deref, DerefMut};
 use x86::current::paging::PAddr;
 
 use arrayvec::ArrayVec;
+use fallible_collections::FallibleVec;
 use kpi::process::FrameId;
 use lazy_static::lazy_static;
 
@@ -25,6 +26,7 @@ use crate::memory::{Frame, VAddr, LARGE_PAGE_SIZE};
 use crate::nrproc::NrProcess;
 use crate::process::{
     Eid, Executor, Pid, Process, ResumeHandle, MAX_FRAMES_PER_PROCESS, MAX_PROCESSES,
+    MAX_USER_BUFFER_LEN,
 };
 
 use super::debug;
@@ -70,6 +72,16 @@ lazy_static! {
     };
 }
 
+/// There is no separate user address space on unix, we only enforce the
+/// length limit.
+pub fn validate_user_range(_pid: Pid, _base: u64, len: usize, _write: bool) -> Result<(), KError> {
+    if len > MAX_USER_BUFFER_LEN {
+        Err(KError::UserBufferTooLarge { len })
+    } else {
+        Ok(())
+    }
+}
+
 /// TODO: This code is same as x86_64 process. Can we remove it?
 pub struct UserPtr<T> {
     value: *mut T,
@@ -98,33 +110,6 @@ impl<T> DerefMut for UserPtr<T> {
     }
 }
 
-pub struct UserValue<T> {
-    value: T,
-}
-
-impl<T> UserValue<T> {
-    pub fn new(pointer: T) -> UserValue<T> {
-        UserValue { value: pointer }
-    }
-
-    pub fn as_mut_ptr(&mut self) -> *mut T {
-        unsafe { core::mem::transmute(&self.value) }
-    }
-}
-
-impl<T> Deref for UserValue<T> {
-    type Target = T;
-    fn deref(&self) -> &T {
-        &self.value
-    }
-}
-
-impl<T> DerefMut for UserValue<T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.value
-    }
-}
-
 #[derive(Debug, Eq, PartialEq)]
 pub struct UserSlice<'a> {
     pub buffer: &'a mut [u8],
@@ -142,6 +127,15 @@ impl<'a> UserSlice<'a> {
             unsafe { core::slice::from_raw_parts_mut(slice_ptr.as_mut_ptr(), len) };
         UserSlice { buffer: user_slice }
     }
+
+    /// Copies `src` into the buffer, starting `offset` bytes in.
+    pub fn copy_to(&mut self, offset: usize, src: &[u8]) -> Result<(), KError> {
+        if offset + src.len() > self.buffer.len() {
+            return Err(KError::BadAddress);
+        }
+        self.buffer[offset..offset + src.len()].copy_from_slice(src);
+        Ok(())
+    }
 }
 
 impl<'a> Deref for UserSlice<'a> {
@@ -233,6 +227,7 @@ impl Process for UnixProcess {
         _pid: Pid,
         _module: &Module,
         _writable_sections: Vec<Frame>,
+        _offset: VAddr,
     ) -> Result<(), KError> {
         self.vspace.map_frame(
             VAddr::from(0x2000_0000),
@@ -253,6 +248,10 @@ impl Process for UnixProcess {
         Ok(0)
     }
 
+    fn last_mapped(&self) -> (VAddr, usize) {
+        (VAddr::zero(), 0)
+    }
+
     fn vspace_mut(&mut self) -> &mut Self::A {
         &mut self.vspace
     }
@@ -265,6 +264,23 @@ impl Process for UnixProcess {
         Ok(Box::new(UnixThread::default()))
     }
 
+    fn restore_executor(&mut self, _eid: Eid, _state: kpi::arch::SaveArea) -> Result<(), KError> {
+        Err(KError::NotSupported)
+    }
+
+    fn migrate_executor(
+        &mut self,
+        _eid: Eid,
+        _to_region: atopology::NodeId,
+        _state: kpi::arch::SaveArea,
+    ) -> Result<(), KError> {
+        Err(KError::NotSupported)
+    }
+
+    fn resume_executor(&mut self, _eid: Eid) -> Result<Box<Self::E>, KError> {
+        Err(KError::NotSupported)
+    }
+
     fn allocate_fd(&mut self) -> Option<(u64, &mut Fd)> {
         Some((1, &mut self.fd))
     }
@@ -292,10 +308,33 @@ impl Process for UnixProcess {
     fn deallocate_frame(&mut self, _fid: FrameId) -> Result<Frame, KError> {
         Err(KError::InvalidFrameId)
     }
+
+    fn owned_frames(&self, frames: &mut Vec<Frame>) -> Result<(), KError> {
+        for mapping in self.vspace.mappings.values() {
+            if mapping.frame.size() > 0 {
+                frames.try_push(mapping.frame)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        self.vspace.clear();
+        self.fd = Default::default();
+        self.pinfo = Default::default();
+    }
 }
 
-pub fn spawn(binary: &'static str) -> Result<Pid, KError> {
-    let pid = crate::process::make_process::<UnixProcess>(binary)?;
+pub fn spawn(binary: &'static str, root: &str) -> Result<Pid, KError> {
+    let pid = crate::process::make_process::<UnixProcess>(binary, root)?;
     crate::process::allocate_dispatchers::<UnixProcess>(pid)?;
     Ok(0)
 }
+
+/// Starts the init process of the command-line (returns how many processes
+/// we started).
+pub fn start_init() -> Result<usize, KError> {
+    let kcb = kcb::per_core();
+    spawn(kcb.cmdline.init_binary, kcb.cmdline.init_root)?;
+    Ok(1)
+}
@@ -0,0 +1,33 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Copying data between kernel and user-space memory.
+//!
+//! There is no separate user address space on unix, these are plain copies.
+
+use crate::error::KError;
+
+/// Copies `dst.len()` bytes from user-space address `src` into `dst`.
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), KError> {
+    let src = unsafe { core::slice::from_raw_parts(src as *const u8, dst.len()) };
+    dst.copy_from_slice(src);
+    Ok(())
+}
+
+/// Copies `src` to the user-space address `dst`.
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), KError> {
+    let dst = unsafe { core::slice::from_raw_parts_mut(dst as *mut u8, src.len()) };
+    dst.copy_from_slice(src);
+    Ok(())
+}
+
+/// Reads a `T` from the user-space address `src`.
+pub fn read_user<T: Copy>(src: u64) -> Result<T, KError> {
+    Ok(unsafe { core::ptr::read_unaligned(src as *const T) })
+}
+
+/// Writes `value` to the user-space address `dst`.
+pub fn write_user<T: Copy>(dst: u64, value: &T) -> Result<(), KError> {
+    unsafe { core::ptr::write_unaligned(dst as *mut T, *value) };
+    Ok(())
+}
@@ -1,7 +1,12 @@
 // Copyright © 2021 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-//! A dummy vspace implementation for the unix platform.
+//! A vspace implementation for the unix platform.
+//!
+//! Mappings are emulated with `mmap` in the address space of the host process:
+//! every mapped region is backed by anonymous host memory with the protection
+//! bits derived from the [`MapAction`]. The frame that "backs" a mapping is
+//! only recorded (so `resolve` works as expected), its content is not shared.
 
 use alloc::boxed::Box;
 use core::fmt;
@@ -31,6 +36,25 @@ impl fmt::Debug for VSpace {
     }
 }
 
+/// Translate `rights` to the protection bits for `mmap`/`mprotect`.
+fn to_prot(rights: MapAction) -> libc::c_int {
+    match rights {
+        MapAction::None => libc::PROT_NONE,
+        MapAction::ReadUser | MapAction::ReadKernel => libc::PROT_READ,
+        MapAction::ReadWriteUser
+        | MapAction::ReadWriteUserNoCache
+        | MapAction::ReadWriteUserWriteThrough
+        | MapAction::ReadWriteUserWriteCombine
+        | MapAction::ReadWriteKernel => libc::PROT_READ | libc::PROT_WRITE,
+        MapAction::ReadExecuteUser | MapAction::ReadExecuteKernel => {
+            libc::PROT_READ | libc::PROT_EXEC
+        }
+        MapAction::ReadWriteExecuteUser | MapAction::ReadWriteExecuteKernel => {
+            libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC
+        }
+    }
+}
+
 impl VSpace {
     pub fn new() -> VSpace {
         VSpace {
@@ -41,43 +65,142 @@ impl VSpace {
         }
     }
 
+    /// Reserve `size` bytes at `vbase` in the host process.
+    ///
+    /// Fails with `AlreadyMapped` if the host already uses (part of) the
+    /// region.
+    fn mmap(vbase: VAddr, size: usize, rights: MapAction) -> Result<(), KError> {
+        let addr = unsafe {
+            libc::mmap(
+                vbase.as_usize() as *mut libc::c_void,
+                size,
+                to_prot(rights),
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED_NOREPLACE,
+                -1,
+                0,
+            )
+        };
+
+        if addr == libc::MAP_FAILED {
+            Err(KError::AlreadyMapped { base: vbase })
+        } else if addr as usize != vbase.as_usize() {
+            // Older kernels ignore MAP_FIXED_NOREPLACE and treat the
+            // address as a hint:
+            unsafe { libc::munmap(addr, size) };
+            Err(KError::AlreadyMapped { base: vbase })
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn map_generic(
         &mut self,
-        _vbase: VAddr,
-        _pregion: (PAddr, usize),
-        _rights: MapAction,
-        _create_mappings: bool,
+        vbase: VAddr,
+        pregion: (PAddr, usize),
+        rights: MapAction,
+        create_mappings: bool,
     ) -> Result<(), KError> {
-        Ok(())
+        if !create_mappings {
+            return Ok(());
+        }
+
+        match VSpace::mmap(vbase, pregion.1, rights) {
+            // Identity mappings of host memory are already there
+            Ok(()) | Err(KError::AlreadyMapped { .. }) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Find the mapping that contains `vaddr`.
+    fn find(&self, vaddr: VAddr) -> Option<(&core::ops::Range<usize>, &MappingInfo)> {
+        self.mappings
+            .iter()
+            .find(|(range, _)| range.contains(&vaddr.as_usize()))
     }
 }
 
 impl AddressSpace for VSpace {
     fn map_frame(&mut self, base: VAddr, frame: Frame, action: MapAction) -> Result<(), KError> {
         let ma = MappingInfo::new(frame, action);
-        self.mappings.insert(ma.vrange(base), ma);
-        unimplemented!("map_frame");
+        let range = ma.vrange(base);
+        if self
+            .mappings
+            .keys()
+            .any(|r| r.start < range.end && range.start < r.end)
+        {
+            return Err(KError::AlreadyMapped { base });
+        }
+
+        VSpace::mmap(base, frame.size(), action)?;
+        self.mappings.insert(range, ma);
+        Ok(())
     }
 
     fn map_memory_requirements(_base: VAddr, _frames: &[Frame]) -> usize {
-        unimplemented!("map_memory_requirements");
+        // The host maintains the page-tables for us
+        0
+    }
+
+    fn adjust(&mut self, vaddr: VAddr, rights: MapAction) -> Result<(VAddr, usize), KError> {
+        let range = self
+            .find(vaddr)
+            .map(|(range, _)| range.clone())
+            .ok_or(KError::NotMapped)?;
+
+        let r = unsafe {
+            libc::mprotect(
+                range.start as *mut libc::c_void,
+                range.end - range.start,
+                to_prot(rights),
+            )
+        };
+        if r != 0 {
+            return Err(KError::NotMapped);
+        }
+
+        let mapping = self.mappings.get_mut(&range).ok_or(KError::NotMapped)?;
+        mapping.rights = rights;
+        Ok((VAddr::from(range.start), range.end - range.start))
     }
 
-    fn adjust(&mut self, _vaddr: VAddr, _rights: MapAction) -> Result<(VAddr, usize), KError> {
-        unimplemented!("adjust");
+    fn resolve(&self, vaddr: VAddr) -> Result<(PAddr, MapAction), KError> {
+        let (range, mapping) = self.find(vaddr).ok_or(KError::NotMapped)?;
+        let offset = vaddr.as_usize() - range.start;
+        Ok((mapping.frame.base + offset, mapping.rights))
     }
 
-    fn resolve(&self, _vaddr: VAddr) -> Result<(PAddr, MapAction), KError> {
-        unimplemented!("resolve");
+    fn next_mapping(&self, vaddr: VAddr) -> Option<(VAddr, Frame, MapAction)> {
+        self.mappings
+            .iter()
+            .filter(|(range, _)| range.start >= vaddr.as_usize())
+            .min_by_key(|(range, _)| range.start)
+            .map(|(range, mapping)| (VAddr::from(range.start), mapping.frame, mapping.rights))
     }
 
-    fn unmap(&mut self, _vaddr: VAddr) -> Result<TlbFlushHandle, KError> {
-        unimplemented!("unmap");
+    fn unmap(&mut self, vaddr: VAddr) -> Result<TlbFlushHandle, KError> {
+        let range = self
+            .find(vaddr)
+            .map(|(range, _)| range.clone())
+            .ok_or(KError::NotMapped)?;
+        let mapping = self.mappings.remove(&range).ok_or(KError::NotMapped)?;
+
+        unsafe { libc::munmap(range.start as *mut libc::c_void, range.end - range.start) };
+        Ok(TlbFlushHandle::new(VAddr::from(range.start), mapping.frame))
+    }
+}
+
+impl VSpace {
+    /// Removes all mappings (and their memory in the host process).
+    pub fn clear(&mut self) {
+        for range in self.mappings.keys() {
+            unsafe { libc::munmap(range.start as *mut libc::c_void, range.end - range.start) };
+        }
+        self.mappings.clear();
     }
 }
 
 impl Drop for VSpace {
     fn drop(&mut self) {
-        panic!("Drop for VSpace!");
+        self.clear();
     }
 }
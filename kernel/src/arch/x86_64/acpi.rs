@@ -8,11 +8,15 @@ use core::ffi::VaList;
 use core::ptr;
 
 use cstr_core::CStr;
+use fallible_collections::FallibleVec;
 use klogger::sprint;
 use libacpica::*;
 use log::{debug, error, info, trace};
 
 use crate::alloc::alloc;
+use crate::alloc::vec::Vec;
+use crate::error::KError;
+use crate::irqroute::SourceOverride;
 use crate::kcb::Kcb;
 use crate::memory::vspace::MapAction;
 
@@ -669,3 +673,97 @@ pub(crate) fn init() -> Result<(), ACPI_STATUS> {
 
     Ok(())
 }
+
+/// The HPET description table (the parts we use).
+#[repr(C, packed)]
+struct HpetTable {
+    header: ACPI_TABLE_HEADER,
+    /// Hardware id of the event timer block.
+    id: u32,
+    /// Where the registers are (a generic address structure).
+    address_space_id: u8,
+    register_bit_width: u8,
+    register_bit_offset: u8,
+    access_width: u8,
+    address: u64,
+}
+
+/// Address space of a generic address structure: system memory.
+const ACPI_ADR_SPACE_SYSTEM_MEMORY: u8 = 0;
+
+/// The physical address of the HPET registers (from the `HPET` table), if
+/// the machine has an HPET.
+pub(crate) fn hpet_address() -> Option<PAddr> {
+    let mut table: *mut ACPI_TABLE_HEADER = ptr::null_mut();
+    let ret = unsafe { AcpiGetTable(b"HPET\0".as_ptr() as *mut i8, 1, &mut table) };
+    if ret != AE_OK || table.is_null() {
+        return None;
+    }
+
+    // Safe: ACPICA found the table, it stays mapped
+    let hpet = unsafe { ptr::read_unaligned(table as *const HpetTable) };
+    if hpet.address_space_id != ACPI_ADR_SPACE_SYSTEM_MEMORY || hpet.address == 0 {
+        return None;
+    }
+    Some(PAddr::from(hpet.address))
+}
+
+/// Bytes of the MADT before its entries (header, local APIC address and
+/// flags).
+const MADT_ENTRIES_OFFSET: usize = core::mem::size_of::<ACPI_TABLE_HEADER>() + 8;
+
+/// Type of a MADT entry: interrupt source override.
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+
+/// An interrupt source override entry of the MADT.
+#[repr(C, packed)]
+struct MadtInterruptOverride {
+    entry_type: u8,
+    length: u8,
+    /// Always 0 (ISA).
+    bus: u8,
+    source: u8,
+    gsi: u32,
+    /// MPS INTI flags (polarity and trigger mode).
+    flags: u16,
+}
+
+/// The interrupt source overrides of the MADT (`APIC` table), empty if the
+/// machine has none.
+pub(crate) fn interrupt_overrides() -> Result<Vec<SourceOverride>, KError> {
+    let mut overrides = Vec::new();
+    let mut table: *mut ACPI_TABLE_HEADER = ptr::null_mut();
+    let ret = unsafe { AcpiGetTable(b"APIC\0".as_ptr() as *mut i8, 1, &mut table) };
+    if ret != AE_OK || table.is_null() {
+        return Ok(overrides);
+    }
+
+    // Safe: ACPICA found the table, it stays mapped
+    let header = unsafe { ptr::read_unaligned(table) };
+    let base = table as *const u8;
+    let mut offset = MADT_ENTRIES_OFFSET;
+    while offset + 2 <= header.Length as usize {
+        // Safe: The entry header is within the table
+        let (entry_type, length) = unsafe { (*base.add(offset), *base.add(offset + 1) as usize) };
+        if length < 2 || offset + length > header.Length as usize {
+            break;
+        }
+        if entry_type == MADT_INTERRUPT_OVERRIDE
+            && length >= core::mem::size_of::<MadtInterruptOverride>()
+        {
+            // Safe: The entry is within the table
+            let entry =
+                unsafe { ptr::read_unaligned(base.add(offset) as *const MadtInterruptOverride) };
+            let (source, gsi, flags) = (entry.source, entry.gsi, entry.flags);
+            trace!(
+                "Interrupt source override IRQ {} -> GSI {} ({:#x})",
+                source,
+                gsi,
+                flags
+            );
+            overrides.try_push(SourceOverride::new(source, gsi, flags))?;
+        }
+        offset += length;
+    }
+    Ok(overrides)
+}
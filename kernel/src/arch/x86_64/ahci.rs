@@ -0,0 +1,390 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A driver for SATA disks behind an AHCI controller.
+//!
+//! It's attached to every PCI function of class "SATA controller" (see
+//! `devices`) and registers the disk of every port that has one with
+//! `crate::block`. Ports use a single command slot and we poll for
+//! completion (interrupts are delivered to processes, see `ps2`): a command
+//! moves at most [`MAX_SECTORS`] through a bounce buffer. ATAPI drives and
+//! port multipliers are ignored.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use core::ptr;
+use core::time::Duration;
+
+use kpi::system::{DeviceInfo, DeviceResource};
+use log::{debug, info, warn};
+
+use crate::block::{self, BlockDevice, SECTOR_SIZE};
+use crate::devices::Driver;
+use crate::error::KError;
+use crate::memory::reserved;
+use crate::memory::vspace::MapAction;
+use crate::memory::{
+    paddr_to_kernel_vaddr, Frame, PAddr, PhysicalPageProvider, BASE_PAGE_SIZE, KERNEL_BASE,
+};
+use crate::round_up;
+
+/// Mass storage controller, SATA (PCI class and subclass).
+const CLASS: (u8, u8) = (0x01, 0x06);
+
+/// Global registers of the controller.
+const PORTS_IMPLEMENTED: usize = 0x0c;
+const GLOBAL_CONTROL: usize = 0x04;
+/// The controller speaks AHCI (and not only legacy IDE).
+const AHCI_ENABLE: u32 = 1 << 31;
+
+/// Registers of a port (relative to [`port_base`]).
+const CLB: usize = 0x00;
+const CLBU: usize = 0x04;
+const FB: usize = 0x08;
+const FBU: usize = 0x0c;
+const IS: usize = 0x10;
+const IE: usize = 0x14;
+const CMD: usize = 0x18;
+const TFD: usize = 0x20;
+const SIG: usize = 0x24;
+const SSTS: usize = 0x28;
+const SERR: usize = 0x30;
+const CI: usize = 0x38;
+
+/// Bits of `CMD`.
+const CMD_ST: u32 = 1 << 0;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+/// Task file error (in `IS`).
+const IS_TFES: u32 = 1 << 30;
+
+/// Busy and data request (in `TFD`).
+const TFD_BUSY: u32 = 0x80 | 0x08;
+
+/// A device is present and communicating (`SSTS.DET`).
+const DET_PRESENT: u32 = 0x3;
+
+/// Signature of a SATA disk.
+const SIG_ATA: u32 = 0x0000_0101;
+
+/// ATA commands we use.
+const ATA_IDENTIFY: u8 = 0xec;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xea;
+
+/// Register FIS, host to device.
+const FIS_REG_H2D: u8 = 0x27;
+
+/// Where the structures of a port are in its page: the command list
+/// (32 headers), the received FISes and the command table of slot 0 (with
+/// one PRD entry).
+const COMMAND_LIST: usize = 0x0;
+const RECEIVED_FIS: usize = 0x400;
+const COMMAND_TABLE: usize = 0x800;
+const PRDT: usize = COMMAND_TABLE + 0x80;
+
+/// Most sectors a command moves (the size of the bounce buffer).
+const MAX_SECTORS: usize = BASE_PAGE_SIZE / SECTOR_SIZE;
+
+/// How long we wait for the port or a command.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Virtual address of the registers of `port`.
+fn port_base(hba: u64, port: usize) -> u64 {
+    hba + 0x100 + port as u64 * 0x80
+}
+
+fn read(base: u64, register: usize) -> u32 {
+    unsafe { ptr::read_volatile((base + register as u64) as *const u32) }
+}
+
+fn write(base: u64, register: usize, value: u32) {
+    unsafe { ptr::write_volatile((base + register as u64) as *mut u32, value) }
+}
+
+/// Spins until `done` returns true, fails after [`TIMEOUT`].
+fn wait(mut done: impl FnMut() -> bool) -> Result<(), KError> {
+    let start = rawtime::Instant::now();
+    while !done() {
+        if start.elapsed() > TIMEOUT {
+            return Err(KError::DiskTimeout);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// A disk on a port of an AHCI controller.
+struct Port {
+    name: String,
+    /// Virtual address of the registers of the port.
+    regs: u64,
+    /// Command list, received FISes and command table.
+    memory: Frame,
+    /// The data of the commands.
+    buffer: Frame,
+    sectors: u64,
+}
+
+impl Port {
+    /// Sets up `port` of the controller at `hba`, returns `None` if there
+    /// is no SATA disk on it.
+    fn new(hba: u64, port: usize, device: &DeviceInfo) -> Result<Option<Port>, KError> {
+        let regs = port_base(hba, port);
+        if read(regs, SSTS) & 0xf != DET_PRESENT || read(regs, SIG) != SIG_ATA {
+            return Ok(None);
+        }
+
+        let (memory, buffer) = {
+            let kcb = super::kcb::per_core();
+            let mut mem_manager = kcb.mem_manager();
+            let mut memory = mem_manager.allocate_base_page()?;
+            let buffer = match mem_manager.allocate_base_page() {
+                Ok(buffer) => buffer,
+                Err(e) => {
+                    mem_manager.release_base_page(memory)?;
+                    return Err(e);
+                }
+            };
+            unsafe { memory.zero() };
+            (memory, buffer)
+        };
+        // Gives the frames back if we fail
+        let mut disk = Port {
+            name: format!("{} port {}", device.name, port),
+            regs,
+            memory,
+            buffer,
+            sectors: 0,
+        };
+
+        disk.stop()?;
+        let base = disk.memory.base.as_u64();
+        write(regs, CLB, (base + COMMAND_LIST as u64) as u32);
+        write(regs, CLBU, ((base + COMMAND_LIST as u64) >> 32) as u32);
+        write(regs, FB, (base + RECEIVED_FIS as u64) as u32);
+        write(regs, FBU, ((base + RECEIVED_FIS as u64) >> 32) as u32);
+        write(regs, SERR, u32::MAX);
+        write(regs, IS, u32::MAX);
+        // We poll
+        write(regs, IE, 0);
+        write(regs, CMD, read(regs, CMD) | CMD_FRE);
+        write(regs, CMD, read(regs, CMD) | CMD_ST);
+
+        disk.identify()?;
+        Ok(Some(disk))
+    }
+
+    /// Stops processing commands (so we can move the command list).
+    fn stop(&self) -> Result<(), KError> {
+        write(self.regs, CMD, read(self.regs, CMD) & !CMD_ST);
+        wait(|| read(self.regs, CMD) & CMD_CR == 0)?;
+        write(self.regs, CMD, read(self.regs, CMD) & !CMD_FRE);
+        wait(|| read(self.regs, CMD) & CMD_FR == 0)
+    }
+
+    /// Reads the size of the disk.
+    fn identify(&mut self) -> Result<(), KError> {
+        self.command(ATA_IDENTIFY, 0, 0, false)?;
+        let identify = self.buffer();
+        // Words 100-103: number of sectors for 48-bit addressing
+        let mut sectors = [0u8; 8];
+        sectors.copy_from_slice(&identify[200..208]);
+        self.sectors = u64::from_le_bytes(sectors);
+        Ok(())
+    }
+
+    fn buffer(&mut self) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                self.buffer.kernel_vaddr().as_mut_ptr::<u8>(),
+                self.buffer.size(),
+            )
+        }
+    }
+
+    /// Runs ATA command `cmd` for `count` sectors starting at `lba`,
+    /// with the bounce buffer as data.
+    fn command(&mut self, cmd: u8, lba: u64, count: usize, write_data: bool) -> Result<(), KError> {
+        let regs = self.regs;
+        wait(|| read(regs, TFD) & TFD_BUSY == 0)?;
+
+        let memory = self.memory.kernel_vaddr().as_mut_ptr::<u8>();
+        let table = self.memory.base.as_u64() + COMMAND_TABLE as u64;
+        let bytes = if cmd == ATA_IDENTIFY {
+            SECTOR_SIZE
+        } else {
+            count * SECTOR_SIZE
+        };
+        unsafe {
+            // Command header of slot 0: FIS length (in dwords), direction
+            // and number of PRD entries
+            let header = memory.add(COMMAND_LIST) as *mut u32;
+            let prds = if bytes > 0 { 1 } else { 0 };
+            let flags = 5 | if write_data { 1 << 6 } else { 0 } | prds << 16;
+            ptr::write_volatile(header, flags);
+            ptr::write_volatile(header.add(1), 0);
+            ptr::write_volatile(header.add(2), table as u32);
+            ptr::write_volatile(header.add(3), (table >> 32) as u32);
+
+            let lba = lba.to_le_bytes();
+            let fis: [u8; 20] = [
+                FIS_REG_H2D,
+                // It's a command
+                0x80,
+                cmd,
+                0,
+                lba[0],
+                lba[1],
+                lba[2],
+                // LBA mode
+                0x40,
+                lba[3],
+                lba[4],
+                lba[5],
+                0,
+                count as u8,
+                (count >> 8) as u8,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ];
+            ptr::copy_nonoverlapping(fis.as_ptr(), memory.add(COMMAND_TABLE), fis.len());
+
+            let prd = memory.add(PRDT) as *mut u32;
+            let data = self.buffer.base.as_u64();
+            ptr::write_volatile(prd, data as u32);
+            ptr::write_volatile(prd.add(1), (data >> 32) as u32);
+            ptr::write_volatile(prd.add(2), 0);
+            ptr::write_volatile(prd.add(3), bytes.saturating_sub(1) as u32);
+        }
+
+        write(regs, IS, u32::MAX);
+        write(regs, CI, 1);
+        wait(|| read(regs, CI) & 1 == 0 || read(regs, IS) & IS_TFES != 0)?;
+        if read(regs, IS) & IS_TFES != 0 {
+            let status = read(regs, TFD) as u8;
+            debug!("{}: command {:#x} failed", self.name, cmd);
+            write(regs, IS, u32::MAX);
+            // Clear the error, the port stops processing commands on errors
+            self.stop()?;
+            write(regs, SERR, u32::MAX);
+            write(regs, CMD, read(regs, CMD) | CMD_FRE);
+            write(regs, CMD, read(regs, CMD) | CMD_ST);
+            return Err(KError::DiskError { status });
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Port {
+    fn drop(&mut self) {
+        // The port may still have them
+        if self.stop().is_err() {
+            warn!("{} didn't stop, leaking its memory", self.name);
+            return;
+        }
+        let kcb = super::kcb::per_core();
+        let mut mem_manager = kcb.mem_manager();
+        for frame in &[self.memory, self.buffer] {
+            if let Err(e) = mem_manager.release_base_page(*frame) {
+                warn!("Can't free memory of {}: {}", self.name, e);
+            }
+        }
+    }
+}
+
+impl BlockDevice for Port {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), KError> {
+        block::check(self, sector, buf.len())?;
+        for (idx, chunk) in buf.chunks_mut(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = sector + (idx * MAX_SECTORS) as u64;
+            self.command(ATA_READ_DMA_EXT, lba, chunk.len() / SECTOR_SIZE, false)?;
+            chunk.copy_from_slice(&self.buffer()[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), KError> {
+        block::check(self, sector, buf.len())?;
+        for (idx, chunk) in buf.chunks(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = sector + (idx * MAX_SECTORS) as u64;
+            self.buffer()[..chunk.len()].copy_from_slice(chunk);
+            self.command(ATA_WRITE_DMA_EXT, lba, chunk.len() / SECTOR_SIZE, true)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), KError> {
+        self.command(ATA_FLUSH_CACHE_EXT, 0, 0, false)
+    }
+}
+
+/// The driver for AHCI controllers.
+pub struct Ahci;
+
+impl Driver for Ahci {
+    fn name(&self) -> &'static str {
+        "ahci"
+    }
+
+    fn matches(&self, device: &DeviceInfo) -> bool {
+        (device.class, device.subclass) == CLASS
+    }
+
+    fn attach(&self, device: &DeviceInfo) -> Result<(), KError> {
+        // The registers are in the last BAR (ABAR)
+        let (base, size) = device
+            .resources
+            .iter()
+            .rev()
+            .find_map(|resource| match *resource {
+                DeviceResource::Memory { base, size } => Some((base, size)),
+                DeviceResource::IoPorts { .. } => None,
+            })
+            .ok_or(KError::NotSupported)?;
+        let size = round_up!(size as usize, BASE_PAGE_SIZE);
+        reserved::check(PAddr::from(base), size, None)?;
+        super::kcb::per_core()
+            .arch
+            .init_vspace()
+            .map_identity_with_offset(
+                PAddr::from(KERNEL_BASE),
+                PAddr::from(base),
+                size,
+                MapAction::ReadWriteKernel,
+            )?;
+        super::devices::enable_bus_master(device);
+
+        let hba = paddr_to_kernel_vaddr(PAddr::from(base)).as_u64();
+        write(hba, GLOBAL_CONTROL, read(hba, GLOBAL_CONTROL) | AHCI_ENABLE);
+        let ports = read(hba, PORTS_IMPLEMENTED);
+        info!("AHCI controller {} with ports {:#x}", device.name, ports);
+
+        for port in (0..32).filter(|port| ports & (1 << port) != 0) {
+            match Port::new(hba, port, device) {
+                Ok(Some(disk)) => {
+                    block::register(Box::try_new(disk)?)?;
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Can't use {} port {}: {}", device.name, port, e),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Kernel side of user-space barriers (`ProcessOperation::BarrierWait`).
+//!
+//! A thread that waited long enough at a barrier (see `vibrio::sync::Barrier`)
+//! asks us to wait until the barrier word changes. We halt the core with
+//! `monitor`/`mwait` on the word, so the last thread that arrives wakes all
+//! waiting cores with the store that releases the barrier, without an IPI
+//! and without spinning siblings competing for the core. On CPUs without
+//! `mwait` we spin for a bit instead.
+//!
+//! We wait at most until the next interrupt and then return to user-space,
+//! which handles it and calls us again if the word didn't change yet. With a
+//! timeout we also set an alarm (see `sleep`) and tell user-space how much
+//! of the timeout is left.
+
+use core::hint::spin_loop;
+use core::sync::atomic::AtomicU64;
+
+use x86::cpuid::CpuId;
+
+use crate::error::KError;
+use crate::process::Pid;
+
+use super::process::validate_user_range;
+use super::sleep::Alarm;
+use super::usercopy::read_user;
+
+#[cfg(target_os = "none")]
+global_asm!(include_str!("barrier.S"), options(att_syntax));
+
+extern "C" {
+    /// Returns 0 on success and 1 if we faulted on `word`.
+    fn nrk_monitor_mwait(word: u64, expected: u64) -> u64;
+}
+
+/// How often we check the word without `mwait`.
+const SPIN_LIMIT: usize = 4096;
+
+static HAS_MWAIT: spin::Once<bool> = spin::Once::new();
+
+fn has_mwait() -> bool {
+    *HAS_MWAIT.call_once(|| {
+        CpuId::new()
+            .get_feature_info()
+            .map_or(false, |f| f.has_monitor_mwait())
+    })
+}
+
+/// Halts the core until an interrupt arrives, returns false right away if
+/// the CPU doesn't have `mwait`.
+pub(super) fn halt_until_interrupt() -> bool {
+    if !has_mwait() {
+        return false;
+    }
+    // Nobody writes this, only an interrupt wakes us up
+    let word: u64 = 0;
+    unsafe { nrk_monitor_mwait(&word as *const u64 as u64, 0) };
+    true
+}
+
+/// Halts the core until the kernel `word` isn't `expected` anymore or an
+/// interrupt arrives, returns false right away if the CPU doesn't have
+/// `mwait`.
+pub(super) fn halt_until_changed(word: &AtomicU64, expected: u64) -> bool {
+    if !has_mwait() {
+        return false;
+    }
+    // Can't fault on a kernel address
+    unsafe { nrk_monitor_mwait(word as *const AtomicU64 as u64, expected) };
+    true
+}
+
+/// Waits until the (8-byte aligned) word at user-space address `word` of
+/// `pid` isn't `expected` anymore or an interrupt arrives. A `timeout` (in
+/// ns, 0 for none) makes sure an interrupt arrives once it's up.
+///
+/// Returns true if the word changed and the nanoseconds left of `timeout`.
+pub fn wait(pid: Pid, word: u64, expected: u64, timeout: u64) -> Result<(bool, u64), KError> {
+    if word % 8 != 0 {
+        return Err(KError::InvalidSyscallArgument1 { a: word });
+    }
+    validate_user_range(pid, word, 8, false)?;
+    let alarm = if timeout > 0 {
+        Some(Alarm::set(timeout)?)
+    } else {
+        None
+    };
+
+    if has_mwait() {
+        if unsafe { nrk_monitor_mwait(word, expected) } != 0 {
+            return Err(KError::BadAddress);
+        }
+    } else {
+        for _i in 0..SPIN_LIMIT {
+            if read_user::<u64>(word)? != expected {
+                break;
+            }
+            spin_loop();
+        }
+    }
+
+    let changed = read_user::<u64>(word)? != expected;
+    Ok((changed, alarm.map_or(0, |alarm| alarm.remaining())))
+}
@@ -0,0 +1,229 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Catches system calls that run for too long (`syscall-budget` feature).
+//!
+//! Every system call may spend a budget of cycles in the kernel
+//! (`sysbudget=<ms>` on the command-line, 10 ms by default, 0 turns it off).
+//! On entry, [`enter`] arms the second performance counter of the core (the
+//! profiler uses the first one) to count unhalted kernel cycles and to raise
+//! an NMI once the budget is used up. The NMI handler (`profile.S`, on the
+//! NMI stack of the core) records the kernel call-chain at that point. On
+//! exit, [`exit`] disarms the counter and, if the NMI fired, logs a warning
+//! with the call and the call-chain. This points at lock contention or
+//! unbounded loops in the kernel.
+//!
+//! Halted cycles don't count, so system calls that wait with `mwait` (e.g.,
+//! `Process::sleep`) stay within their budget. Without performance counters
+//! we compare the TSC on exit instead (skipping the calls that wait on
+//! purpose) and can't tell where the time went.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use kpi::process::SyscallRecord;
+use kpi::{ProcessOperation, SystemCall};
+use log::{info, warn};
+use x86::msr::{rdmsr, wrmsr};
+
+use super::lapic;
+use super::profile::{self, InterruptFrame, MAX_DEPTH};
+use super::MAX_CORES;
+use crate::timer_wheel;
+
+const IA32_PMC1: u32 = 0xc2;
+const IA32_PERFEVTSEL1: u32 = 0x187;
+
+/// `IA32_PERFEVTSEL1`: count unhalted core cycles (event 0x3c) in kernel
+/// mode, interrupt on overflow.
+const EVENT_KERNEL_CYCLES: u64 = 0x3c | 1 << 17 | 1 << 20 | 1 << 22;
+
+/// Bit of the second counter in the global status and control MSRs.
+const PMC1: u64 = 1 << 1;
+
+/// Budget if `sysbudget` isn't given.
+const DEFAULT_MS: u64 = 10;
+
+/// Budget of a system call in cycles (0 if the check is off).
+static BUDGET: AtomicU64 = AtomicU64::new(0);
+
+/// Can we use the second performance counter?
+static COUNTER: AtomicBool = AtomicBool::new(false);
+
+/// How many system calls went over budget.
+static OVER_BUDGET: AtomicU64 = AtomicU64::new(0);
+
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+/// The system call a core is running.
+struct Call {
+    /// TSC on entry.
+    start: AtomicU64,
+    /// Number of valid entries in `ips` (0 if the counter didn't overflow).
+    depth: AtomicUsize,
+    /// Call-chain when the counter overflowed.
+    ips: [AtomicU64; MAX_DEPTH],
+}
+
+impl Call {
+    const fn new() -> Call {
+        Call {
+            start: AtomicU64::new(0),
+            depth: AtomicUsize::new(0),
+            ips: [ZERO; MAX_DEPTH],
+        }
+    }
+}
+
+static CALLS: [Call; MAX_CORES] = {
+    const IDLE: Call = Call::new();
+    [IDLE; MAX_CORES]
+};
+
+/// Sets the budget to `ms` milliseconds (from the command-line, needs the
+/// calibrated TSC).
+pub fn init(ms: &str) {
+    let ms = ms.parse::<u64>().unwrap_or_else(|_e| {
+        warn!("Invalid sysbudget={} ignored", ms);
+        DEFAULT_MS
+    });
+    if ms == 0 {
+        info!("System call budget: off");
+        return;
+    }
+
+    let cycles =
+        timer_wheel::ns_to_cycles(ms * 1_000_000).clamp(profile::MIN_PERIOD, profile::MAX_PERIOD);
+    let counter = profile::counters() >= 2;
+    BUDGET.store(cycles, Ordering::Relaxed);
+    COUNTER.store(counter, Ordering::Relaxed);
+    info!(
+        "System call budget: {} ms ({} cycles, {})",
+        ms,
+        cycles,
+        if counter {
+            "performance counter"
+        } else {
+            "TSC only"
+        }
+    );
+}
+
+/// How many system calls went over budget so far.
+pub fn over_budget() -> u64 {
+    OVER_BUDGET.load(Ordering::Relaxed)
+}
+
+/// Starts the budget of a system call on `core`.
+pub fn enter(core: usize) {
+    let budget = BUDGET.load(Ordering::Relaxed);
+    if budget == 0 {
+        return;
+    }
+
+    let call = &CALLS[core];
+    call.depth.store(0, Ordering::Relaxed);
+    call.start
+        .store(unsafe { x86::time::rdtsc() }, Ordering::Relaxed);
+    if COUNTER.load(Ordering::Relaxed) {
+        // Safe: `init` checked that the counter exists
+        unsafe {
+            wrmsr(IA32_PERFEVTSEL1, 0);
+            wrmsr(IA32_PMC1, profile::reload(budget));
+            // The APIC masks the LVT entry on delivery
+            lapic::set_lvt_pmi(profile::LVT_NMI);
+            wrmsr(IA32_PERFEVTSEL1, EVENT_KERNEL_CYCLES);
+            wrmsr(
+                profile::IA32_PERF_GLOBAL_CTRL,
+                rdmsr(profile::IA32_PERF_GLOBAL_CTRL) | PMC1,
+            );
+        }
+    }
+}
+
+/// Ends the budget of the system call on `core`, logs a warning if it was
+/// over budget.
+pub fn exit(core: usize, function: u64, operation: u64, args: [u64; 4]) {
+    let budget = BUDGET.load(Ordering::Relaxed);
+    if budget == 0 {
+        return;
+    }
+
+    let call = &CALLS[core];
+    let elapsed = unsafe { x86::time::rdtsc() } - call.start.load(Ordering::Relaxed);
+    let over = if COUNTER.load(Ordering::Relaxed) {
+        unsafe { wrmsr(IA32_PERFEVTSEL1, 0) };
+        call.depth.load(Ordering::Acquire) > 0
+    } else {
+        elapsed > budget && !waits(function, operation)
+    };
+    if !over {
+        return;
+    }
+
+    OVER_BUDGET.fetch_add(1, Ordering::Relaxed);
+    let record = SyscallRecord {
+        function,
+        operation,
+        args,
+        core: core as u64,
+        ..Default::default()
+    };
+    warn!(
+        "System call {} on core {} took {} us (budget {} us)",
+        record.call(),
+        core,
+        timer_wheel::cycles_to_us(elapsed),
+        timer_wheel::cycles_to_us(budget)
+    );
+
+    let depth = call.depth.swap(0, Ordering::Relaxed);
+    let mut symbolizer = profile::Symbolizer::new();
+    for (frame, ip) in call.ips[..depth].iter().enumerate() {
+        let ip = ip.load(Ordering::Relaxed);
+        warn!("  #{:<2} {:#x} {}", frame, ip, symbolizer.name(ip));
+    }
+}
+
+/// Does the system call wait (for a time or an event) on purpose?
+fn waits(function: u64, operation: u64) -> bool {
+    SystemCall::new(function) == SystemCall::Process
+        && matches!(
+            ProcessOperation::from(operation),
+            ProcessOperation::Sleep
+                | ProcessOperation::WaitPid
+                | ProcessOperation::BarrierWait
+                | ProcessOperation::FutexWait
+                | ProcessOperation::SubscribeEvent
+        )
+}
+
+/// Called by the NMI handler, returns `true` if the counter of the budget
+/// overflowed.
+///
+/// We can't use the KCB here, the kernel stack we interrupted tells us the
+/// core.
+pub fn overflow(frame: &InterruptFrame, rbp: u64) -> bool {
+    if !COUNTER.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    unsafe {
+        if rdmsr(profile::IA32_PERF_GLOBAL_STATUS) & PMC1 == 0 {
+            return false;
+        }
+        wrmsr(profile::IA32_PERF_GLOBAL_OVF_CTRL, PMC1);
+        wrmsr(IA32_PERFEVTSEL1, 0);
+    }
+
+    if let Some(core) = super::stacks::core_of(frame.rsp as usize) {
+        let mut ips = [0; MAX_DEPTH];
+        // Safe: We're in the NMI handler and `frame` is what it interrupted
+        let depth = unsafe { profile::call_chain(frame, rbp, &mut ips) };
+        let call = &CALLS[core];
+        for (slot, ip) in call.ips.iter().zip(ips[..depth].iter()) {
+            slot.store(*ip, Ordering::Relaxed);
+        }
+        call.depth.store(depth, Ordering::Release);
+    }
+    true
+}
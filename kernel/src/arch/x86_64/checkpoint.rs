@@ -0,0 +1,356 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Saving a process to a file and starting a new process from it.
+//!
+//! A checkpoint image starts with a [`Header`], followed by a [`Page`]
+//! record (and the contents unless it's a zero page) for every writable
+//! user page of the process and a [`FdEntry`] for every open file.
+//!
+//! Only the calling core of a process is saved. Other cores that run while
+//! the checkpoint is written can leave it inconsistent, so applications
+//! should quiesce them first.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::{size_of, MaybeUninit};
+
+use fallible_collections::FallibleVec;
+use kpi::io::{FileFlags, FileModes};
+use kpi::process::Capabilities;
+use log::{debug, warn};
+
+use crate::cnrfs::MlnrKernelNode;
+use crate::error::KError;
+use crate::fs::{Len, Offset, FD, MAX_FILES_PER_PROCESS};
+use crate::memory::vspace::MapAction;
+use crate::memory::{
+    paddr_to_kernel_vaddr, Frame, KernelAllocator, PAddr, PhysicalPageProvider, VAddr,
+    BASE_PAGE_SIZE,
+};
+use crate::nr;
+use crate::nrproc::NrProcess;
+use crate::process::{allocate_dispatchers, find_module, make_process, Pid};
+
+use super::process::{Ring3Process, INVALID_EXECUTOR_START};
+use super::Module;
+
+/// Identifies a checkpoint image (and its format version).
+const MAGIC: u64 = 0x6e726b_636b70_0001;
+
+/// Start of a checkpoint image.
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct Header {
+    magic: u64,
+    /// Name of the module the process was loaded from.
+    binary: [u8; Module::MAX_NAME_LEN],
+    binary_len: u64,
+    /// Executor that made the checkpoint (and its NUMA node).
+    eid: u64,
+    affinity: u64,
+    /// Registers of the executor (returning from the checkpoint system call).
+    state: kpi::arch::SaveArea,
+    /// Number of `Page` and `FdEntry` records that follow.
+    pages: u64,
+    fds: u64,
+}
+
+/// A writable base page of the address-space.
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct Page {
+    base: u64,
+    /// Set if the page is mapped executable.
+    executable: u64,
+    /// Set if the page contents follow the record (otherwise it's zeroed).
+    data: u64,
+}
+
+/// An open file of the process.
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct FdEntry {
+    fd: u64,
+    mnode: u64,
+    flags: u64,
+    offset: i64,
+}
+
+fn as_bytes<T: Copy>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+fn write_at(pid: Pid, fd: FD, data: &[u8], offset: Offset) -> Result<Offset, KError> {
+    let buffer: Arc<[u8]> = Arc::from(data);
+    let len = MlnrKernelNode::write_buffer(pid, fd, buffer, offset)?;
+    if len as usize != data.len() {
+        return Err(KError::NoSpace);
+    }
+    Ok(offset + len as Offset)
+}
+
+fn read_at(pid: Pid, fd: FD, data: &mut [u8], offset: Offset) -> Result<Offset, KError> {
+    let buffer = MlnrKernelNode::read_buffer(pid, fd, data.len() as Len, offset)?;
+    if buffer.len() != data.len() {
+        return Err(KError::InvalidCheckpoint);
+    }
+    data.copy_from_slice(&buffer);
+    Ok(offset + buffer.len() as Offset)
+}
+
+fn read_value<T: Copy>(pid: Pid, fd: FD, offset: Offset) -> Result<(T, Offset), KError> {
+    let mut value = MaybeUninit::<T>::uninit();
+    let buf =
+        unsafe { core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    let offset = read_at(pid, fd, buf, offset)?;
+    Ok((unsafe { value.assume_init() }, offset))
+}
+
+/// Should the page be in the checkpoint image?
+///
+/// Read-only pages come from the binary and device memory (mapped uncached,
+/// write-combining and/or identity mapped) can't be restored.
+fn is_saved(vaddr: VAddr, frame: Option<Frame>, rights: MapAction) -> bool {
+    let identity = frame.map_or(false, |frame| frame.base.as_u64() == vaddr.as_u64());
+    rights.is_user()
+        && rights.is_writable()
+        && rights != MapAction::ReadWriteUserNoCache
+        && rights != MapAction::ReadWriteUserWriteCombine
+        && !identity
+}
+
+/// Writes a checkpoint of `pid` (the process running on the current core) to
+/// the file at `pathname`, returns the size of the image.
+pub fn checkpoint(pid: Pid, pathname: u64) -> Result<(u64, u64), KError> {
+    if !nr::KernelNode::capabilities(pid)?.contains(Capabilities::CHECKPOINT) {
+        return Err(KError::MissingCapability);
+    }
+    let (module, _offset) = NrProcess::<Ring3Process>::module(pid)?;
+
+    let kcb = super::kcb::per_core();
+    let (eid, affinity) = {
+        let executor = kcb.arch.current_executor()?;
+        (executor.eid, executor.affinity)
+    };
+    // The restored process sees the checkpoint system call return `true`
+    let mut state: kpi::arch::SaveArea =
+        **kcb.arch.save_area.as_ref().ok_or(KError::ProcessNotSet)?;
+    state.set_syscall_ret1(0);
+    state.set_syscall_ret2(1);
+    state.set_syscall_error_code(kpi::SystemCallError::Ok);
+
+    let flags = FileFlags::O_WRONLY | FileFlags::O_CREAT | FileFlags::O_TRUNC;
+    let modes = FileModes::S_IRUSR | FileModes::S_IWUSR;
+    let (image, _) = MlnrKernelNode::map_fd(pid, pathname, flags.into(), modes.into())?;
+
+    let r = write_image(pid, image, module, eid as u64, affinity as u64, state);
+    MlnrKernelNode::unmap_fd(pid, image)?;
+    let size = r?;
+
+    debug!("Checkpoint of {} is {} bytes", pid, size);
+    Ok((size as u64, 0))
+}
+
+fn write_image(
+    pid: Pid,
+    image: FD,
+    module: &'static Module,
+    eid: u64,
+    affinity: u64,
+    state: kpi::arch::SaveArea,
+) -> Result<Offset, KError> {
+    let mut header = Header {
+        magic: MAGIC,
+        binary: module.name,
+        binary_len: module.name().len() as u64,
+        eid,
+        affinity,
+        state,
+        pages: 0,
+        fds: 0,
+    };
+    let mut offset = size_of::<Header>() as Offset;
+
+    let mut cursor = VAddr::zero();
+    while let Some((base, frame, rights)) = NrProcess::<Ring3Process>::next_mapping(pid, cursor)? {
+        let size = frame.map_or(BASE_PAGE_SIZE, |frame| frame.size());
+        cursor = base + size;
+        if !is_saved(base, frame, rights) {
+            continue;
+        }
+
+        for page_offset in (0..size).step_by(BASE_PAGE_SIZE) {
+            let page = Page {
+                base: (base + page_offset).as_u64(),
+                executable: (rights == MapAction::ReadWriteExecuteUser) as u64,
+                data: frame.is_some() as u64,
+            };
+            offset = write_at(pid, image, as_bytes(&page), offset)?;
+            if let Some(frame) = frame {
+                let contents = unsafe {
+                    core::slice::from_raw_parts(
+                        (frame.kernel_vaddr() + page_offset).as_ptr::<u8>(),
+                        BASE_PAGE_SIZE,
+                    )
+                };
+                offset = write_at(pid, image, contents, offset)?;
+            }
+            header.pages += 1;
+        }
+    }
+
+    for fd in 0..MAX_FILES_PER_PROCESS as FD {
+        if fd == image {
+            continue;
+        }
+        if let Ok((mnode, flags, fd_offset)) = MlnrKernelNode::fd_state(pid, fd) {
+            let entry = FdEntry {
+                fd,
+                mnode,
+                flags: flags.into(),
+                offset: fd_offset,
+            };
+            offset = write_at(pid, image, as_bytes(&entry), offset)?;
+            header.fds += 1;
+        }
+    }
+
+    write_at(pid, image, as_bytes(&header), 0)?;
+    Ok(offset)
+}
+
+/// Starts a new process from the checkpoint in the file at `pathname`
+/// (opened by `pid`), returns the pid of the new process (a child of `pid`).
+pub fn restore(pid: Pid, pathname: u64) -> Result<(u64, u64), KError> {
+    if !nr::KernelNode::capabilities(pid)?.contains(Capabilities::CHECKPOINT) {
+        return Err(KError::MissingCapability);
+    }
+
+    let (image, _) = MlnrKernelNode::map_fd(pid, pathname, FileFlags::O_RDONLY.into(), 0)?;
+    let r = restore_image(pid, image);
+    MlnrKernelNode::unmap_fd(pid, image)?;
+    let new_pid = r?;
+
+    Ok((new_pid as u64, 0))
+}
+
+fn restore_image(pid: Pid, image: FD) -> Result<Pid, KError> {
+    let (header, offset) = read_value::<Header>(pid, image, 0)?;
+    if header.magic != MAGIC || header.binary_len as usize > Module::MAX_NAME_LEN {
+        return Err(KError::InvalidCheckpoint);
+    }
+    let binary = core::str::from_utf8(&header.binary[..header.binary_len as usize])
+        .map_err(|_e| KError::InvalidCheckpoint)?;
+
+    let kcb = super::kcb::per_core();
+    let module = find_module(binary).ok_or(KError::InvalidCheckpoint)?;
+
+    // Restored processes aren't confined and don't have any capabilities
+    let new_pid = make_process::<Ring3Process>(module.name(), "/")?;
+    // They get the arguments of init (we don't save any)
+    let restored = nr::KernelNode::spawned(new_pid, Some(pid), kcb.cmdline.init_args)
+        .and_then(|_| allocate_dispatchers::<Ring3Process>(new_pid))
+        .and_then(|_| restore_contents(pid, image, new_pid, &header, offset))
+        .and_then(|_| {
+            NrProcess::<Ring3Process>::restore_executor(new_pid, header.eid as usize, header.state)
+        })
+        .and_then(|_| {
+            nr::KernelNode::allocate_core_to_process(
+                new_pid,
+                INVALID_EXECUTOR_START,
+                Some(header.affinity as usize),
+                None,
+            )
+        });
+    if let Err(e) = restored {
+        // It never ran, tear it down again (its parent doesn't know it)
+        #[cfg(target_os = "none")]
+        super::process::abandon(new_pid);
+        return Err(e);
+    }
+
+    Ok(new_pid)
+}
+
+/// Copies the pages and file descriptors of the checkpoint (starting at
+/// `offset` of `image`) into the new process `new_pid`.
+fn restore_contents(
+    pid: Pid,
+    image: FD,
+    new_pid: Pid,
+    header: &Header,
+    mut offset: Offset,
+) -> Result<(), KError> {
+    let kcb = super::kcb::per_core();
+    for _i in 0..header.pages {
+        let (page, next) = read_value::<Page>(pid, image, offset)?;
+        offset = next;
+        let base = VAddr::from(page.base);
+        if !base.is_base_page_aligned() || base.as_u64() >= kpi::KERNEL_BASE {
+            return Err(KError::InvalidCheckpoint);
+        }
+
+        // Read the contents before we allocate anything for them
+        let data = if page.data != 0 {
+            let data = MlnrKernelNode::read_buffer(pid, image, BASE_PAGE_SIZE as Len, offset)?;
+            if data.len() != BASE_PAGE_SIZE {
+                return Err(KError::InvalidCheckpoint);
+            }
+            offset += BASE_PAGE_SIZE as Offset;
+            Some(data)
+        } else {
+            None
+        };
+        let fill = |contents: &mut [u8]| match data.as_ref() {
+            Some(data) => contents.copy_from_slice(data),
+            None => contents.fill(0),
+        };
+
+        // Pages of the binary and executors are mapped by the new process
+        // already, everything else becomes anonymous memory again
+        if let Ok((paddr, _)) = NrProcess::<Ring3Process>::resolve(new_pid, base) {
+            let kernel_vaddr = paddr_to_kernel_vaddr(PAddr::from(paddr));
+            fill(unsafe {
+                core::slice::from_raw_parts_mut(kernel_vaddr.as_mut_ptr::<u8>(), BASE_PAGE_SIZE)
+            });
+            continue;
+        }
+
+        let rights = if page.executable != 0 {
+            MapAction::ReadWriteExecuteUser
+        } else {
+            MapAction::ReadWriteUser
+        };
+        let mut frames = Vec::try_with_capacity(1)?;
+        KernelAllocator::try_refill_tcache(20, 0)?;
+        let frame = kcb.mem_manager().allocate_base_page()?;
+        fill(unsafe {
+            core::slice::from_raw_parts_mut(frame.kernel_vaddr().as_mut_ptr::<u8>(), BASE_PAGE_SIZE)
+        });
+        frames
+            .try_push(frame)
+            .expect("Can't fail see `try_with_capacity`");
+        if let Err(e) = NrProcess::<Ring3Process>::map_anonymous(new_pid, base, frames, rights) {
+            // Not mapped, nobody else releases it
+            kcb.mem_manager().release_base_page(frame)?;
+            return Err(e);
+        }
+    }
+
+    for _i in 0..header.fds {
+        let (entry, next) = read_value::<FdEntry>(pid, image, offset)?;
+        offset = next;
+        if let Err(e) = MlnrKernelNode::restore_fd(
+            new_pid,
+            entry.fd,
+            entry.mnode,
+            FileFlags::from(entry.flags),
+            entry.offset,
+        ) {
+            warn!("Can't restore fd {} of {}: {:?}", entry.fd, new_pid, e);
+        }
+    }
+
+    Ok(())
+}
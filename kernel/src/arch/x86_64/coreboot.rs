@@ -12,9 +12,11 @@ use core::sync::atomic::AtomicBool;
 use apic::ApicDriver;
 use log::trace;
 use x86::apic::ApicId;
-use x86::current::paging::PAddr;
+use x86::current::paging::{PAddr, VAddr};
 
-use crate::memory::vspace::MapAction;
+use crate::error::KError;
+use crate::memory::reserved::{self, Reserved};
+use crate::memory::vspace::{AddressSpace, MapAction};
 use crate::round_up;
 use crate::stack::Stack;
 
@@ -68,6 +70,16 @@ fn get_boostrap_code_size() -> usize {
     boostrap_code_size.into()
 }
 
+/// Reserves the memory at REAL_MODE_BASE that holds the bootstrap code (so
+/// only [`copy_bootstrap_code`] maps it).
+pub fn reserve_bootstrap_region() -> Result<(), KError> {
+    reserved::reserve(
+        PAddr::from(REAL_MODE_BASE as u64),
+        get_boostrap_code_size(),
+        Reserved::RealModeBootstrap,
+    )
+}
+
 /// Puts the bootstrap code at a well defined segement that an
 /// app core (booting in 16-bit mode can read from) (for us this is
 /// REAL_MODE_BASE).
@@ -82,19 +94,70 @@ unsafe fn copy_bootstrap_code() {
     let ap_bootstrap_code: &'static [u8] = get_orignal_bootstrap_code();
     let real_mode_destination: &'static mut [u8] = get_boostrap_code_region();
 
-    let kcb = kcb::get_kcb();
-    kcb.arch
+    let base = PAddr::from(REAL_MODE_BASE as u64);
+    let size = round_up!(boot_code_size, BASE_PAGE_SIZE);
+    reserved::check(base, size, Some(Reserved::RealModeBootstrap))
+        .expect("Bootstrap code overlaps reserved memory");
+    let kcb = kcb::per_core();
+    let mapped = kcb
+        .arch
         .init_vspace()
-        .map_identity(
-            PAddr::from(REAL_MODE_BASE as u64),
-            round_up!(boot_code_size, BASE_PAGE_SIZE),
-            MapAction::ReadWriteExecuteKernel,
-        )
-        .expect("Can't map bootstrap code");
+        .map_identity(base, size, MapAction::ReadWriteExecuteKernel);
+    match mapped {
+        // `lock_bootstrap_code` took the rights away after the last core booted
+        Err(KError::ConflictingRights { .. }) => {
+            set_bootstrap_rights(MapAction::ReadWriteExecuteKernel);
+        }
+        r => r.expect("Can't map bootstrap code"),
+    }
 
     real_mode_destination.copy_from_slice(ap_bootstrap_code);
 }
 
+/// Makes the bootstrap code region read-only and non-executable (once all
+/// cores are up). [`copy_bootstrap_code`] makes it writable again if we boot
+/// another core later.
+///
+/// Only flushes the TLB of the current core. Returns the number of pages it
+/// changed.
+pub fn lock_bootstrap_code() -> usize {
+    set_bootstrap_rights(MapAction::ReadKernel)
+}
+
+/// Changes the rights of the identity mapped bootstrap code region.
+///
+/// Mappings that are larger than a base page stay as they are (they map more
+/// than the bootstrap code).
+fn set_bootstrap_rights(rights: MapAction) -> usize {
+    let (start, len) = bootstrap_region();
+
+    let kcb = kcb::per_core();
+    let mut vspace = kcb.arch.init_vspace();
+    let mut pages = 0;
+    for vaddr in (start.as_usize()..start.as_usize() + len).step_by(BASE_PAGE_SIZE) {
+        let vaddr = VAddr::from(vaddr);
+        match vspace.next_page(vaddr) {
+            Some((base, _paddr, size, _rights)) if base == vaddr && size == BASE_PAGE_SIZE => {
+                vspace
+                    .adjust(vaddr, rights)
+                    .expect("Can't change rights of bootstrap code");
+                unsafe { x86::tlb::flush(vaddr.as_usize()) };
+                pages += 1;
+            }
+            _ => {}
+        }
+    }
+    pages
+}
+
+/// Returns the region of the bootstrap code (where `start_ap.S` is copied to).
+pub fn bootstrap_region() -> (VAddr, usize) {
+    (
+        VAddr::from(REAL_MODE_BASE),
+        round_up!(get_boostrap_code_size(), BASE_PAGE_SIZE),
+    )
+}
+
 /// Initializes the information passed to the APP core by writing
 /// overwriting a bunch of declared symbols inside of `start_ap.S`
 /// to pass arguments, set the correct stack and page-table
@@ -245,7 +308,7 @@ unsafe fn get_boostrap_code_region() -> &'static mut [u8] {
 /// # Safety
 /// Can easily reset the wrong core (bad for memory safety).
 unsafe fn wakeup_core(core_id: ApicId) {
-    let kcb = kcb::get_kcb();
+    let kcb = kcb::per_core();
 
     // x86 core boot protocol, without sleeping:
     kcb.arch.apic().ipi_init(core_id);
@@ -274,7 +337,7 @@ pub unsafe fn initialize<A>(
     copy_bootstrap_code();
 
     // Initialize bootstrap assembly with correct parameters
-    let kcb = super::kcb::get_kcb();
+    let kcb = super::kcb::per_core();
     setup_boostrap_code(
         init_function as u64,
         args,
@@ -0,0 +1,376 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! ELF core dumps of user processes that crash.
+//!
+//! A process that called `Process::set_core_dump(true)` gets a core dump
+//! written to `/core.<pid>` (in its file-system root) when it takes a fatal
+//! page-fault or general protection fault. The dump is what `gdb <binary>
+//! core.<pid>` expects: an ELF header, a `PT_NOTE` segment with the
+//! registers of the faulting core (`NT_PRSTATUS` and `NT_PRFPREG`) and a
+//! `PT_LOAD` segment for every range of the user address-space.
+//!
+//! Only the faulting core is in the dump. Device memory is listed without
+//! contents: `VSpace::map_device` maps it at its physical address (with any
+//! caching attribute), we leave out every range mapped like that and every
+//! range that isn't cached write-back.
+
+use alloc::format;
+use alloc::sync::Arc;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use kpi::io::{FileFlags, FileModes};
+use log::{error, info};
+
+use crate::cnrfs::MlnrKernelNode;
+use crate::error::KError;
+use crate::fs::{Offset, FD};
+use crate::memory::vspace::{MapAction, MappedRange};
+use crate::memory::{paddr_to_kernel_vaddr, VAddr, LARGE_PAGE_SIZE};
+use crate::nr;
+use crate::nrproc::NrProcess;
+use crate::process::{Pid, MAX_PROCESSES};
+
+use super::process::Ring3Process;
+
+/// Processes that want a core dump when they crash.
+static ENABLED: [AtomicBool; MAX_PROCESSES] = {
+    const OFF: AtomicBool = AtomicBool::new(false);
+    [OFF; MAX_PROCESSES]
+};
+
+/// Turns core dumps of `pid` on or off.
+pub fn set(pid: Pid, enable: bool) {
+    if let Some(enabled) = ENABLED.get(pid) {
+        enabled.store(enable, Ordering::Relaxed);
+    }
+}
+
+/// Does `pid` want a core dump?
+pub fn enabled(pid: Pid) -> bool {
+    ENABLED
+        .get(pid)
+        .map_or(false, |enabled| enabled.load(Ordering::Relaxed))
+}
+
+/// Signal we report for a crash (SIGSEGV).
+const SIGSEGV: i32 = 11;
+
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+const NT_PRFPREG: u32 = 2;
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct FileHeader {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+/// Header of a note, followed by the (padded) name and descriptor.
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct NoteHeader {
+    namesz: u32,
+    descsz: u32,
+    kind: u32,
+    /// "CORE" padded to 8 bytes.
+    name: [u8; 8],
+}
+
+/// `struct elf_prstatus` of Linux on x86-64.
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct PrStatus {
+    signo: i32,
+    code: i32,
+    errno: i32,
+    cursig: u16,
+    _pad: u16,
+    sigpend: u64,
+    sighold: u64,
+    pid: i32,
+    ppid: i32,
+    pgrp: i32,
+    sid: i32,
+    /// User, system and children's times (as `timeval`s).
+    times: [u64; 8],
+    /// `struct user_regs_struct`.
+    regs: [u64; 27],
+    fpvalid: u32,
+    _pad2: u32,
+}
+
+fn as_bytes<T: Copy>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+fn note<T: Copy>(kind: u32, desc: &T) -> (NoteHeader, &[u8]) {
+    let header = NoteHeader {
+        namesz: 5,
+        descsz: size_of::<T>() as u32,
+        kind,
+        name: *b"CORE\0\0\0\0",
+    };
+    (header, as_bytes(desc))
+}
+
+/// Is the range memory we can (and want to) read?
+///
+/// Reading device registers can have side-effects, so we skip identity
+/// mapped ranges (`MapDevice`). Other memory is only mapped at its physical
+/// address by chance, it's listed without contents then too.
+fn is_dumped(range: &MappedRange) -> bool {
+    range.rights.is_user()
+        && range.rights.is_write_back()
+        && range.vaddr.as_u64() != range.paddr.as_u64()
+}
+
+fn segment_flags(rights: MapAction) -> u32 {
+    let mut flags = PF_R;
+    if rights.is_writable() {
+        flags |= PF_W;
+    }
+    if rights.is_executable() {
+        flags |= PF_X;
+    }
+    flags
+}
+
+fn write_at(pid: Pid, fd: FD, data: &[u8], offset: Offset) -> Result<Offset, KError> {
+    let buffer: Arc<[u8]> = Arc::from(data);
+    let len = MlnrKernelNode::write_buffer(pid, fd, buffer, offset)?;
+    if len as usize != data.len() {
+        return Err(KError::NoSpace);
+    }
+    Ok(offset + len as Offset)
+}
+
+/// Writes a core dump of `pid` (which crashed on the current core with the
+/// registers in `state`) if it asked for one.
+///
+/// `cs` and `ss` are the segments the process ran with. Failing to write the
+/// dump is only logged, we're about to abort anyways.
+pub fn dump(pid: Pid, state: &kpi::arch::SaveArea, cs: u64, ss: u64) {
+    if !enabled(pid) {
+        return;
+    }
+    // Don't dump again if we crash while dumping
+    set(pid, false);
+
+    let path = format!("/core.{}", pid);
+    let flags = FileFlags::O_WRONLY | FileFlags::O_CREAT | FileFlags::O_TRUNC;
+    let modes = FileModes::S_IRUSR | FileModes::S_IWUSR;
+    let r =
+        MlnrKernelNode::open(pid, path.clone(), flags.into(), modes.into()).and_then(|(fd, _)| {
+            let r = write_core(pid, fd, state, cs, ss);
+            MlnrKernelNode::unmap_fd(pid, fd)?;
+            r
+        });
+
+    match r {
+        Ok(size) => info!(
+            "Wrote core dump of process {} to {} ({} bytes)",
+            pid, path, size
+        ),
+        Err(e) => error!("Can't write core dump of process {}: {:?}", pid, e),
+    }
+}
+
+fn write_core(
+    pid: Pid,
+    fd: FD,
+    state: &kpi::arch::SaveArea,
+    cs: u64,
+    ss: u64,
+) -> Result<Offset, KError> {
+    let ppid = nr::KernelNode::process(pid)?.parent.unwrap_or(0);
+    let prstatus = PrStatus {
+        signo: SIGSEGV,
+        code: 0,
+        errno: 0,
+        cursig: SIGSEGV as u16,
+        _pad: 0,
+        sigpend: 0,
+        sighold: 0,
+        pid: pid as i32,
+        ppid: ppid as i32,
+        pgrp: pid as i32,
+        sid: pid as i32,
+        times: [0; 8],
+        regs: [
+            state.r15,
+            state.r14,
+            state.r13,
+            state.r12,
+            state.rbp,
+            state.rbx,
+            state.r11,
+            state.r10,
+            state.r9,
+            state.r8,
+            state.rax,
+            state.rcx,
+            state.rdx,
+            state.rsi,
+            state.rdi,
+            u64::MAX, // orig_rax: not in a system call
+            state.rip,
+            cs,
+            state.rflags,
+            state.rsp,
+            ss,
+            state.fs, // fs_base
+            state.gs, // gs_base
+            0,
+            0,
+            0,
+            0,
+        ],
+        fpvalid: 1,
+        _pad2: 0,
+    };
+    let notes = [
+        note(NT_PRSTATUS, &prstatus),
+        note(NT_PRFPREG, &state.fxsave),
+    ];
+    let notes_size: usize = notes
+        .iter()
+        .map(|(_header, desc)| size_of::<NoteHeader>() + desc.len())
+        .sum();
+
+    // The segments are the ranges of the user address-space
+    let mut segments = 0;
+    let mut cursor = VAddr::zero();
+    while let Some(range) = NrProcess::<Ring3Process>::next_range(pid, cursor)? {
+        cursor = range.vaddr + range.size;
+        segments += 1;
+    }
+    let phnum = segments + 1;
+    if phnum > u16::MAX as usize {
+        return Err(KError::NotSupported);
+    }
+
+    let header = FileHeader {
+        ident: [0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        kind: ET_CORE,
+        machine: EM_X86_64,
+        version: 1,
+        entry: 0,
+        phoff: size_of::<FileHeader>() as u64,
+        shoff: 0,
+        flags: 0,
+        ehsize: size_of::<FileHeader>() as u16,
+        phentsize: size_of::<ProgramHeader>() as u16,
+        phnum: phnum as u16,
+        shentsize: 0,
+        shnum: 0,
+        shstrndx: 0,
+    };
+    let mut offset = write_at(pid, fd, as_bytes(&header), 0)?;
+
+    // Notes come right after the program headers, the contents of the
+    // segments after the notes
+    let notes_offset = offset as usize + phnum * size_of::<ProgramHeader>();
+    let note_segment = ProgramHeader {
+        kind: PT_NOTE,
+        flags: 0,
+        offset: notes_offset as u64,
+        vaddr: 0,
+        paddr: 0,
+        filesz: notes_size as u64,
+        memsz: 0,
+        align: 4,
+    };
+    offset = write_at(pid, fd, as_bytes(&note_segment), offset)?;
+
+    // A process can't change its address-space while we dump (it's stopped
+    // on this core) but other cores of it could, we stop at `segments` to
+    // match the header
+    let mut data_offset = notes_offset + notes_size;
+    let mut cursor = VAddr::zero();
+    let mut ranges = 0;
+    while let Some(range) = NrProcess::<Ring3Process>::next_range(pid, cursor)? {
+        if ranges == segments {
+            break;
+        }
+        cursor = range.vaddr + range.size;
+        ranges += 1;
+
+        let filesz = if is_dumped(&range) { range.size } else { 0 };
+        let segment = ProgramHeader {
+            kind: PT_LOAD,
+            flags: segment_flags(range.rights),
+            offset: data_offset as u64,
+            vaddr: range.vaddr.as_u64(),
+            paddr: 0,
+            filesz: filesz as u64,
+            memsz: range.size as u64,
+            align: range.page_size as u64,
+        };
+        offset = write_at(pid, fd, as_bytes(&segment), offset)?;
+        data_offset += filesz;
+    }
+
+    for (header, desc) in notes.iter() {
+        offset = write_at(pid, fd, as_bytes(header), offset)?;
+        offset = write_at(pid, fd, desc, offset)?;
+    }
+
+    let mut cursor = VAddr::zero();
+    for _i in 0..ranges {
+        let range = match NrProcess::<Ring3Process>::next_range(pid, cursor)? {
+            Some(range) => range,
+            None => break,
+        };
+        cursor = range.vaddr + range.size;
+        if !is_dumped(&range) {
+            continue;
+        }
+
+        let contents = unsafe {
+            core::slice::from_raw_parts(
+                paddr_to_kernel_vaddr(range.paddr).as_ptr::<u8>(),
+                range.size,
+            )
+        };
+        // Every write copies the buffer, so don't write GiBs at once
+        for chunk in contents.chunks(LARGE_PAGE_SIZE) {
+            offset = write_at(pid, fd, chunk, offset)?;
+        }
+    }
+
+    Ok(offset)
+}
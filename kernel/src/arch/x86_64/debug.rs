@@ -67,6 +67,9 @@ pub unsafe fn putb(b: u8) {
 /// Currently we only support the debug exit method from qemu, which conveniently
 /// allows us to supply an exit code for testing purposes.
 pub fn shutdown(val: ExitReason) -> ! {
+    // The tail of the log (e.g., a panic) goes to the disk before QEMU exits
+    crate::disklog::flush();
+
     unsafe {
         // For QEMU with debug-exit,iobase=0xf4,iosize=0x04
         // qemu will call: exit((val << 1) | 1);
@@ -127,7 +130,7 @@ pub fn cause_double_fault() {
 /// as part of the test
 #[cfg(feature = "test-double-fault")]
 pub fn assert_being_on_fault_stack() {
-    let (low, high) = super::kcb::get_kcb().arch.fault_stack_range();
+    let (low, high) = super::kcb::per_core().arch.fault_stack_range();
     let rsp = x86::current::registers::rsp();
     debug_assert!(
         rsp >= low && rsp <= high,
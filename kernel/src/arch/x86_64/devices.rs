@@ -0,0 +1,362 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Finds the devices of the machine (see `crate::devices`) and the drivers
+//! the kernel has for them.
+//!
+//! [`discover`] scans the PCI configuration space (with the BARs of every
+//! function), takes the I/O APICs from the ACPI tables and probes the legacy
+//! devices (serial ports, PS/2 controller). [`bind`] attaches the drivers
+//! of [`DRIVERS`] once the kernel is up (e.g., `ahci` for SATA disks).
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use fallible_collections::FallibleVec;
+use kpi::system::{DeviceDriver, DeviceInfo, DeviceLocation, DeviceResource};
+use log::{info, warn};
+
+use crate::devices::{self, Driver};
+use crate::error::KError;
+
+/// PCI devices we know by name (vendor, device, name).
+const PCI_NAMES: &[(u16, u16, &str)] = &[
+    (0x15ad, 0x07b0, "vmxnet3"),
+    (0x15ad, 0x0820, "pvrdma"),
+    (0x8086, 0x100e, "e1000"),
+    (0x8086, 0x10d3, "e1000e"),
+    (0x1af4, 0x1000, "virtio-net"),
+    (0x1af4, 0x1001, "virtio-blk"),
+    (0x8086, 0x2922, "ich9-ahci"),
+];
+
+/// The serial ports (see `debug`).
+const SERIAL_PORTS: &[(u16, &str)] = &[(0x3f8, "com1"), (0x2f8, "com2")];
+
+/// The console on the serial ports (set up in `debug::init`).
+struct Serial;
+
+impl Driver for Serial {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn matches(&self, device: &DeviceInfo) -> bool {
+        SERIAL_PORTS
+            .iter()
+            .any(|(_port, name)| device.name == *name)
+    }
+
+    fn attach(&self, _device: &DeviceInfo) -> Result<(), KError> {
+        Ok(())
+    }
+}
+
+/// The keyboard (see `ps2`).
+struct Ps2Keyboard;
+
+impl Driver for Ps2Keyboard {
+    fn name(&self) -> &'static str {
+        "ps2"
+    }
+
+    fn matches(&self, device: &DeviceInfo) -> bool {
+        device.name == "i8042"
+    }
+
+    fn attach(&self, _device: &DeviceInfo) -> Result<(), KError> {
+        super::ps2::init()
+    }
+}
+
+/// Interrupt routing (see `ioapic`).
+struct IoApic;
+
+impl Driver for IoApic {
+    fn name(&self) -> &'static str {
+        "ioapic"
+    }
+
+    fn matches(&self, device: &DeviceInfo) -> bool {
+        device.name == "ioapic"
+    }
+
+    fn attach(&self, _device: &DeviceInfo) -> Result<(), KError> {
+        Ok(())
+    }
+}
+
+/// The drivers of the kernel.
+static DRIVERS: [&dyn Driver; 4] = [&Serial, &Ps2Keyboard, &IoApic, &super::ahci::Ahci];
+
+/// A device without resources (filled in by the caller).
+fn device(name: String, location: DeviceLocation) -> DeviceInfo {
+    DeviceInfo {
+        id: 0,
+        name,
+        location,
+        vendor_id: 0,
+        device_id: 0,
+        class: 0,
+        subclass: 0,
+        resources: Vec::new(),
+        driver: DeviceDriver::None,
+    }
+}
+
+/// Finds the devices and records them, call once on the boot core.
+pub fn discover() {
+    if let Err(e) = discover_all() {
+        warn!("Can't record all devices: {}", e);
+    }
+}
+
+fn discover_all() -> Result<(), KError> {
+    let mut found = 0;
+    for info in pci_devices()? {
+        devices::add(info)?;
+        found += 1;
+    }
+
+    for io_apic in atopology::MACHINE_TOPOLOGY.io_apics() {
+        let mut info = device(
+            String::from("ioapic"),
+            DeviceLocation::Acpi {
+                table: String::from("APIC"),
+            },
+        );
+        info.resources.try_push(DeviceResource::Memory {
+            base: io_apic.address as u64,
+            size: 0x1000,
+        })?;
+        devices::add(info)?;
+        found += 1;
+    }
+
+    for (port, name) in SERIAL_PORTS {
+        let mut info = device(String::from(*name), DeviceLocation::Platform);
+        info.resources.try_push(DeviceResource::IoPorts {
+            base: *port,
+            count: 8,
+        })?;
+        devices::add(info)?;
+        found += 1;
+    }
+
+    if super::ps2::present() {
+        let mut info = device(String::from("i8042"), DeviceLocation::Platform);
+        info.resources.try_push(DeviceResource::IoPorts {
+            base: 0x60,
+            count: 1,
+        })?;
+        info.resources.try_push(DeviceResource::IoPorts {
+            base: 0x64,
+            count: 1,
+        })?;
+        devices::add(info)?;
+        found += 1;
+    }
+
+    info!("Found {} devices", found);
+    Ok(())
+}
+
+/// Attaches the drivers of the kernel to the devices, call once the kernel
+/// can run them (timers work).
+pub fn bind() {
+    devices::bind(&DRIVERS);
+}
+
+/// Reads a 32-bit register of the PCI configuration space.
+fn pci_read(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    unsafe {
+        x86::io::outl(PCI_CONF_ADDR, pci_address(bus, device, function, offset));
+        x86::io::inl(PCI_CONF_DATA)
+    }
+}
+
+/// Writes a 32-bit register of the PCI configuration space.
+fn pci_write(bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+    unsafe {
+        x86::io::outl(PCI_CONF_ADDR, pci_address(bus, device, function, offset));
+        x86::io::outl(PCI_CONF_DATA, value);
+    }
+}
+
+/// Lets the PCI function `device` access memory (DMA), for drivers.
+pub fn enable_bus_master(device: &DeviceInfo) {
+    const COMMAND: u8 = 0x4;
+    const BUS_MASTER: u32 = 0x4;
+
+    if let DeviceLocation::Pci {
+        bus,
+        device,
+        function,
+    } = device.location
+    {
+        let command = pci_read(bus, device, function, COMMAND);
+        pci_write(bus, device, function, COMMAND, command | BUS_MASTER);
+    }
+}
+
+/// Stops all PCI functions from accessing memory and raising interrupts
+/// (before we jump into a new kernel, see `kexec`).
+pub fn stop_all() {
+    const COMMAND: u8 = 0x4;
+    const BUS_MASTER: u32 = 0x4;
+    const INTX_DISABLE: u32 = 1 << 10;
+    const CLASS_BRIDGE: u8 = 0x6;
+
+    let devices = match pci_devices() {
+        Ok(devices) => devices,
+        Err(e) => {
+            warn!("Can't stop PCI devices: {}", e);
+            return;
+        }
+    };
+    // Bridges stay on, the new kernel only turns on the devices behind them
+    for info in devices.iter().filter(|info| info.class != CLASS_BRIDGE) {
+        if let DeviceLocation::Pci {
+            bus,
+            device,
+            function,
+        } = info.location
+        {
+            let command = pci_read(bus, device, function, COMMAND);
+            let command = (command & !BUS_MASTER) | INTX_DISABLE;
+            pci_write(bus, device, function, COMMAND, command);
+        }
+    }
+}
+
+const PCI_CONF_ADDR: u16 = 0xcf8;
+const PCI_CONF_DATA: u16 = 0xcfc;
+
+fn pci_address(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    (1 << 31)
+        | ((bus as u32) << 16)
+        | ((device as u32) << 11)
+        | ((function as u32) << 8)
+        | (offset as u32 & 0xfc)
+}
+
+/// Finds all PCI functions (by brute-force scanning all buses).
+fn pci_devices() -> Result<Vec<DeviceInfo>, KError> {
+    let mut devices = Vec::new();
+    for bus in 0..=255u8 {
+        for device in 0..32u8 {
+            for function in 0..8u8 {
+                let id = pci_read(bus, device, function, 0x0);
+                if id & 0xffff == 0xffff {
+                    if function == 0 {
+                        break;
+                    }
+                    continue;
+                }
+
+                let (vendor_id, device_id) = (id as u16, (id >> 16) as u16);
+                let name = PCI_NAMES
+                    .iter()
+                    .find(|(v, d, _name)| (*v, *d) == (vendor_id, device_id))
+                    .map_or_else(
+                        || format!("{:04x}:{:04x}", vendor_id, device_id),
+                        |(_v, _d, name)| String::from(*name),
+                    );
+                let class = pci_read(bus, device, function, 0x8);
+                let mut info = self::device(
+                    name,
+                    DeviceLocation::Pci {
+                        bus,
+                        device,
+                        function,
+                    },
+                );
+                info.vendor_id = vendor_id;
+                info.device_id = device_id;
+                info.class = (class >> 24) as u8;
+                info.subclass = (class >> 16) as u8;
+                info.resources = bars(bus, device, function)?;
+                devices.try_push(info)?;
+
+                // Only multi-function devices have more than function 0
+                let header_type = (pci_read(bus, device, 0, 0xc) >> 16) as u8;
+                if function == 0 && header_type & 0x80 == 0 {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(devices)
+}
+
+/// Reads the base address registers of a PCI function (and their sizes).
+fn bars(bus: u8, device: u8, function: u8) -> Result<Vec<DeviceResource>, KError> {
+    const COMMAND: u8 = 0x4;
+    // Decodes I/O ports and memory
+    const DECODE: u32 = 0x3;
+
+    let header_type = (pci_read(bus, device, function, 0xc) >> 16) as u8 & 0x7f;
+    let count = match header_type {
+        0 => 6,
+        // PCI-to-PCI bridge
+        1 => 2,
+        _ => 0,
+    };
+
+    // The size is what sticks when we write all ones, the device shouldn't
+    // decode these addresses meanwhile
+    let command = pci_read(bus, device, function, COMMAND);
+    pci_write(bus, device, function, COMMAND, command & !DECODE);
+    let resources = size_bars(bus, device, function, count);
+    pci_write(bus, device, function, COMMAND, command);
+    resources
+}
+
+fn size_bars(bus: u8, device: u8, function: u8, count: u8) -> Result<Vec<DeviceResource>, KError> {
+    let probe = |idx: u8| -> (u32, u32) {
+        let offset = 0x10 + 4 * idx;
+        let value = pci_read(bus, device, function, offset);
+        pci_write(bus, device, function, offset, 0xffff_ffff);
+        let mask = pci_read(bus, device, function, offset);
+        pci_write(bus, device, function, offset, value);
+        (value, mask)
+    };
+
+    let mut resources = Vec::new();
+    let mut idx = 0;
+    while idx < count {
+        let (value, mask) = probe(idx);
+        idx += 1;
+        if mask == 0 {
+            // Not implemented
+            continue;
+        }
+
+        if value & 0x1 == 0x1 {
+            let size = (!((mask & 0xffff_fffc) | 0xffff_0000)).wrapping_add(1);
+            resources.try_push(DeviceResource::IoPorts {
+                base: (value & 0xfffc) as u16,
+                count: size as u16,
+            })?;
+            continue;
+        }
+
+        let mut base = (value & 0xffff_fff0) as u64;
+        let mut mask = (mask & 0xffff_fff0) as u64 | 0xffff_ffff_0000_0000;
+        // A 64-bit BAR takes two registers
+        if (value >> 1) & 0x3 == 0x2 && idx < count {
+            let (high, high_mask) = probe(idx);
+            idx += 1;
+            base |= (high as u64) << 32;
+            mask = (mask & 0xffff_ffff) | (high_mask as u64) << 32;
+        }
+        if base != 0 {
+            resources.try_push(DeviceResource::Memory {
+                base,
+                size: (!mask).wrapping_add(1),
+            })?;
+        }
+    }
+    Ok(resources)
+}
@@ -0,0 +1,62 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Jumping back out of a panicking subsystem (see `crate::domain`).
+//!
+//! [`call`] runs a function and remembers its stack pointer, [`resume`]
+//! returns from the [`call`] by restoring it (and the registers `call`
+//! saved), like `setjmp`/`longjmp` without the "returns twice".
+//!
+//! NMIs don't touch `%gs` (see `profile.S`), we count the cores that handle
+//! one here instead of in the KCB ([`in_nmi`]).
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(target_os = "none")]
+global_asm!(include_str!("domain.S"), options(att_syntax));
+
+extern "C" {
+    fn nrk_domain_call(context: *mut u64, f: extern "C" fn(*mut u8), arg: *mut u8) -> u64;
+    fn nrk_domain_resume(context: *const u64) -> !;
+}
+
+/// Where [`resume`] returns to.
+#[derive(Debug, Default)]
+pub struct Context {
+    rsp: u64,
+}
+
+/// Runs `f(arg)`, returns `false` if [`resume`] jumped out of it.
+///
+/// # Safety
+/// `context` has to stay where it is until `f` returns or we resumed.
+pub unsafe fn call(context: &mut Context, f: extern "C" fn(*mut u8), arg: *mut u8) -> bool {
+    nrk_domain_call(&mut context.rsp, f, arg) == 0
+}
+
+/// Returns from the [`call`] that stored `context`.
+///
+/// # Safety
+/// The [`call`] has to be still running on the current core (further up the
+/// stack). Nothing in between is dropped.
+pub unsafe fn resume(context: &Context) -> ! {
+    nrk_domain_resume(&context.rsp)
+}
+
+/// Cores that are in the NMI handler right now.
+static NMIS: AtomicUsize = AtomicUsize::new(0);
+
+/// Called when the NMI handler starts handling an NMI.
+pub fn enter_nmi() {
+    NMIS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Called before the NMI handler returns.
+pub fn leave_nmi() {
+    NMIS.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Is any core in the NMI handler? A panic there can't be recovered.
+pub fn in_nmi() -> bool {
+    NMIS.load(Ordering::Relaxed) != 0
+}
@@ -0,0 +1,457 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A driver for Intel 8254x/8257x NICs (e1000, e1000e) for smoltcp.
+//!
+//! QEMU emulates these by default (`-net nic,model=e1000` or `e1000e`) and
+//! many lab machines have one on board. We use the legacy descriptors that
+//! all of them understand: one receive and one transmit ring of
+//! [`RING_SIZE`] buffers of 2 KiB each. Like the vmxnet3 driver, the NIC
+//! runs without interrupts and is polled by `network`.
+
+use core::ptr;
+use core::time::Duration;
+
+use arrayvec::ArrayVec;
+use kpi::system::DeviceInfo;
+use log::{info, warn};
+use smoltcp::phy::{self, Device, DeviceCapabilities};
+use smoltcp::time::Instant;
+use smoltcp::wire::EthernetAddress;
+use vmxnet3::smoltcp::PhyStats;
+
+use crate::error::KError;
+use crate::memory::{Frame, KernelAllocator, PhysicalPageProvider, BASE_PAGE_SIZE};
+
+/// Device ids (of vendor Intel) we drive: 82540EM (QEMU `e1000`), 82545EM,
+/// 82574L (QEMU `e1000e`), 82579LM and I217-LM.
+const DEVICE_IDS: &[u16] = &[0x100e, 0x100f, 0x10d3, 0x1502, 0x153a];
+
+/// Registers.
+const CTRL: usize = 0x0000;
+const STATUS: usize = 0x0008;
+const ICR: usize = 0x00c0;
+const IMC: usize = 0x00d8;
+const RCTL: usize = 0x0100;
+const TCTL: usize = 0x0400;
+const TIPG: usize = 0x0410;
+const RDBAL: usize = 0x2800;
+const RDBAH: usize = 0x2804;
+const RDLEN: usize = 0x2808;
+const RDH: usize = 0x2810;
+const RDT: usize = 0x2818;
+const TDBAL: usize = 0x3800;
+const TDBAH: usize = 0x3804;
+const TDLEN: usize = 0x3808;
+const TDH: usize = 0x3810;
+const TDT: usize = 0x3818;
+/// Multicast table (128 registers).
+const MTA: usize = 0x5200;
+/// Receive address 0 (low and high).
+const RAL: usize = 0x5400;
+const RAH: usize = 0x5404;
+
+/// Bits of `CTRL`.
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+
+/// Link is up (in `STATUS`).
+const STATUS_LU: u32 = 1 << 1;
+
+/// Bits of `RCTL`: enable, accept broadcasts, strip the CRC (2 KiB buffers).
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_BAM: u32 = 1 << 15;
+const RCTL_SECRC: u32 = 1 << 26;
+
+/// Bits of `TCTL`: enable, pad short packets, collision threshold and
+/// distance (the recommended values).
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x10 << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+
+/// Recommended inter-packet gap for copper.
+const TIPG_COPPER: u32 = 0x0060_200a;
+
+/// Valid bit of `RAH`.
+const RAH_AV: u32 = 1 << 31;
+
+/// Descriptor done, end of packet (in the status of descriptors).
+const DESC_DD: u8 = 1 << 0;
+const DESC_EOP: u8 = 1 << 1;
+
+/// Commands of transmit descriptors: end of packet, insert the CRC, report
+/// the status.
+const TX_EOP: u8 = 1 << 0;
+const TX_IFCS: u8 = 1 << 1;
+const TX_RS: u8 = 1 << 3;
+
+/// Number of descriptors (and buffers) of a ring.
+const RING_SIZE: usize = 32;
+
+/// Size of a buffer.
+const BUFFER_SIZE: usize = 2048;
+
+/// Pages with the buffers of both rings.
+const BUFFER_PAGES: usize = 2 * RING_SIZE * BUFFER_SIZE / BASE_PAGE_SIZE;
+
+/// Where the rings are in the descriptor page.
+const RX_RING: usize = 0x0;
+const TX_RING: usize = 0x800;
+
+/// How long we wait for the reset.
+const RESET_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// A legacy receive descriptor (the NIC uses the fields we don't).
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+#[allow(dead_code)]
+struct RxDescriptor {
+    addr: u64,
+    length: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+/// A legacy transmit descriptor.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+#[allow(dead_code)]
+struct TxDescriptor {
+    addr: u64,
+    length: u16,
+    cso: u8,
+    cmd: u8,
+    status: u8,
+    css: u8,
+    special: u16,
+}
+
+/// Can we drive `device`?
+pub fn matches(device: &DeviceInfo) -> bool {
+    device.vendor_id == 0x8086 && DEVICE_IDS.contains(&device.device_id)
+}
+
+/// The registers of the NIC.
+#[derive(Clone, Copy)]
+struct Registers(u64);
+
+impl Registers {
+    fn read(&self, register: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.0 + register as u64) as *const u32) }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.0 + register as u64) as *mut u32, value) }
+    }
+}
+
+/// A ring of descriptors and its buffers.
+struct Ring {
+    /// Kernel address of the descriptors.
+    descriptors: u64,
+    /// Kernel addresses of the buffers.
+    buffers: [u64; RING_SIZE],
+    /// The next descriptor we look at.
+    next: usize,
+}
+
+impl Ring {
+    /// Descriptor `idx` (`D` is the descriptor type of the ring).
+    fn get<D: Copy>(&self, idx: usize) -> D {
+        unsafe { ptr::read_volatile((self.descriptors as *const D).add(idx)) }
+    }
+
+    fn set<D: Copy>(&self, idx: usize, descriptor: D) {
+        unsafe { ptr::write_volatile((self.descriptors as *mut D).add(idx), descriptor) }
+    }
+
+    fn buffer(&mut self, idx: usize, len: usize) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.buffers[idx] as *mut u8, len) }
+    }
+}
+
+/// An e1000 NIC.
+pub struct E1000 {
+    regs: Registers,
+    rx: Ring,
+    tx: Ring,
+    /// The page with the rings and the pages with the buffers.
+    frames: ArrayVec<Frame, { BUFFER_PAGES + 1 }>,
+    stats: PhyStats,
+}
+
+impl E1000 {
+    /// Resets the NIC whose registers are mapped at `regs` and starts
+    /// receiving packets for `mac`.
+    pub fn new(regs: u64, mac: EthernetAddress) -> Result<E1000, KError> {
+        let mut nic = E1000 {
+            regs: Registers(regs),
+            rx: Ring {
+                descriptors: 0,
+                buffers: [0; RING_SIZE],
+                next: 0,
+            },
+            tx: Ring {
+                descriptors: 0,
+                buffers: [0; RING_SIZE],
+                next: 0,
+            },
+            frames: ArrayVec::new(),
+            stats: Default::default(),
+        };
+        nic.reset()?;
+
+        // Gives the pages back if we fail
+        while !nic.frames.is_full() {
+            KernelAllocator::try_refill_tcache(1, 0)?;
+            let kcb = super::kcb::per_core();
+            let mut frame = kcb.mem_manager().allocate_base_page()?;
+            unsafe { frame.zero() };
+            nic.frames.push(frame);
+        }
+        nic.setup(mac);
+        Ok(nic)
+    }
+
+    /// Stops the NIC (and all DMA) and masks its interrupts.
+    fn reset(&self) -> Result<(), KError> {
+        self.regs.write(IMC, u32::MAX);
+        self.regs.write(CTRL, self.regs.read(CTRL) | CTRL_RST);
+        let start = rawtime::Instant::now();
+        while self.regs.read(CTRL) & CTRL_RST != 0 {
+            if start.elapsed() > RESET_TIMEOUT {
+                return Err(KError::NetworkDeviceUnavailable);
+            }
+            core::hint::spin_loop();
+        }
+        self.regs.write(IMC, u32::MAX);
+        let _pending = self.regs.read(ICR);
+        Ok(())
+    }
+
+    fn setup(&mut self, mac: EthernetAddress) {
+        let regs = self.regs;
+        regs.write(CTRL, regs.read(CTRL) | CTRL_SLU | CTRL_ASDE);
+
+        let mac = mac.as_bytes();
+        regs.write(RAL, u32::from_le_bytes([mac[0], mac[1], mac[2], mac[3]]));
+        regs.write(RAH, u16::from_le_bytes([mac[4], mac[5]]) as u32 | RAH_AV);
+        for idx in 0..128 {
+            regs.write(MTA + 4 * idx, 0);
+        }
+
+        // The descriptors are in the first page, then come the buffers
+        let rings = self.frames[0];
+        let frames = &self.frames;
+        let buffer = |ring: usize, idx: usize| -> (u64, u64) {
+            let n = ring * RING_SIZE + idx;
+            let frame = frames[1 + n * BUFFER_SIZE / BASE_PAGE_SIZE];
+            let offset = (n * BUFFER_SIZE % BASE_PAGE_SIZE) as u64;
+            (
+                frame.base.as_u64() + offset,
+                frame.kernel_vaddr().as_u64() + offset,
+            )
+        };
+        self.rx.descriptors = rings.kernel_vaddr().as_u64() + RX_RING as u64;
+        self.tx.descriptors = rings.kernel_vaddr().as_u64() + TX_RING as u64;
+        for idx in 0..RING_SIZE {
+            let (rx_paddr, rx_vaddr) = buffer(0, idx);
+            self.rx.buffers[idx] = rx_vaddr;
+            self.rx.set(
+                idx,
+                RxDescriptor {
+                    addr: rx_paddr,
+                    ..Default::default()
+                },
+            );
+            let (tx_paddr, tx_vaddr) = buffer(1, idx);
+            self.tx.buffers[idx] = tx_vaddr;
+            // All buffers are free
+            self.tx.set(
+                idx,
+                TxDescriptor {
+                    addr: tx_paddr,
+                    status: DESC_DD,
+                    ..Default::default()
+                },
+            );
+        }
+
+        // Both descriptors have 16 bytes
+        let ring_len = (RING_SIZE * core::mem::size_of::<RxDescriptor>()) as u32;
+        let rx_ring = rings.base.as_u64() + RX_RING as u64;
+        regs.write(RDBAL, rx_ring as u32);
+        regs.write(RDBAH, (rx_ring >> 32) as u32);
+        regs.write(RDLEN, ring_len);
+        regs.write(RDH, 0);
+        // The NIC owns all but one descriptor (head == tail means empty)
+        regs.write(RDT, (RING_SIZE - 1) as u32);
+        regs.write(RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+
+        let tx_ring = rings.base.as_u64() + TX_RING as u64;
+        regs.write(TDBAL, tx_ring as u32);
+        regs.write(TDBAH, (tx_ring >> 32) as u32);
+        regs.write(TDLEN, ring_len);
+        regs.write(TDH, 0);
+        regs.write(TDT, 0);
+        regs.write(TIPG, TIPG_COPPER);
+        regs.write(TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+
+        info!(
+            "e1000 link {}",
+            if regs.read(STATUS) & STATUS_LU != 0 {
+                "up"
+            } else {
+                "down"
+            }
+        );
+    }
+
+    /// Packet counters and neighbors of the NIC.
+    pub fn stats(&self) -> &PhyStats {
+        &self.stats
+    }
+
+    /// Are there received packets waiting to be processed?
+    pub fn rx_pending(&mut self) -> bool {
+        self.rx.get::<RxDescriptor>(self.rx.next).status & DESC_DD != 0
+    }
+
+    /// Is the next transmit descriptor free?
+    fn tx_free(&self) -> bool {
+        self.tx.get::<TxDescriptor>(self.tx.next).status & DESC_DD != 0
+    }
+}
+
+impl Drop for E1000 {
+    fn drop(&mut self) {
+        // The NIC must not write to the pages anymore
+        if self.reset().is_err() {
+            warn!("e1000 didn't reset, leaking its memory");
+            return;
+        }
+        let kcb = super::kcb::per_core();
+        let mut mem_manager = kcb.mem_manager();
+        for frame in self.frames.drain(..) {
+            if let Err(e) = mem_manager.release_base_page(frame) {
+                warn!("Can't free memory of e1000: {}", e);
+            }
+        }
+    }
+}
+
+impl<'a> Device<'a> for E1000 {
+    type RxToken = RxPacket<'a>;
+    type TxToken = TxPacket<'a>;
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        if !self.rx_pending() || !self.tx_free() {
+            return None;
+        }
+        Some((
+            RxPacket {
+                regs: self.regs,
+                ring: &mut self.rx,
+                stats: &self.stats,
+            },
+            TxPacket {
+                regs: self.regs,
+                ring: &mut self.tx,
+                stats: &self.stats,
+            },
+        ))
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        if !self.tx_free() {
+            return None;
+        }
+        Some(TxPacket {
+            regs: self.regs,
+            ring: &mut self.tx,
+            stats: &self.stats,
+        })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = 1514;
+        caps.max_burst_size = Some(1);
+        caps
+    }
+}
+
+/// A received packet (the next descriptor of the receive ring).
+pub struct RxPacket<'a> {
+    regs: Registers,
+    ring: &'a mut Ring,
+    stats: &'a PhyStats,
+}
+
+impl<'a> phy::RxToken for RxPacket<'a> {
+    fn consume<R, F>(self, _timestamp: Instant, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        let idx = self.ring.next;
+        let descriptor: RxDescriptor = self.ring.get(idx);
+        let result = if descriptor.status & DESC_EOP == 0 || descriptor.errors != 0 {
+            Err(smoltcp::Error::Malformed)
+        } else {
+            let buffer = self.ring.buffer(idx, descriptor.length as usize);
+            self.stats.rx(buffer);
+            f(buffer)
+        };
+
+        // Give the descriptor back to the NIC
+        self.ring.set(
+            idx,
+            RxDescriptor {
+                addr: descriptor.addr,
+                ..Default::default()
+            },
+        );
+        self.regs.write(RDT, idx as u32);
+        self.ring.next = (idx + 1) % RING_SIZE;
+        result
+    }
+}
+
+/// A packet to send (the next descriptor of the transmit ring).
+pub struct TxPacket<'a> {
+    regs: Registers,
+    ring: &'a mut Ring,
+    stats: &'a PhyStats,
+}
+
+impl<'a> phy::TxToken for TxPacket<'a> {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        if len > BUFFER_SIZE {
+            return Err(smoltcp::Error::Exhausted);
+        }
+        let idx = self.ring.next;
+        let descriptor: TxDescriptor = self.ring.get(idx);
+        let result = f(self.ring.buffer(idx, len))?;
+
+        self.ring.set(
+            idx,
+            TxDescriptor {
+                addr: descriptor.addr,
+                length: len as u16,
+                cmd: TX_EOP | TX_IFCS | TX_RS,
+                ..Default::default()
+            },
+        );
+        self.ring.next = (idx + 1) % RING_SIZE;
+        self.regs.write(TDT, self.ring.next as u32);
+        self.stats.tx(len);
+        Ok(result)
+    }
+}
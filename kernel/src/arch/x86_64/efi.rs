@@ -0,0 +1,240 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Wrapper for the UEFI runtime services.
+//!
+//! The bootloader relocates the runtime services into the kernel address
+//! space (see `bootloader/src/runtime.rs`) and passes us the table in
+//! `KernelArgs`. This gives us the firmware's wall-clock time and access to
+//! (persistent) UEFI variables without dedicated drivers.
+//!
+//! The runtime services are not reentrant, all calls are serialized with a
+//! lock.
+
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::fmt;
+
+use bootloader_shared::KernelArgs;
+use fallible_collections::FallibleVecGlobal;
+use log::{debug, info, warn};
+use uefi::{Guid, Status};
+
+use super::memory::paddr_to_kernel_vaddr;
+use crate::error::KError;
+use crate::sync::Mutex;
+
+/// `EFI_VARIABLE_NON_VOLATILE`
+pub const VARIABLE_NON_VOLATILE: u32 = 0x1;
+/// `EFI_VARIABLE_BOOTSERVICE_ACCESS`
+pub const VARIABLE_BOOTSERVICE_ACCESS: u32 = 0x2;
+/// `EFI_VARIABLE_RUNTIME_ACCESS`
+pub const VARIABLE_RUNTIME_ACCESS: u32 = 0x4;
+
+/// Vendor GUID we use for nrk's own variables.
+pub const NRK_VENDOR: Guid = Guid::from_values(
+    0x6e726b00,
+    0x6e72,
+    0x4b00,
+    0x8000,
+    [0x6e, 0x72, 0x6b, 0x6e, 0x72, 0x6b],
+);
+
+/// `EFI_TIME_UNSPECIFIED_TIMEZONE`
+const UNSPECIFIED_TIMEZONE: i16 = 0x07ff;
+
+/// `EFI_TIME`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Time {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    _pad1: u8,
+    pub nanosecond: u32,
+    /// Offset from UTC in minutes (or `0x7ff` if unspecified).
+    pub time_zone: i16,
+    pub daylight: u8,
+    _pad2: u8,
+}
+
+impl Time {
+    /// Seconds since the unix epoch (assumes UTC if the firmware doesn't
+    /// know the time zone).
+    pub fn unix_timestamp(&self) -> u64 {
+        // Days since 1970-01-01 (from Howard Hinnant's `days_from_civil`)
+        let (y, m) = if self.month <= 2 {
+            (self.year as i64 - 1, self.month as i64 + 9)
+        } else {
+            (self.year as i64, self.month as i64 - 3)
+        };
+        let era = y.div_euclid(400);
+        let yoe = y - era * 400;
+        let doy = (153 * m + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+
+        let mut secs =
+            days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        // Localtime = UTC - TimeZone
+        if self.time_zone != UNSPECIFIED_TIMEZONE {
+            secs += self.time_zone as i64 * 60;
+        }
+        secs as u64
+    }
+}
+
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// `EFI_RUNTIME_SERVICES` (up to the services we use).
+#[repr(C)]
+struct RuntimeServices {
+    header: [u8; 24],
+    get_time: unsafe extern "efiapi" fn(time: *mut Time, capabilities: *mut c_void) -> Status,
+    set_time: usize,
+    get_wakeup_time: usize,
+    set_wakeup_time: usize,
+    set_virtual_address_map: usize,
+    convert_pointer: usize,
+    get_variable: unsafe extern "efiapi" fn(
+        name: *const u16,
+        vendor: *const Guid,
+        attributes: *mut u32,
+        data_size: *mut usize,
+        data: *mut u8,
+    ) -> Status,
+    get_next_variable_name: usize,
+    set_variable: unsafe extern "efiapi" fn(
+        name: *const u16,
+        vendor: *const Guid,
+        attributes: u32,
+        data_size: usize,
+        data: *const u8,
+    ) -> Status,
+}
+
+struct Services(Option<&'static RuntimeServices>);
+
+// Safe: We only call into the firmware with the lock held.
+unsafe impl Send for Services {}
+
+static RUNTIME_SERVICES: Mutex<Services> = Mutex::new(Services(None));
+
+/// Finds the runtime services (if the bootloader relocated them for us).
+pub fn init(args: &KernelArgs) {
+    if args.runtime_services.as_u64() == 0 {
+        info!("UEFI runtime services are not available");
+        return;
+    }
+
+    let table = paddr_to_kernel_vaddr(args.runtime_services);
+    RUNTIME_SERVICES.lock().0 = Some(unsafe { &*table.as_ptr::<RuntimeServices>() });
+    match get_time() {
+        Ok(time) => info!(
+            "UEFI runtime services at {:#x}, time is {} ({})",
+            table,
+            time,
+            time.unix_timestamp()
+        ),
+        Err(e) => warn!(
+            "UEFI runtime services at {:#x} but GetTime failed: {}",
+            table, e
+        ),
+    }
+    match record_build() {
+        Ok(true) => info!(
+            "First boot of {} on this machine",
+            crate::build_info::GIT_VERSION
+        ),
+        Ok(false) => debug!("{} booted before", crate::build_info::GIT_VERSION),
+        Err(e) => debug!("Can't record the kernel build: {}", e),
+    }
+}
+
+/// Keeps the git revision of the kernel in the `LastBuild` variable (in
+/// NVRAM across reboots), returns true if a different build booted last.
+///
+/// The variable is only written when the build changes, NVRAM wears out.
+fn record_build() -> Result<bool, KError> {
+    let build = crate::build_info::GIT_VERSION.as_bytes();
+    let mut last = [0u8; 64];
+    if let Ok((_attributes, len)) = get_variable(&NRK_VENDOR, "LastBuild", &mut last) {
+        if last.get(..len) == Some(build) {
+            return Ok(false);
+        }
+    }
+    set_variable(
+        &NRK_VENDOR,
+        "LastBuild",
+        VARIABLE_NON_VOLATILE | VARIABLE_BOOTSERVICE_ACCESS | VARIABLE_RUNTIME_ACCESS,
+        build,
+    )?;
+    Ok(true)
+}
+
+/// Calls into the firmware with `f`.
+fn call(f: impl FnOnce(&RuntimeServices) -> Status) -> Result<(), KError> {
+    let services = RUNTIME_SERVICES.lock();
+    let rt = services.0.ok_or(KError::EfiRuntimeUnavailable)?;
+    match f(rt) {
+        Status::SUCCESS => Ok(()),
+        status => Err(KError::EfiRuntimeError {
+            status: status.0 as usize,
+        }),
+    }
+}
+
+/// Reads the current time from the firmware's real-time clock.
+pub fn get_time() -> Result<Time, KError> {
+    let mut time = Time::default();
+    call(|rt| unsafe { (rt.get_time)(&mut time, core::ptr::null_mut()) })?;
+    Ok(time)
+}
+
+fn ucs2(name: &str) -> Result<Vec<u16>, KError> {
+    let mut ucs2 = Vec::try_with_capacity(name.len() + 1)?;
+    ucs2.extend(name.encode_utf16());
+    ucs2.push(0);
+    Ok(ucs2)
+}
+
+/// Reads variable `name` of `vendor` into `data`.
+///
+/// Returns the attributes of the variable and its size.
+pub fn get_variable(vendor: &Guid, name: &str, data: &mut [u8]) -> Result<(u32, usize), KError> {
+    let name = ucs2(name)?;
+    let mut attributes = 0;
+    let mut size = data.len();
+    call(|rt| unsafe {
+        (rt.get_variable)(
+            name.as_ptr(),
+            vendor,
+            &mut attributes,
+            &mut size,
+            data.as_mut_ptr(),
+        )
+    })?;
+    Ok((attributes, size))
+}
+
+/// Writes variable `name` of `vendor`, an empty `data` deletes the variable.
+///
+/// Variables that should survive a reboot need
+/// `VARIABLE_NON_VOLATILE | VARIABLE_BOOTSERVICE_ACCESS | VARIABLE_RUNTIME_ACCESS`.
+pub fn set_variable(vendor: &Guid, name: &str, attributes: u32, data: &[u8]) -> Result<(), KError> {
+    let name = ucs2(name)?;
+    call(|rt| unsafe {
+        (rt.set_variable)(name.as_ptr(), vendor, attributes, data.len(), data.as_ptr())
+    })
+}
@@ -0,0 +1,152 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Kernel side of futexes (`ProcessOperation::FutexWait` and `FutexWake`).
+//!
+//! A thread that can't make progress on a 32-bit word of its address space
+//! (e.g., a contended `vibrio::sync::Mutex`) waits with `FutexWait` until a
+//! thread on another core calls `FutexWake` for the same word. Every core
+//! has a slot in [`WAITERS`] that says which word it waits for and a wake-up
+//! counter in [`WAKEUPS`]; the waiting core halts with `monitor`/`mwait` on
+//! its counter and `FutexWake` bumps the counters of the cores it wakes. On
+//! CPUs without `mwait` we spin for a bit instead.
+//!
+//! Like barrier waits we also return to user-space at every interrupt (with
+//! the time that is left of a timeout), the caller checks its word and waits
+//! again.
+//!
+//! Futex words can also be part of a `Poll`, which is ready once the word
+//! changed. `FutexWake` wakes up all polling cores (see `poll`).
+
+use core::convert::TryFrom;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crossbeam_utils::CachePadded;
+use kpi::io::PollEvents;
+
+use crate::error::KError;
+use crate::process::Pid;
+use crate::sync::Mutex;
+
+use super::process::validate_user_range;
+use super::sleep::Alarm;
+use super::usercopy::read_user;
+use super::MAX_CORES;
+
+/// How often we check the wake-up counter without `mwait`.
+const SPIN_LIMIT: usize = 4096;
+
+/// A core that waits in `FutexWait`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+struct Waiter {
+    pid: Pid,
+    /// User-space address of the word.
+    word: u64,
+}
+
+/// What every core waits for (indexed by core).
+static WAITERS: Mutex<[Option<Waiter>; MAX_CORES]> = Mutex::new([None; MAX_CORES]);
+
+/// Wake-up counter of every core (indexed by core), bumped with `WAITERS`
+/// locked when a core gets woken up.
+static WAKEUPS: [CachePadded<AtomicU64>; MAX_CORES] = {
+    const ZERO: CachePadded<AtomicU64> = CachePadded::new(AtomicU64::new(0));
+    [ZERO; MAX_CORES]
+};
+
+fn check_word(pid: Pid, word: u64) -> Result<(), KError> {
+    if word % 4 != 0 {
+        return Err(KError::InvalidSyscallArgument1 { a: word });
+    }
+    validate_user_range(pid, word, 4, false)
+}
+
+/// Waits until another core wakes us up for the (4-byte aligned) word at
+/// user-space address `word` of `pid` or an interrupt arrives. Returns
+/// right away if the word isn't `expected`. A `timeout` (in ns, 0 for none)
+/// makes sure an interrupt arrives once it's up.
+///
+/// Returns true if we got woken up and the nanoseconds left of `timeout`.
+pub fn wait(
+    pid: Pid,
+    core: usize,
+    word: u64,
+    expected: u32,
+    timeout: u64,
+) -> Result<(bool, u64), KError> {
+    check_word(pid, word)?;
+    let alarm = if timeout > 0 {
+        Some(Alarm::set(timeout)?)
+    } else {
+        None
+    };
+    let left = || alarm.as_ref().map_or(0, |alarm| alarm.remaining());
+
+    let wakeup = &WAKEUPS[core];
+    let seen = {
+        let mut waiters = WAITERS.lock();
+        // A `wake` that changed the word after this check finds us
+        if read_user::<u32>(word)? != expected {
+            return Ok((false, left()));
+        }
+        waiters[core] = Some(Waiter { pid, word });
+        wakeup.load(Ordering::Acquire)
+    };
+
+    if !super::barrier::halt_until_changed(wakeup, seen) {
+        for _i in 0..SPIN_LIMIT {
+            if wakeup.load(Ordering::Acquire) != seen {
+                break;
+            }
+            spin_loop();
+        }
+    }
+
+    // `wake` clears the slot and bumps the counter under the lock, so the
+    // counter tells if somebody woke us up before we stop waiting
+    let mut waiters = WAITERS.lock();
+    waiters[core] = None;
+    Ok((wakeup.load(Ordering::Acquire) != seen, left()))
+}
+
+/// Wakes up at most `count` cores that wait for the word at user-space
+/// address `word` of `pid` (in no particular order).
+///
+/// Returns the number of cores we woke up.
+pub fn wake(pid: Pid, word: u64, count: u64) -> Result<u64, KError> {
+    check_word(pid, word)?;
+
+    let waiter = Some(Waiter { pid, word });
+    let mut woken = 0;
+    let mut waiters = WAITERS.lock();
+    for (core, slot) in waiters.iter_mut().enumerate() {
+        if woken == count {
+            break;
+        }
+        if *slot == waiter {
+            *slot = None;
+            // The store wakes the core from `mwait`
+            WAKEUPS[core].fetch_add(1, Ordering::Release);
+            woken += 1;
+        }
+    }
+    drop(waiters);
+
+    // Polls don't register in `WAITERS`, they check the word again
+    super::poll::notify();
+    Ok(woken)
+}
+
+/// Readiness of the futex word at user-space address `word` of `pid` for
+/// `events` of a `Poll`: `POLLIN` once it isn't `expected` anymore,
+/// `POLLNVAL` if it isn't a valid word.
+pub fn poll(pid: Pid, word: u64, expected: u64, events: PollEvents) -> Result<PollEvents, KError> {
+    let expected = match u32::try_from(expected) {
+        Ok(expected) if check_word(pid, word).is_ok() => expected,
+        _ => return Ok(PollEvents::POLLNVAL),
+    };
+    let mut ready = PollEvents::empty();
+    ready.set(PollEvents::POLLIN, read_user::<u32>(word)? != expected);
+    Ok(ready & events)
+}
@@ -0,0 +1,334 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Hardware inventory of the machine, so benchmark results can be annotated
+//! with the exact hardware they ran on.
+//!
+//! The BSP takes the inventory once during boot (after the topology is
+//! parsed): the machine and firmware from SMBIOS, the CPU model, microcode
+//! revision and caches from CPUID, the NUMA nodes from the topology and the
+//! memory regions, and the PCI devices `devices::discover` found.
+//! It logs a summary, user-space can get all of it with
+//! `System::hardware_info`.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use fallible_collections::FallibleVec;
+use kpi::system::{CacheInfo, DeviceLocation, HardwareInfo, NodeInfo, PciDevice};
+use log::{debug, info, warn};
+use x86::cpuid::{CacheType, CpuId};
+
+use crate::error::KError;
+use crate::kcb::LocalCore;
+use crate::memory::vspace::MapAction;
+use crate::memory::{paddr_to_kernel_vaddr, DataSize, Frame, PAddr, BASE_PAGE_SIZE};
+use crate::round_up;
+
+use super::memory::KERNEL_BASE;
+
+/// The inventory (set once during boot).
+static INVENTORY: spin::Once<HardwareInfo> = spin::Once::new();
+
+/// Takes the inventory and logs a summary, `memory` are the physical memory
+/// regions the kernel manages (annotated with their NUMA node).
+pub fn init(core: &mut LocalCore, memory: &[Frame]) {
+    let inventory = match collect(core, memory) {
+        Ok(inventory) => inventory,
+        Err(e) => {
+            warn!("Can't take hardware inventory: {:?}", e);
+            return;
+        }
+    };
+    log_summary(&inventory);
+    INVENTORY.call_once(|| inventory);
+}
+
+/// The inventory taken at boot.
+pub fn get() -> Result<&'static HardwareInfo, KError> {
+    INVENTORY.get().ok_or(KError::NotSupported)
+}
+
+fn collect(core: &mut LocalCore, memory: &[Frame]) -> Result<HardwareInfo, KError> {
+    let mut inventory = HardwareInfo::default();
+
+    if let Some((system, firmware)) = smbios(core) {
+        inventory.system = system;
+        inventory.firmware = firmware;
+    }
+
+    let cpuid = CpuId::new();
+    if let Some(brand) = cpuid.get_processor_brand_string() {
+        inventory.cpu_model = String::from(brand.as_str().trim());
+    }
+    if let Some(fi) = cpuid.get_feature_info() {
+        inventory.cpu_family = fi.family_id() as u32;
+        inventory.cpu_model_id = fi.model_id() as u32;
+        inventory.cpu_stepping = fi.stepping_id() as u32;
+    }
+    inventory.microcode = microcode();
+
+    for cache in cpuid.get_cache_parameters().into_iter().flatten() {
+        let kind = match cache.cache_type() {
+            CacheType::Data => "data",
+            CacheType::Instruction => "instruction",
+            CacheType::Unified => "unified",
+            _ => continue,
+        };
+        let size = cache.associativity()
+            * cache.physical_line_partitions()
+            * cache.coherency_line_size()
+            * cache.sets();
+        inventory.caches.try_push(CacheInfo {
+            level: cache.level(),
+            kind: String::from(kind),
+            size: size as u64,
+            shared_by: cache.max_cores_for_cache() as u64,
+        })?;
+    }
+
+    let node_memory = |id| -> u64 {
+        memory
+            .iter()
+            .filter(|frame| frame.affinity == id)
+            .map(|frame| frame.size as u64)
+            .sum()
+    };
+    if atopology::MACHINE_TOPOLOGY.num_nodes() > 0 {
+        for node in atopology::MACHINE_TOPOLOGY.nodes() {
+            inventory.nodes.try_push(NodeInfo {
+                id: node.id,
+                threads: node.threads().count() as u64,
+                memory: node_memory(node.id),
+            })?;
+        }
+    } else {
+        inventory.nodes.try_push(NodeInfo {
+            id: 0,
+            threads: atopology::MACHINE_TOPOLOGY.num_threads() as u64,
+            memory: node_memory(0),
+        })?;
+    }
+    inventory.memory = memory.iter().map(|frame| frame.size as u64).sum();
+
+    inventory.pci_devices = pci_devices()?;
+
+    Ok(inventory)
+}
+
+fn log_summary(inventory: &HardwareInfo) {
+    if !inventory.system.is_empty() {
+        info!(
+            "Machine: {} (firmware {})",
+            inventory.system, inventory.firmware
+        );
+    }
+    info!(
+        "CPU: {} (family {:#x} model {:#x} stepping {}, microcode {:#x})",
+        inventory.cpu_model,
+        inventory.cpu_family,
+        inventory.cpu_model_id,
+        inventory.cpu_stepping,
+        inventory.microcode
+    );
+
+    let mut caches = String::new();
+    for cache in inventory.caches.iter() {
+        let suffix = match cache.kind.as_str() {
+            "data" => "d",
+            "instruction" => "i",
+            _ => "",
+        };
+        caches += format!(
+            " L{}{} {}",
+            cache.level,
+            suffix,
+            DataSize::from_bytes(cache.size as usize)
+        )
+        .as_str();
+    }
+    info!("Caches:{}", caches);
+
+    let mut nodes = String::new();
+    for node in inventory.nodes.iter() {
+        nodes += format!(
+            " [node {}: {} threads, {}]",
+            node.id,
+            node.threads,
+            DataSize::from_bytes(node.memory as usize)
+        )
+        .as_str();
+    }
+    info!(
+        "Memory: {} in {} NUMA node(s){}",
+        DataSize::from_bytes(inventory.memory as usize),
+        inventory.nodes.len(),
+        nodes
+    );
+
+    info!("PCI: {} devices", inventory.pci_devices.len());
+    for dev in inventory.pci_devices.iter() {
+        debug!(
+            "  {:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}.{:02x}",
+            dev.bus,
+            dev.device,
+            dev.function,
+            dev.vendor_id,
+            dev.device_id,
+            dev.class,
+            dev.subclass
+        );
+    }
+}
+
+/// Microcode revision of the current core.
+fn microcode() -> u64 {
+    use x86::msr::{rdmsr, wrmsr, IA32_BIOS_SIGN_ID};
+
+    let cpuid = CpuId::new();
+    let is_intel = cpuid
+        .get_vendor_info()
+        .map_or(false, |v| v.as_str() == "GenuineIntel");
+
+    unsafe {
+        if is_intel {
+            // Intel updates the MSR when we execute CPUID after clearing it,
+            // the revision is in the upper half
+            wrmsr(IA32_BIOS_SIGN_ID, 0);
+            let _ = CpuId::new().get_feature_info();
+            rdmsr(IA32_BIOS_SIGN_ID) >> 32
+        } else {
+            // AMD reports the patch level in the lower half
+            rdmsr(IA32_BIOS_SIGN_ID) & 0xffff_ffff
+        }
+    }
+}
+
+/// The PCI functions (found by `devices::discover`).
+fn pci_devices() -> Result<Vec<PciDevice>, KError> {
+    let mut pci = Vec::new();
+    for info in crate::devices::list()? {
+        if let DeviceLocation::Pci {
+            bus,
+            device,
+            function,
+        } = info.location
+        {
+            pci.try_push(PciDevice {
+                bus,
+                device,
+                function,
+                vendor_id: info.vendor_id,
+                device_id: info.device_id,
+                class: info.class,
+                subclass: info.subclass,
+            })?;
+        }
+    }
+    Ok(pci)
+}
+
+/// Maps `len` bytes of physical memory at `base` (firmware tables that the
+/// kernel doesn't map by default).
+fn map_physical(core: &mut LocalCore, base: PAddr, len: usize) -> Option<&'static [u8]> {
+    let adjusted_len = (base - base.align_down_to_base_page().as_usize()).as_usize() + len;
+    core.kcb_mut()
+        .arch
+        .init_vspace()
+        .map_identity_with_offset(
+            PAddr::from(KERNEL_BASE),
+            base.align_down_to_base_page(),
+            round_up!(adjusted_len, BASE_PAGE_SIZE),
+            MapAction::ReadKernel,
+        )
+        .ok()?;
+
+    let vaddr = paddr_to_kernel_vaddr(base);
+    Some(unsafe { core::slice::from_raw_parts(vaddr.as_ptr::<u8>(), len) })
+}
+
+/// Returns the machine (vendor and product) and firmware (vendor and
+/// version) from the SMBIOS tables.
+fn smbios(core: &mut LocalCore) -> Option<(String, String)> {
+    let args = super::kcb::per_core().arch.kernel_args();
+    let u16_at = |b: &[u8], at: usize| u16::from_le_bytes([b[at], b[at + 1]]) as usize;
+    let u32_at =
+        |b: &[u8], at: usize| u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]]) as usize;
+
+    // Prefer the 64-bit entry point, both give us the structure table
+    let (table, len) = if args.smbios3_entry.as_u64() != 0 {
+        let entry = map_physical(core, args.smbios3_entry, 0x18)?;
+        if &entry[0..5] != b"_SM3_" {
+            return None;
+        }
+        let table = u64::from_le_bytes(entry[0x10..0x18].try_into().ok()?);
+        (PAddr::from(table), u32_at(entry, 0x0c))
+    } else if args.smbios_entry.as_u64() != 0 {
+        let entry = map_physical(core, args.smbios_entry, 0x1f)?;
+        if &entry[0..4] != b"_SM_" {
+            return None;
+        }
+        (PAddr::from(u32_at(entry, 0x18) as u64), u16_at(entry, 0x16))
+    } else {
+        return None;
+    };
+    let table = map_physical(core, table, len)?;
+
+    // The n-th (starting at 1) string of a structure
+    let string = |strings: &[u8], n: u8| -> String {
+        match n {
+            0 => String::new(),
+            n => strings
+                .split(|b| *b == 0)
+                .nth(n as usize - 1)
+                .and_then(|s| core::str::from_utf8(s).ok())
+                .map_or(String::new(), |s| String::from(s.trim())),
+        }
+    };
+
+    let (mut system, mut firmware) = (String::new(), String::new());
+    let mut offset = 0;
+    while offset + 4 <= table.len() {
+        let (kind, header_len) = (table[offset], table[offset + 1] as usize);
+        if header_len < 4 || offset + header_len > table.len() {
+            break;
+        }
+        let header = &table[offset..offset + header_len];
+
+        // The strings follow the formatted part and end with two NULs
+        let strings = &table[offset + header_len..];
+        let strings_len = strings
+            .windows(2)
+            .position(|w| w == [0, 0])
+            .unwrap_or(strings.len());
+        let strings = &strings[..strings_len];
+
+        match kind {
+            // BIOS information
+            0 if header_len > 0x5 => {
+                firmware = format!(
+                    "{} {}",
+                    string(strings, header[0x4]),
+                    string(strings, header[0x5])
+                );
+            }
+            // System information
+            1 if header_len > 0x5 => {
+                system = format!(
+                    "{} {}",
+                    string(strings, header[0x4]),
+                    string(strings, header[0x5])
+                );
+            }
+            // End of table
+            127 => break,
+            _ => {}
+        }
+
+        offset += header_len + strings_len + 2;
+    }
+
+    Some((system, firmware))
+}
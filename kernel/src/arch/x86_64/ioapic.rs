@@ -0,0 +1,245 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Programs the I/O APICs (from the MADT) for the interrupt routes of
+//! processes (see `irqroute`).
+//!
+//! Every I/O APIC handles the GSIs from its `global_irq_base` on, one per
+//! input pin. A route becomes a redirection entry (in compatibility format)
+//! for the pin that delivers the GSI's vector to the APIC of the core.
+//! Routes are configured with the interrupt source overrides of the MADT
+//! (see `irqroute::Route::new`).
+//! Without interrupt remapping (VT-d) the entry can only address APIC ids
+//! below 256; remapped routes would get an interrupt remapping table entry
+//! and a redirection entry in remappable format instead.
+
+use alloc::vec::Vec;
+use core::ptr;
+
+use fallible_collections::FallibleVec;
+use log::{info, trace, warn};
+use x86::apic::ApicId;
+
+use super::memory::{paddr_to_kernel_vaddr, PAddr, VAddr, BASE_PAGE_SIZE, KERNEL_BASE};
+use crate::error::KError;
+use crate::irqroute::{self, Gsi, Polarity, Route, SourceOverride, Trigger};
+use crate::memory::reserved::{self, Reserved};
+use crate::memory::vspace::MapAction;
+use crate::process::Pid;
+use crate::sync::Mutex;
+
+/// Register select (offset of the MMIO window).
+const IOREGSEL: usize = 0x00;
+/// Register data (offset of the MMIO window).
+const IOWIN: usize = 0x10;
+
+/// Version register (number of pins in bits 16..24).
+const IOAPICVER: u32 = 0x01;
+/// First redirection entry, every entry has two registers.
+const IOREDTBL: u32 = 0x10;
+
+/// Redirection entry: active low.
+const RTE_ACTIVE_LOW: u64 = 1 << 13;
+/// Redirection entry: level triggered.
+const RTE_LEVEL: u64 = 1 << 15;
+/// Redirection entry: masked.
+const RTE_MASKED: u64 = 1 << 16;
+/// Redirection entry: destination (physical APIC id).
+const RTE_DESTINATION_SHIFT: u64 = 56;
+
+/// An I/O APIC of the machine.
+struct IoApic {
+    /// Kernel address of the registers.
+    registers: VAddr,
+    /// The GSI of the first pin.
+    gsi_base: Gsi,
+    pins: u32,
+}
+
+impl IoApic {
+    /// # Safety
+    /// `registers` has to map the registers of an I/O APIC.
+    unsafe fn new(registers: VAddr, gsi_base: Gsi) -> IoApic {
+        let mut ioapic = IoApic {
+            registers,
+            gsi_base,
+            pins: 0,
+        };
+        ioapic.pins = ((ioapic.read(IOAPICVER) >> 16) & 0xff) + 1;
+        ioapic
+    }
+
+    fn read(&mut self, reg: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.registers + IOREGSEL).as_mut_ptr::<u32>(), reg);
+            ptr::read_volatile((self.registers + IOWIN).as_ptr::<u32>())
+        }
+    }
+
+    fn write(&mut self, reg: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.registers + IOREGSEL).as_mut_ptr::<u32>(), reg);
+            ptr::write_volatile((self.registers + IOWIN).as_mut_ptr::<u32>(), value);
+        }
+    }
+
+    /// The pin of `gsi` (if this I/O APIC has it).
+    fn pin(&self, gsi: Gsi) -> Option<u32> {
+        gsi.checked_sub(self.gsi_base)
+            .filter(|pin| *pin < self.pins)
+    }
+
+    fn set_entry(&mut self, pin: u32, entry: u64) {
+        let reg = IOREDTBL + 2 * pin;
+        // Mask first, the entry is inconsistent while we write the halves
+        self.write(reg, RTE_MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+
+    fn mask_all(&mut self) {
+        for pin in 0..self.pins {
+            self.set_entry(pin, RTE_MASKED);
+        }
+    }
+}
+
+/// The I/O APICs (the lock also serializes the register accesses).
+static IOAPICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+
+/// The interrupt source overrides of the MADT.
+static OVERRIDES: Mutex<Vec<SourceOverride>> = Mutex::new(Vec::new());
+
+/// Maps the registers of the I/O APICs and masks all their pins, reads the
+/// interrupt source overrides.
+pub fn init() {
+    let kcb = super::kcb::per_core();
+    let mut ioapics = IOAPICS.lock();
+
+    *OVERRIDES.lock() =
+        super::acpi::interrupt_overrides().expect("Can't read the interrupt source overrides");
+
+    for io_apic in atopology::MACHINE_TOPOLOGY.io_apics() {
+        info!("Initialize IO APIC {:?}", io_apic);
+
+        let paddr = PAddr::from(io_apic.address as u64);
+        reserved::reserve(paddr, BASE_PAGE_SIZE, Reserved::IoApic).expect("Can't reserve IO APIC");
+        reserved::check(paddr, BASE_PAGE_SIZE, Some(Reserved::IoApic))
+            .expect("IO APIC overlaps reserved memory");
+        kcb.arch
+            .init_vspace()
+            .map_identity_with_offset(
+                PAddr::from(KERNEL_BASE),
+                paddr,
+                BASE_PAGE_SIZE,
+                MapAction::ReadWriteKernel,
+            )
+            .expect("Can't create APIC mapping?");
+
+        // Safe: We just mapped the registers
+        let mut ioapic =
+            unsafe { IoApic::new(paddr_to_kernel_vaddr(paddr), io_apic.global_irq_base) };
+        trace!(
+            "IO APIC for GSI {} -- {}",
+            ioapic.gsi_base,
+            ioapic.gsi_base + ioapic.pins
+        );
+        ioapic.mask_all();
+        ioapics
+            .try_push(ioapic)
+            .expect("Not enough memory to initialize IO APICs");
+    }
+}
+
+/// The redirection entry for `route` (to the core with `apic_id`).
+fn redirection_entry(route: &Route, apic_id: ApicId) -> Result<u64, KError> {
+    let apic_id = match apic_id {
+        ApicId::XApic(id) => id as u32,
+        ApicId::X2Apic(id) => id,
+    };
+    if apic_id > 0xff {
+        return Err(KError::InterruptNeedsRemapping { apic_id });
+    }
+
+    let mut entry = route.vector as u64 | (apic_id as u64) << RTE_DESTINATION_SHIFT;
+    if route.polarity == Polarity::ActiveLow {
+        entry |= RTE_ACTIVE_LOW;
+    }
+    if route.trigger == Trigger::Level {
+        entry |= RTE_LEVEL;
+    }
+    Ok(entry)
+}
+
+/// Delivers `gsi` to `core` for process `pid` (`Irq::irqalloc`).
+///
+/// Returns the vector the interrupt arrives with.
+pub fn route(pid: Pid, gsi: Gsi, core: usize) -> Result<u8, KError> {
+    if core >= atopology::MACHINE_TOPOLOGY.num_threads() {
+        return Err(KError::InvalidSyscallArgument1 { a: core as u64 });
+    }
+
+    let overrides = OVERRIDES.lock();
+    let route = irqroute::add(pid, gsi, core, &overrides, |route| {
+        let apic_id = atopology::MACHINE_TOPOLOGY.threads[route.core].apic_id();
+        let entry = redirection_entry(route, apic_id)?;
+
+        let mut ioapics = IOAPICS.lock();
+        let (ioapic, pin) = ioapics
+            .iter_mut()
+            .find_map(|ioapic| ioapic.pin(gsi).map(|pin| (ioapic, pin)))
+            .ok_or(KError::NoSuchInterrupt { gsi: gsi as u64 })?;
+        ioapic.set_entry(pin, entry);
+        Ok(())
+    })?;
+
+    info!(
+        "Process {} gets GSI {} on core {} (vector {})",
+        pid, route.gsi, route.core, route.vector
+    );
+    Ok(route.vector)
+}
+
+/// Masks (or unmasks) the route of `pid` for `gsi`, e.g., while the driver
+/// polls its device. Interrupts that come in while the line is masked are
+/// delivered on unmask if the line is still asserted (level triggered).
+pub fn set_masked(pid: Pid, gsi: Gsi, masked: bool) -> Result<(), KError> {
+    irqroute::with_route(pid, gsi, |route| {
+        let apic_id = atopology::MACHINE_TOPOLOGY.threads[route.core].apic_id();
+        let mut entry = redirection_entry(route, apic_id)?;
+        if masked {
+            entry |= RTE_MASKED;
+        }
+
+        let mut ioapics = IOAPICS.lock();
+        let (ioapic, pin) = ioapics
+            .iter_mut()
+            .find_map(|ioapic| ioapic.pin(gsi).map(|pin| (ioapic, pin)))
+            .ok_or(KError::NoSuchInterrupt { gsi: gsi as u64 })?;
+        trace!("Process {} masks GSI {}: {}", pid, gsi, masked);
+        ioapic.set_entry(pin, entry);
+        Ok(())
+    })
+}
+
+/// Masks the GSIs of `pid` (which exited).
+pub fn release(pid: Pid) {
+    irqroute::release(pid, |route| {
+        let mut ioapics = IOAPICS.lock();
+        match ioapics
+            .iter_mut()
+            .find_map(|ioapic| ioapic.pin(route.gsi).map(|pin| (ioapic, pin)))
+        {
+            Some((ioapic, pin)) => ioapic.set_entry(pin, RTE_MASKED),
+            None => warn!("Can't mask GSI {} of process {}", route.gsi, pid),
+        }
+    });
+}
+
+/// Masks all interrupts on the I/O APICs (before we jump into a new kernel,
+/// see `kexec`).
+pub fn disable_all() {
+    for ioapic in IOAPICS.lock().iter_mut() {
+        ioapic.mask_all();
+    }
+}
@@ -34,6 +34,7 @@
 #![allow(warnings)]
 
 use core::fmt;
+use core::sync::atomic::Ordering;
 
 use alloc::boxed::Box;
 
@@ -46,19 +47,20 @@ use x86::{dtables, Ring};
 
 use apic::ApicDriver;
 use klogger::{sprint, sprintln};
+use kpi::process::SchedulingClass;
 use log::{info, trace, warn};
 
-use crate::kcb::ArchSpecificKcb;
-use crate::memory::vspace::MapAction;
-use crate::memory::Frame;
+use crate::kcb::{ArchSpecificKcb, LocalCore};
 use crate::panic::{backtrace, backtrace_from};
-use crate::process::{Executor, ResumeHandle};
+use crate::process::{Executor, Pid, ResumeHandle};
+use crate::softirq::{self, SoftIrq};
 use crate::{cnrfs, nr, nrproc, ExitReason};
 
 use super::gdt::GdtTable;
-use super::kcb::{get_kcb, Arch86Kcb};
-use super::memory::{PAddr, VAddr, BASE_PAGE_SIZE, KERNEL_BASE};
+use super::kcb::{per_core, Arch86Kcb};
+use super::memory::{VAddr, KERNEL_BASE};
 use super::process::{Ring3Process, Ring3Resumer};
+use super::usercopy::read_user;
 use super::{debug, timer};
 
 /// A macro to initialize an entry in an IDT table.
@@ -93,7 +95,8 @@ macro_rules! idt_set {
         //
         // $ist is normally set to 0, which means we use the interrupt_stack from the kcb.
         // $ist is set to 1 for double-faults and other severe exceptions
-        // to use the `unrecoverable_fault_stack` from the kcb
+        // to use the `unrecoverable_fault_stack` from the kcb, 2 for NMIs
+        // to use the `nmi_stack`
         $idt_table[$num] = DescriptorBuilder::interrupt_descriptor(seg, $f as u64)
             .dpl(Ring::Ring3)
             .ist($ist)
@@ -107,9 +110,23 @@ pub const TLB_WORK_PENDING: u8 = 251;
 /// The IDT entry for handling GC in cnr.
 pub const MLNR_GC_INIT: u8 = 250;
 
+/// `Arch86Kcb::nested_irq` outside of [`with_interrupts`].
+pub(crate) const IRQ_WINDOW_CLOSED: u64 = 0;
+
+/// `Arch86Kcb::nested_irq` in [`with_interrupts`] while no interrupt arrived
+/// (`isr.S` compares with 1).
+pub(crate) const IRQ_WINDOW_OPEN: u64 = 1;
+
 /// The IDT table can hold a maximum of 256 entries.
 pub const IDT_SIZE: usize = 256;
 
+/// The first vector of `isr_device_handlers` in `isr.S` (the ones before are
+/// the PIC interrupts).
+const FIRST_DEVICE_HANDLER: usize = 48;
+
+/// Handlers in `isr_device_handlers`, up to the last vector we route.
+const DEVICE_HANDLERS: usize = crate::irqroute::MAX_VECTOR as usize + 1 - FIRST_DEVICE_HANDLER;
+
 /// The IDT table that is installed early on during initialization.
 ///
 /// Later on each core is free to use their own IDT table
@@ -130,7 +147,9 @@ impl Default for IdtTable {
 
         idt_set!(table.0, 0, isr_handler0, 0);
         idt_set!(table.0, 1, isr_handler1, 0);
-        idt_set!(table.0, 2, isr_handler2, 0);
+        // NMIs go to the profiler first (see `profile.S`), on their own
+        // stack: they can arrive while we're still on the user stack
+        idt_set!(table.0, 2, nrk_profile_nmi, 2);
         idt_set!(table.0, 3, isr_handler3, 0);
         idt_set!(table.0, 4, isr_handler4, 0);
         idt_set!(table.0, 5, isr_handler5, 0);
@@ -173,6 +192,20 @@ impl Default for IdtTable {
         idt_set!(table.0, 46, isr_handler46, 0);
         idt_set!(table.0, 47, isr_handler47, 0);
 
+        // Device interrupts routed to processes (see `irqroute`):
+        extern "C" {
+            static isr_device_handlers: [u64; DEVICE_HANDLERS];
+        }
+        let seg = SegmentSelector::new(GdtTable::CS_KERNEL_INDEX as u16, Ring::Ring0);
+        for (idx, handler) in unsafe { isr_device_handlers.iter() }.enumerate() {
+            table.0[FIRST_DEVICE_HANDLER + idx] =
+                DescriptorBuilder::interrupt_descriptor(seg, *handler)
+                    .dpl(Ring::Ring3)
+                    .ist(0)
+                    .present()
+                    .finish();
+        }
+
         idt_set!(table.0, TLB_WORK_PENDING as usize, isr_handler251, 0);
         idt_set!(table.0, MLNR_GC_INIT as usize, isr_handler250, 0);
         idt_set!(table.0, apic::TSC_TIMER_VECTOR as usize, isr_handler252, 0);
@@ -286,10 +319,10 @@ unsafe fn unhandled_irq(a: &ExceptionArguments) {
     sprintln!("{:?}", a);
     backtrace();
 
-    let kcb = get_kcb();
+    let kcb = per_core();
     sprintln!("Register State:\n{:?}", kcb.arch.save_area);
 
-    if !kcb.in_panic_mode {
+    if !kcb.in_panic_mode() {
         kcb.arch.save_area.as_ref().map(|sa| {
             backtrace_from(sa.rbp, sa.rsp, sa.rip);
         });
@@ -307,7 +340,7 @@ unsafe fn pf_handler(a: &ExceptionArguments) {
 
     let err = PageFaultError::from_bits_truncate(a.exception as u32);
     let faulting_address = x86::controlregs::cr2();
-    let kcb = get_kcb();
+    let kcb = per_core();
 
     // If this is a user-mode page-fault make sure it's not a spurious
     // page-fault by not having a replica in-sync with others
@@ -334,22 +367,33 @@ unsafe fn pf_handler(a: &ExceptionArguments) {
                 r.resume()
             }
             Err(_) => {
+                // The page might have been evicted under memory pressure
+                if super::reclaim::reload(pid, faulting_address_va).is_ok() {
+                    trace!(
+                        "Reloaded evicted page {} for {} on {}",
+                        faulting_address_va,
+                        pid,
+                        kcb.arch.hwthread_id()
+                    );
+                    let r = kcb_iret_handle(kcb);
+                    r.resume()
+                }
                 // unresolved page-fault, proceed with abort below
             }
         }
+
+        crash(pid, a);
     }
 
     sprintln!("[IRQ] Page Fault on {}", kcb.arch.id());
     sprintln!("{}", err);
-
-    // Enable user-space access
-    x86::current::rflags::stac();
-
-    for i in 0..32 {
-        let ptr = (a.rsp as *const u64).offset(i);
-        sprintln!("stack[{}] = {:#x}", i, *ptr);
+    if !err.contains(PageFaultError::US) && (faulting_address as u64) < KERNEL_BASE {
+        // SMAP (or SMEP if it's an instruction fetch)
+        sprintln!("Kernel accessed user-space memory outside of the usercopy helpers");
     }
 
+    dump_stack(a.rsp, 32);
+
     // Print where the fault happend in the address-space:
     let faulting_address = x86::controlregs::cr2();
     sprint!("Faulting address: {:#x}", faulting_address);
@@ -382,9 +426,10 @@ unsafe fn pf_handler(a: &ExceptionArguments) {
     }
 
     sprintln!("{:?}", a);
-    let kcb = get_kcb();
+    let kcb = per_core();
     sprintln!("Register State:\n{:?}", kcb.arch.save_area);
-    if !kcb.in_panic_mode {
+    // User-space faults got a user backtrace already (see `crash`)
+    if !kcb.in_panic_mode() && !err.contains(PageFaultError::US) {
         kcb.arch.save_area.as_ref().map(|sa| {
             backtrace_from(sa.rbp, sa.rsp, sa.rip);
         });
@@ -401,7 +446,7 @@ unsafe fn dbg_handler(a: &ExceptionArguments) {
     let desc = &EXCEPTIONS[a.vector as usize];
     warn!("Got debug interrupt {}", desc.source);
 
-    let kcb = get_kcb();
+    let kcb = per_core();
     assert!(kcb.arch.has_executor(), "Not from user-space?");
     let r = Ring3Resumer::new_restore(kcb.arch.get_save_area_ptr());
     r.resume()
@@ -421,11 +466,17 @@ unsafe fn timer_handler(a: &ExceptionArguments) {
     }
 
     // Periodically advance replica state, then resume immediately
-    nr::KernelNode::synchronize();
-    let kcb = get_kcb();
-    for pid in 0..crate::process::MAX_PROCESSES {
-        nrproc::NrProcess::<Ring3Process>::synchronize(pid);
-    }
+    softirq::raise(SoftIrq::Timer);
+    softirq::raise(SoftIrq::Replication);
+    softirq::raise(SoftIrq::Rcu);
+    let kcb = per_core();
+    super::stacks::check(&kcb.arch);
+    super::profile::tick(kcb.arch.id());
+    let sched = kcb
+        .arch
+        .current_executor()
+        .map(|e| (e.pid, e.sched_class, e.sched_deadline))
+        .ok();
 
     if kcb.arch.has_executor() {
         // TODO(process-mgmt): Ensures that we still periodically
@@ -444,8 +495,34 @@ unsafe fn timer_handler(a: &ExceptionArguments) {
                     .map(|t| t.id == thread.id)
                     .unwrap_or(false)
         };
-        if is_replica_main_thread {
-            timer::set(timer::DEFAULT_TIMER_DEADLINE);
+        // The load-balancer needs a periodic timer on every busy core
+        let balancing = super::migrate::balancing();
+        if balancing {
+            let mut core = crate::kcb::LocalCore::new();
+            super::migrate::balance(&mut core);
+        }
+        let (class, hint) = sched
+            .map_or((SchedulingClass::default(), None), |(_pid, class, hint)| {
+                (class, hint)
+            });
+        let mut deadline = crate::scheduler::policy::current().tick(
+            class,
+            hint,
+            is_replica_main_thread || balancing,
+        );
+        if softirq::run_pending() {
+            deadline =
+                Some(deadline.map_or(softirq::RETRY_DEADLINE, |d| d.min(softirq::RETRY_DEADLINE)));
+        }
+        if !kcb.arch.has_executor() {
+            // The OOM killer killed the process we were running
+            crate::scheduler::schedule()
+        }
+        if let Some(deadline) = deadline {
+            timer::set(deadline);
+        }
+        if let Some(retry) = super::vcpu::deliver_overdue(kcb, VAddr::from(a.rip)) {
+            timer::set_before(timer::now() + retry);
         }
 
         // Return immediately
@@ -458,18 +535,41 @@ unsafe fn timer_handler(a: &ExceptionArguments) {
     }
 }
 
+/// Soft IRQ that advances the replicas (raised by the timer).
+pub fn replication_softirq() {
+    nr::KernelNode::synchronize();
+    let kcb = per_core();
+    match kcb.arch.current_executor().map(|e| (e.pid, e.sched_class)) {
+        Ok((pid, SchedulingClass::LowLatency)) => {
+            // Keep the work on low-latency cores bounded: only advance the
+            // replica of the process we're running, leave the rest to others
+            nrproc::NrProcess::<Ring3Process>::synchronize(pid);
+        }
+        _ => {
+            for pid in 0..crate::process::MAX_PROCESSES {
+                nrproc::NrProcess::<Ring3Process>::synchronize(pid);
+            }
+        }
+    }
+}
+
 /// Handler for a general protection exception.
 ///
 /// TODO: Right now we terminate kernel.
 /// Should abort process and resume.
 unsafe fn gp_handler(a: &ExceptionArguments) {
     let desc = &EXCEPTIONS[a.vector as usize];
+
+    // Faults in user-space
+    if a.cs & 0x3 == 0x3 {
+        if let Ok(pid) = per_core().current_pid() {
+            crash(pid, a);
+        }
+    }
+
     sprint!("\n[IRQ] GENERAL PROTECTION FAULT: ");
     sprintln!("From {}", desc.source);
 
-    // Enable user-space access
-    x86::current::rflags::stac();
-
     if a.exception > 0 {
         sprintln!(
             "Error value: {:?}",
@@ -490,15 +590,13 @@ unsafe fn gp_handler(a: &ExceptionArguments) {
     });*/
 
     sprintln!("{:?}", a);
-    let kcb = get_kcb();
+    let kcb = per_core();
     sprintln!("Register State:\n{:?}", kcb.arch.save_area);
 
-    for i in 0..12 {
-        let ptr = (a.rsp as *const u64).offset(i);
-        sprintln!("stack[{}] = {:#x}", i, *ptr);
-    }
+    dump_stack(a.rsp, 12);
 
-    if !kcb.in_panic_mode {
+    // User-space faults got a user backtrace already (see `crash`)
+    if !kcb.in_panic_mode() && a.cs & 0x3 != 0x3 {
         kcb.arch.save_area.as_ref().map(|sa| {
             backtrace_from(sa.rbp, sa.rsp, sa.rip);
         });
@@ -507,6 +605,62 @@ unsafe fn gp_handler(a: &ExceptionArguments) {
     debug::shutdown(ExitReason::GeneralProtectionFault);
 }
 
+/// Prints the first `words` of the stack at `rsp`.
+///
+/// A user-space stack is read with `read_user` (we don't open up user
+/// memory with SMAP for this), a word we can't read is printed as such.
+unsafe fn dump_stack(rsp: u64, words: u64) {
+    for i in 0..words {
+        let addr = rsp + i * 8;
+        if addr < KERNEL_BASE {
+            match read_user::<u64>(addr) {
+                Ok(word) => sprintln!("stack[{}] = {:#x}", i, word),
+                Err(_) => sprintln!("stack[{}] = <unreadable>", i),
+            }
+        } else {
+            sprintln!("stack[{}] = {:#x}", i, *(addr as *const u64));
+        }
+    }
+}
+
+/// Process `pid` faulted in user-space and can't continue.
+///
+/// Prints a (symbolized) backtrace of the process and writes a core dump (if
+/// the process wants one). A child process (or a service that gets
+/// restarted) is then terminated and the core goes back to the scheduler,
+/// otherwise this returns and the fault aborts the system.
+unsafe fn crash(pid: Pid, a: &ExceptionArguments) {
+    let kcb = per_core();
+    let survives = nr::KernelNode::process(pid).map_or(false, |p| p.parent.is_some())
+        || crate::services::restarts(pid, kpi::process::CRASHED);
+    if let Some(sa) = kcb.arch.save_area.as_ref() {
+        if survives {
+            // The fault handler doesn't get to print it
+            warn!("Process {} crashed at {:#x}", pid, a.rip);
+            sprintln!("Register State:\n{:?}", sa);
+        }
+        match nrproc::NrProcess::<Ring3Process>::module(pid) {
+            Ok((module, offset)) => crate::panic::backtrace_user(
+                pid,
+                module.as_slice(),
+                offset.as_u64(),
+                sa.rbp,
+                sa.rip,
+            ),
+            Err(_e) => sprintln!("User backtrace unavailable (binary of {} missing)", pid),
+        }
+        super::coredump::dump(pid, &**sa, a.cs, a.ss);
+    }
+
+    if survives {
+        // Safety: We're handling an exception from user-space
+        let mut core = LocalCore::new();
+        if super::process::terminate(&mut core, pid, kpi::process::CRASHED).is_ok() {
+            crate::scheduler::schedule()
+        }
+    }
+}
+
 fn kcb_resume_handle(kcb: &crate::kcb::Kcb<Arch86Kcb>) -> Ring3Resumer {
     Ring3Resumer::new_restore(kcb.arch.get_save_area_ptr())
 }
@@ -577,12 +731,16 @@ pub extern "C" fn handle_generic_exception_early(a: ExceptionArguments) -> ! {
 #[no_mangle]
 pub extern "C" fn handle_generic_exception(a: ExceptionArguments) -> ! {
     unsafe {
+        super::mitigations::enter_kernel();
         let start = x86::time::rdtsc();
         assert!(a.vector < 256);
         trace!("handle_generic_exception {:?}", a);
         acknowledge();
 
-        let kcb = get_kcb();
+        let kcb = per_core();
+        kcb.enter_irq(a.cs & 0x3 == 0x3);
+        crate::cputime::enter_kernel(kcb.arch.id());
+        super::irqstats::count(kcb.arch.id(), a.vector);
 
         // If we have an active process we should do scheduler activations:
         // TODO(scheduling): do proper masking based on some VCPU mask
@@ -594,22 +752,23 @@ pub extern "C" fn handle_generic_exception(a: ExceptionArguments) -> ! {
             let p = plock.as_mut().unwrap();
 
             let resumer = {
-                let was_disabled = {
-                    trace!("vcpu state is: pc_disabled {:?}", p.vcpu().pc_disabled);
-                    let was_disabled = p.vcpu().upcalls_disabled(VAddr::from(a.rip));
-                    p.vcpu().disable_upcalls();
-                    was_disabled
-                };
+                trace!(
+                    "vcpu state is: pc_disabled {:?}",
+                    p.vcpu().critical_region()
+                );
+                let was_disabled = p.vcpu().upcalls_disabled(VAddr::from(a.rip));
 
                 if was_disabled {
-                    // Resume to the current save area...
-                    warn!("Upcalling while disabled");
+                    // Deliver it once user-space leaves the critical section,
+                    // resume to the current save area until then...
+                    super::vcpu::defer(kcb, a.vector, a.exception);
                     kcb_resume_handle(kcb)
                 } else {
+                    p.vcpu().disable_upcalls();
                     // Copy CURRENT_SAVE_AREA to process enabled save area
                     // then resume in the upcall handler
                     kcb.arch.save_area.as_ref().map(|sa| {
-                        p.vcpu().enabled_state = **sa;
+                        p.vcpu().set_enabled_state(&**sa);
                     });
 
                     p.upcall(a.vector, a.exception)
@@ -630,13 +789,13 @@ pub extern "C" fn handle_generic_exception(a: ExceptionArguments) -> ! {
         } else if a.vector == 0x3 {
             dbg_handler(&a);
         } else if a.vector == TLB_WORK_PENDING.into() {
-            let kcb = get_kcb();
+            let kcb = per_core();
             trace!("got an interrupt {:?}", kcb.arch.id());
             super::tlb::dequeue(kcb.arch.id());
 
             if kcb.arch.has_executor() {
                 // Return immediately
-                kcb.tlb_time += x86::time::rdtsc() - start;
+                kcb.add_tlb_time(x86::time::rdtsc() - start);
                 kcb_iret_handle(kcb).resume()
             } else {
                 // Go to scheduler instead
@@ -646,7 +805,7 @@ pub extern "C" fn handle_generic_exception(a: ExceptionArguments) -> ! {
             // nr::KernelNode::synchronize(); /* TODO: Do we need this?
             super::tlb::dequeue(kcb.arch.id());
 
-            let kcb = get_kcb();
+            let kcb = per_core();
             if kcb.arch.has_executor() {
                 kcb_iret_handle(kcb).resume()
             } else {
@@ -685,72 +844,39 @@ pub unsafe fn register_handler(
     //handlers[vector] = handler;
 }
 
-/// Initialize IO APICs by enumerating them
-/// and making sure the device registers are mapped
-/// in the kernel-space.
-pub fn ioapic_initialize() {
-    let ioapic_len = atopology::MACHINE_TOPOLOGY.io_apics().count();
-    /*crate::memory::KernelAllocator::try_refill_tcache(4 * ioapic_len, 0)
-    .expect("Refill didn't work");*/
-
-    let kcb = get_kcb();
-
-    for io_apic in atopology::MACHINE_TOPOLOGY.io_apics() {
-        info!("Initialize IO APIC {:?}", io_apic);
-
-        let paddr = PAddr::from(io_apic.address as u64);
-        let ioapic_frame = Frame::new(paddr, BASE_PAGE_SIZE, 0);
-        let vbase = PAddr::from(KERNEL_BASE);
-        kcb.arch
-            .init_vspace()
-            .map_identity_with_offset(
-                vbase,
-                ioapic_frame.base,
-                ioapic_frame.size(),
-                MapAction::ReadWriteKernel,
-            )
-            .expect("Can't create APIC mapping?");
-    }
-}
-
-/// Establishes a route for a GSI on the IOAPIC.
+/// Runs `f` (a soft IRQ handler) with interrupts enabled, returns true if
+/// an interrupt arrived in the meantime.
 ///
-/// # TODO
-/// Currently this just enables everything and routes it to
-/// core 0. This is because, we should probably just support MSI(X)
-/// and don't invest a lot in legacy interrupts...
-pub fn ioapic_establish_route(_gsi: u64, _core: u64) {
-    use crate::memory::vspace::MapAction;
-    use crate::memory::{paddr_to_kernel_vaddr, PAddr};
-
-    for io_apic in atopology::MACHINE_TOPOLOGY.io_apics() {
-        let addr = PAddr::from(io_apic.address as u64);
-
-        let mut inst =
-            unsafe { x86::apic::ioapic::IoApic::new(paddr_to_kernel_vaddr(addr).as_usize()) };
-        trace!(
-            "This IOAPIC supports {} Interrupts",
-            inst.supported_interrupts()
-        );
-
-        for i in 0..inst.supported_interrupts() {
-            let gsi = io_apic.global_irq_base + i as u32;
-            if gsi < 16 {
-                trace!(
-                    "Enable irq {} which maps to GSI#{}",
-                    i,
-                    io_apic.global_irq_base + i as u32
-                );
-                if i != 2 && i != 1 {
-                    inst.enable(i, 0);
-                }
-            }
-        }
+/// An interrupt in kernel mode would overwrite the save area of the core,
+/// so while `f` runs `isr.S` only notes the vector of the first interrupt
+/// and returns to `f` with interrupts disabled. Afterwards we acknowledge it
+/// and send the vector to ourselves again, the core takes it as usual once
+/// it leaves the kernel (a level-triggered device interrupt may arrive
+/// twice).
+pub fn with_interrupts(f: fn()) -> bool {
+    let kcb = per_core();
+    kcb.arch
+        .nested_irq
+        .store(IRQ_WINDOW_OPEN, Ordering::Relaxed);
+    enable();
+    f();
+    disable();
+    let vector = kcb
+        .arch
+        .nested_irq
+        .swap(IRQ_WINDOW_CLOSED, Ordering::Relaxed);
+    if vector == IRQ_WINDOW_OPEN {
+        return false;
     }
+
+    trace!("Interrupt {} arrived during a soft IRQ", vector);
+    acknowledge();
+    super::tlb::send_self_ipi(vector as u8);
+    true
 }
 
-fn acknowledge() {
-    let kcb = get_kcb();
+pub(crate) fn acknowledge() {
+    let kcb = per_core();
     let mut apic = kcb.arch.apic();
     apic.eoi();
 }
@@ -0,0 +1,72 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Interrupt statistics.
+//!
+//! Every core counts the interrupts and exceptions it takes per vector
+//! ([`count`]). The counts of all cores are printed with the stats system
+//! call and can be queried one by one (`System::irq_count`), e.g., to check
+//! that the MSIs of a device end up on the right core or to spot interrupt
+//! storms while bringing up a driver.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use log::info;
+use x86::irq::EXCEPTIONS;
+
+use super::irq::{IDT_SIZE, MLNR_GC_INIT, TLB_WORK_PENDING};
+use super::MAX_CORES;
+use crate::error::KError;
+
+const VECTOR_INIT: AtomicU64 = AtomicU64::new(0);
+const CORE_INIT: [AtomicU64; IDT_SIZE] = [VECTOR_INIT; IDT_SIZE];
+static COUNTS: [[AtomicU64; IDT_SIZE]; MAX_CORES] = [CORE_INIT; MAX_CORES];
+
+/// What a vector is used for.
+fn vector_name(vector: usize) -> &'static str {
+    match vector {
+        0..=31 => EXCEPTIONS.get(vector).map_or("exception", |e| e.mnemonic),
+        v if v == apic::TSC_TIMER_VECTOR as usize => "timer",
+        v if v == TLB_WORK_PENDING as usize => "tlb/call IPI",
+        v if v == MLNR_GC_INIT as usize => "gc IPI",
+        32..=249 => "device",
+        _ => "spurious",
+    }
+}
+
+/// Counts an interrupt (or exception) `vector` on `core`.
+#[inline(always)]
+pub fn count(core: usize, vector: u64) {
+    // Only the core itself writes its counts
+    let counter = &COUNTS[core][vector as usize];
+    counter.store(counter.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+}
+
+/// How many interrupts `vector` `core` took so far.
+pub fn get(core: usize, vector: u64) -> Result<u64, KError> {
+    let counts = COUNTS
+        .get(core)
+        .ok_or(KError::InvalidSyscallArgument1 { a: core as u64 })?;
+    let counter = counts
+        .get(vector as usize)
+        .ok_or(KError::InvalidSyscallArgument2 { a: vector })?;
+    Ok(counter.load(Ordering::Relaxed))
+}
+
+/// Prints the counts of all vectors that were taken at least once.
+pub fn report() {
+    for (core, counts) in COUNTS.iter().enumerate() {
+        for (vector, counter) in counts.iter().enumerate() {
+            let n = counter.load(Ordering::Relaxed);
+            if n > 0 {
+                info!(
+                    "IRQ core {} vector {} ({}): {}",
+                    core,
+                    vector,
+                    vector_name(vector),
+                    n
+                );
+            }
+        }
+    }
+}
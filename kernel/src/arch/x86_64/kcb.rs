@@ -9,8 +9,9 @@ use alloc::sync::Arc;
 use core::cell::{RefCell, RefMut};
 use core::pin::Pin;
 use core::ptr;
+use core::sync::atomic::AtomicU64;
 
-use apic::x2apic::X2APICDriver;
+use apic::LocalApic;
 use arrayvec::ArrayVec;
 use cnr::{Replica as MlnrReplica, ReplicaToken as MlnrReplicaToken};
 use log::trace;
@@ -22,7 +23,7 @@ use x86::msr::{wrmsr, IA32_KERNEL_GSBASE};
 use crate::cnrfs::MlnrKernelNode;
 use crate::error::KError;
 use crate::fs::{FileSystem, MlnrFS};
-use crate::kcb::{ArchSpecificKcb, Kcb};
+use crate::kcb::{ArchSpecificKcb, Kcb, LocalCore};
 use crate::nrproc::NrProcess;
 use crate::process::Pid;
 use crate::process::MAX_PROCESSES;
@@ -53,6 +54,9 @@ pub fn try_get_kcb<'a>() -> Option<&'a mut Kcb<Arch86Kcb>> {
 
 /// Retrieve the KCB by reading the gs register.
 ///
+/// Prefer [`per_core`] for read-only access, or [`LocalCore::kcb_mut`] if
+/// mutable access is required.
+///
 /// # Panic
 /// This will fail in case the KCB is not yet set (i.e., early on during
 /// initialization).
@@ -65,6 +69,26 @@ pub fn get_kcb<'a>() -> &'a mut Kcb<Arch86Kcb> {
     }
 }
 
+/// Shared access to the KCB of the current core.
+///
+/// # Panic
+/// This will fail in case the KCB is not yet set (i.e., early on during
+/// initialization).
+pub fn per_core<'a>() -> &'a Kcb<Arch86Kcb> {
+    unsafe {
+        let kcb = segmentation::rdgsbase() as *const Kcb<Arch86Kcb>;
+        assert!(kcb != ptr::null(), "KCB not found in gs register.");
+        &*kcb
+    }
+}
+
+impl LocalCore {
+    /// Mutable access to the KCB of the current core.
+    pub fn kcb_mut(&mut self) -> &mut Kcb<Arch86Kcb> {
+        get_kcb()
+    }
+}
+
 /// Installs the KCB by setting storing a pointer to it in the `gs`
 /// register.
 ///
@@ -104,8 +128,15 @@ pub struct Arch86Kcb {
     /// here).
     pub save_area: Option<Pin<Box<kpi::arch::SaveArea>>>,
 
+    /// Vector of the interrupt that arrived while soft IRQs ran with
+    /// interrupts enabled (`irq::IRQ_WINDOW_OPEN` until one arrives,
+    /// `irq::IRQ_WINDOW_CLOSED` outside of soft IRQs), written by `isr.S`.
+    pub(crate) nested_irq: AtomicU64,
+
     /// A handle to the core-local interrupt driver.
-    pub(crate) apic: RefCell<X2APICDriver>,
+    ///
+    /// Set once the page-tables can be modified (see `lapic::init`).
+    apic: RefCell<Option<LocalApic>>,
 
     /// A per-core GdtTable
     pub(crate) gdt: GdtTable,
@@ -161,6 +192,11 @@ pub struct Arch86Kcb {
     /// This member should probably not be touched from normal code.
     unrecoverable_fault_stack: Option<OwnedStack>,
 
+    /// The stack the CPU switches to for NMIs, they can arrive anywhere
+    /// (e.g., in `syscall_enter` before it switched away from the user
+    /// stack, see `set_interrupt_stacks`).
+    nmi_stack: Option<OwnedStack>,
+
     /// A handle to the syscall stack memory location.
     ///
     /// We switch rsp/rbp to this stack in `exec.S`.
@@ -172,26 +208,26 @@ pub struct Arch86Kcb {
 static_assertions::const_assert_eq!(memoffset::offset_of!(Arch86Kcb, syscall_stack_top), 0);
 // The `save_area` entry must be at offset 8 of KCB (for assembly code)
 static_assertions::const_assert_eq!(memoffset::offset_of!(Arch86Kcb, save_area), 8);
+// The `nested_irq` entry must be at offset 16 of KCB (for assembly code)
+static_assertions::const_assert_eq!(memoffset::offset_of!(Arch86Kcb, nested_irq), 16);
 
 impl Arch86Kcb {
-    pub(crate) fn new(
-        kernel_args: &'static KernelArgs,
-        apic: X2APICDriver,
-        init_vspace: PageTable,
-    ) -> Arch86Kcb {
+    pub(crate) fn new(kernel_args: &'static KernelArgs, init_vspace: PageTable) -> Arch86Kcb {
         Arch86Kcb {
             kernel_args,
             syscall_stack_top: ptr::null_mut(),
-            apic: RefCell::new(apic),
+            apic: RefCell::new(None),
             gdt: Default::default(),
             tss: TaskStateSegment::new(),
             idt: Default::default(),
             current_executor: None, // We don't have an executor to schedule initially
             save_area: None,
+            nested_irq: AtomicU64::new(super::irq::IRQ_WINDOW_CLOSED),
             init_vspace: RefCell::new(init_vspace),
             interrupt_stack: None,
             syscall_stack: None,
             unrecoverable_fault_stack: None,
+            nmi_stack: None,
             cnr_replica: None,
             cnrfs: None,
             id: 0,
@@ -200,8 +236,14 @@ impl Arch86Kcb {
         }
     }
 
-    pub fn apic(&self) -> RefMut<X2APICDriver> {
-        self.apic.borrow_mut()
+    pub fn apic(&self) -> RefMut<LocalApic> {
+        RefMut::map(self.apic.borrow_mut(), |apic| {
+            apic.as_mut().expect("Local APIC not initialized")
+        })
+    }
+
+    pub fn set_apic(&mut self, apic: LocalApic) {
+        *self.apic.get_mut() = Some(apic);
     }
 
     pub fn init_vspace(&self) -> RefMut<PageTable> {
@@ -243,9 +285,16 @@ impl Arch86Kcb {
         &mut self,
         new_executor: Box<Ring3Executor>,
     ) -> Option<Box<Ring3Executor>> {
+        crate::cputime::switch(self.id(), Some(new_executor.pid));
         self.current_executor.replace(new_executor)
     }
 
+    /// Removes the current process from the core (e.g., after it migrated).
+    pub fn take_current_executor(&mut self) -> Option<Box<Ring3Executor>> {
+        crate::cputime::switch(self.id(), None);
+        self.current_executor.take()
+    }
+
     pub fn has_executor(&self) -> bool {
         self.current_executor.is_some()
     }
@@ -258,7 +307,20 @@ impl Arch86Kcb {
         Ok(p)
     }
 
-    pub fn set_interrupt_stacks(&mut self, ex_stack: OwnedStack, fault_stack: OwnedStack) {
+    pub fn current_executor_mut(&mut self) -> Result<&mut Box<Ring3Executor>, KError> {
+        let p = self
+            .current_executor
+            .as_mut()
+            .ok_or(KError::ProcessNotSet)?;
+        Ok(p)
+    }
+
+    pub fn set_interrupt_stacks(
+        &mut self,
+        ex_stack: OwnedStack,
+        fault_stack: OwnedStack,
+        nmi_stack: OwnedStack,
+    ) {
         // Add the stack-top to the TSS so the CPU ends up switching
         // to this stack on an interrupt
         debug_assert_eq!(ex_stack.base() as u64 % 16, 0, "Stack not 16-byte aligned");
@@ -270,6 +332,9 @@ impl Arch86Kcb {
             "Stack not 16-byte aligned"
         );
         self.tss.set_ist(0, fault_stack.base() as u64);
+        // ist[1] for NMIs
+        debug_assert_eq!(nmi_stack.base() as u64 % 16, 0, "Stack not 16-byte aligned");
+        self.tss.set_ist(1, nmi_stack.base() as u64);
 
         // Link TSS in Gdt
         // It's important to only construct the GdtTable
@@ -279,6 +344,17 @@ impl Arch86Kcb {
 
         self.interrupt_stack = Some(ex_stack);
         self.unrecoverable_fault_stack = Some(fault_stack);
+        self.nmi_stack = Some(nmi_stack);
+    }
+
+    /// Returns one of the kernel stacks of this core (if it's set).
+    pub fn kernel_stack(&self, kind: kpi::system::KernelStack) -> Option<&OwnedStack> {
+        match kind {
+            kpi::system::KernelStack::Syscall => self.syscall_stack.as_ref(),
+            kpi::system::KernelStack::Interrupt => self.interrupt_stack.as_ref(),
+            kpi::system::KernelStack::Fault => self.unrecoverable_fault_stack.as_ref(),
+            kpi::system::KernelStack::Nmi => self.nmi_stack.as_ref(),
+        }
     }
 
     pub fn set_syscall_stack(&mut self, stack: OwnedStack) {
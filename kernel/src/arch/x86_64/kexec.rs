@@ -0,0 +1,798 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Soft-reboot into a new kernel without going through the firmware (like
+//! Linux' kexec, `System::kexec`).
+//!
+//! [`kexec`] does what the bootloader does, except that it can't ask UEFI
+//! for memory:
+//!
+//! - It picks a physical region (2 MiB aligned, in memory that was
+//!   conventional when we booted) for the image of the new kernel, its init
+//!   stack, its `KernelArgs` and its binary (module `kernel`). Like the
+//!   bootloader it picks a random place (with randomness from `entropy`)
+//!   unless we booted with `kaslr=off`.
+//! - It prepares a copy of that region in memory outside of it, since the
+//!   old kernel still uses the region: the relocated image, the arguments
+//!   and the binary. The page-tables of the new kernel are built like the
+//!   bootloader builds them (physical memory mapped 1:1 and at
+//!   `KERNEL_BASE`, the image with the rights of its segments), also outside
+//!   of the region. The memory map of the new kernel is the one we got with
+//!   the memory of the old kernel as conventional memory again.
+//! - It stops the devices (see `devices::stop_all`) and parks the other
+//!   cores. Every core stops its performance counters and loads an empty IDT
+//!   (the copy overwrites the old one), then `kexec.S` switches to the new
+//!   page-tables, copies the region in place and jumps to the new kernel.
+//!
+//! The new kernel boots as if it came from the bootloader: it starts the app
+//! cores again (INIT/SIPI gets them out of the old kernel), finds the devices
+//! and reads the ACPI tables. It gets the modules of the first boot, the new
+//! binary replaces `kernel` (services replaced with `Process::reload` run
+//! their first binary again). Its `module_source` is `Kexec`.
+//!
+//! What doesn't carry over: the framebuffer (the bootloader got it from UEFI,
+//! the new kernel boots without a progress bar). The UEFI runtime services
+//! stay where the first boot relocated them.
+
+use alloc::vec::Vec;
+use core::mem::{size_of, ManuallyDrop};
+use core::ops::Range;
+use core::time::Duration;
+
+use bootloader_shared::{
+    Kaslr, KernelArgs, Module, ModuleSource, RandomSource, KERNEL_ARGS, KERNEL_ELF, KERNEL_PT,
+    KERNEL_STACK, MODULE, UEFI_MEMORY_MAP,
+};
+use fallible_collections::FallibleVec;
+use kpi::process::Capabilities;
+use log::{info, warn};
+use uefi::table::boot::{MemoryDescriptor, MemoryType};
+use x86::bits64::paging::*;
+
+use crate::error::KError;
+use crate::memory::vspace::MapAction;
+use crate::memory::{Frame, KernelAllocator, PhysicalPageProvider, KERNEL_BASE};
+use crate::nr;
+use crate::prelude::overlaps;
+use crate::process::{find_module, Pid};
+use crate::round_up;
+
+use super::kcb;
+use super::memory::paddr_to_kernel_vaddr;
+use super::wx::Segments;
+
+#[cfg(target_os = "none")]
+global_asm!(include_str!("kexec.S"), options(att_syntax));
+
+extern "C" {
+    fn nrk_kexec_jump(
+        pml4: u64,
+        chunks: *const Chunk,
+        count: u64,
+        stack: u64,
+        entry: u64,
+        arg: u64,
+    ) -> !;
+}
+
+/// Pages of the init stack of the new kernel, the first one is a guard page
+/// (same as the bootloader).
+const STACK_PAGES: usize = 768;
+
+/// We don't put the new kernel below 16 MiB (same as the bootloader).
+const MIN_ADDRESS: u64 = 16 * 1024 * 1024;
+
+/// How many more regions the memory map of the new kernel can have than
+/// ours (we cut regions out of conventional memory).
+const EXTRA_DESCRIPTORS: usize = 128;
+
+/// How long we wait for the other cores to stop.
+const PARK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A piece of the region of the new kernel that `kexec.S` copies in place.
+#[repr(C)]
+struct Chunk {
+    /// Kernel address of the copy.
+    src: u64,
+    /// Physical address (mapped 1:1 in the new page-tables).
+    dst: u64,
+    len: u64,
+}
+
+/// Where things are in the region of the new kernel (offsets).
+struct Layout {
+    stack: Range<u64>,
+    args: Range<u64>,
+    module: Range<u64>,
+}
+
+impl Layout {
+    fn new(image: u64, descriptors: usize, cmdline: usize, binary: usize) -> Layout {
+        let image = round_up!(image, LARGE_PAGE_SIZE as u64);
+        let stack = image..image + (STACK_PAGES * BASE_PAGE_SIZE) as u64;
+        let args_size =
+            Layout::memory_map_offset() + descriptors * size_of::<MemoryDescriptor>() + cmdline;
+        let args = stack.end..stack.end + round_up!(args_size as u64, BASE_PAGE_SIZE as u64);
+        let module = args.end..args.end + round_up!(binary as u64, BASE_PAGE_SIZE as u64);
+        Layout {
+            stack,
+            args,
+            module,
+        }
+    }
+
+    /// Where the memory map is, relative to the `KernelArgs`.
+    const fn memory_map_offset() -> usize {
+        round_up!(size_of::<KernelArgs>(), 64)
+    }
+
+    fn size(&self) -> u64 {
+        self.module.end
+    }
+}
+
+/// Large pages outside of the region of the new kernel, we give them back
+/// if we don't jump.
+struct Pages {
+    region: Range<u64>,
+    pages: Vec<Frame>,
+}
+
+impl Pages {
+    /// A zeroed large page that isn't in the region.
+    fn allocate(&mut self) -> Result<Frame, KError> {
+        // Pages in the region, we give them back once we have one
+        let mut rejected: Vec<Frame> = Vec::new();
+        let page = loop {
+            KernelAllocator::try_refill_tcache(0, 1)?;
+            let frame = kcb::per_core().mem_manager().allocate_large_page()?;
+            if overlaps(&(frame.base.as_u64()..frame.end().as_u64()), &self.region) {
+                rejected.try_push(frame)?;
+            } else {
+                break frame;
+            }
+        };
+        for frame in rejected {
+            release(frame);
+        }
+
+        let mut page = page;
+        unsafe { page.zero() };
+        self.pages.try_push(page)?;
+        Ok(page)
+    }
+}
+
+impl Drop for Pages {
+    fn drop(&mut self) {
+        for frame in self.pages.drain(..) {
+            release(frame);
+        }
+    }
+}
+
+fn release(frame: Frame) {
+    // Goes back to our cache, can't fail unless it's full (then we leak it)
+    let _r = kcb::per_core().mem_manager().release_large_page(frame);
+}
+
+/// The copy of the region of the new kernel (in large pages).
+struct Staged(Vec<Frame>);
+
+impl Staged {
+    /// Writes `bytes` at `offset` in the region.
+    fn write(&self, offset: u64, bytes: &[u8]) {
+        let mut done = 0;
+        while done < bytes.len() {
+            let at = offset as usize + done;
+            let page = &self.0[at / LARGE_PAGE_SIZE];
+            let len = core::cmp::min(LARGE_PAGE_SIZE - at % LARGE_PAGE_SIZE, bytes.len() - done);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    bytes[done..].as_ptr(),
+                    (page.kernel_vaddr() + at % LARGE_PAGE_SIZE).as_mut_ptr::<u8>(),
+                    len,
+                );
+            }
+            done += len;
+        }
+    }
+
+    /// Writes `value` at `offset` in the region.
+    fn write_value<T>(&self, offset: u64, value: &T) {
+        let bytes =
+            unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+        self.write(offset, bytes);
+    }
+}
+
+/// Loads the new kernel into the staged region.
+struct Loader<'a> {
+    staged: &'a Staged,
+    /// Where the new kernel runs (`KERNEL_BASE` + its physical address).
+    offset: u64,
+}
+
+impl<'a> elfloader::ElfLoader for Loader<'a> {
+    fn allocate(
+        &mut self,
+        _load_headers: elfloader::LoadableHeaders,
+    ) -> Result<(), elfloader::ElfLoaderErr> {
+        // The layout has room for the segments
+        Ok(())
+    }
+
+    fn load(
+        &mut self,
+        _flags: elfloader::Flags,
+        base: u64,
+        region: &[u8],
+    ) -> Result<(), elfloader::ElfLoaderErr> {
+        self.staged.write(base, region);
+        Ok(())
+    }
+
+    fn relocate(
+        &mut self,
+        entry: &elfloader::Rela<elfloader::P64>,
+    ) -> Result<(), elfloader::ElfLoaderErr> {
+        use elfloader::TypeRela64;
+        if let TypeRela64::R_RELATIVE = TypeRela64::from(entry.get_type()) {
+            let value = self.offset + entry.get_addend();
+            self.staged.write_value(entry.get_offset(), &value);
+            Ok(())
+        } else {
+            Err(elfloader::ElfLoaderErr::UnsupportedRelocationEntry)
+        }
+    }
+
+    fn make_readonly(&mut self, _base: u64, _size: usize) -> Result<(), elfloader::ElfLoaderErr> {
+        // The new kernel write-protects itself (see `wx`)
+        Ok(())
+    }
+}
+
+/// The page-tables of the new kernel.
+struct Tables<'a> {
+    pages: &'a mut Pages,
+    /// The large pages the tables are in and how much of the last one we
+    /// used.
+    frames: Vec<Frame>,
+    used: usize,
+    pml4: PAddr,
+}
+
+impl<'a> Tables<'a> {
+    fn new(pages: &'a mut Pages) -> Result<Tables<'a>, KError> {
+        let mut tables = Tables {
+            pages,
+            frames: Vec::new(),
+            used: LARGE_PAGE_SIZE,
+            pml4: PAddr::zero(),
+        };
+        tables.pml4 = tables.table()?;
+        Ok(tables)
+    }
+
+    /// A zeroed page for a table.
+    fn table(&mut self) -> Result<PAddr, KError> {
+        if self.used == LARGE_PAGE_SIZE {
+            let frame = self.pages.allocate()?;
+            self.frames.try_push(frame)?;
+            self.used = 0;
+        }
+        let frame = self.frames.last().expect("Have a page for tables");
+        let table = frame.base + self.used;
+        self.used += BASE_PAGE_SIZE;
+        Ok(table)
+    }
+
+    /// Maps the physical memory `[pbase, pbase + size)` at `vbase`, with the
+    /// largest pages that fit.
+    fn map(&mut self, vbase: u64, pbase: u64, size: u64, rights: MapAction) -> Result<(), KError> {
+        let aligned = |vaddr: u64, paddr: u64, page: usize, left: u64| {
+            vaddr % page as u64 == 0 && paddr % page as u64 == 0 && left >= page as u64
+        };
+
+        let mut done = 0;
+        while done < size {
+            let (vaddr, paddr, left) = (vbase + done, pbase + done, size - done);
+            let va = VAddr::from(vaddr);
+
+            let pml4 = unsafe { table::<PML4>(self.pml4) };
+            if !pml4[pml4_index(va)].is_present() {
+                let pdpt = self.table()?;
+                pml4[pml4_index(va)] = PML4Entry::new(pdpt, PML4Flags::P | PML4Flags::RW);
+            }
+            let pdpt = unsafe { table::<PDPT>(pml4[pml4_index(va)].address()) };
+            if aligned(vaddr, paddr, HUGE_PAGE_SIZE, left) {
+                pdpt[pdpt_index(va)] = PDPTEntry::new(
+                    PAddr::from(paddr),
+                    PDPTFlags::P | PDPTFlags::PS | rights.to_pdpt_rights(),
+                );
+                done += HUGE_PAGE_SIZE as u64;
+                continue;
+            }
+
+            if !pdpt[pdpt_index(va)].is_present() {
+                let pd = self.table()?;
+                pdpt[pdpt_index(va)] = PDPTEntry::new(pd, PDPTFlags::P | PDPTFlags::RW);
+            }
+            let pd = unsafe { table::<PD>(pdpt[pdpt_index(va)].address()) };
+            if aligned(vaddr, paddr, LARGE_PAGE_SIZE, left) {
+                pd[pd_index(va)] = PDEntry::new(
+                    PAddr::from(paddr),
+                    PDFlags::P | PDFlags::PS | rights.to_pd_rights(),
+                );
+                done += LARGE_PAGE_SIZE as u64;
+                continue;
+            }
+
+            if !pd[pd_index(va)].is_present() {
+                let pt = self.table()?;
+                pd[pd_index(va)] = PDEntry::new(pt, PDFlags::P | PDFlags::RW);
+            }
+            let pt = unsafe { table::<PT>(pd[pd_index(va)].address()) };
+            pt[pt_index(va)] = PTEntry::new(PAddr::from(paddr), PTFlags::P | rights.to_pt_rights());
+            done += BASE_PAGE_SIZE as u64;
+        }
+        Ok(())
+    }
+}
+
+/// The table at `paddr` (one of ours, accessed through the kernel's map of
+/// physical memory).
+unsafe fn table<T>(paddr: PAddr) -> &'static mut T {
+    &mut *paddr_to_kernel_vaddr(paddr).as_mut_ptr::<T>()
+}
+
+/// How the bootloader maps a region of the memory map (see
+/// `map_physical_memory`): the rights of the 1:1 map and of the map at
+/// `KERNEL_BASE`.
+fn rights(ty: MemoryType) -> (MapAction, MapAction) {
+    let identity = match ty {
+        MemoryType::LOADER_CODE
+        | MemoryType::BOOT_SERVICES_CODE
+        | MemoryType::RUNTIME_SERVICES_CODE
+        | MemoryType::PAL_CODE => MapAction::ReadExecuteKernel,
+        MemoryType::LOADER_DATA
+        | MemoryType::BOOT_SERVICES_DATA
+        | MemoryType::RUNTIME_SERVICES_DATA
+        | MemoryType::ACPI_RECLAIM
+        | MemoryType::ACPI_NON_VOLATILE
+        | MemoryType::MMIO
+        | MemoryType::MMIO_PORT_SPACE
+        | MemoryType::PERSISTENT_MEMORY
+        | MemoryType(KERNEL_PT)
+        | MemoryType(KERNEL_STACK)
+        | MemoryType(UEFI_MEMORY_MAP) => MapAction::ReadWriteKernel,
+        MemoryType::CONVENTIONAL => MapAction::ReadWriteExecuteKernel,
+        MemoryType(KERNEL_ELF) | MemoryType(KERNEL_ARGS) | MemoryType(MODULE) => {
+            MapAction::ReadKernel
+        }
+        _ => MapAction::None,
+    };
+    let offset = match ty {
+        MemoryType::CONVENTIONAL
+        | MemoryType::BOOT_SERVICES_DATA
+        | MemoryType::LOADER_DATA
+        | MemoryType::RUNTIME_SERVICES_CODE
+        | MemoryType::RUNTIME_SERVICES_DATA
+        | MemoryType(KERNEL_PT)
+        | MemoryType(MODULE)
+        | MemoryType(KERNEL_ARGS) => identity,
+        _ => MapAction::None,
+    };
+    (identity, offset)
+}
+
+/// Was the region conventional memory when we booted? (Regions of the
+/// bootloader are too once we jump.)
+fn is_free(ty: MemoryType) -> bool {
+    matches!(
+        ty,
+        MemoryType::CONVENTIONAL
+            | MemoryType(KERNEL_ELF)
+            | MemoryType(KERNEL_PT)
+            | MemoryType(KERNEL_STACK)
+            | MemoryType(KERNEL_ARGS)
+            | MemoryType(UEFI_MEMORY_MAP)
+    )
+}
+
+fn end(region: &MemoryDescriptor) -> u64 {
+    region.phys_start + region.page_count * BASE_PAGE_SIZE as u64
+}
+
+/// A 2 MiB aligned place in conventional memory (at or above `MIN_ADDRESS`)
+/// where `size` bytes fit: a random one if `randomize` is set (like the
+/// bootloader, see `bootloader/src/kaslr.rs`), the lowest one otherwise.
+///
+/// Returns the place and how we picked it.
+fn find_place(
+    map: &[MemoryDescriptor],
+    size: u64,
+    randomize: bool,
+) -> Result<(u64, Kaslr), KError> {
+    let align = LARGE_PAGE_SIZE as u64;
+    // (first place, number of places) of every region the kernel fits in
+    let places = || {
+        map.iter()
+            .filter(|region| region.ty == MemoryType::CONVENTIONAL)
+            .filter_map(move |region| {
+                let start = round_up!(region.phys_start.max(MIN_ADDRESS), align);
+                (end(region) >= start + size)
+                    .then(|| (start, (end(region) - start - size) / align + 1))
+            })
+    };
+    let slots: u64 = places().map(|(_first, count)| count).sum();
+    if slots == 0 {
+        return Err(KError::NoPlaceForKernel {
+            size: size as usize,
+        });
+    }
+    if !randomize {
+        let lowest = places().map(|(first, _count)| first).min();
+        return Ok((lowest.unwrap_or(MIN_ADDRESS), Kaslr::Disabled));
+    }
+
+    let mut pick = crate::entropy::next_u64() % slots;
+    for (first, count) in places() {
+        if pick < count {
+            let source = RandomSource::Kernel;
+            return Ok((first + pick * align, Kaslr::Randomized { source, slots }));
+        }
+        pick -= count;
+    }
+    unreachable!("Picked a place out of range");
+}
+
+/// The memory map for the new kernel: ours with the bootloader's regions
+/// as conventional memory and the `regions` (base, size, type) cut out of
+/// conventional memory.
+fn memory_map(
+    map: &[MemoryDescriptor],
+    regions: &[(u64, u64, u32)],
+) -> Result<Vec<MemoryDescriptor>, KError> {
+    let mut new_map: Vec<MemoryDescriptor> = Vec::try_with_capacity(map.len() + 2 * regions.len())?;
+    for region in map {
+        let mut region = *region;
+        if is_free(region.ty) {
+            region.ty = MemoryType::CONVENTIONAL;
+        }
+        new_map.try_push(region)?;
+    }
+
+    for (base, size, ty) in regions.iter().copied() {
+        let idx = new_map
+            .iter()
+            .position(|r| {
+                r.ty == MemoryType::CONVENTIONAL && r.phys_start <= base && base + size <= end(r)
+            })
+            .ok_or(KError::ReservedMemory { base })?;
+        let region = new_map[idx];
+
+        let mut cut = region;
+        cut.ty = MemoryType(ty);
+        cut.phys_start = base;
+        cut.page_count = size / BASE_PAGE_SIZE as u64;
+        let mut after = region;
+        after.phys_start = base + size;
+        after.page_count = (end(&region) - base - size) / BASE_PAGE_SIZE as u64;
+
+        new_map[idx].page_count = (base - region.phys_start) / BASE_PAGE_SIZE as u64;
+        new_map.try_push(cut)?;
+        new_map.try_push(after)?;
+    }
+    new_map.retain(|region| region.page_count > 0);
+    Ok(new_map)
+}
+
+/// Soft-reboots into the kernel `binary` (an ELF file) with `command_line`.
+///
+/// Only returns (with an error) if it can't prepare the new kernel, the old
+/// one then keeps running.
+pub fn kexec(binary: &[u8], command_line: &str) -> Result<(), KError> {
+    let kernel_args = kcb::per_core().arch.kernel_args();
+    let elf = elfloader::ElfBinary::new(binary).map_err(|_e| KError::UnableToParseElf)?;
+    let mut segments: Segments = Default::default();
+    elf.load(&mut segments)
+        .map_err(|_e| KError::UnableToParseElf)?;
+    let image = segments
+        .load
+        .iter()
+        .map(|(range, _rights)| range.end)
+        .max()
+        .ok_or(KError::UnableToParseElf)?;
+
+    let descriptors = kernel_args.mm_iter.len() + EXTRA_DESCRIPTORS;
+    let layout = Layout::new(image, descriptors, command_line.len(), binary.len());
+    let randomize = kcb::per_core().cmdline.kaslr != "off";
+    let (base, kaslr) = find_place(&kernel_args.mm_iter, layout.size(), randomize)?;
+    let region = base..base + layout.size();
+    let offset = KERNEL_BASE + base;
+    info!(
+        "Loading new kernel at {:#x} -- {:#x}",
+        region.start, region.end
+    );
+
+    let mut pages = Pages {
+        region: region.clone(),
+        pages: Vec::new(),
+    };
+    let mut staged = Staged(Vec::new());
+    for _chunk in (0..layout.size()).step_by(LARGE_PAGE_SIZE) {
+        staged.0.try_push(pages.allocate()?)?;
+    }
+    elf.load(&mut Loader {
+        staged: &staged,
+        offset,
+    })
+    .map_err(|_e| KError::UnableToLoad)?;
+    staged.write(layout.module.start, binary);
+
+    // The memory map without the page-tables, which map it
+    let regions = [
+        (base, round_up!(image, BASE_PAGE_SIZE as u64), KERNEL_ELF),
+        (
+            base + layout.stack.start,
+            layout.stack.end - layout.stack.start,
+            KERNEL_STACK,
+        ),
+        (
+            base + layout.args.start,
+            layout.args.end - layout.args.start,
+            KERNEL_ARGS,
+        ),
+        (
+            base + layout.module.start,
+            layout.module.end - layout.module.start,
+            MODULE,
+        ),
+    ];
+    let map = memory_map(&kernel_args.mm_iter, &regions)?;
+
+    let mut tables = Tables::new(&mut pages)?;
+    for region in map.iter() {
+        let size = region.page_count * BASE_PAGE_SIZE as u64;
+        let covers_apic = region.phys_start <= 0xfee0_0000 && end(region) >= 0xfee0_0000;
+        if region.phys_start == 0 || covers_apic {
+            continue;
+        }
+        let (identity, at_offset) = rights(region.ty);
+        if identity != MapAction::None {
+            tables.map(region.phys_start, region.phys_start, size, identity)?;
+        }
+        if at_offset != MapAction::None {
+            tables.map(
+                KERNEL_BASE + region.phys_start,
+                region.phys_start,
+                size,
+                at_offset,
+            )?;
+        }
+    }
+    for (range, rights) in segments.load.iter() {
+        let size = range.end - range.start;
+        tables.map(offset + range.start, base + range.start, size, *rights)?;
+    }
+    let stack = base + layout.stack.start + BASE_PAGE_SIZE as u64;
+    let stack_size = (STACK_PAGES - 1) * BASE_PAGE_SIZE;
+    tables.map(
+        KERNEL_BASE + stack,
+        stack,
+        stack_size as u64,
+        MapAction::ReadWriteKernel,
+    )?;
+    let pml4 = tables.pml4;
+    let table_pages = core::mem::take(&mut tables.frames);
+    drop(tables);
+
+    let mut all_regions: Vec<(u64, u64, u32)> = Vec::new();
+    all_regions.try_extend_from_slice(&regions[..])?;
+    for frame in table_pages.iter() {
+        all_regions.try_push((frame.base.as_u64(), LARGE_PAGE_SIZE as u64, KERNEL_PT))?;
+    }
+    let map = memory_map(&kernel_args.mm_iter, &all_regions)?;
+    if map.len() > descriptors {
+        return Err(KError::CapacityOverflow);
+    }
+
+    // The arguments, the memory map and the command line
+    let map_start = layout.args.start + Layout::memory_map_offset() as u64;
+    let map_size = map.len() * size_of::<MemoryDescriptor>();
+    let cmdline_start = map_start + map_size as u64;
+    for (idx, region) in map.iter().enumerate() {
+        staged.write_value(
+            map_start + (idx * size_of::<MemoryDescriptor>()) as u64,
+            region,
+        );
+    }
+    staged.write(cmdline_start, command_line.as_bytes());
+    let (map_vaddr, cmdline_vaddr) = (offset + map_start, offset + cmdline_start);
+
+    let mut args = ManuallyDrop::new(KernelArgs::new());
+    // Safe: the new kernel finds these where we tell it (and never frees
+    // them)
+    unsafe {
+        args.mm_iter =
+            Vec::from_raw_parts(map_vaddr as *mut MemoryDescriptor, map.len(), map.len());
+        args.command_line = core::str::from_utf8_unchecked(core::slice::from_raw_parts(
+            cmdline_vaddr as *const u8,
+            command_line.len(),
+        ));
+    }
+    args.mm = (PAddr::from(map_vaddr), map_size);
+    args.pml4 = pml4;
+    args.stack = (PAddr::from(KERNEL_BASE + stack), stack_size);
+    args.kernel_elf_offset = VAddr::from(offset);
+    args.kaslr = kaslr;
+    args.acpi1_rsdp = kernel_args.acpi1_rsdp;
+    args.acpi2_rsdp = kernel_args.acpi2_rsdp;
+    args.smbios_entry = kernel_args.smbios_entry;
+    args.smbios3_entry = kernel_args.smbios3_entry;
+    args.runtime_services = kernel_args.runtime_services;
+    args.module_source = ModuleSource::Kexec;
+    let module = base + layout.module.start;
+    args.modules.push(Module::new(
+        "kernel",
+        VAddr::from(KERNEL_BASE + module),
+        PAddr::from(module),
+        binary.len(),
+    ));
+    for module in kernel_args.modules.iter() {
+        if module.name() != "kernel" && !args.modules.is_full() {
+            args.modules.push(module.clone());
+        }
+    }
+    staged.write_value(layout.args.start, &*args);
+
+    // What `kexec.S` copies
+    let chunk_page = pages.allocate()?;
+    let chunks = chunk_page.kernel_vaddr().as_mut_ptr::<Chunk>();
+    let count = staged.0.len();
+    if count * size_of::<Chunk>() > LARGE_PAGE_SIZE {
+        return Err(KError::CapacityOverflow);
+    }
+    for (idx, page) in staged.0.iter().enumerate() {
+        let at = (idx * LARGE_PAGE_SIZE) as u64;
+        let chunk = Chunk {
+            src: page.kernel_vaddr().as_u64(),
+            dst: base + at,
+            len: core::cmp::min(LARGE_PAGE_SIZE as u64, layout.size() - at),
+        };
+        unsafe { chunks.add(idx).write(chunk) };
+    }
+
+    let entry = offset + elf.entry_point();
+    info!(
+        "Soft-rebooting into a new kernel at {:#x} ({} bytes, {} regions in its memory map)",
+        entry,
+        binary.len(),
+        map.len()
+    );
+    shutdown();
+
+    // Safe: everything the jump needs is outside of the region it
+    // overwrites, the old kernel is gone after it
+    unsafe {
+        nrk_kexec_jump(
+            pml4.as_u64(),
+            chunks,
+            count as u64,
+            offset + layout.stack.end - BASE_PAGE_SIZE as u64,
+            entry,
+            offset + layout.args.start,
+        )
+    }
+}
+
+/// Soft-reboots into `binary` on behalf of process `pid`, which needs the
+/// `KEXEC` capability.
+///
+/// `binary` is a path in the file-system of `pid` if it starts with `/`
+/// (e.g., a kernel that was uploaded over the network), the name of a module
+/// otherwise. Only returns (with an error) if the old kernel keeps running.
+pub fn kexec_binary(pid: Pid, binary: &str, command_line: &str) -> Result<(), KError> {
+    if !nr::KernelNode::capabilities(pid)?.contains(Capabilities::KEXEC) {
+        return Err(KError::MissingCapability);
+    }
+
+    info!("Soft-reboot into {} requested by {}", binary, pid);
+    if binary.starts_with('/') {
+        let image = super::reload::read_binary(pid, binary)?;
+        kexec(&image, command_line)
+    } else {
+        let module = find_module(binary).ok_or(KError::InvalidFile)?;
+        // Safe: Modules stay mapped in the kernel address space
+        kexec(unsafe { module.as_slice() }, command_line)
+    }
+}
+
+/// Stops everything that could write to memory while we copy the new
+/// kernel in place: the devices, the timer and the other cores.
+fn shutdown() {
+    // The log goes to a disk we're about to stop
+    crate::disklog::flush();
+
+    #[cfg(not(feature = "bsp-only"))]
+    park_other_cores();
+
+    super::irq::disable();
+    super::ioapic::disable_all();
+    super::devices::stop_all();
+    super::timer::disable();
+    unsafe { stop_nmis() };
+}
+
+/// Makes sure the core doesn't take an NMI on its way into the new kernel:
+/// the copy overwrites the IDT, the handlers and their (IST) stacks.
+///
+/// The performance counters (profiler, `budget`) raise the only NMIs we
+/// arm, we stop them. Any other NMI hits an empty IDT and resets the
+/// machine instead of running whatever is in memory by then.
+///
+/// # Safety
+/// The core can't handle NMIs (or any exception) afterwards.
+unsafe fn stop_nmis() {
+    super::profile::silence();
+    use x86::bits64::segmentation::Descriptor64;
+    use x86::dtables::{lidt, DescriptorTablePointer};
+
+    let empty: DescriptorTablePointer<Descriptor64> = DescriptorTablePointer {
+        limit: 0,
+        base: core::ptr::null(),
+    };
+    lidt(&empty);
+}
+
+/// Halts all other cores (with interrupts disabled), the new kernel starts
+/// them again.
+#[cfg(not(feature = "bsp-only"))]
+fn park_other_cores() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static PARKED: AtomicUsize = AtomicUsize::new(0);
+
+    let me = kcb::per_core().arch.id();
+    let mut count = 0;
+    for thread in atopology::MACHINE_TOPOLOGY.threads() {
+        if thread.id == me {
+            continue;
+        }
+        count += 1;
+        let parked = super::smp::call_on(
+            thread.id,
+            || {
+                unsafe {
+                    x86::irq::disable();
+                    stop_nmis();
+                }
+                PARKED.fetch_add(1, Ordering::Release);
+                loop {
+                    unsafe {
+                        x86::irq::disable();
+                        x86::halt();
+                    }
+                }
+            },
+            super::smp::Wait::No,
+        );
+        if let Err(e) = parked {
+            warn!("Can't stop core {}: {}", thread.id, e);
+        }
+    }
+
+    let start = rawtime::Instant::now();
+    while PARKED.load(Ordering::Acquire) < count {
+        if start.elapsed() > PARK_TIMEOUT {
+            warn!(
+                "Only {} of {} cores stopped, jumping anyways",
+                PARKED.load(Ordering::Relaxed),
+                count
+            );
+            break;
+        }
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Picks the interface to the local APIC.
+//!
+//! We use the x2APIC (registers are MSRs) if the CPU has one and fall back to
+//! the xAPIC (registers are mapped at the APIC base) otherwise, e.g., on older
+//! machines or QEMU CPU models without x2APIC support. The BSP decides, the
+//! app cores use the same mode.
+
+use core::ptr;
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use apic::x2apic::X2APICDriver;
+use apic::xapic::XAPICDriver;
+use apic::{ApicDriver, LocalApic};
+use driverkit::DriverControl;
+use log::{info, warn};
+use x86::msr::{rdmsr, wrmsr, IA32_APIC_BASE};
+
+use super::memory::{paddr_to_kernel_vaddr, PAddr, BASE_PAGE_SIZE, KERNEL_BASE};
+use super::vspace::page_table::PageTable;
+use crate::memory::reserved::{self, Reserved};
+use crate::memory::vspace::MapAction;
+
+// Offsets of registers in the xAPIC register page, the x2APIC has them in
+// the MSR `0x800 + offset / 16`.
+
+/// LVT timer register.
+pub(super) const LVT_TIMER: usize = 0x320;
+/// LVT performance monitoring counter register.
+const LVT_PMI: usize = 0x340;
+/// Initial count of the APIC timer.
+pub(super) const TIMER_INITIAL_COUNT: usize = 0x380;
+/// Current count of the APIC timer.
+pub(super) const TIMER_CURRENT_COUNT: usize = 0x390;
+/// Divide configuration of the APIC timer.
+pub(super) const TIMER_DIVIDE: usize = 0x3e0;
+
+/// First x2APIC MSR.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Kernel address of the xAPIC registers (0 if we use the x2APIC).
+static XAPIC_REGISTERS: AtomicUsize = AtomicUsize::new(0);
+
+/// BSP flag in `IA32_APIC_BASE`.
+const APIC_BASE_BSP: u64 = 1 << 8;
+
+/// Should the current core use the x2APIC?
+fn use_x2apic() -> bool {
+    let is_bsp = unsafe { rdmsr(IA32_APIC_BASE) } & APIC_BASE_BSP != 0;
+    if !is_bsp {
+        // Follow the BSP (which is initialized first)
+        return XAPIC_REGISTERS.load(Ordering::Relaxed) == 0;
+    }
+
+    let cpuid = x86::cpuid::CpuId::new();
+    let has_x2apic = cpuid.get_feature_info().map_or(false, |f| f.has_x2apic());
+    if !has_x2apic {
+        warn!("No x2APIC, falling back to the xAPIC.");
+    }
+    has_x2apic
+}
+
+/// Constructs the driver for the local APIC of the current core and attaches
+/// it.
+///
+/// For the xAPIC, the register page is mapped in `vspace` (at the usual
+/// `KERNEL_BASE` offset) first.
+pub(super) fn init(vspace: &mut PageTable) -> LocalApic {
+    let mut apic = if use_x2apic() {
+        LocalApic::X2Apic(X2APICDriver::default())
+    } else {
+        let base = PAddr::from(unsafe { rdmsr(IA32_APIC_BASE) } & !0xfff);
+        reserved::check(base, BASE_PAGE_SIZE, Some(Reserved::LocalApic))
+            .expect("Local APIC overlaps reserved memory");
+        vspace
+            .map_identity_with_offset(
+                PAddr::from(KERNEL_BASE),
+                base,
+                BASE_PAGE_SIZE,
+                MapAction::ReadWriteKernel,
+            )
+            .expect("Can't create local APIC mapping?");
+
+        let registers = paddr_to_kernel_vaddr(base);
+        XAPIC_REGISTERS.store(registers.as_usize(), Ordering::Relaxed);
+        // Safe: every core decodes its own APIC at the same physical address
+        // and only touches it through its own driver.
+        let mmio = unsafe {
+            slice::from_raw_parts_mut(
+                registers.as_mut_ptr::<u32>(),
+                BASE_PAGE_SIZE / core::mem::size_of::<u32>(),
+            )
+        };
+        LocalApic::XApic(XAPICDriver::new(mmio))
+    };
+    // Attach the driver to take control of the APIC:
+    apic.attach();
+
+    info!(
+        "{} id: {}, logical_id: {}, version: {:#x}, is bsp: {}",
+        if apic.is_x2apic() { "x2APIC" } else { "xAPIC" },
+        apic.id(),
+        apic.logical_id(),
+        apic.version(),
+        apic.bsp()
+    );
+
+    apic
+}
+
+/// Reads the register at `offset` of the local APIC of the current core.
+///
+/// Doesn't go through the driver (which has no interface for most
+/// registers and may be borrowed).
+pub(super) fn read(offset: usize) -> u32 {
+    let registers = XAPIC_REGISTERS.load(Ordering::Relaxed);
+    unsafe {
+        if registers == 0 {
+            rdmsr(X2APIC_MSR_BASE + (offset >> 4) as u32) as u32
+        } else {
+            ptr::read_volatile((registers + offset) as *const u32)
+        }
+    }
+}
+
+/// Writes `value` to the register at `offset` of the local APIC of the
+/// current core (see [`read`]).
+///
+/// # Safety
+/// Changes the local APIC configuration behind the back of the driver.
+pub(super) unsafe fn write(offset: usize, value: u32) {
+    let registers = XAPIC_REGISTERS.load(Ordering::Relaxed);
+    if registers == 0 {
+        wrmsr(X2APIC_MSR_BASE + (offset >> 4) as u32, value as u64);
+    } else {
+        ptr::write_volatile((registers + offset) as *mut u32, value);
+    }
+}
+
+/// Writes the LVT entry for performance counter overflows.
+///
+/// Doesn't go through the driver (we get here from the NMI handler, with the
+/// driver possibly borrowed).
+///
+/// # Safety
+/// Changes how counter overflows are delivered.
+pub(super) unsafe fn set_lvt_pmi(entry: u64) {
+    write(LVT_PMI, entry as u32);
+}
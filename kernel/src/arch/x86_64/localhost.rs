@@ -0,0 +1,216 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! UDP sockets of processes on the loopback interface (`SystemCall::Net`),
+//! so processes can talk to each other over 127.0.0.1 without a NIC.
+//!
+//! The sockets live on their own interface ([`Network::loopback`]), which the
+//! first [`bind`] brings up; it doesn't depend on the NIC the kernel may
+//! drive (see [`super::network::start`]). Nothing on it needs a timer:
+//! [`send_to`] polls the interface, which delivers the datagram to the
+//! receiving socket right away, and [`recv_from`] doesn't block. Processes
+//! wait for datagrams with `Poll` ([`poll`]), `send_to` wakes them up.
+//!
+//! A socket belongs to the process that bound it, the kernel closes the
+//! sockets of a process when it exits ([`release`]).
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+
+use kpi::io::PollEvents;
+use kpi::net::{Endpoint, LOCALHOST, MAX_DATAGRAM};
+use smoltcp::socket::{Socket, SocketHandle, UdpPacketMetadata, UdpSocket, UdpSocketBuffer};
+use smoltcp::wire::{IpAddress, IpEndpoint};
+
+use crate::error::KError;
+use crate::process::Pid;
+use crate::sync::Mutex;
+
+use super::network::Network;
+
+/// Sockets a process may have open.
+pub const MAX_SOCKETS: usize = 16;
+
+/// Datagrams a socket buffers in each direction.
+const QUEUE_LEN: usize = 8;
+
+/// Ports [`bind`] picks from for port 0.
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+/// The loopback interface and the sockets of processes on it.
+struct Localhost {
+    network: Network,
+    /// Owner and handle of every socket.
+    sockets: BTreeMap<u64, (Pid, SocketHandle)>,
+    /// Id of the next socket.
+    next: u64,
+}
+
+static LOCALHOST: Mutex<Option<Localhost>> = Mutex::new(None);
+
+impl Localhost {
+    /// Is a socket bound to `port`?
+    fn in_use(&self, port: u16) -> bool {
+        self.network.sockets.iter().any(|socket| match socket {
+            Socket::Udp(udp) => udp.endpoint().port == port,
+            _ => false,
+        })
+    }
+
+    /// The handle of socket `id` of process `pid`.
+    fn socket(&self, pid: Pid, id: u64) -> Result<SocketHandle, KError> {
+        match self.sockets.get(&id) {
+            Some((owner, handle)) if *owner == pid => Ok(*handle),
+            _ => Err(KError::NoSuchSocket { id }),
+        }
+    }
+}
+
+/// Runs `f` on the loopback interface (brings it up first if needed).
+fn with<R>(f: impl FnOnce(&mut Localhost) -> Result<R, KError>) -> Result<R, KError> {
+    let mut localhost = LOCALHOST.lock();
+    let localhost = localhost.get_or_insert_with(|| Localhost {
+        network: Network::loopback(),
+        sockets: BTreeMap::new(),
+        next: 1,
+    });
+    f(localhost)
+}
+
+/// Opens a socket of `pid` bound to `port` (an unused port if it's 0),
+/// returns its id.
+pub fn bind(pid: Pid, port: u64) -> Result<u64, KError> {
+    if port > u16::MAX as u64 {
+        return Err(KError::InvalidSyscallArgument1 { a: port });
+    }
+
+    with(|localhost| {
+        let owned = localhost
+            .sockets
+            .values()
+            .filter(|(owner, _handle)| *owner == pid)
+            .count();
+        if owned >= MAX_SOCKETS {
+            return Err(KError::OpenFileLimit);
+        }
+        let port = match port as u16 {
+            0 => EPHEMERAL_PORTS
+                .clone()
+                .find(|port| !localhost.in_use(*port))
+                .ok_or(KError::OpenFileLimit)?,
+            port if localhost.in_use(port) => return Err(KError::PortInUse { port }),
+            port => port,
+        };
+
+        let rx_buffer = UdpSocketBuffer::new(
+            vec![UdpPacketMetadata::EMPTY; QUEUE_LEN],
+            vec![0; QUEUE_LEN * MAX_DATAGRAM],
+        );
+        let tx_buffer = UdpSocketBuffer::new(
+            vec![UdpPacketMetadata::EMPTY; QUEUE_LEN],
+            vec![0; QUEUE_LEN * MAX_DATAGRAM],
+        );
+        let mut socket = UdpSocket::new(rx_buffer, tx_buffer);
+        socket.bind(port).map_err(|_e| KError::PortInUse { port })?;
+
+        let id = localhost.next;
+        localhost.next += 1;
+        let handle = localhost.network.sockets.add(socket);
+        localhost.sockets.insert(id, (pid, handle));
+        Ok(id)
+    })
+}
+
+/// Sends `data` from socket `id` of `pid` to `to` (in 127.0.0.0/8).
+pub fn send_to(pid: Pid, id: u64, data: &[u8], to: Endpoint) -> Result<usize, KError> {
+    if to.addr[0] != LOCALHOST[0] {
+        return Err(KError::Unreachable { addr: to.addr });
+    }
+    if data.len() > MAX_DATAGRAM {
+        return Err(KError::UserBufferTooLarge { len: data.len() });
+    }
+
+    let sent = with(|localhost| {
+        let handle = localhost.socket(pid, id)?;
+        let [a, b, c, d] = to.addr;
+        let endpoint = IpEndpoint::new(IpAddress::v4(a, b, c, d), to.port);
+        localhost
+            .network
+            .sockets
+            .get::<UdpSocket>(handle)
+            .send_slice(data, endpoint)
+            .map_err(|e| match e {
+                smoltcp::Error::Exhausted => KError::NoSpace,
+                _ => KError::InvalidSyscallArgument4 { a: u64::from(to) },
+            })?;
+        // Delivers it to the receiving socket
+        localhost.network.poll()?;
+        Ok(data.len())
+    })?;
+
+    super::poll::notify();
+    Ok(sent)
+}
+
+/// Readiness of socket `id` of `pid` for `events` of a `Poll`: `POLLIN`
+/// once a datagram arrived, `POLLOUT` while it can queue one, `POLLNVAL` if
+/// there is no such socket.
+pub fn poll(pid: Pid, id: u64, events: PollEvents) -> Result<PollEvents, KError> {
+    with(|localhost| {
+        let handle = match localhost.socket(pid, id) {
+            Ok(handle) => handle,
+            Err(_e) => return Ok(PollEvents::POLLNVAL),
+        };
+        localhost.network.poll()?;
+        let socket = localhost.network.sockets.get::<UdpSocket>(handle);
+        let mut ready = PollEvents::empty();
+        ready.set(PollEvents::POLLIN, socket.can_recv());
+        ready.set(PollEvents::POLLOUT, socket.can_send());
+        Ok(ready & events)
+    })
+}
+
+/// Takes the next datagram socket `id` of `pid` received into `buf`, returns
+/// its length (cut to `buf.len()`) and sender, `None` if there is none.
+pub fn recv_from(pid: Pid, id: u64, buf: &mut [u8]) -> Result<Option<(usize, Endpoint)>, KError> {
+    with(|localhost| {
+        let handle = localhost.socket(pid, id)?;
+        localhost.network.poll()?;
+        let mut socket = localhost.network.sockets.get::<UdpSocket>(handle);
+        match socket.recv_slice(buf) {
+            Ok((len, from)) => {
+                let addr = match from.addr {
+                    IpAddress::Ipv4(addr) => addr.0,
+                    _ => [0; 4],
+                };
+                Ok(Some((len, Endpoint::new(addr, from.port))))
+            }
+            Err(_e) => Ok(None),
+        }
+    })
+}
+
+/// Closes socket `id` of `pid`.
+pub fn close(pid: Pid, id: u64) -> Result<(), KError> {
+    with(|localhost| {
+        let handle = localhost.socket(pid, id)?;
+        localhost.sockets.remove(&id);
+        localhost.network.sockets.remove(handle);
+        Ok(())
+    })
+}
+
+/// Closes all sockets of `pid` (it exited).
+pub fn release(pid: Pid) {
+    if let Some(localhost) = LOCALHOST.lock().as_mut() {
+        let Localhost {
+            network, sockets, ..
+        } = localhost;
+        sockets.retain(|_id, (owner, handle)| {
+            if *owner == pid {
+                network.sockets.remove(*handle);
+            }
+            *owner != pid
+        });
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Access to model-specific registers and CPUID for measurement processes.
+//!
+//! Processes with the `MEASURE` capability (granted to init with
+//! `initcaps=measure` on the command-line) can read the registers and
+//! leaves in [`MSR_ALLOWLIST`] and [`CPUID_ALLOWLIST`] on the core they run
+//! on.
+
+use kpi::process::Capabilities;
+
+use crate::error::KError;
+use crate::nr;
+use crate::process::Pid;
+
+#[cfg(target_os = "none")]
+global_asm!(include_str!("measure.S"), options(att_syntax));
+
+extern "C" {
+    /// Returns 0 on success and 1 if the MSR doesn't exist.
+    fn nrk_rdmsr_safe(msr: u32, value: *mut u64) -> u64;
+    /// Returns 0 on success and 1 if the MSR doesn't exist (or doesn't take
+    /// the value).
+    fn nrk_wrmsr_safe(msr: u32, value: u64) -> u64;
+}
+
+/// MSRs a measurement process may read.
+pub const MSR_ALLOWLIST: &[u32] = &[
+    0x10,  // IA32_TIME_STAMP_COUNTER
+    0xe7,  // IA32_MPERF
+    0xe8,  // IA32_APERF
+    0x198, // IA32_PERF_STATUS
+    0x19c, // IA32_THERM_STATUS
+    0x1b1, // IA32_PACKAGE_THERM_STATUS
+    0x606, // MSR_RAPL_POWER_UNIT
+    0x611, // MSR_PKG_ENERGY_STATUS
+    0x619, // MSR_DRAM_ENERGY_STATUS
+    0x639, // MSR_PP0_ENERGY_STATUS
+    0x641, // MSR_PP1_ENERGY_STATUS
+];
+
+/// CPUID leaves a measurement process may query.
+pub const CPUID_ALLOWLIST: &[u32] = &[
+    0x0,         // Vendor and maximum leaf
+    0x1,         // Version and features
+    0x6,         // Thermal and power management
+    0xa,         // Architectural performance monitoring
+    0x15,        // TSC and core crystal clock
+    0x16,        // Processor frequency
+    0x8000_0007, // Invariant TSC
+];
+
+/// Fails unless `pid` has the `MEASURE` capability.
+pub fn check_capability(pid: Pid) -> Result<(), KError> {
+    if nr::KernelNode::capabilities(pid)?.contains(Capabilities::MEASURE) {
+        Ok(())
+    } else {
+        Err(KError::MissingCapability)
+    }
+}
+
+/// Reads `msr` on the current core for `pid`.
+pub fn read_msr(pid: Pid, msr: u32) -> Result<u64, KError> {
+    check_capability(pid)?;
+    if !MSR_ALLOWLIST.contains(&msr) {
+        return Err(KError::MsrNotAllowed { msr });
+    }
+
+    rdmsr_safe(msr)
+}
+
+/// Reads `msr` on the current core, fails if it doesn't exist (e.g., in a
+/// VM).
+pub(super) fn rdmsr_safe(msr: u32) -> Result<u64, KError> {
+    let mut value = 0;
+    match unsafe { nrk_rdmsr_safe(msr, &mut value) } {
+        0 => Ok(value),
+        _ => Err(KError::MsrUnavailable { msr }),
+    }
+}
+
+/// Writes `value` to `msr` on the current core, fails if it doesn't exist
+/// or doesn't take the value.
+///
+/// # Safety
+/// Writes a model-specific register, the caller has to know what it does.
+pub(super) unsafe fn wrmsr_safe(msr: u32, value: u64) -> Result<(), KError> {
+    match nrk_wrmsr_safe(msr, value) {
+        0 => Ok(()),
+        _ => Err(KError::MsrUnavailable { msr }),
+    }
+}
+
+/// Queries CPUID `leaf` (and `subleaf`) on the current core for `pid`,
+/// returns (ebx:eax, edx:ecx).
+pub fn cpuid(pid: Pid, leaf: u32, subleaf: u32) -> Result<(u64, u64), KError> {
+    check_capability(pid)?;
+    if !CPUID_ALLOWLIST.contains(&leaf) {
+        return Err(KError::CpuidLeafNotAllowed { leaf });
+    }
+
+    let r = unsafe { core::arch::x86_64::__cpuid_count(leaf, subleaf) };
+    Ok((
+        (r.ebx as u64) << 32 | r.eax as u64,
+        (r.edx as u64) << 32 | r.ecx as u64,
+    ))
+}
@@ -0,0 +1,120 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Moving executors between cores (and NUMA nodes).
+//!
+//! A migration is initiated by the core that runs the executor: It stores the
+//! user-space state (registers and FPU) in the executor, takes it off the
+//! core in its process replica (`NrProcess::migrate_executor`) and then
+//! hands the core assignment over to the target core
+//! (`KernelNode::migrate_core`). The target core picks it up the next time it
+//! enters the scheduler and resumes it. The executor keeps its stacks and vCPU
+//! area, when it moves to a different NUMA node it switches to the page-tables
+//! of that node's replica.
+
+use log::{debug, trace};
+
+use crate::error::KError;
+use crate::kcb::LocalCore;
+use crate::nr;
+use crate::nrproc::NrProcess;
+
+use super::process::Ring3Process;
+
+/// Should cores periodically try to spread out executors (see [`balance`])?
+///
+/// Enabled with `balance=spread` on the kernel command-line.
+pub fn balancing() -> bool {
+    super::kcb::per_core().cmdline.balance == "spread"
+}
+
+/// Moves the executor running on the current core to (the unused) core `to`,
+/// it resumes from `state` there.
+///
+/// Only returns (with an error) if the executor couldn't be moved, it then
+/// still runs on the current core.
+pub fn migrate(
+    core: &mut LocalCore,
+    to: atopology::GlobalThreadId,
+    state: kpi::arch::SaveArea,
+) -> Result<(), KError> {
+    let kcb = core.kcb_mut();
+    let from = kcb.arch.hwthread_id();
+    let to_region = atopology::MACHINE_TOPOLOGY
+        .threads()
+        .find(|thread| thread.id == to)
+        .map(|thread| thread.node_id.unwrap_or(0))
+        .ok_or(KError::InvalidGlobalThreadId)?;
+    let (pid, eid) = {
+        let executor = kcb.arch.current_executor()?;
+        (executor.pid, executor.eid)
+    };
+
+    NrProcess::<Ring3Process>::migrate_executor(pid, from, eid, to_region, state)?;
+    if let Err(e) = nr::KernelNode::migrate_core(pid, from, to, eid) {
+        // Someone else got `to` first, keep running here
+        let region = kcb.arch.node();
+        NrProcess::<Ring3Process>::migrate_executor(pid, from, eid, region, state)?;
+        let executor = NrProcess::resume_executor(kcb, pid, eid)?;
+        let _old = kcb.arch.swap_current_executor(executor);
+        return Err(e);
+    }
+
+    debug!(
+        "Migrated executor {} of {} from {} to {}",
+        eid, pid, from, to
+    );
+    let _executor = kcb.arch.take_current_executor();
+    // We won't get TLB shootdowns for the process anymore
+    unsafe { x86::tlb::flush_all() };
+    crate::scheduler::schedule()
+}
+
+/// Load-balancing policy that spreads executors across physical cores.
+///
+/// If the current core shares its physical core with another busy hardware
+/// thread while a physical core on the same NUMA node is entirely unused, the
+/// executor moves there. Of two busy siblings only the one with the higher id
+/// moves. Low-latency executors are left alone.
+pub fn balance(core: &mut LocalCore) {
+    let kcb = core.kcb_mut();
+    let state = match (kcb.arch.current_executor(), kcb.arch.save_area.as_ref()) {
+        (Ok(executor), Some(save_area))
+            if executor.sched_class != kpi::process::SchedulingClass::LowLatency =>
+        {
+            **save_area
+        }
+        _ => return,
+    };
+    let allocated = match nr::KernelNode::allocated_cores() {
+        Ok(allocated) => allocated,
+        Err(_) => return,
+    };
+
+    let current = atopology::MACHINE_TOPOLOGY.current_thread();
+    let is_sibling = |a: &atopology::Thread, b: &atopology::Thread| {
+        a.package_id == b.package_id && a.core_id == b.core_id
+    };
+    // Nothing to gain if we have the physical core to ourselves
+    let mut busy_siblings = atopology::MACHINE_TOPOLOGY
+        .threads()
+        .filter(|t| t.id != current.id && is_sibling(t, current) && allocated.contains(t.id))
+        .peekable();
+    if busy_siblings.peek().is_none() || busy_siblings.any(|t| t.id > current.id) {
+        return;
+    }
+
+    let idle = atopology::MACHINE_TOPOLOGY.threads().find(|t| {
+        t.node_id == current.node_id
+            && atopology::MACHINE_TOPOLOGY
+                .threads()
+                .filter(|s| is_sibling(s, t))
+                .all(|s| !allocated.contains(s.id))
+    });
+    if let Some(target) = idle {
+        trace!("Balance: move {} to idle core {}", current.id, target.id);
+        if let Err(e) = migrate(core, target.id, state) {
+            trace!("Balance: can't move {}: {:?}", current.id, e);
+        }
+    }
+}
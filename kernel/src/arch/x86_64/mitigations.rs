@@ -0,0 +1,194 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Mitigations for speculative execution attacks (Spectre v2 and MDS).
+//!
+//! `mitigations=` on the command line selects them:
+//!
+//! * `auto` (default): What the CPU supports, except IBRS on CPUs without
+//!   enhanced IBRS (it would slow down user-space as well).
+//! * `off`: None.
+//! * A list, e.g., `mitigations='ibrs,ibpb,mds'`.
+//!
+//! The mitigations are:
+//!
+//! * `ibrs`: Sets IBRS (and STIBP) in `IA32_SPEC_CTRL` on every core. It
+//!   stays on in user-space. Without enhanced IBRS, setting it only
+//!   restricts the branch predictions up to that point, so we write it again
+//!   on every kernel entry ([`enter_kernel`]).
+//! * `ibpb`: Flushes the branch predictors (`IA32_PRED_CMD`) when a core
+//!   switches to another address space (see `Ring3Executor`).
+//! * `mds`: Clears the CPU buffers with `verw` right before we return to
+//!   user-space (see `Ring3Resumer`).
+//!
+//! We don't have retpolines: the compiler can't emit them for the kernel,
+//! IBRS covers indirect branches in the kernel instead.
+//!
+//! The kernel reports what it uses at boot and with
+//! `SystemOperation::GetInfo` ([`info`]). `s06_mitigations_benchmark`
+//! measures what they cost.
+
+use core::arch::x86_64::__cpuid_count;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+
+use kpi::system::{MitigationInfo, MitigationSet};
+use log::{info, warn};
+use spin::Once;
+use x86::msr::{rdmsr, wrmsr};
+
+use super::gdt::GdtTable;
+
+const IA32_SPEC_CTRL: u32 = 0x48;
+const IA32_PRED_CMD: u32 = 0x49;
+const IA32_ARCH_CAPABILITIES: u32 = 0x10a;
+
+const SPEC_CTRL_IBRS: u64 = 1 << 0;
+const SPEC_CTRL_STIBP: u64 = 1 << 1;
+const PRED_CMD_IBPB: u64 = 1 << 0;
+const ARCH_CAP_IBRS_ALL: u64 = 1 << 1;
+const ARCH_CAP_MDS_NO: u64 = 1 << 5;
+
+/// CPUID leaf 7 (EDX) feature bits.
+const CPUID_MD_CLEAR: u32 = 1 << 10;
+const CPUID_SPEC_CTRL: u32 = 1 << 26;
+const CPUID_STIBP: u32 = 1 << 27;
+const CPUID_ARCH_CAPABILITIES: u32 = 1 << 29;
+
+/// What we decided on at boot.
+static INFO: Once<MitigationInfo> = Once::new();
+
+/// Value for `IA32_SPEC_CTRL` on every core (0 leaves it alone).
+static SPEC_CTRL: AtomicU64 = AtomicU64::new(0);
+
+/// Flush the branch predictors on address-space switches?
+static IBPB: AtomicBool = AtomicBool::new(false);
+
+/// Write `IA32_SPEC_CTRL` again on every kernel entry (IBRS without enhanced
+/// IBRS)?
+static IBRS_ON_ENTRY: AtomicBool = AtomicBool::new(false);
+
+/// The selector `verw` clears the CPU buffers with, 0 if we don't.
+///
+/// Read by the assembly that returns to user-space (hence `no_mangle`).
+#[no_mangle]
+static MDS_CLEAR_SELECTOR: AtomicU16 = AtomicU16::new(0);
+
+/// What the CPU supports.
+fn available() -> (MitigationSet, bool, u64) {
+    let edx = unsafe { __cpuid_count(7, 0) }.edx;
+    let arch_caps = if edx & CPUID_ARCH_CAPABILITIES != 0 {
+        unsafe { rdmsr(IA32_ARCH_CAPABILITIES) }
+    } else {
+        0
+    };
+    let stibp = if edx & CPUID_STIBP != 0 {
+        SPEC_CTRL_STIBP
+    } else {
+        0
+    };
+
+    let available = MitigationSet {
+        ibrs: edx & CPUID_SPEC_CTRL != 0,
+        ibpb: edx & CPUID_SPEC_CTRL != 0,
+        mds: edx & CPUID_MD_CLEAR != 0 && arch_caps & ARCH_CAP_MDS_NO == 0,
+    };
+    (available, arch_caps & ARCH_CAP_IBRS_ALL != 0, stibp)
+}
+
+/// Picks the mitigations (from the command-line) and enables them on the
+/// BSP. The other cores call [`enable`] when they start.
+pub fn init(args: &str) {
+    let (available, enhanced_ibrs, stibp) = available();
+
+    let mut active = MitigationSet::default();
+    match args {
+        "off" => {}
+        "auto" => {
+            active = available;
+            active.ibrs = available.ibrs && enhanced_ibrs;
+        }
+        list => {
+            for mitigation in list.split(',') {
+                let (wanted, supported) = match mitigation {
+                    "ibrs" => (&mut active.ibrs, available.ibrs),
+                    "ibpb" => (&mut active.ibpb, available.ibpb),
+                    "mds" => (&mut active.mds, available.mds),
+                    _ => {
+                        warn!("Unknown mitigation {} ignored", mitigation);
+                        continue;
+                    }
+                };
+                if supported {
+                    *wanted = true;
+                } else {
+                    warn!("Mitigation {} isn't supported by the CPU", mitigation);
+                }
+            }
+        }
+    }
+
+    if active.ibrs {
+        SPEC_CTRL.store(SPEC_CTRL_IBRS | stibp, Ordering::Relaxed);
+        IBRS_ON_ENTRY.store(!enhanced_ibrs, Ordering::Relaxed);
+    }
+    IBPB.store(active.ibpb, Ordering::Relaxed);
+    if active.mds {
+        MDS_CLEAR_SELECTOR.store(GdtTable::kernel_ss_selector().bits(), Ordering::Relaxed);
+    }
+    let info = INFO.call_once(|| MitigationInfo {
+        active,
+        available,
+        enhanced_ibrs,
+    });
+    enable();
+
+    info!(
+        "Mitigations: ibrs={} ibpb={} mds={} (CPU supports ibrs={}{} ibpb={} mds={})",
+        info.active.ibrs,
+        info.active.ibpb,
+        info.active.mds,
+        info.available.ibrs,
+        if info.enhanced_ibrs {
+            " (enhanced)"
+        } else {
+            ""
+        },
+        info.available.ibpb,
+        info.available.mds,
+    );
+}
+
+/// Enables the mitigations on the current core.
+pub fn enable() {
+    let spec_ctrl = SPEC_CTRL.load(Ordering::Relaxed);
+    if spec_ctrl != 0 {
+        // Safe: `init` checked that the CPU has the MSR
+        unsafe { wrmsr(IA32_SPEC_CTRL, spec_ctrl) };
+    }
+}
+
+/// Called when a core enters the kernel (system calls, interrupts and
+/// exceptions).
+pub fn enter_kernel() {
+    if IBRS_ON_ENTRY.load(Ordering::Relaxed) {
+        // Safe: `init` checked that the CPU has the MSR
+        unsafe { wrmsr(IA32_SPEC_CTRL, SPEC_CTRL.load(Ordering::Relaxed)) };
+    }
+}
+
+/// Called before a core switches to another address space.
+pub fn switch_address_space() {
+    if IBPB.load(Ordering::Relaxed) {
+        // Safe: `init` checked that the CPU has the MSR
+        unsafe { wrmsr(IA32_PRED_CMD, PRED_CMD_IBPB) };
+    }
+}
+
+/// The mitigations the kernel uses (for `SystemOperation::GetInfo`).
+pub fn info() -> MitigationInfo {
+    INFO.get().copied().unwrap_or(MitigationInfo {
+        active: MitigationSet::default(),
+        available: MitigationSet::default(),
+        enhanced_ibrs: false,
+    })
+}
@@ -29,23 +29,23 @@ use core::sync::atomic::{AtomicBool, Ordering};
 
 use crate::cnrfs::{MlnrKernelNode, Modify};
 use crate::kcb::{BootloaderArguments, Kcb};
-use crate::memory::{mcache, Frame, GlobalMemory, BASE_PAGE_SIZE};
+use crate::memory::reserved::{self, Reserved};
+use crate::memory::{mcache, memmap::MemoryMap, Frame, GlobalMemory, BASE_PAGE_SIZE};
 use crate::nr::{KernelNode, Op};
+use crate::placement::{self, ReplicaPlacement};
 use crate::stack::OwnedStack;
 use crate::{xmain, ExitReason};
 
-use apic::x2apic;
-use apic::ApicDriver;
 use arrayvec::ArrayVec;
 use cnr::{Log as MlnrLog, Replica as MlnrReplica};
-use driverkit::DriverControl;
 use fallible_collections::{FallibleVecGlobal, TryClone};
 use klogger::sprint;
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use node_replication::{Log, Replica};
 use x86::bits64::paging::{PAddr, VAddr, PML4};
 use x86::{controlregs, cpuid};
 
+use bootloader_shared::splash::BootStage;
 pub use bootloader_shared::*;
 
 use crate::fallible_string::FallibleString;
@@ -54,17 +54,67 @@ use memory::paddr_to_kernel_vaddr;
 use vspace::page_table::PageTable;
 
 pub mod acpi;
+pub mod ahci;
+pub mod barrier;
+#[cfg(feature = "syscall-budget")]
+pub mod budget;
+pub mod checkpoint;
 pub mod coreboot;
+pub mod coredump;
 pub mod debug;
+pub mod devices;
+pub mod domain;
+#[cfg(feature = "smoltcp")]
+pub mod e1000;
+pub mod efi;
+pub mod futex;
 pub mod gdt;
+pub mod hwinfo;
+pub mod ioapic;
 pub mod irq;
+pub mod irqstats;
 pub mod kcb;
+pub mod kexec;
+pub mod lapic;
+#[cfg(feature = "smoltcp")]
+pub mod localhost;
+pub mod measure;
 pub mod memory;
+pub mod migrate;
+pub mod mitigations;
+pub mod netconsole;
+pub mod netpoll;
+#[cfg(feature = "smoltcp")]
+pub mod network;
+pub mod poll;
+pub mod power;
 pub mod process;
+pub mod profile;
+pub mod ps2;
+pub mod reclaim;
+pub mod refclock;
+#[cfg(target_os = "none")]
+pub mod reload;
+#[cfg(feature = "smoltcp")]
+pub mod remote;
+pub mod sleep;
+pub mod smp;
+pub mod splash;
+pub mod stacks;
 pub mod syscall;
+pub mod thp;
 pub mod timer;
+#[cfg(feature = "smoltcp")]
+pub mod timesync;
 pub mod tlb;
+pub mod tsc;
+pub mod usercopy;
+pub mod vcpu;
 pub mod vspace;
+pub mod wx;
+
+#[path = "../traits.rs"]
+pub mod traits;
 
 mod isr;
 
@@ -76,17 +126,21 @@ fn assert_required_cpu_features() {
     let cpuid = cpuid::CpuId::new();
     let fi = cpuid.get_feature_info();
     let has_apic = fi.as_ref().map_or(false, |f| f.has_apic());
-    let has_x2apic = fi.as_ref().map_or(false, |f| f.has_x2apic());
     let has_tsc = fi.as_ref().map_or(false, |f| f.has_tsc());
     let has_syscalls = fi.as_ref().map_or(false, |f| f.has_sysenter_sysexit());
     let has_pae = fi.as_ref().map_or(false, |f| f.has_pae());
     let has_msr = fi.as_ref().map_or(false, |f| f.has_msr());
+    let has_pat = fi.as_ref().map_or(false, |f| f.has_pat());
 
     let has_sse = fi.as_ref().map_or(false, |f| f.has_sse());
     let has_sse3 = fi.as_ref().map_or(false, |f| f.has_sse3());
     let _has_avx = fi.as_ref().map_or(false, |f| f.has_avx());
     let has_osfxsr = fi.as_ref().map_or(false, |f| f.has_fxsave_fxstor());
 
+    let efi = cpuid.get_extended_feature_info();
+    let has_smep = efi.as_ref().map_or(false, |f| f.has_smep());
+    let has_smap = efi.as_ref().map_or(false, |f| f.has_smap());
+
     assert!(has_tsc, "No RDTSC? Run on a more modern machine!");
     assert!(has_sse, "No SSE? Run on a more modern machine!");
     assert!(has_osfxsr, "No fxsave? Run on a more modern machine!");
@@ -95,10 +149,31 @@ fn assert_required_cpu_features() {
     //assert!(has_avx, "No AVX? Run on a more modern machine!");
 
     assert!(has_apic, "No APIC? Run on a more modern machine!");
-    assert!(has_x2apic, "No X2APIC? Run on a more modern machine!");
     assert!(has_syscalls, "No sysenter? Run on a more modern machine!");
     assert!(has_pae, "No PAE? Run on a more modern machine!");
     assert!(has_msr, "No MSR? Run on a more modern machine!");
+    assert!(has_pat, "No PAT? Run on a more modern machine!");
+    assert!(has_smep, "No SMEP? Run on a more modern machine!");
+    assert!(has_smap, "No SMAP? Run on a more modern machine!");
+}
+
+/// Memory types of the page attribute table, selected with the PCD and PWT
+/// bits of a page-table entry (the PAT bit is never set):
+///
+/// - 0 (-): write-back
+/// - 1 (PWT): write-through
+/// - 2 (PCD): write-combining (instead of uncached-minus)
+/// - 3 (PCD | PWT): uncached
+///
+/// Entries 4-7 keep their reset values. See `MapAction::to_pt_rights`.
+const PAT: u64 = 0x0007_0406_0001_0406;
+
+/// Programs the page attribute table of the core (see [`PAT`]).
+///
+/// Has to be the same on all cores and happen before any memory is mapped
+/// write-combining.
+fn enable_pat() {
+    unsafe { x86::msr::wrmsr(x86::msr::IA32_PAT, PAT) };
 }
 
 /// Enable SSE functionality and disable the old x87 FPU.
@@ -143,15 +218,137 @@ pub fn enable_fsgsbase() {
     };
 }
 
+/// Enables SMEP and SMAP: the kernel faults if it executes or accesses
+/// user-space memory (by accident).
+///
+/// The only places where we access user memory on purpose are the copy
+/// helpers in `usercopy` (and `nrk_monitor_mwait`), they open a window with
+/// `stac`/`clac` for the duration of the access.
+///
+/// The bootloader enables both on the BSP already but the application
+/// cores start from `start_ap.S` without them.
+///
+/// `stac`/`clac` are invalid opcodes on a CPU without SMAP, so we check for
+/// it here, before the first `clac` (the BSP calls this before
+/// `assert_required_cpu_features`).
+pub fn enable_smep_smap() {
+    let efi = cpuid::CpuId::new().get_extended_feature_info();
+    assert!(
+        efi.as_ref().map_or(false, |f| f.has_smep() && f.has_smap()),
+        "No SMEP/SMAP? Run on a more modern machine!"
+    );
+
+    unsafe {
+        let mut cr4: controlregs::Cr4 = controlregs::cr4();
+        cr4 |= controlregs::Cr4::CR4_ENABLE_SMEP | controlregs::Cr4::CR4_ENABLE_SMAP;
+        controlregs::cr4_write(cr4);
+        // In case someone left a window open
+        x86::bits64::rflags::clac();
+    };
+}
+
+/// Sets CR0.WP: read-only pages are read-only for the kernel too (without
+/// it, the write-protection of the kernel image in `wx` only holds for
+/// user-space).
+///
+/// `start_ap.S` sets it on the application cores, the BSP gets CR0 from
+/// the bootloader (and the firmware) and we don't rely on them.
+pub fn enable_write_protect() {
+    unsafe {
+        let mut cr0 = controlregs::cr0();
+        cr0 |= controlregs::Cr0::CR0_WRITE_PROTECT;
+        controlregs::cr0_write(cr0);
+    }
+}
+
 /// Goes to sleep / halts the core.
 ///
-/// Interrupts are enabled before going to sleep.
+/// Interrupts are enabled before going to sleep, the core then waits in the
+/// idle state of the power policy (see `power`).
 pub fn halt() -> ! {
-    unsafe {
+    irq::enable();
+    loop {
+        power::idle()
+    }
+}
+
+/// The x86-64 platform.
+pub struct X86_64;
+
+/// The platform we're compiled for.
+pub type Platform = X86_64;
+
+impl traits::IrqControl for X86_64 {
+    fn enable() {
         irq::enable();
-        loop {
-            x86::halt()
-        }
+    }
+
+    fn disable() {
+        irq::disable();
+    }
+
+    fn acknowledge() {
+        irq::acknowledge();
+    }
+
+    fn with_interrupts(f: fn()) -> bool {
+        irq::with_interrupts(f)
+    }
+}
+
+impl traits::Timer for X86_64 {
+    const DEFAULT_DEADLINE: u64 = timer::DEFAULT_TIMER_DEADLINE;
+
+    fn set(deadline: u64) {
+        timer::set(deadline);
+    }
+
+    fn now() -> u64 {
+        timer::now()
+    }
+
+    fn global_now() -> u64 {
+        timer::global_now()
+    }
+}
+
+impl traits::ContextSwitch for X86_64 {
+    type Resumer = process::Ring3Resumer;
+
+    fn resume_current() -> Self::Resumer {
+        process::Ring3Resumer::new_restore(kcb::per_core().arch.get_save_area_ptr())
+    }
+
+    fn halt() -> ! {
+        halt()
+    }
+}
+
+impl traits::KernelVSpace for X86_64 {
+    fn map_identity(
+        base: PAddr,
+        size: usize,
+        rights: crate::memory::vspace::MapAction,
+    ) -> Result<(), crate::error::KError> {
+        kcb::per_core()
+            .arch
+            .init_vspace()
+            .map_identity(base, size, rights)
+    }
+}
+
+impl traits::CoreBoot for X86_64 {
+    type CoreId = x86::apic::ApicId;
+
+    unsafe fn boot_core<A>(
+        core_id: Self::CoreId,
+        init_function: fn(Arc<A>, &AtomicBool),
+        args: Arc<A>,
+        initialized: &AtomicBool,
+        stack: &dyn crate::stack::Stack,
+    ) -> Result<(), crate::error::KError> {
+        coreboot::initialize(core_id, init_function, args, initialized, stack);
+        Ok(())
     }
 }
 
@@ -176,21 +373,36 @@ unsafe fn find_current_ptables() -> PageTable {
     }
 }
 
-/// Construct the driver object to manipulate the interrupt controller (XAPIC)
-fn init_apic() -> x2apic::X2APICDriver {
-    let mut apic = x2apic::X2APICDriver::default();
-    // Attach the driver to take control of the APIC:
-    apic.attach();
+/// Registers the physical memory that isn't ours to allocate or map: the
+/// kernel image, the data the bootloader set up, modules, ACPI tables, the
+/// local APIC and the bootstrap code of the app cores.
+fn reserve_boot_regions(kernel_args: &KernelArgs) {
+    use uefi::table::boot::MemoryType;
 
-    info!(
-        "x2APIC id: {}, logical_id: {}, version: {:#x}, is bsp: {}",
-        apic.id(),
-        apic.logical_id(),
-        apic.version(),
-        apic.bsp()
-    );
+    for region in kernel_args.mm_iter.iter() {
+        let kind = match region.ty {
+            MemoryType(KERNEL_ELF) => Reserved::KernelImage,
+            MemoryType(KERNEL_PT)
+            | MemoryType(KERNEL_STACK)
+            | MemoryType(KERNEL_ARGS)
+            | MemoryType(UEFI_MEMORY_MAP) => Reserved::BootData,
+            MemoryType(MODULE) => Reserved::Module,
+            MemoryType::ACPI_RECLAIM | MemoryType::ACPI_NON_VOLATILE => Reserved::AcpiTables,
+            _ => continue,
+        };
+        let base = PAddr::from(region.phys_start);
+        let size = region.page_count as usize * BASE_PAGE_SIZE;
+        reserved::reserve(base, size, kind).expect("Can't reserve memory map region");
+    }
 
-    apic
+    // Even with the x2APIC (MSRs) the xAPIC registers are still decoded at
+    // the APIC base (and we need them if there is no x2APIC)
+    let apic_base = unsafe { x86::msr::rdmsr(x86::msr::IA32_APIC_BASE) } & !0xfff;
+    reserved::reserve(PAddr::from(apic_base), BASE_PAGE_SIZE, Reserved::LocalApic)
+        .expect("Can't reserve local APIC");
+
+    #[cfg(not(feature = "bsp-only"))]
+    coreboot::reserve_bootstrap_region().expect("Can't reserve bootstrap code");
 }
 
 #[cfg(not(feature = "bsp-only"))]
@@ -216,7 +428,11 @@ fn start_app_core(args: Arc<AppCoreArgs>, initialized: &AtomicBool) {
     enable_sse();
     enable_fsgsbase();
     assert_required_cpu_features();
+    enable_smep_smap();
+    enable_write_protect();
+    enable_pat();
     syscall::enable_fast_syscalls();
+    mitigations::enable();
     irq::disable();
 
     unsafe {
@@ -228,7 +444,7 @@ fn start_app_core(args: Arc<AppCoreArgs>, initialized: &AtomicBool) {
     let emanager = mcache::TCacheSp::new(args.node);
     let init_ptable = unsafe { find_current_ptables() }; // Safe, done once during init
 
-    let arch = kcb::Arch86Kcb::new(args.kernel_args, init_apic(), init_ptable);
+    let arch = kcb::Arch86Kcb::new(args.kernel_args, init_ptable);
     let mut kcb =
         Kcb::<kcb::Arch86Kcb>::new(args.kernel_binary, args.cmdline, emanager, arch, args.node);
 
@@ -243,6 +459,7 @@ fn start_app_core(args: Arc<AppCoreArgs>, initialized: &AtomicBool) {
     static_kcb.arch.set_interrupt_stacks(
         OwnedStack::new(128 * BASE_PAGE_SIZE),
         OwnedStack::new(128 * BASE_PAGE_SIZE),
+        OwnedStack::new(32 * BASE_PAGE_SIZE),
     );
     static_kcb
         .arch
@@ -250,6 +467,8 @@ fn start_app_core(args: Arc<AppCoreArgs>, initialized: &AtomicBool) {
     static_kcb
         .arch
         .set_save_area(Box::pin(kpi::x86_64::SaveArea::empty()));
+    let apic = lapic::init(&mut static_kcb.arch.init_vspace());
+    static_kcb.arch.set_apic(apic);
     static_kcb.enable_print_buffering(
         String::try_with_capacity(128).expect("Not enough memory to initialize system"),
     );
@@ -310,13 +529,18 @@ fn boot_app_cores(
     let kcb = kcb::get_kcb();
     debug_assert_eq!(kcb.node, 0, "The BSP core is not on node 0?");
 
-    // Let's go with one replica per NUMA node for now:
+    // One replica per NUMA node, unless the command-line asks for fewer:
     let numa_nodes = core::cmp::max(1, atopology::MACHINE_TOPOLOGY.num_nodes());
+    let num_replicas = ReplicaPlacement::from_arg(cmdline.replicas).replicas(numa_nodes);
+    info!(
+        "Using {} kernel replica(s) for {} NUMA node(s)",
+        num_replicas, numa_nodes
+    );
 
     let mut replicas: Vec<Arc<Replica<'static, KernelNode>>> =
-        Vec::try_with_capacity(numa_nodes).expect("Not enough memory to initialize system");
+        Vec::try_with_capacity(num_replicas).expect("Not enough memory to initialize system");
     let mut fs_replicas: Vec<Arc<MlnrReplica<'static, MlnrKernelNode>>> =
-        Vec::try_with_capacity(numa_nodes).expect("Not enough memory to initialize system");
+        Vec::try_with_capacity(num_replicas).expect("Not enough memory to initialize system");
 
     // Push the replica for node 0
     debug_assert_eq!(kcb.node, 0, "The BSP core is not on node 0?");
@@ -325,14 +549,15 @@ fn boot_app_cores(
     debug_assert!(fs_replicas.capacity() >= 1, "No re-allocation.");
     fs_replicas.push(fs_replica);
 
-    for node in 1..numa_nodes {
+    for replica in 1..num_replicas {
+        let node = placement::home_node(replica, numa_nodes, num_replicas);
         kcb.set_allocation_affinity(node as atopology::NodeId)
             .expect("Can't set affinity");
 
-        debug_assert!(replicas.capacity() > node, "No re-allocation.");
+        debug_assert!(replicas.capacity() > replica, "No re-allocation.");
         replicas.push(Replica::<'static, KernelNode>::new(&log));
 
-        debug_assert!(fs_replicas.capacity() > node, "No re-allocation.");
+        debug_assert!(fs_replicas.capacity() > replica, "No re-allocation.");
         fs_replicas.push(MlnrReplica::new(
             fs_logs
                 .try_clone()
@@ -355,7 +580,13 @@ fn boot_app_cores(
 
     for thread in threads_to_boot {
         let node = thread.node_id.unwrap_or(0);
-        trace!("Booting {:?} on node {}", thread, node);
+        let replica = placement::replica_of(node as usize, numa_nodes, num_replicas);
+        trace!(
+            "Booting {:?} on node {} (replica {})",
+            thread,
+            node,
+            replica
+        );
         kcb.set_allocation_affinity(node)
             .expect("Can't set affinity");
 
@@ -376,10 +607,10 @@ fn boot_app_cores(
             global_memory,
             thread: thread.id,
             _log: log.clone(),
-            replica: replicas[node as usize]
+            replica: replicas[replica]
                 .try_clone()
                 .expect("Not enough memory to initialize system"),
-            fs_replica: fs_replicas[node as usize]
+            fs_replica: fs_replicas[replica]
                 .try_clone()
                 .expect("Not enough memory to initialize system"),
         })
@@ -492,6 +723,8 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     sprint!("\r\n");
     enable_sse();
     enable_fsgsbase();
+    enable_smep_smap();
+    enable_write_protect();
     unsafe {
         gdt::setup_early_gdt();
         irq::setup_early_idt();
@@ -505,16 +738,96 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     // We construct a &'static mut for KernelArgs (mut is just because of `mm_iter`)
     let kernel_args: &'static mut KernelArgs =
         unsafe { transmute::<u64, &'static mut KernelArgs>(argc as u64) };
+    splash::init(kernel_args);
+    splash::progress(BootStage::KernelEntry);
 
     // Parse the command line arguments
     let cmdline = BootloaderArguments::from_str(kernel_args.command_line);
-    klogger::init(cmdline.log_filter).expect("Can't set-up logging");
+    // Mirror the console to the network and to a disk if requested
+    let netlog = !cmdline.net_log.is_empty() && netconsole::init(cmdline.net_log);
+    let disklog = !cmdline.log_disk.is_empty() && crate::disklog::init(cmdline.log_disk);
+    if !netconsole::install_logger(cmdline.log_filter) {
+        klogger::init(cmdline.log_filter).expect("Can't set-up logging");
+    }
+    if !cmdline.net_log.is_empty() && !netlog {
+        warn!("Invalid netlog={} ignored", cmdline.net_log);
+    }
+    if !cmdline.log_disk.is_empty() && !disklog {
+        warn!("Invalid logdisk={} ignored", cmdline.log_disk);
+    }
+    // Synchronize the wall clock and serve the management console once the
+    // network is up
+    #[cfg(feature = "smoltcp")]
+    let ntp = timesync::init(cmdline.ntp);
+    #[cfg(feature = "smoltcp")]
+    let mgmt = remote::init(cmdline.mgmt_key);
+    #[cfg(not(feature = "smoltcp"))]
+    if !cmdline.ntp.is_empty() || !cmdline.mgmt_key.is_empty() {
+        warn!("ntp and mgmtkey are ignored (they need the `smoltcp` feature)");
+    }
 
     info!(
         "Started at {} with {:?} since CPU startup",
         *rawtime::WALL_TIME_ANCHOR,
         *rawtime::BOOT_TIME_ANCHOR
     );
+    crate::features::report();
+    info!("Modules loaded from {:?}", kernel_args.module_source);
+    match kernel_args.kaslr {
+        Kaslr::Randomized { source, slots } => info!(
+            "Kernel binary at {:#x} (KASLR: one of {} places, randomness from {:?})",
+            kernel_args.kernel_elf_offset, slots, source
+        ),
+        Kaslr::Disabled => info!(
+            "Kernel binary at {:#x} (KASLR disabled)",
+            kernel_args.kernel_elf_offset
+        ),
+        Kaslr::Unavailable => warn!(
+            "Kernel binary at {:#x} (KASLR unavailable, the bootloader had no randomness)",
+            kernel_args.kernel_elf_offset
+        ),
+    }
+    if cmdline.kaslr != "on" && cmdline.kaslr != "off" {
+        warn!("Invalid kaslr={} (KASLR stays on)", cmdline.kaslr);
+    }
+    mitigations::init(cmdline.mitigations);
+    for module in kernel_args.modules.iter() {
+        if let Some(hash) = module.sha256 {
+            let hex: arrayvec::ArrayString<64> =
+                hash.iter().fold(arrayvec::ArrayString::new(), |mut s, b| {
+                    let _r = core::fmt::Write::write_fmt(&mut s, format_args!("{:02x}", b));
+                    s
+                });
+            info!("Module {} sha256 {}", module.name(), hex);
+        }
+    }
+
+    // Seed the random number generator (deterministically if requested)
+    let seed = cmdline.seed.parse::<u64>().ok();
+    if seed.is_none() && !cmdline.seed.is_empty() {
+        warn!("Invalid seed={} ignored", cmdline.seed);
+    }
+    crate::entropy::init(seed);
+    crate::scheduler::policy::init(cmdline.sched);
+    crate::softirq::register(
+        crate::softirq::SoftIrq::Timer,
+        crate::timer_wheel::run_expired,
+    );
+    crate::softirq::register(
+        crate::softirq::SoftIrq::Replication,
+        irq::replication_softirq,
+    );
+    crate::softirq::register(crate::softirq::SoftIrq::Rcu, crate::sync::rcu::reclaim);
+    crate::softirq::register(crate::softirq::SoftIrq::Oom, reclaim::oom_softirq);
+    #[cfg(feature = "smoltcp")]
+    crate::softirq::register(crate::softirq::SoftIrq::NetRx, network::rx_softirq);
+    crate::softirq::register(
+        crate::softirq::SoftIrq::Flush,
+        crate::fs::image::flush_softirq,
+    );
+    crate::oom::init(cmdline.oom);
+    crate::ulog::init(cmdline.user_log);
+    crate::nrstats::init(cmdline.nr_batch);
 
     // At this point we should be able to handle exceptions:
     #[cfg(feature = "test-pfault-early")]
@@ -525,6 +838,7 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     // Figure out what this machine supports,
     // fail if it doesn't have what we need.
     assert_required_cpu_features();
+    enable_pat();
     syscall::enable_fast_syscalls();
 
     // Initializes the serial console.
@@ -545,57 +859,43 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     // Set up early memory management
     //
     // We walk the memory regions given to us by uefi, since this consumes
-    // the UEFI iterator we copy the frames into a `ArrayVec`.
+    // the UEFI iterator we copy the frames into a `MemoryMap` (which splits
+    // giant regions and remembers the ones we ignore).
     //
     // Ideally, if this works, we should end up with an early TCache
     // that has a small amount of space we can allocate from, and a list of (yet) unmaintained
     // regions of memory.
-    let mut emanager: Option<mcache::TCacheSp> = None;
-    let mut memory_regions: ArrayVec<Frame, MAX_PHYSICAL_REGIONS> = ArrayVec::new();
+    //
+    // Before that we register the memory that belongs to the kernel, the
+    // bootloader, the firmware and the APIC so no allocator hands it out.
+    reserve_boot_regions(kernel_args);
+    let mut memory_map = MemoryMap::default();
     for region in &mut kernel_args.mm_iter {
         if region.ty == MemoryType::CONVENTIONAL {
             debug!("Found physical memory region {:?}", region);
 
             let base: PAddr = PAddr::from(region.phys_start);
             let size: usize = region.page_count as usize * BASE_PAGE_SIZE;
-            let f = Frame::new(base, size, 0);
-
-            const ONE_MIB: usize = 1 * 1024 * 1024;
-            const EARLY_MEMORY_CAPACITY: usize = 32 * 1024 * 1024;
-            if base.as_usize() >= ONE_MIB {
-                if size > EARLY_MEMORY_CAPACITY && emanager.is_none() {
-                    // This seems like a good frame for the early allocator on the BSP core.
-                    // We don't have NUMA information yet so we'd hope that on
-                    // a NUMA machine this memory will be on node 0.
-                    // Ideally `mem_iter` is ordered by physical address which would increase
-                    // our chances, but the UEFI spec doesn't guarantee anything :S
-                    let (early_frame, high) = f.split_at(EARLY_MEMORY_CAPACITY);
-                    emanager = Some(mcache::TCacheSp::new_with_frame(0, early_frame));
-
-                    if high != Frame::empty() {
-                        assert!(!memory_regions.is_full());
-                        memory_regions.push(high);
-                    }
-                } else {
-                    assert!(!memory_regions.is_full());
-                    memory_regions.push(f);
-                }
-            } else {
-                // Ignore all physical memory below 1 MiB
-                // because it's not worth the hassle of dealing with it
-                // Some of the memory here will be used by coreboot, there we just assume
-                // the memory is free for us to use -- so in case someone
-                // wants to change it have a look there first!
-            }
+            memory_map.add(Frame::new(base, size, 0));
         }
     }
+
+    // This seems like a good frame for the early allocator on the BSP core.
+    // We don't have NUMA information yet so we'd hope that on
+    // a NUMA machine this memory will be on node 0.
+    // Ideally `mem_iter` is ordered by physical address which would increase
+    // our chances, but the UEFI spec doesn't guarantee anything :S
+    const EARLY_MEMORY_CAPACITY: usize = 32 * 1024 * 1024;
+    let emanager = memory_map
+        .take(EARLY_MEMORY_CAPACITY)
+        .map(|early_frame| mcache::TCacheSp::new_with_frame(0, early_frame));
     let emanager = emanager
         .expect("Couldn't build an early physical memory manager, increase system main memory?");
 
     let init_ptable = unsafe { find_current_ptables() }; // Safe, done once during init
     trace!("vspace found");
 
-    let arch = kcb::Arch86Kcb::new(kernel_args, init_apic(), init_ptable);
+    let arch = kcb::Arch86Kcb::new(kernel_args, init_ptable);
 
     // Construct the Kcb so we can access these things later on in the code
     let mut kcb = Kcb::new(kernel_binary, cmdline, emanager, arch, 0);
@@ -609,6 +909,7 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     static_kcb.arch.set_interrupt_stacks(
         OwnedStack::new(128 * BASE_PAGE_SIZE),
         OwnedStack::new(128 * BASE_PAGE_SIZE),
+        OwnedStack::new(32 * BASE_PAGE_SIZE),
     );
     static_kcb
         .arch
@@ -616,6 +917,8 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     static_kcb
         .arch
         .set_save_area(Box::pin(kpi::x86_64::SaveArea::empty()));
+    let apic = lapic::init(&mut static_kcb.arch.init_vspace());
+    static_kcb.arch.set_apic(apic);
     static_kcb.enable_print_buffering(
         String::try_with_capacity(128).expect("Not enough memory to initialize system"),
     );
@@ -630,12 +933,20 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     #[cfg(feature = "test-double-fault")]
     debug::cause_double_fault();
 
+    // Find the UEFI runtime services (wall-clock time and variables)
+    efi::init(static_kcb.arch.kernel_args());
+
     // Initialize the ACPI sub-system (needs alloc)
     {
         let r = acpi::init();
         assert!(r.is_ok());
     }
 
+    // Calibrate the TSC and pick the timer (needs ACPI for the HPET)
+    timer::init();
+    #[cfg(feature = "syscall-budget")]
+    budget::init(cmdline.syscall_budget);
+
     // Initialize the machine topology (needs ACPI and alloc):
     {
         lazy_static::initialize(&atopology::MACHINE_TOPOLOGY);
@@ -660,13 +971,23 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
             "We don't support as many replicas as we have NUMA nodes."
         );
     }
+    splash::progress(BootStage::Topology);
 
     // Identify NUMA region for physical memory (needs topology)
+    memory_map.report();
+    reserved::report();
     let mut annotated_regions = ArrayVec::new();
-    identify_numa_affinity(&memory_regions, &mut annotated_regions);
+    identify_numa_affinity(memory_map.regions(), &mut annotated_regions);
     // Make sure we don't accidentially use the memory_regions but rather,
     // use the correctly `annotated_regions` now!
-    drop(memory_regions);
+    drop(memory_map);
+
+    // Find the devices and take the hardware inventory (needs topology and
+    // memory regions)
+    devices::discover();
+    // Safety: Core initialization, nothing else holds a token for the core
+    let mut core = unsafe { crate::kcb::LocalCore::new() };
+    hwinfo::init(&mut core, annotated_regions.as_slice());
 
     // Initialize memory allocators (needs annotated memory regions, KCB)
     // the memory for those allocators needs to be local to the region.
@@ -688,9 +1009,13 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
         let tcache = mcache::TCache::new(0);
         kcb.set_physical_memory_manager(tcache);
     }
+    if !crate::oom::fill_reserves() {
+        warn!("Not enough memory for the OOM reserves");
+    }
+    splash::progress(BootStage::MemoryInit);
 
     // Set-up interrupt routing drivers (I/O APIC controllers)
-    irq::ioapic_initialize();
+    ioapic::init();
 
     // Create the global operation log and first replica
     // and store it in the BSP kcb
@@ -704,13 +1029,20 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     }
 
     let num_nodes = atopology::MACHINE_TOPOLOGY.num_nodes();
+    let num_replicas = if num_nodes > 0 {
+        ReplicaPlacement::from_arg(cmdline.replicas).replicas(num_nodes)
+    } else {
+        0
+    };
     let func = move |rid: &[AtomicBool; cnr::MAX_REPLICAS_PER_LOG], idx: usize| {
         assert_eq!(rid.len(), cnr::MAX_REPLICAS_PER_LOG);
-        for replica in 0..num_nodes {
+        for replica in 0..num_replicas {
             if rid[replica].load(Ordering::Relaxed) == true {
+                // Any core of the node that holds the replica will do
+                let node = placement::home_node(replica, num_nodes, num_replicas);
                 let mut cores = atopology::MACHINE_TOPOLOGY
                     .nodes()
-                    .nth(replica)
+                    .nth(node)
                     .unwrap()
                     .threads();
                 let core_id = cores.nth(idx - 1).unwrap().id;
@@ -726,6 +1058,19 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
         }
     };
 
+    // Every file-system replica we create from now on starts with the files
+    // of the archive
+    if let Some(module) = kernel_args
+        .modules
+        .iter()
+        .find(|module| module.name() == crate::fs::rootfs::MODULE_NAME)
+    {
+        // Safe: Modules stay mapped in the kernel address space
+        if let Err(e) = crate::fs::rootfs::init(unsafe { module.as_slice() }) {
+            error!("Ignoring module {}: {}", module.name(), e);
+        }
+    }
+
     let cores_per_node = atopology::MACHINE_TOPOLOGY
         .nodes()
         .nth(0)
@@ -776,9 +1121,37 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
         fs_logs,
         fs_replica,
     );
+    splash::progress(BootStage::CoresStarted);
+
+    // Measure the TSC offsets of all cores (needs the cores up)
+    tsc::sync_all();
+    // Start counting the energy of the packages
+    power::init();
+
+    // All cores are up, make the kernel image read-only
+    wx::protect();
+
+    // Start the drivers of the kernel (they may need timers)
+    devices::bind();
+    // Now that the disks are up
+    crate::disklog::start();
+    // Polled from the `NetRx` soft IRQ of this core
+    #[cfg(feature = "smoltcp")]
+    {
+        let wanted_by = [("netlog", netlog), ("ntp", ntp), ("mgmtkey", mgmt)]
+            .iter()
+            .find(|(_arg, set)| *set)
+            .map(|(arg, _set)| *arg);
+        network::start(cmdline.net, wanted_by);
+    }
+    #[cfg(not(feature = "smoltcp"))]
+    if cmdline.net == "on" {
+        warn!("net=on is ignored (it needs the `smoltcp` feature)");
+    }
 
     // Done with initialization, now we go in
     // the arch-independent part:
+    splash::progress(BootStage::Running);
     let _r = xmain();
 
     error!("Returned from main, shutting down...");
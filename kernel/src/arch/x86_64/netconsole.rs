@@ -0,0 +1,295 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Mirrors the console output to a UDP endpoint (`netlog=<ip>:<port>` on the
+//! command line), so machines in a rack can be debugged without a serial
+//! concentrator (e.g., receive it with `socat UDP-LISTEN:6666 stdout`).
+//!
+//! The kernel installs its own logger (instead of klogger's) which prints
+//! complete lines to the serial console (staged per core, see
+//! `crate::logbuf`) and, with `netlog`, keeps a copy of every line (with its
+//! sequence number) and of the output of processes in a ring buffer (the log
+//! on disk, see `crate::disklog`, gets the same copy). Once the network
+//! interface is up ([`super::network::Network`]) every poll sends what's in
+//! the buffer, so output from before the network came up isn't lost (unless
+//! the buffer overflowed, which is reported).
+//!
+//! Panics are only printed on the serial console.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::sync::Mutex;
+use arrayvec::ArrayVec;
+use klogger::sprint;
+use log::{LevelFilter, Metadata, Record};
+
+/// Size of the buffer for output that wasn't sent yet.
+const RING_SIZE: usize = 64 * 1024;
+
+/// Biggest payload of a UDP packet we send.
+const PACKET_SIZE: usize = 1024;
+
+/// UDP port we send from.
+pub const LOCAL_PORT: u16 = 6666;
+
+/// Output that wasn't sent yet (oldest bytes get overwritten).
+struct Ring {
+    data: [u8; RING_SIZE],
+    start: usize,
+    len: usize,
+    /// Bytes overwritten before we could send them.
+    lost: usize,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Ring {
+            data: [0; RING_SIZE],
+            start: 0,
+            len: 0,
+            lost: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len == RING_SIZE {
+            self.start = (self.start + 1) % RING_SIZE;
+            self.len -= 1;
+            self.lost += 1;
+        }
+        self.data[(self.start + self.len) % RING_SIZE] = byte;
+        self.len += 1;
+    }
+
+    /// Copies the oldest bytes into `buf` (without removing them), ends at
+    /// a line break if the buffer has more than what fits.
+    fn peek(&self, buf: &mut [u8]) -> usize {
+        let len = core::cmp::min(self.len, buf.len());
+        for (i, byte) in buf[..len].iter_mut().enumerate() {
+            *byte = self.data[(self.start + i) % RING_SIZE];
+        }
+        if len < self.len {
+            if let Some(newline) = buf[..len].iter().rposition(|b| *b == b'\n') {
+                return newline + 1;
+            }
+        }
+        len
+    }
+
+    /// Removes the oldest `len` bytes.
+    fn consume(&mut self, len: usize) {
+        self.start = (self.start + len) % RING_SIZE;
+        self.len -= len;
+    }
+}
+
+impl fmt::Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.push(byte);
+        }
+        Ok(())
+    }
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring::new());
+
+/// Is the console mirrored?
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Where we send the output to.
+static ENDPOINT: spin::Once<([u8; 4], u16)> = spin::Once::new();
+
+/// Log filter (like klogger's: `level` or `target=level`, comma separated).
+struct Filter {
+    default: LevelFilter,
+    directives: ArrayVec<(&'static str, LevelFilter), 8>,
+}
+
+impl Filter {
+    fn parse(spec: &'static str) -> Filter {
+        let mut filter = Filter {
+            default: LevelFilter::Info,
+            directives: ArrayVec::new(),
+        };
+        for directive in spec.split(',').filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    let level = level.parse().unwrap_or(LevelFilter::Info);
+                    let _r = filter.directives.try_push((target, level));
+                }
+                None => filter.default = directive.parse().unwrap_or(LevelFilter::Info),
+            }
+        }
+        filter
+    }
+
+    /// Level of the most specific directive for `target`.
+    fn level(&self, target: &str) -> LevelFilter {
+        self.directives
+            .iter()
+            .filter(|(prefix, _)| target.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, core::cmp::max)
+    }
+}
+
+struct Logger {
+    filter: spin::Once<Filter>,
+}
+
+static LOGGER: Logger = Logger {
+    filter: spin::Once::new(),
+};
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.get().map_or(false, |filter| {
+            metadata.level() <= filter.level(metadata.target())
+        })
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let core = super::kcb::try_get_kcb().map_or(0, |kcb| kcb.arch.id());
+            let args = format_args!(
+                "[{:5}] - {}: {}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+            crate::logbuf::stage(core, args, |line| {
+                let seq = {
+                    let _r = klogger::SERIAL_LINE_MUTEX.lock();
+                    sprint!("{}\r\n", line);
+                    crate::logbuf::sequence()
+                };
+                mirror(format_args!("{:08} {}\n", seq, line));
+            });
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Parses `<a.b.c.d>:<port>`.
+fn parse_endpoint(endpoint: &str) -> Option<([u8; 4], u16)> {
+    let (ip, port) = endpoint.split_once(':')?;
+    let mut octets = [0u8; 4];
+    let mut parts = ip.split('.');
+    for octet in octets.iter_mut() {
+        *octet = parts.next()?.parse().ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((octets, port.parse().ok()?))
+}
+
+/// Mirrors the console to `endpoint` (once the logger is installed).
+///
+/// Returns false if `endpoint` is invalid.
+pub fn init(endpoint: &str) -> bool {
+    let endpoint = match parse_endpoint(endpoint) {
+        Some(endpoint) => endpoint,
+        None => return false,
+    };
+    ENDPOINT.call_once(|| endpoint);
+    ENABLED.store(true, Ordering::Relaxed);
+    true
+}
+
+/// Installs the kernel logger with the log filter `filter`, returns false
+/// if there already is a logger.
+pub fn install_logger(filter: &'static str) -> bool {
+    let filter = LOGGER.filter.call_once(|| Filter::parse(filter));
+    if log::set_logger(&LOGGER).is_err() {
+        return false;
+    }
+    log::set_max_level(filter.max());
+    true
+}
+
+/// Keeps a copy of `args` to send it once the network is up (and to write
+/// it to the disk log).
+pub fn mirror(args: fmt::Arguments) {
+    crate::disklog::mirror(args);
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    // Don't spin if we interrupted ourselves (the output gets lost)
+    if let Some(mut ring) = RING.try_lock() {
+        let _r = ring.write_fmt(args);
+    }
+}
+
+/// Keeps a copy of `s` to send it once the network is up.
+pub fn write(s: &str) {
+    mirror(format_args!("{}", s));
+}
+
+/// Opens the socket we send the output from, `None` if the console isn't
+/// mirrored.
+#[cfg(feature = "smoltcp")]
+pub fn attach(
+    sockets: &mut smoltcp::socket::SocketSet<'static>,
+) -> Option<smoltcp::socket::SocketHandle> {
+    use alloc::vec;
+    use smoltcp::socket::{UdpPacketMetadata, UdpSocket, UdpSocketBuffer};
+
+    ENDPOINT.get()?;
+    let rx_buffer = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY], vec![0; 64]);
+    let tx_buffer = UdpSocketBuffer::new(
+        vec![UdpPacketMetadata::EMPTY; 16],
+        vec![0; 16 * PACKET_SIZE],
+    );
+    let mut socket = UdpSocket::new(rx_buffer, tx_buffer);
+    socket.bind(LOCAL_PORT).ok()?;
+    Some(sockets.add(socket))
+}
+
+/// Sends the buffered output (as much as the socket takes).
+#[cfg(feature = "smoltcp")]
+pub fn flush(
+    sockets: &mut smoltcp::socket::SocketSet<'static>,
+    handle: smoltcp::socket::SocketHandle,
+) {
+    use smoltcp::socket::UdpSocket;
+    use smoltcp::wire::{IpAddress, IpEndpoint};
+
+    let ([a, b, c, d], port) = match ENDPOINT.get() {
+        Some(endpoint) => *endpoint,
+        None => return,
+    };
+    let endpoint = IpEndpoint::new(IpAddress::v4(a, b, c, d), port);
+    let mut socket = sockets.get::<UdpSocket>(handle);
+    let mut packet = [0u8; PACKET_SIZE];
+
+    while socket.can_send() {
+        // Don't hold the lock while we send: smoltcp logs too
+        let (len, lost) = match RING.try_lock() {
+            Some(mut ring) => (
+                ring.peek(&mut packet),
+                core::mem::replace(&mut ring.lost, 0),
+            ),
+            None => return,
+        };
+        if lost > 0 {
+            let mut notice = arrayvec::ArrayString::<64>::new();
+            let _r = writeln!(notice, "[netconsole: {} bytes lost]", lost);
+            let _r = socket.send_slice(notice.as_bytes(), endpoint);
+        }
+        if len == 0 || socket.send_slice(&packet[..len], endpoint).is_err() {
+            break;
+        }
+        RING.lock().consume(len);
+    }
+}
@@ -0,0 +1,40 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! System-wide interrupt moderation tunables for the network drivers.
+//!
+//! The drivers run in user-space (rump), the kernel only keeps the tunables
+//! so they can be changed at run-time (see `System::set_net_moderation`).
+//! How they are used is described in [`kpi::system::NetModeration`].
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use kpi::system::NetModeration;
+use log::info;
+
+static POLL_THRESHOLD: AtomicU64 = AtomicU64::new(NetModeration::DEFAULT.poll_threshold);
+static IDLE_POLLS: AtomicU64 = AtomicU64::new(NetModeration::DEFAULT.idle_polls);
+
+/// Returns the current tunables.
+pub fn get() -> NetModeration {
+    NetModeration {
+        poll_threshold: POLL_THRESHOLD.load(Ordering::Relaxed),
+        idle_polls: IDLE_POLLS.load(Ordering::Relaxed),
+    }
+}
+
+/// Updates the tunables, fields that are 0 are left unchanged.
+pub fn set(moderation: NetModeration) -> NetModeration {
+    if moderation.poll_threshold != 0 {
+        POLL_THRESHOLD.store(moderation.poll_threshold, Ordering::Relaxed);
+    }
+    if moderation.idle_polls != 0 {
+        IDLE_POLLS.store(moderation.idle_polls, Ordering::Relaxed);
+    }
+
+    let current = get();
+    if moderation.poll_threshold != 0 || moderation.idle_polls != 0 {
+        info!("Network interrupt moderation: {:?}", current);
+    }
+    current
+}
@@ -0,0 +1,469 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The kernel's network interface (a vmxnet3 or e1000 NIC with the smoltcp
+//! stack).
+//!
+//! The interface uses the first NIC `devices` found that we have a driver
+//! for ([`Nic`]). Without one, [`Network::loopback`] brings up an interface
+//! that only reaches itself (127.0.0.1), e.g., to test network code on
+//! machines without a supported NIC.
+//!
+//! Once the interface is polled it answers ARP requests for its address and
+//! pings (smoltcp does this without any sockets). [`Network::netstat`] logs
+//! the interface state which helps to figure out why a machine isn't
+//! reachable (e.g., in CI).
+//!
+//! smoltcp has no timers of its own: TCP retransmissions, delayed ACKs etc.
+//! only happen when the interface is polled after their deadline. Users of the
+//! interface should therefore not spin on [`Network::poll`] with their own
+//! clock but alternate between [`Network::poll`] and [`Network::wait`] which
+//! returns when a packet arrives or when the next deadline of the stack
+//! ([`Network::poll_delay`]) passed.
+//!
+//! At boot the kernel only brings up the interface with `net=on` or if the
+//! command line asks for something that needs it, e.g., `netlog` ([`start`]),
+//! otherwise the NIC is left to the drivers of processes (e.g., rump). The
+//! interface is then polled by the `NetRx` soft IRQ of the boot core
+//! ([`rx_softirq`]), which a timer raises at the next deadline of the stack
+//! and at least every [`POLL_INTERVAL`].
+//!
+//! The stack runs in the `network` panic domain (see `crate::domain`): if it
+//! panics, the kernel keeps running without network and [`Network::new`] and
+//! [`Network::poll`] fail with `KError::SubsystemFailed`.
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use core::time::Duration;
+
+use kpi::system::DeviceResource;
+use log::{debug, info, warn};
+use smoltcp::iface::{EthernetInterface, EthernetInterfaceBuilder, NeighborCache};
+use smoltcp::phy::{Device, DeviceCapabilities, Loopback, RxToken, TxToken};
+use smoltcp::socket::{Socket, SocketHandle, SocketSet};
+use smoltcp::time::{Duration as NetDuration, Instant};
+use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr};
+use vmxnet3::smoltcp::{DevQueuePhy, PhyStats};
+use vmxnet3::vmx::VMXNet3;
+
+use super::e1000::{self, E1000};
+use super::remote::Remote;
+use super::timesync::TimeSync;
+
+use crate::domain::NETWORK;
+use crate::error::KError;
+use crate::memory::reserved;
+use crate::memory::vspace::MapAction;
+use crate::memory::{PAddr, BASE_PAGE_SIZE};
+use crate::round_up;
+use crate::sync::Mutex;
+use crate::timer_wheel;
+
+/// MAC address of the interface.
+pub const ETHERNET_ADDR: EthernetAddress = EthernetAddress([0x56, 0xb4, 0x44, 0xe9, 0x62, 0xdc]);
+
+/// IPv4 address of the interface (the host is at 172.31.0.20, see `run.py`).
+pub const IP_ADDR: [u8; 4] = [172, 31, 0, 10];
+
+/// IPv4 address of the loopback interface.
+pub const LOOPBACK_ADDR: [u8; 4] = [127, 0, 0, 1];
+
+/// How often the interface brought up at boot is polled (the NICs run
+/// without interrupts).
+pub const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How often [`Network::wait`] checks for received packets.
+pub const RX_CHECK: Duration = Duration::from_millis(1);
+
+/// The interface brought up at boot (see [`start`]).
+static INTERFACE: Mutex<Option<Network>> = Mutex::new(None);
+
+/// The current time for the network stack (time since boot).
+pub fn now() -> Instant {
+    Instant::from_millis(rawtime::duration_since_boot().as_millis() as i64)
+}
+
+/// A NIC we have a driver for.
+pub enum Nic {
+    Vmxnet3(DevQueuePhy),
+    E1000(E1000),
+    /// Everything sent is received again.
+    Loopback(Loopback),
+}
+
+impl Nic {
+    /// Initializes the first NIC (found by `devices`) we have a driver for.
+    fn new() -> Result<Nic, KError> {
+        let kcb = super::kcb::per_core();
+        let nic = crate::devices::find_by(|info| info.name == "vmxnet3" || e1000::matches(info))
+            .ok_or(KError::NetworkDeviceUnavailable)?;
+        let driver = if nic.name == "vmxnet3" {
+            "vmxnet3"
+        } else {
+            "e1000"
+        };
+        crate::devices::attach(nic.id, driver)?;
+        for resource in nic.resources.iter() {
+            if let DeviceResource::Memory { base, size } = *resource {
+                let size = round_up!(size as usize, BASE_PAGE_SIZE);
+                reserved::check(PAddr::from(base), size, None)?;
+                kcb.arch.init_vspace().map_identity(
+                    PAddr::from(base),
+                    size,
+                    MapAction::ReadWriteKernel,
+                )?;
+            }
+        }
+
+        if driver == "vmxnet3" {
+            let mut vmx = VMXNet3::new(2, 2)?;
+            vmx.attach_pre()?;
+            vmx.init();
+            let device = DevQueuePhy::new(vmx).map_err(|_e| KError::OutOfMemory)?;
+            Ok(Nic::Vmxnet3(device))
+        } else {
+            // The registers are in the first BAR
+            let regs = nic
+                .resources
+                .iter()
+                .find_map(|resource| match *resource {
+                    DeviceResource::Memory { base, .. } => Some(base),
+                    DeviceResource::IoPorts { .. } => None,
+                })
+                .ok_or(KError::NetworkDeviceUnavailable)?;
+            super::devices::enable_bus_master(&nic);
+            Ok(Nic::E1000(E1000::new(regs, ETHERNET_ADDR)?))
+        }
+    }
+
+    /// Packet counters and neighbors of the NIC (the loopback interface
+    /// doesn't count).
+    pub fn stats(&self) -> Option<&PhyStats> {
+        match self {
+            Nic::Vmxnet3(nic) => Some(nic.stats()),
+            Nic::E1000(nic) => Some(nic.stats()),
+            Nic::Loopback(_nic) => None,
+        }
+    }
+
+    /// Are there received packets waiting to be processed?
+    pub fn rx_pending(&mut self) -> bool {
+        match self {
+            Nic::Vmxnet3(nic) => nic.rx_pending(),
+            Nic::E1000(nic) => nic.rx_pending(),
+            // Nothing arrives from the outside, `poll` receives what it sent
+            Nic::Loopback(_nic) => false,
+        }
+    }
+}
+
+impl<'a> Device<'a> for Nic {
+    type RxToken = NicRx<'a>;
+    type TxToken = NicTx<'a>;
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        match self {
+            Nic::Vmxnet3(nic) => nic
+                .receive()
+                .map(|(rx, tx)| (NicRx::Vmxnet3(rx), NicTx::Vmxnet3(tx))),
+            Nic::E1000(nic) => nic
+                .receive()
+                .map(|(rx, tx)| (NicRx::E1000(rx), NicTx::E1000(tx))),
+            Nic::Loopback(nic) => nic
+                .receive()
+                .map(|(rx, tx)| (NicRx::Loopback(rx), NicTx::Loopback(tx))),
+        }
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        match self {
+            Nic::Vmxnet3(nic) => nic.transmit().map(NicTx::Vmxnet3),
+            Nic::E1000(nic) => nic.transmit().map(NicTx::E1000),
+            Nic::Loopback(nic) => nic.transmit().map(NicTx::Loopback),
+        }
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        match self {
+            Nic::Vmxnet3(nic) => nic.capabilities(),
+            Nic::E1000(nic) => nic.capabilities(),
+            Nic::Loopback(nic) => nic.capabilities(),
+        }
+    }
+}
+
+/// A received packet of a [`Nic`].
+pub enum NicRx<'a> {
+    Vmxnet3(<DevQueuePhy as Device<'a>>::RxToken),
+    E1000(<E1000 as Device<'a>>::RxToken),
+    Loopback(<Loopback as Device<'a>>::RxToken),
+}
+
+impl<'a> RxToken for NicRx<'a> {
+    fn consume<R, F>(self, timestamp: Instant, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        match self {
+            NicRx::Vmxnet3(rx) => rx.consume(timestamp, f),
+            NicRx::E1000(rx) => rx.consume(timestamp, f),
+            NicRx::Loopback(rx) => rx.consume(timestamp, f),
+        }
+    }
+}
+
+/// A packet to send on a [`Nic`].
+pub enum NicTx<'a> {
+    Vmxnet3(<DevQueuePhy as Device<'a>>::TxToken),
+    E1000(<E1000 as Device<'a>>::TxToken),
+    Loopback(<Loopback as Device<'a>>::TxToken),
+}
+
+impl<'a> TxToken for NicTx<'a> {
+    fn consume<R, F>(self, timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        match self {
+            NicTx::Vmxnet3(tx) => tx.consume(timestamp, len, f),
+            NicTx::E1000(tx) => tx.consume(timestamp, len, f),
+            NicTx::Loopback(tx) => tx.consume(timestamp, len, f),
+        }
+    }
+}
+
+/// The network interface and the sockets on it.
+pub struct Network {
+    pub iface: EthernetInterface<'static, Nic>,
+    pub sockets: SocketSet<'static>,
+    /// Socket that sends the console output (see `netconsole`).
+    netconsole: Option<SocketHandle>,
+    /// Socket that synchronizes the wall clock (see `timesync`).
+    timesync: Option<TimeSync>,
+    /// Socket of the management console (see `remote`).
+    remote: Option<Remote>,
+}
+
+// Safety: The NIC is only accessed by whoever holds `INTERFACE` (its
+// registers and rings aren't tied to the core that initialized it).
+unsafe impl Send for Network {}
+
+impl Network {
+    /// Initializes the NIC and brings up the interface with
+    /// [`ETHERNET_ADDR`] and [`IP_ADDR`] (and starts sending the console
+    /// output if it is mirrored, synchronizing the wall clock if there is an
+    /// SNTP server and serving the management console if it has a key).
+    pub fn new() -> Result<Network, KError> {
+        NETWORK.enter(|| {
+            let [a, b, c, d] = IP_ADDR;
+            let mut network =
+                Network::with_nic(Nic::new()?, IpCidr::new(IpAddress::v4(a, b, c, d), 24));
+            network.netconsole = super::netconsole::attach(&mut network.sockets);
+            network.timesync = super::timesync::attach(&mut network.sockets);
+            network.remote = super::remote::attach(&mut network.sockets);
+            Ok(network)
+        })?
+    }
+
+    /// Brings up the loopback interface with [`LOOPBACK_ADDR`] (needs no
+    /// NIC).
+    pub fn loopback() -> Network {
+        let [a, b, c, d] = LOOPBACK_ADDR;
+        info!("Network on the loopback interface");
+        Network::with_nic(
+            Nic::Loopback(Loopback::new()),
+            IpCidr::new(IpAddress::v4(a, b, c, d), 8),
+        )
+    }
+
+    fn with_nic(nic: Nic, addr: IpCidr) -> Network {
+        let iface = EthernetInterfaceBuilder::new(nic)
+            .ip_addrs([addr])
+            .ethernet_addr(ETHERNET_ADDR)
+            .neighbor_cache(NeighborCache::new(BTreeMap::new()))
+            .finalize();
+        Network {
+            iface,
+            sockets: SocketSet::new(vec![]),
+            netconsole: None,
+            timesync: None,
+            remote: None,
+        }
+    }
+
+    /// Processes received packets, sends pending ones (including ARP and
+    /// ICMP replies) and handles expired TCP timers.
+    ///
+    /// Returns true if the state of a socket may have changed. Fails with
+    /// `KError::SubsystemFailed` once the network stack panicked.
+    pub fn poll(&mut self) -> Result<bool, KError> {
+        NETWORK.enter(|| self.poll_stack())
+    }
+
+    fn poll_stack(&mut self) -> bool {
+        if let Some(handle) = self.netconsole {
+            super::netconsole::flush(&mut self.sockets, handle);
+        }
+        while crate::fault::fire(crate::fault::Fault::DropPacket) {
+            match self.iface.device_mut().receive() {
+                Some((rx, _tx)) => {
+                    let _r = rx.consume(now(), |_packet| Ok(()));
+                }
+                None => break,
+            }
+        }
+        let readiness_changed = match self.iface.poll(&mut self.sockets, now()) {
+            Ok(readiness_changed) => readiness_changed,
+            Err(e) => {
+                debug!("poll error: {}", e);
+                false
+            }
+        };
+        // Right after the packets arrived, for the SNTP reply's timestamp
+        if let Some(timesync) = self.timesync.as_mut() {
+            timesync.poll(&mut self.sockets);
+        }
+        if let Some(remote) = self.remote.as_mut() {
+            remote.poll(&mut self.sockets);
+        }
+        readiness_changed
+    }
+
+    /// How long until the stack has to be polled again even if no packets
+    /// arrive (e.g., for a retransmission), `None` if there is nothing to do
+    /// until the next packet.
+    pub fn poll_delay(&self) -> Option<Duration> {
+        self.iface
+            .poll_delay(&self.sockets, now())
+            .map(|d: NetDuration| Duration::from_millis(d.total_millis()))
+    }
+
+    /// Waits until a packet arrives, the next deadline of the stack passes or
+    /// `max` elapsed (whatever comes first).
+    ///
+    /// The NIC runs without interrupts: the core halts until a timer wakes it
+    /// up every [`RX_CHECK`] to look at the receive queue.
+    pub fn wait(&mut self, max: Duration) {
+        let timeout = self.poll_delay().map_or(max, |delay| delay.min(max));
+        let start = rawtime::Instant::now();
+        while !self.iface.device_mut().rx_pending() {
+            let left = match timeout.checked_sub(start.elapsed()) {
+                Some(left) if left > Duration::from_secs(0) => left,
+                _ => break,
+            };
+            match super::sleep::Alarm::set(left.min(RX_CHECK).as_nanos() as u64) {
+                Ok(alarm) => alarm.wait(),
+                Err(_e) => core::hint::spin_loop(),
+            }
+        }
+    }
+
+    /// Logs interface addresses and counters, the neighbors we know about
+    /// and the state of all sockets.
+    pub fn netstat(&self) {
+        info!("Interface {}:", self.iface.ethernet_addr());
+        for cidr in self.iface.ip_addrs() {
+            info!("  inet {}", cidr);
+        }
+
+        if let Some(stats) = self.iface.device().stats() {
+            info!(
+                "  RX packets {} bytes {} (ARP {} ICMP {})",
+                stats.rx_packets.get(),
+                stats.rx_bytes.get(),
+                stats.rx_arp.get(),
+                stats.rx_icmp.get()
+            );
+            info!(
+                "  TX packets {} bytes {}",
+                stats.tx_packets.get(),
+                stats.tx_bytes.get()
+            );
+
+            info!("Neighbors:");
+            for (ip, mac) in stats.neighbors.borrow().iter() {
+                info!("  {} at {}", ip, mac);
+            }
+        }
+
+        info!("Sockets:");
+        for socket in self.sockets.iter() {
+            match socket {
+                Socket::Tcp(tcp) => info!(
+                    "  tcp {} {} {}",
+                    tcp.local_endpoint(),
+                    tcp.remote_endpoint(),
+                    tcp.state()
+                ),
+                Socket::Udp(udp) => info!("  udp {}", udp.endpoint()),
+                Socket::Icmp(_) => info!("  icmp"),
+                Socket::Raw(_) => info!("  raw"),
+                _ => info!("  unknown"),
+            }
+        }
+    }
+}
+
+/// Brings up the interface if `net` is `on`, or if it's `auto` and
+/// `wanted_by` names a boot argument that needs it (e.g., `netlog`), and polls
+/// it from the `NetRx` soft IRQ of the current core (the boot core).
+pub fn start(net: &str, wanted_by: Option<&str>) {
+    let wanted = match net {
+        "on" => true,
+        "off" => {
+            if let Some(arg) = wanted_by {
+                warn!("{} doesn't work without network (net=off)", arg);
+            }
+            false
+        }
+        "auto" => wanted_by.is_some(),
+        _ => {
+            warn!("Invalid net={} ignored", net);
+            wanted_by.is_some()
+        }
+    };
+    if !wanted {
+        return;
+    }
+
+    let network = match Network::new() {
+        Ok(network) => network,
+        Err(e) => {
+            warn!("Kernel network interface not started: {}", e);
+            return;
+        }
+    };
+    *INTERFACE.lock() = Some(network);
+    match timer_wheel::add(POLL_INTERVAL, poll_interface, 0) {
+        Ok(_timer) => info!(
+            "Kernel network interface up, polled every {} ms",
+            POLL_INTERVAL.as_millis()
+        ),
+        Err(e) => warn!("Can't poll the kernel network interface: {}", e),
+    }
+}
+
+/// Raises the `NetRx` soft IRQ (timer callback).
+fn poll_interface(_arg: u64) {
+    crate::softirq::raise(crate::softirq::SoftIrq::NetRx);
+}
+
+/// Polls the interface brought up at boot (`NetRx` soft IRQ), again once the
+/// next timer of the stack (e.g., a TCP retransmission) is due.
+pub fn rx_softirq() {
+    let mut next = POLL_INTERVAL;
+    // Skip a round rather than spin on a lock the interrupted code holds
+    if let Some(mut interface) = INTERFACE.try_lock() {
+        if let Some(network) = interface.as_mut() {
+            if let Err(e) = network.poll() {
+                warn!("Kernel network interface stopped: {}", e);
+                *interface = None;
+                return;
+            }
+            next = network.poll_delay().map_or(next, |delay| delay.min(next));
+        }
+    }
+    if let Err(e) = timer_wheel::add(next, poll_interface, 0) {
+        warn!("Stopped polling the kernel network interface: {}", e);
+    }
+}
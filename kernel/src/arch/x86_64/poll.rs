@@ -0,0 +1,171 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Waiting for files, loopback sockets, the keyboard and futex words to
+//! become ready (`FileOperation::Poll`).
+//!
+//! If none of the entries is ready and the caller gave a timeout, the core
+//! marks itself as polling in [`POLLERS`] and halts with `monitor`/`mwait`
+//! on its wake-up counter in [`WAKEUPS`]. Whatever can make an entry ready
+//! calls [`notify`], which bumps the counters of all polling cores: a
+//! datagram arriving on a loopback socket (`localhost::send_to`), a key
+//! queued for the focus (`ps2`) and `FutexWake` (`futex::wake`). We don't
+//! track who polls what, a woken core checks all its entries again. On CPUs
+//! without `mwait` we spin for a bit instead.
+//!
+//! Like futex waits we also return to user-space at every interrupt (with
+//! the time that is left of the timeout), `PollSet::wait` calls us again.
+
+use core::hint::spin_loop;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crossbeam_utils::CachePadded;
+use kpi::io::{PollEvents, PollFd, PollKind, POLL_FOREVER};
+
+use crate::cnrfs;
+use crate::error::KError;
+use crate::process::Pid;
+use crate::sync::Mutex;
+
+use super::process::validate_user_range;
+use super::sleep::Alarm;
+use super::usercopy::{read_user, write_user};
+use super::MAX_CORES;
+
+/// How often we check the wake-up counter without `mwait`.
+const SPIN_LIMIT: usize = 4096;
+
+/// Which cores wait in `Poll` (indexed by core).
+static POLLERS: Mutex<[bool; MAX_CORES]> = Mutex::new([false; MAX_CORES]);
+
+/// Wake-up counter of every core (indexed by core), bumped with `POLLERS`
+/// locked.
+static WAKEUPS: [CachePadded<AtomicU64>; MAX_CORES] = {
+    const ZERO: CachePadded<AtomicU64> = CachePadded::new(AtomicU64::new(0));
+    [ZERO; MAX_CORES]
+};
+
+/// The current core polls until it's dropped.
+struct Polling {
+    core: usize,
+    /// The wake-up counter when we started.
+    seen: u64,
+}
+
+impl Polling {
+    fn start(core: usize) -> Polling {
+        let mut pollers = POLLERS.lock();
+        pollers[core] = true;
+        Polling {
+            core,
+            seen: WAKEUPS[core].load(Ordering::Acquire),
+        }
+    }
+
+    /// Halts the core until [`notify`] or an interrupt wakes it up.
+    fn halt(&self) {
+        let wakeup = &WAKEUPS[self.core];
+        if !super::barrier::halt_until_changed(wakeup, self.seen) {
+            for _i in 0..SPIN_LIMIT {
+                if wakeup.load(Ordering::Acquire) != self.seen {
+                    break;
+                }
+                spin_loop();
+            }
+        }
+    }
+}
+
+impl Drop for Polling {
+    fn drop(&mut self) {
+        POLLERS.lock()[self.core] = false;
+    }
+}
+
+/// Wakes up all cores that wait in `Poll` (something might be ready).
+pub fn notify() {
+    let pollers = POLLERS.lock();
+    for (core, polling) in pollers.iter().enumerate() {
+        if *polling {
+            // The store wakes the core from `mwait`
+            WAKEUPS[core].fetch_add(1, Ordering::Release);
+        }
+    }
+}
+
+/// Readiness of `entry` of `pid` for its events.
+fn readiness(pid: Pid, entry: &PollFd) -> Result<PollEvents, KError> {
+    let events = entry.events();
+    match entry.kind() {
+        PollKind::File => cnrfs::MlnrKernelNode::poll(pid, entry.fd, events),
+        #[cfg(feature = "smoltcp")]
+        PollKind::Socket => super::localhost::poll(pid, entry.fd, events),
+        #[cfg(not(feature = "smoltcp"))]
+        PollKind::Socket => Ok(PollEvents::POLLNVAL),
+        PollKind::Keyboard => crate::keyboard::poll(pid, events),
+        PollKind::Futex => super::futex::poll(pid, entry.fd, entry.arg, events),
+        PollKind::Unknown => Ok(PollEvents::POLLNVAL),
+    }
+}
+
+/// Updates `revents` of the `count` entries at user-space address `fds`,
+/// returns how many are ready.
+fn check(pid: Pid, fds: u64, count: usize) -> Result<u64, KError> {
+    let mut ready = 0;
+    for idx in 0..count {
+        let entry = fds + (idx * size_of::<PollFd>()) as u64;
+        let mut pfd: PollFd = read_user(entry)?;
+        let revents = readiness(pid, &pfd)?;
+        if !revents.is_empty() {
+            ready += 1;
+        }
+        pfd.revents = revents.bits();
+        write_user(entry, &pfd)?;
+    }
+    Ok(ready)
+}
+
+/// Checks the `PollFd` array of `len` bytes at user-space address `fds` of
+/// `pid`. If no entry is ready waits until one might be, an interrupt
+/// arrives or `timeout` (in ns, 0 to return right away, `POLL_FOREVER` for
+/// none) is up.
+///
+/// Returns the number of ready entries and the nanoseconds left of
+/// `timeout`.
+pub fn poll(
+    pid: Pid,
+    core: usize,
+    fds: u64,
+    len: usize,
+    timeout: u64,
+) -> Result<(u64, u64), KError> {
+    validate_user_range(pid, fds, len, true)?;
+    let count = len / size_of::<PollFd>();
+    if timeout == 0 {
+        return Ok((check(pid, fds, count)?, 0));
+    }
+
+    let alarm = if timeout != POLL_FOREVER {
+        Some(Alarm::set(timeout)?)
+    } else {
+        None
+    };
+    let left = || {
+        alarm
+            .as_ref()
+            .map_or(POLL_FOREVER, |alarm| alarm.remaining())
+    };
+
+    {
+        // A `notify` after this finds us, one before it made its entry
+        // ready already
+        let polling = Polling::start(core);
+        let ready = check(pid, fds, count)?;
+        if ready > 0 {
+            return Ok((ready, left()));
+        }
+        polling.halt();
+    }
+    Ok((check(pid, fds, count)?, left()))
+}
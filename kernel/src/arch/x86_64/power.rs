@@ -0,0 +1,363 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Power management for energy measurements: the frequency (P-states) and
+//! idle states (C-states) of the cores and the energy they use (RAPL).
+//!
+//! `System::set_power_policy` (needs the `POWER` capability, e.g.,
+//! `initcaps=power`) sets for all cores:
+//!
+//! * A frequency governor: We request a ratio in `IA32_PERF_CTL` (Enhanced
+//!   SpeedStep) between the lowest and highest ratio of `MSR_PLATFORM_INFO`,
+//!   turbo ratios up to the one of `MSR_TURBO_RATIO_LIMIT`. We don't
+//!   evaluate the ACPI `_PSS` objects (no AML interpreter at that point),
+//!   on the machines we use they list the same ratios. `Governor::Firmware`
+//!   (the default) puts back what the cores had at boot.
+//! * What idle cores do ([`idle`]): spin, `hlt` or `mwait` with the deepest
+//!   C-state CPUID leaf 5 lists.
+//!
+//! RAPL counts the energy of every package (and its DRAM) in 32-bit
+//! registers that wrap around after 20+ minutes at 200 W. We add up what
+//! they counted since boot whenever someone asks (the stats system call and
+//! `System::power`), long measurements have to ask at least that often.
+
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid_count;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use fallible_collections::FallibleVec;
+use kpi::process::Capabilities;
+use kpi::system::{Governor, IdleState, PackageEnergy, PackageId, PowerInfo};
+use log::{info, warn};
+use x86::cpuid::CpuId;
+
+use crate::error::KError;
+use crate::nr;
+use crate::process::Pid;
+use crate::sync::Mutex;
+
+use super::kcb;
+use super::measure::{rdmsr_safe, wrmsr_safe};
+use super::MAX_CORES;
+
+#[cfg(target_os = "none")]
+global_asm!(include_str!("power.S"), options(att_syntax));
+
+extern "C" {
+    fn nrk_mwait_idle(word: u64, hint: u32);
+}
+
+const MSR_PLATFORM_INFO: u32 = 0xce;
+const IA32_PERF_CTL: u32 = 0x199;
+const MSR_TURBO_RATIO_LIMIT: u32 = 0x1ad;
+const MSR_RAPL_POWER_UNIT: u32 = 0x606;
+const MSR_PKG_ENERGY_STATUS: u32 = 0x611;
+const MSR_DRAM_ENERGY_STATUS: u32 = 0x619;
+
+/// The ratio in `IA32_PERF_CTL`.
+const PERF_CTL_RATIO: u64 = 0xff << 8;
+
+/// How long we wait for the other cores (to apply a policy or read their
+/// energy counters).
+#[cfg(not(feature = "bsp-only"))]
+const REMOTE_TIMEOUT: core::time::Duration = core::time::Duration::from_secs(1);
+
+/// `BOOT_PERF_CTL` of a core we didn't change yet.
+const UNSET: u64 = u64::MAX;
+
+static GOVERNOR: Mutex<Governor> = Mutex::new(Governor::Firmware);
+
+/// What idle cores do (an index in `IdleState::ALL`).
+static IDLE: AtomicUsize = AtomicUsize::new(IdleState::Halt as usize);
+
+/// The `mwait` hint for `IdleState::Mwait`.
+static MWAIT_HINT: AtomicU32 = AtomicU32::new(0);
+
+/// `IA32_PERF_CTL` of every core before we changed it.
+static BOOT_PERF_CTL: [AtomicU64; MAX_CORES] = {
+    const INIT: AtomicU64 = AtomicU64::new(UNSET);
+    [INIT; MAX_CORES]
+};
+
+/// The energy counters of every package.
+static ENERGY: Mutex<Vec<Counter>> = Mutex::new(Vec::new());
+
+/// The ratios (frequency in 100 MHz) the cores support.
+#[derive(Debug, Clone, Copy)]
+struct Ratios {
+    min: u8,
+    max: u8,
+    turbo: u8,
+}
+
+fn ratios() -> Result<Ratios, KError> {
+    let eist = CpuId::new()
+        .get_feature_info()
+        .map_or(false, |f| f.has_eist());
+    if !eist {
+        return Err(KError::FrequencyControlUnavailable);
+    }
+
+    let info = rdmsr_safe(MSR_PLATFORM_INFO).map_err(|_e| KError::FrequencyControlUnavailable)?;
+    let (max, min) = ((info >> 8) as u8, (info >> 40) as u8);
+    if min == 0 || max < min {
+        return Err(KError::FrequencyControlUnavailable);
+    }
+    // The ratio limit for one active core is the highest
+    let turbo = rdmsr_safe(MSR_TURBO_RATIO_LIMIT).map_or(max, |limits| (limits as u8).max(max));
+
+    Ok(Ratios { min, max, turbo })
+}
+
+/// The `mwait` hint for the deepest C-state of the CPU.
+fn mwait_hint() -> Result<u32, KError> {
+    let mwait = CpuId::new()
+        .get_feature_info()
+        .map_or(false, |f| f.has_monitor_mwait());
+    if !mwait {
+        return Err(KError::MwaitUnavailable);
+    }
+
+    let (max_leaf, leaf) = unsafe { (__cpuid_count(0, 0).eax, __cpuid_count(5, 0)) };
+    // ECX bit 0: EDX has the number of sub-states of C0 to C7 (4 bits each)
+    if max_leaf < 5 || leaf.ecx & 0x1 == 0 {
+        return Ok(0);
+    }
+    let deepest = (1..8)
+        .rev()
+        .find(|c| (leaf.edx >> (4 * c)) & 0xf != 0)
+        .unwrap_or(1);
+    // The hint of C1 is 0, C2 is 0x10 etc.
+    Ok((deepest - 1) << 4)
+}
+
+/// Sets the frequency `governor` and `idle` state of all cores for process
+/// `pid`, which needs the `POWER` capability.
+pub fn set_policy(pid: Pid, governor: Governor, idle: IdleState) -> Result<(), KError> {
+    if !nr::KernelNode::capabilities(pid)?.contains(Capabilities::POWER) {
+        return Err(KError::MissingCapability);
+    }
+
+    let ratio = match governor {
+        Governor::Firmware => None,
+        Governor::Performance => Some(ratios()?.turbo),
+        Governor::Powersave => Some(ratios()?.min),
+        Governor::Fixed(ratio) => {
+            let ratios = ratios()?;
+            if ratio < ratios.min || ratio > ratios.turbo {
+                return Err(KError::RatioOutOfRange {
+                    ratio,
+                    min: ratios.min,
+                    max: ratios.turbo,
+                });
+            }
+            Some(ratio)
+        }
+    };
+    if idle == IdleState::Mwait {
+        MWAIT_HINT.store(mwait_hint()?, Ordering::Relaxed);
+    }
+
+    let mut current = GOVERNOR.lock();
+    #[cfg(feature = "bsp-only")]
+    apply(ratio);
+    #[cfg(not(feature = "bsp-only"))]
+    super::smp::call_on_all(
+        move || apply(ratio),
+        super::smp::Wait::Timeout(REMOTE_TIMEOUT),
+    )?;
+    *current = governor;
+    IDLE.store(idle as usize, Ordering::Relaxed);
+
+    info!("Power policy: governor {:?}, idle {:?}", governor, idle);
+    Ok(())
+}
+
+/// Requests `ratio` on the current core (or puts back what it had at boot
+/// for `None`).
+fn apply(ratio: Option<u8>) {
+    let core = kcb::per_core().arch.id();
+    let boot = &BOOT_PERF_CTL[core];
+    let value = match ratio {
+        // Nothing to put back if we never changed it
+        None if boot.load(Ordering::Relaxed) == UNSET => return,
+        None => boot.load(Ordering::Relaxed),
+        Some(ratio) => match rdmsr_safe(IA32_PERF_CTL) {
+            Ok(current) => {
+                // Only this core writes its entry
+                if boot.load(Ordering::Relaxed) == UNSET {
+                    boot.store(current, Ordering::Relaxed);
+                }
+                (current & !PERF_CTL_RATIO) | (ratio as u64) << 8
+            }
+            Err(e) => {
+                warn!("Core {} can't set its frequency: {}", core, e);
+                return;
+            }
+        },
+    };
+
+    // Safe: Only requests another frequency
+    if let Err(e) = unsafe { wrmsr_safe(IA32_PERF_CTL, value) } {
+        warn!("Core {} can't set its frequency: {}", core, e);
+    }
+}
+
+/// What an idle core does until the next interrupt arrives (interrupts are
+/// enabled).
+pub fn idle() {
+    match IdleState::ALL[IDLE.load(Ordering::Relaxed)] {
+        IdleState::Poll => spin_loop(),
+        IdleState::Halt => unsafe { x86::halt() },
+        IdleState::Mwait => {
+            // Nobody writes this, only an interrupt wakes us up
+            let word: u64 = 0;
+            let hint = MWAIT_HINT.load(Ordering::Relaxed);
+            unsafe { nrk_mwait_idle(&word as *const u64 as u64, hint) };
+        }
+    }
+}
+
+/// The RAPL energy counters of a package.
+struct Counter {
+    package: PackageId,
+    /// Energy unit of the counters (1 / 2^unit J).
+    unit: u32,
+    package_last: u32,
+    package_total: u64,
+    /// `None` if the CPU doesn't measure the DRAM.
+    dram_last: Option<u32>,
+    dram_total: u64,
+}
+
+impl Counter {
+    fn to_uj(&self, energy: u64) -> u64 {
+        ((energy as u128 * 1_000_000) >> self.unit) as u64
+    }
+
+    fn info(&self) -> PackageEnergy {
+        PackageEnergy {
+            package: self.package,
+            package_uj: self.to_uj(self.package_total),
+            dram_uj: self.dram_last.map(|_last| self.to_uj(self.dram_total)),
+        }
+    }
+}
+
+/// Adds what the energy counters of the package of the current core counted
+/// since we last read them.
+fn sample() {
+    let package = atopology::MACHINE_TOPOLOGY.current_thread().package_id as PackageId;
+    let unit = match rdmsr_safe(MSR_RAPL_POWER_UNIT) {
+        Ok(units) => ((units >> 8) & 0x1f) as u32,
+        // No RAPL (e.g., in a VM)
+        Err(_e) => return,
+    };
+    let energy = match rdmsr_safe(MSR_PKG_ENERGY_STATUS) {
+        Ok(energy) => energy as u32,
+        Err(_e) => return,
+    };
+    let dram = rdmsr_safe(MSR_DRAM_ENERGY_STATUS).ok().map(|e| e as u32);
+
+    let mut counters = ENERGY.lock();
+    match counters.iter_mut().find(|c| c.package == package) {
+        Some(counter) => {
+            counter.package_total += energy.wrapping_sub(counter.package_last) as u64;
+            counter.package_last = energy;
+            if let (Some(last), Some(dram)) = (counter.dram_last, dram) {
+                counter.dram_total += dram.wrapping_sub(last) as u64;
+                counter.dram_last = Some(dram);
+            }
+        }
+        None => {
+            let counter = Counter {
+                package,
+                unit,
+                package_last: energy,
+                package_total: 0,
+                dram_last: dram,
+                dram_total: 0,
+            };
+            if counters.try_push(counter).is_err() {
+                warn!("Can't track the energy of package {}", package);
+            }
+        }
+    }
+}
+
+/// Reads the energy counters of all packages (on one core of each).
+fn sample_all() {
+    // Only the boot core runs
+    #[cfg(feature = "bsp-only")]
+    sample();
+
+    #[cfg(not(feature = "bsp-only"))]
+    for package in atopology::MACHINE_TOPOLOGY.packages() {
+        if let Some(thread) = package.threads().next() {
+            let wait = super::smp::Wait::Timeout(REMOTE_TIMEOUT);
+            if let Err(e) = super::smp::call_on(thread.id, sample, wait) {
+                warn!(
+                    "Can't read the energy counters of core {}: {}",
+                    thread.id, e
+                );
+            }
+        }
+    }
+}
+
+/// Starts counting the energy of the packages (needs all cores up).
+pub fn init() {
+    sample_all();
+    match ratios() {
+        Ok(ratios) => info!(
+            "Frequency ratios: {}-{} (turbo {}), energy counters for {} packages",
+            ratios.min,
+            ratios.max,
+            ratios.turbo,
+            ENERGY.lock().len()
+        ),
+        Err(_e) => info!(
+            "No frequency control, energy counters for {} packages",
+            ENERGY.lock().len()
+        ),
+    }
+}
+
+/// The power management settings and the energy every package used (for
+/// `SystemOperation::GetInfo`).
+pub fn info() -> PowerInfo {
+    sample_all();
+    let ratios = ratios().unwrap_or(Ratios {
+        min: 0,
+        max: 0,
+        turbo: 0,
+    });
+    PowerInfo {
+        governor: *GOVERNOR.lock(),
+        idle: IdleState::ALL[IDLE.load(Ordering::Relaxed)],
+        min_ratio: ratios.min,
+        max_ratio: ratios.max,
+        turbo_ratio: ratios.turbo,
+        energy: ENERGY.lock().iter().map(Counter::info).collect(),
+    }
+}
+
+/// Prints the power management settings and the energy every package used
+/// (with the stats system call).
+pub fn report() {
+    let info = info();
+    info!("Power: governor {:?}, idle {:?}", info.governor, info.idle);
+    for energy in info.energy {
+        match energy.dram_uj {
+            Some(dram_uj) => info!(
+                "Package {} energy: {} uJ (DRAM {} uJ)",
+                energy.package, energy.package_uj, dram_uj
+            ),
+            None => info!(
+                "Package {} energy: {} uJ",
+                energy.package, energy.package_uj
+            ),
+        }
+    }
+}
@@ -6,13 +6,12 @@ use alloc::collections::TryReserveError;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::cmp::PartialEq;
-use core::ops::{Deref, DerefMut};
 use core::{fmt, ptr};
 
 use arrayvec::ArrayVec;
 use fallible_collections::try_vec;
 use fallible_collections::FallibleVec;
-use kpi::process::{FrameId, ELF_OFFSET, EXECUTOR_OFFSET};
+use kpi::process::{FrameId, SchedulingClass, ELF_OFFSET, EXECUTOR_OFFSET};
 use lazy_static::lazy_static;
 use log::{debug, info, trace, warn};
 use node_replication::{Dispatch, Log, Replica};
@@ -20,6 +19,7 @@ use x86::bits64::paging::*;
 use x86::bits64::rflags;
 use x86::controlregs;
 
+use crate::autostart::{self, Program};
 use crate::error::KError;
 use crate::fs::{Fd, MAX_FILES_PER_PROCESS};
 use crate::kcb::ArchSpecificKcb;
@@ -30,16 +30,33 @@ use crate::memory::{paddr_to_kernel_vaddr, Frame, KernelAllocator, PAddr, VAddr}
 use crate::nrproc::NrProcess;
 use crate::process::{
     Eid, Executor, Pid, Process, ResumeHandle, MAX_FRAMES_PER_PROCESS, MAX_PROCESSES,
-    MAX_WRITEABLE_SECTIONS_PER_PROCESS,
+    MAX_USER_BUFFER_LEN, MAX_WRITEABLE_SECTIONS_PER_PROCESS,
 };
 use crate::round_up;
+use crate::services::{self, RestartPolicy};
+use crate::timer_wheel;
 
 use super::kcb::Arch86Kcb;
+use super::usercopy::{copy_to_user, read_user, write_user};
 use super::vspace::*;
 use super::Module;
 use super::MAX_NUMA_NODES;
 
-const INVALID_EXECUTOR_START: VAddr = VAddr(0xdeadffff);
+pub(super) const INVALID_EXECUTOR_START: VAddr = VAddr(0xdeadffff);
+
+/// Assembly that clears the CPU buffers (MDS, see `mitigations`) if
+/// `MDS_CLEAR_SELECTOR` is set, the last thing before we return to
+/// user-space (for `concat!` in the `Ring3Resumer` assembly).
+macro_rules! mds_clear {
+    () => {
+        "
+                cmpw $$0, MDS_CLEAR_SELECTOR(%rip)
+                je 1f
+                verw MDS_CLEAR_SELECTOR(%rip)
+            1:
+        "
+    };
+}
 
 lazy_static! {
     pub static ref PROCESS_TABLE: ArrayVec<ArrayVec<Arc<Replica<'static, NrProcess<Ring3Process>>>, MAX_PROCESSES>, MAX_NUMA_NODES> = {
@@ -78,6 +95,41 @@ lazy_static! {
     };
 }
 
+/// Checks that `len` bytes starting at `base` are mapped in the address space
+/// of `pid` and accessible from user-space (and writable if `write` is set).
+///
+/// # TODO
+/// This walks the buffer one base page at a time, which makes large
+/// file-operations slow. Ideally we'd use the size of the mappings instead.
+pub fn validate_user_range(pid: Pid, base: u64, len: usize, write: bool) -> Result<(), KError> {
+    if len > MAX_USER_BUFFER_LEN {
+        return Err(KError::UserBufferTooLarge { len });
+    }
+    let range = kpi::decode::user_range(base, len as u64).map_err(|_e| KError::BadAddress)?;
+
+    let mut page = range.start & !(BASE_PAGE_SIZE as u64 - 1);
+    while page < range.end {
+        let rights = match NrProcess::<Ring3Process>::access_rights(pid, VAddr::from(page)) {
+            Ok(rights) => rights,
+            Err(_e) => {
+                // Bring back evicted pages before looking again
+                super::reclaim::reload(pid, VAddr::from(page)).map_err(|_e| KError::BadAddress)?;
+                continue;
+            }
+        };
+        if !rights.is_user() || (write && !rights.is_writable()) {
+            return Err(KError::BadAddress);
+        }
+        page += BASE_PAGE_SIZE as u64;
+    }
+
+    Ok(())
+}
+
+/// A pointer to a `T` in user-space.
+///
+/// SMAP is enabled, so the kernel can't dereference it: the value is copied
+/// in and out with the helpers in `usercopy`.
 pub struct UserPtr<T> {
     value: *mut T,
 }
@@ -87,111 +139,67 @@ impl<T> UserPtr<T> {
         UserPtr { value: pointer }
     }
 
-    pub fn vaddr(&self) -> VAddr {
-        VAddr::from(self.value as u64)
+    /// Creates a pointer to a `T` in the address space of `pid`, fails if the
+    /// memory isn't mapped for user-space (or writable if `write` is set).
+    pub fn checked(pid: Pid, pointer: *mut T, write: bool) -> Result<UserPtr<T>, KError> {
+        validate_user_range(pid, pointer as u64, core::mem::size_of::<T>(), write)?;
+        Ok(UserPtr::new(pointer))
     }
-}
 
-impl<T> Deref for UserPtr<T> {
-    type Target = T;
-    fn deref(&self) -> &Self::Target {
-        unsafe {
-            rflags::stac();
-            &*self.value
-        }
+    pub fn vaddr(&self) -> VAddr {
+        VAddr::from(self.value as u64)
     }
 }
 
-impl<T> DerefMut for UserPtr<T> {
-    fn deref_mut(&mut self) -> &mut T {
-        unsafe {
-            rflags::stac();
-            &mut *self.value
-        }
+impl<T: Copy> UserPtr<T> {
+    /// Copies the value from user-space.
+    pub fn read(&self) -> Result<T, KError> {
+        read_user(self.value as u64)
     }
-}
 
-impl<T> Drop for UserPtr<T> {
-    fn drop(&mut self) {
-        unsafe { rflags::clac() };
+    /// Copies `value` to user-space.
+    pub fn write(&mut self, value: &T) -> Result<(), KError> {
+        write_user(self.value as u64, value)
     }
 }
 
-pub struct UserValue<T> {
-    value: T,
-}
-
-impl<T> UserValue<T> {
-    pub fn new(pointer: T) -> UserValue<T> {
-        UserValue { value: pointer }
-    }
-
-    pub fn as_mut_ptr(&mut self) -> *mut T {
-        unsafe { core::mem::transmute(&self.value) }
-    }
-}
-
-impl<T> Deref for UserValue<T> {
-    type Target = T;
-    fn deref(&self) -> &T {
-        unsafe {
-            rflags::stac();
-            &self.value
-        }
-    }
+/// A buffer of `len` bytes at `base` in user-space.
+///
+/// SMAP is enabled, so the kernel can't access the buffer directly: data is
+/// copied into it with `copy_to` (which fails with `KError::BadAddress`
+/// instead of faulting if the process unmapped the memory).
+pub struct UserSlice {
+    base: u64,
+    len: usize,
 }
 
-impl<T> DerefMut for UserValue<T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe {
-            rflags::stac();
-            &mut self.value
-        }
+impl UserSlice {
+    pub fn new(base: u64, len: usize) -> UserSlice {
+        UserSlice { base, len }
     }
-}
 
-impl<T> Drop for UserValue<T> {
-    fn drop(&mut self) {
-        unsafe { rflags::clac() };
+    /// Creates a slice of `len` bytes at `base` in the address space of
+    /// `pid`, fails if the memory isn't mapped for user-space (or writable
+    /// if `write` is set).
+    pub fn checked(pid: Pid, base: u64, len: usize, write: bool) -> Result<UserSlice, KError> {
+        validate_user_range(pid, base, len, write)?;
+        Ok(UserSlice::new(base, len))
     }
-}
-
-pub struct UserSlice<'a> {
-    pub buffer: &'a mut [u8],
-}
 
-impl<'a> UserSlice<'a> {
-    pub fn new(base: u64, len: usize) -> UserSlice<'a> {
-        let mut user_ptr = VAddr::from(base);
-        let slice_ptr = UserPtr::new(&mut user_ptr);
-        let user_slice: &mut [u8] =
-            unsafe { core::slice::from_raw_parts_mut(slice_ptr.as_mut_ptr(), len) };
-        UserSlice { buffer: user_slice }
+    pub fn len(&self) -> usize {
+        self.len
     }
-}
 
-impl<'a> Deref for UserSlice<'a> {
-    type Target = [u8];
-    fn deref(&self) -> &Self::Target {
-        unsafe {
-            rflags::stac();
-            &*self.buffer
-        }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
     }
-}
 
-impl<'a> DerefMut for UserSlice<'a> {
-    fn deref_mut(&mut self) -> &mut [u8] {
-        unsafe {
-            rflags::stac();
-            self.buffer
+    /// Copies `src` into the buffer, starting `offset` bytes in.
+    pub fn copy_to(&mut self, offset: usize, src: &[u8]) -> Result<(), KError> {
+        if offset + src.len() > self.len {
+            return Err(KError::BadAddress);
         }
-    }
-}
-
-impl<'a> Drop for UserSlice<'a> {
-    fn drop(&mut self) {
-        unsafe { rflags::clac() };
+        copy_to_user(self.base + offset as u64, src)
     }
 }
 
@@ -216,6 +224,7 @@ pub struct Ring3Resumer {
 
 impl ResumeHandle for Ring3Resumer {
     unsafe fn resume(self) -> ! {
+        crate::cputime::leave_kernel(kcb::per_core().arch.id());
         match self.typ {
             ResumeStrategy::Start => self.start(),
             ResumeStrategy::Upcall => self.upcall(),
@@ -292,7 +301,7 @@ impl Ring3Resumer {
         //info!("resuming User-space with ctxt: {:?}", (*(self.save_area)),);
 
         // Resumes a process using iretq
-        llvm_asm!("
+        llvm_asm!(concat!("
                 // Restore fs and gs registers
                 swapgs
                 movq 19*8(%rdi), %rsi
@@ -330,10 +339,14 @@ impl Ring3Resumer {
                 // %rip
                 pushq 16*8(%rdi)
 
+                ",
+                mds_clear!(),
+                "
+
                 // Restore rdi register last, since it was used to reach `state`
                 movq 5*8(%rdi), %rdi
                 iretq
-                " ::
+                ") ::
             "{rdi}" (self.save_area));
 
         unreachable!("We should not come here!");
@@ -350,7 +363,7 @@ impl Ring3Resumer {
         // This routine assumes the following set-up
         // %rdi points to SaveArea
         // r11 has rflags
-        llvm_asm!("
+        llvm_asm!(concat!("
                 // Restore CPU registers
                 movq  0*8(%rdi), %rax
                 movq  1*8(%rdi), %rbx
@@ -385,10 +398,14 @@ impl Ring3Resumer {
                 movq  4*8(%rdi), %rsi
                 movq  5*8(%rdi), %rdi
 
+                ",
+                mds_clear!(),
+                "
+
                 // Let's do sysretq instead of iretq (slow, measure?)
                 // (TODO: we need to be more careful about CVE-2012-0217)
                 sysretq
-            " ::
+            ") ::
             "{r11}" (user_rflags.bits())
             "{rdi}" (self.save_area));
 
@@ -410,7 +427,7 @@ impl Ring3Resumer {
         // %rcx Program entry point in Ring 3
         // %r11 RFlags
         trace!("Jumping to {:#x}", self.entry_point);
-        llvm_asm!("
+        llvm_asm!(concat!("
                 // rax: contains stack pointer
                 movq       $$0, %rbx
                 // rcx: has entry point
@@ -436,8 +453,12 @@ impl Ring3Resumer {
                 movq %rax, %rbp
                 movq %rax, %rsp
 
+                ",
+                mds_clear!(),
+                "
+
                 sysretq
-            " ::
+            ") ::
             "{rcx}" (self.entry_point.as_u64())
             "{rdi}" (self.cpu_ctl)
             "{rsi}" (self.vector)
@@ -465,7 +486,7 @@ impl Ring3Resumer {
         // %rcx Program entry point in Ring 3
         // %r11 RFlags
         trace!("Jumping to {:#x}", self.entry_point);
-        llvm_asm!("
+        llvm_asm!(concat!("
                 // rax: contains stack pointer
                 movq       $$0, %rbx
                 // rcx: has entry point
@@ -492,8 +513,12 @@ impl Ring3Resumer {
                 movq %rax, %rbp
                 movq %rax, %rsp
 
+                ",
+                mds_clear!(),
+                "
+
                 sysretq
-            " ::
+            ") ::
             "{rcx}" (self.entry_point.as_u64())
             "{rdi}" (self.cpu_ctl)
             "{rsi}" (self.vector)
@@ -549,6 +574,14 @@ pub struct Ring3Executor {
 
     /// A handle to the vspace PML4 entry point.
     pub pml4: PAddr,
+    /// Scheduling class of the executor.
+    pub sched_class: SchedulingClass,
+    /// Deadline hint (in rdtsc cycles) for `SchedulingClass::LowLatency`.
+    pub sched_deadline: Option<u64>,
+    /// Resume from `save_area` instead of the entry point when started
+    /// (for executors restored from a checkpoint or migrated from another
+    /// core).
+    pub restored: bool,
 }
 
 // CPU context save area (must be first, see exec.S)
@@ -609,11 +642,21 @@ impl Ring3Executor {
             // executor on a different replica (which means the advance log on
             // pfault would not really advance the right set of page-tables)
             pml4: process.vspace.pml4_address(),
+            sched_class: SchedulingClass::Normal,
+            sched_deadline: None,
+            restored: false,
         }
     }
 
-    pub fn vcpu(&self) -> UserPtr<kpi::arch::VirtualCpu> {
-        UserPtr::new(self.vcpu_ctl.as_mut_ptr())
+    /// The vCPU control area of the executor.
+    ///
+    /// This goes through the kernel mapping of the area (`vcpu_ctl_kernel`),
+    /// with SMAP enabled we can't touch the user-space mapping.
+    #[allow(clippy::mut_from_ref)]
+    pub fn vcpu(&self) -> &mut kpi::arch::VirtualCpu {
+        // Safety: The area is allocated for this executor and lives as long
+        // as the process; it's only accessed by the core running the executor.
+        unsafe { &mut *self.vcpu_ctl_kernel.as_mut_ptr() }
     }
 
     pub fn vcpu_addr(&self) -> VAddr {
@@ -654,11 +697,24 @@ impl Executor for Ring3Executor {
 
     /// Start the process (run it for the first time).
     fn start(&self) -> Self::Resumer {
-        let kcb = kcb::get_kcb();
+        if self.restored {
+            assert_eq!(
+                kcb::per_core().node,
+                self.affinity,
+                "Run on remote replica?"
+            );
+
+            // The state may come from an interrupt, `sysret` would clobber
+            // %rcx and %r11
+            self.maybe_switch_vspace();
+            return Ring3Resumer::new_iret(&self.save_area as *const kpi::arch::SaveArea);
+        }
+
+        let kcb = kcb::per_core();
         assert_eq!(kcb.arch.node(), self.affinity, "Run on remote replica?");
 
         self.maybe_switch_vspace();
-        let entry_point = unsafe { (*self.vcpu_kernel()).resume_with_upcall };
+        let entry_point = unsafe { (*self.vcpu_kernel()).upcall_entry() };
 
         if entry_point == INVALID_EXECUTOR_START {
             Ring3Resumer::new_start(self.entry_point, self.stack_top())
@@ -667,9 +723,9 @@ impl Executor for Ring3Executor {
             // handler, but on the regular stack (for that dispatcher) and not
             // the upcall stack. It's used to add a new core to a process.
 
-            let entry_point = unsafe { (*self.vcpu_kernel()).resume_with_upcall };
+            let entry_point = unsafe { (*self.vcpu_kernel()).upcall_entry() };
             trace!("Added core entry point is at {:#x}", entry_point);
-            let cpu_ctl = self.vcpu().vaddr().as_u64();
+            let cpu_ctl = self.vcpu_ctl.as_u64();
 
             Ring3Resumer::new_upcall(
                 entry_point,
@@ -682,18 +738,26 @@ impl Executor for Ring3Executor {
     }
 
     fn resume(&self) -> Self::Resumer {
-        assert_eq!(kcb::get_kcb().node, self.affinity, "Run on remote replica?");
+        assert_eq!(
+            kcb::per_core().node,
+            self.affinity,
+            "Run on remote replica?"
+        );
 
         self.maybe_switch_vspace();
         Ring3Resumer::new_restore(&self.save_area as *const kpi::arch::SaveArea)
     }
 
     fn upcall(&self, vector: u64, exception: u64) -> Self::Resumer {
-        assert_eq!(kcb::get_kcb().node, self.affinity, "Run on remote replica?");
+        assert_eq!(
+            kcb::per_core().node,
+            self.affinity,
+            "Run on remote replica?"
+        );
 
         self.maybe_switch_vspace();
-        let entry_point = self.vcpu().resume_with_upcall;
-        let cpu_ctl = self.vcpu().vaddr().as_u64();
+        let entry_point = self.vcpu().upcall_entry();
+        let cpu_ctl = self.vcpu_ctl.as_u64();
 
         Ring3Resumer::new_upcall(
             entry_point,
@@ -709,6 +773,7 @@ impl Executor for Ring3Executor {
             let current_pml4 = PAddr::from(controlregs::cr3());
             if current_pml4 != self.pml4 {
                 trace!("Switching to 0x{:x}", self.pml4);
+                super::mitigations::switch_address_space();
                 controlregs::cr3_write(self.pml4.into());
             }
         }
@@ -739,11 +804,23 @@ pub struct Ring3Process {
     pub frames: ArrayVec<Option<Frame>, MAX_FRAMES_PER_PROCESS>,
     /// Frames of the writeable ELF data section (shared across all replicated Process structs)
     pub writeable_sections: ArrayVec<Frame, MAX_WRITEABLE_SECTIONS_PER_PROCESS>,
+    /// Frames of the read-only ELF sections (every replica has its own
+    /// copy, they're freed with the process).
+    pub elf_frames: Vec<Frame>,
     /// Section in ELF where last read-only header is
     ///
     /// (TODO(robustness): assumes that all read-only segments come before
     /// writable segments).
     pub read_only_offset: VAddr,
+    /// Checkpointed executor state (and id) the next executor resumes from.
+    pub restore: Option<(Eid, Box<kpi::arch::SaveArea>)>,
+    /// Executors that were handed out to cores (so they can be migrated).
+    ///
+    /// Holds at most one entry per executor of the process, room for them is
+    /// made when they're created (see `allocate_executors`).
+    pub assigned: Vec<Ring3Executor>,
+    /// Region that got mapped by the last `load` or `allocate_executors`.
+    pub last_mapped: (VAddr, usize),
 }
 
 impl Ring3Process {
@@ -771,7 +848,11 @@ impl Ring3Process {
             pinfo: Default::default(),
             frames,
             writeable_sections: ArrayVec::new(),
+            elf_frames: Vec::new(),
             read_only_offset: VAddr::zero(),
+            restore: None,
+            assigned: Vec::new(),
+            last_mapped: (VAddr::zero(), 0),
         })
     }
 }
@@ -832,6 +913,10 @@ impl elfloader::ElfLoader for Ring3Process {
 
             let large_pages = size_page / LARGE_PAGE_SIZE;
             debug!("page_base {} lps: {}", page_base, large_pages);
+            if !flags.is_write() {
+                FallibleVec::try_reserve(&mut self.elf_frames, large_pages)
+                    .map_err(|_e| elfloader::ElfLoaderErr::OutOfMemory)?;
+            }
 
             // TODO(correctness): add 20 as estimate of worst case pt requirements
             KernelAllocator::try_refill_tcache(20, large_pages).expect("Refill didn't work");
@@ -844,6 +929,17 @@ impl elfloader::ElfLoader for Ring3Process {
             // that is read-write and that fits within data_frame (so replication works out)
             // We should probably return an error and request more bigger data frames if what
             // we provide initially doesn't work out...
+            let start = self.offset + page_base;
+            let end = start + size_page;
+            self.last_mapped = match self.last_mapped {
+                (_, 0) => (start, size_page),
+                (base, size) => {
+                    let start = core::cmp::min(base, start);
+                    let end = core::cmp::max(base + size, end);
+                    (start, end.as_usize() - start.as_usize())
+                }
+            };
+
             let mut wsection_idx = 0;
             for i in 0..large_pages {
                 let frame = if flags.is_write() {
@@ -867,9 +963,11 @@ impl elfloader::ElfLoader for Ring3Process {
                             || map_action == MapAction::ReadExecuteUser
                     );
                     let mut pmanager = kcb.mem_manager();
-                    pmanager
+                    let frame = pmanager
                         .allocate_large_page()
-                        .expect("We refilled so allocation should work.")
+                        .expect("We refilled so allocation should work.");
+                    self.elf_frames.push(frame);
+                    frame
                 };
 
                 trace!(
@@ -1038,23 +1136,22 @@ impl Process for Ring3Process {
         pid: Pid,
         module: &Module,
         writeable_sections: Vec<Frame>,
+        offset: VAddr,
     ) -> Result<(), KError> {
         self.pid = pid;
+        self.offset = offset;
         // TODO(error-handling): properly unwind on error
         self.writeable_sections.clear();
         for sec in writeable_sections {
             self.writeable_sections.try_push(sec)?;
         }
+        self.last_mapped = (offset, 0);
 
         // Load the Module into the process address-space
         // This needs mostly sanitation work on elfloader and
         // ElfLoad trait impl for process to be safe
         unsafe {
             let e = elfloader::ElfBinary::new(module.as_slice())?;
-            if !e.is_pie() {
-                // We don't have an offset for non-pie applications (rump apps)
-                self.offset = VAddr::zero();
-            }
             self.entry_point = VAddr::from(e.entry_point());
             e.load(self)?;
         }
@@ -1095,14 +1192,72 @@ impl Process for Ring3Process {
         &mut self,
         for_region: atopology::NodeId,
     ) -> Result<Box<Ring3Executor>, KError> {
-        match &mut self.executor_cache[for_region as usize] {
+        let executor = match &mut self.executor_cache[for_region as usize] {
             Some(ref mut executor_list) => {
-                let ret = executor_list.pop().ok_or(KError::ExecutorCacheExhausted)?;
-                //info!("get executor {} with affinity {}", ret.eid, for_region);
-                Ok(ret)
+                if let Some((eid, state)) = self.restore.take() {
+                    // Same executor means same stacks and vCPU area as in the
+                    // checkpoint (if it's on this node)
+                    let idx = executor_list
+                        .iter()
+                        .position(|executor| executor.eid == eid)
+                        .or_else(|| executor_list.len().checked_sub(1))
+                        .ok_or(KError::ExecutorCacheExhausted)?;
+                    let mut ret = executor_list.remove(idx);
+                    ret.save_area = *state;
+                    ret.restored = true;
+                    ret
+                } else {
+                    //info!("get executor {} with affinity {}", ret.eid, for_region);
+                    executor_list.pop().ok_or(KError::ExecutorCacheExhausted)?
+                }
+            }
+            None => return Err(KError::NoExecutorAllocated),
+        };
+
+        // An executor that is handed out again replaces its old entry
+        match self.assigned.iter_mut().find(|e| e.eid == executor.eid) {
+            Some(entry) => *entry = *executor,
+            None => {
+                debug_assert!(self.assigned.len() < self.assigned.capacity());
+                self.assigned.push(*executor);
             }
-            None => Err(KError::NoExecutorAllocated),
         }
+        Ok(executor)
+    }
+
+    fn migrate_executor(
+        &mut self,
+        eid: Eid,
+        to_region: atopology::NodeId,
+        state: kpi::arch::SaveArea,
+    ) -> Result<(), KError> {
+        // The executor keeps its stacks and vCPU area, it only has to use the
+        // page-tables of the replica on the new node
+        let pml4 = self.vspace.pml4_address();
+        let executor = self
+            .assigned
+            .iter_mut()
+            .find(|executor| executor.eid == eid)
+            .ok_or(KError::ExecutorNoLongerValid)?;
+        executor.affinity = to_region;
+        executor.pml4 = pml4;
+        executor.save_area = state;
+        executor.restored = true;
+        Ok(())
+    }
+
+    fn resume_executor(&mut self, eid: Eid) -> Result<Box<Ring3Executor>, KError> {
+        let executor = self
+            .assigned
+            .iter()
+            .find(|executor| executor.eid == eid)
+            .ok_or(KError::ExecutorNoLongerValid)?;
+        Ok(Box::try_new(*executor)?)
+    }
+
+    fn restore_executor(&mut self, eid: Eid, state: kpi::arch::SaveArea) -> Result<(), KError> {
+        self.restore = Some((eid, Box::try_new(state)?));
+        Ok(())
     }
 
     /// Create a series of dispatcher objects for the process
@@ -1110,6 +1265,9 @@ impl Process for Ring3Process {
         let executor_space_requirement = Ring3Executor::EXECUTOR_SPACE_REQUIREMENT;
         let executors_to_create = memory.size() / executor_space_requirement;
 
+        // So handing them out doesn't have to allocate
+        FallibleVec::try_reserve(&mut self.assigned, executors_to_create)?;
+
         KernelAllocator::try_refill_tcache(20, 0).expect("Refill didn't work");
         {
             self.vspace
@@ -1167,6 +1325,7 @@ impl Process for Ring3Process {
             self.current_eid
         );
 
+        self.last_mapped = (self.executor_offset, memory.size());
         self.executor_offset += memory.size();
         Ok(executors_to_create)
     }
@@ -1229,6 +1388,54 @@ impl Process for Ring3Process {
             _ => Err(KError::InvalidFileDescriptor),
         }
     }
+
+    fn owned_frames(&self, frames: &mut Vec<Frame>) -> Result<(), KError> {
+        for (base, mapping) in self.vspace.mappings.iter() {
+            let frame = mapping.frame;
+            // Devices are mapped at their physical address (see
+            // `VSpaceOperation::MapDevice`), the registered frames follow below
+            let device = base.as_u64() == frame.base.as_u64();
+            if device || self.elf_frames.contains(&frame) || self.frames.contains(&Some(frame)) {
+                continue;
+            }
+            frames.try_push(frame)?;
+        }
+        for frame in self.frames.iter().flatten() {
+            frames.try_push(*frame)?;
+        }
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        let kcb = crate::kcb::get_kcb();
+        for frame in self.elf_frames.drain(..) {
+            if let Err(e) = kcb.mem_manager().release_large_page(frame) {
+                warn!("Can't release ELF section {:?}: {:?}", frame, e);
+            }
+        }
+        self.vspace.clear();
+
+        // Like `Ring3Process::new`, without allocating a new address space
+        self.current_eid = 0;
+        self.offset = VAddr::from(ELF_OFFSET);
+        self.pinfo = Default::default();
+        self.entry_point = VAddr::zero();
+        for executors in self.executor_cache.iter_mut() {
+            *executors = None;
+        }
+        self.executor_offset = VAddr::from(EXECUTOR_OFFSET);
+        for fd in self.fds.iter_mut() {
+            *fd = None;
+        }
+        for frame in self.frames.iter_mut() {
+            *frame = None;
+        }
+        self.writeable_sections.clear();
+        self.read_only_offset = VAddr::zero();
+        self.restore = None;
+        self.assigned.clear();
+        self.last_mapped = (VAddr::zero(), 0);
+    }
 }
 
 /// Spawns a new process
@@ -1239,24 +1446,416 @@ impl Process for Ring3Process {
 /// - Then we continue by creating a new Process through an nr call
 /// - Then we allocate a bunch of memory on all NUMA nodes to create enough dispatchers
 ///   so we can run on all cores
-/// - Finally we allocate a dispatcher to the current core (0) and start running the process
+/// - Finally we allocate a dispatcher to the first free core of `program` and
+///   start running the process
+///
+/// The process sees the file-system sub-tree at `program.root` as its `/`.
 #[cfg(target_os = "none")]
-pub fn spawn(binary: &'static str) -> Result<Pid, KError> {
+pub fn spawn(program: &Program<'static>) -> Result<Pid, KError> {
     use crate::nr;
     use crate::process::{allocate_dispatchers, make_process};
 
-    let pid = make_process::<Ring3Process>(binary)?;
-    allocate_dispatchers::<Ring3Process>(pid)?;
+    // Don't load a process that can't run anyway
+    check_free_core(&program.cores)?;
+
+    let pid = make_process::<Ring3Process>(program.binary, program.root)?;
+    let started = nr::KernelNode::spawned(pid, None, program.args)
+        .and_then(|_| allocate_dispatchers::<Ring3Process>(pid))
+        .and_then(|_| {
+            if program.capabilities.is_empty() {
+                Ok(())
+            } else {
+                nr::KernelNode::grant_capabilities(pid, program.capabilities)
+            }
+        })
+        .and_then(|_| allocate_first_free_core(pid, &program.cores));
+    if let Err(e) = started {
+        abandon(pid);
+        return Err(e);
+    }
 
-    // Set current thread to run executor from our process (on the current core)
-    let kcb = kcb::get_kcb();
+    Ok(pid)
+}
 
-    let _gtid = nr::KernelNode::allocate_core_to_process(
-        pid,
-        INVALID_EXECUTOR_START, // This VAddr is irrelevant as it is overriden later
-        Some(kcb.arch.node_id),
-        Some(kcb.arch.id),
-    )?;
+/// Fails with `CoreAlreadyAllocated` if none of `cores` (or no core at all
+/// if `cores` is empty) is free.
+#[cfg(target_os = "none")]
+fn check_free_core(cores: &[usize]) -> Result<(), KError> {
+    use crate::nr;
 
+    let allocated = nr::KernelNode::allocated_cores()?;
+    let free = atopology::MACHINE_TOPOLOGY
+        .threads()
+        .map(|thread| thread.id)
+        .filter(|gtid| cores.is_empty() || cores.contains(gtid))
+        .any(|gtid| gtid < super::MAX_CORES && !allocated.contains(gtid));
+    if free {
+        Ok(())
+    } else {
+        Err(KError::CoreAlreadyAllocated)
+    }
+}
+
+/// Tears down the process `pid` that failed to start.
+#[cfg(target_os = "none")]
+pub(crate) fn abandon(pid: Pid) {
+    use crate::nr;
+
+    // It never ran, so it has no cores to stop
+    let destroyed =
+        nr::KernelNode::exit(pid, kpi::process::KILLED).and_then(|_released| destroy(pid));
+    if let Err(e) = destroyed {
+        warn!("Can't destroy process {} that didn't start: {:?}", pid, e);
+    }
+}
+
+/// Gives `pid` the first core out of `cores` that is free (any free core if
+/// `cores` is empty).
+#[cfg(target_os = "none")]
+fn allocate_first_free_core(pid: Pid, cores: &[usize]) -> Result<(), KError> {
+    use crate::nr;
+
+    if cores.is_empty() {
+        return nr::KernelNode::allocate_core_to_process(pid, INVALID_EXECUTOR_START, None, None)
+            .map(|_gtid| ());
+    }
+
+    let mut error = KError::InvalidGlobalThreadId;
+    for gtid in cores.iter().copied() {
+        let node = match atopology::MACHINE_TOPOLOGY
+            .threads()
+            .find(|thread| thread.id == gtid)
+        {
+            Some(thread) => thread.node_id.unwrap_or(0),
+            None => continue,
+        };
+        match nr::KernelNode::allocate_core_to_process(
+            pid,
+            INVALID_EXECUTOR_START, // This VAddr is irrelevant as it is overriden later
+            Some(node),
+            Some(gtid),
+        ) {
+            Ok(_gtid) => return Ok(()),
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+/// Starts the processes of `init.toml` (or `init=` of the command-line if
+/// there is none, see `autostart`).
+///
+/// Returns how many processes we started, fails if we couldn't start any.
+#[cfg(target_os = "none")]
+pub fn start_init() -> Result<usize, KError> {
+    let kcb = kcb::per_core();
+    let config = kcb
+        .arch
+        .kernel_args()
+        .modules
+        .iter()
+        .find(|module| module.name() == autostart::CONFIG_MODULE)
+        // Safe: Modules stay mapped in the kernel address space
+        .map(|module| unsafe { module.as_slice() })
+        .or_else(|| crate::fs::rootfs::file(autostart::CONFIG_PATH));
+
+    let mut started = 0;
+    let mut failure = None;
+    for program in autostart::programs(config, &kcb.cmdline, kcb.arch.id()).iter() {
+        match spawn(program) {
+            Ok(pid) => {
+                info!("Started {} ({}) as {}", program.binary, program.args, pid);
+                started += 1;
+                if program.restart != RestartPolicy::Never {
+                    if let Err(e) = services::register(program, pid) {
+                        warn!("Can't supervise {}: {}", program.binary, e);
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("Can't start {}: {}", program.binary, e);
+                failure.get_or_insert(e);
+            }
+        }
+    }
+
+    match failure {
+        Some(e) if started == 0 => Err(e),
+        _ => Ok(started),
+    }
+}
+
+/// Spawns `binary` as a child of `parent` on a free core
+/// (`ProcessOperation::Spawn`).
+///
+/// The child sees the same file-system root as init, gets `args` as its
+/// command line and `capabilities` (which the parent has to have). It
+/// inherits the file descriptor limit and the descriptors in the bitmask
+/// `fds` of the parent (except `O_CLOEXEC` ones).
+///
+/// The kernel keeps `args` until the child is destroyed (see [`destroy`]).
+#[cfg(target_os = "none")]
+pub fn spawn_child(
+    parent: Pid,
+    binary: &'static str,
+    args: Box<str>,
+    capabilities: kpi::process::Capabilities,
+    fds: u64,
+) -> Result<Pid, KError> {
+    use crate::cnrfs;
+    use crate::nr;
+    use crate::process::{allocate_dispatchers, make_process};
+
+    if !nr::KernelNode::capabilities(parent)?.contains(capabilities) {
+        return Err(KError::MissingCapability);
+    }
+
+    // Don't load a process that can't run anyway
+    check_free_core(&[])?;
+
+    let pid = make_process::<Ring3Process>(binary, kcb::per_core().cmdline.init_root)?;
+    let args: &'static str = {
+        let mut slot = ARGS[pid].lock();
+        let args = slot.insert(args);
+        // Safety: The box stays in `ARGS` until the process is destroyed
+        unsafe { &*(&**args as *const str) }
+    };
+
+    let started = nr::KernelNode::spawned(pid, Some(parent), args)
+        .and_then(|_| cnrfs::MlnrKernelNode::inherit(parent, pid, fds))
+        .and_then(|_| allocate_dispatchers::<Ring3Process>(pid))
+        .and_then(|_| {
+            if capabilities.is_empty() {
+                Ok(())
+            } else {
+                nr::KernelNode::grant_capabilities(pid, capabilities)
+            }
+        })
+        .and_then(|_| {
+            nr::KernelNode::allocate_core_to_process(pid, INVALID_EXECUTOR_START, None, None)
+        });
+    if let Err(e) = started {
+        abandon(pid);
+        return Err(e);
+    }
+
+    info!(
+        "Process {} spawned {} ({}) as {}",
+        parent, binary, args, pid
+    );
     Ok(pid)
 }
+
+/// Terminates process `pid` with exit `code` (`ProcessOperation::Exit` and
+/// `ProcessOperation::Kill`).
+///
+/// All cores that run the process drop its executor and go back to the
+/// scheduler: the current core when this returns, other cores once they
+/// handled an IPI.
+#[cfg(target_os = "none")]
+pub fn terminate(core: &mut kcb::LocalCore, pid: Pid, code: u64) -> Result<(), KError> {
+    stop(core, pid, code, super::smp::Wait::No)
+}
+
+/// Like [`terminate`], but returns only once all cores dropped the executors
+/// of the process (e.g., before another process takes its place).
+#[cfg(target_os = "none")]
+pub fn terminate_and_wait(core: &mut kcb::LocalCore, pid: Pid, code: u64) -> Result<(), KError> {
+    stop(core, pid, code, super::smp::Wait::Forever)
+}
+
+#[cfg(target_os = "none")]
+fn stop(
+    core: &mut kcb::LocalCore,
+    pid: Pid,
+    code: u64,
+    wait: super::smp::Wait,
+) -> Result<(), KError> {
+    use super::smp;
+    use crate::nr;
+
+    let released = nr::KernelNode::exit(pid, code)?;
+    info!("Process {} exited with {}", pid, code);
+    super::ioapic::release(pid);
+    #[cfg(feature = "smoltcp")]
+    super::localhost::release(pid);
+    if let Some((service, backoff)) = services::exited(pid, code) {
+        schedule_restart(service, backoff);
+    }
+    // Unless its parent waits for it
+    schedule_reap();
+
+    let current = kcb::per_core().arch.id();
+    for gtid in released.iter().filter(|gtid| *gtid != current) {
+        let stop = move || {
+            // Safety: Runs in the IPI handler which doesn't have a token
+            let mut core = unsafe { kcb::LocalCore::new() };
+            drop_executor(&mut core, pid);
+        };
+        if let Err(e) = smp::call_on(gtid, stop, wait) {
+            warn!("Can't stop process {} on core {}: {:?}", pid, gtid, e);
+        }
+    }
+    drop_executor(core, pid);
+
+    Ok(())
+}
+
+/// Command lines of spawned children, a child's is freed when it's
+/// destroyed.
+#[cfg(target_os = "none")]
+static ARGS: [crate::sync::Mutex<Option<Box<str>>>; MAX_PROCESSES] = {
+    const NONE: crate::sync::Mutex<Option<Box<str>>> = crate::sync::Mutex::new(None);
+    [NONE; MAX_PROCESSES]
+};
+
+/// Tears down the exited process `pid` and frees everything it had, its
+/// pid can be reused afterwards.
+///
+/// Processes are destroyed once their parent got their exit code
+/// (`ProcessOperation::WaitPid`), processes without a parent right after
+/// they exited (see [`schedule_reap`]). Fails if the process still runs or
+/// somebody else destroys it.
+#[cfg(target_os = "none")]
+pub fn destroy(pid: Pid) -> Result<(), KError> {
+    use super::smp;
+    use crate::{cnrfs, nr};
+
+    nr::KernelNode::destroy(pid)?;
+
+    // Cores drop the executors of an exited process once they handled the
+    // IPI from `stop`, which may not have happened yet (ours already did)
+    let current = kcb::per_core().arch.id();
+    let stop = move || {
+        if kcb::per_core().arch.id() != current {
+            // Safety: Runs in the IPI handler which doesn't have a token
+            let mut core = unsafe { kcb::LocalCore::new() };
+            drop_executor(&mut core, pid);
+        }
+    };
+    smp::call_on_all(stop, smp::Wait::Forever)?;
+
+    let frames = NrProcess::<Ring3Process>::destroy(pid)?;
+    release_frames(pid, &frames);
+    match cnrfs::MlnrKernelNode::remove_process(pid) {
+        Ok(()) | Err(KError::NoFileDescForPid) => {}
+        Err(e) => warn!("Can't remove the descriptors of process {}: {:?}", pid, e),
+    }
+    super::coredump::set(pid, false);
+    // Nobody can find the arguments once the pid is free
+    let _args = ARGS[pid].lock().take();
+    nr::KernelNode::free_pid(pid)?;
+    info!("Process {} destroyed", pid);
+
+    // Its exited children have no parent anymore
+    schedule_reap();
+    Ok(())
+}
+
+/// Gives the memory a destroyed process owned back to the NCaches.
+#[cfg(target_os = "none")]
+fn release_frames(pid: Pid, frames: &[Frame]) {
+    use crate::memory::{BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+
+    let gmanager = match kcb::per_core().physical_memory.gmanager {
+        Some(gmanager) => gmanager,
+        None => return,
+    };
+    for frame in frames.iter() {
+        let mut ncache = gmanager.node_caches[frame.affinity as usize].lock();
+        let released = match frame.size() {
+            BASE_PAGE_SIZE => ncache.release_base_page(*frame),
+            LARGE_PAGE_SIZE => ncache.release_large_page(*frame),
+            _ => Err(KError::InvalidFrame),
+        };
+        if let Err(e) = released {
+            warn!("Can't release {:?} of {}: {:?}", frame, pid, e);
+        }
+    }
+}
+
+/// Destroys the exited processes without a parent on the next timer tick.
+#[cfg(target_os = "none")]
+fn schedule_reap() {
+    if let Err(e) = timer_wheel::add(core::time::Duration::from_millis(0), reap, 0) {
+        warn!("Can't reap processes: {:?}", e);
+    }
+}
+
+/// Destroys the exited processes without a parent (timer callback).
+#[cfg(target_os = "none")]
+fn reap(_arg: u64) {
+    use crate::nr;
+
+    let orphans = match nr::KernelNode::orphans() {
+        Ok(orphans) => orphans,
+        Err(e) => {
+            warn!("Can't list exited processes: {:?}", e);
+            return;
+        }
+    };
+    for (pid, _generation) in orphans.iter() {
+        if let Err(e) = destroy(*pid) {
+            warn!("Can't destroy process {}: {:?}", pid, e);
+        }
+    }
+}
+
+/// Starts the service `idx` again after `backoff` (see `services`).
+#[cfg(target_os = "none")]
+pub(crate) fn schedule_restart(idx: usize, backoff: core::time::Duration) {
+    if let Err(e) = timer_wheel::add(backoff, restart_service, idx as u64) {
+        warn!("Can't restart service {}: {:?}", idx, e);
+    }
+}
+
+/// Starts the service `idx` again (timer callback, see `services`).
+#[cfg(target_os = "none")]
+fn restart_service(idx: u64) {
+    if let Some(backoff) = services::restart(idx as usize, spawn) {
+        schedule_restart(idx as usize, backoff);
+    }
+}
+
+/// Drops the executor of the current core if it belongs to `pid`.
+#[cfg(target_os = "none")]
+fn drop_executor(core: &mut kcb::LocalCore, pid: Pid) {
+    let arch = &mut core.kcb_mut().arch;
+    if arch.current_executor().map_or(false, |e| e.pid == pid) {
+        let _executor = arch.take_current_executor();
+        // We won't get TLB shootdowns for the process anymore
+        unsafe { x86::tlb::flush_all() };
+    }
+}
+
+/// Lists the memory use of all live processes (`System::stats`).
+pub fn report_memory() {
+    use crate::memory::DataSize;
+    use crate::nr;
+
+    let processes = match nr::KernelNode::processes() {
+        Ok(processes) => processes,
+        Err(e) => {
+            warn!("Can't list processes: {:?}", e);
+            return;
+        }
+    };
+    for (pid, _generation) in processes.iter() {
+        let memory = match NrProcess::<Ring3Process>::pinfo(*pid) {
+            Ok(pinfo) => pinfo.memory,
+            Err(_e) => continue,
+        };
+        let size = |bytes: u64| DataSize::from_bytes(bytes as usize);
+        info!(
+            "Process {}: VSS {} RSS {} (4K {} 2M {} 1G {}) evicted {} reserved {}",
+            pid,
+            size(memory.virtual_size),
+            size(memory.resident),
+            size(memory.base_pages),
+            size(memory.large_pages),
+            size(memory.huge_pages),
+            size(memory.evicted),
+            size(memory.reserved)
+        );
+    }
+}
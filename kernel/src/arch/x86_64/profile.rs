@@ -0,0 +1,396 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A sampling profiler for the kernel.
+//!
+//! Every core programs its first performance counter to count unhalted
+//! cycles and to raise an NMI (through the local APIC) every `period`
+//! cycles. The kernel runs with interrupts disabled, so a regular interrupt
+//! would only ever sample user space. The NMI handler (`profile.S`) records
+//! the interrupted RIP and, if we were in the kernel, the call-chain (by
+//! following the frame pointers on the kernel stack). It runs on a stack of
+//! its own (the `nmi_stack` of the KCB): an NMI can arrive anywhere, e.g., in
+//! `syscall_enter` while we're still on the user stack.
+//!
+//! `dump` first stops sampling on all cores (and waits for them), then
+//! aggregates the samples by their symbolized call-chain and prints
+//! them in the "collapsed stack" format (`frame;frame;frame count`) on the
+//! serial console, which `flamegraph.pl` or `inferno-flamegraph` turn into a
+//! flamegraph. Samples in user space show up as `[user]`.
+//!
+//! `start`/`stop` tell all cores to start or stop sampling with a remote
+//! call (see [`super::smp`]), cores also check on every timer interrupt.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use core::cell::UnsafeCell;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use klogger::sprintln;
+use x86::msr::{rdmsr, wrmsr};
+
+use super::lapic;
+use super::smp::{self, Wait};
+use super::MAX_CORES;
+use crate::error::KError;
+use crate::kcb;
+
+#[cfg(target_os = "none")]
+global_asm!(include_str!("profile.S"), options(att_syntax));
+
+const IA32_PMC0: u32 = 0xc1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+pub(super) const IA32_PERF_GLOBAL_STATUS: u32 = 0x38e;
+pub(super) const IA32_PERF_GLOBAL_CTRL: u32 = 0x38f;
+pub(super) const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+/// `IA32_PERFEVTSEL0`: count unhalted core cycles (event 0x3c) in user and
+/// kernel mode, interrupt on overflow.
+const EVENT_CYCLES: u64 = 0x3c | 1 << 16 | 1 << 17 | 1 << 20 | 1 << 22;
+
+/// LVT entry that delivers the counter overflow as NMI.
+pub(super) const LVT_NMI: u64 = 0b100 << 8;
+
+/// Masks the LVT entry.
+const LVT_MASKED: u64 = 1 << 16;
+
+/// Smallest sampling period we accept (in cycles).
+pub const MIN_PERIOD: u64 = 10_000;
+
+/// Biggest sampling period (the counter is reloaded with a 32-bit write).
+pub const MAX_PERIOD: u64 = i32::MAX as u64;
+
+/// Number of samples we keep (until the next `dump`).
+const MAX_SAMPLES: usize = 8192;
+
+/// Deepest call-chain we record.
+pub const MAX_DEPTH: usize = 16;
+
+#[derive(Clone, Copy)]
+struct Sample {
+    /// Number of valid entries in `ips` (0 if the sample is empty).
+    depth: usize,
+    /// Sampled in user space?
+    user: bool,
+    /// Interrupted RIP followed by the return addresses.
+    ips: [u64; MAX_DEPTH],
+}
+
+impl Sample {
+    const EMPTY: Sample = Sample {
+        depth: 0,
+        user: false,
+        ips: [0; MAX_DEPTH],
+    };
+}
+
+struct Samples(UnsafeCell<[Sample; MAX_SAMPLES]>);
+
+// Safe: Every NMI writes to its own sample (see `NEXT`), we only read them
+// in `dump` once sampling stopped.
+unsafe impl Sync for Samples {}
+
+static SAMPLES: Samples = Samples(UnsafeCell::new([Sample::EMPTY; MAX_SAMPLES]));
+
+/// Index of the next free sample.
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// Samples we dropped because the buffer was full.
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Sampling period in cycles (0 if the profiler is stopped).
+static PERIOD: AtomicU64 = AtomicU64::new(0);
+
+/// Period the counter of every core is armed with.
+static ARMED: [AtomicU64; MAX_CORES] = {
+    const DISARMED: AtomicU64 = AtomicU64::new(0);
+    [DISARMED; MAX_CORES]
+};
+
+/// What the CPU pushes on an interrupt.
+#[repr(C)]
+pub struct InterruptFrame {
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Number of general-purpose counters of the architectural performance
+/// monitoring (0 without version 2 or later, for the global status and
+/// control MSRs).
+pub(super) fn counters() -> u32 {
+    let leaf = unsafe { core::arch::x86_64::__cpuid(0xa) };
+    let version = leaf.eax & 0xff;
+    if version >= 2 {
+        (leaf.eax >> 8) & 0xff
+    } else {
+        0
+    }
+}
+
+/// Do we have a counter for the profiler?
+fn available() -> bool {
+    counters() >= 1
+}
+
+/// Value to load into the counter so it overflows after `period` cycles.
+pub(super) fn reload(period: u64) -> u64 {
+    0u64.wrapping_sub(period)
+}
+
+unsafe fn arm(period: u64) {
+    wrmsr(IA32_PERFEVTSEL0, 0);
+    wrmsr(IA32_PMC0, reload(period));
+    lapic::set_lvt_pmi(LVT_NMI);
+    wrmsr(IA32_PERFEVTSEL0, EVENT_CYCLES);
+    wrmsr(IA32_PERF_GLOBAL_CTRL, rdmsr(IA32_PERF_GLOBAL_CTRL) | 0x1);
+}
+
+unsafe fn disarm() {
+    wrmsr(IA32_PERFEVTSEL0, 0);
+    lapic::set_lvt_pmi(LVT_NMI | LVT_MASKED);
+}
+
+/// Stops all counters of the core and masks their NMI (the profiler's and
+/// the one of `budget`), e.g., before we jump into another kernel.
+///
+/// # Safety
+/// Changes how counter overflows are delivered.
+pub(super) unsafe fn silence() {
+    if counters() > 0 {
+        wrmsr(IA32_PERF_GLOBAL_CTRL, 0);
+    }
+    lapic::set_lvt_pmi(LVT_NMI | LVT_MASKED);
+}
+
+/// Starts sampling every `period` cycles on all cores.
+pub fn start(period: u64) -> Result<(), KError> {
+    if !available() {
+        return Err(KError::ProfilerUnavailable);
+    }
+    if period < MIN_PERIOD || period > MAX_PERIOD {
+        return Err(KError::InvalidProfilePeriod { period });
+    }
+
+    PERIOD.store(period, Ordering::Relaxed);
+    // Otherwise the other cores only notice on their next timer interrupt
+    smp::call_on_all(|| tick(kcb::per_core().arch.id()), Wait::No)
+}
+
+/// Stops sampling on all cores (keeps the samples).
+pub fn stop() {
+    PERIOD.store(0, Ordering::Relaxed);
+    if smp::call_on_all(|| tick(kcb::per_core().arch.id()), Wait::No).is_err() {
+        tick(kcb::per_core().arch.id());
+    }
+}
+
+/// Arms or disarms the counter of `core` if the profiler was started or
+/// stopped, called on every timer interrupt.
+pub fn tick(core: usize) {
+    let period = PERIOD.load(Ordering::Relaxed);
+    if ARMED[core].swap(period, Ordering::Relaxed) != period {
+        // Safe: `start` checked that the counter exists
+        unsafe {
+            if period != 0 {
+                arm(period);
+            } else {
+                disarm();
+            }
+        }
+    }
+}
+
+/// Called by the NMI handler (`profile.S`), returns 0 if the NMI wasn't
+/// raised by the performance counter.
+#[no_mangle]
+extern "C" fn nrk_profile_sample(frame: &InterruptFrame, rbp: u64) -> u64 {
+    super::domain::enter_nmi();
+    let handled = sample(frame, rbp);
+    super::domain::leave_nmi();
+    handled
+}
+
+/// Handles an NMI of the performance counters (see [`nrk_profile_sample`]).
+fn sample(frame: &InterruptFrame, rbp: u64) -> u64 {
+    #[cfg(feature = "syscall-budget")]
+    let budget = super::budget::overflow(frame, rbp) as u64;
+    #[cfg(not(feature = "syscall-budget"))]
+    let budget = 0;
+
+    if ARMED.iter().all(|armed| armed.load(Ordering::Relaxed) == 0) {
+        return budget;
+    }
+
+    unsafe {
+        if rdmsr(IA32_PERF_GLOBAL_STATUS) & 0x1 == 0 {
+            return budget;
+        }
+        record(frame, rbp);
+
+        wrmsr(IA32_PERF_GLOBAL_OVF_CTRL, 0x1);
+        let period = PERIOD.load(Ordering::Relaxed);
+        if period != 0 {
+            wrmsr(IA32_PMC0, reload(period));
+            // The APIC masks the LVT entry on delivery
+            lapic::set_lvt_pmi(LVT_NMI);
+        } else {
+            disarm();
+        }
+    }
+    1
+}
+
+/// Records the interrupted RIP and the kernel call-chain.
+unsafe fn record(frame: &InterruptFrame, rbp: u64) {
+    let idx = NEXT.fetch_add(1, Ordering::Relaxed);
+    if idx >= MAX_SAMPLES {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    let sample = &mut (*SAMPLES.0.get())[idx];
+    sample.user = frame.cs & 0x3 != 0;
+    sample.depth = call_chain(frame, rbp, &mut sample.ips);
+}
+
+/// Writes the interrupted RIP followed by the kernel call-chain to `ips`,
+/// returns the number of entries.
+///
+/// We only follow frame pointers that point into the kernel stack we were
+/// interrupted on (further up than the previous frame), anything else ends
+/// the call-chain.
+pub(super) unsafe fn call_chain(
+    frame: &InterruptFrame,
+    rbp: u64,
+    ips: &mut [u64; MAX_DEPTH],
+) -> usize {
+    ips[0] = frame.rip;
+    let mut depth = 1;
+
+    if frame.cs & 0x3 == 0 {
+        if let Some((_limit, base)) = super::stacks::find(frame.rsp as usize) {
+            let mut lowest = frame.rsp as usize;
+            let mut fp = rbp as usize;
+            while depth < MAX_DEPTH && fp >= lowest && fp + 16 <= base && fp % 8 == 0 {
+                let fp_ptr = fp as *const u64;
+                let return_address = core::ptr::read_volatile(fp_ptr.add(1));
+                if return_address == 0 {
+                    break;
+                }
+                ips[depth] = return_address;
+                depth += 1;
+                lowest = fp + 16;
+                fp = core::ptr::read_volatile(fp_ptr) as usize;
+            }
+        }
+    }
+
+    depth
+}
+
+/// Resolves instruction pointers to function names.
+pub(super) struct Symbolizer {
+    context: Option<crate::panic::DebugContext>,
+    relocated_offset: u64,
+    names: BTreeMap<u64, String>,
+}
+
+impl Symbolizer {
+    pub(super) fn new() -> Self {
+        let kcb = kcb::per_core();
+        let context = elfloader::ElfBinary::new(kcb.kernel_binary())
+            .ok()
+            .and_then(|elf| crate::panic::new_ctxt(&elf));
+        Symbolizer {
+            context,
+            relocated_offset: kcb.arch.kernel_args().kernel_elf_offset.as_u64(),
+            names: BTreeMap::new(),
+        }
+    }
+
+    /// Name of the function that contains `ip`.
+    pub(super) fn name(&mut self, ip: u64) -> &str {
+        let context = self.context.as_ref();
+        #[cfg(not(feature = "addr2line"))]
+        let context = context.map(|c| match *c {});
+        let relocated_offset = self.relocated_offset;
+        self.names.entry(ip).or_insert_with(|| {
+            // The last frame is the function itself, the ones before are
+            // inlined into it
+            let mut name = None;
+            let _r = backtracer_core::resolve(
+                context,
+                relocated_offset,
+                ip as *mut core::ffi::c_void,
+                |symbol| {
+                    if let Some(symbol_name) = symbol.name() {
+                        let mut s = String::new();
+                        let _r = write!(s, "{}", symbol_name);
+                        name = Some(s);
+                    }
+                },
+            );
+            // `;` separates frames in the collapsed format
+            name.map(|n| n.replace(';', ":"))
+                .unwrap_or_else(|| alloc::format!("{:#x}", ip))
+        })
+    }
+}
+
+/// Prints the samples (collapsed by call-chain) on the console and clears
+/// them.
+pub fn dump() -> Result<(), KError> {
+    // Don't take samples while we read them: wait until every core disarmed
+    // its counter
+    let period = PERIOD.swap(0, Ordering::Relaxed);
+    if let Err(e) = smp::call_on_all(|| tick(kcb::per_core().arch.id()), Wait::Forever) {
+        PERIOD.store(period, Ordering::Relaxed);
+        return Err(e);
+    }
+
+    let count = core::cmp::min(NEXT.load(Ordering::Relaxed), MAX_SAMPLES);
+    let mut symbolizer = Symbolizer::new();
+    let mut stacks: BTreeMap<String, usize> = BTreeMap::new();
+
+    // Safe: Sampling stopped on all cores (NMIs of a core are handled
+    // before it runs the remote call)
+    let samples = unsafe { &mut (*SAMPLES.0.get())[..count] };
+    for sample in samples.iter_mut().filter(|s| s.depth > 0) {
+        let mut stack = String::new();
+        // Outermost frame first
+        for (idx, ip) in sample.ips[..sample.depth].iter().enumerate().rev() {
+            if !stack.is_empty() {
+                stack.push(';');
+            }
+            if sample.user {
+                stack.push_str("[user]");
+            } else if idx == 0 {
+                stack.push_str(symbolizer.name(*ip));
+            } else {
+                // Return addresses point after the call
+                stack.push_str(symbolizer.name(*ip - 1));
+            }
+        }
+        *stacks.entry(stack).or_insert(0) += 1;
+        sample.depth = 0;
+    }
+
+    sprintln!(
+        "===== profile begin: {} samples ({} dropped) =====",
+        count,
+        DROPPED.load(Ordering::Relaxed)
+    );
+    for (stack, samples) in stacks.iter() {
+        sprintln!("{} {}", stack, samples);
+    }
+    sprintln!("===== profile end =====");
+
+    NEXT.store(0, Ordering::Relaxed);
+    DROPPED.store(0, Ordering::Relaxed);
+    PERIOD.store(period, Ordering::Relaxed);
+    smp::call_on_all(|| tick(kcb::per_core().arch.id()), Wait::No)
+}
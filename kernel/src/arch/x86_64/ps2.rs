@@ -0,0 +1,82 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Reads the keyboard of the PS/2 controller (i8042).
+//!
+//! The controller raises IRQ 1 for every byte, but legacy interrupts are
+//! delivered to processes (see `irq::handle_generic_exception`). Instead, a
+//! timer on the boot core checks the controller every [`POLL_INTERVAL`] and
+//! passes the scancodes to `keyboard`. It's attached as the driver of the
+//! `i8042` device (see `devices`). The firmware leaves the controller
+//! translating to scancode set 1.
+
+use core::time::Duration;
+
+use log::{info, warn};
+use x86::io;
+
+use crate::error::KError;
+use crate::timer_wheel;
+
+/// Data port (scancodes).
+const DATA: u16 = 0x60;
+
+/// Status register.
+const STATUS: u16 = 0x64;
+
+/// There is a byte in the data port.
+const OUTPUT_FULL: u8 = 0x1;
+
+/// The byte is from the mouse.
+const AUX_DATA: u8 = 0x20;
+
+/// How often we check for keys.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Most bytes we read at once (the controller only buffers one).
+const MAX_BYTES: usize = 16;
+
+/// Is there a controller?
+pub fn present() -> bool {
+    // Nothing answers on the port otherwise
+    unsafe { io::inb(STATUS) != 0xff }
+}
+
+/// Starts polling the keyboard, call on the boot core.
+pub fn init() -> Result<(), KError> {
+    // Drop what was typed during boot
+    read(|_byte| {});
+    timer_wheel::add(POLL_INTERVAL, poll, 0)?;
+    info!(
+        "PS/2 keyboard polled every {} ms",
+        POLL_INTERVAL.as_millis()
+    );
+    Ok(())
+}
+
+/// Passes the keyboard bytes waiting in the controller to `f`.
+fn read(mut f: impl FnMut(u8)) {
+    for _i in 0..MAX_BYTES {
+        let (status, byte) = unsafe {
+            let status = io::inb(STATUS);
+            if status & OUTPUT_FULL == 0 {
+                return;
+            }
+            (status, io::inb(DATA))
+        };
+        if status & AUX_DATA == 0 {
+            f(byte);
+        }
+    }
+}
+
+fn poll(_arg: u64) {
+    let mut queued = false;
+    read(|byte| queued |= crate::keyboard::scancode(byte));
+    if queued {
+        super::poll::notify();
+    }
+    if let Err(e) = timer_wheel::add(POLL_INTERVAL, poll, 0) {
+        warn!("Stopped polling the PS/2 keyboard: {}", e);
+    }
+}
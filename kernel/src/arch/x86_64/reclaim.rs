@@ -0,0 +1,227 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Reclaims memory from user-space processes under memory pressure.
+//!
+//! The only user pages the kernel can re-create on its own are anonymous
+//! base pages (mapped zeroed by `VSpaceOperation::Map`) that still contain
+//! nothing but zeroes. [`reclaim`] unmaps such pages and gives their frames
+//! back to the allocator, the next access to one of them page-faults and
+//! [`reload`] maps a freshly zeroed frame in its place.
+//!
+//! When that's not enough the OOM killer ([`oom_softirq`]) terminates a
+//! process and [`release`]s its anonymous memory.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use arrayvec::ArrayVec;
+use log::{error, trace, warn};
+
+use crate::error::KError;
+use crate::memory::{
+    DataSize, Frame, KernelAllocator, PhysicalPageProvider, VAddr, BASE_PAGE_SIZE,
+};
+use crate::nr;
+use crate::nrproc::NrProcess;
+use crate::oom::{self, Candidate};
+use crate::process::{Pid, MAX_PROCESSES};
+
+use super::process::Ring3Process;
+
+/// Number of user pages that were evicted.
+static EVICTED: AtomicU64 = AtomicU64::new(0);
+
+/// Number of evicted user pages that were accessed again.
+static RELOADED: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of (evicted, reloaded) user pages so far.
+pub fn stats() -> (u64, u64) {
+    (
+        EVICTED.load(Ordering::Relaxed),
+        RELOADED.load(Ordering::Relaxed),
+    )
+}
+
+/// Checks if `frame` contains only zeroes.
+fn is_zero(frame: &Frame) -> bool {
+    let words = unsafe {
+        core::slice::from_raw_parts(
+            frame.kernel_vaddr().as_ptr::<u64>(),
+            frame.size() / core::mem::size_of::<u64>(),
+        )
+    };
+    words.iter().all(|w| *w == 0)
+}
+
+/// Tries to free up to `pages` base pages by evicting user memory, returns
+/// the number of pages that were freed (they end up in the TCache of the
+/// current core).
+pub fn reclaim(pages: usize) -> usize {
+    let mut freed = 0;
+
+    for pid in 0..MAX_PROCESSES {
+        let mut cursor = VAddr::zero();
+        while freed < pages {
+            let (vaddr, frame) = match NrProcess::<Ring3Process>::next_anonymous(pid, cursor) {
+                Ok(Some(page)) => page,
+                _ => break,
+            };
+            cursor = vaddr + BASE_PAGE_SIZE;
+
+            // Avoid the shootdown for pages that are in use anyways
+            if !is_zero(&frame) {
+                continue;
+            }
+
+            match evict(pid, vaddr) {
+                Ok(true) => freed += 1,
+                Ok(false) => {}
+                Err(e) => trace!("Can't evict {:#x} of {}: {:?}", vaddr, pid, e),
+            }
+        }
+    }
+
+    freed
+}
+
+/// Evicts the anonymous page at `vaddr` if it (still) contains only zeroes.
+fn evict(pid: Pid, vaddr: VAddr) -> Result<bool, KError> {
+    let handle = NrProcess::<Ring3Process>::evict(pid, vaddr)?;
+    let frame = handle.frame;
+    super::tlb::shootdown_synchronized(handle, pid);
+
+    // User-space can't write to the page anymore, if it's still zero there
+    // is nothing to lose by dropping it
+    let zero = is_zero(&frame);
+    match NrProcess::<Ring3Process>::evict_commit(pid, vaddr, zero)? {
+        Some(frame) => {
+            let kcb = super::kcb::per_core();
+            kcb.mem_manager().release_base_page(frame)?;
+            EVICTED.fetch_add(1, Ordering::Relaxed);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Maps a zeroed frame in place of the evicted page that contains `vaddr`.
+///
+/// Returns `Ok` if the access can be retried (the page is mapped again or
+/// in the middle of being evicted) and an error if `vaddr` was never
+/// evicted.
+pub fn reload(pid: Pid, vaddr: VAddr) -> Result<(), KError> {
+    let base = VAddr::from(vaddr.as_u64() & !(BASE_PAGE_SIZE as u64 - 1));
+    let kcb = super::kcb::per_core();
+
+    // The frame for the page and page-tables we may need again
+    if KernelAllocator::try_refill_tcache(7, 0).is_err() {
+        reclaim(7);
+    }
+    let mut frame = kcb.mem_manager().allocate_base_page()?;
+    unsafe { frame.zero() };
+
+    match NrProcess::<Ring3Process>::reload(pid, base, frame) {
+        Ok(true) => {
+            RELOADED.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+        r => {
+            kcb.mem_manager().release_base_page(frame)?;
+            r.map(|_used| ())
+        }
+    }
+}
+
+/// Unmaps all anonymous pages of the (exited) process `pid` and gives their
+/// frames back to the NCaches, returns the number of pages.
+pub fn release(pid: Pid) -> usize {
+    let kcb = super::kcb::per_core();
+    let gmanager = match kcb.physical_memory.gmanager {
+        Some(gmanager) => gmanager,
+        None => return 0,
+    };
+
+    let mut released = 0;
+    let mut cursor = VAddr::zero();
+    while let Ok(Some((vaddr, _frame))) = NrProcess::<Ring3Process>::next_anonymous(pid, cursor) {
+        cursor = vaddr + BASE_PAGE_SIZE;
+        let handle = match NrProcess::<Ring3Process>::evict(pid, vaddr) {
+            Ok(handle) => handle,
+            Err(e) => {
+                trace!("Can't release {:#x} of {}: {:?}", vaddr, pid, e);
+                continue;
+            }
+        };
+        super::tlb::shootdown_synchronized(handle, pid);
+
+        // Nobody needs the content anymore
+        if let Ok(Some(frame)) = NrProcess::<Ring3Process>::evict_commit(pid, vaddr, true) {
+            let mut ncache = gmanager.node_caches[frame.affinity as usize].lock();
+            match ncache.release_base_page(frame) {
+                Ok(()) => released += 1,
+                Err(e) => warn!("Can't release {:?} of {}: {:?}", frame, pid, e),
+            }
+        }
+    }
+
+    released
+}
+
+/// Handler of `SoftIrq::Oom`: refills the reserves of the NUMA nodes and
+/// kills a process if there isn't enough memory left for that.
+pub fn oom_softirq() {
+    if oom::fill_reserves() {
+        return;
+    }
+
+    let processes = match nr::KernelNode::processes() {
+        Ok(processes) => processes,
+        Err(e) => {
+            warn!("OOM killer can't list processes: {:?}", e);
+            return;
+        }
+    };
+    let supervisor = oom::supervisor();
+    let candidates: ArrayVec<Candidate, MAX_PROCESSES> = processes
+        .iter()
+        .filter(|(pid, _generation)| Some(*pid) != supervisor)
+        .filter_map(|(pid, _generation)| {
+            let priority = nr::KernelNode::process(*pid).ok()?.oom_priority;
+            let resident = NrProcess::<Ring3Process>::pinfo(*pid).ok()?.memory.resident;
+            Some(Candidate {
+                pid: *pid,
+                resident,
+                priority,
+            })
+        })
+        .collect();
+
+    let victim = match oom::current().select(&candidates) {
+        Some(victim) => victim,
+        None => {
+            warn!(
+                "Out of memory, no process to kill ({})",
+                oom::current().name()
+            );
+            return;
+        }
+    };
+
+    // Safety: Soft IRQs run on the core that raised them, we don't hold a
+    // token for the core here
+    let mut core = unsafe { crate::kcb::LocalCore::new() };
+    if let Err(e) = super::process::terminate(&mut core, victim, kpi::process::KILLED) {
+        warn!("OOM killer can't kill {}: {:?}", victim, e);
+        return;
+    }
+    let released = release(victim);
+    oom::killed(victim);
+
+    error!(
+        "Out of memory: killed process {} ({}), released {}",
+        victim,
+        oom::current().name(),
+        DataSize::from_bytes(released * BASE_PAGE_SIZE)
+    );
+    oom::fill_reserves();
+}
@@ -0,0 +1,197 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Reference clocks with a known frequency, we measure the TSC and the APIC
+//! timer against them during boot (see `timer::init`).
+//!
+//! We use the HPET if the ACPI tables describe one, the PIT (which every PC
+//! has, or at least emulates) otherwise. On CPUs without an invariant TSC
+//! the kernel also keeps time with the HPET ([`Hpet::counter`]).
+
+use core::hint::spin_loop;
+use core::ptr;
+
+use log::{trace, warn};
+use x86::io::{inb, outb};
+
+use super::memory::{paddr_to_kernel_vaddr, PAddr, VAddr, BASE_PAGE_SIZE, KERNEL_BASE};
+use crate::memory::reserved::{self, Reserved};
+use crate::memory::vspace::MapAction;
+
+/// A clock we can busy-wait on.
+pub trait ReferenceClock {
+    fn name(&self) -> &'static str;
+
+    /// Spins for `us` microseconds.
+    fn wait(&self, us: u64);
+}
+
+/// General capabilities and id register (the counter period in
+/// femtoseconds is in bits 32..64).
+const HPET_GCAP_ID: usize = 0x00;
+/// General configuration register.
+const HPET_GEN_CONF: usize = 0x10;
+/// Main counter register.
+const HPET_MAIN_COUNTER: usize = 0xf0;
+
+/// General capabilities: the main counter has 64 bits.
+const HPET_COUNT_SIZE_CAP: u64 = 1 << 13;
+/// General configuration: the main counter runs.
+const HPET_ENABLE_CNF: u64 = 1 << 0;
+
+/// The longest period an HPET may have (100 ns, in femtoseconds).
+const HPET_MAX_PERIOD: u64 = 100_000_000;
+
+/// The high precision event timer.
+pub struct Hpet {
+    /// Kernel address of the registers.
+    registers: VAddr,
+    /// Femtoseconds per counter tick.
+    period: u64,
+    /// Mask for the width of the main counter.
+    counter_mask: u64,
+}
+
+impl Hpet {
+    /// Maps the HPET of the ACPI tables (if there is one) and starts its
+    /// counter.
+    pub fn find() -> Option<Hpet> {
+        let address = super::acpi::hpet_address()?;
+        let base = PAddr::from(address.as_u64() & !(BASE_PAGE_SIZE as u64 - 1));
+
+        reserved::reserve(base, BASE_PAGE_SIZE, Reserved::Hpet).ok()?;
+        if reserved::check(base, BASE_PAGE_SIZE, Some(Reserved::Hpet)).is_err() {
+            warn!("HPET overlaps reserved memory, ignored");
+            return None;
+        }
+        super::kcb::per_core()
+            .arch
+            .init_vspace()
+            .map_identity_with_offset(
+                PAddr::from(KERNEL_BASE),
+                base,
+                BASE_PAGE_SIZE,
+                MapAction::ReadWriteKernel,
+            )
+            .ok()?;
+
+        let mut hpet = Hpet {
+            registers: paddr_to_kernel_vaddr(address),
+            period: 0,
+            counter_mask: u32::MAX as u64,
+        };
+        let capabilities = hpet.read(HPET_GCAP_ID);
+        hpet.period = capabilities >> 32;
+        if hpet.period == 0 || hpet.period > HPET_MAX_PERIOD {
+            warn!("HPET has an invalid period ({} fs), ignored", hpet.period);
+            return None;
+        }
+        if capabilities & HPET_COUNT_SIZE_CAP != 0 {
+            hpet.counter_mask = u64::MAX;
+        }
+        trace!(
+            "HPET at {:#x}, period {} fs, {} bit counter",
+            address,
+            hpet.period,
+            hpet.counter_mask.count_ones()
+        );
+
+        let config = hpet.read(HPET_GEN_CONF);
+        hpet.write(HPET_GEN_CONF, config | HPET_ENABLE_CNF);
+        Some(hpet)
+    }
+
+    /// The main counter (wraps at [`Hpet::counter_mask`]).
+    pub fn counter(&self) -> u64 {
+        self.read(HPET_MAIN_COUNTER) & self.counter_mask
+    }
+
+    /// Mask for the width of the main counter.
+    pub fn counter_mask(&self) -> u64 {
+        self.counter_mask
+    }
+
+    /// Femtoseconds per counter tick.
+    pub fn period(&self) -> u64 {
+        self.period
+    }
+
+    fn read(&self, reg: usize) -> u64 {
+        unsafe { ptr::read_volatile((self.registers + reg).as_ptr::<u64>()) }
+    }
+
+    fn write(&mut self, reg: usize, value: u64) {
+        unsafe { ptr::write_volatile((self.registers + reg).as_mut_ptr::<u64>(), value) }
+    }
+}
+
+impl ReferenceClock for Hpet {
+    fn name(&self) -> &'static str {
+        "HPET"
+    }
+
+    fn wait(&self, us: u64) {
+        let ticks = us * 1_000_000_000 / self.period;
+        let start = self.read(HPET_MAIN_COUNTER);
+        while (self.read(HPET_MAIN_COUNTER).wrapping_sub(start) & self.counter_mask) < ticks {
+            spin_loop();
+        }
+    }
+}
+
+/// PIT input frequency (in Hz).
+const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// PIT channel 2 data port.
+const PIT_CHANNEL2: u16 = 0x42;
+/// PIT mode/command port.
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 2: lobyte/hibyte access, mode 0 (interrupt on terminal count).
+const PIT_CHANNEL2_ONE_SHOT: u8 = 0b1011_0000;
+
+/// NMI status and control port (controls the gate of PIT channel 2).
+const NMI_SC: u16 = 0x61;
+/// NMI status and control: gate of PIT channel 2.
+const NMI_SC_GATE2: u8 = 1 << 0;
+/// NMI status and control: PC speaker (driven by PIT channel 2).
+const NMI_SC_SPEAKER: u8 = 1 << 1;
+/// NMI status and control: output of PIT channel 2.
+const NMI_SC_OUT2: u8 = 1 << 5;
+
+/// The programmable interval timer (channel 2, which isn't connected to an
+/// interrupt).
+pub struct Pit;
+
+impl Pit {
+    /// Counts down `ticks` (at most `u16::MAX`) on channel 2.
+    fn count(&self, ticks: u16) {
+        unsafe {
+            // Gate on, speaker off
+            let sc = inb(NMI_SC);
+            outb(NMI_SC, (sc & !NMI_SC_SPEAKER) | NMI_SC_GATE2);
+
+            outb(PIT_COMMAND, PIT_CHANNEL2_ONE_SHOT);
+            outb(PIT_CHANNEL2, ticks as u8);
+            outb(PIT_CHANNEL2, (ticks >> 8) as u8);
+            while inb(NMI_SC) & NMI_SC_OUT2 == 0 {
+                spin_loop();
+            }
+        }
+    }
+}
+
+impl ReferenceClock for Pit {
+    fn name(&self) -> &'static str {
+        "PIT"
+    }
+
+    fn wait(&self, us: u64) {
+        // The counter has 16 bits (~54 ms)
+        let mut ticks = us * PIT_FREQUENCY / 1_000_000;
+        while ticks > 0 {
+            let chunk = core::cmp::min(ticks, u16::MAX as u64);
+            self.count(chunk as u16);
+            ticks -= chunk;
+        }
+    }
+}
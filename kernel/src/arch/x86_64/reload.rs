@@ -0,0 +1,141 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Replacing a service with a new binary without a reboot
+//! (`Process::reload`).
+//!
+//! A process with the `RELOAD` capability passes the path of an ELF file
+//! (e.g., one it received over the network and wrote to the file-system) or
+//! the name of a module. The kernel makes it the binary of the service (see
+//! `process::replace_module`), terminates the running instance and starts a
+//! new one in its place. The new instance gets the arguments, file-system
+//! root, cores and capabilities of the old one and is restarted according
+//! to the same policy (see `services`). If the new binary doesn't start we
+//! go back to the old one (and restart it like a failed service if that
+//! doesn't start either). A replaced binary is freed once no process runs
+//! it anymore.
+//!
+//! The network stack runs in the processes, so there are no kernel sockets
+//! to hand over: the new instance opens its own (e.g., listens on the same
+//! port). Open files aren't handed over either.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use fallible_collections::FallibleVec;
+use kpi::io::FileFlags;
+use kpi::process::Capabilities;
+use kpi::FileOperation;
+use log::{info, warn};
+
+use crate::cnrfs::MlnrKernelNode;
+use crate::error::KError;
+use crate::fallible_string::FallibleString;
+use crate::fs::FD;
+use crate::kcb::LocalCore;
+use crate::nr;
+use crate::process::{find_module, release_replaced, replace_module, restore_module, Binary, Pid};
+use crate::services;
+
+use super::process::{schedule_restart, spawn, terminate_and_wait, Ring3Process};
+
+/// How much of a binary we read at once.
+const CHUNK: usize = 64 * 1024;
+
+/// Replaces the service `pid` with an instance of `binary` on behalf of
+/// `caller`, returns the pid of the new instance.
+///
+/// `binary` is a path in the file-system of `caller` if it starts with `/`,
+/// the name of a module otherwise. A process can't replace itself.
+pub fn reload(core: &mut LocalCore, caller: Pid, pid: Pid, binary: &str) -> Result<Pid, KError> {
+    if !nr::KernelNode::capabilities(caller)?.contains(Capabilities::RELOAD) {
+        return Err(KError::MissingCapability);
+    }
+    if pid == caller {
+        return Err(KError::NotSupported);
+    }
+    let capabilities = nr::KernelNode::capabilities(pid)?;
+
+    // Check the binary before we stop anything
+    let image = if binary.starts_with('/') {
+        let image = read_binary(caller, binary)?;
+        elfloader::ElfBinary::new(&image).map_err(|_e| KError::UnableToParseElf)?;
+        Binary::File(image)
+    } else {
+        Binary::Module(find_module(binary).ok_or(KError::InvalidFile)?)
+    };
+
+    let (service, mut program) = services::detach(pid).ok_or(KError::NotAService { pid })?;
+    // The new instance has the privileges of the old one
+    program.capabilities = capabilities;
+    if let Err(e) = replace_module(program.binary, image) {
+        services::attach(service, pid);
+        return Err(e);
+    }
+    // The new instance may get the cores of the old one
+    if let Err(e) = terminate_and_wait(core, pid, kpi::process::KILLED) {
+        restore_module(program.binary);
+        services::attach(service, pid);
+        return Err(e);
+    }
+
+    let result = match spawn(&program) {
+        Ok(new_pid) => {
+            info!(
+                "Replaced {} ({}) with {} as {}",
+                program.binary, pid, binary, new_pid
+            );
+            services::attach(service, new_pid);
+            Ok(new_pid)
+        }
+        Err(e) => {
+            warn!(
+                "Can't start {} ({}), going back to the old binary of {}",
+                binary, e, program.binary
+            );
+            restore_module(program.binary);
+            match spawn(&program) {
+                Ok(old_pid) => services::attach(service, old_pid),
+                Err(e) => {
+                    warn!("Can't start {} again: {}", program.binary, e);
+                    schedule_restart(service, services::failed(service));
+                }
+            }
+            Err(e)
+        }
+    };
+    // The old instance is gone, so may be its binary
+    release_replaced::<Ring3Process>();
+    result
+}
+
+/// Reads the binary at `path` (in the file-system of `pid`).
+pub(crate) fn read_binary(pid: Pid, path: &str) -> Result<Vec<u8>, KError> {
+    let mut filename = String::try_with_capacity(path.len())?;
+    filename.try_push_str(path)?;
+    let (fd, _) = MlnrKernelNode::open(pid, filename, FileFlags::O_RDONLY.into(), 0)?;
+    let image = read_all(pid, fd);
+    MlnrKernelNode::unmap_fd(pid, fd)?;
+    image
+}
+
+fn read_all(pid: Pid, fd: FD) -> Result<Vec<u8>, KError> {
+    let mut image = Vec::new();
+    loop {
+        let len = image.len();
+        FallibleVec::try_reserve(&mut image, CHUNK)?;
+        image.resize(len + CHUNK, 0);
+        let (read, _) = MlnrKernelNode::file_io(
+            FileOperation::ReadAt,
+            pid,
+            fd,
+            image[len..].as_mut_ptr() as u64,
+            CHUNK as u64,
+            len as i64,
+        )?;
+        image.truncate(len + read as usize);
+        if (read as usize) < CHUNK {
+            return Ok(image);
+        }
+    }
+}
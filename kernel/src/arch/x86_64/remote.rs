@@ -0,0 +1,162 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Serves the management console (see `crate::mgmt`) on the kernel's network
+//! interface, one session at a time (`mgmtkey=<key>` on the command line),
+//! e.g., `socat - TCP:172.31.0.10:2323`.
+//!
+//! A key on the command line brings up the interface at boot (see
+//! [`super::network::start`]). Every poll of it reads the lines the client
+//! sent and sends the output of the commands. Clients that connect while
+//! wrong keys locked the console ([`Throttle`]) are told so and disconnected
+//! before they can send a key.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use log::{info, warn};
+use smoltcp::socket::{SocketHandle, SocketSet, TcpSocket, TcpSocketBuffer, TcpState};
+
+use crate::mgmt::{Session, Throttle, GREETING, MIN_KEY_LEN, PORT};
+
+/// Longest line a client may send.
+const MAX_LINE: usize = 256;
+
+/// The pre-shared key.
+static KEY: spin::Once<&'static str> = spin::Once::new();
+
+/// Serves the console with `key` once the network is up (nothing if `key`
+/// is empty).
+///
+/// Returns true if there is a (valid) key.
+pub fn init(key: &'static str) -> bool {
+    if key.is_empty() {
+        return false;
+    }
+    if key.len() < MIN_KEY_LEN {
+        warn!(
+            "mgmtkey ignored, the key needs at least {} characters",
+            MIN_KEY_LEN
+        );
+        return false;
+    }
+    KEY.call_once(|| key);
+    true
+}
+
+/// The socket of the console and the session on it.
+pub struct Remote {
+    handle: SocketHandle,
+    session: Option<Session>,
+    /// What the client sent after the last line break.
+    line: String,
+    /// Output the socket didn't take yet.
+    output: Vec<u8>,
+    /// Close once the output is sent.
+    closing: bool,
+    /// Wrong keys of past sessions.
+    throttle: Throttle,
+}
+
+/// Opens the socket of the console, `None` if there is no key.
+pub fn attach(sockets: &mut SocketSet<'static>) -> Option<Remote> {
+    KEY.get()?;
+    let socket = TcpSocket::new(
+        TcpSocketBuffer::new(vec![0; 1024]),
+        TcpSocketBuffer::new(vec![0; 4096]),
+    );
+    Some(Remote {
+        handle: sockets.add(socket),
+        session: None,
+        line: String::new(),
+        output: Vec::new(),
+        closing: false,
+        throttle: Throttle::new(),
+    })
+}
+
+impl Remote {
+    /// Runs the commands the client sent and sends their output.
+    pub fn poll(&mut self, sockets: &mut SocketSet<'static>) {
+        let key = match KEY.get() {
+            Some(key) => *key,
+            None => return,
+        };
+        let mut socket = sockets.get::<TcpSocket>(self.handle);
+
+        if !socket.is_open() {
+            // Wait for the next client
+            self.session = None;
+            self.line.clear();
+            self.output.clear();
+            self.closing = false;
+            if socket.listen(PORT).is_err() {
+                return;
+            }
+        }
+        if socket.is_active() && self.session.is_none() {
+            info!(
+                "Management console: connection from {}",
+                socket.remote_endpoint()
+            );
+            self.session = Some(Session::new(key));
+            if self.throttle.allows(rawtime::duration_since_boot()) {
+                self.output.extend_from_slice(GREETING.as_bytes());
+            } else {
+                self.output
+                    .extend_from_slice(b"too many wrong keys, try again later\n");
+                self.closing = true;
+            }
+        }
+
+        if let Some(session) = self.session.as_mut() {
+            while !self.closing && socket.can_recv() {
+                let mut received = [0u8; 64];
+                let len = match socket.recv_slice(&mut received) {
+                    Ok(len) if len > 0 => len,
+                    _ => break,
+                };
+                for byte in &received[..len] {
+                    if *byte != b'\n' {
+                        self.line.push(*byte as char);
+                        if self.line.len() > MAX_LINE {
+                            self.output.extend_from_slice(b"line too long\n");
+                            self.closing = true;
+                            break;
+                        }
+                        continue;
+                    }
+
+                    let mut out = String::new();
+                    let authenticated = session.authenticated();
+                    let open = session.input(&self.line, &mut out);
+                    self.line.clear();
+                    self.output.extend_from_slice(out.as_bytes());
+                    if !authenticated {
+                        if session.authenticated() {
+                            self.throttle.succeeded();
+                        } else {
+                            self.throttle.failed(rawtime::duration_since_boot());
+                        }
+                    }
+                    if !open {
+                        self.closing = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        if !self.output.is_empty() && socket.can_send() {
+            if let Ok(sent) = socket.send_slice(&self.output) {
+                self.output.drain(..sent);
+            }
+        }
+        // Close once everything is out (or when the client closed its end)
+        let client_closed = socket.state() == TcpState::CloseWait;
+        if (self.closing && self.output.is_empty()) || client_closed {
+            socket.close();
+        }
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Sleeping in system calls (`ProcessOperation::Sleep` and the timeouts of
+//! `WaitPid` and `BarrierWait`).
+//!
+//! An [`Alarm`] puts a timer on the core's timer wheel, which keeps the
+//! deadline timer armed for it, and since a tick of the wheel is rather
+//! coarse (2^20 cycles) it also programs the deadline timer for the exact
+//! TSC value. We then halt the core with `mwait` until an interrupt arrives.
+//! Like barrier waits we return to user-space at every interrupt (with the
+//! time that is left), the wrappers in `kpi::syscalls::Process` call us again
+//! until it's up.
+//!
+//! Precision: a sleep never ends early and ends late by the latency of the
+//! timer interrupt and the system call return (a few microseconds on bare
+//! metal, more in a VM). On CPUs without `mwait` (or VMs that don't expose
+//! it) we spin in the kernel for at most [`MAX_SPIN`] at a time instead.
+
+use core::hint::spin_loop;
+use core::time::Duration;
+
+use crate::error::KError;
+use crate::timer_wheel::{self, TimerId};
+
+use super::barrier;
+
+/// How long we spin without `mwait` before we go back to user-space (so it
+/// can handle interrupts).
+const MAX_SPIN: Duration = Duration::from_millis(1);
+
+/// How often waits for events that don't wake us up (e.g., a child exiting)
+/// check again (in ns).
+pub const POLL_INTERVAL_NS: u64 = 1_000_000;
+
+/// A wake-up call for the current core, `ns` nanoseconds from now.
+///
+/// Dropping the alarm removes its timer.
+pub struct Alarm {
+    /// Time (`timer::now`) at which the alarm goes off.
+    deadline: u64,
+    timer: TimerId,
+}
+
+impl Alarm {
+    pub fn set(ns: u64) -> Result<Alarm, KError> {
+        let deadline = now().saturating_add(timer_wheel::ns_to_cycles(ns));
+        let timer = timer_wheel::add(Duration::from_nanos(ns), wake, 0)?;
+        // After the wheel armed the timer for its (later) tick
+        super::timer::set_before(deadline);
+        Ok(Alarm { deadline, timer })
+    }
+
+    /// Nanoseconds until the alarm goes off (0 once it went off).
+    pub fn remaining(&self) -> u64 {
+        timer_wheel::cycles_to_ns(self.deadline.saturating_sub(now()))
+    }
+
+    /// Halts the core until an interrupt arrives (e.g., the alarm goes off).
+    pub fn wait(&self) {
+        if barrier::halt_until_interrupt() {
+            return;
+        }
+
+        let until = core::cmp::min(
+            self.deadline,
+            now().saturating_add(timer_wheel::ns_to_cycles(MAX_SPIN.as_nanos() as u64)),
+        );
+        while now() < until {
+            spin_loop();
+        }
+    }
+}
+
+impl Drop for Alarm {
+    fn drop(&mut self) {
+        let _expired = timer_wheel::cancel(self.timer);
+    }
+}
+
+/// The interrupt that runs the timer already woke the core.
+fn wake(_arg: u64) {}
+
+/// The time of the current core (the deadline timer uses it uncorrected).
+fn now() -> u64 {
+    super::timer::now()
+}
+
+/// Sleeps for `ns` nanoseconds or until an interrupt arrives.
+///
+/// Returns the nanoseconds left to sleep.
+pub fn sleep(ns: u64) -> Result<u64, KError> {
+    if ns == 0 {
+        return Ok(0);
+    }
+    let alarm = Alarm::set(ns)?;
+    alarm.wait();
+    Ok(alarm.remaining())
+}
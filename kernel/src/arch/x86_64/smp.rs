@@ -0,0 +1,132 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Runs functions on other cores (like Linux' `smp_call_function`).
+//!
+//! The function is put in the IPI work queue of every target core (see
+//! [`super::tlb`]) and the cores get an IPI, they run it from their
+//! interrupt handler. The caller can wait for all cores to finish (with a
+//! timeout). While it waits, the caller handles the work that's sent to its
+//! own core, so two cores calling each other don't deadlock.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::fmt;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+
+use log::trace;
+
+use crate::error::KError;
+
+use super::tlb::{self, WorkItem};
+
+/// Whether [`call_on`] and [`call_on_all`] wait for the function to run.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Wait {
+    /// Return once the function is queued on every core.
+    No,
+    /// Return once every core ran the function, fail if that takes longer
+    /// (cores that didn't get to it yet may still run it later).
+    Timeout(Duration),
+    /// Return once every core ran the function.
+    Forever,
+}
+
+/// A function that runs on one or more cores.
+pub struct RemoteCall {
+    func: Box<dyn Fn() + Send + Sync>,
+    /// Cores that didn't run it yet.
+    pending: AtomicUsize,
+}
+
+impl RemoteCall {
+    /// Runs the function on the current core (from the IPI handler).
+    pub(crate) fn run(&self) {
+        (self.func)();
+        self.pending.fetch_sub(1, Ordering::Release);
+    }
+}
+
+impl fmt::Debug for RemoteCall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RemoteCall")
+            .field("pending", &self.pending.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+/// Runs `func` on core `gtid` (directly if that's us).
+pub fn call_on<F>(gtid: atopology::GlobalThreadId, func: F, wait: Wait) -> Result<(), KError>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    if gtid >= atopology::MACHINE_TOPOLOGY.num_threads() {
+        return Err(KError::InvalidGlobalThreadId);
+    }
+    call(core::iter::once(gtid), func, wait)
+}
+
+/// Runs `func` on every core, including ours.
+pub fn call_on_all<F>(func: F, wait: Wait) -> Result<(), KError>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    call(
+        atopology::MACHINE_TOPOLOGY.threads().map(|t| t.id),
+        func,
+        wait,
+    )
+}
+
+fn call<I, F>(cores: I, func: F, wait: Wait) -> Result<(), KError>
+where
+    I: Iterator<Item = atopology::GlobalThreadId> + Clone,
+    F: Fn() + Send + Sync + 'static,
+{
+    let my_gtid = super::kcb::per_core().arch.id();
+    let remote = cores.clone().filter(|gtid| *gtid != my_gtid);
+    let call = Arc::try_new(RemoteCall {
+        func: Box::try_new(func)?,
+        pending: AtomicUsize::new(remote.clone().count()),
+    })?;
+    let start = rawtime::Instant::now();
+    let timed_out = || match wait {
+        Wait::Timeout(timeout) => start.elapsed() > timeout,
+        Wait::No | Wait::Forever => false,
+    };
+
+    for gtid in remote {
+        trace!("Send remote call to {}", gtid);
+        let mut work = WorkItem::Call(call.clone());
+        while let Err(full) = tlb::try_enqueue(gtid, work) {
+            // Wait for the core to make room (and do our own work meanwhile)
+            if timed_out() {
+                let pending = call.pending.load(Ordering::Relaxed);
+                return Err(KError::RemoteCallTimeout { pending });
+            }
+            work = full;
+            tlb::dequeue(my_gtid);
+            spin_loop();
+        }
+        tlb::send_work_ipi(atopology::MACHINE_TOPOLOGY.threads[gtid].apic_id());
+    }
+
+    if cores.clone().any(|gtid| gtid == my_gtid) {
+        (call.func)();
+    }
+
+    if wait == Wait::No {
+        return Ok(());
+    }
+    while call.pending.load(Ordering::Acquire) > 0 {
+        if timed_out() {
+            let pending = call.pending.load(Ordering::Relaxed);
+            return Err(KError::RemoteCallTimeout { pending });
+        }
+        tlb::dequeue(my_gtid);
+        spin_loop();
+    }
+    Ok(())
+}
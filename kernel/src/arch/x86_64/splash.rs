@@ -0,0 +1,44 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Boot progress on the framebuffer (see `bootloader_shared::splash`).
+//!
+//! The bootloader draws the first stages, we continue where it left off. All
+//! functions do nothing if there is no (usable) framebuffer.
+
+use bootloader_shared::splash::{BootStage, Splash};
+use bootloader_shared::KernelArgs;
+
+use crate::domain::GRAPHICS;
+use crate::sync::Mutex;
+
+static SPLASH: Mutex<Option<Splash<'static>>> = Mutex::new(None);
+
+/// Takes over the framebuffer from `args`.
+pub fn init(args: &mut KernelArgs) {
+    if let (Some(frame_buffer), Some(mode)) = (args.frame_buffer.take(), args.mode_info.as_ref()) {
+        *SPLASH.lock() = Splash::new(frame_buffer, mode);
+    }
+}
+
+/// Marks `stage` as completed.
+pub fn progress(stage: BootStage) {
+    // A bug in the drawing code shouldn't stop the boot
+    let _r = GRAPHICS.enter(|| {
+        if let Some(splash) = SPLASH.lock().as_mut() {
+            splash.progress(stage);
+        }
+    });
+}
+
+/// Marks the boot as failed (called on panic).
+pub fn fail() {
+    let _r = GRAPHICS.enter(|| {
+        // Don't deadlock if we panic while drawing
+        if let Some(mut splash) = SPLASH.try_lock() {
+            if let Some(splash) = splash.as_mut() {
+                splash.fail();
+            }
+        }
+    });
+}
@@ -0,0 +1,145 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Overflow checks and usage statistics for the kernel stacks of every core.
+//!
+//! The kernel stacks are [`OwnedStack`]s which have a canary at their limit
+//! (see `stack.rs`). Every core checks the canaries of its stacks on every
+//! timer interrupt and panics if one got overwritten. The cores also publish
+//! where their stacks are, so the usage of any core's stacks can be queried
+//! (`System::stack_usage`).
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use kpi::system::{KernelStack, StackUsage};
+use log::info;
+
+use super::kcb::Arch86Kcb;
+use super::MAX_CORES;
+use crate::error::KError;
+use crate::stack::{self, OwnedStack, Stack};
+
+/// Where a kernel stack is (limit and size), both 0 if we don't know yet.
+struct StackLocation {
+    limit: AtomicUsize,
+    size: AtomicUsize,
+}
+
+impl StackLocation {
+    const fn new() -> Self {
+        StackLocation {
+            limit: AtomicUsize::new(0),
+            size: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns (limit, base) if the stack contains `addr`.
+    fn contains(&self, addr: usize) -> Option<(usize, usize)> {
+        let limit = self.limit.load(Ordering::Relaxed);
+        let base = limit + self.size.load(Ordering::Relaxed);
+        if limit <= addr && addr < base {
+            Some((limit, base))
+        } else {
+            None
+        }
+    }
+}
+
+const LOCATION_INIT: StackLocation = StackLocation::new();
+const CORE_INIT: [StackLocation; 4] = [LOCATION_INIT; 4];
+static STACKS: [[StackLocation; 4]; MAX_CORES] = [CORE_INIT; MAX_CORES];
+
+fn stack_name(kind: KernelStack) -> &'static str {
+    match kind {
+        KernelStack::Syscall => "syscall",
+        KernelStack::Interrupt => "interrupt",
+        KernelStack::Fault => "fault",
+        KernelStack::Nmi => "nmi",
+    }
+}
+
+/// Checks the stacks of the current core (`kcb`), panics if one of them
+/// overflowed.
+pub fn check(kcb: &Arch86Kcb) {
+    for kind in KernelStack::ALL.iter().copied() {
+        if let Some(stack) = kcb.kernel_stack(kind) {
+            check_stack(kcb.id(), kind, stack);
+        }
+    }
+}
+
+fn check_stack(core: usize, kind: KernelStack, stack: &OwnedStack) {
+    let location = &STACKS[core][kind as usize];
+    location
+        .limit
+        .store(stack.limit() as usize, Ordering::Relaxed);
+    location.size.store(stack.size(), Ordering::Relaxed);
+
+    if !stack.canary_intact() {
+        panic!(
+            "Kernel stack overflow: the {} stack of core {} ({:p} -- {:p}, {} bytes) overflowed",
+            stack_name(kind),
+            core,
+            stack.limit(),
+            stack.base(),
+            stack.size()
+        );
+    }
+}
+
+/// Returns how much of the stack `kind` of `core` was used.
+pub fn usage(core: usize, kind: KernelStack) -> Result<StackUsage, KError> {
+    let location = STACKS
+        .get(core)
+        .ok_or(KError::InvalidSyscallArgument1 { a: core as u64 })?;
+    let location = &location[kind as usize];
+    let limit = location.limit.load(Ordering::Relaxed);
+    let size = location.size.load(Ordering::Relaxed);
+    if limit == 0 {
+        return Ok(StackUsage::default());
+    }
+
+    // Safety: Kernel stacks are never freed
+    let used = unsafe {
+        if !stack::canary_intact(limit as *const u8) {
+            size
+        } else {
+            stack::max_used(limit as *const u8, size)
+        }
+    };
+    Ok(StackUsage {
+        max_used: used as u64,
+        size: size as u64,
+    })
+}
+
+/// Returns the (published) kernel stack that contains `addr` as a
+/// (limit, base) tuple.
+pub fn find(addr: usize) -> Option<(usize, usize)> {
+    STACKS
+        .iter()
+        .flatten()
+        .find_map(|location| location.contains(addr))
+}
+
+/// Returns the core whose (published) kernel stack contains `addr`.
+pub fn core_of(addr: usize) -> Option<usize> {
+    STACKS.iter().position(|core| {
+        core.iter()
+            .any(|location| location.contains(addr).is_some())
+    })
+}
+
+/// Prints the stack usage of the current core.
+pub fn report(kcb: &Arch86Kcb) {
+    for kind in KernelStack::ALL.iter().copied() {
+        if let Some(stack) = kcb.kernel_stack(kind) {
+            info!(
+                "Kernel {} stack: {} of {} bytes used",
+                stack_name(kind),
+                stack.max_used(),
+                stack.size()
+            );
+        }
+    }
+}
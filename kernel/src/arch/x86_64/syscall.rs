@@ -3,10 +3,14 @@
 
 #![allow(warnings)]
 
+use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
-use core::convert::TryInto;
+use core::convert::{TryFrom, TryInto};
+use core::mem::size_of;
+use core::pin::Pin;
 
 use fallible_collections::{FallibleVec, FallibleVecGlobal};
 use klogger::{sprint, sprintln};
@@ -15,28 +19,45 @@ use x86::bits64::paging::{PAddr, VAddr, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
 use x86::bits64::rflags;
 use x86::msr::{rdmsr, wrmsr, IA32_EFER, IA32_FMASK, IA32_LSTAR, IA32_STAR};
 
-use kpi::process::FrameId;
+use kpi::io::{FileInfo, IoVec, IOV_MAX};
+use kpi::process::{
+    AffinityMask, CoreRequestFlags, FrameId, MapFlags, MappingRange, SchedulingClass, SyscallRecord,
+};
 use kpi::{
-    FileOperation, ProcessOperation, SystemCall, SystemCallError, SystemOperation, VSpaceOperation,
+    FileOperation, NetOperation, ProcessOperation, SystemCall, SystemCallError, SystemOperation,
+    TestOperation, VSpaceOperation,
 };
 
 use crate::error::KError;
+use crate::fallible_string::TryString;
+use crate::fault::CoreFault;
 use crate::fs::FileSystem;
-use crate::kcb::ArchSpecificKcb;
+use crate::kcb::{ArchSpecificKcb, Kcb, LocalCore};
 use crate::memory::vspace::MapAction;
-use crate::memory::{Frame, PhysicalPageProvider, KERNEL_BASE};
+use crate::memory::{AllocatorStatistics, Frame, PhysicalPageProvider, KERNEL_BASE};
 use crate::process::{Pid, ResumeHandle};
+use crate::sync::Mutex;
 use crate::{cnrfs, nr, nrproc};
 
 use super::gdt::GdtTable;
-use super::process::{Ring3Process, UserValue};
+use super::kcb::Arch86Kcb;
+use super::process::{validate_user_range, Ring3Process, Ring3Resumer};
+use super::usercopy::{copy_from_user, copy_to_user, read_user, write_user};
+use super::MAX_CORES;
 
 extern "C" {
     #[no_mangle]
     fn syscall_enter();
 }
 
-fn handle_system(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError> {
+fn handle_system(
+    core: &mut LocalCore,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    arg5: u64,
+) -> Result<(u64, u64), KError> {
     let op = SystemOperation::from(arg1);
 
     match op {
@@ -61,32 +82,182 @@ fn handle_system(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
             // TODO(dependency): Get rid of serde/serde_cbor, use something sane instead
             let serialized = serde_cbor::to_vec(&return_threads).unwrap();
             if serialized.len() <= vaddr_buf_len as usize {
-                let mut user_slice = super::process::UserSlice::new(vaddr_buf, serialized.len());
-                user_slice.copy_from_slice(serialized.as_slice());
+                let pid = super::kcb::per_core().current_pid()?;
+                validate_user_range(pid, vaddr_buf, serialized.len(), true)?;
+                copy_to_user(vaddr_buf, serialized.as_slice())?;
             }
 
             Ok((serialized.len() as u64, 0))
         }
         SystemOperation::Stats => {
-            let kcb = super::kcb::get_kcb();
-            info!("IRQ handler time: {} cycles", kcb.tlb_time);
+            let kcb = super::kcb::per_core();
+            info!("IRQ handler time: {} cycles", kcb.tlb_time());
+            if cfg!(feature = "syscall-timing") {
+                let (count, cycles) = kcb.syscall_time();
+                info!("System calls: {} kernel time: {} cycles", count, cycles);
+            }
+            let (evicted, reloaded) = super::reclaim::stats();
+            info!("User pages evicted: {} reloaded: {}", evicted, reloaded);
+            let (promoted, failed) = super::thp::stats();
+            info!("Large pages promoted: {} failed: {}", promoted, failed);
+            #[cfg(feature = "syscall-budget")]
+            info!("System calls over budget: {}", super::budget::over_budget());
+            let (reserve_used, killed) = crate::oom::stats();
+            info!(
+                "OOM reserve used: {} processes killed: {}",
+                reserve_used, killed
+            );
+            super::process::report_memory();
+            crate::services::report();
+            let (stalls, advances) = super::tlb::log_stats();
+            info!("FS log stalls: {} replica advances: {}", stalls, advances);
+            super::stacks::report(&kcb.arch);
+            super::irqstats::report();
+            crate::nrstats::report();
+            super::power::report();
             Ok((0, 0))
         }
         SystemOperation::GetCoreID => {
-            let kcb = super::kcb::get_kcb();
+            let kcb = super::kcb::per_core();
             Ok((kcb.arch.id() as u64, 0))
         }
+        SystemOperation::MigrateCore => {
+            let to = arg2 as atopology::GlobalThreadId;
+            let kcb = core.kcb_mut();
+            if to == kcb.arch.hwthread_id() {
+                return Ok((0, 0));
+            }
+
+            // Return from the system call on the new core
+            let mut state = **kcb.arch.save_area.as_ref().ok_or(KError::ProcessNotSet)?;
+            state.set_syscall_error_code(SystemCallError::Ok);
+            super::migrate::migrate(core, to, state)?;
+            unreachable!("migrate only returns on errors")
+        }
+        SystemOperation::ReadMsr => {
+            let pid = super::kcb::per_core().current_pid()?;
+            let value = super::measure::read_msr(pid, arg2 as u32)?;
+            Ok((value, 0))
+        }
+        SystemOperation::Cpuid => {
+            let pid = super::kcb::per_core().current_pid()?;
+            super::measure::cpuid(pid, arg2 as u32, arg3 as u32)
+        }
+        SystemOperation::NetModeration => {
+            // Reading them (both 0) is allowed for the drivers of any process
+            if arg2 != 0 || arg3 != 0 {
+                let pid = super::kcb::per_core().current_pid()?;
+                if !nr::KernelNode::capabilities(pid)?.contains(kpi::process::Capabilities::TUNE) {
+                    return Err(KError::MissingCapability);
+                }
+            }
+            let current = super::netpoll::set(kpi::system::NetModeration {
+                poll_threshold: arg2,
+                idle_polls: arg3,
+            });
+            Ok((current.poll_threshold, current.idle_polls))
+        }
+        SystemOperation::StackUsage => {
+            let kind = kpi::system::KernelStack::ALL
+                .get(arg3 as usize)
+                .ok_or(KError::InvalidSyscallArgument2 { a: arg3 })?;
+            let usage = super::stacks::usage(arg2 as usize, *kind)?;
+            Ok((usage.max_used, usage.size))
+        }
+        SystemOperation::GetRandom => {
+            let pid = super::kcb::per_core().current_pid()?;
+            validate_user_range(pid, arg2, arg3 as usize, true)?;
+
+            let mut buf = [0u8; 256];
+            let mut copied = 0;
+            while copied < arg3 {
+                let len = core::cmp::min(buf.len() as u64, arg3 - copied) as usize;
+                crate::entropy::fill(&mut buf[..len]);
+                copy_to_user(arg2 + copied, &buf[..len])?;
+                copied += len as u64;
+            }
+            Ok((0, 0))
+        }
+        SystemOperation::Profile => {
+            let pid = super::kcb::per_core().current_pid()?;
+            super::measure::check_capability(pid)?;
+            let command = kpi::system::ProfileCommand::ALL
+                .get(arg2 as usize)
+                .ok_or(KError::InvalidSyscallArgument1 { a: arg2 })?;
+            match command {
+                kpi::system::ProfileCommand::Start => super::profile::start(arg3)?,
+                kpi::system::ProfileCommand::Stop => super::profile::stop(),
+                kpi::system::ProfileCommand::Dump => super::profile::dump()?,
+            }
+            Ok((0, 0))
+        }
+        SystemOperation::GetInfo => {
+            let kind = kpi::system::InfoKind::ALL
+                .get(arg4 as usize)
+                .ok_or(KError::InvalidSyscallArgument3 { a: arg4 })?;
+            // TODO(dependency): Get rid of serde/serde_cbor, use something sane instead
+            let serialized = match kind {
+                kpi::system::InfoKind::Build => serde_cbor::to_vec(&crate::build_info::info()?),
+                kpi::system::InfoKind::Hardware => serde_cbor::to_vec(super::hwinfo::get()?),
+                kpi::system::InfoKind::Clock => serde_cbor::to_vec(&super::tsc::info()?),
+                kpi::system::InfoKind::Devices => serde_cbor::to_vec(&crate::devices::list()?),
+                kpi::system::InfoKind::Mitigations => {
+                    serde_cbor::to_vec(&super::mitigations::info())
+                }
+                kpi::system::InfoKind::Power => serde_cbor::to_vec(&super::power::info()),
+            }
+            .unwrap();
+            if serialized.len() <= arg3 as usize {
+                let pid = super::kcb::per_core().current_pid()?;
+                validate_user_range(pid, arg2, serialized.len(), true)?;
+                copy_to_user(arg2, serialized.as_slice())?;
+            }
+            Ok((serialized.len() as u64, 0))
+        }
+        SystemOperation::IrqCount => {
+            let count = super::irqstats::get(arg2 as usize, arg3)?;
+            Ok((count, 0))
+        }
+        SystemOperation::Kexec => {
+            let pid = super::kcb::per_core().current_pid()?;
+            let (binary_len, cmdline_len) = (arg3 as usize, arg5 as usize);
+            validate_user_range(pid, arg2, binary_len, false)?;
+            validate_user_range(pid, arg4, cmdline_len, false)?;
+
+            let mut binary: Vec<u8> = Vec::try_with_capacity(binary_len)?;
+            binary.resize(binary_len, 0);
+            copy_from_user(binary.as_mut_slice(), arg2)?;
+            let binary = core::str::from_utf8(&binary).map_err(|_e| KError::NotSupported)?;
+
+            let mut cmdline: Vec<u8> = Vec::try_with_capacity(cmdline_len)?;
+            cmdline.resize(cmdline_len, 0);
+            copy_from_user(cmdline.as_mut_slice(), arg4)?;
+            let cmdline = core::str::from_utf8(&cmdline).map_err(|_e| KError::NotSupported)?;
+
+            super::kexec::kexec_binary(pid, binary, cmdline)?;
+            unreachable!("kexec only returns on errors")
+        }
+        SystemOperation::SetPowerPolicy => {
+            let pid = super::kcb::per_core().current_pid()?;
+            let governor = kpi::system::Governor::from_args(arg2, arg3)
+                .ok_or(KError::InvalidSyscallArgument1 { a: arg2 })?;
+            let idle = kpi::system::IdleState::ALL
+                .get(arg4 as usize)
+                .ok_or(KError::InvalidSyscallArgument3 { a: arg4 })?;
+            super::power::set_policy(pid, governor, *idle)?;
+            Ok((0, 0))
+        }
         SystemOperation::Unknown => Err(KError::InvalidSystemOperation { a: arg1 }),
     }
 }
 
 /// System call handler for printing
-fn process_print(buf: UserValue<&str>) -> Result<(u64, u64), KError> {
-    let mut kcb = super::kcb::get_kcb();
-    let buffer: &str = *buf;
+fn process_print(buffer: &str) -> Result<(u64, u64), KError> {
+    let kcb = super::kcb::per_core();
+    super::netconsole::write(buffer);
 
     // A poor mans line buffer scheme:
-    match &mut kcb.print_buffer {
+    match &mut *kcb.print_buffer() {
         Some(kbuf) => match buffer.find("\n") {
             Some(idx) => {
                 let (low, high) = buffer.split_at(idx + 1);
@@ -120,7 +291,14 @@ fn process_print(buf: UserValue<&str>) -> Result<(u64, u64), KError> {
 }
 
 /// System call handler for process exit
-fn process_exit(code: u64) -> Result<(u64, u64), KError> {
+fn process_exit(core: &mut LocalCore, code: u64) -> Result<(u64, u64), KError> {
+    let pid = super::kcb::per_core().current_pid()?;
+    if nr::KernelNode::process(pid)?.parent.is_some() || crate::services::restarts(pid, code) {
+        // Someone waits for it or it gets restarted, the system keeps running
+        super::process::terminate(core, pid, code)?;
+        crate::scheduler::schedule()
+    }
+
     debug!("Process got exit, we are done for now...");
     // TODO: For now just a dummy version that exits Qemu
     if code != 0 {
@@ -132,53 +310,224 @@ fn process_exit(code: u64) -> Result<(u64, u64), KError> {
     }
 }
 
-fn handle_process(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError> {
+fn handle_process(
+    core: &mut LocalCore,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    arg5: u64,
+) -> Result<(u64, u64), KError> {
     let op = ProcessOperation::from(arg1);
 
     match op {
         ProcessOperation::Log => {
-            let buffer: *const u8 = arg2 as *const u8;
-            let len: usize = arg3 as usize;
+            let pid = super::kcb::per_core().current_pid()?;
+            let len = arg3 as usize;
+            // The level is in the low, the length of the target in the high
+            // 32 bits
+            let level = *kpi::process::LogLevel::ALL
+                .get(arg5 as u32 as usize)
+                .ok_or(KError::InvalidSyscallArgument4 { a: arg5 })?;
+            let target_len = (arg5 >> 32) as usize;
+            validate_user_range(pid, arg2, len, false)?;
+            validate_user_range(pid, arg4, target_len, false)?;
+
+            match crate::ulog::admit(pid, level, len + target_len) {
+                crate::ulog::Verdict::Print { dropped } if dropped > 0 => {
+                    let _r = klogger::SERIAL_LINE_MUTEX.lock();
+                    sprint!("[pid {}] {} log messages dropped\r\n", pid, dropped);
+                    super::netconsole::mirror(format_args!(
+                        "[pid {}] {} log messages dropped\n",
+                        pid, dropped
+                    ));
+                }
+                crate::ulog::Verdict::Print { .. } => {}
+                crate::ulog::Verdict::Filtered | crate::ulog::Verdict::Dropped => {
+                    return Ok((0, 0));
+                }
+            }
 
-            let user_str = unsafe {
-                let slice = core::slice::from_raw_parts(buffer, len);
-                core::str::from_utf8_unchecked(slice)
-            };
+            let mut kbuf: Vec<u8> = Vec::try_with_capacity(len)?;
+            kbuf.resize(len, 0);
+            copy_from_user(kbuf.as_mut_slice(), arg2)?;
+            let kstr = core::str::from_utf8(kbuf.as_slice()).map_err(|_e| KError::NotSupported)?;
+
+            if level == kpi::process::LogLevel::Print {
+                return process_print(kstr);
+            }
 
-            process_print(UserValue::new(user_str))
+            let mut target: Vec<u8> = Vec::try_with_capacity(target_len)?;
+            target.resize(target_len, 0);
+            copy_from_user(target.as_mut_slice(), arg4)?;
+            let target =
+                core::str::from_utf8(target.as_slice()).map_err(|_e| KError::NotSupported)?;
+
+            let _r = klogger::SERIAL_LINE_MUTEX.lock();
+            sprint!("[{}] - {}: {}\r\n", level.as_str(), target, kstr);
+            super::netconsole::mirror(format_args!(
+                "[{}] - {}: {}\n",
+                level.as_str(),
+                target,
+                kstr
+            ));
+            Ok((0, 0))
         }
         ProcessOperation::GetVCpuArea => unsafe {
-            let kcb = super::kcb::get_kcb();
+            let kcb = super::kcb::per_core();
 
             let vcpu_vaddr = kcb.arch.current_executor()?.vcpu_addr().as_u64();
 
             Ok((vcpu_vaddr, 0))
         },
         ProcessOperation::AllocateVector => {
-            // TODO: missing proper IRQ resource allocation...
-            let vector = arg2;
-            let core = arg3;
-            super::irq::ioapic_establish_route(vector, core);
-            Ok((vector, core))
+            let pid = super::kcb::per_core().current_pid()?;
+            let gsi = u32::try_from(arg2).map_err(|_e| KError::NoSuchInterrupt { gsi: arg2 })?;
+            super::ioapic::route(pid, gsi, arg3 as usize)?;
+            Ok((arg2, arg3))
+        }
+        ProcessOperation::MaskVector => {
+            let pid = super::kcb::per_core().current_pid()?;
+            let gsi = u32::try_from(arg2).map_err(|_e| KError::NoSuchInterrupt { gsi: arg2 })?;
+            super::ioapic::set_masked(pid, gsi, arg3 != 0)?;
+            Ok((0, 0))
         }
         ProcessOperation::Exit => {
             let exit_code = arg2;
-            process_exit(exit_code)
+            process_exit(core, exit_code)
+        }
+        ProcessOperation::Checkpoint => {
+            let pid = super::kcb::per_core().current_pid()?;
+            super::checkpoint::checkpoint(pid, arg2)
+        }
+        ProcessOperation::Restore => {
+            let pid = super::kcb::per_core().current_pid()?;
+            super::checkpoint::restore(pid, arg2)
+        }
+        ProcessOperation::Spawn => {
+            let pid = super::kcb::per_core().current_pid()?;
+            let len = arg3 as usize;
+            validate_user_range(pid, arg2, len, false)?;
+
+            let mut kbuf: Vec<u8> = Vec::try_with_capacity(len)?;
+            kbuf.resize(len, 0);
+            copy_from_user(kbuf.as_mut_slice(), arg2)?;
+            let cmdline = core::str::from_utf8(&kbuf).map_err(|_e| KError::NotSupported)?;
+            let (binary, args) = cmdline.split_once(' ').unwrap_or((cmdline, ""));
+            // Modules stay around, so does their name
+            let binary = crate::process::find_module(binary)
+                .ok_or(KError::InvalidFile)?
+                .name();
+            // The kernel keeps the arguments until the child is destroyed
+            let args = String::from(TryString::try_from(args)?).into_boxed_str();
+
+            let capabilities = kpi::process::Capabilities::from(arg4);
+            let child = super::process::spawn_child(pid, binary, args, capabilities, arg5)?;
+            Ok((child as u64, 0))
+        }
+        ProcessOperation::WaitPid => {
+            let pid = super::kcb::per_core().current_pid()?;
+            let child = nr::KernelNode::process(arg2 as Pid).map_err(|_e| KError::NotAChild)?;
+            if child.parent != Some(pid) {
+                return Err(KError::NotAChild);
+            }
+
+            // Once we have its exit code nobody needs the child anymore
+            let reaped = |code: u64| {
+                if let Err(e) = super::process::destroy(arg2 as Pid) {
+                    warn!("Can't destroy process {}: {:?}", arg2, e);
+                }
+                Ok((1, code))
+            };
+            match child.exit_code {
+                Some(code) => reaped(code),
+                None if arg3 == 0 => Ok((0, 0)),
+                None => {
+                    // Exits don't wake us up, check again after a while and
+                    // return the time left of the timeout (in ns)
+                    let nap = core::cmp::min(arg3, super::sleep::POLL_INTERVAL_NS);
+                    let slept = nap - super::sleep::sleep(nap)?;
+                    match nr::KernelNode::process(arg2 as Pid)?.exit_code {
+                        Some(code) => reaped(code),
+                        None => Ok((0, arg3 - slept)),
+                    }
+                }
+            }
+        }
+        ProcessOperation::Kill => {
+            let pid = super::kcb::per_core().current_pid()?;
+            let child = nr::KernelNode::process(arg2 as Pid).map_err(|_e| KError::NotAChild)?;
+            if child.parent != Some(pid) {
+                return Err(KError::NotAChild);
+            }
+
+            super::process::terminate(core, arg2 as Pid, kpi::process::KILLED)?;
+            Ok((0, 0))
+        }
+        ProcessOperation::Trace => {
+            let pid = super::kcb::per_core().current_pid()?;
+            let target = arg2 as Pid;
+            if target != pid {
+                let child = nr::KernelNode::process(target).map_err(|_e| KError::NotAChild)?;
+                if child.parent != Some(pid) {
+                    return Err(KError::NotAChild);
+                }
+            }
+
+            crate::strace::set(target, arg3 != 0);
+            Ok((0, 0))
+        }
+        ProcessOperation::ReadTrace => {
+            let pid = super::kcb::per_core().current_pid()?;
+            let target = arg2 as Pid;
+            if target != pid {
+                let child = nr::KernelNode::process(target).map_err(|_e| KError::NotAChild)?;
+                if child.parent != Some(pid) {
+                    return Err(KError::NotAChild);
+                }
+            }
+
+            let (buf, capacity) = (arg3, arg4 as usize / size_of::<SyscallRecord>());
+            if capacity > 0 {
+                validate_user_range(pid, buf, capacity * size_of::<SyscallRecord>(), true)?;
+            }
+
+            let mut count = 0;
+            while count < capacity {
+                match crate::strace::pop(target) {
+                    Some(record) => {
+                        write_user(buf + (count * size_of::<SyscallRecord>()) as u64, &record)?;
+                        count += 1;
+                    }
+                    None => break,
+                }
+            }
+            Ok((count as u64, 0))
+        }
+        ProcessOperation::SetCoreDump => {
+            let pid = super::kcb::per_core().current_pid()?;
+            super::coredump::set(pid, arg2 != 0);
+            Ok((0, 0))
         }
         ProcessOperation::GetProcessInfo => {
             let vaddr_buf = arg2; // buf.as_mut_ptr() as u64
             let vaddr_buf_len = arg3; // buf.len() as u64
-            let kcb = super::kcb::get_kcb();
+            let kcb = super::kcb::per_core();
 
             let pid = kcb.current_pid()?;
             let mut pinfo = nrproc::NrProcess::<Ring3Process>::pinfo(pid)?;
-            pinfo.cmdline = kcb.cmdline.init_args;
+            let entry = nr::KernelNode::process(pid)?;
+            pinfo.cmdline = entry.args;
             pinfo.app_cmdline = kcb.cmdline.app_args;
+            pinfo.pid = pid as u64;
+            pinfo.generation = nr::KernelNode::process_generation(pid)?;
+            pinfo.cpu_time = crate::cputime::process(pid);
+            pinfo.thread_cpu_time = crate::cputime::thread(kcb.arch.id());
 
             let serialized = serde_cbor::to_vec(&pinfo).unwrap();
             if serialized.len() <= vaddr_buf_len as usize {
-                let mut user_slice = super::process::UserSlice::new(vaddr_buf, serialized.len());
-                user_slice.copy_from_slice(serialized.as_slice());
+                validate_user_range(pid, vaddr_buf, serialized.len(), true)?;
+                copy_to_user(vaddr_buf, serialized.as_slice())?;
             }
 
             Ok((serialized.len() as u64, 0))
@@ -186,7 +535,7 @@ fn handle_process(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
         ProcessOperation::RequestCore => {
             let gtid: usize = arg2.try_into().unwrap();
             let entry_point = arg3;
-            let kcb = super::kcb::get_kcb();
+            let kcb = super::kcb::per_core();
 
             let mut affinity = None;
             for thread in atopology::MACHINE_TOPOLOGY.threads() {
@@ -206,6 +555,58 @@ fn handle_process(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
 
             Ok((arg2, 0))
         }
+        ProcessOperation::RequestCores => {
+            let mask_ptr = arg2;
+            let count: usize = arg3.try_into().unwrap_or(0);
+            let flags = kpi::decode::core_request_flags(arg4).map_err(|_e| KError::InvalidFlags)?;
+            let entry_point = arg5;
+            let kcb = super::kcb::per_core();
+            let pid = kcb.current_pid()?;
+
+            validate_user_range(pid, mask_ptr, size_of::<AffinityMask>(), true)?;
+            let user_mask: AffinityMask = read_user(mask_ptr)?;
+
+            // Only consider hardware threads that actually exist:
+            let mut mask = AffinityMask::empty();
+            for thread in atopology::MACHINE_TOPOLOGY.threads() {
+                if user_mask.contains(thread.id) {
+                    mask.set(thread.id);
+                }
+            }
+            if count == 0 || mask.is_empty() {
+                return Err(KError::InvalidGlobalThreadId);
+            }
+
+            let allocated = nr::KernelNode::allocate_cores_to_process(
+                pid,
+                VAddr::from(entry_point),
+                mask,
+                count,
+                flags.contains(CoreRequestFlags::GANG),
+            )?;
+            write_user(mask_ptr, &allocated)?;
+
+            Ok((allocated.count() as u64, 0))
+        }
+        ProcessOperation::SetSchedulingClass => {
+            let class = SchedulingClass::from(arg2);
+            if class == SchedulingClass::Unknown {
+                return Err(KError::InvalidSchedulingClass { a: arg2 });
+            }
+            let deadline = if arg3 > 0 { Some(arg3) } else { None };
+
+            let executor = core.kcb_mut().arch.current_executor_mut()?;
+            executor.sched_class = class;
+            executor.sched_deadline = deadline;
+            trace!(
+                "Executor {} switched to {:?} (deadline {:?})",
+                executor.eid,
+                class,
+                deadline
+            );
+
+            Ok((0, 0))
+        }
         ProcessOperation::AllocatePhysical => {
             let page_size: usize = arg2.try_into().unwrap_or(0);
             //let affinity: usize = arg3.try_into().unwrap_or(0);
@@ -215,7 +616,7 @@ fn handle_process(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
                 return Err(KError::InvalidSyscallArgument1 { a: arg2 });
             }
 
-            let kcb = super::kcb::get_kcb();
+            let kcb = super::kcb::per_core();
 
             // Figure out what memory to allocate
             let (bp, lp) = if page_size == BASE_PAGE_SIZE {
@@ -242,26 +643,161 @@ fn handle_process(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
 
             Ok((fid as u64, frame.base.as_u64()))
         }
-        ProcessOperation::SubscribeEvent => Err(KError::InvalidProcessOperation { a: arg1 }),
+        ProcessOperation::SubscribeEvent => {
+            let pid = super::kcb::per_core().current_pid()?;
+            let next_event: fn(Pid) -> Result<Option<u64>, KError> = match arg2 {
+                e if e == kpi::process::Event::OutOfMemory as u64 => {
+                    if !nr::KernelNode::capabilities(pid)?
+                        .contains(kpi::process::Capabilities::SUPERVISE)
+                    {
+                        return Err(KError::MissingCapability);
+                    }
+                    crate::oom::subscribe(pid)?;
+                    |pid| Ok(crate::oom::next_event(pid)?.map(|victim| victim as u64))
+                }
+                e if e == kpi::process::Event::Keyboard as u64 => {
+                    crate::keyboard::subscribe(pid)?;
+                    crate::keyboard::next_event
+                }
+                _ => return Err(KError::InvalidSyscallArgument1 { a: arg2 }),
+            };
+
+            match next_event(pid)? {
+                Some(value) => Ok((1, value)),
+                None if arg3 == 0 => Ok((0, 0)),
+                None => {
+                    // Events don't wake us up, check again after a while and
+                    // return the time left of the timeout (in ns)
+                    let nap = core::cmp::min(arg3, super::sleep::POLL_INTERVAL_NS);
+                    let slept = nap - super::sleep::sleep(nap)?;
+                    match next_event(pid)? {
+                        Some(value) => Ok((1, value)),
+                        None => Ok((0, arg3 - slept)),
+                    }
+                }
+            }
+        }
+        ProcessOperation::SetOomPriority => {
+            let pid = super::kcb::per_core().current_pid()?;
+            let target = arg2 as Pid;
+            if target != pid {
+                let child = nr::KernelNode::process(target).map_err(|_e| KError::NotAChild)?;
+                if child.parent != Some(pid) {
+                    return Err(KError::NotAChild);
+                }
+            }
+
+            let priority = kpi::process::OomPriority::ALL
+                .get(arg3 as usize)
+                .ok_or(KError::InvalidSyscallArgument2 { a: arg3 })?;
+            nr::KernelNode::set_oom_priority(target, *priority)?;
+            Ok((0, 0))
+        }
+        ProcessOperation::Reload => {
+            let pid = super::kcb::per_core().current_pid()?;
+            let len = arg4 as usize;
+            validate_user_range(pid, arg3, len, false)?;
+
+            let mut kbuf: Vec<u8> = Vec::try_with_capacity(len)?;
+            kbuf.resize(len, 0);
+            copy_from_user(kbuf.as_mut_slice(), arg3)?;
+            let binary = core::str::from_utf8(&kbuf).map_err(|_e| KError::InvalidName)?;
+
+            let new_pid = super::reload::reload(core, pid, arg2 as Pid, binary)?;
+            Ok((new_pid as u64, 0))
+        }
+        ProcessOperation::ClaimDevice => {
+            let pid = super::kcb::per_core().current_pid()?;
+            let capabilities = nr::KernelNode::capabilities(pid)?;
+            if !capabilities.contains(kpi::process::Capabilities::DEVICES) {
+                return Err(KError::MissingCapability);
+            }
+            crate::devices::claim(pid, arg2)?;
+            Ok((0, 0))
+        }
+        ProcessOperation::SetFdLimit => {
+            let pid = super::kcb::per_core().current_pid()?;
+            crate::cnrfs::MlnrKernelNode::set_fd_limit(pid, arg2)?;
+            Ok((0, 0))
+        }
+        ProcessOperation::SetLogLevel => {
+            let pid = super::kcb::per_core().current_pid()?;
+            let level = kpi::process::LogLevel::ALL
+                .get(arg2 as usize)
+                .ok_or(KError::InvalidSyscallArgument1 { a: arg2 })?;
+            crate::ulog::set_level(pid, *level);
+            Ok((0, 0))
+        }
+        ProcessOperation::BarrierWait => {
+            let pid = super::kcb::per_core().current_pid()?;
+            let (changed, left) = super::barrier::wait(pid, arg2, arg3, arg4)?;
+            Ok((changed as u64, left))
+        }
+        ProcessOperation::Sleep => {
+            let left = super::sleep::sleep(arg2)?;
+            Ok((left, 0))
+        }
+        ProcessOperation::FutexWait => {
+            let kcb = super::kcb::per_core();
+            let pid = kcb.current_pid()?;
+            let expected =
+                u32::try_from(arg3).map_err(|_e| KError::InvalidSyscallArgument2 { a: arg3 })?;
+            let (woken, left) = super::futex::wait(pid, kcb.arch.id(), arg2, expected, arg4)?;
+            Ok((woken as u64, left))
+        }
+        ProcessOperation::FutexWake => {
+            let pid = super::kcb::per_core().current_pid()?;
+            let woken = super::futex::wake(pid, arg2, arg3)?;
+            Ok((woken, 0))
+        }
+        ProcessOperation::DeliverUpcall => {
+            // Doesn't return in case there is an upcall to deliver
+            super::vcpu::deliver_from_syscall(super::kcb::per_core());
+            Ok((0, 0))
+        }
         ProcessOperation::Unknown => Err(KError::InvalidProcessOperation { a: arg1 }),
     }
 }
 
 /// System call handler for vspace operations
-fn handle_vspace(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError> {
+fn handle_vspace(arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> Result<(u64, u64), KError> {
     let op = VSpaceOperation::from(arg1);
     let base = VAddr::from(arg2);
     let region_size = arg3;
     trace!("handle_vspace {:?} {:#x} {:#x}", op, base, region_size);
 
-    let kcb = super::kcb::get_kcb();
+    // Access rights and caching attribute of Map and MapDevice
+    let rights = || -> Result<MapAction, KError> {
+        let flags = kpi::decode::map_flags(arg4).map_err(|_e| KError::InvalidFlags)?;
+        MapAction::try_from(flags)
+    };
+    // DRAM is also in the (write-back) direct map of the kernel, mapping it
+    // with another caching attribute would alias it
+    let dram_rights = || -> Result<MapAction, KError> {
+        let rights = rights()?;
+        if !rights.is_write_back() {
+            return Err(KError::InvalidFlags);
+        }
+        Ok(rights)
+    };
+
+    let kcb = super::kcb::per_core();
     let mut p = kcb.arch.current_executor()?;
 
     match op {
         VSpaceOperation::Map => unsafe {
+            let rights = dram_rights()?;
             let (bp, lp) = crate::memory::size_to_pages(region_size as usize);
             let mut frames = Vec::try_with_capacity(bp + lp)?;
-            crate::memory::KernelAllocator::try_refill_tcache(20 + bp, lp)?;
+            if let Err(e) = crate::memory::KernelAllocator::try_refill_tcache(20 + bp, lp) {
+                // Under memory pressure, make room by evicting user pages we
+                // can re-create (this only helps with base pages)
+                let freed = super::reclaim::reclaim(20 + bp);
+                if lp > 0 || kcb.mem_manager().free_base_pages() < 20 + bp {
+                    debug!("Reclaimed {} pages, still not enough memory", freed);
+                    return Err(e);
+                }
+            }
 
             // TODO(apihell): This `paddr` is bogus, it will return the PAddr of the
             // first frame mapped but if you map multiple Frames, no chance getting that
@@ -301,27 +837,24 @@ fn handle_vspace(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
                 }
             }
 
-            nrproc::NrProcess::<Ring3Process>::map_frames(
-                p.pid,
-                base,
-                frames,
-                MapAction::ReadWriteUser,
-            )
-            .expect("Can't map memory");
+            nrproc::NrProcess::<Ring3Process>::map_anonymous(p.pid, base, frames, rights)
+                .expect("Can't map memory");
 
             Ok((paddr.unwrap().as_u64(), total_len as u64))
         },
         VSpaceOperation::MapDevice => unsafe {
+            let rights = rights()?;
             let paddr = PAddr::from(base.as_u64());
             let size = region_size as usize;
 
+            // Processes can't map memory that belongs to the kernel or the
+            // firmware
+            crate::memory::reserved::check(paddr, size, None)?;
+            // ...or to a device that another process (or the kernel) drives
+            crate::devices::check_map(p.pid, paddr.as_u64(), size as u64)?;
             let frame = Frame::new(paddr, size, kcb.node);
 
-            nrproc::NrProcess::<Ring3Process>::map_device_frame(
-                p.pid,
-                frame,
-                MapAction::ReadWriteUser,
-            )
+            nrproc::NrProcess::<Ring3Process>::map_device_frame(p.pid, frame, rights)
         },
         VSpaceOperation::MapFrame => unsafe {
             let base = VAddr::from(arg2);
@@ -347,6 +880,100 @@ fn handle_vspace(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
             trace!("Identify base {:#x}.", base);
             nrproc::NrProcess::<Ring3Process>::resolve(p.pid, base)
         },
+        VSpaceOperation::Reserve => {
+            let rights = dram_rights()?;
+            let size = region_size as usize;
+            if base.as_u64() % BASE_PAGE_SIZE as u64 != 0 {
+                return Err(KError::InvalidBase);
+            }
+            if size == 0 || size % BASE_PAGE_SIZE != 0 {
+                return Err(KError::InvalidLength);
+            }
+
+            nrproc::NrProcess::<Ring3Process>::reserve(p.pid, base, size, rights)?;
+            Ok((base.as_u64(), size as u64))
+        }
+        VSpaceOperation::Unreserve => {
+            nrproc::NrProcess::<Ring3Process>::unreserve(p.pid, base)?;
+            Ok((base.as_u64(), 0))
+        }
+        VSpaceOperation::Commit => {
+            let size = region_size as usize;
+            if base.as_u64() % BASE_PAGE_SIZE as u64 != 0 || size % BASE_PAGE_SIZE != 0 {
+                return Err(KError::InvalidBase);
+            }
+            if base.as_u64().checked_add(region_size).is_none() {
+                return Err(KError::BaseOverflow {
+                    base: base.as_u64(),
+                });
+            }
+
+            // Page by page, a large commit shouldn't need all the memory in
+            // the TCache at once
+            let mut committed = 0;
+            for offset in (0..size).step_by(BASE_PAGE_SIZE) {
+                // The frame for the page and page-tables we may need
+                if crate::memory::KernelAllocator::try_refill_tcache(7, 0).is_err() {
+                    super::reclaim::reclaim(7);
+                }
+                let mut frame = kcb.mem_manager().allocate_base_page()?;
+                unsafe { frame.zero() };
+
+                match nrproc::NrProcess::<Ring3Process>::commit(p.pid, base + offset, frame) {
+                    Ok(true) => committed += BASE_PAGE_SIZE,
+                    r => {
+                        kcb.mem_manager().release_base_page(frame)?;
+                        r?;
+                    }
+                }
+            }
+
+            Ok((base.as_u64(), committed as u64))
+        }
+        VSpaceOperation::Dump => {
+            let (buf, capacity) = (arg2, arg3 as usize / size_of::<MappingRange>());
+            let console = arg4 != 0;
+            if capacity > 0 {
+                validate_user_range(p.pid, buf, capacity * size_of::<MappingRange>(), true)?;
+            }
+            // Physical addresses only with the capability (0 otherwise)
+            let inspect =
+                nr::KernelNode::capabilities(p.pid)?.contains(kpi::process::Capabilities::INSPECT);
+            if console {
+                info!("Address-space of process {}:", p.pid);
+            }
+
+            let mut count = 0;
+            let mut cursor = VAddr::zero();
+            while let Some(range) = nrproc::NrProcess::<Ring3Process>::next_range(p.pid, cursor)? {
+                cursor = range.vaddr + range.size;
+                let paddr = if inspect { range.paddr.as_u64() } else { 0 };
+                if console {
+                    info!(
+                        "  {:#x} - {:#x} {:#x} -> {:#x} ({} KiB pages) {}",
+                        range.vaddr,
+                        range.vaddr + range.size,
+                        range.size,
+                        paddr,
+                        range.page_size / 1024,
+                        range.rights
+                    );
+                }
+                if count < capacity {
+                    let entry = MappingRange {
+                        start: range.vaddr.as_u64(),
+                        end: (range.vaddr + range.size).as_u64(),
+                        paddr,
+                        page_size: range.page_size as u64,
+                        flags: MapFlags::from(range.rights).bits(),
+                    };
+                    write_user(buf + (count * size_of::<MappingRange>()) as u64, &entry)?;
+                }
+                count += 1;
+            }
+
+            Ok((count as u64, 0))
+        }
         VSpaceOperation::Unknown => {
             error!("Got an invalid VSpaceOperation code.");
             Err(KError::InvalidVSpaceOperation { a: arg1 })
@@ -364,7 +991,7 @@ fn handle_fileio(
 ) -> Result<(u64, u64), KError> {
     let op = FileOperation::from(arg1);
 
-    let kcb = super::kcb::get_kcb();
+    let kcb = super::kcb::per_core();
     let pid = kcb.arch.current_pid()?;
 
     match op {
@@ -375,7 +1002,6 @@ fn handle_fileio(
             let pathname = arg2;
             let flags = arg3;
             let modes = arg4;
-            let _r = user_virt_addr_valid(pid, pathname, 0)?;
             cnrfs::MlnrKernelNode::map_fd(pid, pathname, flags, modes)
         }
         FileOperation::Read | FileOperation::Write => {
@@ -383,7 +1009,7 @@ fn handle_fileio(
             let buffer = arg3;
             let len = arg4;
 
-            let _r = user_virt_addr_valid(pid, buffer, len)?;
+            validate_user_range(pid, buffer, len as usize, op == FileOperation::Read)?;
             cnrfs::MlnrKernelNode::file_io(op, pid, fd, buffer, len, -1)
         }
         FileOperation::ReadAt | FileOperation::WriteAt => {
@@ -391,8 +1017,12 @@ fn handle_fileio(
             let buffer = arg3;
             let len = arg4;
             let offset = arg5 as i64;
+            // Positional I/O never uses (or moves) the cursor of `fd`.
+            if offset < 0 {
+                return Err(KError::InvalidOffset);
+            }
 
-            let _r = user_virt_addr_valid(pid, buffer, len)?;
+            validate_user_range(pid, buffer, len as usize, op == FileOperation::ReadAt)?;
             cnrfs::MlnrKernelNode::file_io(op, pid, fd, buffer, len, offset)
         }
         FileOperation::Close => {
@@ -403,13 +1033,11 @@ fn handle_fileio(
             let name = arg2;
             let info_ptr = arg3;
 
-            let _r = user_virt_addr_valid(pid, name, 0)?;
+            validate_user_range(pid, info_ptr, size_of::<FileInfo>(), true)?;
             cnrfs::MlnrKernelNode::file_info(pid, name, info_ptr)
         }
         FileOperation::Delete => {
             let name = arg2;
-
-            let _r = user_virt_addr_valid(pid, name, 0)?;
             cnrfs::MlnrKernelNode::file_delete(pid, name)
         }
         FileOperation::WriteDirect => {
@@ -419,9 +1047,10 @@ fn handle_fileio(
                 offset = 0;
             }
 
-            let mut kernslice = crate::process::KernSlice::new(arg2, len as usize);
+            validate_user_range(pid, arg2, len as usize, false)?;
+            let mut kernslice = crate::process::KernSlice::new(arg2, len as usize)?;
             let mut buffer = unsafe { Arc::get_mut_unchecked(&mut kernslice.buffer) };
-            let cnrfs = super::kcb::get_kcb().arch.cnrfs.as_ref().unwrap();
+            let cnrfs = super::kcb::per_core().arch.cnrfs.as_ref().unwrap();
 
             let len = cnrfs.write(2, &mut buffer, offset)?;
 
@@ -430,19 +1059,77 @@ fn handle_fileio(
         FileOperation::FileRename => {
             let oldname = arg2;
             let newname = arg3;
-
-            let _r = user_virt_addr_valid(pid, oldname, 0)?;
-            let _r = user_virt_addr_valid(pid, newname, 0)?;
-
             cnrfs::MlnrKernelNode::file_rename(pid, oldname, newname)
         }
         FileOperation::MkDir => {
             let pathname = arg2;
             let modes = arg3;
-            let _r = user_virt_addr_valid(pid, pathname, 0)?;
-
             cnrfs::MlnrKernelNode::mkdir(pid, pathname, modes)
         }
+        FileOperation::Poll => {
+            let core = super::kcb::per_core().arch.id();
+            super::poll::poll(pid, core, arg2, arg3 as usize, arg4)
+        }
+        FileOperation::RingEnter => crate::ioring::enter(pid, arg2, arg3),
+        FileOperation::ReadV | FileOperation::WriteV => {
+            let fd = arg2;
+            let iov = arg3;
+            let len = arg4 as usize;
+            let count = len / size_of::<IoVec>();
+            if count > IOV_MAX {
+                return Err(KError::InvalidLength);
+            }
+            validate_user_range(pid, iov, len, false)?;
+
+            // Check all buffers before we touch the file:
+            let is_read = op == FileOperation::ReadV;
+            let mut vecs: Vec<IoVec> = Vec::try_with_capacity(count)?;
+            for idx in 0..count {
+                let vec: IoVec = read_user(iov + (idx * size_of::<IoVec>()) as u64)?;
+                validate_user_range(pid, vec.base, vec.len as usize, is_read)?;
+                vecs.push(vec);
+            }
+
+            // Segments use (and advance) the offset of `fd` one after the
+            // other, a short read or write ends the request.
+            let op = if is_read {
+                FileOperation::Read
+            } else {
+                FileOperation::Write
+            };
+            let mut total = 0;
+            for vec in vecs.iter().filter(|vec| vec.len > 0) {
+                let (done, _) = cnrfs::MlnrKernelNode::file_io(op, pid, fd, vec.base, vec.len, -1)?;
+                total += done;
+                if done < vec.len {
+                    break;
+                }
+            }
+
+            Ok((total, 0))
+        }
+        FileOperation::Symlink => {
+            let target = arg2;
+            let linkpath = arg3;
+            cnrfs::MlnrKernelNode::symlink(pid, target, linkpath)
+        }
+        FileOperation::ReadLink => {
+            let pathname = arg2;
+            let buf = arg3;
+            let len = arg4;
+            validate_user_range(pid, buf, len as usize, true)?;
+            cnrfs::MlnrKernelNode::readlink(pid, pathname, buf, len)
+        }
+        FileOperation::Fsync => {
+            let fd = arg2;
+            cnrfs::MlnrKernelNode::fsync(pid, fd)
+        }
+        FileOperation::Mount => {
+            let pathname = arg2;
+            let kind = arg3;
+            let limit = arg4;
+            cnrfs::MlnrKernelNode::mount(pid, pathname, kind, limit)
+        }
         FileOperation::Unknown => {
             unreachable!("FileOperation not allowed");
             Err(KError::NotSupported)
@@ -450,79 +1137,169 @@ fn handle_fileio(
     }
 }
 
-/// TODO: This method makes file-operations slow, improve it to use large page
-/// sizes. Or maintain a list of (low, high) memory limits per process and check
-/// if (base, size) are within the process memory limits.
-fn user_virt_addr_valid(pid: Pid, base: u64, size: u64) -> Result<(u64, u64), KError> {
-    let mut base = base;
-    let upper_addr = base + size;
-
-    if upper_addr < KERNEL_BASE {
-        while base <= upper_addr {
-            // Validate addresses for the buffer end.
-            if upper_addr - base <= BASE_PAGE_SIZE as u64 {
-                let _r = nrproc::NrProcess::<Ring3Process>::resolve(pid, VAddr::from(base))?;
-                return nrproc::NrProcess::<Ring3Process>::resolve(
-                    pid,
-                    VAddr::from(upper_addr - 1),
-                );
-            }
+/// System call handler for fault injection
+fn handle_test(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError> {
+    let op = TestOperation::from(arg1);
+    let pid = super::kcb::per_core().current_pid()?;
 
-            let _r = nrproc::NrProcess::<Ring3Process>::resolve(pid, VAddr::from(base))?;
-            base += BASE_PAGE_SIZE as u64;
+    match op {
+        TestOperation::FailAllocations => {
+            crate::fault::arm(pid, crate::fault::Fault::FailAllocation, arg2)?;
+            Ok((0, 0))
+        }
+        TestOperation::DropPackets => {
+            crate::fault::arm(pid, crate::fault::Fault::DropPacket, arg2)?;
+            Ok((0, 0))
+        }
+        TestOperation::DelayCombine => {
+            crate::fault::arm_combine_delay(pid, arg2, arg3)?;
+            Ok((0, 0))
+        }
+        TestOperation::Reset => {
+            crate::fault::reset(pid)?;
+            Ok((0, 0))
+        }
+        TestOperation::LoseSaveArea => {
+            let core = super::kcb::per_core().arch.id();
+            crate::fault::arm_on_core(pid, core, CoreFault::LoseSaveArea)?;
+            Ok((0, 0))
+        }
+        TestOperation::LoseProcess => {
+            let core = super::kcb::per_core().arch.id();
+            crate::fault::arm_on_core(pid, core, CoreFault::LoseProcess)?;
+            Ok((0, 0))
         }
-        return Ok((base, size));
+        TestOperation::Unknown => Err(KError::InvalidTestOperation { a: arg1 }),
     }
-    Err(KError::BadAddress)
 }
 
-#[allow(unused)]
-fn debug_print_syscall(function: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64) {
-    sprint!("syscall: {:?}", SystemCall::new(function));
-
-    match SystemCall::new(function) {
-        SystemCall::System => {
-            sprintln!(
-                " {:?} {} {} {} {}",
-                SystemOperation::from(arg1),
-                arg2,
-                arg3,
-                arg4,
-                arg5
-            );
+/// System call handler for the UDP sockets of processes (see `localhost`)
+#[cfg(feature = "smoltcp")]
+fn handle_net(arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64) -> Result<(u64, u64), KError> {
+    use super::localhost;
+    use kpi::net::{Endpoint, MAX_DATAGRAM};
+
+    let op = NetOperation::from(arg1);
+    let pid = super::kcb::per_core().current_pid()?;
+
+    match op {
+        NetOperation::UdpBind => Ok((localhost::bind(pid, arg2)?, 0)),
+        NetOperation::UdpSendTo => {
+            let len = arg4 as usize;
+            if len > MAX_DATAGRAM {
+                return Err(KError::UserBufferTooLarge { len });
+            }
+            validate_user_range(pid, arg3, len, false)?;
+            let mut data = [0u8; MAX_DATAGRAM];
+            copy_from_user(&mut data[..len], arg3)?;
+            let sent = localhost::send_to(pid, arg2, &data[..len], Endpoint::from(arg5))?;
+            Ok((sent as u64, 0))
         }
-        SystemCall::Process => {
-            sprintln!(
-                " {:?} {} {} {} {}",
-                ProcessOperation::from(arg1),
-                arg2,
-                arg3,
-                arg4,
-                arg5
-            );
+        NetOperation::UdpRecvFrom => {
+            let len = core::cmp::min(arg4 as usize, MAX_DATAGRAM);
+            validate_user_range(pid, arg3, len, true)?;
+            let mut data = [0u8; MAX_DATAGRAM];
+            match localhost::recv_from(pid, arg2, &mut data[..len])? {
+                Some((received, from)) => {
+                    copy_to_user(arg3, &data[..received])?;
+                    Ok((received as u64, u64::from(from)))
+                }
+                None => Ok((0, 0)),
+            }
         }
-        SystemCall::VSpace => {
-            sprintln!(
-                " {:?} {} {} {} {}",
-                VSpaceOperation::from(arg1),
-                arg2,
-                arg3,
-                arg4,
-                arg5
-            );
+        NetOperation::UdpClose => {
+            localhost::close(pid, arg2)?;
+            Ok((0, 0))
         }
-        SystemCall::FileIO => {
-            sprintln!(
-                " {:?} {} {} {} {}",
-                FileOperation::from(arg1),
-                arg2,
-                arg3,
-                arg4,
-                arg5
+        NetOperation::Unknown => Err(KError::InvalidNetOperation { a: arg1 }),
+    }
+}
+
+/// Without a network stack there are no sockets
+#[cfg(not(feature = "smoltcp"))]
+fn handle_net(arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64) -> Result<(u64, u64), KError> {
+    Err(KError::InvalidNetOperation { a: arg1 })
+}
+
+#[allow(unused)]
+fn debug_print_syscall(function: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64) {
+    let record = SyscallRecord {
+        function,
+        operation: arg1,
+        args: [arg2, arg3, arg4, arg5],
+        ..Default::default()
+    };
+    sprintln!("syscall: {}", record.call());
+}
+
+/// Does `sa` (the state saved on a system call entry) resume user-space?
+///
+/// `sysret` to a kernel or non-canonical address would fault (or run) in
+/// ring 0.
+fn returns_to_user(sa: &kpi::arch::SaveArea) -> bool {
+    kpi::decode::user_range(sa.rip, 0).is_ok()
+}
+
+/// Kills the caller of a system call we can't return from and schedules
+/// something else.
+fn abort_syscall(mut core: LocalCore) -> ! {
+    let kcb = core.kcb_mut();
+    let id = kcb.arch.id();
+    match kcb.current_pid() {
+        Ok(pid) => {
+            error!(
+                "System call on core {} without a valid save area, killing process {}",
+                id, pid
             );
+            if let Err(e) = super::process::terminate(&mut core, pid, kpi::process::CRASHED) {
+                error!("Can't terminate process {}: {:?}", pid, e);
+                let _executor = core.kcb_mut().arch.take_current_executor();
+            }
         }
-        SystemCall::Unknown => unreachable!(),
+        Err(_e) => error!("System call on core {} without a process", id),
+    }
+
+    drop(core);
+    crate::scheduler::schedule()
+}
+
+/// Save areas that `CoreFault::LoseSaveArea` took away from their core.
+static LOST_SAVE_AREAS: Mutex<[Option<Pin<Box<kpi::arch::SaveArea>>>; MAX_CORES]> = {
+    const NONE: Option<Pin<Box<kpi::arch::SaveArea>>> = None;
+    Mutex::new([NONE; MAX_CORES])
+};
+
+/// Takes the save area away from the core if the caller armed
+/// `CoreFault::LoseSaveArea` (so its next system call enters
+/// [`syscall_without_save_area`]) and returns how to resume the caller.
+///
+/// The caller returns with `iretq` and interrupts disabled, an interrupt
+/// would find no save area either.
+fn lose_save_area(kcb: &mut Kcb<Arch86Kcb>) -> Option<Ring3Resumer> {
+    if !crate::fault::fire_on_core(kcb.arch.id(), CoreFault::LoseSaveArea) {
+        return None;
     }
+    let mut save_area = kcb.arch.save_area.take()?;
+    save_area.rflags &= !rflags::RFlags::FLAGS_IF.bits();
+    let resumer = Ring3Resumer::new_iret(&*save_area);
+    // Keeps it alive until the caller is gone
+    LOST_SAVE_AREAS.lock()[kcb.arch.id()] = Some(save_area);
+    Some(resumer)
+}
+
+/// Entered instead of [`syscall_handle`] if the core has no save area (see
+/// `syscall_enter`), the state of the caller is lost.
+#[inline(never)]
+#[no_mangle]
+pub extern "C" fn syscall_without_save_area() -> ! {
+    // Safety: We just entered the kernel from a system call.
+    let mut core = unsafe { LocalCore::new() };
+    // The next process on the core needs the save area that a test took away
+    let kcb = core.kcb_mut();
+    if let Some(save_area) = LOST_SAVE_AREAS.lock()[kcb.arch.id()].take() {
+        kcb.arch.set_save_area(save_area);
+    }
+    abort_syscall(core)
 }
 
 #[inline(never)]
@@ -535,16 +1312,78 @@ pub extern "C" fn syscall_handle(
     arg4: u64,
     arg5: u64,
 ) -> ! {
-    let status: Result<(u64, u64), KError> = match SystemCall::new(function) {
-        SystemCall::System => handle_system(arg1, arg2, arg3),
-        SystemCall::Process => handle_process(arg1, arg2, arg3),
-        SystemCall::VSpace => handle_vspace(arg1, arg2, arg3),
-        SystemCall::FileIO => handle_fileio(arg1, arg2, arg3, arg4, arg5),
-        _ => Err(KError::InvalidSyscallArgument1 { a: function }),
+    #[cfg(feature = "syscall-timing")]
+    let start = unsafe { x86::time::rdtsc() };
+    // Safety: We just entered the kernel from a system call.
+    let mut core = unsafe { LocalCore::new() };
+    super::mitigations::enter_kernel();
+    core.kcb_mut().enter_syscall();
+
+    // Don't trust the per-core state: we can't return to a caller whose save
+    // area is missing or broken (it gets killed), a call on a core that has
+    // no process (anymore) fails
+    if !core
+        .kcb_mut()
+        .arch
+        .save_area
+        .as_ref()
+        .map_or(false, |sa| returns_to_user(sa))
+    {
+        abort_syscall(core);
+    }
+    let has_process = core.kcb_mut().arch.has_executor()
+        && !crate::fault::fire_on_core(core.kcb_mut().arch.id(), CoreFault::LoseProcess);
+    crate::cputime::enter_kernel(core.kcb_mut().arch.id());
+    #[cfg(feature = "syscall-budget")]
+    super::budget::enter(core.kcb_mut().arch.id());
+
+    // Reject calls that don't match their signature before dispatching:
+    let decoded = kpi::decode::operation(function, arg1)
+        .and_then(|op| kpi::decode::arguments(op, [arg2, arg3, arg4, arg5]));
+
+    // Only look up the process if someone traces system calls
+    let mut traced = None;
+    if crate::strace::active() {
+        let kcb = core.kcb_mut();
+        if let Ok(pid) = kcb.current_pid() {
+            if crate::strace::is_traced(pid) {
+                let record = SyscallRecord {
+                    function,
+                    operation: arg1,
+                    args: [arg2, arg3, arg4, arg5],
+                    core: kcb.arch.id() as u64,
+                    ..Default::default()
+                };
+                crate::strace::enter(pid, &record);
+                traced = Some((pid, record));
+            }
+        }
+    }
+
+    let status: Result<(u64, u64), KError> = match decoded {
+        _ if !has_process => Err(KError::ProcessNotSet),
+        Err(error) => Err(KError::InvalidSyscallArguments { error }),
+        Ok(()) => match SystemCall::new(function) {
+            SystemCall::System => handle_system(&mut core, arg1, arg2, arg3, arg4, arg5),
+            SystemCall::Process => handle_process(&mut core, arg1, arg2, arg3, arg4, arg5),
+            SystemCall::VSpace => handle_vspace(arg1, arg2, arg3, arg4),
+            SystemCall::FileIO => handle_fileio(arg1, arg2, arg3, arg4, arg5),
+            SystemCall::Test => handle_test(arg1, arg2, arg3),
+            SystemCall::Net => handle_net(arg1, arg2, arg3, arg4, arg5),
+            _ => Err(KError::InvalidSyscallArgument1 { a: function }),
+        },
     };
 
+    if let Some((pid, mut record)) = traced {
+        match &status {
+            Ok((a1, a2)) => record.ret = [*a1, *a2],
+            Err(e) => record.error = SystemCallError::from(e.clone()) as u64,
+        }
+        crate::strace::exit(pid, record);
+    }
+
     let r = {
-        let kcb = super::kcb::get_kcb();
+        let kcb = core.kcb_mut();
 
         let _retcode = match status {
             Ok((a1, a2)) => {
@@ -562,7 +1401,15 @@ pub extern "C" fn syscall_handle(
             }
         };
 
-        super::process::Ring3Resumer::new_restore(kcb.arch.get_save_area_ptr())
+        #[cfg(feature = "syscall-timing")]
+        kcb.add_syscall_time(unsafe { x86::time::rdtsc() } - start);
+        #[cfg(feature = "syscall-budget")]
+        super::budget::exit(kcb.arch.id(), function, arg1, [arg2, arg3, arg4, arg5]);
+
+        match lose_save_area(kcb) {
+            Some(resumer) => resumer,
+            None => Ring3Resumer::new_restore(kcb.arch.get_save_area_ptr()),
+        }
     };
 
     unsafe { r.resume() }
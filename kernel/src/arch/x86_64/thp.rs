@@ -0,0 +1,137 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Transparent huge-pages: promotes anonymous user memory to large pages.
+//!
+//! With `thp=on` on the kernel command-line, idle cores look for large-page
+//! aligned ranges of a process that are backed by 512 anonymous base pages
+//! with the same rights ([`promote_next`]). Such a range is unmapped, copied
+//! into a large page and then mapped as one 2 MiB page, which saves TLB
+//! entries for programs that map a lot of memory with base pages.
+//!
+//! If the large page can't be mapped, the base pages are mapped again.
+//! Promoted pages are demoted to base pages again when one of their base
+//! pages gets unmapped (see `Op::MemUnmap`).
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use log::{debug, error, trace, warn};
+
+use crate::error::KError;
+use crate::memory::{Frame, KernelAllocator, PhysicalPageProvider, VAddr, BASE_PAGE_SIZE};
+use crate::nrproc::NrProcess;
+use crate::process::{Pid, MAX_PROCESSES};
+
+use super::process::Ring3Process;
+
+/// Number of ranges that were promoted to large pages.
+static PROMOTED: AtomicU64 = AtomicU64::new(0);
+
+/// Number of promotions that were given up (e.g., no large page left).
+static FAILED: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of (promoted, failed) promotions so far.
+pub fn stats() -> (u64, u64) {
+    (
+        PROMOTED.load(Ordering::Relaxed),
+        FAILED.load(Ordering::Relaxed),
+    )
+}
+
+/// Is promotion enabled (`thp=on`)?
+pub fn enabled() -> bool {
+    super::kcb::per_core().cmdline.thp == "on"
+}
+
+/// Promotes the first promotable range of any process, returns `true` if
+/// one was promoted.
+pub fn promote_next() -> bool {
+    for pid in 0..MAX_PROCESSES {
+        let (base, first) = match NrProcess::<Ring3Process>::promotable(pid, VAddr::zero()) {
+            Ok(Some(range)) => range,
+            _ => continue,
+        };
+
+        match promote(pid, base, first) {
+            Ok(()) => {
+                PROMOTED.fetch_add(1, Ordering::Relaxed);
+                trace!("Promoted {:#x} of {}", base, pid);
+                return true;
+            }
+            Err(e) => {
+                FAILED.fetch_add(1, Ordering::Relaxed);
+                debug!("Can't promote {:#x} of {}: {:?}", base, pid, e);
+                return false;
+            }
+        }
+    }
+
+    false
+}
+
+/// Replaces the base pages at `base` with a large page on the NUMA node of
+/// `first` (the frame of the first base page).
+fn promote(pid: Pid, base: VAddr, first: Frame) -> Result<(), KError> {
+    let kcb = super::kcb::per_core();
+    let gmanager = kcb
+        .physical_memory
+        .gmanager
+        .ok_or(KError::GlobalMemoryNotSet)?;
+
+    // Page-tables for the large mapping
+    KernelAllocator::try_refill_tcache(7, 0)?;
+    let large = gmanager.node_caches[first.affinity as usize]
+        .lock()
+        .allocate_large_page()?;
+
+    let (handle, frames) = match NrProcess::<Ring3Process>::promote(pid, base) {
+        Ok(r) => r,
+        Err(e) => {
+            release_large_page(large);
+            return Err(e);
+        }
+    };
+    super::tlb::shootdown_synchronized(handle, pid);
+
+    // User-space can't access the pages anymore (a fault on them retries
+    // until the promotion is committed)
+    for (i, frame) in frames.iter().enumerate() {
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                frame.kernel_vaddr().as_ptr::<u8>(),
+                (large.kernel_vaddr() + i * BASE_PAGE_SIZE).as_mut_ptr::<u8>(),
+                BASE_PAGE_SIZE,
+            );
+        }
+    }
+
+    match NrProcess::<Ring3Process>::promote_commit(pid, base, Some(large)) {
+        Ok(()) => {
+            for frame in frames {
+                let mut ncache = gmanager.node_caches[frame.affinity as usize].lock();
+                if let Err(e) = ncache.release_base_page(frame) {
+                    warn!("Can't release {:?} of {}: {:?}", frame, pid, e);
+                }
+            }
+            Ok(())
+        }
+        Err(e) => {
+            release_large_page(large);
+            // The base pages still have their page-tables, mapping them
+            // again doesn't need memory
+            if let Err(rollback) = NrProcess::<Ring3Process>::promote_commit(pid, base, None) {
+                error!("Can't map {:#x} of {} again: {:?}", base, pid, rollback);
+            }
+            Err(e)
+        }
+    }
+}
+
+fn release_large_page(frame: Frame) {
+    let kcb = super::kcb::per_core();
+    if let Some(gmanager) = kcb.physical_memory.gmanager {
+        let _r = gmanager.node_caches[frame.affinity as usize]
+            .lock()
+            .release_large_page(frame);
+    }
+}
@@ -2,21 +2,298 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 //! Timer API
+//!
+//! The timer interrupt of a core comes from its local APIC timer, which is
+//! driven by one of the [`TimerBackend`]s (both take an absolute deadline in
+//! cycles of [`now`]):
+//!
+//! - [`TscDeadline`]: the timer fires when the TSC reaches the deadline
+//!   (preferred).
+//! - [`ApicOneShot`]: the timer counts down at the (calibrated) APIC timer
+//!   frequency, for CPUs without TSC-deadline mode or without an invariant
+//!   TSC (the TSC would tick slower or faster with the core frequency).
+//!
+//! The kernel keeps time ([`now`]) with the TSC if it's invariant. Otherwise
+//! it reads the HPET ([`HpetClock`]) and converts its counter to cycles of
+//! the calibrated TSC frequency, so timeouts and the timer wheel don't
+//! depend on the clock. Without an HPET we stay with the TSC and warn.
+//!
+//! [`init`] measures the TSC and APIC timer frequencies against a reference
+//! clock (the HPET, or the PIT, see `refclock`) during boot and picks the
+//! clock and the backend for all cores.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
-use super::kcb::get_kcb;
 use apic::ApicDriver;
+use log::{info, warn};
+use x86::cpuid::CpuId;
+use x86::msr::{rdmsr, wrmsr, IA32_TSC_DEADLINE};
+use x86::time::rdtsc;
+
+use super::kcb::per_core;
+use super::lapic;
+use super::refclock::{self, Hpet, ReferenceClock};
+use super::MAX_CORES;
 
 /// Default when to raise the next timer irq (in rdtsc ticks)
 pub const DEFAULT_TIMER_DEADLINE: u64 = 2_000_000_000;
 
+/// Raises the timer interrupt of the current core at a TSC deadline.
+pub trait TimerBackend: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Arms the timer for when the TSC reaches `tsc`.
+    fn arm(&self, tsc: u64);
+
+    /// The deadline the timer is armed for (`None` if it isn't armed or
+    /// already fired).
+    fn armed(&self) -> Option<u64>;
+
+    /// Stops the timer.
+    fn disarm(&self);
+}
+
+/// The local APIC timer in TSC-deadline mode.
+pub struct TscDeadline;
+
+impl TimerBackend for TscDeadline {
+    fn name(&self) -> &'static str {
+        "TSC-deadline"
+    }
+
+    fn arm(&self, tsc: u64) {
+        let kcb = per_core();
+        let mut apic = kcb.arch.apic();
+        apic.tsc_enable();
+        apic.tsc_set(tsc);
+    }
+
+    fn armed(&self) -> Option<u64> {
+        match unsafe { rdmsr(IA32_TSC_DEADLINE) } {
+            0 => None,
+            tsc => Some(tsc),
+        }
+    }
+
+    fn disarm(&self) {
+        unsafe { wrmsr(IA32_TSC_DEADLINE, 0) };
+    }
+}
+
+/// LVT timer: masked.
+const LVT_TIMER_MASKED: u32 = 1 << 16;
+
+/// Divide configuration of the APIC timer: divide by 16.
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// APIC timer ticks per millisecond (set by [`init`]).
+static APIC_TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+/// The local APIC timer in one-shot mode.
+pub struct ApicOneShot;
+
+/// The deadline the APIC timer of a core is armed for (in TSC cycles).
+static ONE_SHOT_DEADLINES: [AtomicU64; MAX_CORES] = {
+    const NONE: AtomicU64 = AtomicU64::new(0);
+    [NONE; MAX_CORES]
+};
+
+impl TimerBackend for ApicOneShot {
+    fn name(&self) -> &'static str {
+        "APIC one-shot"
+    }
+
+    fn arm(&self, tsc: u64) {
+        let ns = crate::timer_wheel::cycles_to_ns(tsc.saturating_sub(now()));
+        let ticks = ns as u128 * APIC_TICKS_PER_MS.load(Ordering::Relaxed) as u128 / 1_000_000;
+        let ticks = ticks.clamp(1, u32::MAX as u128) as u32;
+
+        ONE_SHOT_DEADLINES[per_core().arch.id()].store(tsc, Ordering::Relaxed);
+        unsafe {
+            // One-shot mode (bits 17..19 clear), unmasked
+            lapic::write(lapic::LVT_TIMER, apic::TSC_TIMER_VECTOR as u32);
+            lapic::write(lapic::TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+            lapic::write(lapic::TIMER_INITIAL_COUNT, ticks);
+        }
+    }
+
+    fn armed(&self) -> Option<u64> {
+        if lapic::read(lapic::TIMER_CURRENT_COUNT) == 0 {
+            return None;
+        }
+        Some(ONE_SHOT_DEADLINES[per_core().arch.id()].load(Ordering::Relaxed))
+    }
+
+    fn disarm(&self) {
+        unsafe { lapic::write(lapic::TIMER_INITIAL_COUNT, 0) };
+    }
+}
+
+static BACKENDS: [&dyn TimerBackend; 2] = [&TscDeadline, &ApicOneShot];
+
+/// Index of [`TscDeadline`] in [`BACKENDS`].
+const TSC_DEADLINE: usize = 0;
+/// Index of [`ApicOneShot`] in [`BACKENDS`].
+const APIC_ONE_SHOT: usize = 1;
+
+/// Index of the backend we use in [`BACKENDS`] (set by [`init`]).
+static BACKEND: AtomicUsize = AtomicUsize::new(TSC_DEADLINE);
+
+fn backend() -> &'static dyn TimerBackend {
+    BACKENDS[BACKEND.load(Ordering::Relaxed)]
+}
+
+/// Keeps time with the HPET, in cycles of the calibrated TSC frequency.
+pub struct HpetClock {
+    hpet: Hpet,
+    /// TSC cycles per microsecond.
+    cycles_per_us: u64,
+    /// The main counter extended to 64 bits, when it was last read.
+    last: AtomicU64,
+}
+
+impl HpetClock {
+    fn new(hpet: Hpet, cycles_per_us: u64) -> HpetClock {
+        let last = AtomicU64::new(hpet.counter());
+        HpetClock {
+            hpet,
+            cycles_per_us,
+            last,
+        }
+    }
+
+    fn now(&self) -> u64 {
+        // A 32-bit counter wraps after a few minutes, the timer interrupts
+        // read it more often than that
+        let last = self.last.load(Ordering::Acquire);
+        let ticks = last + (self.hpet.counter().wrapping_sub(last) & self.hpet.counter_mask());
+        self.last.fetch_max(ticks, Ordering::AcqRel);
+
+        // Femtoseconds to microseconds
+        let cycles =
+            ticks as u128 * self.hpet.period() as u128 * self.cycles_per_us as u128 / 1_000_000_000;
+        cycles as u64
+    }
+}
+
+/// The HPET if the TSC isn't invariant (set by [`init`]).
+static HPET_CLOCK: spin::Once<HpetClock> = spin::Once::new();
+
+/// The current time (in TSC cycles) on the current core, the timer
+/// backends take deadlines relative to it.
+pub fn now() -> u64 {
+    match HPET_CLOCK.get() {
+        Some(clock) => clock.now(),
+        None => unsafe { rdtsc() },
+    }
+}
+
+/// Like [`now`], but comparable with `global_now` of other cores.
+pub fn global_now() -> u64 {
+    match HPET_CLOCK.get() {
+        Some(clock) => clock.now(),
+        None => super::tsc::now(),
+    }
+}
+
+/// How long we measure the TSC and the APIC timer against the reference
+/// clock.
+const CALIBRATION_US: u64 = 10_000;
+
+/// Measures the TSC (cycles per microsecond) and the APIC timer (ticks per
+/// millisecond) against `clock`.
+fn calibrate(clock: &dyn ReferenceClock) -> (u64, u64) {
+    unsafe {
+        lapic::write(
+            lapic::LVT_TIMER,
+            LVT_TIMER_MASKED | apic::TSC_TIMER_VECTOR as u32,
+        );
+        lapic::write(lapic::TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        lapic::write(lapic::TIMER_INITIAL_COUNT, u32::MAX);
+    }
+    let start = unsafe { rdtsc() };
+    clock.wait(CALIBRATION_US);
+    let cycles = unsafe { rdtsc() } - start;
+    let ticks = u32::MAX - lapic::read(lapic::TIMER_CURRENT_COUNT);
+    unsafe { lapic::write(lapic::TIMER_INITIAL_COUNT, 0) };
+
+    (
+        core::cmp::max(cycles / CALIBRATION_US, 1),
+        core::cmp::max(ticks as u64 * 1000 / CALIBRATION_US, 1),
+    )
+}
+
+/// Calibrates the TSC and the APIC timer and picks the clock and the timer
+/// backend (on the BSP, needs ACPI).
+pub fn init() {
+    let hpet = refclock::Hpet::find();
+    let clock: &dyn ReferenceClock = match hpet.as_ref() {
+        Some(hpet) => hpet,
+        None => &refclock::Pit,
+    };
+    let (cycles_per_us, apic_ticks_per_ms) = calibrate(clock);
+    crate::timer_wheel::set_cycles_per_us(cycles_per_us);
+    APIC_TICKS_PER_MS.store(apic_ticks_per_ms, Ordering::Relaxed);
+
+    let cpuid = CpuId::new();
+    let has_deadline = cpuid
+        .get_feature_info()
+        .map_or(false, |f| f.has_tsc_deadline());
+    let invariant = cpuid
+        .get_advanced_power_mgmt_info()
+        .map_or(false, |apm| apm.has_invariant_tsc());
+    let selected = if has_deadline && invariant {
+        TSC_DEADLINE
+    } else {
+        APIC_ONE_SHOT
+    };
+    BACKEND.store(selected, Ordering::Relaxed);
+
+    // Don't change this line without changing
+    // `s01_timer_apic_one_shot` in integration-tests.rs:
+    info!(
+        "Timer: {} (calibrated with the {}: {} TSC cycles/us, {} APIC ticks/ms)",
+        backend().name(),
+        clock.name(),
+        cycles_per_us,
+        apic_ticks_per_ms
+    );
+
+    if !invariant {
+        match hpet {
+            Some(hpet) => {
+                HPET_CLOCK.call_once(|| HpetClock::new(hpet, cycles_per_us));
+                info!("Clock: HPET (the TSC isn't invariant)");
+            }
+            None => warn!("TSC is not invariant and there is no HPET, time may drift"),
+        }
+    }
+}
+
 /// Register a periodic timer to advance replica
 ///
 /// TODO(api): Ideally this should come from Instant::now() +
 /// Duration::from_millis(10) and for that we need a way to reliably
 /// convert between TSC and Instant
+///
+/// Fires earlier if a timer on the core's timer wheel expires before.
 pub fn set(deadline: u64) {
-    let kcb = get_kcb();
-    let mut apic = kcb.arch.apic();
-    apic.tsc_enable();
-    unsafe { apic.tsc_set(x86::time::rdtsc() + deadline) };
+    let deadline = crate::timer_wheel::next_deadline().map_or(deadline, |d| d.min(deadline));
+    backend().arm(now() + deadline);
+}
+
+/// Makes sure the timer irq fires at the latest when [`now`] reaches `tsc`
+/// (an absolute time, unlike [`set`]).
+///
+/// Leaves the timer alone if it's already armed for an earlier time.
+pub fn set_before(tsc: u64) {
+    let backend = backend();
+    if backend.armed().map_or(true, |armed| tsc < armed) {
+        backend.arm(tsc);
+    }
+}
+
+/// Stops the timer of the current core.
+pub fn disable() {
+    backend().disarm();
 }
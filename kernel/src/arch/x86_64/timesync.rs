@@ -0,0 +1,161 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Synchronizes the wall clock (see `crate::clock`) with an SNTP server
+//! (`ntp=<ip>[:<port>]` on the command line), so timestamps in the logs of
+//! different machines can be compared.
+//!
+//! Once the network interface is up ([`super::network::Network`]) every poll
+//! picks up the reply of the server and sends a new request every
+//! [`INTERVAL`] (every [`RETRY`] until the server answers). The offset of a
+//! reply is only known to within half its round trip, so we ignore replies
+//! that took longer than [`MAX_DELAY`].
+//!
+//! A server on the command line brings up the interface at boot (see
+//! [`super::network::start`]), which is polled from then on. In between two
+//! answers the clock drifts with the TSC.
+
+use alloc::vec;
+use core::time::Duration;
+
+use log::{debug, info, warn};
+use smoltcp::socket::{SocketHandle, SocketSet, UdpPacketMetadata, UdpSocket, UdpSocketBuffer};
+use smoltcp::wire::{IpAddress, IpEndpoint};
+
+use crate::clock;
+use crate::sntp::{self, PACKET_SIZE};
+
+/// How often we ask the server once we got an answer.
+pub const INTERVAL: Duration = Duration::from_secs(64);
+
+/// How often we ask the server until it answers.
+pub const RETRY: Duration = Duration::from_secs(2);
+
+/// Round trip (in ns) of the slowest reply we take.
+pub const MAX_DELAY: i64 = 100_000_000;
+
+/// UDP port we send from.
+pub const LOCAL_PORT: u16 = 6123;
+
+/// The server (address and port).
+static SERVER: spin::Once<([u8; 4], u16)> = spin::Once::new();
+
+/// Parses `<a.b.c.d>[:<port>]`.
+fn parse_server(server: &str) -> Option<([u8; 4], u16)> {
+    let (ip, port) = match server.split_once(':') {
+        Some((ip, port)) => (ip, port.parse().ok()?),
+        None => (server, sntp::PORT),
+    };
+    let mut octets = [0u8; 4];
+    let mut parts = ip.split('.');
+    for octet in octets.iter_mut() {
+        *octet = parts.next()?.parse().ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((octets, port))
+}
+
+/// Synchronizes the clock with `server` once the network is up (nothing
+/// if `server` is empty).
+///
+/// Returns true if there is a (valid) server.
+pub fn init(server: &str) -> bool {
+    if server.is_empty() {
+        return false;
+    }
+    match parse_server(server) {
+        Some(server) => {
+            SERVER.call_once(|| server);
+            true
+        }
+        None => {
+            warn!("Invalid ntp={} ignored", server);
+            false
+        }
+    }
+}
+
+/// The socket we talk to the server on.
+pub struct TimeSync {
+    handle: SocketHandle,
+    /// Transmit timestamp of the request we wait for.
+    pending: Option<u64>,
+    /// When (since boot) we send the next request.
+    next: Duration,
+    /// Did we get an answer yet?
+    synchronized: bool,
+}
+
+/// Opens the socket we ask the server on, `None` if there is no server.
+pub fn attach(sockets: &mut SocketSet<'static>) -> Option<TimeSync> {
+    SERVER.get()?;
+    let rx_buffer =
+        UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 4], vec![0; 4 * PACKET_SIZE]);
+    let tx_buffer = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY], vec![0; PACKET_SIZE]);
+    let mut socket = UdpSocket::new(rx_buffer, tx_buffer);
+    socket.bind(LOCAL_PORT).ok()?;
+    Some(TimeSync {
+        handle: sockets.add(socket),
+        pending: None,
+        next: Duration::from_secs(0),
+        synchronized: false,
+    })
+}
+
+impl TimeSync {
+    /// Takes the reply of the server and sends the next request if it's
+    /// time.
+    pub fn poll(&mut self, sockets: &mut SocketSet<'static>) {
+        let ([a, b, c, d], port) = match SERVER.get() {
+            Some(server) => *server,
+            None => return,
+        };
+        let server = IpEndpoint::new(IpAddress::v4(a, b, c, d), port);
+        let mut socket = sockets.get::<UdpSocket>(self.handle);
+        let now = rawtime::duration_since_boot();
+
+        while let Ok((packet, endpoint)) = socket.recv() {
+            let arrival = sntp::timestamp(clock::now());
+            let reply = match sntp::parse(packet) {
+                Some(reply) if endpoint == server && Some(reply.originate) == self.pending => reply,
+                _ => {
+                    debug!("Ignored SNTP packet from {}", endpoint);
+                    continue;
+                }
+            };
+            self.pending = None;
+
+            let (offset, delay) = (sntp::offset(&reply, arrival), sntp::delay(&reply, arrival));
+            if delay > MAX_DELAY {
+                debug!("SNTP reply took {} us, ignored", delay / 1000);
+                continue;
+            }
+            clock::adjust(offset);
+            self.next = now + INTERVAL;
+            if self.synchronized {
+                debug!("Wall clock adjusted by {} us", offset / 1000);
+            } else {
+                let time = clock::now();
+                info!(
+                    "Wall clock synchronized with {}: offset {} us, round trip {} us, Unix time {}.{:03}",
+                    server,
+                    offset / 1000,
+                    delay / 1000,
+                    time.as_secs(),
+                    time.subsec_millis()
+                );
+                self.synchronized = true;
+            }
+        }
+
+        if now >= self.next && socket.can_send() {
+            let time = clock::now();
+            if socket.send_slice(&sntp::request(time), server).is_ok() {
+                self.pending = Some(sntp::timestamp(time));
+                self.next = now + RETRY;
+            }
+        }
+    }
+}
@@ -4,9 +4,9 @@
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::ops::Range;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
-use apic::ApicDriver;
+use apic::{ApicDriver, LocalApic};
 use bit_field::BitField;
 use crossbeam_queue::ArrayQueue;
 use fallible_collections::FallibleVecGlobal;
@@ -18,8 +18,11 @@ use x86::apic::{
 };
 
 use super::memory::BASE_PAGE_SIZE;
+use super::process::Ring3Process;
 use crate::kcb;
 use crate::memory::vspace::TlbFlushHandle;
+use crate::nrproc::NrProcess;
+use crate::process::Pid;
 use crate::{cnrfs, is_page_aligned, nr};
 
 // In the xAPIC mode, the Destination Format Register (DFR) through the MMIO
@@ -58,11 +61,15 @@ lazy_static! {
 pub enum WorkItem {
     Shootdown(Arc<Shootdown>),
     AdvanceReplica(usize),
+    /// Run a function (see [`super::smp`]).
+    Call(Arc<super::smp::RemoteCall>),
 }
 
 #[derive(Debug)]
 pub struct Shootdown {
     vregion: Range<u64>,
+    /// Process whose replica has to be synchronized before flushing.
+    sync: Option<Pid>,
     ack: AtomicBool,
 }
 
@@ -73,6 +80,7 @@ impl Shootdown {
         debug_assert!(is_page_aligned!(vregion.end));
         Shootdown {
             vregion,
+            sync: None,
             ack: AtomicBool::new(false),
         }
     }
@@ -89,6 +97,12 @@ impl Shootdown {
 
     /// Flush the TLB entries.
     fn process(&self) {
+        // The page-table of a replica that didn't apply the unmap yet would
+        // still let this core re-fill the TLB entries we're about to flush:
+        if let Some(pid) = self.sync {
+            NrProcess::<Ring3Process>::synchronize(pid);
+        }
+
         // Safe to acknowledge first as we won't return/interrupt
         // before this function completes:
         self.acknowledge();
@@ -107,25 +121,53 @@ impl Shootdown {
     }
 }
 
+/// Number of times an FS log was full and a lagging replica had to be told to
+/// catch up (before the log could collect its oldest entries). Stalls on the
+/// kernel and process logs aren't counted.
+static LOG_STALLS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of times a core synced its FS replica with a log, when asked to
+/// by a stalled appender ([`advance_replica`]) or while it was idle
+/// ([`eager_advance_fs_replica`]), on all cores together.
+static LOG_ADVANCES: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the (stalls, advances) of the FS logs so far.
+pub fn log_stats() -> (u64, u64) {
+    (
+        LOG_STALLS.load(Ordering::Relaxed),
+        LOG_ADVANCES.load(Ordering::Relaxed),
+    )
+}
+
 pub fn enqueue(gtid: atopology::GlobalThreadId, s: WorkItem) {
     trace!("TLB enqueue shootdown msg {:?}", s);
     let _ignore = IPI_WORKQUEUE[gtid as usize].push(s);
 }
 
+/// Like [`enqueue`] but hands back the work if the queue of `gtid` is full.
+pub fn try_enqueue(gtid: atopology::GlobalThreadId, s: WorkItem) -> Result<(), WorkItem> {
+    trace!("TLB enqueue msg {:?}", s);
+    IPI_WORKQUEUE[gtid as usize].push(s)
+}
+
+/// Handles all the work in the queue of `gtid` (IPIs for several work items
+/// can arrive as one).
 pub fn dequeue(gtid: atopology::GlobalThreadId) {
-    match IPI_WORKQUEUE[gtid as usize].pop() {
-        Some(msg) => match msg {
+    // None: IPI request was handled by eager_advance_fs_replica()
+    while let Some(msg) = IPI_WORKQUEUE[gtid as usize].pop() {
+        match msg {
             WorkItem::Shootdown(s) => {
                 trace!("TLB channel got msg {:?}", s);
                 s.process();
             }
             WorkItem::AdvanceReplica(log_id) => advance_log(log_id),
-        },
-        None => { /*IPI request was handled by eager_advance_fs_replica()*/ }
+            WorkItem::Call(call) => call.run(),
+        }
     }
 }
 
 fn advance_log(log_id: usize) {
+    LOG_ADVANCES.fetch_add(1, Ordering::Relaxed);
     // All metadata operations are done using log 1. So, make sure that the
     // replica has applied all those operation before any other log sync.
     if log_id != 1 {
@@ -141,7 +183,7 @@ fn advance_log(log_id: usize) {
 }
 
 pub fn eager_advance_fs_replica() {
-    let kcb = kcb::get_kcb();
+    let kcb = kcb::per_core();
     let core_id = kcb.arch.id();
 
     match IPI_WORKQUEUE[core_id].pop() {
@@ -152,10 +194,11 @@ pub fn eager_advance_fs_replica() {
                     enqueue(core_id, msg)
                 }
                 WorkItem::AdvanceReplica(log_id) => advance_log(*log_id),
+                WorkItem::Call(call) => call.run(),
             }
         }
         None => {
-            let kcb = super::kcb::get_kcb();
+            let kcb = super::kcb::per_core();
             match kcb.arch.cnr_replica.as_ref() {
                 Some(replica) => {
                     let log_id = replica.1.id();
@@ -170,26 +213,68 @@ pub fn eager_advance_fs_replica() {
     }
 }
 
+/// Builds a fixed IPI with `vector` for the core with `apic_id` (physical
+/// destination), in the format `apic` is using.
+fn fixed_ipi(apic: &LocalApic, vector: u8, apic_id: ApicId) -> Icr {
+    if apic.is_x2apic() {
+        Icr::for_x2apic(
+            vector,
+            apic_id,
+            DestinationShorthand::NoShorthand,
+            DeliveryMode::Fixed,
+            DestinationMode::Physical,
+            DeliveryStatus::Idle,
+            Level::Assert,
+            TriggerMode::Edge,
+        )
+    } else {
+        // xAPIC IDs are 8 bits (there is no x2APIC to address more cores)
+        let apic_id = match apic_id {
+            ApicId::XApic(id) => id,
+            ApicId::X2Apic(id) => id as u8,
+        };
+        Icr::for_xapic(
+            vector,
+            ApicId::XApic(apic_id),
+            DestinationShorthand::NoShorthand,
+            DeliveryMode::Fixed,
+            DestinationMode::Physical,
+            DeliveryStatus::Idle,
+            Level::Assert,
+            TriggerMode::Edge,
+        )
+    }
+}
+
 pub fn send_ipi_to_apic(apic_id: ApicId) {
-    let kcb = super::kcb::get_kcb();
+    let kcb = super::kcb::per_core();
     let mut apic = kcb.arch.apic();
+    let icr = fixed_ipi(&apic, super::irq::MLNR_GC_INIT, apic_id);
 
-    let icr = Icr::for_x2apic(
-        super::irq::MLNR_GC_INIT,
-        apic_id,
-        DestinationShorthand::NoShorthand,
-        DeliveryMode::Fixed,
-        DestinationMode::Physical,
-        DeliveryStatus::Idle,
-        Level::Assert,
-        TriggerMode::Edge,
-    );
+    unsafe { apic.send_ipi(icr) }
+}
+
+/// Sends `vector` to the current core (see `irq::with_interrupts`).
+pub fn send_self_ipi(vector: u8) {
+    let kcb = super::kcb::per_core();
+    let apic_id = atopology::MACHINE_TOPOLOGY.threads[kcb.arch.id()].apic_id();
+    let mut apic = kcb.arch.apic();
+    let icr = fixed_ipi(&apic, vector, apic_id);
+
+    unsafe { apic.send_ipi(icr) }
+}
+
+/// Tells `apic_id` that it has work in its queue.
+pub fn send_work_ipi(apic_id: ApicId) {
+    let kcb = super::kcb::per_core();
+    let mut apic = kcb.arch.apic();
+    let icr = fixed_ipi(&apic, super::irq::TLB_WORK_PENDING, apic_id);
 
     unsafe { apic.send_ipi(icr) }
 }
 
 fn send_ipi_multicast(ldr: u32) {
-    let kcb = super::kcb::get_kcb();
+    let kcb = super::kcb::per_core();
     let mut apic = kcb.arch.apic();
 
     let icr = Icr::for_x2apic(
@@ -210,10 +295,22 @@ fn send_ipi_multicast(ldr: u32) {
 /// Runs the TLB shootdown protocol.
 ///
 /// Takes the `TlbFlushHandle` and figures out what cores it needs to send an IPI to.
-/// It divides IPIs into clusters to avoid overhead of sending IPIs individually.
+/// It divides IPIs into clusters to avoid overhead of sending IPIs individually
+/// (with the x2APIC, in xAPIC mode every core gets its own IPI).
 /// Finally, waits until all cores have acknowledged the IPI before it returns.
 pub fn shootdown(handle: TlbFlushHandle) {
-    let my_gtid = super::kcb::get_kcb().arch.id();
+    shootdown_inner(handle, None)
+}
+
+/// Like [`shootdown`], but also synchronizes the replica of `pid` on the
+/// receiving cores (needed if the unmapped frame is about to be freed).
+pub fn shootdown_synchronized(handle: TlbFlushHandle, pid: Pid) {
+    shootdown_inner(handle, Some(pid))
+}
+
+fn shootdown_inner(handle: TlbFlushHandle, sync: Option<Pid>) {
+    let my_gtid = super::kcb::per_core().arch.id();
+    let x2apic = super::kcb::per_core().arch.apic().is_x2apic();
 
     // We support up to 16 IPI clusters, this will address `16*16 = 256` cores
     // Cluster ID (LDR[31:16]) is the address of the destination cluster
@@ -246,20 +343,30 @@ pub fn shootdown(handle: TlbFlushHandle) {
     for gtid in handle.cores() {
         if gtid != my_gtid {
             let apic_id = atopology::MACHINE_TOPOLOGY.threads[gtid].apic_id();
-            let cluster_addr = apic_id.x2apic_logical_cluster_address();
-            let cluster = apic_id.x2apic_logical_cluster_id();
-
-            trace!(
-                "Send shootdown to gtid:{} in cluster:{} cluster_addr:{}",
-                gtid,
-                cluster,
-                cluster_addr
-            );
-            cluster_destination[cluster as usize].set_bit(cluster_addr as usize, true);
-
-            let shootdown = Arc::try_new(Shootdown::new(range.clone()))
-                .expect("TODO(error-handling): ideally: no possible failure during shootdown");
+            if x2apic {
+                let cluster_addr = apic_id.x2apic_logical_cluster_address();
+                let cluster = apic_id.x2apic_logical_cluster_id();
+
+                trace!(
+                    "Send shootdown to gtid:{} in cluster:{} cluster_addr:{}",
+                    gtid,
+                    cluster,
+                    cluster_addr
+                );
+                cluster_destination[cluster as usize].set_bit(cluster_addr as usize, true);
+            }
+
+            let shootdown = Arc::try_new(Shootdown {
+                sync,
+                ..Shootdown::new(range.clone())
+            })
+            .expect("TODO(error-handling): ideally: no possible failure during shootdown");
             enqueue(gtid, WorkItem::Shootdown(shootdown.clone()));
+            if !x2apic {
+                // We only use logical (cluster) destinations with the x2APIC
+                trace!("Send shootdown to gtid:{}", gtid);
+                send_work_ipi(apic_id);
+            }
 
             debug_assert!(shootdowns.len() < shootdowns.capacity(), "Avoid realloc");
             shootdowns.push(shootdown);
@@ -290,6 +397,7 @@ pub fn shootdown(handle: TlbFlushHandle) {
 
 pub fn advance_replica(gtid: atopology::GlobalThreadId, log_id: usize) {
     trace!("Send AdvanceReplica IPI for {} to {}", log_id, gtid);
+    LOG_STALLS.fetch_add(1, Ordering::Relaxed);
     let apic_id = atopology::MACHINE_TOPOLOGY.threads[gtid as usize].apic_id();
 
     enqueue(gtid, WorkItem::AdvanceReplica(log_id));
@@ -0,0 +1,215 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Synchronization of the time-stamp counters (TSC) of all cores.
+//!
+//! Even with an invariant TSC the counters of different cores (or sockets)
+//! aren't guaranteed to start at the same value, so subtracting timestamps
+//! taken on two cores can give negative durations. After all cores are up the
+//! BSP measures the offset of every other core's TSC to its own: it asks the
+//! core (with a remote call) to answer a number of requests with its TSC and
+//! takes the answer of the fastest round trip (the other core read its TSC
+//! somewhere in between, so the error is at most half the round trip).
+//!
+//! [`now`] returns the TSC of the current core corrected by its offset, these
+//! timestamps can be compared across cores. User-space gets the offsets with
+//! `System::clock_info`. A core that comes up later has to be measured with
+//! [`sync`] before its timestamps are corrected.
+
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use fallible_collections::FallibleVec;
+use kpi::system::{ClockInfo, CoreClock};
+use log::{debug, info, warn};
+use x86::cpuid::CpuId;
+
+use crate::error::KError;
+use crate::sync::Mutex;
+
+use super::smp::{self, Wait};
+use super::MAX_CORES;
+
+/// Number of request/answer rounds per core.
+const ROUNDS: u64 = 64;
+
+/// How long we wait for a core to answer a request.
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The core whose TSC the others are corrected to.
+static REFERENCE: AtomicUsize = AtomicUsize::new(0);
+
+/// How much the TSC of a core is ahead of the reference (in cycles).
+static OFFSETS: [AtomicI64; MAX_CORES] = {
+    const ZERO: AtomicI64 = AtomicI64::new(0);
+    [ZERO; MAX_CORES]
+};
+
+/// How far off the offset of a core may be (half the round trip in cycles,
+/// 0 if the core wasn't measured).
+static UNCERTAINTY: [AtomicU64; MAX_CORES] = {
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; MAX_CORES]
+};
+
+/// Identifies a measurement, cores that answer too late see a different
+/// one and give up.
+static SESSION: AtomicU64 = AtomicU64::new(0);
+/// Round the reference asks for.
+static REQUEST: AtomicU64 = AtomicU64::new(0);
+/// Round the other core answered.
+static ANSWERED: AtomicU64 = AtomicU64::new(0);
+/// TSC of the other core in its last answer.
+static ANSWER: AtomicU64 = AtomicU64::new(0);
+
+/// Only one measurement at a time.
+static SYNC: Mutex<()> = Mutex::new(());
+
+/// TSC of the current core, corrected so it's comparable with the TSC of
+/// every other core.
+#[inline]
+pub fn now() -> u64 {
+    let tsc = unsafe { x86::time::rdtsc() };
+    match super::kcb::try_get_kcb() {
+        Some(kcb) => correct(kcb.arch.id(), tsc),
+        None => tsc,
+    }
+}
+
+/// Corrects the timestamp `tsc` taken on core `gtid`.
+pub fn correct(gtid: usize, tsc: u64) -> u64 {
+    let offset = OFFSETS
+        .get(gtid)
+        .map_or(0, |offset| offset.load(Ordering::Relaxed));
+    (tsc as i64).wrapping_sub(offset) as u64
+}
+
+/// Answers the requests of the reference core (runs on the measured core).
+fn answer(session: u64) {
+    let start = rawtime::Instant::now();
+    let mut round = 1;
+    while round <= ROUNDS {
+        if SESSION.load(Ordering::Acquire) != session || start.elapsed() > TIMEOUT * 2 {
+            return;
+        }
+        if REQUEST.load(Ordering::Acquire) == round {
+            ANSWER.store(unsafe { x86::time::rdtsc() }, Ordering::Relaxed);
+            ANSWERED.store(round, Ordering::Release);
+            round += 1;
+        }
+        spin_loop();
+    }
+}
+
+/// Measures the offset of the TSC of core `gtid` to the TSC of the current
+/// core (which becomes the reference).
+pub fn sync(gtid: usize) -> Result<(), KError> {
+    let me = super::kcb::per_core().arch.id();
+    if gtid >= MAX_CORES || gtid >= atopology::MACHINE_TOPOLOGY.num_threads() {
+        return Err(KError::InvalidGlobalThreadId);
+    }
+    let _guard = SYNC.lock();
+    REFERENCE.store(me, Ordering::Relaxed);
+    if gtid == me {
+        OFFSETS[gtid].store(0, Ordering::Relaxed);
+        UNCERTAINTY[gtid].store(0, Ordering::Relaxed);
+        return Ok(());
+    }
+
+    REQUEST.store(0, Ordering::Relaxed);
+    ANSWERED.store(0, Ordering::Relaxed);
+    let session = SESSION.fetch_add(1, Ordering::AcqRel) + 1;
+    smp::call_on(gtid, move || answer(session), Wait::No)?;
+
+    let mut best: Option<(u64, i64)> = None;
+    for round in 1..=ROUNDS {
+        let start = rawtime::Instant::now();
+        let before = unsafe { x86::time::rdtsc() };
+        REQUEST.store(round, Ordering::Release);
+        while ANSWERED.load(Ordering::Acquire) != round {
+            if start.elapsed() > TIMEOUT {
+                SESSION.fetch_add(1, Ordering::AcqRel);
+                return Err(KError::RemoteCallTimeout { pending: 1 });
+            }
+            spin_loop();
+        }
+        let after = unsafe { x86::time::rdtsc() };
+        let theirs = ANSWER.load(Ordering::Relaxed);
+
+        // They read their TSC somewhere between `before` and `after`
+        let rtt = after - before;
+        let offset = theirs as i64 - (before + rtt / 2) as i64;
+        if best.map_or(true, |(best_rtt, _)| rtt < best_rtt) {
+            best = Some((rtt, offset));
+        }
+    }
+
+    let (rtt, offset) = best.unwrap_or((0, 0));
+    OFFSETS[gtid].store(offset, Ordering::Relaxed);
+    UNCERTAINTY[gtid].store(rtt / 2, Ordering::Relaxed);
+    debug!(
+        "TSC of core {} is {} cycles ahead of core {} (+/- {})",
+        gtid,
+        offset,
+        me,
+        rtt / 2
+    );
+    Ok(())
+}
+
+/// Measures the TSC offsets of all cores to the current core.
+pub fn sync_all() {
+    let cpuid = CpuId::new();
+    let invariant = cpuid
+        .get_advanced_power_mgmt_info()
+        .map_or(false, |apm| apm.has_invariant_tsc());
+    if !invariant {
+        warn!("TSC is not invariant, timestamps of different cores may drift apart");
+    }
+
+    let me = super::kcb::per_core().arch.id();
+    let (mut synced, mut max_offset, mut max_uncertainty) = (0, 0, 0);
+    for thread in atopology::MACHINE_TOPOLOGY.threads() {
+        match sync(thread.id) {
+            Ok(()) if thread.id != me => {
+                synced += 1;
+                max_offset =
+                    core::cmp::max(max_offset, OFFSETS[thread.id].load(Ordering::Relaxed).abs());
+                max_uncertainty = core::cmp::max(
+                    max_uncertainty,
+                    UNCERTAINTY[thread.id].load(Ordering::Relaxed),
+                );
+            }
+            Ok(()) => {}
+            Err(e) => warn!("Can't synchronize TSC of core {}: {}", thread.id, e),
+        }
+    }
+
+    // Don't change this line without changing `s03_coreboot` in
+    // integration-test.rs:
+    info!(
+        "TSC: synchronized {} cores to core {}, max offset {} cycles (+/- {})",
+        synced, me, max_offset, max_uncertainty
+    );
+    if crate::timer_wheel::cycles_to_us(max_uncertainty) > 0 {
+        warn!("TSC offsets are only known to within a microsecond or worse");
+    }
+}
+
+/// The offsets of all cores (for `System::clock_info`).
+pub fn info() -> Result<ClockInfo, KError> {
+    let mut info = ClockInfo {
+        reference: REFERENCE.load(Ordering::Relaxed),
+        cores: Default::default(),
+        wall_offset: crate::clock::offset(),
+    };
+    for thread in atopology::MACHINE_TOPOLOGY.threads() {
+        info.cores.try_push(CoreClock {
+            core: thread.id,
+            offset: OFFSETS[thread.id].load(Ordering::Relaxed),
+            uncertainty: UNCERTAINTY[thread.id].load(Ordering::Relaxed),
+        })?;
+    }
+    Ok(info)
+}
@@ -0,0 +1,58 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Copying data between kernel and user-space memory.
+//!
+//! The buffers are validated before we copy (see `process::validate_user_range`)
+//! but a process can still unmap them concurrently on another core. The
+//! instructions that touch user memory are listed in the exception table
+//! (see `usercopy.S`), a page-fault on them makes the copy fail with
+//! `KError::BadAddress` instead of bringing down the kernel.
+//!
+//! SMAP is enabled on all cores (see `enable_smep_smap`), the copy routine
+//! is where we open a window to user memory (`stac`/`clac`), any other
+//! access from the kernel to a user address faults.
+
+use core::mem::{size_of, MaybeUninit};
+
+use crate::error::KError;
+
+#[cfg(target_os = "none")]
+global_asm!(include_str!("usercopy.S"), options(att_syntax));
+
+extern "C" {
+    /// Returns 0 on success and 1 if we faulted during the copy.
+    fn nrk_copy_user(dst: *mut u8, src: *const u8, len: usize) -> u64;
+}
+
+/// Copies `dst.len()` bytes from user-space address `src` into `dst`.
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), KError> {
+    match unsafe { nrk_copy_user(dst.as_mut_ptr(), src as *const u8, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(KError::BadAddress),
+    }
+}
+
+/// Copies `src` to the user-space address `dst`.
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), KError> {
+    match unsafe { nrk_copy_user(dst as *mut u8, src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(KError::BadAddress),
+    }
+}
+
+/// Reads a `T` from the user-space address `src`.
+pub fn read_user<T: Copy>(src: u64) -> Result<T, KError> {
+    let mut value = MaybeUninit::<T>::uninit();
+    let buf =
+        unsafe { core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    copy_from_user(buf, src)?;
+    Ok(unsafe { value.assume_init() })
+}
+
+/// Writes `value` to the user-space address `dst`.
+pub fn write_user<T: Copy>(dst: u64, value: &T) -> Result<(), KError> {
+    let buf =
+        unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    copy_to_user(dst, buf)
+}
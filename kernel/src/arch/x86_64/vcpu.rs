@@ -0,0 +1,159 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Upcalls for executors in a user-level critical section.
+//!
+//! User-space disables upcalls in its vCPU area while it runs code that
+//! can't be interrupted by its own scheduler (e.g., while manipulating the
+//! run-queue or holding a spin-lock). An upcall that arrives in that time is
+//! noted in the vCPU area ([`defer`]) and delivered once user-space leaves the
+//! critical section and asks for it (`ProcessOperation::DeliverUpcall`).
+//!
+//! The kernel never delivers an upcall while upcalls are disabled, the
+//! vCPU area (`enabled_state`) may still hold state user-space needs. A
+//! process that holds off an upcall for longer than [`MAX_DEFERRAL`] gets a
+//! warning and it's counted in the vCPU area
+//! (`VirtualCpu::overdue_upcalls`); the timer keeps checking
+//! ([`deliver_overdue`]) and delivers the upcall as soon as the executor
+//! enabled upcalls again.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use log::{trace, warn};
+use x86::bits64::paging::VAddr;
+
+use crate::arch::MAX_CORES;
+use crate::kcb::Kcb;
+use crate::process::{Executor, ResumeHandle};
+
+use super::kcb::Arch86Kcb;
+use super::timer;
+
+/// How long (in TSC cycles) a core may defer an upcall before the kernel
+/// counts it as overdue.
+pub const MAX_DEFERRAL: u64 = 2_000_000;
+
+/// When (`timer::now`) the upcall pending on a core was deferred (0 if
+/// there is none).
+static DEFERRED_SINCE: [AtomicU64; MAX_CORES] = {
+    const NONE: AtomicU64 = AtomicU64::new(0);
+    [NONE; MAX_CORES]
+};
+
+/// Was the upcall pending on a core already counted as overdue?
+static OVERDUE: [AtomicBool; MAX_CORES] = {
+    const NO: AtomicBool = AtomicBool::new(false);
+    [NO; MAX_CORES]
+};
+
+/// Notes the upcall `vector` for the current executor which is in a
+/// critical section and makes sure the timer checks on it within
+/// [`MAX_DEFERRAL`] (an earlier deadline of the timer stays).
+pub fn defer(kcb: &Kcb<Arch86Kcb>, vector: u64, exception: u64) {
+    if let Ok(executor) = kcb.arch.current_executor() {
+        trace!("defer upcall {:#x} on core {}", vector, kcb.arch.id());
+        executor.vcpu().defer_upcall(vector, exception);
+
+        let since = &DEFERRED_SINCE[kcb.arch.id()];
+        if since.load(Ordering::Relaxed) == 0 {
+            let now = timer::now();
+            since.store(now, Ordering::Relaxed);
+            timer::set_before(now + MAX_DEFERRAL);
+        }
+    }
+}
+
+/// Delivers the upcall that was deferred on the current core to the
+/// executor which asked for it with a system call
+/// (`ProcessOperation::DeliverUpcall`).
+///
+/// Returns if there is no such upcall.
+pub fn deliver_from_syscall(kcb: &Kcb<Arch86Kcb>) {
+    upcall(kcb, true)
+}
+
+/// Delivers the upcall that was deferred on the current core to the
+/// executor, which was interrupted.
+///
+/// Returns if there is no such upcall.
+fn deliver(kcb: &Kcb<Arch86Kcb>) {
+    upcall(kcb, false)
+}
+
+/// The state to return to once the upcall is handled is the one in the save
+/// area of the core (which is the state of the caller in case of a system
+/// call, the system call returns successfully then).
+fn upcall(kcb: &Kcb<Arch86Kcb>, syscall: bool) {
+    DEFERRED_SINCE[kcb.arch.id()].store(0, Ordering::Relaxed);
+    OVERDUE[kcb.arch.id()].store(false, Ordering::Relaxed);
+
+    if let Ok(executor) = kcb.arch.current_executor() {
+        let vcpu = executor.vcpu();
+        if let Some((vector, exception)) = vcpu.take_pending_upcall() {
+            vcpu.disable_upcalls();
+            kcb.arch.save_area.as_ref().map(|sa| {
+                let mut state = **sa;
+                if syscall {
+                    state.set_syscall_error_code(kpi::SystemCallError::Ok);
+                }
+                vcpu.set_enabled_state(&state);
+            });
+
+            unsafe { executor.upcall(vector, exception).resume() }
+        }
+    }
+}
+
+/// Called on a timer interrupt (from user-space at `rip`): delivers the
+/// deferred upcall if the executor left its critical section without
+/// asking for it, counts it as overdue if it's still disabled after
+/// [`MAX_DEFERRAL`].
+///
+/// Returns if there is nothing to deliver (yet), with the deadline for
+/// the timer to check again.
+pub fn deliver_overdue(kcb: &Kcb<Arch86Kcb>, rip: VAddr) -> Option<u64> {
+    let since = DEFERRED_SINCE[kcb.arch.id()].load(Ordering::Relaxed);
+    if since == 0 {
+        return None;
+    }
+
+    let (in_resume, disabled) = match kcb.arch.current_executor() {
+        Ok(executor) => {
+            let vcpu = executor.vcpu();
+            let (start, end) = vcpu.critical_region();
+            (start <= rip && rip <= end, vcpu.is_disabled())
+        }
+        Err(_) => {
+            DEFERRED_SINCE[kcb.arch.id()].store(0, Ordering::Relaxed);
+            OVERDUE[kcb.arch.id()].store(false, Ordering::Relaxed);
+            return None;
+        }
+    };
+
+    if in_resume {
+        // The user-space scheduler is restoring the state we'd save, it's
+        // done in a few instructions so try again a bit later:
+        return Some(MAX_DEFERRAL / 16);
+    }
+
+    if !disabled {
+        deliver(kcb);
+        // The user-space scheduler cleared the upcall itself
+        return None;
+    }
+
+    let waited = timer::now() - since;
+    if waited < MAX_DEFERRAL {
+        return Some(MAX_DEFERRAL - waited);
+    }
+    if !OVERDUE[kcb.arch.id()].swap(true, Ordering::Relaxed) {
+        let executor = kcb.arch.current_executor().unwrap();
+        warn!(
+            "Executor {} stayed in a critical section for too long, upcall is overdue",
+            executor.eid
+        );
+        executor.vcpu().count_overdue_upcall();
+    }
+    // Keep checking until it enables upcalls again
+    Some(MAX_DEFERRAL)
+}
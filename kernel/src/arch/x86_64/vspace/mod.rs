@@ -78,6 +78,17 @@ impl AddressSpace for VSpace {
         self.page_table.resolve(addr)
     }
 
+    fn next_mapping(&self, vaddr: VAddr) -> Option<(VAddr, Frame, MapAction)> {
+        self.mappings
+            .range((Included(vaddr), Unbounded))
+            .next()
+            .map(|(base, mapping)| (*base, mapping.frame, mapping.rights))
+    }
+
+    fn next_page(&self, vaddr: VAddr) -> Option<(VAddr, PAddr, usize, MapAction)> {
+        self.page_table.next_page(vaddr)
+    }
+
     fn unmap(&mut self, base: VAddr) -> Result<TlbFlushHandle, KError> {
         for (&existing_base, existing_mapping) in
             self.mappings.range((Unbounded, Included(base))).rev()
@@ -118,6 +129,13 @@ impl VSpace {
         })
     }
 
+    /// Removes all user-space mappings (without freeing the frames they
+    /// map).
+    pub(crate) fn clear(&mut self) {
+        self.page_table.free_user_tables();
+        self.mappings = BTreeMap::new();
+    }
+
     pub fn map_identity(
         &mut self,
         base: PAddr,
@@ -39,6 +39,14 @@ pub struct PageTable {
 
 impl Drop for PageTable {
     fn drop(&mut self) {
+        self.free_user_tables();
+    }
+}
+
+impl PageTable {
+    /// Unmaps everything below `KERNEL_BASE` and frees the page-tables that
+    /// mapped it (the PML4 stays).
+    pub(crate) fn free_user_tables(&mut self) {
         use alloc::alloc::dealloc;
 
         // Do a DFS and free all page-table memory allocated below kernel-base,
@@ -159,6 +167,63 @@ impl AddressSpace for PageTable {
         Err(KError::NotMapped)
     }
 
+    fn next_page(&self, vaddr: VAddr) -> Option<(VAddr, PAddr, usize, MapAction)> {
+        let start = vaddr.as_usize();
+        let before =
+            |base: usize, size: usize| base.checked_add(size).map_or(false, |end| end <= start);
+
+        for pml4_idx in pml4_index(vaddr)..PAGE_SIZE_ENTRIES {
+            if !self.pml4[pml4_idx].is_present() {
+                continue;
+            }
+            // Upper half of the address space is sign-extended
+            let pml4_base = if pml4_idx < PAGE_SIZE_ENTRIES / 2 {
+                pml4_idx * PML4_SLOT_SIZE
+            } else {
+                0xffff_0000_0000_0000 | (pml4_idx * PML4_SLOT_SIZE)
+            };
+
+            let pdpt = self.get_pdpt(self.pml4[pml4_idx]);
+            for pdpt_idx in 0..PAGE_SIZE_ENTRIES {
+                let pdpt_base = pml4_base + pdpt_idx * HUGE_PAGE_SIZE;
+                if before(pdpt_base, HUGE_PAGE_SIZE) || !pdpt[pdpt_idx].is_present() {
+                    continue;
+                }
+                if pdpt[pdpt_idx].is_page() {
+                    let rights: MapAction = pdpt[pdpt_idx].flags().into();
+                    let paddr = pdpt[pdpt_idx].address();
+                    return Some((VAddr::from(pdpt_base), paddr, HUGE_PAGE_SIZE, rights));
+                }
+
+                let pd = self.get_pd(pdpt[pdpt_idx]);
+                for pd_idx in 0..PAGE_SIZE_ENTRIES {
+                    let pd_base = pdpt_base + pd_idx * LARGE_PAGE_SIZE;
+                    if before(pd_base, LARGE_PAGE_SIZE) || !pd[pd_idx].is_present() {
+                        continue;
+                    }
+                    if pd[pd_idx].is_page() {
+                        let rights: MapAction = pd[pd_idx].flags().into();
+                        let paddr = pd[pd_idx].address();
+                        return Some((VAddr::from(pd_base), paddr, LARGE_PAGE_SIZE, rights));
+                    }
+
+                    let pt = self.get_pt(pd[pd_idx]);
+                    for pt_idx in 0..PAGE_SIZE_ENTRIES {
+                        let pt_base = pd_base + pt_idx * BASE_PAGE_SIZE;
+                        if before(pt_base, BASE_PAGE_SIZE) || !pt[pt_idx].is_present() {
+                            continue;
+                        }
+                        let rights: MapAction = pt[pt_idx].flags().into();
+                        let paddr = pt[pt_idx].address();
+                        return Some((VAddr::from(pt_base), paddr, BASE_PAGE_SIZE, rights));
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
     fn unmap(&mut self, base: VAddr) -> Result<TlbFlushHandle, KError> {
         if !base.is_base_page_aligned() {
             return Err(KError::InvalidBase);
@@ -192,6 +257,12 @@ impl PageTable {
     /// Constructs an identity map but with an offset added to the region.
     ///
     /// This can be useful for example to map physical memory above `KERNEL_BASE`.
+    ///
+    /// Mapping (part of) a region again is fine: pages that already map the
+    /// same physical memory with the same `rights` are left alone and only
+    /// the rest of the region is mapped. Fails (without changing anything)
+    /// with `AlreadyMapped` if part of the region maps other physical memory
+    /// and with `ConflictingRights` if it maps it with different rights.
     pub(crate) fn map_identity_with_offset(
         &mut self,
         at_offset: PAddr,
@@ -212,7 +283,54 @@ impl PageTable {
             pbase + size
         );
 
-        self.map_generic(vbase, (pbase, size), rights, true)
+        // Check the existing mappings of the whole region first ...
+        let vend = vbase + size;
+        let mut cursor = vbase;
+        while let Some((gap, len)) = self.next_identity_gap(cursor, vend, at_offset, rights)? {
+            cursor = gap + len;
+        }
+        // ... then fill in what isn't mapped yet
+        let mut cursor = vbase;
+        while let Some((gap, len)) = self.next_identity_gap(cursor, vend, at_offset, rights)? {
+            let pgap = PAddr::from(gap.as_u64() - at_offset.as_u64());
+            self.map_generic(gap, (pgap, len), rights, true)?;
+            cursor = gap + len;
+        }
+
+        Ok(())
+    }
+
+    /// Finds the first unmapped range in `start` -- `end`.
+    ///
+    /// The mappings we skip over have to be identity mappings (shifted by
+    /// `at_offset`) with `rights`.
+    fn next_identity_gap(
+        &self,
+        start: VAddr,
+        end: VAddr,
+        at_offset: PAddr,
+        rights: MapAction,
+    ) -> Result<Option<(VAddr, usize)>, KError> {
+        let mut cursor = start;
+        while cursor < end {
+            match self.next_page(cursor) {
+                Some((vaddr, paddr, size, cur_rights)) if vaddr < end => {
+                    if vaddr > cursor {
+                        return Ok(Some((cursor, (vaddr - cursor).as_usize())));
+                    }
+                    if paddr.as_u64() != vaddr.as_u64().wrapping_sub(at_offset.as_u64()) {
+                        return Err(KError::AlreadyMapped { base: cursor });
+                    }
+                    if cur_rights != rights {
+                        return Err(KError::ConflictingRights { base: cursor });
+                    }
+                    cursor = vaddr + size;
+                }
+                _ => return Ok(Some((cursor, (end - cursor).as_usize()))),
+            }
+        }
+
+        Ok(None)
     }
 
     /// Identity maps a given physical memory range [`base`, `base` + `size`]
@@ -129,3 +129,104 @@ proptest! {
         }
     }
 }
+
+/// Walking the page-tables finds the pages in order and with their size.
+#[test]
+fn next_page() {
+    use crate::memory::detmem::DA;
+
+    let mut vspace =
+        VSpace::new(DA::new().expect("Unable to create DA")).expect("Unable to create vspace");
+    KernelAllocator::try_refill_tcache(14, 14).expect("Can't refill TCache");
+
+    let base = VAddr::from(0x40_0000u64);
+    let large = Frame::new(PAddr::from(0x20_0000u64), LARGE_PAGE_SIZE, 0);
+    vspace
+        .map_frame(base, large, MapAction::ReadWriteUser)
+        .expect("Can't map large page");
+    let small = Frame::new(PAddr::from(0x1000u64), BASE_PAGE_SIZE, 0);
+    vspace
+        .map_frame(base + 2 * LARGE_PAGE_SIZE, small, MapAction::ReadUser)
+        .expect("Can't map base page");
+
+    assert_eq!(
+        vspace.next_page(VAddr::zero()),
+        Some((base, large.base, LARGE_PAGE_SIZE, MapAction::ReadWriteUser))
+    );
+    assert_eq!(
+        vspace.next_page(base + 0x1000),
+        Some((base, large.base, LARGE_PAGE_SIZE, MapAction::ReadWriteUser))
+    );
+    assert_eq!(
+        vspace.next_page(base + LARGE_PAGE_SIZE),
+        Some((
+            base + 2 * LARGE_PAGE_SIZE,
+            small.base,
+            BASE_PAGE_SIZE,
+            MapAction::ReadUser
+        ))
+    );
+    assert_eq!(
+        vspace.next_page(base + 2 * LARGE_PAGE_SIZE + BASE_PAGE_SIZE),
+        None
+    );
+}
+
+/// Identity mapping a region again succeeds, mapping it with other rights or
+/// over a different mapping fails.
+#[test]
+fn map_identity_twice() {
+    use crate::memory::detmem::DA;
+
+    let mut vspace =
+        VSpace::new(DA::new().expect("Unable to create DA")).expect("Unable to create vspace");
+    KernelAllocator::try_refill_tcache(14, 14).expect("Can't refill TCache");
+
+    let base = PAddr::from(0x40_0000u64);
+    vspace
+        .map_identity(base, 0x4000, MapAction::ReadWriteKernel)
+        .expect("Can't map region");
+    assert!(vspace
+        .map_identity(base, 0x4000, MapAction::ReadWriteKernel)
+        .is_ok());
+
+    // Overlaps the region, the rest of it gets mapped
+    vspace
+        .map_identity(
+            base + 0x2000usize,
+            LARGE_PAGE_SIZE,
+            MapAction::ReadWriteKernel,
+        )
+        .expect("Can't extend region");
+    assert_eq!(
+        vspace.resolve(VAddr::from(0x60_1000u64)),
+        Ok((PAddr::from(0x60_1000u64), MapAction::ReadWriteKernel))
+    );
+
+    assert_eq!(
+        vspace.map_identity(base + 0x1000usize, 0x1000, MapAction::ReadKernel),
+        Err(KError::ConflictingRights {
+            base: VAddr::from(0x40_1000u64)
+        })
+    );
+
+    let frame = Frame::new(PAddr::from(0x1000u64), BASE_PAGE_SIZE, 0);
+    vspace
+        .map_frame(VAddr::from(0x80_0000u64), frame, MapAction::ReadWriteKernel)
+        .expect("Can't map frame");
+    assert_eq!(
+        vspace.map_identity(
+            PAddr::from(0x7f_f000u64),
+            0x2000,
+            MapAction::ReadWriteKernel
+        ),
+        Err(KError::AlreadyMapped {
+            base: VAddr::from(0x80_0000u64)
+        })
+    );
+    // Nothing was mapped
+    assert_eq!(
+        vspace.resolve(VAddr::from(0x7f_f000u64)),
+        Err(KError::NotMapped)
+    );
+}
@@ -0,0 +1,241 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Write-protection of the kernel image (W^X).
+//!
+//! The bootloader maps every segment of the kernel ELF with the rights of its
+//! program header: text is read-only and executable, rodata is read-only and
+//! not executable. It leaves the RELRO region (GOT, vtables etc.) writable,
+//! the relocations have to be written first.
+//!
+//! Once all cores are up, [`protect`] makes the RELRO region read-only,
+//! applies the rights of the program headers again (so we don't depend on
+//! what the bootloader did) and takes away the rights of the bootstrap code
+//! for app cores (`start_ap.S` in low memory, which has to be writable and
+//! executable while cores boot). Then it walks the page-tables to check that
+//! no page of the kernel image is writable and executable and that every page
+//! has the rights we expect. The rights bind the kernel itself only with
+//! CR0.WP, which every core sets when it starts (`enable_write_protect`).
+
+use core::ops::Range;
+
+use arrayvec::ArrayVec;
+use log::{info, warn};
+
+use crate::memory::vspace::{AddressSpace, MapAction};
+use crate::memory::VAddr;
+use crate::round_up;
+
+use super::coreboot;
+use super::kcb;
+use super::memory::BASE_PAGE_SIZE;
+
+/// How many loadable segments we expect in the kernel ELF.
+const MAX_SEGMENTS: usize = 8;
+
+/// The (page-aligned) loadable segments of the kernel ELF and its RELRO
+/// region, relative to where the kernel is loaded.
+///
+/// Also used to lay out a new kernel before we jump into it (see `kexec`).
+#[derive(Default)]
+pub(crate) struct Segments {
+    pub(crate) load: ArrayVec<(Range<u64>, MapAction), MAX_SEGMENTS>,
+    relro: Option<Range<u64>>,
+}
+
+impl Segments {
+    /// The rights a page of the kernel image should have.
+    fn rights(&self, vaddr: u64) -> Option<MapAction> {
+        if self.relro.as_ref().map_or(false, |r| r.contains(&vaddr)) {
+            return Some(MapAction::ReadKernel);
+        }
+        self.load
+            .iter()
+            .find(|(range, _)| range.contains(&vaddr))
+            .map(|(_, rights)| *rights)
+    }
+}
+
+impl elfloader::ElfLoader for Segments {
+    fn allocate(
+        &mut self,
+        load_headers: elfloader::LoadableHeaders,
+    ) -> Result<(), elfloader::ElfLoaderErr> {
+        for header in load_headers {
+            let base = header.virtual_addr();
+            let page_base = base & !(BASE_PAGE_SIZE as u64 - 1);
+            let end = round_up!(base + header.mem_size(), BASE_PAGE_SIZE as u64);
+
+            let flags = header.flags();
+            let rights = match (flags.is_execute(), flags.is_write()) {
+                (false, false) => MapAction::ReadKernel,
+                (true, false) => MapAction::ReadExecuteKernel,
+                (false, true) => MapAction::ReadWriteKernel,
+                (true, true) => MapAction::ReadWriteExecuteKernel,
+            };
+            self.load
+                .try_push((page_base..end, rights))
+                .map_err(|_| elfloader::ElfLoaderErr::OutOfMemory)?;
+        }
+        Ok(())
+    }
+
+    fn load(
+        &mut self,
+        _flags: elfloader::Flags,
+        _base: u64,
+        _region: &[u8],
+    ) -> Result<(), elfloader::ElfLoaderErr> {
+        Ok(())
+    }
+
+    fn relocate(
+        &mut self,
+        _entry: &elfloader::Rela<elfloader::P64>,
+    ) -> Result<(), elfloader::ElfLoaderErr> {
+        Ok(())
+    }
+
+    fn make_readonly(&mut self, base: u64, size: usize) -> Result<(), elfloader::ElfLoaderErr> {
+        // The region ends on a page-boundary. If it doesn't start on one we
+        // can include the first page only if nothing of its segment is in
+        // front of it.
+        let page_base = base & !(BASE_PAGE_SIZE as u64 - 1);
+        let starts_segment = self
+            .load
+            .iter()
+            .any(|(range, _)| range.start == page_base && range.end > base);
+        let start = if starts_segment {
+            page_base
+        } else {
+            round_up!(base, BASE_PAGE_SIZE as u64)
+        };
+        self.relro = Some(start..base + size as u64);
+        Ok(())
+    }
+}
+
+/// Write-protects the kernel image and the bootstrap code, then checks the
+/// page-tables.
+///
+/// Needs all cores up (they share the page-tables and have to flush their
+/// TLBs).
+pub fn protect() {
+    let kcb = kcb::per_core();
+    let offset = kcb.arch.kernel_args().kernel_elf_offset.as_u64();
+
+    let mut segments: Segments = Default::default();
+    let binary = elfloader::ElfBinary::new(kcb.kernel_binary()).expect("Can't parse kernel binary");
+    binary
+        .load(&mut segments)
+        .expect("Can't read kernel program headers");
+
+    {
+        let mut vspace = kcb.arch.init_vspace();
+        for (range, rights) in segments.load.iter() {
+            let mut vaddr = offset + range.start;
+            while vaddr < offset + range.end {
+                let (base, size) = vspace
+                    .adjust(VAddr::from(vaddr), *rights)
+                    .expect("Can't adjust rights of kernel segment");
+                vaddr = base.as_u64() + size as u64;
+            }
+        }
+
+        if let Some(relro) = segments.relro.clone() {
+            let mut vaddr = offset + relro.start;
+            while vaddr < offset + relro.end {
+                match vspace.next_page(VAddr::from(vaddr)) {
+                    Some((base, _paddr, size, _rights))
+                        if base.as_u64() == vaddr && vaddr + size as u64 <= offset + relro.end =>
+                    {
+                        vspace
+                            .adjust(base, MapAction::ReadKernel)
+                            .expect("Can't make RELRO read-only");
+                        vaddr += size as u64;
+                    }
+                    _ => {
+                        // A large page that also maps writable data
+                        warn!("Can't make RELRO page {:#x} read-only", vaddr);
+                        vaddr += BASE_PAGE_SIZE as u64;
+                    }
+                }
+            }
+        }
+    }
+    coreboot::lock_bootstrap_code();
+
+    // The other cores may still have the old rights in their TLBs
+    unsafe { x86::tlb::flush_all() };
+    #[cfg(not(feature = "bsp-only"))]
+    super::smp::call_on_all(
+        || unsafe { x86::tlb::flush_all() },
+        super::smp::Wait::Forever,
+    )
+    .expect("Can't flush TLBs after write-protecting the kernel");
+
+    let pages = check(&segments, offset);
+    info!(
+        "Kernel image write-protected: {} pages checked, none writable and executable",
+        pages
+    );
+}
+
+/// Walks the page-tables of the kernel image and the bootstrap code and
+/// panics if a page has the wrong rights. Returns how many pages it checked.
+fn check(segments: &Segments, offset: u64) -> usize {
+    // The rights only bind the kernel with CR0.WP (`enable_write_protect`)
+    let cr0 = unsafe { x86::controlregs::cr0() };
+    assert!(
+        cr0.contains(x86::controlregs::Cr0::CR0_WRITE_PROTECT),
+        "CR0.WP is not set"
+    );
+
+    let kcb = kcb::per_core();
+    let vspace = kcb.arch.init_vspace();
+    let mut pages = 0;
+    let mut wrong = 0;
+
+    for (range, _rights) in segments.load.iter() {
+        for vaddr in range.clone().step_by(BASE_PAGE_SIZE) {
+            let expected = segments.rights(vaddr).expect("Page not in a segment");
+            let rights = match vspace.resolve(VAddr::from(offset + vaddr)) {
+                Ok((_paddr, rights)) => rights,
+                Err(_) => MapAction::None,
+            };
+            // RELRO pages in a large page stay writable (`protect` warned)
+            let in_relro = segments
+                .relro
+                .as_ref()
+                .map_or(false, |r| r.contains(&vaddr));
+            let relro_in_large_page = in_relro && rights == MapAction::ReadWriteKernel;
+            if (rights.is_writable() && rights.is_executable())
+                || (rights != expected && !relro_in_large_page)
+            {
+                warn!(
+                    "Kernel page {:#x} (in ELF: {:#x}) is mapped {}, expected {}",
+                    offset + vaddr,
+                    vaddr,
+                    rights,
+                    expected
+                );
+                wrong += 1;
+            }
+            pages += 1;
+        }
+    }
+
+    let (base, size) = coreboot::bootstrap_region();
+    for vaddr in (base.as_u64()..base.as_u64() + size as u64).step_by(BASE_PAGE_SIZE) {
+        if let Ok((_paddr, rights)) = vspace.resolve(VAddr::from(vaddr)) {
+            if rights.is_writable() || rights.is_executable() {
+                warn!("Bootstrap code page {:#x} is mapped {}", vaddr, rights);
+                wrong += 1;
+            }
+            pages += 1;
+        }
+    }
+
+    assert_eq!(wrong, 0, "Kernel image isn't write-protected");
+    pages
+}
@@ -0,0 +1,319 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Which processes the kernel starts at boot.
+//!
+//! Without a configuration the kernel starts a single process: `init=` from
+//! the command-line (with `initargs`, `initroot` and `initcaps`) on the boot
+//! core. An `init.toml` (a module on the ESP, or `/etc/init.toml` in the
+//! `rootfs` archive) declares the processes instead, they're started in
+//! order:
+//!
+//! ```toml
+//! [[process]]
+//! binary = "init"            # module to run (required)
+//! args = "testcmd=1"         # what `Process::process_info` returns
+//! root = "/scratch"          # file-system root of the process (`/`)
+//! caps = ["measure"]         # capabilities (none)
+//! cores = [0]                # starts on the first free one (any core)
+//!
+//! [[process]]
+//! binary = "redis.bin"
+//! cores = [1, 2]
+//! restart = "on-failure"     # never (default), on-failure or always
+//! ```
+//!
+//! Processes that are restarted are services, see `services`.
+//!
+//! We only understand this subset of TOML: `[[process]]` tables with string,
+//! integer and (single-line) array values and `#` comments. Strings can't
+//! contain escapes or quotes.
+
+// Only the x86-64 kernel starts processes from a configuration
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use arrayvec::ArrayVec;
+use kpi::process::Capabilities;
+
+use crate::error::KError;
+use crate::kcb::BootloaderArguments;
+use crate::process::MAX_PROCESSES;
+use crate::services::RestartPolicy;
+
+/// Name of the module with the configuration.
+pub const CONFIG_MODULE: &str = "init.toml";
+
+/// Path of the configuration in the `rootfs` archive.
+pub const CONFIG_PATH: &str = "etc/init.toml";
+
+/// How many cores a process can list in `cores`.
+pub const MAX_CORES_PER_PROGRAM: usize = 16;
+
+/// A process to start at boot.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Program<'a> {
+    /// Name of the module with the binary.
+    pub binary: &'a str,
+    pub args: &'a str,
+    /// The process is confined to this directory of the file-system.
+    pub root: &'a str,
+    pub capabilities: Capabilities,
+    /// It starts on the first of these cores that is free (any free core if
+    /// empty).
+    pub cores: ArrayVec<usize, MAX_CORES_PER_PROGRAM>,
+    /// Whether the kernel starts it again when it exits.
+    pub restart: RestartPolicy,
+}
+
+impl<'a> Program<'a> {
+    /// Runs `binary` with the defaults of `init.toml`.
+    pub fn new(binary: &'a str) -> Program<'a> {
+        Program {
+            binary,
+            args: "",
+            root: "/",
+            capabilities: Capabilities::NONE,
+            cores: ArrayVec::new(),
+            restart: RestartPolicy::Never,
+        }
+    }
+
+    /// The init process of the command-line, on `core`.
+    pub fn init(cmdline: &BootloaderArguments, core: usize) -> Program<'static> {
+        let mut cores = ArrayVec::new();
+        cores.push(core);
+        Program {
+            binary: cmdline.init_binary,
+            args: cmdline.init_args,
+            root: cmdline.init_root,
+            capabilities: Capabilities::from_names(cmdline.init_caps),
+            cores,
+            restart: RestartPolicy::Never,
+        }
+    }
+}
+
+/// The processes to start: the ones of `config` (if there is a valid one)
+/// or the init process of the command-line (on `core`).
+pub fn programs(
+    config: Option<&'static [u8]>,
+    cmdline: &BootloaderArguments,
+    core: usize,
+) -> ArrayVec<Program<'static>, MAX_PROCESSES> {
+    let parsed = config.map(|config| {
+        core::str::from_utf8(config)
+            .map_err(|_e| KError::InvalidInitConfig { line: 0 })
+            .and_then(parse)
+    });
+    match parsed {
+        Some(Ok(programs)) if !programs.is_empty() => programs,
+        Some(Ok(_programs)) => {
+            log::warn!("{} doesn't declare any process", CONFIG_MODULE);
+            init_only(cmdline, core)
+        }
+        Some(Err(e)) => {
+            log::error!("Ignoring {}: {}", CONFIG_MODULE, e);
+            init_only(cmdline, core)
+        }
+        None => init_only(cmdline, core),
+    }
+}
+
+fn init_only(
+    cmdline: &BootloaderArguments,
+    core: usize,
+) -> ArrayVec<Program<'static>, MAX_PROCESSES> {
+    let mut programs = ArrayVec::new();
+    programs.push(Program::init(cmdline, core));
+    programs
+}
+
+/// A single value of the configuration.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Scalar<'a> {
+    String(&'a str),
+    Integer(usize),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Value<'a> {
+    Scalar(Scalar<'a>),
+    Array(ArrayVec<Scalar<'a>, MAX_CORES_PER_PROGRAM>),
+}
+
+/// Parses the configuration `text`.
+///
+/// Fails with `InvalidInitConfig` (and the number of the offending line) if
+/// it isn't valid.
+pub fn parse(text: &str) -> Result<ArrayVec<Program<'_>, MAX_PROCESSES>, KError> {
+    let mut programs: ArrayVec<Program, MAX_PROCESSES> = ArrayVec::new();
+    // Line of the current `[[process]]`
+    let mut table_line = 0;
+
+    for (idx, line) in text.lines().enumerate() {
+        let invalid = KError::InvalidInitConfig { line: idx + 1 };
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if line == "[[process]]" {
+            if programs.last().map_or(false, |p| p.binary.is_empty()) {
+                return Err(KError::InvalidInitConfig { line: table_line });
+            }
+            programs
+                .try_push(Program::new(""))
+                .map_err(|_e| invalid.clone())?;
+            table_line = idx + 1;
+            continue;
+        }
+
+        let program = programs.last_mut().ok_or_else(|| invalid.clone())?;
+        let (key, value) = line.split_once('=').ok_or_else(|| invalid.clone())?;
+        let value = value_of(value.trim()).ok_or_else(|| invalid.clone())?;
+        match (key.trim(), value) {
+            ("binary", Value::Scalar(Scalar::String(binary))) if !binary.is_empty() => {
+                program.binary = binary
+            }
+            ("args", Value::Scalar(Scalar::String(args))) => program.args = args,
+            ("root", Value::Scalar(Scalar::String(root))) if root.starts_with('/') => {
+                program.root = root
+            }
+            ("caps", Value::Array(items)) => {
+                for item in items {
+                    let capability = match item {
+                        Scalar::String(name) => Capabilities::from_names(name),
+                        Scalar::Integer(_) => Capabilities::NONE,
+                    };
+                    if capability.is_empty() {
+                        return Err(invalid);
+                    }
+                    program.capabilities |= capability;
+                }
+            }
+            ("restart", Value::Scalar(Scalar::String(name))) => {
+                program.restart = RestartPolicy::from_name(name).ok_or_else(|| invalid.clone())?
+            }
+            ("cores", Value::Array(items)) => {
+                for item in items {
+                    match item {
+                        Scalar::Integer(core) => {
+                            program.cores.try_push(core).map_err(|_e| invalid.clone())?
+                        }
+                        Scalar::String(_) => return Err(invalid),
+                    }
+                }
+            }
+            _ => return Err(invalid),
+        }
+    }
+
+    if programs.last().map_or(false, |p| p.binary.is_empty()) {
+        return Err(KError::InvalidInitConfig { line: table_line });
+    }
+    Ok(programs)
+}
+
+/// Removes a `#` comment (outside of a string) from `line`.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (idx, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..idx],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn scalar_of(value: &str) -> Option<Scalar<'_>> {
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(string) if string.contains(|c| c == '"' || c == '\\') => None,
+        Some(string) => Some(Scalar::String(string)),
+        None => value.parse::<usize>().ok().map(Scalar::Integer),
+    }
+}
+
+fn value_of(value: &str) -> Option<Value<'_>> {
+    let items = match value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+        Some(items) => items.trim(),
+        None => return scalar_of(value).map(Value::Scalar),
+    };
+
+    let mut array = ArrayVec::new();
+    // A trailing comma is fine
+    let items = items.strip_suffix(',').unwrap_or(items);
+    if !items.is_empty() {
+        for item in items.split(',') {
+            array.try_push(scalar_of(item.trim())?).ok()?;
+        }
+    }
+    Some(Value::Array(array))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+# Started in order
+[[process]]
+binary = "init"
+args = "testcmd=1 # not a comment"
+caps = ["measure", "fault-injection"]
+cores = [0, 2,]
+
+[[process]]
+binary = "redis.bin" # comment
+root = "/scratch"
+restart = "always"
+"#;
+
+    #[test]
+    fn parse_config() {
+        let programs = parse(CONFIG).expect("Valid configuration");
+        assert_eq!(programs.len(), 2);
+        assert_eq!(programs[0].binary, "init");
+        assert_eq!(programs[0].args, "testcmd=1 # not a comment");
+        assert_eq!(programs[0].root, "/");
+        assert_eq!(
+            programs[0].capabilities,
+            Capabilities::MEASURE | Capabilities::FAULT_INJECTION
+        );
+        assert_eq!(programs[0].cores.as_slice(), &[0, 2]);
+        assert_eq!(programs[1].binary, "redis.bin");
+        assert_eq!(programs[1].root, "/scratch");
+        assert!(programs[1].cores.is_empty());
+        assert_eq!(programs[0].restart, RestartPolicy::Never);
+        assert_eq!(programs[1].restart, RestartPolicy::Always);
+    }
+
+    #[test]
+    fn invalid_configs() {
+        let line = |text| match parse(text) {
+            Err(KError::InvalidInitConfig { line }) => line,
+            _ => 0,
+        };
+        assert_eq!(line("binary = \"init\""), 1);
+        assert_eq!(line("[[process]]\nbinary = init"), 2);
+        assert_eq!(line("[[process]]\nbinary = \"init\"\nuser = \"root\""), 3);
+        assert_eq!(line("[[process]]\nargs = \"\"\n[[process]]"), 1);
+        assert_eq!(line("[[process]]\nbinary = \"a\"\ncaps = [\"root\"]"), 3);
+        assert_eq!(line("[[process]]\nbinary = \"a\"\ncores = [1, \"2\"]"), 3);
+        assert_eq!(line("[[process]]\nbinary = \"a\"\nrestart = \"no\""), 3);
+        assert!(parse("# Nothing").expect("Valid").is_empty());
+    }
+
+    #[test]
+    fn fall_back_to_cmdline() {
+        let cmdline = BootloaderArguments::default();
+        let init = programs(Some(&b"[[process]]"[..]), &cmdline, 3);
+        assert_eq!(init.as_slice(), &[Program::init(&cmdline, 3)]);
+        assert_eq!(init[0].binary, "init");
+        assert_eq!(init[0].cores.as_slice(), &[3]);
+
+        assert_eq!(programs(None, &cmdline, 3), init);
+        assert_eq!(programs(Some(CONFIG.as_bytes()), &cmdline, 3).len(), 2);
+    }
+}
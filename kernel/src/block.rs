@@ -0,0 +1,166 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Disks of the machine.
+//!
+//! Disk drivers (SATA disks behind an AHCI controller, see `arch::ahci`)
+//! implement [`BlockDevice`] and [`register`] every disk they find. Disks
+//! are read and written in whole sectors.
+//!
+//! The kernel log (see `disklog`) and disk mounts of the file-system (see
+//! `fs::image`, through the page cache in `fs::cache`) use the disks. Every
+//! disk has its own lock, a (polled) command on one disk doesn't hold up the
+//! others.
+
+// Only the x86-64 kernel has disk drivers
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use fallible_collections::FallibleVec;
+use log::info;
+
+use crate::error::KError;
+use crate::sync::Mutex;
+
+/// The sector size we support.
+pub const SECTOR_SIZE: usize = 512;
+
+/// A disk.
+pub trait BlockDevice: Send {
+    /// Name of the disk (for the log).
+    fn name(&self) -> &str;
+
+    /// Number of sectors of the disk.
+    fn sectors(&self) -> u64;
+
+    /// Reads the sectors starting at `sector` into `buf` (a multiple of
+    /// [`SECTOR_SIZE`]).
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), KError>;
+
+    /// Writes `buf` (a multiple of [`SECTOR_SIZE`]) to the sectors starting
+    /// at `sector`.
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), KError>;
+
+    /// Returns once everything written is on the disk (and not in a cache
+    /// of the disk).
+    fn flush(&mut self) -> Result<(), KError>;
+}
+
+/// Fails if `len` bytes starting at `sector` aren't whole sectors of
+/// `disk` (for drivers).
+pub fn check(disk: &dyn BlockDevice, sector: u64, len: usize) -> Result<(), KError> {
+    if len % SECTOR_SIZE != 0 {
+        return Err(KError::InvalidLength);
+    }
+    let end = sector
+        .checked_add((len / SECTOR_SIZE) as u64)
+        .ok_or(KError::InvalidOffset)?;
+    if end > disk.sectors() {
+        return Err(KError::InvalidOffset);
+    }
+    Ok(())
+}
+
+/// A disk and the lock that serializes its commands.
+type Disk = Arc<Mutex<Box<dyn BlockDevice>>>;
+
+/// The disks, the lock is only held to find a disk (not while using it).
+static DISKS: Mutex<Vec<Disk>> = Mutex::new(Vec::new());
+
+/// Adds a disk a driver found, returns its index.
+pub fn register(disk: Box<dyn BlockDevice>) -> Result<usize, KError> {
+    let name = disk.name();
+    let mib = disk.sectors() * SECTOR_SIZE as u64 / (1024 * 1024);
+    let mut disks = DISKS.lock();
+    info!("Disk {}: {} ({} MiB)", disks.len(), name, mib);
+    disks.try_push(Arc::try_new(Mutex::new(disk))?)?;
+    Ok(disks.len() - 1)
+}
+
+/// Runs `f` with disk `idx` (`None` if there is no such disk).
+///
+/// Waits until other cores are done with the disk, but not for other
+/// disks.
+pub fn with_disk<R>(idx: usize, f: impl FnOnce(&mut dyn BlockDevice) -> R) -> Option<R> {
+    let disk = DISKS.lock().get(idx).cloned()?;
+    let mut disk = disk.lock();
+    Some(f(disk.as_mut()))
+}
+
+/// Like [`with_disk`], but `None` if another core uses the disk (e.g., to
+/// write to a disk on a panic).
+pub fn try_with_disk<R>(idx: usize, f: impl FnOnce(&mut dyn BlockDevice) -> R) -> Option<R> {
+    let disk = DISKS.try_lock()?.get(idx).cloned()?;
+    let mut disk = disk.try_lock()?;
+    Some(f(disk.as_mut()))
+}
+
+/// A disk in memory (for tests).
+#[cfg(test)]
+pub struct MemDisk {
+    data: Vec<u8>,
+}
+
+#[cfg(test)]
+impl MemDisk {
+    pub fn new(sectors: usize) -> Result<MemDisk, KError> {
+        use fallible_collections::FallibleVecGlobal;
+        let mut data = Vec::try_with_capacity(sectors * SECTOR_SIZE)?;
+        data.resize(sectors * SECTOR_SIZE, 0);
+        Ok(MemDisk { data })
+    }
+}
+
+#[cfg(test)]
+impl BlockDevice for MemDisk {
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    fn sectors(&self) -> u64 {
+        (self.data.len() / SECTOR_SIZE) as u64
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), KError> {
+        check(self, sector, buf.len())?;
+        let start = sector as usize * SECTOR_SIZE;
+        buf.copy_from_slice(&self.data[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), KError> {
+        check(self, sector, buf.len())?;
+        let start = sector as usize * SECTOR_SIZE;
+        self.data[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), KError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whole_sectors() {
+        let mut disk = MemDisk::new(4).expect("Has memory");
+        let data = [0xabu8; 2 * SECTOR_SIZE];
+        disk.write(2, &data).expect("Fits");
+        assert_eq!(disk.write(3, &data), Err(KError::InvalidOffset));
+        assert_eq!(disk.write(0, &data[..100]), Err(KError::InvalidLength));
+        assert_eq!(
+            disk.read(u64::MAX, &mut [0; SECTOR_SIZE]),
+            Err(KError::InvalidOffset)
+        );
+
+        let mut buf = [0u8; SECTOR_SIZE];
+        disk.read(3, &mut buf).expect("Fits");
+        assert!(buf.iter().all(|b| *b == 0xab));
+    }
+}
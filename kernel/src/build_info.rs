@@ -0,0 +1,59 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Identifies the build of the kernel (git revision, build profile, features
+//! and compiler), so crash reports can be matched with the exact binary.
+//!
+//! The values are set by `build.rs`. The kernel prints them at boot and in
+//! the panic header, user space can query them with `System::build_info`.
+
+// `info` is only used by the x86-64 system calls
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use fallible_collections::FallibleVec;
+use kpi::system::BuildInfo;
+
+use crate::error::KError;
+
+/// Git revision (with a `-dirty` suffix if there were uncommitted changes).
+pub const GIT_VERSION: &str = env!("NRK_GIT_VERSION");
+
+/// Cargo build profile (`debug` or `release`).
+pub const BUILD: &str = env!("NRK_BUILD");
+
+/// Enabled cargo features (comma separated).
+pub const FEATURES: &str = env!("NRK_FEATURES");
+
+/// Version of the compiler.
+pub const RUSTC: &str = env!("NRK_RUSTC");
+
+/// All of the above in one line.
+pub static BUILD_ID: &str = concat!(
+    "nrk ",
+    env!("NRK_GIT_VERSION"),
+    " (",
+    env!("NRK_BUILD"),
+    ", ",
+    env!("NRK_RUSTC"),
+    ") features: ",
+    env!("NRK_FEATURES")
+);
+
+/// Returns the build information for user space.
+pub fn info() -> Result<BuildInfo, KError> {
+    let mut features = Vec::new();
+    for feature in FEATURES.split(',').filter(|f| !f.is_empty()) {
+        features.try_push(String::from(feature))?;
+    }
+
+    Ok(BuildInfo {
+        git_version: String::from(GIT_VERSION),
+        build: String::from(BUILD),
+        profile: String::from(crate::features::PROFILE),
+        features,
+        rustc: String::from(RUSTC),
+    })
+}
@@ -0,0 +1,77 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The wall-clock time.
+//!
+//! At boot the wall clock is what the real-time clock says
+//! (`rawtime::WALL_TIME_ANCHOR`, only to the second) plus the time since
+//! boot. The RTCs of different machines can be seconds apart, so the kernel
+//! corrects the wall clock by an offset it gets from an SNTP server (`ntp=<ip>`
+//! on the command line, see `arch::timesync`). Machines that synchronize
+//! with the same server then agree to within a few milliseconds.
+//!
+//! Processes get the offset with `System::clock_info()`.
+
+// Only the x86-64 kernel network stack synchronizes the clock
+#![cfg_attr(
+    any(not(target_os = "none"), not(feature = "smoltcp")),
+    allow(dead_code)
+)]
+
+use core::sync::atomic::{AtomicI64, Ordering};
+use core::time::Duration;
+
+/// Nanoseconds we add to the real-time clock.
+static OFFSET: AtomicI64 = AtomicI64::new(0);
+
+/// `since_boot` on a machine that booted at `anchor` (seconds since the Unix
+/// epoch), corrected by `offset` ns.
+fn unix_time(anchor: u64, since_boot: Duration, offset: i64) -> Duration {
+    let nanos = (anchor as i128 * 1_000_000_000)
+        .saturating_add(since_boot.as_nanos() as i128)
+        .saturating_add(offset as i128)
+        .max(0);
+    Duration::new(
+        (nanos / 1_000_000_000) as u64,
+        (nanos % 1_000_000_000) as u32,
+    )
+}
+
+/// The current wall-clock time (since the Unix epoch).
+pub fn now() -> Duration {
+    unix_time(
+        rawtime::WALL_TIME_ANCHOR.as_unix_time(),
+        rawtime::duration_since_boot(),
+        offset(),
+    )
+}
+
+/// Nanoseconds the wall clock is ahead of the real-time clock.
+pub fn offset() -> i64 {
+    OFFSET.load(Ordering::Relaxed)
+}
+
+/// Moves the wall clock by `delta` ns (forward if positive).
+pub fn adjust(delta: i64) {
+    OFFSET.fetch_add(delta, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrected_time() {
+        let since_boot = Duration::from_millis(2500);
+        assert_eq!(
+            unix_time(1_600_000_000, since_boot, 0),
+            Duration::from_millis(1_600_000_002_500)
+        );
+        assert_eq!(
+            unix_time(1_600_000_000, since_boot, -3_000_000_000),
+            Duration::from_millis(1_599_999_999_500)
+        );
+        // Never before the epoch
+        assert_eq!(unix_time(1, since_boot, i64::MIN), Duration::from_secs(0));
+    }
+}
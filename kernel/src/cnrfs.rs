@@ -1,21 +1,26 @@
 // Copyright © 2021 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-use crate::arch::process::{UserPtr, UserSlice};
+use crate::arch::process::UserSlice;
+use crate::arch::usercopy::{copy_to_user, write_user};
 use crate::error::KError;
+use crate::fallible_string::TryString;
 use crate::fs::fd::FileDesc;
+use crate::fs::{image, path};
 use crate::fs::{
     Buffer, FileDescriptor, FileSystem, Filename, Flags, Len, MlnrFS, Mnode, Modes, NrLock, Offset,
     FD, MNODE_OFFSET,
 };
-use crate::memory::VAddr;
+use crate::nr;
 use crate::prelude::*;
 use crate::process::{userptr_to_str, KernSlice, Pid};
 
 use alloc::sync::Arc;
 use cnr::{Dispatch, LogMapper};
+use core::convert::TryFrom;
 use hashbrown::HashMap;
 use kpi::io::*;
+use kpi::process::Capabilities;
 use kpi::FileOperation;
 
 pub struct MlnrKernelNode {
@@ -29,23 +34,34 @@ pub struct MlnrKernelNode {
 
 impl Default for MlnrKernelNode {
     fn default() -> Self {
+        let fs = MlnrFS::default();
+        crate::fs::rootfs::populate(&fs);
         MlnrKernelNode {
             process_map: NrLock::<HashMap<Pid, FileDesc>>::default(),
-            fs: MlnrFS::default(),
+            fs,
         }
     }
 }
 
 #[derive(Hash, Clone, Debug, PartialEq)]
 pub enum Modify {
-    ProcessAdd(Pid),
+    ProcessAdd(Pid, String),
     ProcessRemove(Pid),
+    ProcessInherit(Pid, Pid, u64),
+    FdLimit(Pid, u64),
     FileOpen(Pid, String, Flags, Modes),
     FileWrite(Pid, FD, Mnode, Arc<[u8]>, Len, Offset),
+    FdAdvance(Pid, FD, Mnode, Len),
     FileClose(Pid, FD),
     FileDelete(Pid, String),
     FileRename(Pid, String, String),
     MkDir(Pid, String, Modes),
+    Symlink(Pid, String, String),
+    Mount(Pid, String, u64, u64),
+    MountDisk(Pid, String, usize, u64, u64, Arc<[u8]>),
+    Fsync(Pid, FD),
+    WriteBehind,
+    FdRestore(Pid, FD, Mnode, Flags, Offset),
 }
 
 // TODO: Stateless op to log mapping. Maintain some state for correct redirection.
@@ -54,16 +70,30 @@ impl LogMapper for Modify {
         debug_assert!(logs.capacity() >= nlogs, "Push can't fail.");
         logs.clear();
         match self {
-            Modify::ProcessAdd(_pid) => push_to_all(nlogs, logs),
+            Modify::ProcessAdd(_pid, _root) => push_to_all(nlogs, logs),
             Modify::ProcessRemove(_pid) => push_to_all(nlogs, logs),
+            Modify::ProcessInherit(_parent, _child, _fds) => push_to_all(nlogs, logs),
+            Modify::FdLimit(_pid, _limit) => push_to_all(nlogs, logs),
             Modify::FileOpen(_pid, _filename, _flags, _modes) => push_to_all(nlogs, logs),
             Modify::FileWrite(_pid, _fd, mnode, _kernslice, _len, _offset) => {
                 logs.push((*mnode as usize - MNODE_OFFSET) % nlogs)
             }
+            Modify::FdAdvance(_pid, _fd, mnode, _len) => {
+                logs.push((*mnode as usize - MNODE_OFFSET) % nlogs)
+            }
             Modify::FileClose(_pid, _fd) => push_to_all(nlogs, logs),
             Modify::FileDelete(_pid, _filename) => push_to_all(nlogs, logs),
             Modify::FileRename(_pid, _oldname, _newname) => push_to_all(nlogs, logs),
             Modify::MkDir(_pid, _name, _modes) => push_to_all(nlogs, logs),
+            Modify::Symlink(_pid, _target, _name) => push_to_all(nlogs, logs),
+            Modify::Mount(_pid, _name, _kind, _limit) => push_to_all(nlogs, logs),
+            Modify::MountDisk(_pid, _name, _disk, _generation, _limit, _archive) => {
+                push_to_all(nlogs, logs)
+            }
+            // Needs the writes of all logs, it snapshots the whole mount.
+            Modify::Fsync(_pid, _fd) => push_to_all(nlogs, logs),
+            Modify::WriteBehind => push_to_all(nlogs, logs),
+            Modify::FdRestore(_pid, _fd, _mnode, _flags, _offset) => push_to_all(nlogs, logs),
         }
 
         fn push_to_all(nlogs: usize, logs: &mut Vec<usize>) {
@@ -77,9 +107,14 @@ impl LogMapper for Modify {
 #[derive(Hash, Clone, Debug, PartialEq)]
 pub enum Access {
     FileRead(Pid, FD, Mnode, Buffer, Len, Offset),
+    /// Read into a kernel buffer (instead of user memory like `FileRead`).
+    FileReadBuffer(Pid, FD, Mnode, Len, Offset),
     FileInfo(Pid, Filename, Mnode, u64),
     FdToMnode(Pid, FD),
+    FdToFlags(Pid, FD),
+    FdState(Pid, FD),
     FileNameToMnode(Pid, Filename),
+    ReadLink(Pid, Filename),
     Synchronize(usize),
 }
 
@@ -92,12 +127,18 @@ impl LogMapper for Access {
             Access::FileRead(_pid, _fd, mnode, _buffer, _len, _offser) => {
                 logs.push((*mnode as usize - MNODE_OFFSET) % nlogs)
             }
+            Access::FileReadBuffer(_pid, _fd, mnode, _len, _offset) => {
+                logs.push((*mnode as usize - MNODE_OFFSET) % nlogs)
+            }
             Access::FileInfo(_pid, _filename, mnode, _info_ptr) => {
                 logs.push((*mnode as usize - MNODE_OFFSET) % nlogs)
             }
             // TODO: Assume that all metadata modifying operations go through log 0.
             Access::FdToMnode(_pid, _fd) => logs.push(0),
+            Access::FdToFlags(_pid, _fd) => logs.push(0),
+            Access::FdState(_pid, _fd) => logs.push(0),
             Access::FileNameToMnode(_pid, _filename) => logs.push(0),
+            Access::ReadLink(_pid, _filename) => logs.push(0),
             // Log number start with 1 in CNR, however, replica uses mod
             // operation which starts with 0; hence `log_id - 1`.
             Access::Synchronize(log_id) => logs.push((*log_id - 1) % nlogs),
@@ -109,27 +150,42 @@ impl LogMapper for Access {
 pub enum MlnrNodeResult {
     ProcessAdded(Pid),
     ProcessRemoved(Pid),
+    FdsInherited,
+    FdLimitSet,
     FileOpened(FD),
     FileAccessed(Len),
+    FileData(Vec<u8>),
+    FdAdvanced(Offset, Len),
     FileClosed(u64),
     FileDeleted,
     FileInfo(FileInfo),
     FileRenamed,
     DirCreated,
+    SymlinkCreated,
+    LinkTarget(String),
+    Mounted,
+    Snapshot(Option<image::Snapshot>),
+    Snapshots(Vec<image::Snapshot>),
     MappedFileToMnode(u64),
+    FdFlags(FileFlags),
+    FdState(Mnode, FileFlags, Offset),
+    FdRestored,
     Synchronized,
 }
 
 /// TODO: Most of the functions looks same as in nr.rs. Merge the
 /// two and maybe move all the functions to a separate file?
 impl MlnrKernelNode {
-    pub fn add_process(pid: usize) -> Result<(u64, u64), KError> {
-        let kcb = super::kcb::get_kcb();
+    /// Register a new process whose file-system namespace is the sub-tree
+    /// at `root` (which is created if it doesn't exist yet).
+    pub fn add_process(pid: usize, root: &str) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::per_core();
         kcb.arch
             .cnr_replica
             .as_ref()
             .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
-                let response = replica.execute_mut_scan(Modify::ProcessAdd(pid), *token);
+                let root = TryString::try_from(root)?.into();
+                let response = replica.execute_mut_scan(Modify::ProcessAdd(pid, root), *token);
                 match response {
                     Ok(MlnrNodeResult::ProcessAdded(pid)) => Ok((pid as u64, 0)),
                     Err(e) => Err(e),
@@ -138,13 +194,69 @@ impl MlnrKernelNode {
             })
     }
 
+    /// Forgets the file descriptors of the (destroyed) process `pid`.
+    pub fn remove_process(pid: Pid) -> Result<(), KError> {
+        let kcb = super::kcb::per_core();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut_scan(Modify::ProcessRemove(pid), *token);
+                match response {
+                    Ok(MlnrNodeResult::ProcessRemoved(_pid)) => Ok(()),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Gives the (spawned) process `child` the descriptors `fds` (a bitmask)
+    /// and the descriptor limit of `parent`.
+    pub fn inherit(parent: Pid, child: Pid, fds: u64) -> Result<(), KError> {
+        let kcb = super::kcb::per_core();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response =
+                    replica.execute_mut_scan(Modify::ProcessInherit(parent, child, fds), *token);
+                match response {
+                    Ok(MlnrNodeResult::FdsInherited) => Ok(()),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Lowers the number of files `pid` can have open to `limit`.
+    pub fn set_fd_limit(pid: Pid, limit: u64) -> Result<(), KError> {
+        let kcb = super::kcb::per_core();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut_scan(Modify::FdLimit(pid, limit), *token);
+                match response {
+                    Ok(MlnrNodeResult::FdLimitSet) => Ok(()),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
     pub fn map_fd(pid: Pid, pathname: u64, flags: u64, modes: u64) -> Result<(FD, u64), KError> {
-        let kcb = super::kcb::get_kcb();
+        let filename = userptr_to_str(pid, pathname)?;
+        MlnrKernelNode::open(pid, filename, flags, modes)
+    }
+
+    /// Opens `filename` for `pid` on behalf of the kernel (like `map_fd`
+    /// with a path that's not in user-space).
+    pub fn open(pid: Pid, filename: String, flags: u64, modes: u64) -> Result<(FD, u64), KError> {
+        let kcb = super::kcb::per_core();
         kcb.arch
             .cnr_replica
             .as_ref()
             .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
-                let filename = userptr_to_str(pathname)?;
                 let response =
                     replica.execute_mut_scan(Modify::FileOpen(pid, filename, flags, modes), *token);
 
@@ -168,12 +280,12 @@ impl MlnrKernelNode {
             Ok((mnode, _)) => mnode,
             Err(_) => return Err(KError::InvalidFileDescriptor),
         };
-        let kcb = super::kcb::get_kcb();
+        let kcb = super::kcb::per_core();
         kcb.arch.cnr_replica.as_ref().map_or(
             Err(KError::ReplicaNotSet),
             |(replica, token)| match op {
                 FileOperation::Write | FileOperation::WriteAt => {
-                    let kernslice = KernSlice::new(buffer, len as usize);
+                    let kernslice = KernSlice::new(buffer, len as usize)?;
 
                     let response = replica.execute_mut(
                         Modify::FileWrite(pid, fd, mnode, kernslice.buffer, len, offset),
@@ -188,6 +300,22 @@ impl MlnrKernelNode {
                 }
 
                 FileOperation::Read | FileOperation::ReadAt => {
+                    // Claim the range at the cursor first (through the log of
+                    // the file, like writes), so concurrent readers of `fd`
+                    // get disjoint data on any replica:
+                    let (offset, len) = if offset == -1 {
+                        match replica.execute_mut(Modify::FdAdvance(pid, fd, mnode, len), *token) {
+                            Ok(MlnrNodeResult::FdAdvanced(offset, len)) => (offset, len),
+                            Err(e) => return Err(e),
+                            Ok(_) => unreachable!("Got unexpected response"),
+                        }
+                    } else {
+                        (offset, len)
+                    };
+                    if len == 0 {
+                        return Ok((0, 0));
+                    }
+
                     let response = replica.execute(
                         Access::FileRead(pid, fd, mnode, buffer, len, offset),
                         *token,
@@ -205,7 +333,7 @@ impl MlnrKernelNode {
     }
 
     pub fn unmap_fd(pid: Pid, fd: u64) -> Result<(u64, u64), KError> {
-        let kcb = super::kcb::get_kcb();
+        let kcb = super::kcb::per_core();
         kcb.arch
             .cnr_replica
             .as_ref()
@@ -221,12 +349,12 @@ impl MlnrKernelNode {
     }
 
     pub fn file_delete(pid: Pid, name: u64) -> Result<(u64, u64), KError> {
-        let kcb = super::kcb::get_kcb();
+        let kcb = super::kcb::per_core();
         kcb.arch
             .cnr_replica
             .as_ref()
             .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
-                let filename = userptr_to_str(name)?;
+                let filename = userptr_to_str(pid, name)?;
                 let response = replica.execute_mut_scan(Modify::FileDelete(pid, filename), *token);
 
                 match response {
@@ -240,7 +368,7 @@ impl MlnrKernelNode {
     pub fn file_info(pid: Pid, name: u64, info_ptr: u64) -> Result<(u64, u64), KError> {
         let (mnode, _) = MlnrKernelNode::filename_to_mnode(pid, name)?;
 
-        let kcb = super::kcb::get_kcb();
+        let kcb = super::kcb::per_core();
         kcb.arch
             .cnr_replica
             .as_ref()
@@ -250,11 +378,7 @@ impl MlnrKernelNode {
 
                 match response {
                     Ok(MlnrNodeResult::FileInfo(f_info)) => {
-                        let user_ptr = UserPtr::new(&mut VAddr::from(info_ptr));
-                        unsafe {
-                            (*user_ptr.as_mut_ptr::<FileInfo>()).ftype = f_info.ftype;
-                            (*user_ptr.as_mut_ptr::<FileInfo>()).fsize = f_info.fsize;
-                        }
+                        write_user(info_ptr, &f_info)?;
                         Ok((0, 0))
                     }
                     Err(e) => Err(e),
@@ -264,13 +388,13 @@ impl MlnrKernelNode {
     }
 
     pub fn file_rename(pid: Pid, oldname: u64, newname: u64) -> Result<(u64, u64), KError> {
-        let kcb = super::kcb::get_kcb();
+        let kcb = super::kcb::per_core();
         kcb.arch
             .cnr_replica
             .as_ref()
             .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
-                let oldfilename = userptr_to_str(oldname)?;
-                let newfilename = userptr_to_str(newname)?;
+                let oldfilename = userptr_to_str(pid, oldname)?;
+                let newfilename = userptr_to_str(pid, newname)?;
 
                 let response = replica
                     .execute_mut_scan(Modify::FileRename(pid, oldfilename, newfilename), *token);
@@ -283,12 +407,12 @@ impl MlnrKernelNode {
     }
 
     pub fn mkdir(pid: Pid, pathname: u64, modes: u64) -> Result<(u64, u64), KError> {
-        let kcb = super::kcb::get_kcb();
+        let kcb = super::kcb::per_core();
         kcb.arch
             .cnr_replica
             .as_ref()
             .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
-                let filename = userptr_to_str(pathname)?;
+                let filename = userptr_to_str(pid, pathname)?;
                 let response =
                     replica.execute_mut_scan(Modify::MkDir(pid, filename, modes), *token);
 
@@ -300,9 +424,139 @@ impl MlnrKernelNode {
             })
     }
 
+    /// Create the symbolic link `linkpath` pointing to `target`.
+    pub fn symlink(pid: Pid, target: u64, linkpath: u64) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::per_core();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let target = userptr_to_str(pid, target)?;
+                let linkpath = userptr_to_str(pid, linkpath)?;
+                let response =
+                    replica.execute_mut_scan(Modify::Symlink(pid, target, linkpath), *token);
+
+                match response {
+                    Ok(MlnrNodeResult::SymlinkCreated) => Ok((0, 0)),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Copy the target of the link `pathname` to the user buffer `buf`
+    /// (at most `len` bytes), returns the number of bytes copied.
+    pub fn readlink(pid: Pid, pathname: u64, buf: u64, len: u64) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::per_core();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(Access::ReadLink(pid, pathname), *token);
+
+                match response {
+                    Ok(MlnrNodeResult::LinkTarget(target)) => {
+                        let copied = core::cmp::min(target.len(), len as usize);
+                        copy_to_user(buf, &target.as_bytes()[..copied])?;
+                        Ok((copied as u64, 0))
+                    }
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Write back the data of `fd`.
+    ///
+    /// Files on a disk mount are written to the disk together with the rest
+    /// of the mount (see `fs::image`), the replicas only take the snapshot
+    /// and we write it once the log is released. This waits for the pages
+    /// the flusher didn't write back yet as well. Other files only live in
+    /// memory, we only check that `fd` is valid.
+    pub fn fsync(pid: Pid, fd: FD) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::per_core();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut_scan(Modify::Fsync(pid, fd), *token);
+
+                match response {
+                    Ok(MlnrNodeResult::Snapshot(Some(snapshot))) => {
+                        image::write_back(&snapshot)?;
+                        Ok((0, 0))
+                    }
+                    Ok(MlnrNodeResult::Snapshot(None)) => Ok((0, 0)),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Snapshots of the disk mounts whose files changed since their last
+    /// snapshot, for the flusher (see `fs::image::flush_softirq`).
+    pub fn write_behind() -> Result<Vec<image::Snapshot>, KError> {
+        let kcb = super::kcb::per_core();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut_scan(Modify::WriteBehind, *token);
+
+                match response {
+                    Ok(MlnrNodeResult::Snapshots(snapshots)) => Ok(snapshots),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Mounts a file-system on `pathname`, for `MountKind::Disk` `limit` is
+    /// the index of the disk and the process needs `Capabilities::DEVICES`.
+    pub fn mount(pid: Pid, pathname: u64, kind: u64, limit: u64) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::per_core();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let filename = userptr_to_str(pid, pathname)?;
+                let op = if MountKind::from(kind) == MountKind::Disk {
+                    if !nr::KernelNode::capabilities(pid)?.contains(Capabilities::DEVICES) {
+                        return Err(KError::PermissionError);
+                    }
+                    // Read the disk once, not in every replica
+                    let disk = limit as usize;
+                    let (generation, archive, capacity) = image::read_disk(disk)?;
+                    Modify::MountDisk(
+                        pid,
+                        filename,
+                        disk,
+                        generation,
+                        capacity as u64,
+                        Arc::from(archive),
+                    )
+                } else {
+                    Modify::Mount(pid, filename, kind, limit)
+                };
+                let disk = matches!(op, Modify::MountDisk(..));
+                let response = replica.execute_mut_scan(op, *token);
+
+                match response {
+                    Ok(MlnrNodeResult::Mounted) => {
+                        if disk {
+                            image::start_flusher();
+                        }
+                        Ok((0, 0))
+                    }
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
     #[inline(always)]
     pub fn fd_to_mnode(pid: Pid, fd: FD) -> Result<(u64, u64), KError> {
-        let kcb = super::kcb::get_kcb();
+        let kcb = super::kcb::per_core();
         kcb.arch
             .cnr_replica
             .as_ref()
@@ -317,9 +571,128 @@ impl MlnrKernelNode {
             })
     }
 
+    /// Readiness of `fd` for `events`.
+    ///
+    /// Regular files never block, they're ready for reading/writing if they
+    /// were opened for it. Invalid descriptors report `POLLNVAL`.
+    pub fn poll(pid: Pid, fd: FD, events: PollEvents) -> Result<PollEvents, KError> {
+        let kcb = super::kcb::per_core();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(Access::FdToFlags(pid, fd), *token);
+
+                match response {
+                    Ok(MlnrNodeResult::FdFlags(flags)) => {
+                        let mut ready = PollEvents::empty();
+                        ready.set(PollEvents::POLLIN, flags.is_read());
+                        ready.set(PollEvents::POLLOUT, flags.is_write());
+                        Ok(ready & events)
+                    }
+                    Err(KError::InvalidFileDescriptor) => Ok(PollEvents::POLLNVAL),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// The file, flags and current offset of `fd` (used to checkpoint a
+    /// process).
+    pub fn fd_state(pid: Pid, fd: FD) -> Result<(Mnode, FileFlags, Offset), KError> {
+        let kcb = super::kcb::per_core();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(Access::FdState(pid, fd), *token);
+
+                match response {
+                    Ok(MlnrNodeResult::FdState(mnode, flags, offset)) => Ok((mnode, flags, offset)),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Re-creates `fd` of a (restored) process, it refers to the existing
+    /// file `mnode`.
+    pub fn restore_fd(
+        pid: Pid,
+        fd: FD,
+        mnode: Mnode,
+        flags: FileFlags,
+        offset: Offset,
+    ) -> Result<(), KError> {
+        let kcb = super::kcb::per_core();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut_scan(
+                    Modify::FdRestore(pid, fd, mnode, flags.into(), offset),
+                    *token,
+                );
+
+                match response {
+                    Ok(MlnrNodeResult::FdRestored) => Ok(()),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Reads up to `len` bytes at `offset` of `fd` into kernel memory.
+    pub fn read_buffer(pid: Pid, fd: FD, len: Len, offset: Offset) -> Result<Vec<u8>, KError> {
+        let (mnode, _) =
+            MlnrKernelNode::fd_to_mnode(pid, fd).map_err(|_e| KError::InvalidFileDescriptor)?;
+        let kcb = super::kcb::per_core();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response =
+                    replica.execute(Access::FileReadBuffer(pid, fd, mnode, len, offset), *token);
+
+                match response {
+                    Ok(MlnrNodeResult::FileData(data)) => Ok(data),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Writes `buffer` (kernel memory) to `fd` at `offset`.
+    pub fn write_buffer(
+        pid: Pid,
+        fd: FD,
+        buffer: Arc<[u8]>,
+        offset: Offset,
+    ) -> Result<Len, KError> {
+        let (mnode, _) =
+            MlnrKernelNode::fd_to_mnode(pid, fd).map_err(|_e| KError::InvalidFileDescriptor)?;
+        let kcb = super::kcb::per_core();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let len = buffer.len() as Len;
+                let response = replica.execute_mut(
+                    Modify::FileWrite(pid, fd, mnode, buffer, len, offset),
+                    *token,
+                );
+
+                match response {
+                    Ok(MlnrNodeResult::FileAccessed(len)) => Ok(len),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
     #[inline(always)]
     pub fn filename_to_mnode(pid: Pid, filename: Filename) -> Result<(u64, u64), KError> {
-        let kcb = super::kcb::get_kcb();
+        let kcb = super::kcb::per_core();
         kcb.arch
             .cnr_replica
             .as_ref()
@@ -335,7 +708,7 @@ impl MlnrKernelNode {
     }
 
     pub fn synchronize_log(log_id: usize) -> Result<(u64, u64), KError> {
-        let kcb = super::kcb::get_kcb();
+        let kcb = super::kcb::per_core();
         kcb.arch
             .cnr_replica
             .as_ref()
@@ -350,12 +723,29 @@ impl MlnrKernelNode {
     }
 }
 
+impl MlnrKernelNode {
+    /// Resolve `name` in the file-system namespace of process `pid`.
+    ///
+    /// Links are resolved in the namespace of the process as well (an
+    /// absolute target of a confined process starts at its root), a link in
+    /// the last component is only followed if `follow` is set.
+    fn path(&self, pid: Pid, name: &str, follow: bool) -> Result<String, KError> {
+        let process_map = self.process_map.read();
+        let fdesc = process_map.get(&pid).ok_or(KError::NoProcessFoundForPid)?;
+        let resolved = path::resolve(name, follow, |link| {
+            self.fs.symlink_target(&fdesc.path(link)?)
+        })?;
+        fdesc.path(&resolved)
+    }
+}
+
 impl Dispatch for MlnrKernelNode {
     type ReadOperation = Access;
     type WriteOperation = Modify;
     type Response = Result<MlnrNodeResult, KError>;
 
     fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
+        let _pin = crate::domain::pin();
         match op {
             Access::FileRead(pid, fd, _mnode, buffer, len, offset) => {
                 let mut userslice = UserSlice::new(buffer, len as usize);
@@ -394,14 +784,24 @@ impl Dispatch for MlnrKernelNode {
                 }
             }
 
-            Access::FileInfo(pid, name, _mnode, _info_ptr) => {
-                let _p = self
-                    .process_map
-                    .read()
+            Access::FileReadBuffer(pid, fd, _mnode, len, offset) => {
+                let process_lookup = self.process_map.read();
+                let p = process_lookup
                     .get(&pid)
                     .ok_or(KError::NoProcessFoundForPid)?;
+                let fd = p.get_fd(fd as usize).ok_or(KError::PermissionError)?;
+                if !fd.get_flags().is_read() {
+                    return Err(KError::PermissionError);
+                }
+
+                let data = self
+                    .fs
+                    .read_buffer(fd.get_mnode(), offset as usize, len as usize)?;
+                Ok(MlnrNodeResult::FileData(data))
+            }
 
-                let filename = userptr_to_str(name)?;
+            Access::FileInfo(pid, name, _mnode, _info_ptr) => {
+                let filename = self.path(pid, &userptr_to_str(pid, name)?, true)?;
                 let mnode = self.fs.lookup(&filename).ok_or(KError::InvalidFile)?;
 
                 let f_info = self.fs.file_info(*mnode);
@@ -419,14 +819,32 @@ impl Dispatch for MlnrKernelNode {
                 Ok(MlnrNodeResult::MappedFileToMnode(mnode_num))
             }
 
-            Access::FileNameToMnode(pid, name) => {
-                let _p = self
-                    .process_map
-                    .read()
+            Access::FdToFlags(pid, fd) => {
+                let process_map_locked = self.process_map.read();
+                let p = process_map_locked
                     .get(&pid)
                     .ok_or(KError::NoProcessFoundForPid)?;
 
-                let filename = userptr_to_str(name)?;
+                let fd = p.get_fd(fd as usize).ok_or(KError::InvalidFileDescriptor)?;
+                Ok(MlnrNodeResult::FdFlags(fd.get_flags()))
+            }
+
+            Access::FdState(pid, fd) => {
+                let process_map_locked = self.process_map.read();
+                let p = process_map_locked
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+
+                let fd = p.get_fd(fd as usize).ok_or(KError::InvalidFileDescriptor)?;
+                Ok(MlnrNodeResult::FdState(
+                    fd.get_mnode(),
+                    fd.get_flags(),
+                    fd.get_offset() as Offset,
+                ))
+            }
+
+            Access::FileNameToMnode(pid, name) => {
+                let filename = self.path(pid, &userptr_to_str(pid, name)?, true)?;
 
                 match self.fs.lookup(&filename) {
                     // match on (file_exists, mnode_number)
@@ -435,6 +853,14 @@ impl Dispatch for MlnrKernelNode {
                 }
             }
 
+            Access::ReadLink(pid, name) => {
+                let filename = self.path(pid, &userptr_to_str(pid, name)?, false)?;
+                match self.fs.symlink_target(&filename)? {
+                    Some(target) => Ok(MlnrNodeResult::LinkTarget(target)),
+                    None => Err(KError::InvalidFile),
+                }
+            }
+
             Access::Synchronize(_log_id) => {
                 // A NOP that just makes sure we've advanced the replica
                 Ok(MlnrNodeResult::Synchronized)
@@ -443,11 +869,20 @@ impl Dispatch for MlnrKernelNode {
     }
 
     fn dispatch_mut(&self, op: Self::WriteOperation) -> Self::Response {
+        let _pin = crate::domain::pin();
         match op {
-            Modify::ProcessAdd(pid) => {
+            Modify::ProcessAdd(pid, root) => {
+                let fdesc = FileDesc::with_root(&root)?;
+                if let Some(root) = fdesc.root() {
+                    match self.fs.mkdir(root, FileModes::S_IRWXU.into()) {
+                        Ok(()) | Err(KError::AlreadyPresent) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+
                 let mut pmap = self.process_map.write();
                 pmap.try_reserve(1)?;
-                pmap.try_insert(pid, FileDesc::default())
+                pmap.try_insert(pid, fdesc)
                     .map_err(|_e| KError::FileDescForPidAlreadyAdded)?;
                 Ok(MlnrNodeResult::ProcessAdded(pid))
             }
@@ -458,18 +893,43 @@ impl Dispatch for MlnrKernelNode {
                 Ok(MlnrNodeResult::ProcessRemoved(pid))
             }
 
+            Modify::ProcessInherit(parent, child, fds) => {
+                let mut pmap = self.process_map.write();
+                // Taken out while we fill it, putting it back can't allocate
+                let mut child_fdesc = pmap.remove(&child).ok_or(KError::NoFileDescForPid)?;
+                let inherited = pmap
+                    .get(&parent)
+                    .ok_or(KError::NoFileDescForPid)
+                    .and_then(|p| p.inherit(&mut child_fdesc, fds));
+                pmap.insert(child, child_fdesc);
+                inherited.map(|_| MlnrNodeResult::FdsInherited)
+            }
+
+            Modify::FdLimit(pid, limit) => {
+                let mut pmap = self.process_map.write();
+                let p = pmap.get_mut(&pid).ok_or(KError::NoFileDescForPid)?;
+                p.set_limit(usize::try_from(limit).unwrap_or(usize::MAX))?;
+                Ok(MlnrNodeResult::FdLimitSet)
+            }
+
             Modify::FileOpen(pid, filename, flags, modes) => {
+                let filename = self.path(pid, &filename, true)?;
                 let flags = FileFlags::from(flags);
                 let mnode = self.fs.lookup(&filename);
-                if mnode.is_none() && !flags.is_create() {
-                    return Err(KError::PermissionError);
+                match mnode {
+                    None if !flags.is_create() => return Err(KError::PermissionError),
+                    Some(_) if flags.is_create() && flags.is_exclusive() => {
+                        return Err(KError::AlreadyPresent)
+                    }
+                    Some(ref mnode) => self.fs.check_access(**mnode, flags)?,
+                    None => {}
                 }
 
                 let mut pmap = self.process_map.write();
                 let p = pmap
                     .get_mut(&pid)
                     .expect("TODO: FileOpen process lookup failed");
-                let (fid, fd) = p.allocate_fd().ok_or(KError::NotSupported)?;
+                let (fid, fd) = p.allocate_fd()?;
 
                 let mnode_num;
                 if let Some(mnode) = mnode {
@@ -512,16 +972,18 @@ impl Dispatch for MlnrKernelNode {
                     return Err(KError::PermissionError);
                 }
 
+                if offset == -1 && flags.is_append() {
+                    // If offset value is not provided and file is opened with O_APPEND flag,
+                    // find the end and write there in one step.
+                    let (end, len) = self.fs.append(mnode_num, &kernslice)?;
+                    fd.update_offset(end + len);
+                    return Ok(MlnrNodeResult::FileAccessed(len as u64));
+                }
+
                 let mut curr_offset: usize = offset as usize;
                 if offset == -1 {
-                    if flags.is_append() {
-                        // If offset value is not provided and file is opened with O_APPEND flag.
-                        let finfo = self.fs.file_info(mnode_num);
-                        curr_offset = finfo.fsize as usize;
-                    } else {
-                        // If offset value is not provided and file is doesn't have O_APPEND flag.
-                        curr_offset = fd.get_offset();
-                    }
+                    // If offset value is not provided and file is doesn't have O_APPEND flag.
+                    curr_offset = fd.get_offset();
                 }
 
                 match self.fs.write(mnode_num, &kernslice, curr_offset) {
@@ -536,6 +998,21 @@ impl Dispatch for MlnrKernelNode {
                 }
             }
 
+            Modify::FdAdvance(pid, fd, _mnode, len) => {
+                let process_lookup = self.process_map.read();
+                let p = process_lookup
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                let fd = p.get_fd(fd as usize).ok_or(KError::PermissionError)?;
+                if !fd.get_flags().is_read() {
+                    return Err(KError::PermissionError);
+                }
+
+                let size = self.fs.file_info(fd.get_mnode()).fsize as usize;
+                let (offset, len) = fd.advance_offset(len as usize, size);
+                Ok(MlnrNodeResult::FdAdvanced(offset as Offset, len as Len))
+            }
+
             Modify::FileClose(pid, fd) => {
                 let mut process_lookup = self.process_map.write();
                 let p = process_lookup
@@ -546,34 +1023,175 @@ impl Dispatch for MlnrKernelNode {
             }
 
             Modify::FileDelete(pid, filename) => {
-                let _p = self
-                    .process_map
-                    .read()
-                    .get(&pid)
-                    .ok_or(KError::NoProcessFoundForPid)?;
+                let filename = self.path(pid, &filename, false)?;
                 let _is_deleted = self.fs.delete(&filename)?;
                 Ok(MlnrNodeResult::FileDeleted)
             }
 
             Modify::FileRename(pid, oldname, newname) => {
-                let _p = self
-                    .process_map
-                    .read()
-                    .get(&pid)
-                    .ok_or(KError::NoProcessFoundForPid)?;
+                let oldname = self.path(pid, &oldname, false)?;
+                let newname = self.path(pid, &newname, false)?;
                 let _is_renamed = self.fs.rename(&oldname, &newname)?;
                 Ok(MlnrNodeResult::FileRenamed)
             }
 
             Modify::MkDir(pid, filename, modes) => {
-                let _p = self
-                    .process_map
-                    .read()
-                    .get(&pid)
-                    .ok_or(KError::NoProcessFoundForPid)?;
+                let filename = self.path(pid, &filename, false)?;
                 let _is_created = self.fs.mkdir(&filename, modes)?;
                 Ok(MlnrNodeResult::DirCreated)
             }
+
+            Modify::Symlink(pid, target, filename) => {
+                let filename = self.path(pid, &filename, false)?;
+                self.fs.symlink(&target, &filename)?;
+                Ok(MlnrNodeResult::SymlinkCreated)
+            }
+
+            Modify::Mount(pid, filename, kind, limit) => {
+                let filename = self.path(pid, &filename, true)?;
+                self.fs
+                    .mount(&filename, MountKind::from(kind), limit as usize)?;
+                Ok(MlnrNodeResult::Mounted)
+            }
+
+            Modify::MountDisk(pid, filename, disk, generation, limit, archive) => {
+                let filename = self.path(pid, &filename, true)?;
+                self.fs
+                    .mount_disk(&filename, disk, generation, limit as usize, &archive)?;
+                Ok(MlnrNodeResult::Mounted)
+            }
+
+            Modify::Fsync(pid, fd) => {
+                let process_lookup = self.process_map.read();
+                let p = process_lookup
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                let fd = p.get_fd(fd as usize).ok_or(KError::InvalidFileDescriptor)?;
+                let mnode = fd.get_mnode();
+                drop(process_lookup);
+
+                Ok(MlnrNodeResult::Snapshot(self.fs.snapshot(mnode)?))
+            }
+
+            Modify::WriteBehind => Ok(MlnrNodeResult::Snapshots(self.fs.write_behind()?)),
+
+            Modify::FdRestore(pid, fd, mnode, flags, offset) => {
+                if !self.fs.contains(mnode) {
+                    return Err(KError::InvalidFile);
+                }
+                if offset < 0 {
+                    return Err(KError::InvalidOffset);
+                }
+
+                let mut process_lookup = self.process_map.write();
+                let p = process_lookup
+                    .get_mut(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                p.restore_fd(fd as usize, mnode, FileFlags::from(flags), offset as usize)?;
+                Ok(MlnrNodeResult::FdRestored)
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+    use std::thread;
+
+    use super::*;
+
+    /// Readers sharing a file descriptor each get their own part of the file.
+    #[test]
+    fn concurrent_fd_advance() {
+        const THREADS: usize = 4;
+        const READS: usize = 32;
+        const CHUNK: usize = 8;
+
+        let node = Arc::new(MlnrKernelNode::default());
+        let pid = 1;
+        let flags = u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT);
+        let modes = u64::from(FileModes::S_IRWXU);
+        assert!(node
+            .dispatch_mut(Modify::ProcessAdd(pid, "/".into()))
+            .is_ok());
+        let fd = match node.dispatch_mut(Modify::FileOpen(pid, "file".into(), flags, modes)) {
+            Ok(MlnrNodeResult::FileOpened(fd)) => fd,
+            r => panic!("Unexpected response {:?}", r),
+        };
+        let mnode = MNODE_OFFSET as Mnode;
+
+        // Make the file a bit shorter than what all readers want in total.
+        let size = THREADS * READS * CHUNK - CHUNK / 2;
+        let data: Arc<[u8]> = alloc::vec![0xb; size].into();
+        let write = Modify::FileWrite(pid, fd, mnode, data, size as Len, 0);
+        assert!(node.dispatch_mut(write).is_ok());
+
+        let mut threads = Vec::with_capacity(THREADS);
+        for _t in 0..THREADS {
+            let node = node.clone();
+            threads.push(thread::spawn(move || {
+                let mut ranges = Vec::with_capacity(READS);
+                for _r in 0..READS {
+                    let advance = Modify::FdAdvance(pid, fd, mnode, CHUNK as Len);
+                    match node.dispatch_mut(advance) {
+                        Ok(MlnrNodeResult::FdAdvanced(offset, len)) => ranges.push((offset, len)),
+                        r => panic!("Unexpected response {:?}", r),
+                    }
+                }
+                ranges
+            }));
+        }
+
+        let mut ranges: Vec<(Offset, Len)> = threads
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .filter(|(_offset, len)| *len > 0)
+            .collect();
+        ranges.sort_unstable();
+
+        // The ranges cover the file exactly once:
+        let mut end = 0;
+        for (offset, len) in ranges {
+            assert_eq!(offset, end);
+            end += len as Offset;
+        }
+        assert_eq!(end, size as Offset);
+    }
+
+    /// Links of a confined process are resolved inside its root.
+    #[test]
+    fn confined_symlinks() {
+        let node = MlnrKernelNode::default();
+        let flags = u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT);
+        let modes = u64::from(FileModes::S_IRWXU);
+        assert!(node
+            .dispatch_mut(Modify::ProcessAdd(1, "/jail".into()))
+            .is_ok());
+
+        let link = Modify::Symlink(1, "/etc/../data".into(), "link".into());
+        assert!(matches!(
+            node.dispatch_mut(link),
+            Ok(MlnrNodeResult::SymlinkCreated)
+        ));
+        let open = Modify::FileOpen(1, "/link/./file".into(), flags, modes);
+        assert!(matches!(
+            node.dispatch_mut(open),
+            Ok(MlnrNodeResult::FileOpened(_))
+        ));
+        assert!(node.fs.lookup("/jail/data/file").is_some());
+        assert!(node.fs.lookup("/jail/link").is_some());
+
+        // Deleting the link leaves the target alone.
+        assert!(node
+            .dispatch_mut(Modify::FileDelete(1, "link".into()))
+            .is_ok());
+        assert!(node.fs.lookup("/jail/link").is_none());
+        assert!(node.fs.lookup("/jail/data/file").is_some());
+
+        let link = Modify::Symlink(1, "loop".into(), "loop".into());
+        assert!(node.dispatch_mut(link).is_ok());
+        let open = Modify::FileOpen(1, "loop".into(), flags, modes);
+        assert!(matches!(node.dispatch_mut(open), Err(KError::SymlinkLoop)));
+    }
+}
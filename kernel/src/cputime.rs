@@ -0,0 +1,146 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Accounting of the CPU time processes spend in user space and in the
+//! kernel (see `ProcessInfo::cpu_time`).
+//!
+//! Every core takes a timestamp whenever it enters the kernel (system calls,
+//! interrupts and exceptions) and whenever it resumes the process. The time
+//! in between is charged to the executor that runs on the core (the
+//! "thread") and to its process. Time a core spends in the kernel without an
+//! executor (idle, or before the first process starts) isn't charged to
+//! anyone.
+
+// The hooks are only called by the x86-64 kernel
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use kpi::process::CpuTime;
+
+use crate::arch::MAX_CORES;
+use crate::process::{Pid, MAX_PROCESSES};
+
+/// `CoreClock::pid` if the core doesn't run an executor.
+const NO_PROCESS: usize = usize::MAX;
+
+/// Time used (in TSC cycles).
+struct Usage {
+    user: AtomicU64,
+    kernel: AtomicU64,
+}
+
+impl Usage {
+    const fn new() -> Self {
+        Usage {
+            user: AtomicU64::new(0),
+            kernel: AtomicU64::new(0),
+        }
+    }
+
+    fn reset(&self) {
+        self.user.store(0, Ordering::Relaxed);
+        self.kernel.store(0, Ordering::Relaxed);
+    }
+
+    fn cpu_time(&self) -> CpuTime {
+        CpuTime {
+            user_us: crate::timer_wheel::cycles_to_us(self.user.load(Ordering::Relaxed)),
+            kernel_us: crate::timer_wheel::cycles_to_us(self.kernel.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Accounting state of a core (only updated by the core itself).
+struct CoreClock {
+    /// Process of the executor that runs on the core.
+    pid: AtomicUsize,
+    /// Last time the core entered or left the kernel.
+    since: AtomicU64,
+    /// Is the core in user space (of `pid`)?
+    in_user: AtomicBool,
+    /// Time used by the current executor.
+    thread: Usage,
+}
+
+impl CoreClock {
+    const fn new() -> Self {
+        CoreClock {
+            pid: AtomicUsize::new(NO_PROCESS),
+            since: AtomicU64::new(0),
+            in_user: AtomicBool::new(false),
+            thread: Usage::new(),
+        }
+    }
+
+    /// Returns the cycles since the last call (or `switch`).
+    fn lap(&self) -> u64 {
+        let now = unsafe { x86::time::rdtsc() };
+        now.saturating_sub(self.since.swap(now, Ordering::Relaxed))
+    }
+}
+
+static CORES: [CoreClock; MAX_CORES] = {
+    const CLOCK: CoreClock = CoreClock::new();
+    [CLOCK; MAX_CORES]
+};
+
+static PROCESSES: [Usage; MAX_PROCESSES] = {
+    const USAGE: Usage = Usage::new();
+    [USAGE; MAX_PROCESSES]
+};
+
+/// Starts accounting for a new process `pid`.
+pub fn reset(pid: Pid) {
+    PROCESSES[pid].reset();
+}
+
+/// A new executor of process `pid` (or none) runs on `core` from now on.
+pub fn switch(core: usize, pid: Option<Pid>) {
+    let clock = &CORES[core];
+    clock
+        .pid
+        .store(pid.unwrap_or(NO_PROCESS), Ordering::Relaxed);
+    clock.in_user.store(false, Ordering::Relaxed);
+    clock.thread.reset();
+    let _lap = clock.lap();
+}
+
+/// `core` entered the kernel, charges the time since it left the kernel as
+/// user time.
+pub fn enter_kernel(core: usize) {
+    let clock = &CORES[core];
+    if !clock.in_user.swap(false, Ordering::Relaxed) {
+        // A nested interrupt or the core doesn't run a process
+        return;
+    }
+    let cycles = clock.lap();
+    let pid = clock.pid.load(Ordering::Relaxed);
+    if let Some(process) = PROCESSES.get(pid) {
+        clock.thread.user.fetch_add(cycles, Ordering::Relaxed);
+        process.user.fetch_add(cycles, Ordering::Relaxed);
+    }
+}
+
+/// `core` resumes its executor, charges the time since it entered the
+/// kernel as kernel time.
+pub fn leave_kernel(core: usize) {
+    let clock = &CORES[core];
+    let cycles = clock.lap();
+    let pid = clock.pid.load(Ordering::Relaxed);
+    if let Some(process) = PROCESSES.get(pid) {
+        clock.thread.kernel.fetch_add(cycles, Ordering::Relaxed);
+        process.kernel.fetch_add(cycles, Ordering::Relaxed);
+        clock.in_user.store(true, Ordering::Relaxed);
+    }
+}
+
+/// CPU time used by process `pid` so far.
+pub fn process(pid: Pid) -> CpuTime {
+    PROCESSES[pid].cpu_time()
+}
+
+/// CPU time used by the executor that runs on `core` so far.
+pub fn thread(core: usize) -> CpuTime {
+    CORES[core].thread.cpu_time()
+}
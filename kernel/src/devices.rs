@@ -0,0 +1,335 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The devices of the machine and who drives them.
+//!
+//! The architecture discovers the devices at boot and records them with
+//! [`add`] (PCI functions, devices described by ACPI tables, legacy devices
+//! of the platform). Once the kernel can run drivers, [`bind`] offers every
+//! device to the in-kernel [`Driver`]s: the first one that matches it and
+//! attaches successfully drives it.
+//!
+//! A process with the `DEVICES` capability can claim a device that has no
+//! driver yet (`Process::claim_device`), e.g., to run a network driver in
+//! user-space. Only the driver of a device can map its memory
+//! ([`check_map`]), also nobody can map a device before it's claimed.
+//! Devices of processes are free again when they exit.
+//! `System::devices` lists all devices and their drivers.
+
+// Only the x86-64 kernel discovers devices
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use fallible_collections::FallibleVec;
+use kpi::system::{DeviceDriver, DeviceId, DeviceInfo, DeviceResource};
+use log::{info, warn};
+
+use crate::error::KError;
+use crate::nr;
+use crate::prelude::overlaps;
+use crate::process::Pid;
+use crate::sync::Mutex;
+
+/// A driver in the kernel.
+pub trait Driver: Sync {
+    /// Name of the driver (in `System::devices`).
+    fn name(&self) -> &'static str;
+
+    /// Can the driver drive `device`?
+    fn matches(&self, device: &DeviceInfo) -> bool;
+
+    /// Starts driving `device`.
+    fn attach(&self, device: &DeviceInfo) -> Result<(), KError>;
+}
+
+/// Who drives a device.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum Owner {
+    Kernel(&'static str),
+    /// A process (and its generation).
+    Process(Pid, u64),
+}
+
+struct Device {
+    info: DeviceInfo,
+    owner: Option<Owner>,
+}
+
+/// The devices of the machine (in the order they were found).
+struct Devices {
+    devices: Vec<Device>,
+}
+
+impl Devices {
+    const fn new() -> Devices {
+        Devices {
+            devices: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, mut info: DeviceInfo) -> Result<DeviceId, KError> {
+        let id = self.devices.len() as DeviceId;
+        info.id = id;
+        info.driver = DeviceDriver::None;
+        self.devices.try_push(Device { info, owner: None })?;
+        Ok(id)
+    }
+
+    /// Who drives device `idx` (`None` if its process is gone).
+    fn owner(&self, idx: usize, alive: impl Fn(Pid, u64) -> bool) -> Option<Owner> {
+        match self.devices[idx].owner {
+            Some(Owner::Process(pid, generation)) if !alive(pid, generation) => None,
+            owner => owner,
+        }
+    }
+
+    fn claim(
+        &mut self,
+        id: DeviceId,
+        owner: Owner,
+        alive: impl Fn(Pid, u64) -> bool,
+    ) -> Result<(), KError> {
+        let idx = id as usize;
+        if idx >= self.devices.len() {
+            return Err(KError::NoSuchDevice { id });
+        }
+        match self.owner(idx, alive) {
+            Some(current) if current != owner => Err(KError::DeviceInUse { id }),
+            _ => {
+                self.devices[idx].owner = Some(owner);
+                Ok(())
+            }
+        }
+    }
+
+    /// Fails if `[base, base + size)` overlaps memory of a device that
+    /// `pid` (of `generation`) doesn't drive (or nobody drives).
+    fn check_map(
+        &self,
+        pid: Pid,
+        generation: u64,
+        base: u64,
+        size: u64,
+        alive: impl Fn(Pid, u64) -> bool,
+    ) -> Result<(), KError> {
+        let end = base
+            .checked_add(size)
+            .ok_or(KError::BaseOverflow { base })?;
+        for (idx, device) in self.devices.iter().enumerate() {
+            let mapped = device.info.resources.iter().any(|resource| match resource {
+                DeviceResource::Memory {
+                    base: start,
+                    size: len,
+                } => overlaps(&(base..end), &(*start..start.saturating_add(*len))),
+                DeviceResource::IoPorts { .. } => false,
+            });
+            if !mapped {
+                continue;
+            }
+            match self.owner(idx, &alive) {
+                None => return Err(KError::DeviceNotClaimed { id: device.info.id }),
+                Some(Owner::Process(owner, owner_generation))
+                    if (owner, owner_generation) == (pid, generation) => {}
+                Some(_other) => return Err(KError::DeviceInUse { id: device.info.id }),
+            }
+        }
+        Ok(())
+    }
+
+    fn list(&self, alive: impl Fn(Pid, u64) -> bool) -> Result<Vec<DeviceInfo>, KError> {
+        let mut list = Vec::new();
+        for (idx, device) in self.devices.iter().enumerate() {
+            let mut info = device.info.clone();
+            info.driver = match self.owner(idx, &alive) {
+                None => DeviceDriver::None,
+                Some(Owner::Kernel(name)) => DeviceDriver::Kernel(String::from(name)),
+                Some(Owner::Process(pid, _generation)) => DeviceDriver::Process(pid as u64),
+            };
+            list.try_push(info)?;
+        }
+        Ok(list)
+    }
+}
+
+static DEVICES: Mutex<Devices> = Mutex::new(Devices::new());
+
+/// Records a device the architecture found, returns its id.
+pub fn add(info: DeviceInfo) -> Result<DeviceId, KError> {
+    DEVICES.lock().add(info)
+}
+
+/// Attaches the first matching driver of `drivers` to every device that
+/// doesn't have a driver yet.
+pub fn bind(drivers: &[&dyn Driver]) {
+    let unbound: Vec<DeviceInfo> = {
+        let devices = DEVICES.lock();
+        devices
+            .devices
+            .iter()
+            .filter(|device| device.owner.is_none())
+            .map(|device| device.info.clone())
+            .collect()
+    };
+
+    // Drivers can take a while to attach, don't hold the lock
+    for device in unbound.iter() {
+        for driver in drivers.iter().filter(|d| d.matches(device)) {
+            match driver
+                .attach(device)
+                .and_then(|_r| attach(device.id, driver.name()))
+            {
+                Ok(()) => break,
+                Err(e) => warn!("{} can't drive {}: {}", driver.name(), device.name, e),
+            }
+        }
+    }
+}
+
+/// Makes `driver` (of the kernel) the driver of device `id`, e.g., for
+/// drivers that start on demand.
+pub fn attach(id: DeviceId, driver: &'static str) -> Result<(), KError> {
+    DEVICES
+        .lock()
+        .claim(id, Owner::Kernel(driver), nr::KernelNode::is_alive)?;
+    info!("Device {} driven by {}", id, driver);
+    Ok(())
+}
+
+/// The first device `f` is true for, e.g., for drivers that start on
+/// demand.
+pub fn find_by(f: impl Fn(&DeviceInfo) -> bool) -> Option<DeviceInfo> {
+    let devices = DEVICES.lock();
+    devices
+        .devices
+        .iter()
+        .find(|device| f(&device.info))
+        .map(|device| device.info.clone())
+}
+
+/// Makes `pid` the driver of device `id`.
+pub fn claim(pid: Pid, id: DeviceId) -> Result<(), KError> {
+    let generation = nr::KernelNode::process_generation(pid)?;
+    DEVICES.lock().claim(
+        id,
+        Owner::Process(pid, generation),
+        nr::KernelNode::is_alive,
+    )?;
+    info!("Process {} drives device {}", pid, id);
+    Ok(())
+}
+
+/// Fails if `pid` can't map the physical memory `[base, base + size)`
+/// because it belongs to a device with another driver (or one nobody
+/// claimed).
+pub fn check_map(pid: Pid, base: u64, size: u64) -> Result<(), KError> {
+    let generation = nr::KernelNode::process_generation(pid)?;
+    DEVICES
+        .lock()
+        .check_map(pid, generation, base, size, nr::KernelNode::is_alive)
+}
+
+/// All devices (`System::devices`).
+pub fn list() -> Result<Vec<DeviceInfo>, KError> {
+    DEVICES.lock().list(nr::KernelNode::is_alive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kpi::system::DeviceLocation;
+
+    fn device(name: &str, base: u64) -> DeviceInfo {
+        DeviceInfo {
+            id: 0,
+            name: String::from(name),
+            location: DeviceLocation::Platform,
+            vendor_id: 0,
+            device_id: 0,
+            class: 0,
+            subclass: 0,
+            resources: alloc::vec![DeviceResource::Memory { base, size: 0x1000 }],
+            driver: DeviceDriver::None,
+        }
+    }
+
+    #[test]
+    fn claim_devices() {
+        let mut devices = Devices::new();
+        let nic = devices.add(device("nic", 0x8000_0000)).expect("Has room");
+        let disk = devices.add(device("disk", 0x8000_1000)).expect("Has room");
+        assert_eq!((nic, disk), (0, 1));
+
+        let alive = |pid, _generation| pid != 3;
+        devices
+            .claim(nic, Owner::Kernel("vmxnet3"), alive)
+            .expect("Unbound");
+        assert_eq!(
+            devices.claim(nic, Owner::Process(1, 0), alive),
+            Err(KError::DeviceInUse { id: nic })
+        );
+        assert_eq!(
+            devices.claim(7, Owner::Process(1, 0), alive),
+            Err(KError::NoSuchDevice { id: 7 })
+        );
+
+        // Processes that are gone don't drive anything
+        devices
+            .claim(disk, Owner::Process(3, 0), alive)
+            .expect("Unbound");
+        devices
+            .claim(disk, Owner::Process(1, 0), alive)
+            .expect("Driver is gone");
+        devices
+            .claim(disk, Owner::Process(1, 0), alive)
+            .expect("Already drives it");
+
+        let list = devices.list(alive).expect("Has memory");
+        assert_eq!(
+            list[0].driver,
+            DeviceDriver::Kernel(String::from("vmxnet3"))
+        );
+        assert_eq!(list[1].driver, DeviceDriver::Process(1));
+        assert_eq!(list[1].id, disk);
+    }
+
+    #[test]
+    fn map_device_memory() {
+        let mut devices = Devices::new();
+        let nic = devices.add(device("nic", 0x8000_0000)).expect("Has room");
+        let disk = devices.add(device("disk", 0x8000_1000)).expect("Has room");
+        let alive = |_pid, _generation| true;
+
+        // Unclaimed devices can't be mapped
+        assert_eq!(
+            devices.check_map(1, 0, 0x8000_0000, 0x1000, alive),
+            Err(KError::DeviceNotClaimed { id: nic })
+        );
+        // Memory that isn't a device can
+        assert!(devices.check_map(1, 0, 0x9000_0000, 0x1000, alive).is_ok());
+        assert_eq!(
+            devices.check_map(1, 0, u64::MAX - 0xfff, 0x2000, alive),
+            Err(KError::BaseOverflow {
+                base: u64::MAX - 0xfff
+            })
+        );
+
+        devices
+            .claim(nic, Owner::Process(1, 0), alive)
+            .expect("Unbound");
+        assert!(devices.check_map(1, 0, 0x8000_0000, 0x1000, alive).is_ok());
+        // The disk next to it isn't claimed
+        assert!(devices.check_map(1, 0, 0x8000_0000, 0x2000, alive).is_err());
+        assert_eq!(
+            devices.check_map(2, 0, 0x8000_0800, 0x1000, alive),
+            Err(KError::DeviceInUse { id: nic })
+        );
+        // Same pid, another process
+        assert!(devices.check_map(1, 1, 0x8000_0000, 0x1000, alive).is_err());
+        devices
+            .claim(disk, Owner::Process(2, 0), alive)
+            .expect("Unbound");
+        assert!(devices.check_map(2, 0, 0x8000_1000, 0x1000, alive).is_ok());
+    }
+}
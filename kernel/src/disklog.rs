@@ -0,0 +1,439 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Keeps the tail of the kernel log on a disk, so it survives a crash or a
+//! reboot of machines whose serial console nobody captures.
+//!
+//! `logdisk=<disk>:<first sector>:<sectors>` on the command line reserves a
+//! range of sectors of a disk (see `block`) for the log, e.g., a partition
+//! nothing else uses. With it the kernel keeps a copy of every log line (and
+//! the output of processes) from boot on and writes it to the disk every
+//! [`FLUSH_INTERVAL`] once the disk driver is up, on panics and before it
+//! shuts down.
+//!
+//! Every sector is a record: [`MAGIC`], a sequence number (`u64`), the
+//! length of the text (`u16`), two reserved bytes, the CRC-32 of all of it
+//! and up to [`PAYLOAD`] bytes of text (little-endian). Record `n` is in
+//! sector `first + n % sectors`, so the newest records overwrite the oldest
+//! ones. The last record is rewritten until it's full, a sector write either
+//! happens or doesn't (and a torn one fails the CRC), so a crash loses at
+//! most what wasn't flushed yet. After a reboot the log continues after the
+//! newest record on the disk. `scripts/disklog.py` prints the log of a disk
+//! (image): the valid records ordered by sequence number.
+
+// Only the x86-64 kernel has disk drivers
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use arrayvec::ArrayVec;
+use log::{info, warn};
+
+use crate::block::{self, BlockDevice, SECTOR_SIZE};
+use crate::error::KError;
+use crate::sync::Mutex;
+use crate::timer_wheel;
+
+/// Marks a sector as a record.
+pub const MAGIC: [u8; 8] = *b"NRKLOG01";
+
+/// Bytes before the text of a record.
+const HEADER: usize = 24;
+
+/// Most text a record holds.
+pub const PAYLOAD: usize = SECTOR_SIZE - HEADER;
+
+/// How often the log is written to the disk.
+pub const FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Most records a periodic flush writes (disk writes are polled, they
+/// delay everything else on the core).
+const MAX_RECORDS: usize = 8;
+
+/// Size of the buffer for output that isn't on the disk yet.
+const PENDING_SIZE: usize = 64 * 1024;
+
+/// Where the log goes.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Area {
+    pub disk: usize,
+    pub first: u64,
+    pub sectors: u64,
+}
+
+impl Area {
+    /// Parses `<disk>:<first sector>:<sectors>`.
+    pub fn parse(spec: &str) -> Option<Area> {
+        let mut parts = spec.split(':');
+        let area = Area {
+            disk: parts.next()?.parse().ok()?,
+            first: parts.next()?.parse().ok()?,
+            sectors: parts.next()?.parse().ok()?,
+        };
+        if parts.next().is_some() || area.sectors == 0 {
+            return None;
+        }
+        Some(area)
+    }
+
+    fn sector(&self, seq: u64) -> u64 {
+        self.first + seq % self.sectors
+    }
+}
+
+/// CRC-32 (as zlib computes it).
+fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _bit in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Writes record `seq` with `text` to `sector`.
+fn encode(seq: u64, text: &[u8], sector: &mut [u8; SECTOR_SIZE]) {
+    sector.fill(0);
+    sector[0..8].copy_from_slice(&MAGIC);
+    sector[8..16].copy_from_slice(&seq.to_le_bytes());
+    sector[16..18].copy_from_slice(&(text.len() as u16).to_le_bytes());
+    sector[HEADER..HEADER + text.len()].copy_from_slice(text);
+    let crc = crc32(crc32(0, &sector[0..20]), text);
+    sector[20..24].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// The sequence number and text of the record in `sector` (`None` if it
+/// isn't a valid record).
+fn decode(sector: &[u8]) -> Option<(u64, &[u8])> {
+    if sector.len() < SECTOR_SIZE || sector[0..8] != MAGIC {
+        return None;
+    }
+    let mut seq = [0u8; 8];
+    seq.copy_from_slice(&sector[8..16]);
+    let len = u16::from_le_bytes([sector[16], sector[17]]) as usize;
+    let crc = u32::from_le_bytes([sector[20], sector[21], sector[22], sector[23]]);
+    if len > PAYLOAD {
+        return None;
+    }
+    let text = &sector[HEADER..HEADER + len];
+    if crc32(crc32(0, &sector[0..20]), text) != crc {
+        return None;
+    }
+    Some((u64::from_le_bytes(seq), text))
+}
+
+/// Appends to the log on a disk.
+pub struct Writer {
+    area: Area,
+    /// Sequence number of the record we fill.
+    seq: u64,
+    /// Its text.
+    record: ArrayVec<u8, PAYLOAD>,
+}
+
+impl Writer {
+    /// Finds the newest record on `disk`, the log continues in a new record
+    /// after it.
+    pub fn open(disk: &mut dyn BlockDevice, area: Area) -> Result<Writer, KError> {
+        block::check(disk, area.first, (area.sectors as usize) * SECTOR_SIZE)?;
+        let mut sector = [0u8; SECTOR_SIZE];
+        let mut newest = None;
+        for idx in 0..area.sectors {
+            disk.read(area.first + idx, &mut sector)?;
+            if let Some((seq, _text)) = decode(&sector) {
+                newest = newest.max(Some(seq));
+            }
+        }
+        Ok(Writer {
+            area,
+            seq: newest.map_or(0, |seq| seq + 1),
+            record: ArrayVec::new(),
+        })
+    }
+
+    /// Appends `text` to the log and writes the records it went into.
+    pub fn append(&mut self, disk: &mut dyn BlockDevice, mut text: &[u8]) -> Result<(), KError> {
+        let mut sector = [0u8; SECTOR_SIZE];
+        while !text.is_empty() {
+            let len = core::cmp::min(text.len(), self.record.remaining_capacity());
+            let _r = self.record.try_extend_from_slice(&text[..len]);
+            text = &text[len..];
+
+            encode(self.seq, &self.record, &mut sector);
+            disk.write(self.area.sector(self.seq), &sector)?;
+            if self.record.is_full() {
+                self.seq += 1;
+                self.record.clear();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Output that isn't on the disk yet.
+struct Pending {
+    data: ArrayVec<u8, PENDING_SIZE>,
+    /// Oldest bytes we dropped to make room.
+    lost: usize,
+}
+
+impl fmt::Write for Pending {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // The newest output matters most, drop the oldest (a quarter at once
+        // to not move the rest for every line)
+        let s = &s.as_bytes()[s.len().saturating_sub(PENDING_SIZE)..];
+        if s.len() > self.data.remaining_capacity() {
+            let oldest = core::cmp::min(
+                self.data.len(),
+                core::cmp::max(s.len() - self.data.remaining_capacity(), PENDING_SIZE / 4),
+            );
+            self.data.drain(..oldest);
+            self.lost += oldest;
+        }
+        let _r = self.data.try_extend_from_slice(s);
+        Ok(())
+    }
+}
+
+static PENDING: Mutex<Pending> = Mutex::new(Pending {
+    data: ArrayVec::new_const(),
+    lost: 0,
+});
+
+/// Is the log kept?
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static AREA: spin::Once<Area> = spin::Once::new();
+
+static WRITER: Mutex<Option<Writer>> = Mutex::new(None);
+
+/// Keeps the log in `spec` (see [`Area::parse`]) from now on, returns false
+/// if `spec` is invalid.
+pub fn init(spec: &str) -> bool {
+    match Area::parse(spec) {
+        Some(area) => {
+            AREA.call_once(|| area);
+            ENABLED.store(true, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Keeps a copy of `args` to write it to the disk.
+pub fn mirror(args: fmt::Arguments) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    // Don't spin if we interrupted ourselves (the output gets lost)
+    if let Some(mut pending) = PENDING.try_lock() {
+        let _r = pending.write_fmt(args);
+    }
+}
+
+/// Starts writing the log to the disk, call once the disk drivers are up.
+pub fn start() {
+    let area = match AREA.get() {
+        Some(area) => *area,
+        None => return,
+    };
+    match block::with_disk(area.disk, |disk| Writer::open(disk, area)) {
+        Some(Ok(writer)) => {
+            info!(
+                "Kernel log on disk {} sectors {}..{} (from record {})",
+                area.disk,
+                area.first,
+                area.first + area.sectors,
+                writer.seq
+            );
+            *WRITER.lock() = Some(writer);
+        }
+        Some(Err(e)) => {
+            warn!("Can't keep the kernel log on disk {}: {}", area.disk, e);
+            ENABLED.store(false, Ordering::Relaxed);
+            return;
+        }
+        None => {
+            warn!(
+                "Can't keep the kernel log on disk {}: no such disk",
+                area.disk
+            );
+            ENABLED.store(false, Ordering::Relaxed);
+            return;
+        }
+    }
+
+    flush();
+    if let Err(e) = timer_wheel::add(FLUSH_INTERVAL, flush_periodically, 0) {
+        warn!("Kernel log only written to disk on shutdown: {}", e);
+    }
+}
+
+fn flush_periodically(_arg: u64) {
+    flush_records(MAX_RECORDS);
+    if let Err(e) = timer_wheel::add(FLUSH_INTERVAL, flush_periodically, 0) {
+        warn!("Kernel log no longer written to disk: {}", e);
+    }
+}
+
+/// Writes everything that isn't on the disk yet (e.g., before a shutdown).
+///
+/// Does nothing if the disk or the log is in use (e.g., if we panicked while
+/// writing it).
+pub fn flush() {
+    flush_records(usize::MAX);
+}
+
+/// Writes at most `records` records worth of output.
+fn flush_records(records: usize) {
+    let mut writer = match WRITER.try_lock() {
+        Some(writer) => writer,
+        None => return,
+    };
+    let writer = match writer.as_mut() {
+        Some(writer) => writer,
+        None => return,
+    };
+
+    let mut text = [0u8; PAYLOAD];
+    for _i in 0..records {
+        // Only take output once we have the disk, it stays pending otherwise
+        let written = block::try_with_disk(writer.area.disk, |disk| {
+            // Don't hold the lock while we write: the disk driver logs too
+            let (len, lost) = match PENDING.try_lock() {
+                Some(mut pending) => {
+                    let len =
+                        core::cmp::min(pending.data.len(), writer.record.remaining_capacity());
+                    text[..len].copy_from_slice(&pending.data[..len]);
+                    pending.data.drain(..len);
+                    (len, core::mem::replace(&mut pending.lost, 0))
+                }
+                None => return Ok(false),
+            };
+            if len == 0 && lost == 0 {
+                return Ok(false);
+            }
+
+            let mut append = || {
+                if lost > 0 {
+                    let mut notice = arrayvec::ArrayString::<64>::new();
+                    let _r = writeln!(notice, "[disklog: {} bytes lost]", lost);
+                    writer.append(disk, notice.as_bytes())?;
+                }
+                writer.append(disk, &text[..len])
+            };
+            let written = append();
+            if written.is_err() {
+                // Report it with the next record that makes it (we got the
+                // lock above, so this core doesn't hold it)
+                PENDING.lock().lost += lost + len;
+            }
+            written.map(|_r| true)
+        });
+        match written {
+            Some(Ok(true)) => {}
+            Some(Ok(false)) | Some(Err(_e)) | None => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// The log on `disk` (what `scripts/disklog.py` prints).
+    fn recover(disk: &mut dyn BlockDevice, area: Area) -> Vec<u8> {
+        let mut records = Vec::new();
+        let mut sector = [0u8; SECTOR_SIZE];
+        for idx in 0..area.sectors {
+            disk.read(area.first + idx, &mut sector).expect("Fits");
+            if let Some((seq, text)) = decode(&sector) {
+                records.push((seq, text.to_vec()));
+            }
+        }
+        records.sort();
+        records.into_iter().flat_map(|(_seq, text)| text).collect()
+    }
+
+    #[test]
+    fn records() {
+        assert_eq!(crc32(0, b"123456789"), 0xcbf4_3926);
+
+        let mut sector = [0u8; SECTOR_SIZE];
+        encode(7, b"hello", &mut sector);
+        assert_eq!(decode(&sector), Some((7, &b"hello"[..])));
+        sector[HEADER] = b'j';
+        assert_eq!(decode(&sector), None);
+        assert_eq!(decode(&[0; SECTOR_SIZE]), None);
+
+        assert_eq!(
+            Area::parse("1:2048:64"),
+            Some(Area {
+                disk: 1,
+                first: 2048,
+                sectors: 64
+            })
+        );
+        assert_eq!(Area::parse("1:2048"), None);
+        assert_eq!(Area::parse("1:2048:0"), None);
+    }
+
+    #[test]
+    fn pending_keeps_newest() {
+        let mut pending = Pending {
+            data: ArrayVec::new(),
+            lost: 0,
+        };
+        let line = "x".repeat(1000) + "\n";
+        for _i in 0..PENDING_SIZE / line.len() {
+            pending.write_str(&line).expect("Can't fail");
+        }
+        assert_eq!(pending.lost, 0);
+        pending.write_str(&line).expect("Can't fail");
+        pending.write_str("newest\n").expect("Can't fail");
+        assert_eq!(pending.lost, PENDING_SIZE / 4);
+        assert!(pending.data.ends_with(b"x\nnewest\n"));
+    }
+
+    #[test]
+    fn ring() {
+        let mut disk = block::MemDisk::new(16).expect("Has memory");
+        let area = Area {
+            disk: 0,
+            first: 4,
+            sectors: 8,
+        };
+
+        let mut writer = Writer::open(&mut disk, area).expect("Fits");
+        assert_eq!(writer.seq, 0);
+        writer.append(&mut disk, b"boot 1\n").expect("Fits");
+        writer.append(&mut disk, b"more\n").expect("Fits");
+        assert_eq!(recover(&mut disk, area), b"boot 1\nmore\n");
+
+        // Continues after a reboot, the oldest records are overwritten
+        let mut writer = Writer::open(&mut disk, area).expect("Fits");
+        assert_eq!(writer.seq, 1);
+        let line = [b'x'; PAYLOAD];
+        for _i in 0..8 {
+            writer.append(&mut disk, &line).expect("Fits");
+        }
+        writer.append(&mut disk, b"tail\n").expect("Fits");
+        let log = recover(&mut disk, area);
+        assert_eq!(log.len(), 7 * PAYLOAD + 5);
+        assert!(log.ends_with(b"xtail\n"));
+
+        // Nothing outside of the area
+        let mut sector = [0u8; SECTOR_SIZE];
+        disk.read(12, &mut sector).expect("Fits");
+        assert_eq!(decode(&sector), None);
+    }
+}
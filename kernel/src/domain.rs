@@ -0,0 +1,217 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Panic domains: subsystems that can fail without taking down the kernel.
+//!
+//! Non-critical subsystems (the network stack, the framebuffer) run their
+//! code with [`Domain::enter`]. If it panics, the panic handler doesn't halt
+//! the machine but jumps back to where the core entered the domain (see
+//! [`recover`]): the domain is marked as failed, `enter` returns
+//! `KError::SubsystemFailed` and so does every later call to it.
+//!
+//! The kernel is built with `panic=abort`, nothing is unwound: the
+//! destructors of the frames we jump over don't run. Their memory leaks and
+//! locks they hold stay locked, this is why a failed domain doesn't run again.
+//! A domain shouldn't hold locks that the rest of the kernel needs. Panics in
+//! nested domains fail the innermost one.
+//!
+//! Some panics can't be recovered and take down the kernel as usual: panics
+//! in an interrupt or exception handler that interrupted the domain (we'd
+//! jump off its stack without finishing it) and panics while the core holds
+//! a [`Pin`] it didn't hold when it entered the domain (the allocator, NR
+//! replicas and kernel-global locks take one, jumping over them would leave
+//! them locked for everyone).
+
+use core::cell::Cell;
+use core::ops::{Deref, DerefMut};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use klogger::sprintln;
+
+use crate::arch::domain::{self as arch_domain, Context};
+use crate::error::KError;
+use crate::kcb;
+
+/// The network stack (see `arch::network::Network`).
+pub static NETWORK: Domain = Domain::new("network");
+
+/// The boot progress on the framebuffer (see `arch::splash`).
+pub static GRAPHICS: Domain = Domain::new("graphics");
+
+/// A subsystem that is disabled once it panicked.
+#[derive(Debug)]
+pub struct Domain {
+    name: &'static str,
+    failed: AtomicBool,
+}
+
+/// A core that is in a domain (on its stack, see [`Domain::enter`]).
+pub struct Frame {
+    domain: &'static Domain,
+    context: Context,
+    /// The domain we were in before we entered this one.
+    outer: Option<&'static Frame>,
+    /// Interrupt depth of the core when it entered the domain.
+    irq_depth: usize,
+    /// Pins the core held when it entered the domain.
+    pins: usize,
+}
+
+/// Marks code that holds state the rest of the kernel needs: a panic while
+/// the core holds a `Pin` isn't recovered by a domain (see [`pin`]).
+pub struct Pin {
+    pinned: bool,
+}
+
+/// Pins the current core until the returned [`Pin`] is dropped.
+pub fn pin() -> Pin {
+    let kcb = kcb::try_get_kcb();
+    if let Some(kcb) = kcb.as_ref() {
+        kcb.pin();
+    }
+    Pin {
+        pinned: kcb.is_some(),
+    }
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        if self.pinned {
+            if let Some(kcb) = kcb::try_get_kcb() {
+                kcb.unpin();
+            }
+        }
+    }
+}
+
+/// `value` (e.g., a lock guard) with a [`Pin`] for as long as it lives.
+pub struct Pinned<T> {
+    value: T,
+    _pin: Pin,
+}
+
+impl<T> Pinned<T> {
+    pub fn new(value: T) -> Pinned<T> {
+        Pinned { value, _pin: pin() }
+    }
+}
+
+impl<T> Deref for Pinned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for Pinned<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+/// The frame of the innermost domain the current core is in (stored in the
+/// KCB).
+pub type CurrentFrame = Cell<Option<&'static Frame>>;
+
+impl Domain {
+    pub const fn new(name: &'static str) -> Domain {
+        Domain {
+            name,
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    /// Did the subsystem panic?
+    pub fn has_failed(&self) -> bool {
+        self.failed.load(Ordering::Acquire)
+    }
+
+    /// Fails with `KError::SubsystemFailed` if the subsystem panicked.
+    pub fn check(&self) -> Result<(), KError> {
+        if self.has_failed() {
+            Err(KError::SubsystemFailed { name: self.name })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Runs `f` in the domain, fails with `KError::SubsystemFailed` if it
+    /// panics or panicked before.
+    pub fn enter<F, R>(&'static self, f: F) -> Result<R, KError>
+    where
+        F: FnOnce() -> R,
+    {
+        extern "C" fn run<F: FnOnce() -> R, R>(arg: *mut u8) {
+            let (f, result) = unsafe { &mut *(arg as *mut (Option<F>, Option<R>)) };
+            *result = f.take().map(|f| f());
+        }
+
+        self.check()?;
+        let kcb = match kcb::try_get_kcb() {
+            Some(kcb) => kcb,
+            // Too early during boot to recover from a panic
+            None => return Ok(f()),
+        };
+        let current = &kcb.domain;
+        let mut frame = Frame {
+            domain: self,
+            context: Default::default(),
+            outer: current.get(),
+            irq_depth: kcb.irq_depth(),
+            pins: kcb.pins(),
+        };
+        let mut state: (Option<F>, Option<R>) = (Some(f), None);
+
+        // Safe: we take the frame out of the KCB before it goes away
+        current.set(Some(unsafe { &*(&frame as *const Frame) }));
+        let returned = unsafe {
+            arch_domain::call(
+                &mut frame.context,
+                run::<F, R>,
+                &mut state as *mut (Option<F>, Option<R>) as *mut u8,
+            )
+        };
+        current.set(frame.outer);
+
+        match state.1 {
+            Some(result) if returned => Ok(result),
+            _ => Err(KError::SubsystemFailed { name: self.name }),
+        }
+    }
+}
+
+/// Called by the panic handler: if the current core is in a domain, fails the
+/// domain and returns from its [`Domain::enter`]. Returns if it isn't or if
+/// the panic can't be recovered (see the module documentation).
+pub fn recover(info: &PanicInfo) {
+    if arch_domain::in_nmi() {
+        return;
+    }
+    let kcb = match kcb::try_get_kcb() {
+        Some(kcb) => kcb,
+        None => return,
+    };
+    if let Some(frame) = kcb.domain.get() {
+        if kcb.irq_depth() != frame.irq_depth || kcb.pins() != frame.pins {
+            sprintln!(
+                "Subsystem {} panicked in an interrupt handler or while holding kernel state, can't recover",
+                frame.domain.name
+            );
+            return;
+        }
+
+        kcb.domain.take();
+        // A panic while we report this one brings down the kernel (or the
+        // outer domain)
+        kcb.domain.set(frame.outer);
+        frame.domain.failed.store(true, Ordering::Release);
+        sprintln!(
+            "Subsystem {} panicked ({}), it's disabled from now on",
+            frame.domain.name,
+            info
+        );
+        unsafe { arch_domain::resume(&frame.context) }
+    }
+}
@@ -0,0 +1,375 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Sanity checks for the ELF binaries we turn into processes.
+//!
+//! elfloader trusts the headers of a binary, a module that got truncated on
+//! its way to the ESP (or isn't an x86-64 executable at all) loads "fine"
+//! and the process crashes somewhere later. [`verify`] rejects such a binary
+//! before we load it, with an [`ElfProblem`] that says what is wrong:
+//!
+//! - The header: 64-bit, little-endian, current version, x86-64, an
+//!   executable or a position independent executable.
+//! - The program headers are inside the file and there is at least one
+//!   `PT_LOAD` segment.
+//! - Every `PT_LOAD` segment is inside the file, doesn't have more file
+//!   than memory bytes, has a consistent alignment and doesn't overlap
+//!   another one.
+//! - The entry point is in an executable segment.
+
+use core::convert::TryFrom;
+use core::fmt;
+
+use log::warn;
+
+use crate::error::KError;
+
+/// Size of the ELF64 file header.
+const EHDR_SIZE: usize = 64;
+/// Size of an ELF64 program header.
+const PHDR_SIZE: usize = 56;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+
+/// Why a binary can't be loaded.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ElfProblem {
+    /// The file is shorter than the ELF header.
+    Truncated {
+        len: usize,
+    },
+    NotElf,
+    Not64Bit,
+    NotLittleEndian,
+    UnknownVersion {
+        version: u8,
+    },
+    WrongMachine {
+        machine: u16,
+    },
+    NotExecutable {
+        kind: u16,
+    },
+    /// The program header table isn't (completely) in the file.
+    ProgramHeadersOutOfBounds,
+    NoLoadableSegments,
+    /// The file bytes of a segment aren't (completely) in the file.
+    SegmentOutOfBounds {
+        segment: usize,
+    },
+    /// A segment has more file than memory bytes (or wraps around).
+    SegmentSize {
+        segment: usize,
+    },
+    SegmentAlignment {
+        segment: usize,
+    },
+    OverlappingSegments {
+        first: usize,
+        second: usize,
+    },
+    EntryNotInText {
+        entry: u64,
+    },
+}
+
+impl fmt::Display for ElfProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfProblem::Truncated { len } => {
+                write!(f, "only {} bytes, too short for an ELF header", len)
+            }
+            ElfProblem::NotElf => write!(f, "no ELF magic"),
+            ElfProblem::Not64Bit => write!(f, "not a 64-bit ELF file"),
+            ElfProblem::NotLittleEndian => write!(f, "not little-endian"),
+            ElfProblem::UnknownVersion { version } => write!(f, "unknown ELF version {}", version),
+            ElfProblem::WrongMachine { machine } => {
+                write!(f, "built for machine {}, not x86-64", machine)
+            }
+            ElfProblem::NotExecutable { kind } => write!(f, "not an executable (type {})", kind),
+            ElfProblem::ProgramHeadersOutOfBounds => {
+                write!(f, "program headers are outside of the file")
+            }
+            ElfProblem::NoLoadableSegments => write!(f, "no loadable segments"),
+            ElfProblem::SegmentOutOfBounds { segment } => {
+                write!(f, "segment {} is outside of the file (truncated?)", segment)
+            }
+            ElfProblem::SegmentSize { segment } => write!(f, "segment {} has a bad size", segment),
+            ElfProblem::SegmentAlignment { segment } => {
+                write!(f, "segment {} has a bad alignment", segment)
+            }
+            ElfProblem::OverlappingSegments { first, second } => {
+                write!(f, "segments {} and {} overlap", first, second)
+            }
+            ElfProblem::EntryNotInText { entry } => {
+                write!(
+                    f,
+                    "entry point {:#x} is not in an executable segment",
+                    entry
+                )
+            }
+        }
+    }
+}
+
+fn u16_at(image: &[u8], offset: usize) -> u16 {
+    let mut bytes = [0; 2];
+    bytes.copy_from_slice(&image[offset..offset + 2]);
+    u16::from_le_bytes(bytes)
+}
+
+fn u32_at(image: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&image[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn u64_at(image: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&image[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// A `PT_LOAD` program header.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+impl Segment {
+    fn parse(phdr: &[u8]) -> Segment {
+        Segment {
+            flags: u32_at(phdr, 4),
+            offset: u64_at(phdr, 8),
+            vaddr: u64_at(phdr, 16),
+            filesz: u64_at(phdr, 32),
+            memsz: u64_at(phdr, 40),
+            align: u64_at(phdr, 48),
+        }
+    }
+
+    fn check(&self, segment: usize, file_len: u64) -> Result<(), ElfProblem> {
+        match self.offset.checked_add(self.filesz) {
+            Some(end) if end <= file_len => {}
+            _ => return Err(ElfProblem::SegmentOutOfBounds { segment }),
+        }
+        if self.filesz > self.memsz || self.vaddr.checked_add(self.memsz).is_none() {
+            return Err(ElfProblem::SegmentSize { segment });
+        }
+        // 0 and 1 mean no alignment
+        if self.align > 1
+            && (!self.align.is_power_of_two()
+                || self.vaddr % self.align != self.offset % self.align)
+        {
+            return Err(ElfProblem::SegmentAlignment { segment });
+        }
+        Ok(())
+    }
+
+    /// The (virtual) addresses of the segment.
+    fn range(&self) -> core::ops::Range<u64> {
+        self.vaddr..self.vaddr + self.memsz
+    }
+}
+
+/// Checks the headers of the ELF binary in `image`.
+pub fn check(image: &[u8]) -> Result<(), ElfProblem> {
+    if image.len() < EHDR_SIZE {
+        return Err(ElfProblem::Truncated { len: image.len() });
+    }
+    if &image[..4] != ELF_MAGIC {
+        return Err(ElfProblem::NotElf);
+    }
+    if image[4] != ELFCLASS64 {
+        return Err(ElfProblem::Not64Bit);
+    }
+    if image[5] != ELFDATA2LSB {
+        return Err(ElfProblem::NotLittleEndian);
+    }
+    if image[6] != EV_CURRENT {
+        return Err(ElfProblem::UnknownVersion { version: image[6] });
+    }
+    let kind = u16_at(image, 16);
+    if kind != ET_EXEC && kind != ET_DYN {
+        return Err(ElfProblem::NotExecutable { kind });
+    }
+    let machine = u16_at(image, 18);
+    if machine != EM_X86_64 {
+        return Err(ElfProblem::WrongMachine { machine });
+    }
+
+    let entry = u64_at(image, 24);
+    let phoff = u64_at(image, 32);
+    let phentsize = u16_at(image, 54) as usize;
+    let phnum = u16_at(image, 56) as usize;
+    if phnum > 0 && phentsize != PHDR_SIZE {
+        return Err(ElfProblem::ProgramHeadersOutOfBounds);
+    }
+    let phdrs = usize::try_from(phoff)
+        .ok()
+        .and_then(|start| Some((start, start.checked_add(phnum * PHDR_SIZE)?)))
+        .and_then(|(start, end)| image.get(start..end))
+        .ok_or(ElfProblem::ProgramHeadersOutOfBounds)?;
+
+    let loadable = || {
+        phdrs
+            .chunks_exact(PHDR_SIZE)
+            .enumerate()
+            .filter(|(_idx, phdr)| u32_at(phdr, 0) == PT_LOAD)
+            .map(|(idx, phdr)| (idx, Segment::parse(phdr)))
+    };
+    if loadable().next().is_none() {
+        return Err(ElfProblem::NoLoadableSegments);
+    }
+    for (idx, segment) in loadable() {
+        segment.check(idx, image.len() as u64)?;
+    }
+    // Few segments, the quadratic check doesn't need an allocation
+    for (first, a) in loadable() {
+        for (second, b) in loadable().filter(|(idx, _)| *idx > first) {
+            if a.range().start < b.range().end && b.range().start < a.range().end {
+                return Err(ElfProblem::OverlappingSegments { first, second });
+            }
+        }
+    }
+    if !loadable().any(|(_idx, s)| s.flags & PF_X != 0 && s.range().contains(&entry)) {
+        return Err(ElfProblem::EntryNotInText { entry });
+    }
+    Ok(())
+}
+
+/// Checks the ELF binary `binary` (in `image`) before we load it as a
+/// process.
+pub fn verify(binary: &str, image: &[u8]) -> Result<(), KError> {
+    check(image).map_err(|problem| {
+        warn!("Refusing to load {}: {}", binary, problem);
+        KError::InvalidElf { problem }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// A PIE with a text and a data segment (at 0x1000 and 0x3000).
+    fn binary() -> Vec<u8> {
+        let mut image = alloc::vec![0u8; 0x3000];
+        image[..4].copy_from_slice(ELF_MAGIC);
+        image[4] = ELFCLASS64;
+        image[5] = ELFDATA2LSB;
+        image[6] = EV_CURRENT;
+        image[16..18].copy_from_slice(&ET_DYN.to_le_bytes());
+        image[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+        image[24..32].copy_from_slice(&0x1010u64.to_le_bytes());
+        image[32..40].copy_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+        image[54..56].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        image[56..58].copy_from_slice(&2u16.to_le_bytes());
+        set_segment(&mut image, 0, PF_X | 4, 0x1000, 0x1000, 0x1000, 0x1000);
+        set_segment(&mut image, 1, 6, 0x2000, 0x3000, 0x800, 0x2000);
+        image
+    }
+
+    fn set_segment(
+        image: &mut [u8],
+        idx: usize,
+        flags: u32,
+        offset: u64,
+        vaddr: u64,
+        filesz: u64,
+        memsz: u64,
+    ) {
+        let phdr = &mut image[EHDR_SIZE + idx * PHDR_SIZE..][..PHDR_SIZE];
+        phdr[0..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        phdr[4..8].copy_from_slice(&flags.to_le_bytes());
+        phdr[8..16].copy_from_slice(&offset.to_le_bytes());
+        phdr[16..24].copy_from_slice(&vaddr.to_le_bytes());
+        phdr[32..40].copy_from_slice(&filesz.to_le_bytes());
+        phdr[40..48].copy_from_slice(&memsz.to_le_bytes());
+        phdr[48..56].copy_from_slice(&0x1000u64.to_le_bytes());
+    }
+
+    #[test]
+    fn check_header() {
+        assert_eq!(check(&binary()), Ok(()));
+
+        let image = binary();
+        assert_eq!(check(&image[..63]), Err(ElfProblem::Truncated { len: 63 }));
+        assert_eq!(check(&[0; 64]), Err(ElfProblem::NotElf));
+
+        let mut image = binary();
+        image[4] = 1;
+        assert_eq!(check(&image), Err(ElfProblem::Not64Bit));
+
+        let mut image = binary();
+        image[18..20].copy_from_slice(&183u16.to_le_bytes());
+        assert_eq!(
+            check(&image),
+            Err(ElfProblem::WrongMachine { machine: 183 })
+        );
+
+        let mut image = binary();
+        image[16] = 1;
+        assert_eq!(check(&image), Err(ElfProblem::NotExecutable { kind: 1 }));
+
+        let mut image = binary();
+        image[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(check(&image), Err(ElfProblem::ProgramHeadersOutOfBounds));
+    }
+
+    #[test]
+    fn check_segments() {
+        // Cut off in the middle of the data segment (a truncated copy)
+        let image = binary();
+        assert_eq!(
+            check(&image[..0x2400]),
+            Err(ElfProblem::SegmentOutOfBounds { segment: 1 })
+        );
+
+        let mut image = binary();
+        set_segment(&mut image, 1, 6, 0x2000, 0x3000, 0x2000, 0x800);
+        assert_eq!(check(&image), Err(ElfProblem::SegmentSize { segment: 1 }));
+
+        let mut image = binary();
+        set_segment(&mut image, 1, 6, 0x2000, 0x3100, 0x800, 0x800);
+        assert_eq!(
+            check(&image),
+            Err(ElfProblem::SegmentAlignment { segment: 1 })
+        );
+
+        let mut image = binary();
+        set_segment(&mut image, 1, 6, 0x2000, 0x1000, 0x800, 0x800);
+        assert_eq!(
+            check(&image),
+            Err(ElfProblem::OverlappingSegments {
+                first: 0,
+                second: 1
+            })
+        );
+
+        let mut image = binary();
+        image[24..32].copy_from_slice(&0x3010u64.to_le_bytes());
+        assert_eq!(
+            check(&image),
+            Err(ElfProblem::EntryNotInText { entry: 0x3010 })
+        );
+
+        let mut image = binary();
+        image[56..58].copy_from_slice(&0u16.to_le_bytes());
+        assert_eq!(check(&image), Err(ElfProblem::NoLoadableSegments));
+    }
+}
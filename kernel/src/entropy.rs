@@ -0,0 +1,149 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Random numbers for the kernel and user space (`System::get_random`).
+//!
+//! We seed a PRNG from the hardware random number generator (RDSEED or
+//! RDRAND, if the CPU has them) and the jitter of the TSC. If the CPU has
+//! RDRAND, every number we hand out is additionally mixed with its output.
+//!
+//! With `seed=<n>` on the kernel command line the PRNG is seeded with `n`
+//! only and the hardware generator isn't used: every run then gets the same
+//! numbers (and with them the same PIDs and process load addresses), which
+//! helps to reproduce failing test runs.
+//!
+//! The generator is not cryptographically secure.
+
+// Only seeded explicitly by the x86-64 kernel
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use core::arch::x86_64::{_rdrand64_step, _rdseed64_step, _rdtsc};
+use core::ptr;
+
+use crate::sync::Mutex;
+use log::{info, warn};
+use rand::rngs::SmallRng;
+use rand::{RngCore, SeedableRng};
+use x86::cpuid::CpuId;
+
+/// How often we retry RDRAND/RDSEED if it's out of entropy.
+const HW_RETRIES: usize = 16;
+
+/// Number of TSC samples we fold into one word of jitter.
+const JITTER_ROUNDS: usize = 128;
+
+struct Entropy {
+    rng: SmallRng,
+    /// Mix the output with RDRAND.
+    rdrand: bool,
+}
+
+static ENTROPY: Mutex<Option<Entropy>> = Mutex::new(None);
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand() -> Option<u64> {
+    let mut value = 0;
+    for _ in 0..HW_RETRIES {
+        if _rdrand64_step(&mut value) == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[target_feature(enable = "rdseed")]
+unsafe fn rdseed() -> Option<u64> {
+    let mut value = 0;
+    for _ in 0..HW_RETRIES {
+        if _rdseed64_step(&mut value) == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Collects a word of entropy from the TSC: how long a few memory accesses
+/// take varies with the state of the caches, interrupts, SMIs etc.
+fn jitter() -> u64 {
+    let mut pool = 0u64;
+    let mut scratch = [0u64; 64];
+    for round in 0..JITTER_ROUNDS {
+        let start = unsafe { _rdtsc() };
+        for i in 0..scratch.len() {
+            let idx = (i * 7 + round) % scratch.len();
+            // Volatile so the compiler doesn't optimize the loop away
+            unsafe {
+                let value = ptr::read_volatile(&scratch[i]);
+                ptr::write_volatile(&mut scratch[idx], value.wrapping_add(start));
+            }
+        }
+        let delta = unsafe { _rdtsc() }.wrapping_sub(start);
+        pool = (pool.rotate_left(7) ^ delta).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+    pool
+}
+
+/// Seeds the generator from the hardware (deterministically if `seed` is
+/// set).
+fn seeded(seed: Option<u64>) -> Entropy {
+    if let Some(seed) = seed {
+        return Entropy {
+            rng: SmallRng::seed_from_u64(seed),
+            rdrand: false,
+        };
+    }
+
+    let cpuid = CpuId::new();
+    let has_rdrand = cpuid.get_feature_info().map_or(false, |f| f.has_rdrand());
+    let has_rdseed = cpuid
+        .get_extended_feature_info()
+        .map_or(false, |f| f.has_rdseed());
+
+    let mut seed = <SmallRng as SeedableRng>::Seed::default();
+    for chunk in seed.as_mut().chunks_mut(8) {
+        let mut word = jitter();
+        if has_rdseed {
+            word ^= unsafe { rdseed() }.unwrap_or(0);
+        } else if has_rdrand {
+            word ^= unsafe { rdrand() }.unwrap_or(0);
+        }
+        chunk.copy_from_slice(&word.to_le_bytes()[..chunk.len()]);
+    }
+
+    Entropy {
+        rng: SmallRng::from_seed(seed),
+        rdrand: has_rdrand,
+    }
+}
+
+/// Seeds the generator, with `seed` for deterministic runs or from the
+/// hardware otherwise.
+///
+/// Calling this is optional, we seed from the hardware on first use.
+pub fn init(seed: Option<u64>) {
+    *ENTROPY.lock() = Some(seeded(seed));
+    match seed {
+        Some(seed) => warn!("Random numbers are deterministic (seed={})", seed),
+        None => info!("Random number generator seeded from hardware"),
+    }
+}
+
+/// Returns a random number.
+pub fn next_u64() -> u64 {
+    let mut entropy = ENTROPY.lock();
+    let entropy = entropy.get_or_insert_with(|| seeded(None));
+    let value = entropy.rng.next_u64();
+    if entropy.rdrand {
+        value ^ unsafe { rdrand() }.unwrap_or(0)
+    } else {
+        value
+    }
+}
+
+/// Fills `buf` with random bytes.
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        let value = next_u64().to_le_bytes();
+        chunk.copy_from_slice(&value[..chunk.len()]);
+    }
+}
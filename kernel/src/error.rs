@@ -7,26 +7,52 @@ use core::fmt;
 use arrayvec::CapacityError;
 use kpi::SystemCallError;
 
+use crate::elfcheck::ElfProblem;
 use crate::memory::VAddr;
+use crate::process::Pid;
 
 #[derive(PartialEq, Clone, Debug)]
 pub enum KError {
     // General error
     BadAddress,
+    UserBufferTooLarge { len: usize },
     GlobalMemoryNotSet,
     CoreAlreadyAllocated,
+    GangRequestUnsatisfiable { requested: usize, available: usize },
     OutOfMemory,
     ReplicaNotSet,
     ProcessNotSet,
     NotSupported,
     OutOfPids,
     NoExecutorForCore,
+    MsrNotAllowed { msr: u32 },
+    MsrUnavailable { msr: u32 },
+    CpuidLeafNotAllowed { leaf: u32 },
+    FrequencyControlUnavailable,
+    RatioOutOfRange { ratio: u8, min: u8, max: u8 },
+    MwaitUnavailable,
+    NetworkDeviceUnavailable,
+    EfiRuntimeUnavailable,
+    EfiRuntimeError { status: usize },
+    ProfilerUnavailable,
+    InvalidProfilePeriod { period: u64 },
+    RemoteCallTimeout { pending: usize },
+    FaultInjectionDisabled,
+    SubsystemFailed { name: &'static str },
+    NoPlaceForKernel { size: usize },
 
     // Syscall errors
     InvalidSyscallArgument1 { a: u64 },
+    InvalidSyscallArgument2 { a: u64 },
+    InvalidSyscallArgument3 { a: u64 },
+    InvalidSyscallArgument4 { a: u64 },
+    InvalidSyscallArguments { error: SystemCallError },
     InvalidVSpaceOperation { a: u64 },
     InvalidProcessOperation { a: u64 },
     InvalidSystemOperation { a: u64 },
+    InvalidSchedulingClass { a: u64 },
+    InvalidTestOperation { a: u64 },
+    InvalidNetOperation { a: u64 },
 
     // Physical memory errors
     InvalidLayout,
@@ -37,6 +63,7 @@ pub enum KError {
     ManagerAlreadyBorrowed,
     InvalidAffinityId,
     CapacityOverflow,
+    ReservedMemory { base: u64 },
 
     // Process Errors
     ProcessLoadingFailed,
@@ -44,6 +71,7 @@ pub enum KError {
     NoProcessFoundForPid,
     UnableToLoad,
     UnableToParseElf,
+    InvalidElf { problem: ElfProblem },
     NoExecutorAllocated,
     ExecutorCacheExhausted,
     InvalidGlobalThreadId,
@@ -55,12 +83,35 @@ pub enum KError {
     TooManyRegisteredFrames,
     InvalidFileDescriptor,
     BinaryNotFound { binary: &'static str },
+    InvalidCheckpoint,
+    MissingCapability,
+    NotAChild,
+    SupervisorExists { pid: Pid },
+    NotSupervisor,
+    InvalidInitConfig { line: usize },
+    NotAService { pid: Pid },
+    KeyboardInUse { pid: Pid },
+    NoKeyboardFocus,
+    NoSuchDevice { id: u64 },
+    DeviceInUse { id: u64 },
+    DeviceNotClaimed { id: u64 },
+    NoSuchInterrupt { gsi: u64 },
+    InterruptInUse { gsi: u64 },
+    InterruptNeedsRemapping { apic_id: u32 },
+    DiskError { status: u8 },
+    DiskTimeout,
+    NoSuchDisk { idx: usize },
+    NoSuchSocket { id: u64 },
+    PortInUse { port: u16 },
+    Unreachable { addr: [u8; 4] },
 
     // Address space errors
     InvalidFrame,
     AlreadyMapped { base: VAddr },
+    ConflictingRights { base: VAddr },
     BaseOverflow { base: u64 },
     NotMapped,
+    NotReserved { base: VAddr },
     InvalidLength,
     InvalidBase,
 
@@ -72,6 +123,10 @@ pub enum KError {
     AlreadyPresent,
     DirectoryError,
     OpenFileLimit,
+    SymlinkLoop,
+    InvalidName,
+    NoSpace,
+    InvalidArchive { offset: usize },
     FileDescForPidAlreadyAdded,
     NoFileDescForPid,
 }
@@ -112,6 +167,17 @@ impl From<core::alloc::AllocError> for KError {
     }
 }
 
+impl From<vmxnet3::vmx::VMXNet3Error> for KError {
+    fn from(err: vmxnet3::vmx::VMXNet3Error) -> KError {
+        match err {
+            vmxnet3::vmx::VMXNet3Error::OutOfMemory
+            | vmxnet3::vmx::VMXNet3Error::OutOfMemory1 { .. }
+            | vmxnet3::vmx::VMXNet3Error::OutOfMemory2 { .. } => KError::OutOfMemory,
+            _ => KError::NetworkDeviceUnavailable,
+        }
+    }
+}
+
 impl From<slabmalloc::AllocationError> for KError {
     fn from(err: slabmalloc::AllocationError) -> KError {
         match err {
@@ -131,9 +197,58 @@ impl From<KError> for SystemCallError {
     fn from(e: KError) -> SystemCallError {
         match e {
             KError::InvalidSyscallArgument1 { .. } => SystemCallError::NotSupported,
+            KError::InvalidSyscallArgument2 { .. } => SystemCallError::NotSupported,
+            KError::InvalidSyscallArgument3 { .. } => SystemCallError::NotSupported,
+            KError::InvalidSyscallArgument4 { .. } => SystemCallError::NotSupported,
+            KError::InvalidSyscallArguments { error } => error,
             KError::InvalidVSpaceOperation { .. } => SystemCallError::NotSupported,
             KError::InvalidProcessOperation { .. } => SystemCallError::NotSupported,
+            KError::InvalidSchedulingClass { .. } => SystemCallError::NotSupported,
+            KError::InvalidTestOperation { .. } => SystemCallError::NotSupported,
+            KError::InvalidNetOperation { .. } => SystemCallError::NotSupported,
+            KError::FaultInjectionDisabled => SystemCallError::NotSupported,
+            KError::SubsystemFailed { .. } => SystemCallError::NotSupported,
+            KError::NoPlaceForKernel { .. } => SystemCallError::OutOfMemory,
             KError::BadAddress { .. } => SystemCallError::BadAddress,
+            KError::UserBufferTooLarge { .. } => SystemCallError::BadAddress,
+            KError::NotReserved { .. } => SystemCallError::BadAddress,
+            KError::BaseOverflow { .. } => SystemCallError::BadAddress,
+            // The core lost track of the caller (EFAULT rather than a panic)
+            KError::ProcessNotSet => SystemCallError::BadAddress,
+            KError::CoreAlreadyAllocated => SystemCallError::CoreUnavailable,
+            KError::GangRequestUnsatisfiable { .. } => SystemCallError::GangUnsatisfiable,
+            KError::NoSpace => SystemCallError::NoSpace,
+            KError::OpenFileLimit => SystemCallError::OpenFileLimit,
+            KError::SymlinkLoop => SystemCallError::SymlinkLoop,
+            KError::InvalidName => SystemCallError::InvalidName,
+            KError::InvalidOffset => SystemCallError::OffsetError,
+            KError::InvalidCheckpoint => SystemCallError::InvalidCheckpoint,
+            KError::MissingCapability => SystemCallError::PermissionError,
+            KError::NotAChild => SystemCallError::PermissionError,
+            KError::SupervisorExists { .. } => SystemCallError::PermissionError,
+            KError::NotSupervisor => SystemCallError::PermissionError,
+            KError::NotAService { .. } => SystemCallError::PermissionError,
+            KError::KeyboardInUse { .. } => SystemCallError::PermissionError,
+            KError::NoKeyboardFocus => SystemCallError::PermissionError,
+            KError::DeviceInUse { .. } => SystemCallError::PermissionError,
+            KError::DeviceNotClaimed { .. } => SystemCallError::PermissionError,
+            KError::InterruptInUse { .. } => SystemCallError::PermissionError,
+            KError::PortInUse { .. } => SystemCallError::PermissionError,
+            KError::NoSuchSocket { .. } => SystemCallError::BadFileDescriptor,
+            KError::ReservedMemory { .. } => SystemCallError::PermissionError,
+            KError::MsrNotAllowed { .. } => SystemCallError::PermissionError,
+            KError::CpuidLeafNotAllowed { .. } => SystemCallError::PermissionError,
+            KError::MsrUnavailable { .. } => SystemCallError::NotSupported,
+            KError::FrequencyControlUnavailable => SystemCallError::NotSupported,
+            KError::RatioOutOfRange { .. } => SystemCallError::NotSupported,
+            KError::MwaitUnavailable => SystemCallError::NotSupported,
+            KError::ProfilerUnavailable => SystemCallError::NotSupported,
+            KError::InvalidProfilePeriod { .. } => SystemCallError::NotSupported,
+            KError::NoSuchDevice { .. } => SystemCallError::NotSupported,
+            KError::NoSuchDisk { .. } => SystemCallError::NotSupported,
+            KError::NoSuchInterrupt { .. } => SystemCallError::NotSupported,
+            KError::InterruptNeedsRemapping { .. } => SystemCallError::NotSupported,
+            KError::Unreachable { .. } => SystemCallError::NotSupported,
             _ => SystemCallError::InternalError,
         }
     }
@@ -161,16 +276,59 @@ impl fmt::Display for KError {
                 "The requested operation is not supported/does not exist."
             ),
             KError::BadAddress => write!(f, "User-space pointer is not valid."),
+            KError::UserBufferTooLarge { len } => write!(
+                f,
+                "User-space buffer of {} bytes exceeds the system call limit.",
+                len
+            ),
             KError::GlobalMemoryNotSet => write!(f, "Global memory is not yet available."),
+            KError::MsrNotAllowed { msr } => {
+                write!(f, "MSR {:#x} is not on the allowlist.", msr)
+            }
+            KError::MsrUnavailable { msr } => {
+                write!(f, "MSR {:#x} doesn't exist on this core.", msr)
+            }
+            KError::CpuidLeafNotAllowed { leaf } => {
+                write!(f, "CPUID leaf {:#x} is not on the allowlist.", leaf)
+            }
+            KError::FrequencyControlUnavailable => {
+                write!(f, "The CPU doesn't let the kernel set the frequency.")
+            }
+            KError::RatioOutOfRange { ratio, min, max } => {
+                write!(f, "Ratio {} is not between {} and {}.", ratio, min, max)
+            }
+            KError::MwaitUnavailable => write!(f, "The CPU doesn't have mwait."),
             KError::CoreAlreadyAllocated => {
                 write!(
                     f,
                     "The requested core is already allocated by another process."
                 )
             }
+            KError::GangRequestUnsatisfiable {
+                requested,
+                available,
+            } => {
+                write!(
+                    f,
+                    "Can't gang-schedule {} cores, only {} are available.",
+                    requested, available
+                )
+            }
             KError::InvalidSyscallArgument1 { a } => {
                 write!(f, "Invalid 1st syscall argument supplied: {}", a)
             }
+            KError::InvalidSyscallArgument2 { a } => {
+                write!(f, "Invalid 2nd syscall argument supplied: {}", a)
+            }
+            KError::InvalidSyscallArgument3 { a } => {
+                write!(f, "Invalid 3rd syscall argument supplied: {}", a)
+            }
+            KError::InvalidSyscallArgument4 { a } => {
+                write!(f, "Invalid 4th syscall argument supplied: {}", a)
+            }
+            KError::InvalidSyscallArguments { error } => {
+                write!(f, "Syscall arguments don't match the signature: {:?}", error)
+            }
             KError::InvalidVSpaceOperation { a } => {
                 write!(
                     f,
@@ -192,10 +350,30 @@ impl fmt::Display for KError {
                     a
                 )
             }
+            KError::InvalidSchedulingClass { a } => {
+                write!(f, "Invalid scheduling class supplied: {}", a)
+            }
+            KError::InvalidTestOperation { a } => {
+                write!(
+                    f,
+                    "Invalid Test Operation (2nd syscall argument) supplied: {}",
+                    a
+                )
+            }
+            KError::InvalidNetOperation { a } => {
+                write!(
+                    f,
+                    "Invalid Net Operation (2nd syscall argument) supplied: {}",
+                    a
+                )
+            }
             KError::InvalidAffinityId => {
                 write!(f, "Specified an invalid NUMA node ID for affinity.")
             }
             KError::CapacityOverflow => write!(f, "Internal data-structure grew too big"),
+            KError::ReservedMemory { base } => {
+                write!(f, "Physical memory at {:#x} is reserved.", base)
+            }
 
             KError::OutOfPids => write!(f, "Can't spawn more processes (out of Pids)"),
             KError::ProcessLoadingFailed => write!(f, "Can't spawn more processes (out of Pids)"),
@@ -209,6 +387,7 @@ impl fmt::Display for KError {
             KError::NoProcessFoundForPid => write!(f, "No process was associated with the given Pid."),
             KError::UnableToLoad => write!(f, "Couldn't load process, invalid ELF file?"),
             KError::UnableToParseElf => write!(f, "Couldn't parse ELF file, invalid?"),
+            KError::InvalidElf { problem } => write!(f, "Invalid ELF binary: {}.", problem),
             KError::NoExecutorAllocated => write!(f, "We never allocated executors for this affinity region and process (need to fill cache)."),
             KError::ExecutorCacheExhausted => write!(f, "The executor cache for given affinity is empty (need to refill)"),
             KError::InvalidGlobalThreadId => write!(f, "Specified an invalid core"),
@@ -219,11 +398,57 @@ impl fmt::Display for KError {
             KError::TooManyProcesses => write!(f, "Not enough space in process table (out of PIDs)."),
             KError::TooManyRegisteredFrames => write!(f, "Can't register more frames with the process (out of FIDs)."),
             KError::BinaryNotFound { binary } => write!(f, "Can't spawn binary {}: Not found", binary),
+            KError::InvalidCheckpoint => write!(f, "File doesn't contain a valid process checkpoint"),
+            KError::MissingCapability => write!(f, "The process lacks the capability for the operation."),
+            KError::NotAChild => write!(f, "The process is not a child of the calling process."),
+            KError::SupervisorExists { pid } => write!(f, "Process {} already subscribed to OOM kills.", pid),
+            KError::NotSupervisor => write!(f, "The process didn't subscribe to OOM kills."),
+            KError::InvalidInitConfig { line } => write!(f, "Invalid process configuration in line {}", line),
+            KError::NotAService { pid } => write!(f, "Process {} is not a running service.", pid),
+            KError::KeyboardInUse { pid } => write!(f, "Process {} already has the keyboard focus.", pid),
+            KError::NoKeyboardFocus => write!(f, "The process doesn't have the keyboard focus."),
+            KError::NoSuchDevice { id } => write!(f, "There is no device {}.", id),
+            KError::DeviceInUse { id } => write!(f, "Device {} already has a driver.", id),
+            KError::DeviceNotClaimed { id } => write!(f, "Device {} has to be claimed before it's mapped.", id),
+            KError::NoSuchInterrupt { gsi } => write!(f, "There is no interrupt (GSI) {}.", gsi),
+            KError::InterruptInUse { gsi } => {
+                write!(f, "Interrupt (GSI) {} is routed for another process.", gsi)
+            }
+            KError::InterruptNeedsRemapping { apic_id } => write!(
+                f,
+                "Can't route interrupts to APIC id {} without interrupt remapping.",
+                apic_id
+            ),
+            KError::DiskError { status } => {
+                write!(f, "The disk failed the command (status {:#x}).", status)
+            }
+            KError::DiskTimeout => write!(f, "The disk didn't complete the command in time."),
+            KError::NoSuchDisk { idx } => write!(f, "There is no disk {}.", idx),
+            KError::NoSuchSocket { id } => write!(f, "The process has no socket {}.", id),
+            KError::PortInUse { port } => write!(f, "UDP port {} is in use.", port),
+            KError::Unreachable { addr } => write!(
+                f,
+                "{}.{}.{}.{} isn't on the loopback interface.",
+                addr[0], addr[1], addr[2], addr[3]
+            ),
 
             KError::InvalidFrame => write!(f, "Supplied frame was invalid"),
             KError::AlreadyMapped{base} => write!(f, "Address space operation covers existing mapping {:?}", base),
+            KError::ConflictingRights{base} => write!(f, "Memory at {:?} is already mapped with different rights", base),
             KError::BaseOverflow{base} => write!(f, "Provided virtual base {:#x} was invalid (led to overflow on mappings).", base),
+            KError::NetworkDeviceUnavailable => {
+                write!(f, "No (supported) network device was found.")
+            }
+            KError::EfiRuntimeUnavailable => write!(f, "UEFI runtime services are not available."),
+            KError::EfiRuntimeError { status } => write!(f, "UEFI runtime service failed with status {:#x}.", status),
+            KError::ProfilerUnavailable => write!(f, "No performance counters for the profiler on this core."),
+            KError::InvalidProfilePeriod { period } => write!(f, "Invalid sampling period ({} cycles).", period),
+            KError::RemoteCallTimeout { pending } => write!(f, "{} cores didn't run the remote call in time.", pending),
+            KError::FaultInjectionDisabled => write!(f, "The kernel was built without the `fault-injection` feature."),
+            KError::SubsystemFailed { name } => write!(f, "The {} subsystem panicked and is disabled.", name),
+            KError::NoPlaceForKernel { size } => write!(f, "No free physical memory region fits a kernel of {} bytes.", size),
             KError::NotMapped => write!(f, "The requested mapping was not found"),
+            KError::NotReserved { base } => write!(f, "{:?} is not in a reserved region", base),
             KError::InvalidLength => write!(f, "The supplied length was invalid"),
             KError::InvalidBase => write!(f, "The supplied base was invalid (alignment?)"),
 
@@ -242,6 +467,10 @@ impl fmt::Display for KError {
             KError::AlreadyPresent => write!(f, "Fd/File already exists"),
             KError::DirectoryError => write!(f, "Can't read or write to a directory"),
             KError::OpenFileLimit => write!(f, "Maximum files are opened for a process"),
+            KError::SymlinkLoop => write!(f, "Too many symbolic links in a path"),
+            KError::InvalidName => write!(f, "Name is not valid UTF-8"),
+            KError::NoSpace => write!(f, "The mount has reached its size limit"),
+            KError::InvalidArchive { offset } => write!(f, "Invalid archive entry at offset {}", offset),
         }
     }
 }
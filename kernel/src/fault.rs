@@ -0,0 +1,173 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Fault injection for tests (`fault-injection` feature).
+//!
+//! A process with `Capabilities::FAULT_INJECTION` arms a fault with the
+//! `SystemCall::Test` domain, the next N times any core passes the
+//! injection point of the fault (see [`Fault`]) it fires instead of what
+//! normally happens there. This makes error paths that are hard to trigger
+//! otherwise (running out of memory, losing packets, a slow combiner)
+//! reachable from integration tests.
+//!
+//! A [`CoreFault`] only fires on the core that armed it (it breaks the state
+//! of the core for the calling process).
+//!
+//! Without the feature, faults can't be armed and [`fire`] is `false`.
+
+// Faults are only armed by the x86-64 kernel
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use kpi::process::Capabilities;
+use log::{info, trace};
+
+use crate::arch::MAX_CORES;
+use crate::error::KError;
+use crate::nr;
+use crate::process::Pid;
+
+/// The faults that can be injected.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Fault {
+    /// `KernelAllocator::try_refill_tcache` fails with `OutOfMemory`.
+    FailAllocation = 0,
+    /// The network interface drops a received packet before the stack sees
+    /// it.
+    DropPacket = 1,
+    /// The NR combiner of the kernel or of a process spins for a while before
+    /// it applies an operation.
+    DelayCombine = 2,
+}
+
+impl Fault {
+    pub const ALL: [Fault; 3] = [
+        Fault::FailAllocation,
+        Fault::DropPacket,
+        Fault::DelayCombine,
+    ];
+}
+
+/// Faults that fire on the core that armed them, the next time the core
+/// passes their injection point.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum CoreFault {
+    /// The core loses its save area when the system call returns, the next
+    /// system call finds none.
+    LoseSaveArea = 0,
+    /// A system call finds no process on the core.
+    LoseProcess = 1,
+}
+
+impl CoreFault {
+    pub const ALL: [CoreFault; 2] = [CoreFault::LoseSaveArea, CoreFault::LoseProcess];
+}
+
+/// Which core faults are armed on every core.
+static ARMED_ON_CORE: [[AtomicBool; CoreFault::ALL.len()]; MAX_CORES] = {
+    const NO: AtomicBool = AtomicBool::new(false);
+    const CORE: [AtomicBool; CoreFault::ALL.len()] = [NO; CoreFault::ALL.len()];
+    [CORE; MAX_CORES]
+};
+
+/// How often each fault still fires.
+static ARMED: [AtomicU64; Fault::ALL.len()] = {
+    const NONE: AtomicU64 = AtomicU64::new(0);
+    [NONE; Fault::ALL.len()]
+};
+
+/// How long (in rdtsc ticks) a `Fault::DelayCombine` delays.
+static COMBINE_DELAY: AtomicU64 = AtomicU64::new(0);
+
+/// Fails unless faults can be injected (by `pid`).
+pub fn check_capability(pid: Pid) -> Result<(), KError> {
+    if !cfg!(feature = "fault-injection") {
+        return Err(KError::FaultInjectionDisabled);
+    }
+
+    if nr::KernelNode::capabilities(pid)?.contains(Capabilities::FAULT_INJECTION) {
+        Ok(())
+    } else {
+        Err(KError::MissingCapability)
+    }
+}
+
+/// Fire `fault` the next `count` times its injection point is passed.
+pub fn arm(pid: Pid, fault: Fault, count: u64) -> Result<(), KError> {
+    check_capability(pid)?;
+    info!("Process {} armed {:?} for {} times", pid, fault, count);
+    ARMED[fault as usize].store(count, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Arm `Fault::DelayCombine` to delay `count` operations by `cycles` each.
+pub fn arm_combine_delay(pid: Pid, count: u64, cycles: u64) -> Result<(), KError> {
+    check_capability(pid)?;
+    COMBINE_DELAY.store(cycles, Ordering::SeqCst);
+    arm(pid, Fault::DelayCombine, count)
+}
+
+/// Fire `fault` the next time `core` passes its injection point.
+pub fn arm_on_core(pid: Pid, core: usize, fault: CoreFault) -> Result<(), KError> {
+    check_capability(pid)?;
+    info!("Process {} armed {:?} on core {}", pid, fault, core);
+    ARMED_ON_CORE[core][fault as usize].store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Disarms all faults.
+pub fn reset(pid: Pid) -> Result<(), KError> {
+    check_capability(pid)?;
+    for fault in Fault::ALL.iter() {
+        ARMED[*fault as usize].store(0, Ordering::SeqCst);
+    }
+    for core in ARMED_ON_CORE.iter() {
+        for fault in CoreFault::ALL.iter() {
+            core[*fault as usize].store(false, Ordering::SeqCst);
+        }
+    }
+    Ok(())
+}
+
+/// Should `fault` fire at its injection point (uses up one of the times it
+/// was armed for)?
+#[inline(always)]
+pub fn fire(fault: Fault) -> bool {
+    cfg!(feature = "fault-injection") && take(fault)
+}
+
+/// Should `fault` fire on `core` at its injection point (disarms it)?
+#[inline(always)]
+pub fn fire_on_core(core: usize, fault: CoreFault) -> bool {
+    cfg!(feature = "fault-injection")
+        && ARMED_ON_CORE[core][fault as usize].load(Ordering::Relaxed)
+        && ARMED_ON_CORE[core][fault as usize].swap(false, Ordering::SeqCst)
+}
+
+fn take(fault: Fault) -> bool {
+    let armed = &ARMED[fault as usize];
+    // Cheap check first, this is on hot paths:
+    if armed.load(Ordering::Relaxed) == 0 {
+        return false;
+    }
+
+    let fired = armed
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+        .is_ok();
+    if fired {
+        trace!("Injecting {:?}", fault);
+    }
+    fired
+}
+
+/// Injection point of `Fault::DelayCombine`.
+pub fn delay_combine() {
+    if fire(Fault::DelayCombine) {
+        let start = x86::time::rdtsc();
+        let cycles = COMBINE_DELAY.load(Ordering::Relaxed);
+        while x86::time::rdtsc() - start < cycles {
+            core::hint::spin_loop();
+        }
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Optional parts of the kernel and the profiles that select them.
+//!
+//! Every optional subsystem is enabled by a cargo feature. The profiles
+//! (`tiny`, `full` and `benchmark`, see `Cargo.toml`) are sets of these
+//! features. New optional subsystems should be added to [`SUBSYSTEMS`] so
+//! they show up in the report that is printed at boot.
+
+use log::info;
+
+// A kernel should be built with at most one profile:
+#[cfg(all(feature = "tiny", any(feature = "full", feature = "benchmark")))]
+compile_error!("The `tiny` profile can't be combined with `full` or `benchmark`.");
+
+// The tiny profile leaves out everything we don't need to run processes:
+#[cfg(all(
+    feature = "tiny",
+    any(
+        feature = "addr2line",
+        feature = "smoltcp",
+        feature = "integration-test",
+        feature = "lockdep",
+        feature = "syscall-timing",
+        feature = "syscall-budget",
+        feature = "kasan",
+        feature = "fault-injection"
+    )
+))]
+compile_error!(
+    "The `tiny` profile excludes symbolized backtraces, the network stack, self-tests and tracing."
+);
+
+// Tracing distorts measurements:
+#[cfg(all(
+    feature = "benchmark",
+    any(
+        feature = "lockdep",
+        feature = "syscall-timing",
+        feature = "syscall-budget",
+        feature = "kasan",
+        feature = "fault-injection"
+    )
+))]
+compile_error!(
+    "The `benchmark` profile excludes lock and system call tracing, the heap sanitizer and fault injection."
+);
+
+/// An optional subsystem of the kernel.
+pub struct Subsystem {
+    /// What it is.
+    pub name: &'static str,
+    /// The cargo feature that enables it.
+    pub feature: &'static str,
+    /// Is it compiled in?
+    pub enabled: bool,
+}
+
+/// All optional subsystems.
+pub const SUBSYSTEMS: &[Subsystem] = &[
+    Subsystem {
+        name: "Symbolized backtraces",
+        feature: "addr2line",
+        enabled: cfg!(feature = "addr2line"),
+    },
+    Subsystem {
+        name: "Network stack",
+        feature: "smoltcp",
+        enabled: cfg!(feature = "smoltcp"),
+    },
+    Subsystem {
+        name: "Self-tests",
+        feature: "integration-test",
+        enabled: cfg!(feature = "integration-test"),
+    },
+    Subsystem {
+        name: "Lock tracing",
+        feature: "lockdep",
+        enabled: cfg!(feature = "lockdep"),
+    },
+    Subsystem {
+        name: "System call timing",
+        feature: "syscall-timing",
+        enabled: cfg!(feature = "syscall-timing"),
+    },
+    Subsystem {
+        name: "System call budget",
+        feature: "syscall-budget",
+        enabled: cfg!(feature = "syscall-budget"),
+    },
+    Subsystem {
+        name: "Heap sanitizer",
+        feature: "kasan",
+        enabled: cfg!(feature = "kasan"),
+    },
+    Subsystem {
+        name: "Fault injection",
+        feature: "fault-injection",
+        enabled: cfg!(feature = "fault-injection"),
+    },
+    Subsystem {
+        name: "Pre-allocated memory",
+        feature: "prealloc",
+        enabled: cfg!(feature = "prealloc"),
+    },
+];
+
+/// The profile the kernel was built with (`custom` if none was selected).
+pub const PROFILE: &str = if cfg!(feature = "tiny") {
+    "tiny"
+} else if cfg!(feature = "benchmark") {
+    "benchmark"
+} else if cfg!(feature = "full") {
+    "full"
+} else {
+    "custom"
+};
+
+/// Prints the build, the profile and which optional subsystems are compiled in.
+pub fn report() {
+    info!("Kernel build: {}", crate::build_info::BUILD_ID);
+    info!("Kernel profile: {}", PROFILE);
+    for subsystem in SUBSYSTEMS {
+        info!(
+            "  {:<24} {:<4} ({})",
+            subsystem.name,
+            if subsystem.enabled { "on" } else { "off" },
+            subsystem.feature
+        );
+    }
+}
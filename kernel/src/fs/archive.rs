@@ -0,0 +1,428 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Reads the archive the root file-system is populated from at boot.
+//!
+//! Disk mounts (see [`super::image`]) keep their files in the same format,
+//! [`append_cpio`] and [`finish_cpio`] write such an archive.
+//!
+//! Two formats are supported, told apart by their magic:
+//!
+//! - cpio in the "new ASCII" format (`070701` or `070702`, what `cpio -H
+//!   newc` and `scripts/mkrootfs.py` write).
+//! - POSIX ustar (what `tar --format=ustar` writes), with paths of at most
+//!   100 bytes.
+//!
+//! Paths are relative to the root of the archive (`./etc/motd`, `/etc/motd`
+//! and `etc/motd` are all `etc/motd`). Entries other than regular files and directories (links,
+//! devices, ...) are returned as [`EntryKind::Other`] so the caller can skip
+//! them.
+
+use alloc::vec::Vec;
+use core::str;
+
+use crate::error::KError;
+use crate::round_up;
+
+/// Magic of a cpio header (without and with checksums).
+const CPIO_MAGIC: &[u8] = b"070701";
+const CPIO_CRC_MAGIC: &[u8] = b"070702";
+
+/// Size of a cpio header (magic and 13 fields of 8 hex digits).
+const CPIO_HEADER_SIZE: usize = 110;
+
+/// Name of the last entry of a cpio archive.
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+/// Tar archives are made of blocks of this size.
+const TAR_BLOCK_SIZE: usize = 512;
+
+/// Offset of the magic in a tar header (`ustar\0` or `ustar ` for GNU).
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+/// File type bits of a mode (cpio stores them in the mode).
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Format {
+    Cpio,
+    Tar,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Other,
+}
+
+/// A file or directory in the archive.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Entry<'a> {
+    /// Path relative to the root of the archive (without leading `./` or
+    /// `/` and trailing `/`).
+    pub path: &'a str,
+    pub kind: EntryKind,
+    /// Permission bits (`0o777`).
+    pub mode: u32,
+    /// Contents of a file (empty for everything else).
+    pub data: &'a [u8],
+}
+
+/// Iterates over the entries of an archive.
+pub struct Entries<'a> {
+    image: &'a [u8],
+    offset: usize,
+    format: Format,
+    done: bool,
+}
+
+/// Returns the entries of the cpio or tar archive in `image`.
+///
+/// Fails with `InvalidArchive` if `image` is neither.
+pub fn entries(image: &[u8]) -> Result<Entries<'_>, KError> {
+    let format = if image.starts_with(CPIO_MAGIC) || image.starts_with(CPIO_CRC_MAGIC) {
+        Format::Cpio
+    } else if image.len() >= TAR_BLOCK_SIZE && image[TAR_MAGIC_OFFSET..].starts_with(TAR_MAGIC) {
+        Format::Tar
+    } else {
+        return Err(KError::InvalidArchive { offset: 0 });
+    };
+
+    Ok(Entries {
+        image,
+        offset: 0,
+        format,
+        done: false,
+    })
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, KError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let entry = match self.format {
+            Format::Cpio => self.next_cpio(),
+            Format::Tar => self.next_tar(),
+        };
+        match entry {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                // Nothing after a broken header can be trusted
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<'a> Entries<'a> {
+    fn invalid(&self) -> KError {
+        KError::InvalidArchive {
+            offset: self.offset,
+        }
+    }
+
+    /// Takes `len` bytes at `offset`.
+    fn bytes(&self, offset: usize, len: usize) -> Result<&'a [u8], KError> {
+        let image: &'a [u8] = self.image;
+        offset
+            .checked_add(len)
+            .and_then(|end| image.get(offset..end))
+            .ok_or_else(|| self.invalid())
+    }
+
+    fn next_cpio(&mut self) -> Result<Option<Entry<'a>>, KError> {
+        let header = self.bytes(self.offset, CPIO_HEADER_SIZE)?;
+        if !header.starts_with(CPIO_MAGIC) && !header.starts_with(CPIO_CRC_MAGIC) {
+            return Err(self.invalid());
+        }
+        // Fields after the magic: ino, mode, uid, gid, nlink, mtime, filesize,
+        // devmajor, devminor, rdevmajor, rdevminor, namesize, check
+        let field = |idx: usize| {
+            let start = CPIO_MAGIC.len() + idx * 8;
+            str::from_utf8(&header[start..start + 8])
+                .ok()
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        };
+        let (mode, size, name_size) = match (field(1), field(6), field(11)) {
+            (Some(mode), Some(size), Some(name_size)) if name_size > 0 => {
+                (mode, size as usize, name_size as usize)
+            }
+            _ => return Err(self.invalid()),
+        };
+
+        // The name ends with a NUL, name and data are padded to 4 bytes
+        let name_offset = self.offset + CPIO_HEADER_SIZE;
+        let name = self.bytes(name_offset, name_size - 1)?;
+        let name = str::from_utf8(name).map_err(|_e| self.invalid())?;
+        let data_offset = align4(name_offset + name_size);
+        let data = self.bytes(data_offset, size)?;
+        if name == CPIO_TRAILER {
+            return Ok(None);
+        }
+
+        self.offset = align4(data_offset + size);
+        let kind = match mode & S_IFMT {
+            S_IFREG => EntryKind::File,
+            S_IFDIR => EntryKind::Directory,
+            _ => EntryKind::Other,
+        };
+        Ok(Some(Entry {
+            path: relative(name),
+            kind,
+            mode: mode & 0o777,
+            data: if kind == EntryKind::File { data } else { &[] },
+        }))
+    }
+
+    fn next_tar(&mut self) -> Result<Option<Entry<'a>>, KError> {
+        // The archive ends with (at least) one block of zeros
+        let header = match self.bytes(self.offset, TAR_BLOCK_SIZE) {
+            Ok(header) if header.iter().all(|b| *b == 0) => return Ok(None),
+            Ok(header) => header,
+            Err(_e) if self.offset == self.image.len() => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if !header[TAR_MAGIC_OFFSET..].starts_with(TAR_MAGIC) {
+            return Err(self.invalid());
+        }
+
+        let (mode, size) = match (octal(&header[100..108]), octal(&header[124..136])) {
+            (Some(mode), Some(size)) => (mode as u32, size as usize),
+            _ => return Err(self.invalid()),
+        };
+        let data_offset = self.offset + TAR_BLOCK_SIZE;
+        let data = self.bytes(data_offset, size)?;
+
+        // Paths longer than 100 bytes are split into a prefix and a name
+        // which we would have to copy, use cpio for those
+        if header[345] != 0 {
+            return Err(self.invalid());
+        }
+        let name = str::from_utf8(cstr(&header[0..100])).map_err(|_e| self.invalid())?;
+        let path = relative(name);
+
+        self.offset = data_offset + round_up!(size, TAR_BLOCK_SIZE);
+        let kind = match header[156] {
+            b'0' | 0 => EntryKind::File,
+            b'5' => EntryKind::Directory,
+            _ => EntryKind::Other,
+        };
+        Ok(Some(Entry {
+            path,
+            kind,
+            mode: mode & 0o777,
+            data: if kind == EntryKind::File { data } else { &[] },
+        }))
+    }
+}
+
+fn align4(offset: usize) -> usize {
+    round_up!(offset, 4)
+}
+
+/// The bytes of a (not necessarily) NUL-terminated string.
+fn cstr(field: &[u8]) -> &[u8] {
+    let len = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    &field[..len]
+}
+
+/// Parses an octal tar field (padded with spaces or NULs).
+fn octal(field: &[u8]) -> Option<u64> {
+    let digits = str::from_utf8(cstr(field)).ok()?.trim();
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+/// Strips leading `./` and `/` and trailing `/` from `path`.
+fn relative(path: &str) -> &str {
+    let mut path = path;
+    loop {
+        if let Some(rest) = path.strip_prefix("./") {
+            path = rest;
+        } else if let Some(rest) = path.strip_prefix('/') {
+            path = rest;
+        } else {
+            break;
+        }
+    }
+    let path = path.trim_end_matches('/');
+    if path == "." {
+        ""
+    } else {
+        path
+    }
+}
+
+/// Appends a cpio ("new ASCII") entry for `path` to `image`.
+///
+/// `mode` holds the permission bits, the type comes from `kind` (`Other`
+/// entries can't be written).
+pub fn append_cpio(
+    image: &mut Vec<u8>,
+    path: &str,
+    kind: EntryKind,
+    mode: u32,
+    data: &[u8],
+) -> Result<(), KError> {
+    let mode = match kind {
+        EntryKind::File => S_IFREG | (mode & 0o777),
+        EntryKind::Directory => S_IFDIR | (mode & 0o777),
+        EntryKind::Other => return Err(KError::NotSupported),
+    };
+    let name_size = path.len() + 1;
+    let data_offset = align4(image.len() + CPIO_HEADER_SIZE + name_size);
+    image.try_reserve(align4(data_offset + data.len()) - image.len())?;
+
+    // ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor,
+    // rdevmajor, rdevminor, namesize, check
+    let fields = [
+        0,
+        mode,
+        0,
+        0,
+        1,
+        0,
+        data.len() as u32,
+        0,
+        0,
+        0,
+        0,
+        name_size as u32,
+        0,
+    ];
+    image.extend_from_slice(CPIO_MAGIC);
+    for field in fields.iter() {
+        for shift in (0..8).rev() {
+            image.push(b"0123456789abcdef"[(*field >> (shift * 4)) as usize & 0xf]);
+        }
+    }
+    image.extend_from_slice(path.as_bytes());
+    image.push(0);
+    image.resize(data_offset, 0);
+    image.extend_from_slice(data);
+    image.resize(align4(image.len()), 0);
+    Ok(())
+}
+
+/// Appends the entry that ends a cpio archive to `image`.
+pub fn finish_cpio(image: &mut Vec<u8>) -> Result<(), KError> {
+    append_cpio(image, CPIO_TRAILER, EntryKind::File, 0, &[])
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// Appends an entry to the cpio archive `image` (also used by the
+    /// rootfs tests).
+    pub(crate) fn cpio_entry(image: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+        let fields = [
+            1,
+            mode,
+            0,
+            0,
+            1,
+            0,
+            data.len() as u32,
+            0,
+            0,
+            0,
+            0,
+            name.len() as u32 + 1,
+            0,
+        ];
+        image.extend_from_slice(CPIO_MAGIC);
+        for field in fields.iter() {
+            image.extend_from_slice(alloc::format!("{:08x}", field).as_bytes());
+        }
+        image.extend_from_slice(name.as_bytes());
+        image.push(0);
+        image.resize(align4(image.len()), 0);
+        image.extend_from_slice(data);
+        image.resize(align4(image.len()), 0);
+    }
+
+    fn tar_entry(image: &mut Vec<u8>, name: &str, kind: u8, mode: u32, data: &[u8]) {
+        let mut header = [0u8; TAR_BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(alloc::format!("{:07o}", mode).as_bytes());
+        header[124..135].copy_from_slice(alloc::format!("{:011o}", data.len()).as_bytes());
+        header[156] = kind;
+        header[TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + 6].copy_from_slice(b"ustar\0");
+        image.extend_from_slice(&header);
+        image.extend_from_slice(data);
+        image.resize(round_up!(image.len(), TAR_BLOCK_SIZE), 0);
+    }
+
+    #[test]
+    fn cpio_archive() {
+        let mut image = Vec::new();
+        cpio_entry(&mut image, ".", 0o040755, &[]);
+        cpio_entry(&mut image, "./etc", 0o040755, &[]);
+        cpio_entry(&mut image, "./etc/motd", 0o100644, b"hello");
+        cpio_entry(&mut image, "./bin/sh", 0o120777, b"/bin/init");
+        cpio_entry(&mut image, CPIO_TRAILER, 0, &[]);
+
+        let entries: Vec<Entry> = entries(&image).unwrap().map(|e| e.unwrap()).collect();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].path, "");
+        assert_eq!(entries[1].path, "etc");
+        assert_eq!(entries[1].kind, EntryKind::Directory);
+        assert_eq!(
+            entries[2],
+            Entry {
+                path: "etc/motd",
+                kind: EntryKind::File,
+                mode: 0o644,
+                data: b"hello"
+            }
+        );
+        assert_eq!(entries[3].kind, EntryKind::Other);
+    }
+
+    #[test]
+    fn tar_archive() {
+        let mut image = Vec::new();
+        tar_entry(&mut image, "etc/", b'5', 0o755, &[]);
+        tar_entry(&mut image, "etc/motd", b'0', 0o600, b"hello");
+        tar_entry(&mut image, "/data", b'0', 0o644, &[7u8; 700]);
+        image.extend_from_slice(&[0u8; 2 * TAR_BLOCK_SIZE]);
+
+        let entries: Vec<Entry> = entries(&image).unwrap().map(|e| e.unwrap()).collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].path, "etc");
+        assert_eq!(entries[0].kind, EntryKind::Directory);
+        assert_eq!(entries[1].data, b"hello");
+        assert_eq!(entries[1].mode, 0o600);
+        assert_eq!(entries[2].path, "data");
+        assert_eq!(entries[2].data.len(), 700);
+    }
+
+    #[test]
+    fn truncated_archive() {
+        assert!(entries(b"not an archive").is_err());
+
+        let mut image = Vec::new();
+        cpio_entry(&mut image, "motd", 0o100644, b"hello world");
+        image.truncate(image.len() - 8);
+
+        let mut it = entries(&image).unwrap();
+        assert_eq!(it.next(), Some(Err(KError::InvalidArchive { offset: 0 })));
+        assert_eq!(it.next(), None);
+    }
+}
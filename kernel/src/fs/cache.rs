@@ -0,0 +1,578 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Caches the disks of disk mounts (see [`super::image`]) in pages.
+//!
+//! Disk mounts read and write their disk through a [`Cache`] (one per disk,
+//! see [`with_disk`]) instead of sector by sector:
+//!
+//! - Read-ahead: every sequential reader keeps a [`ReadAhead`]. A miss reads
+//!   the rest of the read and the window of the reader in one command. The
+//!   window doubles (up to [`MAX_READ_AHEAD`] pages) while the reader stays
+//!   sequential and closes when it seeks. The window after that is queued for
+//!   the flusher, so the next read of the reader finds it cached.
+//! - Write-behind: writes only change the cached pages (a page is only dirty
+//!   if its data changed). The flusher writes the pages back once they have
+//!   been dirty for [`WRITE_BEHIND`], contiguous pages in one command.
+//! - [`Cache::sync`] is the barrier of `Fsync`: it writes back every dirty
+//!   page and flushes the disk.
+//!
+//! The flusher ([`flush`]) runs from the `Flush` soft IRQ (see
+//! `image::flush_softirq`). A cache keeps at most [`MAX_PAGES`] pages, it
+//! drops the least recently used clean pages (and writes back the dirty ones
+//! if that isn't enough).
+
+// Only the x86-64 kernel has disks
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use fallible_collections::{FallibleVec, FallibleVecGlobal};
+use log::warn;
+
+use crate::block::{self, BlockDevice, SECTOR_SIZE};
+use crate::error::KError;
+use crate::sync::Mutex;
+use crate::timer_wheel;
+
+/// Bytes of a page.
+pub const PAGE_SIZE: usize = 4096;
+
+/// Sectors of a page.
+const SECTORS_PER_PAGE: u64 = (PAGE_SIZE / SECTOR_SIZE) as u64;
+
+/// Most pages a cache keeps.
+pub const MAX_PAGES: usize = 1024;
+
+/// Window of a reader that just turned sequential (in pages).
+const INITIAL_READ_AHEAD: u64 = 4;
+
+/// Largest window of a reader (in pages).
+pub const MAX_READ_AHEAD: u64 = 32;
+
+/// Most pages read or written in one command.
+const MAX_RUN: u64 = 32;
+
+/// Most read-aheads queued for the flusher (per disk).
+const MAX_QUEUED: usize = 8;
+
+/// How long a page stays dirty before the flusher writes it back.
+pub const WRITE_BEHIND: Duration = Duration::from_millis(100);
+
+/// The read-ahead state of a reader.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ReadAhead {
+    /// The page a sequential reader continues at.
+    next: u64,
+    /// Pages read ahead of the reader (0 while it isn't sequential).
+    window: u64,
+}
+
+impl ReadAhead {
+    /// Takes note of a read of the pages `first..last`, returns how many
+    /// pages to read ahead of it.
+    fn advance(&mut self, first: u64, last: u64) -> u64 {
+        // Reads that aren't page-aligned continue in the page the last one
+        // ended in
+        self.window = if first == self.next || first + 1 == self.next {
+            (self.window * 2).clamp(INITIAL_READ_AHEAD, MAX_READ_AHEAD)
+        } else {
+            0
+        };
+        self.next = last;
+        self.window
+    }
+}
+
+/// A cached page of a disk.
+struct Page {
+    data: Vec<u8>,
+    /// When the page changed first since it was written back (`None` if it
+    /// is clean).
+    dirty: Option<u64>,
+    /// Logical time of the last use (to find the least recently used).
+    used: u64,
+}
+
+/// The cached pages of a disk.
+#[derive(Default)]
+pub struct Cache {
+    pages: BTreeMap<u64, Page>,
+    /// Read-aheads for the flusher (first page, number of pages).
+    queued: Vec<(u64, u64)>,
+    /// Logical clock for `Page::used`.
+    clock: u64,
+}
+
+impl Cache {
+    /// Number of pages of `disk` (the last one can be shorter).
+    fn pages_of(disk: &dyn BlockDevice) -> u64 {
+        (disk.sectors() + SECTORS_PER_PAGE - 1) / SECTORS_PER_PAGE
+    }
+
+    /// Bytes of page `page` of `disk`.
+    fn page_len(disk: &dyn BlockDevice, page: u64) -> usize {
+        let sectors = disk
+            .sectors()
+            .saturating_sub(page * SECTORS_PER_PAGE)
+            .min(SECTORS_PER_PAGE);
+        sectors as usize * SECTOR_SIZE
+    }
+
+    /// The pages `len` bytes at byte `offset` of `disk` are in (`first..last`).
+    fn range(disk: &dyn BlockDevice, offset: u64, len: usize) -> Result<(u64, u64), KError> {
+        let end = offset
+            .checked_add(len as u64)
+            .ok_or(KError::InvalidOffset)?;
+        if end > disk.sectors() * SECTOR_SIZE as u64 {
+            return Err(KError::InvalidOffset);
+        }
+        Ok((offset / PAGE_SIZE as u64, (end - 1) / PAGE_SIZE as u64 + 1))
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Reads up to `count` pages starting at `first` in one command (stops at
+    /// the first page that is cached), returns the number of pages read.
+    fn fill(&mut self, disk: &mut dyn BlockDevice, first: u64, count: u64) -> Result<u64, KError> {
+        let end = first
+            .saturating_add(count.min(MAX_RUN))
+            .min(Cache::pages_of(disk));
+        let mut last = first;
+        while last < end && !self.pages.contains_key(&last) {
+            last += 1;
+        }
+        if last == first {
+            return Ok(0);
+        }
+
+        let len = (first..last).map(|page| Cache::page_len(disk, page)).sum();
+        let mut buf = Vec::try_with_capacity(len)?;
+        buf.resize(len, 0);
+        disk.read(first * SECTORS_PER_PAGE, &mut buf)?;
+
+        let used = self.tick();
+        for (idx, chunk) in buf.chunks(PAGE_SIZE).enumerate() {
+            let mut data = Vec::try_with_capacity(chunk.len())?;
+            data.extend_from_slice(chunk);
+            let page = Page {
+                data,
+                dirty: None,
+                used,
+            };
+            self.pages.insert(first + idx as u64, page);
+        }
+        Ok(last - first)
+    }
+
+    /// Page `page` of `disk`, read from the disk if it isn't cached.
+    fn page_mut(&mut self, disk: &mut dyn BlockDevice, page: u64) -> Result<&mut Page, KError> {
+        if !self.pages.contains_key(&page) {
+            self.fill(disk, page, 1)?;
+        }
+        let used = self.tick();
+        let page = self.pages.get_mut(&page).ok_or(KError::InvalidOffset)?;
+        page.used = used;
+        Ok(page)
+    }
+
+    /// Queues a read-ahead for the flusher (read-ahead is only a hint, the
+    /// oldest one is dropped if there are too many).
+    fn queue(&mut self, first: u64, count: u64) {
+        if self.queued.contains(&(first, count)) {
+            return;
+        }
+        if self.queued.len() >= MAX_QUEUED {
+            self.queued.remove(0);
+        }
+        let _r = self.queued.try_push((first, count));
+    }
+
+    /// Drops the least recently used clean pages until at most [`MAX_PAGES`]
+    /// are left.
+    fn shrink(&mut self, disk: &mut dyn BlockDevice) -> Result<(), KError> {
+        while self.pages.len() > MAX_PAGES {
+            let victim = self
+                .pages
+                .iter()
+                .filter(|(_idx, page)| page.dirty.is_none())
+                .min_by_key(|(_idx, page)| page.used)
+                .map(|(idx, _page)| *idx);
+            match victim {
+                Some(idx) => {
+                    self.pages.remove(&idx);
+                }
+                None => {
+                    self.write_back(disk, u64::MAX)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads `buf.len()` bytes at byte `offset` of `disk` for the reader
+    /// `ahead`.
+    pub fn read(
+        &mut self,
+        disk: &mut dyn BlockDevice,
+        offset: u64,
+        buf: &mut [u8],
+        ahead: &mut ReadAhead,
+    ) -> Result<(), KError> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let (first, last) = Cache::range(disk, offset, buf.len())?;
+        let window = ahead.advance(first, last);
+
+        let mut done = 0;
+        for page in first..last {
+            if !self.pages.contains_key(&page) {
+                // The rest of the read and the window in one command
+                self.fill(disk, page, last - page + window)?;
+            }
+            let skip = (offset + done as u64 - page * PAGE_SIZE as u64) as usize;
+            let data = &self.page_mut(disk, page)?.data;
+            let len = core::cmp::min(data.len() - skip, buf.len() - done);
+            buf[done..done + len].copy_from_slice(&data[skip..skip + len]);
+            done += len;
+        }
+        if window > 0 {
+            self.queue(last + window, window);
+        }
+        self.shrink(disk)
+    }
+
+    /// Writes `data` at byte `offset` of `disk` to the cached pages, the pages
+    /// that change are dirty as of `now` (see [`Cache::write_back`]).
+    pub fn write(
+        &mut self,
+        disk: &mut dyn BlockDevice,
+        offset: u64,
+        data: &[u8],
+        now: u64,
+    ) -> Result<(), KError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let (first, last) = Cache::range(disk, offset, data.len())?;
+
+        let mut done = 0;
+        for page in first..last {
+            let skip = (offset + done as u64 - page * PAGE_SIZE as u64) as usize;
+            let page_len = Cache::page_len(disk, page);
+            let len = core::cmp::min(page_len - skip, data.len() - done);
+            let src = &data[done..done + len];
+
+            if len == page_len && !self.pages.contains_key(&page) {
+                // No need to read a page we overwrite
+                let mut data = Vec::try_with_capacity(len)?;
+                data.extend_from_slice(src);
+                let used = self.tick();
+                let new = Page {
+                    data,
+                    dirty: Some(now),
+                    used,
+                };
+                self.pages.insert(page, new);
+            } else {
+                let page = self.page_mut(disk, page)?;
+                if page.data[skip..skip + len] != *src {
+                    page.data[skip..skip + len].copy_from_slice(src);
+                    page.dirty.get_or_insert(now);
+                }
+            }
+            done += len;
+        }
+        self.shrink(disk)
+    }
+
+    /// Writes back the pages that have been dirty since `before` or earlier
+    /// (contiguous pages in one command), returns the number of pages
+    /// written.
+    pub fn write_back(&mut self, disk: &mut dyn BlockDevice, before: u64) -> Result<u64, KError> {
+        let mut runs: Vec<(u64, u64)> = Vec::new();
+        for (idx, page) in self.pages.iter() {
+            match page.dirty {
+                Some(since) if since <= before => {}
+                _ => continue,
+            }
+            match runs.last_mut() {
+                Some((first, count)) if *first + *count == *idx && *count < MAX_RUN => *count += 1,
+                _ => runs.try_push((*idx, 1))?,
+            }
+        }
+
+        let mut written = 0;
+        let mut buf = Vec::new();
+        for (first, count) in runs {
+            buf.clear();
+            for (_idx, page) in self.pages.range(first..first + count) {
+                buf.try_extend_from_slice(&page.data)?;
+            }
+            disk.write(first * SECTORS_PER_PAGE, &buf)?;
+            for (_idx, page) in self.pages.range_mut(first..first + count) {
+                page.dirty = None;
+            }
+            written += count;
+        }
+        Ok(written)
+    }
+
+    /// Writes back every dirty page and waits until the disk has them (the
+    /// barrier of `Fsync`).
+    pub fn sync(&mut self, disk: &mut dyn BlockDevice) -> Result<(), KError> {
+        self.write_back(disk, u64::MAX)?;
+        disk.flush()
+    }
+
+    /// Reads the queued read-aheads (for the flusher), returns the number of
+    /// pages read.
+    pub fn read_ahead(&mut self, disk: &mut dyn BlockDevice) -> Result<u64, KError> {
+        let mut read = 0;
+        for (first, count) in core::mem::take(&mut self.queued) {
+            let end = first.saturating_add(count).min(Cache::pages_of(disk));
+            let mut page = first;
+            while page < end {
+                if self.pages.contains_key(&page) {
+                    page += 1;
+                } else {
+                    let pages = self.fill(disk, page, end - page)?;
+                    page += pages;
+                    read += pages;
+                }
+            }
+        }
+        self.shrink(disk)?;
+        Ok(read)
+    }
+
+    /// Number of dirty pages.
+    #[cfg(test)]
+    pub fn dirty(&self) -> usize {
+        self.pages
+            .values()
+            .filter(|page| page.dirty.is_some())
+            .count()
+    }
+}
+
+/// The caches of the disks (by index of the disk).
+static CACHES: Mutex<Vec<Arc<Mutex<Cache>>>> = Mutex::new(Vec::new());
+
+/// The cache of disk `idx`.
+fn cache(idx: usize) -> Result<Arc<Mutex<Cache>>, KError> {
+    let mut caches = CACHES.lock();
+    while caches.len() <= idx {
+        caches.try_push(Arc::try_new(Mutex::new(Cache::default()))?)?;
+    }
+    Ok(caches[idx].clone())
+}
+
+/// Runs `f` with the cache and disk `idx` (see `block::with_disk`).
+pub fn with_disk<R>(
+    idx: usize,
+    f: impl FnOnce(&mut Cache, &mut dyn BlockDevice) -> Result<R, KError>,
+) -> Result<R, KError> {
+    block::with_disk(idx, |disk| {
+        let cache = cache(idx)?;
+        let mut cache = cache.lock();
+        f(&mut cache, disk)
+    })
+    .ok_or(KError::NoSuchDisk { idx })?
+}
+
+/// Reads the queued read-aheads and writes back the pages that have been
+/// dirty for [`WRITE_BEHIND`] (at rdtsc time `now`) on every disk (the
+/// flusher).
+///
+/// Disks another core uses are skipped, they're flushed the next time.
+pub fn flush(now: u64) {
+    let before = now.saturating_sub(timer_wheel::ns_to_cycles(WRITE_BEHIND.as_nanos() as u64));
+    let disks = CACHES.lock().len();
+    for idx in 0..disks {
+        let cache = match CACHES.lock().get(idx) {
+            Some(cache) => cache.clone(),
+            None => break,
+        };
+        let r = block::try_with_disk(idx, |disk| match cache.try_lock() {
+            Some(mut cache) => cache
+                .read_ahead(disk)
+                .and_then(|_read| cache.write_back(disk, before)),
+            None => Ok(0),
+        });
+        if let Some(Err(e)) = r {
+            warn!("Flushing disk {} failed: {}", idx, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::MemDisk;
+
+    /// Counts the commands that reach the disk.
+    struct Counting {
+        disk: MemDisk,
+        reads: usize,
+        writes: usize,
+        flushes: usize,
+    }
+
+    impl Counting {
+        fn new(sectors: usize) -> Counting {
+            Counting {
+                disk: MemDisk::new(sectors).expect("Has memory"),
+                reads: 0,
+                writes: 0,
+                flushes: 0,
+            }
+        }
+    }
+
+    impl BlockDevice for Counting {
+        fn name(&self) -> &str {
+            "counting"
+        }
+
+        fn sectors(&self) -> u64 {
+            self.disk.sectors()
+        }
+
+        fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), KError> {
+            self.reads += 1;
+            self.disk.read(sector, buf)
+        }
+
+        fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), KError> {
+            self.writes += 1;
+            self.disk.write(sector, buf)
+        }
+
+        fn flush(&mut self) -> Result<(), KError> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    /// Sequential readers read ahead, so they need fewer commands than
+    /// pages, readers that seek don't.
+    #[test]
+    fn read_ahead() {
+        let mut disk = Counting::new(64 * SECTORS_PER_PAGE as usize);
+        let mut cache = Cache::default();
+        let mut ahead = ReadAhead::default();
+        let mut buf = [0u8; PAGE_SIZE];
+
+        for page in 0..16 {
+            cache
+                .read(&mut disk, page * PAGE_SIZE as u64, &mut buf, &mut ahead)
+                .expect("Fits");
+        }
+        assert!(disk.reads < 4, "{} reads", disk.reads);
+        assert!(ahead.window > INITIAL_READ_AHEAD);
+
+        // The flusher reads the queued window
+        let reads = disk.reads;
+        assert!(cache.read_ahead(&mut disk).expect("Fits") > 0);
+        assert_eq!(disk.reads, reads + 1);
+        assert!(cache.pages.contains_key(&(ahead.next + ahead.window)));
+
+        // A seek closes the window
+        let mut seeker = ReadAhead::default();
+        let far = 63 * PAGE_SIZE as u64;
+        cache
+            .read(&mut disk, far, &mut buf, &mut seeker)
+            .expect("Fits");
+        cache
+            .read(&mut disk, PAGE_SIZE as u64, &mut buf, &mut seeker)
+            .expect("Fits");
+        assert_eq!(seeker.window, 0);
+
+        assert!(cache
+            .read(&mut disk, far + 1, &mut buf, &mut ahead)
+            .is_err());
+    }
+
+    /// Writes stay in the cache until they're written back, contiguous
+    /// dirty pages go in one command and unchanged pages aren't written.
+    #[test]
+    fn write_behind() {
+        let mut disk = Counting::new(16 * SECTORS_PER_PAGE as usize + 3);
+        let mut cache = Cache::default();
+        let data = [0xabu8; 3 * PAGE_SIZE];
+
+        cache.write(&mut disk, 100, &data, 1).expect("Fits");
+        cache
+            .write(&mut disk, 8 * PAGE_SIZE as u64, &data, 5)
+            .expect("Fits");
+        assert_eq!(disk.writes, 0);
+        assert_eq!(cache.dirty(), 7);
+
+        // Only the pages dirty long enough
+        assert_eq!(cache.write_back(&mut disk, 1), Ok(4));
+        assert_eq!(disk.writes, 1);
+        let mut sector = [0u8; SECTOR_SIZE];
+        disk.disk.read(0, &mut sector).expect("Fits");
+        assert_eq!((sector[99], sector[100]), (0, 0xab));
+
+        // Writing the same data again doesn't dirty the pages
+        cache.write(&mut disk, 100, &data, 6).expect("Fits");
+        assert_eq!(cache.dirty(), 3);
+
+        // The last page of the disk is shorter
+        cache
+            .write(
+                &mut disk,
+                16 * PAGE_SIZE as u64,
+                &[0xcd; 3 * SECTOR_SIZE],
+                7,
+            )
+            .expect("Fits");
+        assert!(cache
+            .write(
+                &mut disk,
+                16 * PAGE_SIZE as u64 + 1,
+                &[0; 3 * SECTOR_SIZE],
+                7
+            )
+            .is_err());
+
+        cache.sync(&mut disk).expect("Fits");
+        assert_eq!((cache.dirty(), disk.writes, disk.flushes), (0, 3, 1));
+        disk.disk
+            .read(16 * SECTORS_PER_PAGE + 2, &mut sector)
+            .expect("Fits");
+        assert_eq!(sector[0], 0xcd);
+    }
+
+    /// The cache drops clean pages first and writes back dirty ones if it
+    /// has to.
+    #[test]
+    fn bounded() {
+        let pages = MAX_PAGES as u64 + 8;
+        let mut disk = Counting::new((pages * SECTORS_PER_PAGE) as usize);
+        let mut cache = Cache::default();
+        let page = [0x11u8; PAGE_SIZE];
+
+        for idx in 0..pages {
+            cache
+                .write(&mut disk, idx * PAGE_SIZE as u64, &page, idx)
+                .expect("Fits");
+            assert!(cache.pages.len() <= MAX_PAGES);
+        }
+        assert!(disk.writes > 0);
+        cache.sync(&mut disk).expect("Fits");
+
+        let mut buf = [0u8; PAGE_SIZE];
+        disk.disk.read(0, &mut buf).expect("Fits");
+        assert!(buf.iter().all(|b| *b == 0x11));
+    }
+}
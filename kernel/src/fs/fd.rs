@@ -1,11 +1,23 @@
 // Copyright © 2021 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-use super::{Fd, MAX_FILES_PER_PROCESS};
+use alloc::string::String;
+use core::convert::TryFrom;
+
+use kpi::io::FileFlags;
+use kpi::process::INHERITABLE_FDS;
+use kpi::SystemCallError;
+
+use super::{Fd, FileDescriptor, Mnode, MAX_FILES_PER_PROCESS};
 use crate::error::KError;
+use crate::fallible_string::{FallibleString, TryString};
 
 pub struct FileDesc {
     fds: arrayvec::ArrayVec<Option<Fd>, MAX_FILES_PER_PROCESS>,
+    /// The directory the process sees as `/`.
+    root: String,
+    /// New file descriptors are below this (`Process::set_fd_limit`).
+    limit: usize,
 }
 
 impl Default for FileDesc {
@@ -13,17 +25,84 @@ impl Default for FileDesc {
         const NONE_FD: Option<Fd> = None;
         FileDesc {
             fds: arrayvec::ArrayVec::from([NONE_FD; MAX_FILES_PER_PROCESS]),
+            root: String::new(),
+            limit: MAX_FILES_PER_PROCESS,
         }
     }
 }
 
 impl FileDesc {
-    pub fn allocate_fd(&mut self) -> Option<(u64, &mut Fd)> {
-        if let Some(fid) = self.fds.iter().position(|fd| fd.is_none()) {
-            self.fds[fid] = Some(Default::default());
-            Some((fid as u64, self.fds[fid as usize].as_mut().unwrap()))
-        } else {
+    /// File descriptors for a process confined to the sub-tree at `root`.
+    ///
+    /// A `root` of "/" (or "") means the process sees the whole file-system.
+    pub fn with_root(root: &str) -> Result<FileDesc, KError> {
+        let root = root.trim_end_matches('/');
+        let mut fdesc = FileDesc::default();
+        if !root.is_empty() {
+            let root = root.trim_start_matches('/');
+            fdesc.root = String::try_with_capacity(root.len() + 1)?;
+            fdesc.root.try_push('/')?;
+            fdesc.root.try_push_str(root)?;
+        }
+        Ok(fdesc)
+    }
+
+    /// The root directory of the process, `None` if it isn't confined.
+    pub fn root(&self) -> Option<&str> {
+        if self.root.is_empty() {
             None
+        } else {
+            Some(&self.root)
+        }
+    }
+
+    /// Translate a path of the process to a path in the file-system.
+    ///
+    /// Paths of confined processes are placed below their root, whether
+    /// they're absolute or not, and can't contain `..`.
+    pub fn path(&self, name: &str) -> Result<String, KError> {
+        if self.root.is_empty() {
+            return Ok(TryString::try_from(name)?.into());
+        }
+        if name.split('/').any(|component| component == "..") {
+            return Err(KError::PermissionError);
+        }
+
+        let name = name.trim_start_matches('/');
+        let mut path = String::try_with_capacity(self.root.len() + name.len() + 1)?;
+        path.try_push_str(&self.root)?;
+        if !name.is_empty() {
+            path.try_push('/')?;
+            path.try_push_str(name)?;
+        }
+        Ok(path)
+    }
+
+    /// The limit of open files.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Lowers the limit of open files to `limit`, open descriptors above
+    /// it stay open.
+    pub fn set_limit(&mut self, limit: usize) -> Result<(), KError> {
+        if limit > self.limit {
+            return Err(KError::InvalidSyscallArguments {
+                error: SystemCallError::PermissionError,
+            });
+        }
+        self.limit = limit;
+        Ok(())
+    }
+
+    pub fn allocate_fd(&mut self) -> Result<(u64, &mut Fd), KError> {
+        let limit = self.limit;
+        match self.fds[..limit].iter().position(|fd| fd.is_none()) {
+            Some(fid) => {
+                self.fds[fid] = Some(Default::default());
+                Ok((fid as u64, self.fds[fid].as_mut().unwrap()))
+            }
+            None => Err(KError::OpenFileLimit),
         }
     }
 
@@ -40,7 +119,50 @@ impl FileDesc {
         }
     }
 
+    /// Re-creates the descriptor `fd` (e.g., for a restored process).
+    pub fn restore_fd(
+        &mut self,
+        fd: usize,
+        mnode: Mnode,
+        flags: FileFlags,
+        offset: usize,
+    ) -> Result<(), KError> {
+        let slot = self.fds.get_mut(fd).ok_or(KError::InvalidFileDescriptor)?;
+        if slot.is_some() {
+            return Err(KError::AlreadyPresent);
+        }
+
+        let mut restored = Fd::init_fd();
+        restored.update_fd(mnode, flags);
+        restored.update_offset(offset);
+        *slot = Some(restored);
+        Ok(())
+    }
+
     pub fn get_fd(&self, index: usize) -> Option<&Fd> {
-        self.fds[index].as_ref()
+        self.fds.get(index).and_then(|fd| fd.as_ref())
+    }
+
+    /// Gives a (spawned) child the limit and the descriptors of `inherit`
+    /// (a bitmask of descriptors) of its parent `self`.
+    ///
+    /// Descriptors opened with `O_CLOEXEC` are skipped, the child's copies
+    /// start at the current offsets of the parent's.
+    pub fn inherit(&self, child: &mut FileDesc, inherit: u64) -> Result<(), KError> {
+        child.limit = self.limit;
+        for idx in (0..INHERITABLE_FDS).filter(|idx| inherit & (1 << idx) != 0) {
+            let fd = self
+                .get_fd(idx as usize)
+                .ok_or(KError::InvalidFileDescriptor)?;
+            if !fd.get_flags().is_cloexec() {
+                child.restore_fd(
+                    idx as usize,
+                    fd.get_mnode(),
+                    fd.get_flags(),
+                    fd.get_offset(),
+                )?;
+            }
+        }
+        Ok(())
     }
 }
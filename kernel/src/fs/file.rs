@@ -8,6 +8,7 @@ use core::mem::size_of;
 use fallible_collections::{FallibleVec, FallibleVecGlobal};
 use kpi::io::*;
 
+use crate::arch::process::UserSlice;
 use crate::error::KError;
 use crate::memory::BASE_PAGE_SIZE;
 
@@ -149,10 +150,40 @@ impl File {
     /// end_offset(not inclusive).
     pub fn read_file(
         &self,
-        user_slice: &mut [u8],
+        user_slice: &mut UserSlice,
         start_offset: usize,
         end_offset: usize,
     ) -> Result<usize, KError> {
+        self.copy_range(start_offset, end_offset, |dst_start, src| {
+            user_slice.copy_to(dst_start, src)
+        })
+    }
+
+    /// Like `read_file` for a kernel buffer: appends the data from start_offset till
+    /// end_offset(not inclusive) to `out`.
+    pub fn read_range(
+        &self,
+        out: &mut Vec<u8>,
+        start_offset: usize,
+        end_offset: usize,
+    ) -> Result<usize, KError> {
+        self.copy_range(start_offset, end_offset, |_dst_start, src| {
+            out.try_extend_from_slice(src)?;
+            Ok(())
+        })
+    }
+
+    /// Hands the data from start_offset till end_offset(not inclusive) to `copy`, one
+    /// buffer at a time (with the offset of the data in the range).
+    fn copy_range<F>(
+        &self,
+        start_offset: usize,
+        end_offset: usize,
+        mut copy: F,
+    ) -> Result<usize, KError>
+    where
+        F: FnMut(usize, &[u8]) -> Result<(), KError>,
+    {
         let mut buffer_num = offset_to_buffernum(start_offset, BASE_PAGE_SIZE);
         let mut offset_in_buffer = start_offset - (buffer_num * BASE_PAGE_SIZE);
         let mut copied = 0;
@@ -175,8 +206,7 @@ impl File {
                 src_end = src_start + remaining;
                 copied += remaining;
             }
-            user_slice[dst_start..dst_end]
-                .copy_from_slice(&self.mcache[buffer_num].data[src_start..src_end]);
+            copy(dst_start, &self.mcache[buffer_num].data[src_start..src_end])?;
             buffer_num += 1;
             dst_start = dst_end;
             offset_in_buffer = 0;
@@ -185,6 +215,14 @@ impl File {
         Ok(copied)
     }
 
+    /// Appends the contents of the file to `out`.
+    pub fn read_all(&self, out: &mut Vec<u8>) -> Result<(), KError> {
+        for buffer in self.mcache.iter().take_while(|b| !b.data.is_empty()) {
+            out.try_extend_from_slice(&buffer.data)?;
+        }
+        Ok(())
+    }
+
     /// This method is internally called on a write() system-call. The user provided the
     /// data in a user-slice and the method copies that data into the file buffers. Beside
     /// the slice the user also provides the length of the data and it can also specify an
@@ -361,7 +399,8 @@ pub mod test {
         assert_eq!(file.get_size(), 10000);
 
         for i in 0..10000 {
-            file.read_file(&mut rbuffer[i..i + 1], i, i + 1).unwrap();
+            file.read_file(&mut UserSlice::from_slice(&mut rbuffer[i..i + 1]), i, i + 1)
+                .unwrap();
             assert_eq!(rbuffer[i], 0xb);
         }
     }
@@ -0,0 +1,201 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Keeps the files of a disk mount on a disk (see `block`).
+//!
+//! A disk mount (`MountKind::Disk`) is a directory whose files are loaded
+//! from a disk when it's mounted and written back to it (by the flusher and
+//! on `Fsync`). The first sector of the disk holds [`MAGIC`], the generation (`u64`) and the
+//! length (`u64`) of the archive that follows in the next sectors: a cpio
+//! archive of the files and directories of the mount (see `archive`),
+//! little-endian. Symbolic links aren't kept.
+//!
+//! Every snapshot of the mount is a new archive with the next generation. A
+//! disk that already holds a newer generation isn't overwritten, so
+//! concurrent snapshots never replace newer files with older ones. A disk
+//! without [`MAGIC`] is empty.
+//!
+//! The disk is read and written through its page cache (see `cache`). The
+//! flusher ([`flush_softirq`]) runs every [`FLUSH_INTERVAL`] once a disk is
+//! mounted: it snapshots the mounts whose files changed (write-behind of the
+//! files), writes back the pages that have been dirty for long enough and
+//! reads ahead. `Fsync` snapshots the mount right away and waits until the
+//! disk has every page ([`write_back`]).
+
+// The flusher is only started by the x86-64 kernel
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use fallible_collections::FallibleVecGlobal;
+use log::{info, warn};
+
+use super::cache::{self, Cache, ReadAhead};
+use crate::arch::traits::Timer as _;
+use crate::arch::Platform;
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::cnrfs::MlnrKernelNode;
+use crate::error::KError;
+use crate::timer_wheel;
+
+/// Marks a disk that holds the files of a disk mount.
+pub const MAGIC: [u8; 8] = *b"NRKFS001";
+
+/// How often the flusher runs.
+pub const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Is the flusher running?
+static FLUSHER: AtomicBool = AtomicBool::new(false);
+
+/// The files of a disk mount, ready to be written to its disk.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// Index of the disk (see `block`).
+    pub disk: usize,
+    /// Generation of the archive, newer archives have a higher one.
+    pub generation: u64,
+    /// The cpio archive with the files.
+    pub archive: Vec<u8>,
+}
+
+/// Bytes of `disk` the archive can use.
+pub fn capacity(disk: &dyn BlockDevice) -> usize {
+    (disk.sectors().saturating_sub(1) as usize) * SECTOR_SIZE
+}
+
+/// Reads the first sector of `disk` for `ahead`, returns the generation and
+/// length of the archive (`None` if the disk has none).
+fn header(
+    cache: &mut Cache,
+    disk: &mut dyn BlockDevice,
+    ahead: &mut ReadAhead,
+) -> Result<Option<(u64, usize)>, KError> {
+    let mut sector = [0u8; SECTOR_SIZE];
+    cache.read(disk, 0, &mut sector, ahead)?;
+    if sector[..8] != MAGIC {
+        return Ok(None);
+    }
+
+    let generation = u64::from_le_bytes(sector[8..16].try_into().unwrap());
+    let len = u64::from_le_bytes(sector[16..24].try_into().unwrap()) as usize;
+    if len > capacity(disk) {
+        return Err(KError::InvalidArchive { offset: 0 });
+    }
+    Ok(Some((generation, len)))
+}
+
+/// Reads the archive of `disk`, returns its generation and the archive
+/// (empty with generation 0 if the disk has none).
+pub fn load(cache: &mut Cache, disk: &mut dyn BlockDevice) -> Result<(u64, Vec<u8>), KError> {
+    let mut ahead = ReadAhead::default();
+    match header(cache, disk, &mut ahead)? {
+        Some((generation, len)) => {
+            let mut archive = Vec::try_with_capacity(len)?;
+            archive.resize(len, 0);
+            cache.read(disk, SECTOR_SIZE as u64, &mut archive, &mut ahead)?;
+            Ok((generation, archive))
+        }
+        None => Ok((0, Vec::new())),
+    }
+}
+
+/// Writes `snapshot` to the cache of `disk` (dirty as of `now`) unless the
+/// disk already holds the same or a newer generation, returns whether it was
+/// written.
+///
+/// Only the pages that changed are dirty, [`Cache::sync`] or the flusher
+/// write them to the disk.
+pub fn store(
+    cache: &mut Cache,
+    disk: &mut dyn BlockDevice,
+    snapshot: &Snapshot,
+    now: u64,
+) -> Result<bool, KError> {
+    if snapshot.archive.len() > capacity(disk) {
+        return Err(KError::NoSpace);
+    }
+    if let Some((generation, _len)) = header(cache, disk, &mut ReadAhead::default())? {
+        if generation >= snapshot.generation {
+            return Ok(false);
+        }
+    }
+
+    cache.write(disk, SECTOR_SIZE as u64, &snapshot.archive, now)?;
+
+    let mut sector = [0u8; SECTOR_SIZE];
+    sector[..8].copy_from_slice(&MAGIC);
+    sector[8..16].copy_from_slice(&snapshot.generation.to_le_bytes());
+    sector[16..24].copy_from_slice(&(snapshot.archive.len() as u64).to_le_bytes());
+    cache.write(disk, 0, &sector, now)?;
+    Ok(true)
+}
+
+/// Writes `snapshot` to its disk and waits until the disk has every dirty
+/// page of it (the barrier of `Fsync`, see [`store`]).
+pub fn write_back(snapshot: &Snapshot) -> Result<bool, KError> {
+    cache::with_disk(snapshot.disk, |cache, disk| {
+        let written = store(cache, disk, snapshot, Platform::now())?;
+        cache.sync(disk)?;
+        Ok(written)
+    })
+}
+
+/// Reads the archive of disk `idx` (see [`load`]) and the capacity of the
+/// disk.
+pub fn read_disk(idx: usize) -> Result<(u64, Vec<u8>, usize), KError> {
+    cache::with_disk(idx, |cache, disk| {
+        load(cache, disk).map(|(generation, archive)| (generation, archive, capacity(disk)))
+    })
+}
+
+/// Starts the flusher on the current core (once, when the first disk is
+/// mounted).
+pub fn start_flusher() {
+    if FLUSHER.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    match timer_wheel::add(FLUSH_INTERVAL, kick_flusher, 0) {
+        Ok(_timer) => info!(
+            "Disk mounts flushed every {} ms",
+            FLUSH_INTERVAL.as_millis()
+        ),
+        Err(e) => {
+            warn!("Can't start the flusher of disk mounts: {}", e);
+            FLUSHER.store(false, Ordering::Release);
+        }
+    }
+}
+
+/// Raises the `Flush` soft IRQ (timer callback).
+fn kick_flusher(_arg: u64) {
+    crate::softirq::raise(crate::softirq::SoftIrq::Flush);
+}
+
+/// The flusher (`Flush` soft IRQ): puts the snapshots of the disk mounts
+/// whose files changed in the cache, then writes back the pages that have
+/// been dirty for `cache::WRITE_BEHIND` and reads ahead.
+pub fn flush_softirq() {
+    let now = Platform::now();
+    match MlnrKernelNode::write_behind() {
+        Ok(snapshots) => {
+            for snapshot in snapshots.iter() {
+                let r = cache::with_disk(snapshot.disk, |cache, disk| {
+                    store(cache, disk, snapshot, now)
+                });
+                if let Err(e) = r {
+                    warn!("Write-behind to disk {} failed: {}", snapshot.disk, e);
+                }
+            }
+        }
+        Err(e) => warn!("Can't snapshot the disk mounts: {}", e),
+    }
+    cache::flush(now);
+
+    if let Err(e) = timer_wheel::add(FLUSH_INTERVAL, kick_flusher, 0) {
+        warn!("Stopped the flusher of disk mounts: {}", e);
+        FLUSHER.store(false, Ordering::Release);
+    }
+}
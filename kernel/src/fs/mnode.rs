@@ -2,9 +2,10 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use alloc::string::String;
+use alloc::vec::Vec;
 use core::convert::TryFrom;
 
-use kpi::io::FileType;
+use kpi::io::{FileFlags, FileModes, FileType};
 
 use crate::arch::process::UserSlice;
 use crate::error::KError;
@@ -20,6 +21,13 @@ pub struct MemNode {
     name: String,
     node_type: FileType,
     file: Option<File>,
+    /// Target of a symbolic link (empty for files and directories).
+    target: String,
+    /// Index of the mount the mnode belongs to.
+    mount: usize,
+    /// Time of the last write (for LRU eviction). Reads don't update it,
+    /// they run on a single replica and must not change the state.
+    written: u64,
 }
 
 /// Required for the testing
@@ -29,6 +37,7 @@ impl PartialEq for MemNode {
             && (self.name == other.name)
             && (self.node_type == other.node_type)
             && (self.file == other.file)
+            && (self.target == other.target)
     }
 }
 
@@ -40,6 +49,9 @@ impl Default for MemNode {
             name: String::new(),
             node_type: FileType::File,
             file: None,
+            target: String::new(),
+            mount: 0,
+            written: 0,
         }
     }
 }
@@ -53,7 +65,7 @@ impl MemNode {
         node_type: FileType,
     ) -> Result<MemNode, KError> {
         let file = match node_type {
-            FileType::Directory => None,
+            FileType::Directory | FileType::Symlink => None,
             FileType::File => match File::new(modes) {
                 Ok(file) => Some(file),
                 Err(e) => return Err(e),
@@ -65,9 +77,56 @@ impl MemNode {
             name: TryString::try_from(pathname)?.into(),
             node_type,
             file,
+            target: String::new(),
+            mount: 0,
+            written: 0,
         })
     }
 
+    /// Initialize a memory-node for a symbolic link to `target`.
+    pub fn new_symlink(mnode_num: Mnode, pathname: &str, target: &str) -> Result<MemNode, KError> {
+        let mut memnode = MemNode::new(mnode_num, pathname, 0, FileType::Symlink)?;
+        memnode.target = TryString::try_from(target)?.into();
+        Ok(memnode)
+    }
+
+    /// The target of a symbolic link, `None` for files and directories.
+    pub fn target(&self) -> Option<&str> {
+        match self.node_type {
+            FileType::Symlink => Some(&self.target),
+            _ => None,
+        }
+    }
+
+    /// Get the mount the mnode belongs to.
+    pub fn get_mount(&self) -> usize {
+        self.mount
+    }
+
+    /// Move the mnode to another mount.
+    pub fn set_mount(&mut self, mount: usize) {
+        self.mount = mount;
+    }
+
+    /// Record a write at time `now`.
+    pub fn touch(&mut self, now: u64) {
+        self.written = now;
+    }
+
+    /// Get the time of the last write.
+    pub fn last_write(&self) -> u64 {
+        self.written
+    }
+
+    /// Bytes the mnode takes up in its mount (directories and links are
+    /// free).
+    pub fn usage(&self) -> usize {
+        match self.node_type {
+            FileType::Directory | FileType::Symlink => 0,
+            FileType::File => self.get_file_size(),
+        }
+    }
+
     /// Write to an in-memory file.
     pub fn write(&mut self, buffer: &[u8], offset: usize) -> Result<usize, KError> {
         // Return if the user doesn't have write permissions for the file.
@@ -82,23 +141,45 @@ impl MemNode {
 
     /// Read from an in-memory file.
     pub fn read(&self, buffer: &mut UserSlice, offset: usize) -> Result<usize, KError> {
+        match self.read_bounds(offset, buffer.len())? {
+            Some((start, end)) => self.file.as_ref().unwrap().read_file(buffer, start, end),
+            None => Ok(0),
+        }
+    }
+
+    /// Read up to `len` bytes at `offset` from an in-memory file into a
+    /// kernel buffer.
+    pub fn read_buffer(&self, offset: usize, len: usize) -> Result<Vec<u8>, KError> {
+        let mut out = Vec::new();
+        if let Some((start, end)) = self.read_bounds(offset, len)? {
+            out.try_reserve(end - start)?;
+            self.file
+                .as_ref()
+                .unwrap()
+                .read_range(&mut out, start, end)?;
+        }
+        Ok(out)
+    }
+
+    /// The part of the file a read of `len` bytes at `offset` returns (`None`
+    /// if there's nothing to read).
+    fn read_bounds(&self, offset: usize, len: usize) -> Result<Option<(usize, usize)>, KError> {
         // Return if the user doesn't have read permissions for the file.
         if self.node_type != FileType::File || !self.file.as_ref().unwrap().get_mode().is_readable()
         {
             return Err(KError::PermissionError);
         }
 
-        let len: usize = buffer.len();
         let file_size = self.get_file_size();
         if offset > file_size {
-            return Ok(0);
+            return Ok(None);
         }
 
         let bytes_to_read = core::cmp::min(file_size - offset, len);
         let new_offset = offset + bytes_to_read;
 
         if bytes_to_read == 0 {
-            return Ok(0);
+            return Ok(None);
         }
         // Return error if start-offset is greater than or equal to new-offset OR
         // new offset is greater than the file size.
@@ -107,10 +188,25 @@ impl MemNode {
         }
 
         // Read from file only if its not at EOF.
-        self.file
-            .as_ref()
-            .unwrap()
-            .read_file(&mut *buffer, offset, new_offset)
+        Ok(Some((offset, new_offset)))
+    }
+
+    /// Appends the contents of a file to `out` (nothing for directories and
+    /// links).
+    pub fn read_all(&self, out: &mut Vec<u8>) -> Result<(), KError> {
+        match self.file.as_ref() {
+            Some(file) => file.read_all(out),
+            None => Ok(()),
+        }
+    }
+
+    /// The modes of a file, directories and links can be read, written and
+    /// searched.
+    pub fn modes(&self) -> FileModes {
+        match self.file.as_ref() {
+            Some(file) => file.get_mode(),
+            None => FileModes::S_IRWXU,
+        }
     }
 
     /// Get the file size
@@ -123,6 +219,19 @@ impl MemNode {
         self.node_type
     }
 
+    /// Do the modes of the file allow opening it with `flags`?
+    ///
+    /// Reading needs `S_IRUSR`, writing (or truncating) `S_IWUSR`.
+    /// Directories can always be opened.
+    pub fn allows(&self, flags: FileFlags) -> bool {
+        let modes = match self.file.as_ref() {
+            Some(file) if self.node_type == FileType::File => file.get_mode(),
+            _ => return true,
+        };
+        (!flags.is_read() || modes.is_readable())
+            && (!(flags.is_write() || flags.is_truncate()) || modes.is_writable())
+    }
+
     /// Truncate the file in reasponse of O_TRUNC flag.
     pub fn file_truncate(&mut self) -> Result<(), KError> {
         if self.node_type != FileType::File || !self.file.as_ref().unwrap().get_mode().is_writable()
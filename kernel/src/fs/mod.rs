@@ -3,8 +3,11 @@
 
 use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::convert::TryFrom;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use fallible_collections::{try_vec, FallibleVec, FallibleVecGlobal};
 
 use hashbrown::HashMap;
 use kpi::io::*;
@@ -17,14 +20,22 @@ use crate::fallible_string::TryString;
 pub use rwlock::RwLock as NrLock;
 
 pub mod fd;
+pub mod image;
+pub mod rootfs;
 
+mod archive;
+mod cache;
 mod file;
 mod mnode;
+mod mount;
+pub mod path;
 mod rwlock;
 #[cfg(test)]
 mod test;
 
+use archive::EntryKind;
 use mnode::MemNode;
+use mount::{is_below, Mount};
 
 /// The maximum number of open files for a process.
 pub const MAX_FILES_PER_PROCESS: usize = 4096;
@@ -72,6 +83,7 @@ pub trait FileDescriptor {
     fn get_flags(&self) -> FileFlags;
     fn get_offset(&self) -> usize;
     fn update_offset(&self, new_offset: usize);
+    fn advance_offset(&self, len: usize, end: usize) -> (usize, usize);
 }
 
 /// A file descriptor representaion.
@@ -112,6 +124,20 @@ impl FileDescriptor for Fd {
     fn update_offset(&self, new_offset: usize) {
         self.offset.store(new_offset, Ordering::Release);
     }
+
+    /// Atomically move the offset forward by up to `len` bytes without going
+    /// past `end`, returns the old offset and how far it moved.
+    fn advance_offset(&self, len: usize, end: usize) -> (usize, usize) {
+        let mut advanced = 0;
+        let old = self
+            .offset
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |offset| {
+                advanced = core::cmp::min(len, end.saturating_sub(offset));
+                Some(offset + advanced)
+            })
+            .unwrap();
+        (old, advanced)
+    }
 }
 
 /// The mnode number assigned to the first file.
@@ -126,6 +152,10 @@ pub struct MlnrFS {
     files: RwLock<HashMap<String, Arc<Mnode>>>,
     root: (String, Mnode),
     nextmemnode: AtomicUsize,
+    /// Size-limited sub-trees, the first entry is the (unlimited) root.
+    mounts: RwLock<Vec<Mount>>,
+    /// Logical clock to order file writes.
+    clock: AtomicU64,
 }
 
 unsafe impl Sync for MlnrFS {}
@@ -168,6 +198,17 @@ impl Default for MlnrFS {
             files,
             root,
             nextmemnode: AtomicUsize::new(MNODE_OFFSET),
+            mounts: RwLock::new(
+                try_vec![Mount::new(
+                    TryString::try_from(rootdir)
+                        .expect("Not enough memory to initialize system")
+                        .into(),
+                    MountKind::Tmpfs,
+                    usize::MAX,
+                )]
+                .expect("Not enough memory to initialize system"),
+            ),
+            clock: AtomicU64::new(0),
         }
     }
 }
@@ -177,6 +218,348 @@ impl MlnrFS {
     fn get_next_mno(&self) -> usize {
         self.nextmemnode.fetch_add(1, Ordering::Relaxed)
     }
+
+    /// Advance the access clock.
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Find the (innermost) mount that contains `pathname`.
+    fn mount_of(&self, pathname: &str) -> usize {
+        let mounts = self.mounts.read();
+        let mut found = 0;
+        for (idx, mount) in mounts.iter().enumerate() {
+            if mount.contains(pathname) && mounts[found].contains(mount.path()) {
+                found = idx;
+            }
+        }
+        found
+    }
+
+    /// Reserve `bytes` in `mount` for a write to `mnode_num`.
+    ///
+    /// Cache mounts make space by deleting their least recently written files
+    /// (other than `mnode_num`), everything else fails with `NoSpace`.
+    fn charge(&self, mount: usize, bytes: usize, mnode_num: Mnode) -> Result<(), KError> {
+        loop {
+            {
+                let mut mounts = self.mounts.write();
+                if mounts[mount].charge(bytes) {
+                    return Ok(());
+                }
+                if mounts[mount].kind() != MountKind::Cache {
+                    return Err(KError::NoSpace);
+                }
+            }
+
+            let victim = self.least_recently_used(mount, mnode_num)?;
+            self.delete(&victim).map_err(|_e| KError::NoSpace)?;
+        }
+    }
+
+    /// Return `bytes` to `mount`.
+    fn release(&self, mount: usize, bytes: usize) {
+        self.mounts.write()[mount].release(bytes);
+    }
+
+    /// The name of the least recently written file in `mount`, except `skip`.
+    fn least_recently_used(&self, mount: usize, skip: Mnode) -> Result<String, KError> {
+        let files = self.files.read();
+        let mnodes = self.mnodes.read();
+
+        let mut victim: Option<(&String, u64)> = None;
+        for (name, mnode_num) in files.iter() {
+            if **mnode_num == skip {
+                continue;
+            }
+            if let Some(memnode) = mnodes.get(mnode_num) {
+                let memnode = memnode.read();
+                if memnode.get_mount() != mount || memnode.usage() == 0 {
+                    continue;
+                }
+                if victim.map_or(true, |(_, written)| memnode.last_write() < written) {
+                    victim = Some((name, memnode.last_write()));
+                }
+            }
+        }
+
+        let (name, _) = victim.ok_or(KError::NoSpace)?;
+        Ok(TryString::try_from(name.as_str())?.into())
+    }
+
+    /// Mount a file-system of `kind` with a capacity of `limit` bytes on the
+    /// existing directory `pathname`.
+    ///
+    /// Files below `pathname` are moved to the new mount, so it fails with
+    /// `NoSpace` if they exceed `limit`. Disks are mounted with
+    /// [`MlnrFS::mount_disk`].
+    pub fn mount(&self, pathname: &str, kind: MountKind, limit: usize) -> Result<(), KError> {
+        match kind {
+            MountKind::Unknown | MountKind::Disk => Err(KError::InvalidFlags),
+            _ => self.add_mount(pathname, kind, limit).map(|_idx| ()),
+        }
+    }
+
+    /// Mount the files of `archive` (the snapshot `generation` of `disk`, see
+    /// [`image`]) with a capacity of `limit` bytes on the existing, empty
+    /// directory `pathname`.
+    pub fn mount_disk(
+        &self,
+        pathname: &str,
+        disk: usize,
+        generation: u64,
+        limit: usize,
+        archive: &[u8],
+    ) -> Result<(), KError> {
+        if self.mounts.read().iter().any(|m| m.disk() == Some(disk)) {
+            return Err(KError::AlreadyPresent);
+        }
+        let path = pathname.trim_end_matches('/');
+        if self
+            .files
+            .read()
+            .keys()
+            .any(|name| name.len() > path.len() && is_below(path, name))
+        {
+            return Err(KError::AlreadyPresent);
+        }
+
+        let idx = self.add_mount(pathname, MountKind::Disk, limit)?;
+        if !archive.is_empty() {
+            rootfs::extract(self, path, archive)?;
+        }
+        // After the files, they didn't change
+        self.mounts.write()[idx].set_disk(disk, generation);
+        Ok(())
+    }
+
+    /// Adds the mount, returns its index.
+    fn add_mount(&self, pathname: &str, kind: MountKind, limit: usize) -> Result<usize, KError> {
+        match self.lookup(pathname) {
+            Some(mnode) if self.file_info(*mnode).ftype == FileType::Directory.into() => {}
+            _ => return Err(KError::InvalidFile),
+        }
+        let path = pathname.trim_end_matches('/');
+        let path = if path.is_empty() { "/" } else { path };
+        if self.mounts.read().iter().any(|m| m.path() == path) {
+            return Err(KError::AlreadyPresent);
+        }
+
+        let mut mount = Mount::new(TryString::try_from(path)?.into(), kind, limit);
+        let files = self.files.read();
+        let mnodes = self.mnodes.read();
+        let moved = files
+            .iter()
+            .filter(|(name, _)| is_below(path, name))
+            .filter_map(|(_, mnode_num)| mnodes.get(mnode_num));
+
+        for memnode in moved.clone() {
+            if !mount.charge(memnode.read().usage()) {
+                return Err(KError::NoSpace);
+            }
+        }
+        let idx = {
+            let mut mounts = self.mounts.write();
+            mounts.try_reserve(1)?;
+            mounts.push(mount);
+            mounts.len() - 1
+        };
+        for memnode in moved {
+            let mut memnode = memnode.write();
+            self.release(memnode.get_mount(), memnode.usage());
+            memnode.set_mount(idx);
+        }
+
+        Ok(idx)
+    }
+
+    /// The files of the disk mount `mnode_num` belongs to, ready to be
+    /// written to the disk (see [`image`]). `None` if `mnode_num` isn't on a
+    /// disk mount, the file only lives in memory.
+    ///
+    /// Every snapshot of a mount gets the next generation.
+    pub fn snapshot(&self, mnode_num: Mnode) -> Result<Option<image::Snapshot>, KError> {
+        let mount = match self.mnodes.read().get(&mnode_num) {
+            Some(mnode) => mnode.read().get_mount(),
+            None => return Err(KError::InvalidFile),
+        };
+        self.snapshot_mount(mount)
+    }
+
+    /// Snapshots of the disk mounts whose files changed since their last
+    /// snapshot (see [`MlnrFS::snapshot`]), for the flusher.
+    pub fn write_behind(&self) -> Result<Vec<image::Snapshot>, KError> {
+        let mut changed = Vec::new();
+        for (idx, mount) in self.mounts.read().iter().enumerate() {
+            if mount.changed() {
+                changed.try_push(idx)?;
+            }
+        }
+
+        let mut snapshots = Vec::try_with_capacity(changed.len())?;
+        for mount in changed {
+            if let Some(snapshot) = self.snapshot_mount(mount)? {
+                snapshots.push(snapshot);
+            }
+        }
+        Ok(snapshots)
+    }
+
+    /// Take note that files of `mount` changed (disk mounts write them back,
+    /// see [`MlnrFS::write_behind`]).
+    fn changed(&self, mount: usize) {
+        self.mounts.write()[mount].set_changed();
+    }
+
+    /// The files of `mount` (see [`MlnrFS::snapshot`]).
+    fn snapshot_mount(&self, mount: usize) -> Result<Option<image::Snapshot>, KError> {
+        let (path, disk, generation): (String, usize, u64) = {
+            let mut mounts = self.mounts.write();
+            let disk = match mounts[mount].disk() {
+                Some(disk) => disk,
+                None => return Ok(None),
+            };
+            let generation = mounts[mount].next_generation();
+            (
+                TryString::try_from(mounts[mount].path())?.into(),
+                disk,
+                generation,
+            )
+        };
+
+        let files = self.files.read();
+        let mnodes = self.mnodes.read();
+        // Sorted, so every replica writes the same archive
+        let mut names: Vec<&String> = Vec::try_with_capacity(files.len())?;
+        for name in files.keys() {
+            if name.len() > path.len() && is_below(&path, name) {
+                names.push(name);
+            }
+        }
+        names.sort_unstable();
+
+        let mut archive = Vec::new();
+        let mut data = Vec::new();
+        for name in names {
+            let memnode = match files.get(name).and_then(|mnode| mnodes.get(mnode)) {
+                Some(memnode) => memnode.read(),
+                None => continue,
+            };
+            let kind = match memnode.get_mnode_type() {
+                // Files of a mount below this one are on that mount
+                _ if memnode.get_mount() != mount => continue,
+                FileType::File => EntryKind::File,
+                FileType::Directory => EntryKind::Directory,
+                FileType::Symlink => continue,
+            };
+            data.clear();
+            memnode.read_all(&mut data)?;
+            let relative = name[path.len()..].trim_start_matches('/');
+            let mode = (memnode.modes().bits() as u32 & 0o7) << 6;
+            archive::append_cpio(&mut archive, relative, kind, mode, &data)?;
+        }
+        archive::finish_cpio(&mut archive)?;
+
+        Ok(Some(image::Snapshot {
+            disk,
+            generation,
+            archive,
+        }))
+    }
+
+    /// Read up to `len` bytes at `offset` from the file `mnode_num` into a
+    /// kernel buffer (`read` copies to user-space).
+    pub fn read_buffer(
+        &self,
+        mnode_num: Mnode,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, KError> {
+        match self.mnodes.read().get(&mnode_num) {
+            Some(mnode) => mnode.read().read_buffer(offset, len),
+            None => Err(KError::InvalidFile),
+        }
+    }
+
+    /// Write `buffer` at the end of the file `mnode_num`.
+    ///
+    /// The size is read and the file written under the same lock, so
+    /// concurrent appends never overwrite each other. Returns the offset the
+    /// buffer was written at and the number of bytes written.
+    pub fn append(&self, mnode_num: Mnode, buffer: &[u8]) -> Result<(usize, usize), KError> {
+        let mount = match self.mnodes.read().get(&mnode_num) {
+            Some(mnode) => mnode.read().get_mount(),
+            None => return Err(KError::InvalidFile),
+        };
+        self.charge(mount, buffer.len(), mnode_num)?;
+
+        let written = match self.mnodes.read().get(&mnode_num) {
+            Some(mnode) => {
+                let mut mnode = mnode.write();
+                mnode.touch(self.tick());
+                let end = mnode.usage();
+                mnode.write(buffer, end).map(|len| (end, len))
+            }
+            None => Err(KError::InvalidFile),
+        };
+        match written {
+            Ok(_) => self.changed(mount),
+            Err(_) => self.release(mount, buffer.len()),
+        }
+        written
+    }
+
+    /// Check that the modes of `mnode_num` allow opening it with `flags`.
+    pub fn check_access(&self, mnode_num: Mnode, flags: FileFlags) -> Result<(), KError> {
+        match self.mnodes.read().get(&mnode_num) {
+            Some(mnode) if mnode.read().allows(flags) => Ok(()),
+            Some(_) => Err(KError::PermissionError),
+            None => Err(KError::InvalidFile),
+        }
+    }
+
+    /// Create a symbolic link `pathname` that points to `target`.
+    pub fn symlink(&self, target: &str, pathname: &str) -> Result<(), KError> {
+        if target.is_empty() {
+            return Err(KError::InvalidFile);
+        }
+        if self.files.read().get(pathname).is_some() {
+            return Err(KError::AlreadyPresent);
+        }
+
+        let pathname_key = TryString::try_from(pathname)?.into();
+        let mnode_num = self.get_next_mno() as u64;
+        let arc_mnode_num = Arc::try_new(mnode_num)?;
+        let mut mnodes = self.mnodes.write();
+        mnodes.try_reserve(1)?;
+
+        let mut memnode = MemNode::new_symlink(mnode_num, pathname, target)?;
+        memnode.set_mount(self.mount_of(pathname));
+        self.files.write().insert(pathname_key, arc_mnode_num);
+        mnodes.insert(mnode_num, NrLock::new(memnode));
+
+        Ok(())
+    }
+
+    /// The target of `pathname` if it is a symbolic link.
+    pub fn symlink_target(&self, pathname: &str) -> Result<Option<String>, KError> {
+        let mnode_num = match self.files.read().get(pathname) {
+            Some(mnode) => **mnode,
+            None => return Ok(None),
+        };
+        match self.mnodes.read().get(&mnode_num) {
+            Some(memnode) => match memnode.read().target() {
+                Some(target) => Ok(Some(TryString::try_from(target)?.into())),
+                None => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Is there a file (or directory) with the number `mnode_num`?
+    pub fn contains(&self, mnode_num: Mnode) -> bool {
+        self.mnodes.read().contains_key(&mnode_num)
+    }
 }
 
 impl FileSystem for MlnrFS {
@@ -186,6 +569,7 @@ impl FileSystem for MlnrFS {
             return Err(KError::AlreadyPresent);
         }
         let pathname_string = TryString::try_from(pathname)?.into();
+        let mount = self.mount_of(pathname);
 
         let mnode_num = self.get_next_mno() as u64;
         // TODO(error-handling): can we ignore or should we decrease mnode_num
@@ -196,19 +580,45 @@ impl FileSystem for MlnrFS {
 
         // TODO: For now all newly created mnode are for file. How to differentiate
         // between a file and a directory. Take input from the user?
-        let memnode = MemNode::new(mnode_num, pathname, modes, FileType::File)?;
+        let mut memnode = MemNode::new(mnode_num, pathname, modes, FileType::File)?;
+        memnode.set_mount(mount);
 
         self.files.write().insert(pathname_string, arc_mnode_num);
         mnodes.insert(mnode_num, NrLock::new(memnode));
+        self.changed(mount);
 
         Ok(mnode_num)
     }
 
     fn write(&self, mnode_num: Mnode, buffer: &[u8], offset: usize) -> Result<usize, KError> {
-        match self.mnodes.read().get(&mnode_num) {
-            Some(mnode) => mnode.write().write(buffer, offset),
+        let (mount, usage) = match self.mnodes.read().get(&mnode_num) {
+            Some(mnode) => {
+                let mnode = mnode.read();
+                (mnode.get_mount(), mnode.usage())
+            }
+            None => return Err(KError::InvalidFile),
+        };
+
+        // Writing past the end grows the file (fills holes with zeros).
+        let growth = offset
+            .checked_add(buffer.len())
+            .ok_or(KError::InvalidOffset)?
+            .saturating_sub(usage);
+        self.charge(mount, growth, mnode_num)?;
+
+        let written = match self.mnodes.read().get(&mnode_num) {
+            Some(mnode) => {
+                let mut mnode = mnode.write();
+                mnode.touch(self.tick());
+                mnode.write(buffer, offset)
+            }
             None => Err(KError::InvalidFile),
+        };
+        match written {
+            Ok(_len) => self.changed(mount),
+            Err(_) => self.release(mount, growth),
         }
+        written
     }
 
     fn read(
@@ -229,16 +639,17 @@ impl FileSystem for MlnrFS {
 
     fn file_info(&self, mnode: Mnode) -> FileInfo {
         match self.mnodes.read().get(&mnode) {
-            Some(mnode) => match mnode.read().get_mnode_type() {
-                FileType::Directory => FileInfo {
-                    fsize: 0,
-                    ftype: FileType::Directory.into(),
-                },
-                FileType::File => FileInfo {
-                    fsize: mnode.read().get_file_size() as u64,
-                    ftype: FileType::File.into(),
-                },
-            },
+            Some(mnode) => {
+                let mnode = mnode.read();
+                let mounts = self.mounts.read();
+                let mount = &mounts[mnode.get_mount()];
+                FileInfo {
+                    fsize: mnode.usage() as u64,
+                    ftype: mnode.get_mnode_type().into(),
+                    mount_used: mount.used() as u64,
+                    mount_limit: mount.limit() as u64,
+                }
+            }
             None => unreachable!("file_info: shouldn't reach here"),
         }
     }
@@ -247,7 +658,11 @@ impl FileSystem for MlnrFS {
         let mut files = self.files.write();
         if let Some(mnode) = files.get(pathname) {
             if Arc::strong_count(mnode) == 1 {
-                self.mnodes.write().remove(mnode);
+                if let Some(memnode) = self.mnodes.write().remove(mnode) {
+                    let memnode = memnode.read();
+                    self.release(memnode.get_mount(), memnode.usage());
+                    self.changed(memnode.get_mount());
+                }
             } else {
                 return Err(KError::PermissionError);
             }
@@ -263,7 +678,14 @@ impl FileSystem for MlnrFS {
     fn truncate(&self, pathname: &str) -> Result<(), KError> {
         match self.files.read().get(pathname) {
             Some(mnode) => match self.mnodes.read().get(mnode) {
-                Some(memnode) => memnode.write().file_truncate(),
+                Some(memnode) => {
+                    let mut memnode = memnode.write();
+                    let usage = memnode.usage();
+                    memnode.file_truncate()?;
+                    self.release(memnode.get_mount(), usage);
+                    self.changed(memnode.get_mount());
+                    Ok(())
+                }
                 None => Err(KError::InvalidFile),
             },
             None => Err(KError::InvalidFile),
@@ -271,9 +693,10 @@ impl FileSystem for MlnrFS {
     }
 
     fn rename(&self, oldname: &str, newname: &str) -> Result<(), KError> {
-        if self.files.read().get(oldname).is_none() {
-            return Err(KError::InvalidFile);
-        }
+        let mnode_num = match self.files.read().get(oldname) {
+            Some(mnode) => **mnode,
+            None => return Err(KError::InvalidFile),
+        };
         let newname_key = TryString::try_from(newname)?.into();
 
         // If the newfile exists then overwrite it with the oldfile.
@@ -281,15 +704,44 @@ impl FileSystem for MlnrFS {
             self.delete(newname).unwrap();
         }
 
+        // Moving to another mount needs space there.
+        let (old_mount, usage) = match self.mnodes.read().get(&mnode_num) {
+            Some(memnode) => {
+                let memnode = memnode.read();
+                (memnode.get_mount(), memnode.usage())
+            }
+            None => return Err(KError::InvalidFile),
+        };
+        let new_mount = self.mount_of(newname);
+        if new_mount != old_mount {
+            self.charge(new_mount, usage, mnode_num)?;
+        }
+
         // TODO: Can we optimize it somehow?
         let mut lock_at_root = self.files.write();
-        match lock_at_root.remove_entry(oldname) {
+        let renamed = match lock_at_root.remove_entry(oldname) {
             Some((_key, oldnmode)) => match lock_at_root.insert(newname_key, oldnmode) {
                 None => Ok(()),
                 Some(_) => Err(KError::PermissionError),
             },
             None => Err(KError::InvalidFile),
+        };
+        drop(lock_at_root);
+
+        if new_mount != old_mount {
+            match (&renamed, self.mnodes.read().get(&mnode_num)) {
+                (Ok(()), Some(memnode)) => {
+                    memnode.write().set_mount(new_mount);
+                    self.release(old_mount, usage);
+                }
+                _ => self.release(new_mount, usage),
+            }
+        }
+        if renamed.is_ok() {
+            self.changed(old_mount);
+            self.changed(new_mount);
         }
+        renamed
     }
 
     /// Create a directory. The implementation is quite simplistic for now, and only used
@@ -307,12 +759,15 @@ impl FileSystem for MlnrFS {
         let mut mnodes = self.mnodes.write();
         mnodes.try_reserve(1)?;
 
-        let memnode = match MemNode::new(mnode_num, pathname, modes, FileType::Directory) {
+        let mount = self.mount_of(pathname);
+        let mut memnode = match MemNode::new(mnode_num, pathname, modes, FileType::Directory) {
             Ok(memnode) => memnode,
             Err(e) => return Err(e),
         };
+        memnode.set_mount(mount);
         self.files.write().insert(pathname_key, arc_mnode_num);
         mnodes.insert(mnode_num, NrLock::new(memnode));
+        self.changed(mount);
 
         Ok(())
     }
@@ -0,0 +1,114 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Size-limited sub-trees of the file-system.
+//!
+//! A mount can also be backed by a disk (see [`super::image`]).
+
+use alloc::string::String;
+
+use kpi::io::MountKind;
+
+/// A directory (and everything below it) that can hold at most `limit`
+/// bytes of file data.
+#[derive(Debug)]
+pub struct Mount {
+    path: String,
+    kind: MountKind,
+    limit: usize,
+    used: usize,
+    /// Disk that keeps the files (for `MountKind::Disk`).
+    disk: Option<usize>,
+    /// Generation of the last snapshot of the files for the disk.
+    generation: u64,
+    /// Did the files change since the last snapshot?
+    changed: bool,
+}
+
+impl Mount {
+    pub fn new(path: String, kind: MountKind, limit: usize) -> Mount {
+        Mount {
+            path,
+            kind,
+            limit,
+            used: 0,
+            disk: None,
+            generation: 0,
+            changed: false,
+        }
+    }
+
+    /// Keep the files on `disk`, which holds the snapshot `generation`.
+    pub fn set_disk(&mut self, disk: usize, generation: u64) {
+        self.disk = Some(disk);
+        self.generation = generation;
+    }
+
+    pub fn disk(&self) -> Option<usize> {
+        self.disk
+    }
+
+    /// The generation of the next snapshot of the files (which has all
+    /// changes so far).
+    pub fn next_generation(&mut self) -> u64 {
+        self.changed = false;
+        self.generation += 1;
+        self.generation
+    }
+
+    /// Take note that the files changed (only matters on a disk).
+    pub fn set_changed(&mut self) {
+        self.changed = self.disk.is_some();
+    }
+
+    /// Did the files on the disk change since the last snapshot?
+    pub fn changed(&self) -> bool {
+        self.changed
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn kind(&self) -> MountKind {
+        self.kind
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Does the mount contain `pathname`?
+    pub fn contains(&self, pathname: &str) -> bool {
+        is_below(&self.path, pathname)
+    }
+
+    /// Account for `bytes` more, fails if the mount would exceed its limit.
+    pub fn charge(&mut self, bytes: usize) -> bool {
+        match self.used.checked_add(bytes) {
+            Some(used) if used <= self.limit => {
+                self.used = used;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Account for `bytes` less.
+    pub fn release(&mut self, bytes: usize) {
+        debug_assert!(self.used >= bytes, "Released more than was charged");
+        self.used = self.used.saturating_sub(bytes);
+    }
+}
+
+/// Is `pathname` the directory `path` or inside of it?
+pub fn is_below(path: &str, pathname: &str) -> bool {
+    path == "/"
+        || pathname
+            .strip_prefix(path)
+            .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
+}
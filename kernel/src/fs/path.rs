@@ -0,0 +1,101 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Resolves the path names processes pass to the file-system.
+//!
+//! [`MlnrFS`](super::MlnrFS) finds files by their absolute path. [`resolve`]
+//! brings a name into that form: relative names start at the root, empty and
+//! `.` components are dropped, `..` removes the component before it (`..` of
+//! the root is the root) and symbolic links are replaced by their target when
+//! they're found. A `..` after a link therefore goes to the parent of the
+//! target, like on POSIX systems.
+
+use alloc::string::String;
+
+use crate::error::KError;
+use crate::fallible_string::FallibleString;
+
+/// How many links [`resolve`] follows for one name before it gives up with
+/// `SymlinkLoop` (Linux allows 40 as well).
+pub const MAX_SYMLINKS: usize = 40;
+
+/// Longest name [`resolve`] handles, also while it expands links.
+pub const MAX_PATH: usize = 4096;
+
+/// Resolves `name` to an absolute path without `.`, `..`, repeated or
+/// trailing slashes and links.
+///
+/// `readlink` returns the target of a (resolved) path if it is a link. A link
+/// in the last component is only replaced if `follow` is set or `name` ends
+/// with a slash; components don't have to exist.
+pub fn resolve<F>(name: &str, follow: bool, mut readlink: F) -> Result<String, KError>
+where
+    F: FnMut(&str) -> Result<Option<String>, KError>,
+{
+    if name.is_empty() {
+        return Err(KError::InvalidFile);
+    }
+    if name.len() > MAX_PATH {
+        return Err(KError::InvalidLength);
+    }
+
+    let mut resolved = String::try_with_capacity(name.len() + 1)?;
+    let mut rest = String::try_with_capacity(name.len())?;
+    rest.try_push_str(name)?;
+    let mut pos = 0;
+    let mut links = 0;
+
+    loop {
+        let start = pos + rest[pos..].len() - rest[pos..].trim_start_matches('/').len();
+        let end = rest[start..]
+            .find('/')
+            .map_or(rest.len(), |idx| start + idx);
+        if start == end {
+            break;
+        }
+        pos = end;
+
+        match &rest[start..end] {
+            "." => {}
+            ".." => {
+                let parent = resolved.rfind('/').unwrap_or(0);
+                resolved.truncate(parent);
+            }
+            component => {
+                let len = resolved.len();
+                resolved.try_push('/')?;
+                resolved.try_push_str(component)?;
+                if !follow && pos == rest.len() {
+                    continue;
+                }
+
+                if let Some(target) = readlink(&resolved)? {
+                    links += 1;
+                    if links > MAX_SYMLINKS {
+                        return Err(KError::SymlinkLoop);
+                    }
+                    if target.len() + rest.len() - pos > MAX_PATH {
+                        return Err(KError::InvalidLength);
+                    }
+
+                    let mut expanded = String::try_with_capacity(target.len() + rest.len() - pos)?;
+                    expanded.try_push_str(&target)?;
+                    expanded.try_push_str(&rest[pos..])?;
+                    rest = expanded;
+                    pos = 0;
+
+                    if target.starts_with('/') {
+                        resolved.clear();
+                    } else {
+                        resolved.truncate(len);
+                    }
+                }
+            }
+        }
+    }
+
+    if resolved.is_empty() {
+        resolved.try_push('/')?;
+    }
+    Ok(resolved)
+}
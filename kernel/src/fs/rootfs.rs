@@ -0,0 +1,159 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Populates the file-system from an archive at boot.
+//!
+//! The bootloader passes a cpio or tar archive as the `rootfs` module (see
+//! `scripts/mkrootfs.py`). [`init`] checks it before the file-system
+//! replicas are created and every replica extracts it when it's created
+//! ([`populate`]), so they all start out with the same files. This ships
+//! programs, configuration and test data without a block device.
+
+// Only the x86-64 kernel gets modules from a bootloader
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::string::String;
+
+use kpi::io::FileModes;
+use log::{debug, info};
+
+use super::archive::{self, EntryKind};
+use super::{FileSystem, MlnrFS, Modes};
+use crate::error::KError;
+use crate::fallible_string::FallibleString;
+
+/// Name of the module with the archive.
+pub const MODULE_NAME: &str = "rootfs";
+
+/// The archive (if the bootloader passed a valid one).
+static IMAGE: spin::Once<&'static [u8]> = spin::Once::new();
+
+/// Checks the archive in `image` and uses it for all file-system replicas
+/// created from now on.
+pub fn init(image: &'static [u8]) -> Result<(), KError> {
+    let (mut files, mut directories, mut bytes) = (0, 0, 0);
+    for entry in archive::entries(image)? {
+        let entry = entry?;
+        match entry.kind {
+            // The root itself
+            _ if entry.path.is_empty() => {}
+            EntryKind::File => {
+                files += 1;
+                bytes += entry.data.len();
+            }
+            EntryKind::Directory => directories += 1,
+            EntryKind::Other => debug!("Skipping {} in {}", entry.path, MODULE_NAME),
+        }
+    }
+
+    info!(
+        "Root file-system: {} file(s) ({} bytes) and {} directory(ies)",
+        files, bytes, directories
+    );
+    IMAGE.call_once(|| image);
+    Ok(())
+}
+
+/// The contents of the file at `path` (relative to the root) in the
+/// archive, e.g., for configuration the kernel reads before any process
+/// runs.
+pub fn file(path: &str) -> Option<&'static [u8]> {
+    let image: &'static [u8] = IMAGE.get()?;
+    archive::entries(image)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .find(|entry| entry.kind == EntryKind::File && entry.path == path)
+        .map(|entry| entry.data)
+}
+
+/// Extracts the archive (if there is one) into `fs`.
+///
+/// Every replica does this when it's created, one that failed (e.g., ran
+/// out of memory) would have different files than the others: give up.
+pub fn populate(fs: &MlnrFS) {
+    if let Some(image) = IMAGE.get() {
+        if let Err(e) = extract(fs, "", image) {
+            panic!(
+                "Couldn't populate the file-system from {}: {}",
+                MODULE_NAME, e
+            );
+        }
+    }
+}
+
+/// Extracts the archive in `image` into the existing directory `root` of
+/// `fs` (without trailing slash, empty for the root).
+pub(super) fn extract(fs: &MlnrFS, root: &str, image: &[u8]) -> Result<(), KError> {
+    for entry in archive::entries(image)? {
+        let entry = entry?;
+        if entry.path.is_empty() || entry.kind == EntryKind::Other {
+            continue;
+        }
+
+        let mut path = String::try_with_capacity(root.len() + entry.path.len() + 1)?;
+        path.try_push_str(root)?;
+        path.try_push('/')?;
+        path.try_push_str(entry.path)?;
+        // Archives don't have to list the parent directories
+        for (idx, _) in path.match_indices('/').filter(|(idx, _)| *idx > root.len()) {
+            mkdir(fs, &path[..idx], FileModes::S_IRWXU.into())?;
+        }
+
+        let modes = permissions(entry.mode);
+        match entry.kind {
+            EntryKind::Directory => mkdir(fs, &path, modes)?,
+            EntryKind::File => {
+                let mnode = fs.create(&path, modes)?;
+                if !entry.data.is_empty() {
+                    fs.write(mnode, entry.data, 0)?;
+                }
+            }
+            EntryKind::Other => unreachable!("Skipped above"),
+        }
+    }
+    Ok(())
+}
+
+/// Creates the directory `path` unless it exists.
+fn mkdir(fs: &MlnrFS, path: &str, modes: Modes) -> Result<(), KError> {
+    if fs.lookup(path).is_some() {
+        return Ok(());
+    }
+    fs.mkdir(path, modes)
+}
+
+/// The user permissions of a UNIX mode as `FileModes`.
+fn permissions(mode: u32) -> Modes {
+    ((mode >> 6) & 0o7) as Modes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::archive::tests::cpio_entry;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn extract_cpio() {
+        let mut image = Vec::new();
+        cpio_entry(&mut image, "./etc/motd", 0o100644, b"hello");
+        cpio_entry(&mut image, "./bin", 0o040755, &[]);
+        cpio_entry(&mut image, "TRAILER!!!", 0, &[]);
+
+        let fs = MlnrFS::default();
+        extract(&fs, "", &image).expect("Valid archive");
+
+        let etc = fs.lookup("/etc").expect("Parent was created");
+        assert_eq!(
+            fs.file_info(*etc).ftype,
+            kpi::io::FileType::Directory.into()
+        );
+        let motd = fs.lookup("/etc/motd").expect("File was created");
+        assert_eq!(fs.file_info(*motd).fsize, 5);
+        assert!(fs.lookup("/bin").is_some());
+        assert_eq!(
+            permissions(0o100644),
+            FileModes::S_IRUSR.bits() | FileModes::S_IWUSR.bits()
+        );
+    }
+}
@@ -9,6 +9,7 @@ use core::cmp::{Eq, PartialEq};
 use core::sync::atomic::Ordering;
 
 use crate::alloc::borrow::ToOwned;
+use crate::memory::BASE_PAGE_SIZE;
 
 use kpi::io::*;
 use log::trace;
@@ -290,7 +291,11 @@ impl FileSystem for ModelFS {
 
     /// Returns a `dummy` file-info.
     fn file_info(&self, _mnode: Mnode) -> FileInfo {
-        FileInfo { ftype: 0, fsize: 0 }
+        FileInfo {
+            ftype: 0,
+            fsize: 0,
+            ..Default::default()
+        }
     }
 
     /// Return a `dummy` response as this function is only used for open with O_TRUNC flag.
@@ -709,7 +714,15 @@ fn test_file_info() {
         memfs.files.read().get(&String::from("file.txt")),
         Some(&Arc::new(2))
     );
-    assert_eq!(memfs.file_info(2), FileInfo { ftype: 2, fsize: 0 });
+    assert_eq!(
+        memfs.file_info(2),
+        FileInfo {
+            ftype: 2,
+            fsize: 0,
+            mount_used: 0,
+            mount_limit: u64::MAX,
+        }
+    );
 }
 
 /// Test file deletion.
@@ -813,3 +826,370 @@ fn test_file_rename_to_existent_file() {
     // New file points to old mnode.
     assert_eq!(*memfs.lookup(newname).unwrap(), oldmnode);
 }
+
+#[test]
+fn test_fd_root_path() {
+    let fdesc = fd::FileDesc::default();
+    assert_eq!(fdesc.root(), None);
+    assert_eq!(fdesc.path("file.txt").unwrap(), "file.txt");
+
+    let fdesc = fd::FileDesc::with_root("/").unwrap();
+    assert_eq!(fdesc.root(), None);
+
+    let fdesc = fd::FileDesc::with_root("scratch/").unwrap();
+    assert_eq!(fdesc.root(), Some("/scratch"));
+    assert_eq!(fdesc.path("file.txt").unwrap(), "/scratch/file.txt");
+    assert_eq!(fdesc.path("/file.txt").unwrap(), "/scratch/file.txt");
+    assert_eq!(fdesc.path("../file.txt"), Err(KError::PermissionError));
+    assert_eq!(fdesc.path("/").unwrap(), "/scratch");
+}
+
+/// Resolve `name` against the links in `memfs`.
+fn resolve(memfs: &MlnrFS, name: &str, follow: bool) -> Result<String, KError> {
+    path::resolve(name, follow, |link| memfs.symlink_target(link))
+}
+
+#[test]
+fn test_path_normalize() {
+    let memfs: MlnrFS = Default::default();
+    assert_eq!(resolve(&memfs, "file.txt", true).unwrap(), "/file.txt");
+    assert_eq!(resolve(&memfs, "//a/./b///c/", true).unwrap(), "/a/b/c");
+    assert_eq!(resolve(&memfs, "/a/b/../../c/..", true).unwrap(), "/");
+    assert_eq!(resolve(&memfs, "../../a/..", true).unwrap(), "/");
+    assert_eq!(resolve(&memfs, "/.", true).unwrap(), "/");
+    assert_eq!(resolve(&memfs, "", true), Err(KError::InvalidFile));
+    let long = "a/".repeat(path::MAX_PATH);
+    assert_eq!(resolve(&memfs, &long, true), Err(KError::InvalidLength));
+}
+
+#[test]
+fn test_path_symlinks() {
+    let memfs: MlnrFS = Default::default();
+    memfs.mkdir("/dir", FileModes::S_IRWXU.into()).unwrap();
+    memfs.mkdir("/dir/sub", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(memfs.symlink("/dir/sub", "/abs"), Ok(()));
+    assert_eq!(memfs.symlink("sub", "/dir/rel"), Ok(()));
+    assert_eq!(
+        memfs.symlink("sub", "/dir/rel"),
+        Err(KError::AlreadyPresent)
+    );
+    assert_eq!(memfs.symlink("", "/empty"), Err(KError::InvalidFile));
+    assert_eq!(
+        memfs.symlink_target("/dir/rel").unwrap().as_deref(),
+        Some("sub")
+    );
+    assert_eq!(memfs.symlink_target("/dir").unwrap(), None);
+    assert_eq!(memfs.symlink_target("/missing").unwrap(), None);
+
+    assert_eq!(resolve(&memfs, "/abs/f", true).unwrap(), "/dir/sub/f");
+    assert_eq!(resolve(&memfs, "/dir/rel/f", true).unwrap(), "/dir/sub/f");
+    // `..` goes to the parent of the target, not of the link:
+    assert_eq!(resolve(&memfs, "/abs/../f", true).unwrap(), "/dir/f");
+    // The last component is only followed on request (or with a slash):
+    assert_eq!(resolve(&memfs, "/abs", true).unwrap(), "/dir/sub");
+    assert_eq!(resolve(&memfs, "/abs", false).unwrap(), "/abs");
+    assert_eq!(resolve(&memfs, "/abs/", false).unwrap(), "/dir/sub");
+    // Links don't take space and can be deleted like files.
+    assert_eq!(memfs.file_info(*memfs.lookup("/abs").unwrap()).fsize, 0);
+    assert_eq!(memfs.delete("/abs"), Ok(()));
+    assert_eq!(resolve(&memfs, "/abs/f", true).unwrap(), "/abs/f");
+}
+
+#[test]
+fn test_path_symlink_loop() {
+    let memfs: MlnrFS = Default::default();
+    memfs.symlink("/b", "/a").unwrap();
+    memfs.symlink("a", "/b").unwrap();
+    memfs.symlink("self/x", "/self").unwrap();
+    assert_eq!(resolve(&memfs, "/a", true), Err(KError::SymlinkLoop));
+    assert_eq!(resolve(&memfs, "/a/file", false), Err(KError::SymlinkLoop));
+    assert_eq!(resolve(&memfs, "/self", true), Err(KError::SymlinkLoop));
+    assert_eq!(resolve(&memfs, "/a", false).unwrap(), "/a");
+
+    // A chain just below the limit resolves.
+    memfs.symlink("/end", "/l0").unwrap();
+    for i in 1..path::MAX_SYMLINKS {
+        let target = alloc::format!("/l{}", i - 1);
+        memfs.symlink(&target, &alloc::format!("/l{}", i)).unwrap();
+    }
+    let last = alloc::format!("/l{}", path::MAX_SYMLINKS - 1);
+    assert_eq!(resolve(&memfs, &last, true).unwrap(), "/end");
+    let name = alloc::format!("/l{}", path::MAX_SYMLINKS);
+    memfs.symlink(&last, &name).unwrap();
+    assert_eq!(resolve(&memfs, &name, true), Err(KError::SymlinkLoop));
+}
+
+#[test]
+fn test_fd_limit() {
+    let mut fdesc = fd::FileDesc::default();
+    assert_eq!(fdesc.limit(), MAX_FILES_PER_PROCESS);
+    assert_eq!(fdesc.set_limit(2), Ok(()));
+    assert!(fdesc.set_limit(3).is_err());
+
+    assert_eq!(fdesc.allocate_fd().map(|(fid, _fd)| fid), Ok(0));
+    assert_eq!(fdesc.allocate_fd().map(|(fid, _fd)| fid), Ok(1));
+    assert!(matches!(fdesc.allocate_fd(), Err(KError::OpenFileLimit)));
+    assert_eq!(fdesc.deallocate_fd(0), Ok(0));
+    assert_eq!(fdesc.allocate_fd().map(|(fid, _fd)| fid), Ok(0));
+}
+
+#[test]
+fn test_fd_inherit() {
+    let mut parent = fd::FileDesc::default();
+    parent.set_limit(16).unwrap();
+    parent.restore_fd(0, 1, FileFlags::O_RDWR, 8).unwrap();
+    parent
+        .restore_fd(1, 2, FileFlags::O_RDONLY | FileFlags::O_CLOEXEC, 0)
+        .unwrap();
+    parent.restore_fd(3, 3, FileFlags::O_WRONLY, 0).unwrap();
+
+    let mut child = fd::FileDesc::default();
+    parent.inherit(&mut child, 0b11).unwrap();
+    assert_eq!(child.limit(), 16);
+    let fd = child.get_fd(0).expect("Inherited");
+    assert_eq!(
+        (fd.get_mnode(), fd.get_flags(), fd.get_offset()),
+        (1, FileFlags::O_RDWR, 8)
+    );
+    // O_CLOEXEC and not asked for
+    assert!(child.get_fd(1).is_none());
+    assert!(child.get_fd(3).is_none());
+
+    // The offsets advance on their own
+    child.get_fd(0).unwrap().update_offset(12);
+    assert_eq!(parent.get_fd(0).unwrap().get_offset(), 8);
+
+    let mut child = fd::FileDesc::default();
+    assert_eq!(
+        parent.inherit(&mut child, 0b100),
+        Err(KError::InvalidFileDescriptor)
+    );
+}
+
+#[test]
+fn test_mount_limit() {
+    let memfs: MlnrFS = Default::default();
+    assert_eq!(
+        memfs.mount("/tmp", MountKind::Tmpfs, 16),
+        Err(KError::InvalidFile)
+    );
+    memfs.mkdir("/tmp", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(memfs.mount("/tmp", MountKind::Tmpfs, 16), Ok(()));
+    assert_eq!(
+        memfs.mount("/tmp", MountKind::Tmpfs, 16),
+        Err(KError::AlreadyPresent)
+    );
+
+    let outside = memfs.create("file.txt", FileModes::S_IRWXU.into()).unwrap();
+    let inside = memfs
+        .create("/tmp/file.txt", FileModes::S_IRWXU.into())
+        .unwrap();
+    let buffer = [0xb; 10];
+    assert_eq!(memfs.write(inside, &buffer, 0), Ok(10));
+    assert_eq!(memfs.write(inside, &buffer, 10), Err(KError::NoSpace));
+    // Overwriting doesn't need more space.
+    assert_eq!(memfs.write(inside, &buffer, 6), Ok(10));
+    assert_eq!(memfs.write(outside, &buffer, 10), Ok(10));
+
+    let finfo = memfs.file_info(inside);
+    assert_eq!(
+        (finfo.fsize, finfo.mount_used, finfo.mount_limit),
+        (16, 16, 16)
+    );
+
+    // Moving a file out of the mount frees its space.
+    assert_eq!(memfs.rename("/tmp/file.txt", "moved.txt"), Ok(()));
+    assert_eq!(memfs.file_info(inside).mount_limit, u64::MAX);
+    let inside = memfs
+        .create("/tmp/new.txt", FileModes::S_IRWXU.into())
+        .unwrap();
+    assert_eq!(memfs.write(inside, &buffer, 0), Ok(10));
+    assert_eq!(memfs.file_info(inside).mount_used, 10);
+}
+
+#[test]
+fn test_mount_cache_eviction() {
+    let memfs: MlnrFS = Default::default();
+    memfs.mkdir("/cache", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(memfs.mount("/cache", MountKind::Cache, 20), Ok(()));
+
+    let buffer = [0xb; 10];
+    let a = memfs.create("/cache/a", FileModes::S_IRWXU.into()).unwrap();
+    let b = memfs.create("/cache/b", FileModes::S_IRWXU.into()).unwrap();
+    let c = memfs.create("/cache/c", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(memfs.write(a, &buffer, 0), Ok(10));
+    assert_eq!(memfs.write(b, &buffer, 0), Ok(10));
+
+    // Writing `a` again makes `b` the least recently written file, reads
+    // don't count (they only happen on one replica).
+    assert_eq!(memfs.write(a, &buffer, 0), Ok(10));
+    let mut rbuf = [0; 10];
+    assert_eq!(
+        memfs.read(b, &mut UserSlice::new(rbuf.as_mut_ptr() as u64, 10), 0),
+        Ok(10)
+    );
+    assert_eq!(memfs.write(c, &buffer, 0), Ok(10));
+    assert!(memfs.lookup("/cache/a").is_some());
+    assert!(memfs.lookup("/cache/b").is_none());
+    assert_eq!(memfs.file_info(c).mount_used, 20);
+
+    // A single file can't exceed the mount.
+    assert_eq!(memfs.write(c, &[0xb; 30], 0), Err(KError::NoSpace));
+}
+
+#[test]
+fn test_mount_disk() {
+    let memfs: MlnrFS = Default::default();
+    memfs.mkdir("/disk", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(
+        memfs.mount("/disk", MountKind::Disk, 4096),
+        Err(KError::InvalidFlags)
+    );
+    assert_eq!(memfs.mount_disk("/disk", 0, 0, 4096, &[]), Ok(()));
+    memfs.mkdir("/other", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(
+        memfs.mount_disk("/other", 0, 0, 4096, &[]),
+        Err(KError::AlreadyPresent)
+    );
+
+    let outside = memfs.create("file.txt", FileModes::S_IRWXU.into()).unwrap();
+    let a = memfs.create("/disk/a", FileModes::S_IRUSR.into()).unwrap();
+    memfs.mkdir("/disk/dir", FileModes::S_IRWXU.into()).unwrap();
+    let b = memfs
+        .create("/disk/dir/b", FileModes::S_IRWXU.into())
+        .unwrap();
+    assert_eq!(memfs.write(b, &[0xb; 5000], 0), Ok(5000));
+    assert!(matches!(memfs.snapshot(outside), Ok(None)));
+
+    let first = memfs.snapshot(a).unwrap().expect("On a disk mount");
+    let second = memfs.snapshot(b).unwrap().expect("On a disk mount");
+    assert_eq!((first.disk, first.generation, second.generation), (0, 1, 2));
+
+    // Older snapshots don't replace newer ones on the disk.
+    let mut disk = crate::block::MemDisk::new(32).expect("Has memory");
+    let mut pages = cache::Cache::default();
+    assert_eq!(image::store(&mut pages, &mut disk, &second, 0), Ok(true));
+    assert_eq!(image::store(&mut pages, &mut disk, &first, 0), Ok(false));
+
+    // The disk only has it once the cache is synced.
+    let mut cold = cache::Cache::default();
+    assert_eq!(image::load(&mut cold, &mut disk).map(|(g, _a)| g), Ok(0));
+    pages.sync(&mut disk).expect("Fits");
+    let mut cold = cache::Cache::default();
+    let (generation, archive) = image::load(&mut cold, &mut disk).expect("Has an archive");
+    assert_eq!(generation, 2);
+
+    // Mounting the disk again brings back the files.
+    let memfs: MlnrFS = Default::default();
+    memfs.mkdir("/mnt", FileModes::S_IRWXU.into()).unwrap();
+    memfs.create("/mnt/x", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(
+        memfs.mount_disk("/mnt", 0, generation, 4096, &archive),
+        Err(KError::AlreadyPresent)
+    );
+    memfs.mkdir("/disk", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(
+        memfs.mount_disk("/disk", 0, generation, 16 * 1024, &archive),
+        Ok(())
+    );
+    let a = memfs.lookup("/disk/a").expect("File was restored");
+    assert_eq!(
+        memfs.check_access(*a, FileFlags::O_WRONLY),
+        Err(KError::PermissionError)
+    );
+    let dir = memfs.lookup("/disk/dir").expect("Directory was restored");
+    assert_eq!(memfs.file_info(*dir).ftype, FileType::Directory.into());
+    let b = memfs.lookup("/disk/dir/b").expect("File was restored");
+    let finfo = memfs.file_info(*b);
+    assert_eq!((finfo.fsize, finfo.mount_used), (5000, 5000));
+    assert_eq!(memfs.snapshot(*b).unwrap().unwrap().generation, 3);
+
+    // Only disk mounts whose files changed are written behind.
+    assert!(memfs.write_behind().unwrap().is_empty());
+    assert_eq!(memfs.write(*b, &[0xc; 10], 0), Ok(10));
+    let other = memfs.create("other", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(memfs.write(other, &[0xc; 10], 0), Ok(10));
+    let snapshots = memfs.write_behind().unwrap();
+    assert_eq!(snapshots.len(), 1);
+    assert_eq!((snapshots[0].disk, snapshots[0].generation), (0, 4));
+    assert!(memfs.write_behind().unwrap().is_empty());
+}
+
+#[test]
+fn test_file_append() {
+    let memfs: MlnrFS = Default::default();
+    let mnode = memfs.create("file.txt", FileModes::S_IRWXU.into()).unwrap();
+    assert_eq!(memfs.write(mnode, &[0xa; 10], 5), Ok(10));
+
+    assert_eq!(memfs.append(mnode, &[0xb; 4]), Ok((15, 4)));
+    assert_eq!(memfs.append(mnode, &[0xc; 1]), Ok((19, 1)));
+    assert_eq!(memfs.file_info(mnode).fsize, 20);
+    assert_eq!(memfs.file_info(mnode).mount_used, 20);
+    assert_eq!(memfs.append(42, &[0xb; 4]), Err(KError::InvalidFile));
+
+    let ronly = memfs
+        .create("ronly.txt", FileModes::S_IRUSR.into())
+        .unwrap();
+    assert_eq!(memfs.append(ronly, &[0xb; 4]), Err(KError::PermissionError));
+    assert_eq!(memfs.file_info(ronly).mount_used, 20);
+}
+
+/// Kernel reads of a file (e.g., a checkpoint image) across buffers.
+#[test]
+fn test_file_read_buffer() {
+    let memfs: MlnrFS = Default::default();
+    let mnode = memfs.create("image", FileModes::S_IRWXU.into()).unwrap();
+    let data: Vec<u8> = (0..3 * BASE_PAGE_SIZE).map(|i| (i % 251) as u8).collect();
+    assert_eq!(memfs.write(mnode, &data, 0), Ok(data.len()));
+
+    let offset = BASE_PAGE_SIZE - 8;
+    assert_eq!(
+        memfs.read_buffer(mnode, offset, BASE_PAGE_SIZE),
+        Ok(data[offset..offset + BASE_PAGE_SIZE].to_vec())
+    );
+    // Short at the end, nothing after it
+    assert_eq!(
+        memfs.read_buffer(mnode, data.len() - 4, 16),
+        Ok(data[data.len() - 4..].to_vec())
+    );
+    assert_eq!(memfs.read_buffer(mnode, data.len() + 1, 16), Ok(Vec::new()));
+    assert_eq!(memfs.read_buffer(42, 0, 16), Err(KError::InvalidFile));
+
+    let wonly = memfs.create("wonly", FileModes::S_IWUSR.into()).unwrap();
+    assert_eq!(
+        memfs.read_buffer(wonly, 0, 16),
+        Err(KError::PermissionError)
+    );
+}
+
+#[test]
+fn test_file_check_access() {
+    let memfs: MlnrFS = Default::default();
+    let ronly = memfs
+        .create("ronly.txt", FileModes::S_IRUSR.into())
+        .unwrap();
+    let wonly = memfs
+        .create("wonly.txt", FileModes::S_IWUSR.into())
+        .unwrap();
+
+    assert_eq!(memfs.check_access(ronly, FileFlags::O_RDONLY), Ok(()));
+    assert_eq!(
+        memfs.check_access(ronly, FileFlags::O_RDWR),
+        Err(KError::PermissionError)
+    );
+    assert_eq!(
+        memfs.check_access(ronly, FileFlags::O_RDONLY | FileFlags::O_TRUNC),
+        Err(KError::PermissionError)
+    );
+    assert_eq!(memfs.check_access(wonly, FileFlags::O_WRONLY), Ok(()));
+    assert_eq!(
+        memfs.check_access(wonly, FileFlags::O_RDONLY),
+        Err(KError::PermissionError)
+    );
+    // Directories can always be opened.
+    assert_eq!(memfs.check_access(1, FileFlags::O_RDWR), Ok(()));
+    assert_eq!(
+        memfs.check_access(42, FileFlags::O_RDONLY),
+        Err(KError::InvalidFile)
+    );
+}
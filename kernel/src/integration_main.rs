@@ -42,20 +42,13 @@ pub fn xmain() {
     target_arch = "x86_64"
 ))]
 pub fn xmain() {
-    use apic::ApicDriver;
     use core::hint::spin_loop;
     use core::time::Duration;
     use log::info;
 
     unsafe {
-        let tsc = x86::time::rdtsc();
-
-        {
-            let kcb = crate::kcb::get_kcb();
-            let mut apic = kcb.arch.apic();
-            apic.tsc_enable();
-            apic.tsc_set(tsc + 1_000_000_000);
-        }
+        // With the timer backend picked during boot
+        crate::arch::timer::set(1_000_000_000);
 
         // Don't change this line without changing
         // `s01_timer` in integration-tests.rs:
@@ -109,6 +102,21 @@ pub fn xmain() {
     debug::cause_gpfault();
 }
 
+/// Test that the kernel text is read-only once we're up (see `arch::wx`).
+#[cfg(all(
+    feature = "integration-test",
+    feature = "test-wx",
+    target_arch = "x86_64"
+))]
+pub fn xmain() {
+    use log::info;
+
+    let text = xmain as *const () as *mut u8;
+    info!("Writing to kernel text at {:p}", text);
+    unsafe { core::ptr::write_volatile(text, 0xcc) };
+    arch::debug::shutdown(ExitReason::Ok);
+}
+
 /// Test allocation and deallocation of objects of various sizes.
 #[cfg(all(feature = "integration-test", feature = "test-alloc"))]
 pub fn xmain() -> Result<(), crate::error::KError> {
@@ -144,6 +152,27 @@ pub fn xmain() -> Result<(), crate::error::KError> {
     arch::debug::shutdown(ExitReason::Ok);
 }
 
+/// Writes past the end of a heap allocation, the heap sanitizer should
+/// report it when the allocation is freed.
+#[cfg(all(feature = "integration-test", feature = "test-kasan"))]
+pub fn xmain() -> Result<(), crate::error::KError> {
+    use alloc::vec::Vec;
+    use fallible_collections::FallibleVecGlobal;
+    use log::info;
+
+    {
+        let mut buf: Vec<u8> = Vec::try_with_capacity(16)?;
+        buf.push(0xa);
+        // Free something to fill the quarantine a bit
+        drop(Vec::<u8>::try_with_capacity(16)?);
+        info!("overflowing heap object at {:p}", buf.as_ptr());
+        unsafe { core::ptr::write_volatile(buf.as_mut_ptr().add(20), 0xff) };
+    } // The sanitizer checks the red zone here.
+
+    info!("heap overflow was not detected");
+    arch::debug::shutdown(ExitReason::Ok);
+}
+
 /// Checks that we can initialize ACPI, query the ACPI tables,
 /// and parse the topology. The test ensures things work in case we
 /// have no numa nodes.
@@ -448,8 +477,8 @@ pub fn xmain() {
     any(feature = "test-userspace", feature = "test-userspace-smp")
 ))]
 pub fn xmain() {
-    let kcb = kcb::get_kcb();
-    assert!(crate::arch::process::spawn(kcb.cmdline.init_binary).is_ok());
+    let init = crate::arch::process::start_init();
+    assert!(init.is_ok());
     crate::scheduler::schedule()
 }
 
@@ -467,7 +496,7 @@ pub fn xmain() {
 pub fn xmain() {
     use graphviz::*;
 
-    let kcb = kcb::get_kcb();
+    let kcb = kcb::per_core();
     graphviz::render_opts(&*kcb.arch.init_vspace(), &[RenderOption::RankDirectionLR]);
 
     arch::debug::shutdown(ExitReason::Ok);
@@ -485,6 +514,139 @@ pub fn xmain() {
     arch::debug::shutdown(ExitReason::ReturnFromMain);
 }
 
+/// Test that a panic in the network stack disables it instead of halting the
+/// kernel (see `domain`).
+#[cfg(all(
+    feature = "integration-test",
+    feature = "test-panic-domain",
+    target_arch = "x86_64"
+))]
+fn xmain() {
+    use log::info;
+
+    use crate::arch::network::Network;
+    use crate::domain::NETWORK;
+    use crate::error::KError;
+
+    let mut network = Network::loopback();
+    network.poll().expect("Network stack failed");
+
+    let r: Result<(), KError> = NETWORK.enter(|| panic!("Test panic in the network stack"));
+    assert!(matches!(
+        r,
+        Err(KError::SubsystemFailed { name: "network" })
+    ));
+    assert!(matches!(
+        network.poll(),
+        Err(KError::SubsystemFailed { .. })
+    ));
+    info!("Kernel survived, network is disabled");
+
+    arch::debug::shutdown(ExitReason::Ok);
+}
+
+/// Test that the kernel can soft-reboot into (a new instance of) itself
+/// (see `kexec`).
+#[cfg(all(
+    feature = "integration-test",
+    feature = "test-kexec",
+    target_arch = "x86_64"
+))]
+fn xmain() {
+    use log::info;
+
+    let kcb = kcb::per_core();
+    let kernel_args = kcb.arch.kernel_args();
+    if kernel_args.module_source == arch::ModuleSource::Kexec {
+        info!("Soft-reboot done");
+        arch::debug::shutdown(ExitReason::Ok);
+    }
+
+    let r = arch::kexec::kexec(kcb.kernel_binary(), kernel_args.command_line);
+    panic!("Soft-reboot failed: {:?}", r);
+}
+
+/// Test TCP on the loopback interface of the network stack (needs no NIC).
+#[cfg(all(
+    feature = "integration-test",
+    feature = "test-net-loopback",
+    target_arch = "x86_64"
+))]
+fn xmain() {
+    use alloc::vec;
+    use core::time::Duration;
+
+    use log::info;
+
+    use smoltcp::socket::{TcpSocket, TcpSocketBuffer};
+    use smoltcp::wire::{IpAddress, IpEndpoint};
+
+    use crate::arch::network::{Network, LOOPBACK_ADDR};
+
+    const MESSAGE: &[u8] = b"hello loopback";
+
+    let mut network = Network::loopback();
+    let tcp_socket = || {
+        TcpSocket::new(
+            TcpSocketBuffer::new(vec![0; 64]),
+            TcpSocketBuffer::new(vec![0; 64]),
+        )
+    };
+    let server = network.sockets.add(tcp_socket());
+    let client = network.sockets.add(tcp_socket());
+
+    let [a, b, c, d] = LOOPBACK_ADDR;
+    network
+        .sockets
+        .get::<TcpSocket>(server)
+        .listen(6970)
+        .expect("Can listen");
+    network
+        .sockets
+        .get::<TcpSocket>(client)
+        .connect(IpEndpoint::new(IpAddress::v4(a, b, c, d), 6970), 49152)
+        .expect("Can connect");
+
+    let mut sent = false;
+    let mut echoed = false;
+    let start = rawtime::Instant::now();
+    while !echoed && start.elapsed() < Duration::from_secs(5) {
+        network.poll().expect("Network stack failed");
+
+        {
+            let mut socket = network.sockets.get::<TcpSocket>(client);
+            if socket.can_send() && !sent {
+                socket.send_slice(MESSAGE).expect("Has room");
+                sent = true;
+            }
+        }
+        {
+            let mut socket = network.sockets.get::<TcpSocket>(server);
+            if socket.can_recv() {
+                let mut buf = [0u8; 64];
+                let len = socket.recv_slice(&mut buf).expect("Can receive");
+                socket.send_slice(&buf[..len]).expect("Has room");
+            }
+        }
+        {
+            let mut socket = network.sockets.get::<TcpSocket>(client);
+            if socket.can_recv() {
+                let mut buf = [0u8; 64];
+                let len = socket.recv_slice(&mut buf).expect("Can receive");
+                assert_eq!(&buf[..len], MESSAGE);
+                echoed = true;
+            }
+        }
+
+        network.wait(Duration::from_millis(10));
+    }
+
+    assert!(echoed, "No echo on the loopback interface");
+    info!("Echo on 127.0.0.1 received");
+    network.netstat();
+    arch::debug::shutdown(ExitReason::Ok);
+}
+
 /// Test shootdown facilities in the kernel.
 #[cfg(all(
     feature = "integration-test",
@@ -526,7 +688,7 @@ pub fn xmain() {
     use crate::memory::vspace::MapAction;
     use crate::memory::PAddr;
 
-    let kcb = crate::kcb::get_kcb();
+    let kcb = crate::kcb::per_core();
     // TODO(hack): Map potential vmxnet3 bar addresses XD
     for &bar in &[
         0x81828000u64,
@@ -536,6 +698,7 @@ pub fn xmain() {
         0x81003000u64,
         0x81002000u64,
     ] {
+        assert!(crate::memory::reserved::check(PAddr::from(bar), 0x1000, None).is_ok());
         assert!(kcb
             .arch
             .init_vspace()
@@ -579,7 +742,7 @@ pub fn xmain() {
     arch::debug::shutdown(ExitReason::Ok);
 }
 
-/// Test vmxnet3 integrated with smoltcp.
+/// Test the NIC drivers (vmxnet3 or e1000) integrated with smoltcp.
 #[cfg(all(
     feature = "integration-test",
     feature = "test-vmxnet-smoltcp",
@@ -587,99 +750,34 @@ pub fn xmain() {
 ))]
 fn xmain() {
     use alloc::borrow::ToOwned;
-    use alloc::collections::BTreeMap;
     use alloc::vec;
-    use core::cell::Cell;
+    use core::time::Duration;
 
     use log::{debug, info};
 
-    use vmxnet3::smoltcp::DevQueuePhy;
-    use vmxnet3::vmx::VMXNet3;
-
-    use smoltcp::iface::{EthernetInterfaceBuilder, NeighborCache};
-    use smoltcp::socket::SocketSet;
     use smoltcp::socket::{TcpSocket, TcpSocketBuffer};
-    use smoltcp::time::Instant;
-    use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr};
 
-    use crate::memory::vspace::MapAction;
-    use crate::memory::PAddr;
-
-    let vmx = {
-        let kcb = crate::kcb::get_kcb();
-        // TODO(hack): Map potential vmxnet3 bar addresses XD
-        for &bar in &[
-            0x81828000u64,
-            0x81827000u64,
-            0x81005000u64,
-            0x81004000u64,
-            0x81003000u64,
-            0x81002000u64,
-        ] {
-            assert!(kcb
-                .arch
-                .init_vspace()
-                .map_identity(PAddr::from(bar), 0x1000, MapAction::ReadWriteKernel,)
-                .is_ok());
-        }
+    use crate::arch::network::Network;
 
-        let mut vmx = VMXNet3::new(2, 2).unwrap();
-        assert!(vmx.attach_pre().is_ok());
-        vmx.init();
-        vmx
-    };
-
-    #[derive(Debug)]
-    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-    pub struct Clock(Cell<Instant>);
-
-    impl Clock {
-        pub fn new() -> Clock {
-            Clock(Cell::new(Instant::from_millis(0)))
-        }
-
-        pub fn elapsed(&self) -> Instant {
-            self.0.get()
-        }
-    }
-
-    let device = DevQueuePhy::new(vmx).expect("Can't create PHY");
-
-    let neighbor_cache = NeighborCache::new(BTreeMap::new());
+    let mut network = Network::new().expect("Can't initialize network");
 
     let tcp_rx_buffer = TcpSocketBuffer::new(vec![0; 64]);
     let tcp_tx_buffer = TcpSocketBuffer::new(vec![0; 128]);
     let tcp_socket = TcpSocket::new(tcp_rx_buffer, tcp_tx_buffer);
-
-    let ethernet_addr = EthernetAddress([0x56, 0xb4, 0x44, 0xe9, 0x62, 0xdc]);
-    let ip_addrs = [IpCidr::new(IpAddress::v4(172, 31, 0, 10), 24)];
-
-    let builder = EthernetInterfaceBuilder::new(device)
-        .ip_addrs(ip_addrs)
-        .ethernet_addr(ethernet_addr)
-        .neighbor_cache(neighbor_cache);
-    let mut iface = builder.finalize();
-
-    let mut sockets = SocketSet::new(vec![]);
-    let tcp1_handle = sockets.add(tcp_socket);
+    let tcp1_handle = network.sockets.add(tcp_socket);
 
     let mut tcp_6970_active = false;
     let mut done = false;
-    let clock = Clock::new();
+    let start = rawtime::Instant::now();
     // Don't change the next line without changing `integration-test.rs`
     info!("About to serve sockets!");
 
-    while !done && clock.elapsed() < Instant::from_millis(10_000) {
-        match iface.poll(&mut sockets, clock.elapsed()) {
-            Ok(_) => {}
-            Err(e) => {
-                debug!("poll error: {}", e);
-            }
-        }
+    while !done && start.elapsed() < Duration::from_secs(20) {
+        network.poll().expect("Network stack failed");
 
         // tcp:6970: echo with reverse
         {
-            let mut socket = sockets.get::<TcpSocket>(tcp1_handle);
+            let mut socket = network.sockets.get::<TcpSocket>(tcp1_handle);
             if !socket.is_open() {
                 socket.listen(6970).unwrap()
             }
@@ -705,8 +803,11 @@ fn xmain() {
                 done = true;
             }
         }
+
+        network.wait(Duration::from_millis(100));
     }
 
+    network.netstat();
     arch::debug::shutdown(ExitReason::Ok);
 }
 
@@ -751,7 +852,7 @@ pub fn xmain() -> Result<(), crate::error::KError> {
         }
 
         {
-            let kcb = crate::kcb::get_kcb();
+            let kcb = crate::kcb::per_core();
             let mut apic = kcb.arch.apic();
 
             let vector = 251;
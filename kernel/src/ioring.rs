@@ -0,0 +1,84 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Asynchronous I/O through a ring shared with user-space.
+//!
+//! The ring lives in (validated) user memory, see `kpi::io::IoRing` for the
+//! layout. On `RingEnter` we drain the submission queue for as long as there
+//! is space in the completion queue. Requests are executed synchronously on
+//! the calling core.
+
+use core::mem::size_of;
+
+use kpi::io::{CompletionEntry, IoRing, RingHeader, RingOperation, SubmissionEntry, RING_ENTRIES};
+use kpi::{FileOperation, SystemCallError};
+
+use crate::arch::process::validate_user_range;
+use crate::arch::usercopy::{read_user, write_user};
+use crate::cnrfs;
+use crate::error::KError;
+use crate::process::Pid;
+
+/// Process the requests in the ring at user-space address `ring`.
+///
+/// Returns the number of requests that completed.
+pub fn enter(pid: Pid, ring: u64, len: u64) -> Result<(u64, u64), KError> {
+    if len as usize != size_of::<IoRing>() {
+        return Err(KError::InvalidLength);
+    }
+    validate_user_range(pid, ring, size_of::<IoRing>(), true)?;
+    let mut header: RingHeader = read_user(ring)?;
+
+    let mut completed = 0;
+    while header.sq_head != header.sq_tail
+        && header.cq_tail.wrapping_sub(header.cq_head) < RING_ENTRIES as u64
+    {
+        let sqe: SubmissionEntry = read_user(ring + IoRing::sq_offset(header.sq_head))?;
+        let cqe = match execute(pid, &sqe) {
+            Ok(len) => CompletionEntry {
+                user_data: sqe.user_data,
+                result: len,
+                error: 0,
+            },
+            Err(e) => CompletionEntry {
+                user_data: sqe.user_data,
+                result: 0,
+                error: SystemCallError::from(e) as u64,
+            },
+        };
+        write_user(ring + IoRing::cq_offset(header.cq_tail), &cqe)?;
+
+        header.sq_head = header.sq_head.wrapping_add(1);
+        header.cq_tail = header.cq_tail.wrapping_add(1);
+        completed += 1;
+    }
+
+    // Only publish the positions the kernel owns:
+    write_user(ring + IoRing::SQ_HEAD_OFFSET, &header.sq_head)?;
+    write_user(ring + IoRing::CQ_TAIL_OFFSET, &header.cq_tail)?;
+
+    Ok((completed, 0))
+}
+
+/// Execute a single request, returns the number of bytes transferred.
+fn execute(pid: Pid, sqe: &SubmissionEntry) -> Result<u64, KError> {
+    let (op, offset) = match RingOperation::from(sqe.op) {
+        RingOperation::Nop => return Ok(0),
+        RingOperation::Read => (FileOperation::Read, -1),
+        RingOperation::Write => (FileOperation::Write, -1),
+        RingOperation::ReadAt | RingOperation::WriteAt if sqe.offset < 0 => {
+            return Err(KError::InvalidOffset)
+        }
+        RingOperation::ReadAt => (FileOperation::ReadAt, sqe.offset),
+        RingOperation::WriteAt => (FileOperation::WriteAt, sqe.offset),
+        // There are no sockets in the kernel (yet).
+        RingOperation::Send | RingOperation::Recv => return Err(KError::NotSupported),
+        RingOperation::Unknown => return Err(KError::NotSupported),
+    };
+
+    let is_read = op == FileOperation::Read || op == FileOperation::ReadAt;
+    validate_user_range(pid, sqe.buf, sqe.len as usize, is_read)?;
+    let (len, _) = cnrfs::MlnrKernelNode::file_io(op, pid, sqe.fd, sqe.buf, sqe.len, offset)?;
+
+    Ok(len)
+}
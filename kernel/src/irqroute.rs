@@ -0,0 +1,362 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The interrupt routes of the machine: which global system interrupt (GSI)
+//! is delivered with which vector to which core, and which process asked for
+//! it.
+//!
+//! Processes ask for the interrupts of their devices with `Irq::irqalloc`,
+//! the architecture records the route here and programs the interrupt
+//! controller input of the GSI. Routes are removed (and the input masked)
+//! when their process exits, the route of a process that is gone without
+//! that is replaced by the next process asking for the GSI.
+
+// Only the x86-64 kernel routes interrupts
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+use fallible_collections::FallibleVec;
+
+use crate::error::KError;
+use crate::nr;
+use crate::process::Pid;
+use crate::sync::Mutex;
+
+/// A global system interrupt (the inputs of all interrupt controllers,
+/// numbered consecutively).
+pub type Gsi = u32;
+
+/// The vector of GSI 0, GSI `n` is delivered with vector
+/// `GSI_VECTOR_BASE + n`.
+pub const GSI_VECTOR_BASE: u8 = 32;
+
+/// The last vector that is delivered to processes (the IDT has a handler
+/// for every vector up to it).
+pub const MAX_VECTOR: u8 = 249;
+
+/// GSIs below this are the legacy ISA interrupts.
+const ISA_INTERRUPTS: Gsi = 16;
+
+/// Polarity of an interrupt line.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Trigger mode of an interrupt line.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+/// An interrupt source override of the ACPI tables (MADT): ISA interrupt
+/// `source` is connected to `gsi`, with the polarity and trigger mode of the
+/// line (`None` if it conforms to the ISA bus).
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct SourceOverride {
+    pub source: u8,
+    pub gsi: Gsi,
+    pub polarity: Option<Polarity>,
+    pub trigger: Option<Trigger>,
+}
+
+impl SourceOverride {
+    /// An override with the MPS INTI `flags` of the MADT entry.
+    pub fn new(source: u8, gsi: Gsi, flags: u16) -> SourceOverride {
+        SourceOverride {
+            source,
+            gsi,
+            polarity: match flags & 0b11 {
+                0b01 => Some(Polarity::ActiveHigh),
+                0b11 => Some(Polarity::ActiveLow),
+                _ => None,
+            },
+            trigger: match (flags >> 2) & 0b11 {
+                0b01 => Some(Trigger::Edge),
+                0b11 => Some(Trigger::Level),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Delivers `gsi` to `core`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Route {
+    pub gsi: Gsi,
+    pub vector: u8,
+    /// The core (global thread id) that gets the interrupt.
+    pub core: usize,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+    /// The process that asked for the route (and its generation).
+    owner: (Pid, u64),
+}
+
+impl Route {
+    /// A route for `gsi` to `core` for process `pid` (of `generation`).
+    ///
+    /// A GSI with an interrupt source override in `overrides` is the line of
+    /// an ISA interrupt and configured like the override says (like on the
+    /// ISA bus where it conforms: active high, edge triggered). Without one,
+    /// GSIs below 16 are identity mapped ISA interrupts, the other GSIs are
+    /// configured like PCI interrupts (active low, level triggered).
+    pub fn new(
+        gsi: Gsi,
+        core: usize,
+        pid: Pid,
+        generation: u64,
+        overrides: &[SourceOverride],
+    ) -> Result<Route, KError> {
+        let vector = u8::try_from(gsi)
+            .ok()
+            .and_then(|gsi| gsi.checked_add(GSI_VECTOR_BASE))
+            .filter(|vector| *vector <= MAX_VECTOR)
+            .ok_or(KError::NoSuchInterrupt { gsi: gsi as u64 })?;
+        let (polarity, trigger) = match overrides.iter().find(|o| o.gsi == gsi) {
+            Some(o) => (
+                o.polarity.unwrap_or(Polarity::ActiveHigh),
+                o.trigger.unwrap_or(Trigger::Edge),
+            ),
+            None if gsi < ISA_INTERRUPTS => (Polarity::ActiveHigh, Trigger::Edge),
+            None => (Polarity::ActiveLow, Trigger::Level),
+        };
+
+        Ok(Route {
+            gsi,
+            vector,
+            core,
+            polarity,
+            trigger,
+            owner: (pid, generation),
+        })
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.owner.0
+    }
+}
+
+/// The routes, at most one per GSI.
+struct Routes {
+    routes: Vec<Route>,
+}
+
+impl Routes {
+    const fn new() -> Routes {
+        Routes { routes: Vec::new() }
+    }
+
+    /// Records `route`, replaces the route of the same process (or of a
+    /// process that is gone) for the GSI and returns it.
+    fn add(
+        &mut self,
+        route: Route,
+        alive: impl Fn(Pid, u64) -> bool,
+    ) -> Result<Option<Route>, KError> {
+        match self.routes.iter_mut().find(|r| r.gsi == route.gsi) {
+            Some(existing) => {
+                let (pid, generation) = existing.owner;
+                if existing.owner != route.owner && alive(pid, generation) {
+                    return Err(KError::InterruptInUse {
+                        gsi: route.gsi as u64,
+                    });
+                }
+                Ok(Some(core::mem::replace(existing, route)))
+            }
+            None => {
+                self.routes.try_push(route)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Undoes `add` of a route for `gsi` that replaced `previous`.
+    fn revert(&mut self, gsi: Gsi, previous: Option<Route>) {
+        match (self.routes.iter_mut().find(|r| r.gsi == gsi), previous) {
+            (Some(route), Some(previous)) => *route = previous,
+            _ => self.routes.retain(|r| r.gsi != gsi),
+        }
+    }
+
+    /// The route of `gsi` if `pid` owns it.
+    fn get(&self, pid: Pid, gsi: Gsi) -> Result<&Route, KError> {
+        match self.routes.iter().find(|r| r.gsi == gsi) {
+            Some(route) if route.pid() == pid => Ok(route),
+            Some(_route) => Err(KError::InterruptInUse { gsi: gsi as u64 }),
+            None => Err(KError::NoSuchInterrupt { gsi: gsi as u64 }),
+        }
+    }
+
+    /// Removes the routes of `pid`, calls `f` for each of them.
+    fn release(&mut self, pid: Pid, mut f: impl FnMut(&Route)) {
+        self.routes.retain(|r| {
+            if r.pid() == pid {
+                f(r);
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+static ROUTES: Mutex<Routes> = Mutex::new(Routes::new());
+
+/// Records a route for `gsi` to `core` for `pid` (configured with the
+/// interrupt source `overrides` of the machine) and establishes it with
+/// `program` (e.g., writes the redirection entry of an I/O APIC).
+///
+/// `program` must not change anything if it fails, the previous route of
+/// the GSI stays in place then.
+pub fn add(
+    pid: Pid,
+    gsi: Gsi,
+    core: usize,
+    overrides: &[SourceOverride],
+    program: impl FnOnce(&Route) -> Result<(), KError>,
+) -> Result<Route, KError> {
+    let generation = nr::KernelNode::process_generation(pid)?;
+    let route = Route::new(gsi, core, pid, generation, overrides)?;
+
+    let mut routes = ROUTES.lock();
+    let previous = routes.add(route, nr::KernelNode::is_alive)?;
+    if let Err(e) = program(&route) {
+        routes.revert(gsi, previous);
+        return Err(e);
+    }
+    Ok(route)
+}
+
+/// Calls `f` with the route of `gsi` (e.g., to mask or unmask it), fails if
+/// `pid` didn't ask for it.
+///
+/// The routes stay locked while `f` runs, so the route can't be released
+/// or taken over in the meantime.
+pub fn with_route<R>(
+    pid: Pid,
+    gsi: Gsi,
+    f: impl FnOnce(&Route) -> Result<R, KError>,
+) -> Result<R, KError> {
+    let routes = ROUTES.lock();
+    f(routes.get(pid, gsi)?)
+}
+
+/// Removes the routes of `pid` (which exited), `disable` is called for each
+/// of them.
+pub fn release(pid: Pid, disable: impl FnMut(&Route)) {
+    ROUTES.lock().release(pid, disable);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_config() {
+        let com1 = Route::new(4, 0, 1, 0, &[]).expect("ISA interrupt");
+        assert_eq!(com1.vector, 36);
+        assert_eq!(com1.polarity, Polarity::ActiveHigh);
+        assert_eq!(com1.trigger, Trigger::Edge);
+
+        let pci = Route::new(19, 1, 1, 0, &[]).expect("PCI interrupt");
+        assert_eq!(pci.vector, 51);
+        assert_eq!(pci.polarity, Polarity::ActiveLow);
+        assert_eq!(pci.trigger, Trigger::Level);
+
+        assert!(Route::new(217, 0, 1, 0, &[]).is_ok());
+        assert_eq!(
+            Route::new(218, 0, 1, 0, &[]),
+            Err(KError::NoSuchInterrupt { gsi: 218 })
+        );
+        assert!(Route::new(1 << 20, 0, 1, 0, &[]).is_err());
+    }
+
+    #[test]
+    fn route_with_overrides() {
+        // QEMU: IRQ 0 is on GSI 2, the SCI (IRQ 9) is active high, level
+        // triggered
+        let overrides = [
+            SourceOverride::new(0, 2, 0b0000),
+            SourceOverride::new(9, 9, 0b1101),
+        ];
+        assert_eq!(overrides[0].polarity, None);
+        assert_eq!(overrides[0].trigger, None);
+        assert_eq!(overrides[1].polarity, Some(Polarity::ActiveHigh));
+        assert_eq!(overrides[1].trigger, Some(Trigger::Level));
+
+        let timer = Route::new(2, 0, 1, 0, &overrides).expect("ISA interrupt");
+        assert_eq!(timer.polarity, Polarity::ActiveHigh);
+        assert_eq!(timer.trigger, Trigger::Edge);
+
+        let sci = Route::new(9, 0, 1, 0, &overrides).expect("ISA interrupt");
+        assert_eq!(sci.polarity, Polarity::ActiveHigh);
+        assert_eq!(sci.trigger, Trigger::Level);
+
+        // An ISA interrupt on a PCI GSI
+        let moved = [SourceOverride::new(5, 20, 0b1111)];
+        let route = Route::new(20, 0, 1, 0, &moved).expect("Valid");
+        assert_eq!(route.polarity, Polarity::ActiveLow);
+        assert_eq!(route.trigger, Trigger::Level);
+        let route = Route::new(20, 0, 1, 0, &[SourceOverride::new(5, 20, 0)]).expect("Valid");
+        assert_eq!(route.polarity, Polarity::ActiveHigh);
+        assert_eq!(route.trigger, Trigger::Edge);
+    }
+
+    #[test]
+    fn add_and_release_routes() {
+        let mut routes = Routes::new();
+        let alive = |pid, _generation| pid != 3;
+
+        let nic = Route::new(11, 0, 1, 0, &[]).expect("Valid");
+        assert_eq!(routes.add(nic, alive), Ok(None));
+        // The same process can move its interrupt
+        let moved = Route::new(11, 2, 1, 0, &[]).expect("Valid");
+        assert_eq!(routes.add(moved, alive), Ok(Some(nic)));
+        assert_eq!(routes.routes.len(), 1);
+        assert_eq!(routes.routes[0].core, 2);
+        routes.revert(11, Some(nic));
+        assert_eq!(routes.routes[0].core, 0);
+
+        assert_eq!(
+            routes.add(Route::new(11, 0, 2, 0, &[]).expect("Valid"), alive),
+            Err(KError::InterruptInUse { gsi: 11 })
+        );
+        // Same pid, another process
+        assert!(routes
+            .add(Route::new(11, 0, 1, 1, &[]).expect("Valid"), alive)
+            .is_err());
+
+        // Routes of processes that are gone can be taken over
+        routes
+            .add(Route::new(4, 0, 3, 0, &[]).expect("Valid"), alive)
+            .expect("Free");
+        routes
+            .add(Route::new(4, 0, 2, 0, &[]).expect("Valid"), alive)
+            .expect("Owner is gone");
+        let timer = Route::new(0, 0, 2, 0, &[]).expect("Valid");
+        assert_eq!(routes.add(timer, alive), Ok(None));
+        routes.revert(0, None);
+
+        // Only the owner can mask its routes
+        assert_eq!(routes.get(1, 11).map(|r| r.core), Ok(0));
+        assert_eq!(
+            routes.get(2, 11).map(|r| r.core),
+            Err(KError::InterruptInUse { gsi: 11 })
+        );
+        assert_eq!(
+            routes.get(1, 12).map(|r| r.core),
+            Err(KError::NoSuchInterrupt { gsi: 12 })
+        );
+
+        let mut released = Vec::new();
+        routes.release(1, |r| released.push(r.gsi));
+        assert_eq!(released, [11]);
+        assert_eq!(routes.routes.len(), 1);
+        assert_eq!(routes.routes[0].pid(), 2);
+    }
+}
@@ -2,11 +2,17 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 //! KCB is the local kernel control that stores all core local state.
+//!
+//! Most code should only need shared access to the KCB of the current core
+//! (see [`per_core`]): state that changes after initialization lives in
+//! (per-subsystem) cells. Code that needs to mutate the KCB has to prove that
+//! it runs on the core owning the KCB by presenting a [`LocalCore`] token.
 
 use alloc::string::String;
 use alloc::sync::Arc;
-use core::cell::{RefCell, RefMut};
+use core::cell::{Cell, RefCell, RefMut};
 use core::fmt::Debug;
+use core::marker::PhantomData;
 use core::slice::from_raw_parts;
 
 use arrayvec::ArrayVec;
@@ -18,6 +24,7 @@ use slabmalloc::ZoneAllocator;
 use crate::arch::kcb::init_kcb;
 use crate::arch::memory::paddr_to_kernel_vaddr;
 use crate::arch::MAX_NUMA_NODES;
+use crate::domain::CurrentFrame;
 use crate::error::KError;
 
 use crate::arch::process::PROCESS_TABLE;
@@ -28,8 +35,33 @@ use crate::memory::{AllocatorStatistics, GlobalMemory, GrowBackend, PAddr, Physi
 use crate::nr::KernelNode;
 use crate::nrproc::NrProcess;
 use crate::process::{Pid, Process, MAX_PROCESSES};
+use crate::timer_wheel::TimerWheel;
 
-pub use crate::arch::kcb::{get_kcb, try_get_kcb};
+pub use crate::arch::kcb::{get_kcb, per_core, try_get_kcb};
+
+/// Proof that the holder runs on the core that owns the current KCB.
+///
+/// The token is neither `Send` nor `Sync` so it can't leave the core it was
+/// created on, and mutable access to the KCB through [`LocalCore::kcb_mut`]
+/// borrows the token, so there can only be one mutable reference per token.
+pub struct LocalCore {
+    _not_send: PhantomData<*mut ()>,
+}
+
+impl LocalCore {
+    /// Creates the token for the current core.
+    ///
+    /// # Safety
+    /// Should only be called on entry points into the kernel (core
+    /// initialization, system call and interrupt handlers) and the caller
+    /// must ensure there is no other live token (or mutable KCB reference)
+    /// on the current core.
+    pub unsafe fn new() -> LocalCore {
+        LocalCore {
+            _not_send: PhantomData,
+        }
+    }
+}
 
 pub trait MemManager: PhysicalPageProvider + AllocatorStatistics + GrowBackend {}
 
@@ -56,7 +88,79 @@ enum CmdToken {
     #[token("appcmd")]
     AppArgs,
 
-    #[regex("[a-zA-Z0-9\\._-]*")]
+    /// File-system root of the init process.
+    #[token("initroot")]
+    InitRoot,
+
+    /// Load-balancing policy for cores that run processes.
+    #[token("balance")]
+    Balance,
+
+    /// Scheduling policy for cores that run processes.
+    #[token("sched")]
+    Sched,
+
+    /// Transparent huge-page promotion of user memory.
+    #[token("thp")]
+    Thp,
+
+    /// Number of kernel replicas.
+    #[token("replicas")]
+    Replicas,
+
+    /// Capabilities of the init process.
+    #[token("initcaps")]
+    InitCaps,
+
+    /// Seed for deterministic random numbers.
+    #[token("seed")]
+    Seed,
+
+    /// Log level of user-space processes.
+    #[token("ulog")]
+    UserLog,
+
+    /// UDP endpoint that receives a copy of the console output.
+    #[token("netlog")]
+    NetLog,
+
+    /// SNTP server the wall clock is synchronized with.
+    #[token("ntp")]
+    Ntp,
+
+    /// Pre-shared key of the management console.
+    #[token("mgmtkey")]
+    MgmtKey,
+
+    /// Sectors of a disk that keep the kernel log.
+    #[token("logdisk")]
+    LogDisk,
+
+    /// Policy of the OOM killer.
+    #[token("oom")]
+    Oom,
+
+    /// Time a system call may take (with the `syscall-budget` feature).
+    #[token("sysbudget")]
+    SyscallBudget,
+
+    /// Randomize where the bootloader loads the kernel.
+    #[token("kaslr")]
+    Kaslr,
+
+    /// Speculative execution mitigations.
+    #[token("mitigations")]
+    Mitigations,
+
+    /// Operations the NR combiner waits for before it applies a batch.
+    #[token("nrbatch")]
+    NrBatch,
+
+    /// Kernel network interface.
+    #[token("net")]
+    Net,
+
+    #[regex("[a-zA-Z0-9\\._:-]*")]
     Ident,
 
     /// Kernel log level
@@ -80,33 +184,84 @@ pub struct BootloaderArguments {
     pub init_binary: &'static str,
     pub init_args: &'static str,
     pub app_args: &'static str,
+    pub init_root: &'static str,
+    pub balance: &'static str,
+    /// Name of the scheduling policy (see `scheduler::policy`).
+    pub sched: &'static str,
+    /// Promote user memory to large pages (`on` or `off`, see `arch::thp`).
+    pub thp: &'static str,
+    /// How many kernel replicas to create (`node`, `one` or a number, see
+    /// `placement`).
+    pub replicas: &'static str,
+    pub init_caps: &'static str,
+    /// Seed of the random number generator (empty to seed from hardware).
+    pub seed: &'static str,
+    /// Log level new processes start with.
+    pub user_log: &'static str,
+    /// Where to send the console output (`<ip>:<port>`, empty for nowhere).
+    pub net_log: &'static str,
+    /// SNTP server to synchronize the wall clock with (`<ip>[:<port>]`, empty
+    /// for none, see `arch::timesync`).
+    pub ntp: &'static str,
+    /// Key of the management console (empty for no console, see
+    /// `arch::remote`).
+    pub mgmt_key: &'static str,
+    /// Where to keep the kernel log on a disk (`<disk>:<first sector>:<sectors>`,
+    /// empty for nowhere, see `disklog`).
+    pub log_disk: &'static str,
+    /// How the OOM killer picks its victim (see `oom`).
+    pub oom: &'static str,
+    /// Milliseconds a system call may spend in the kernel (see
+    /// `arch::budget`).
+    pub syscall_budget: &'static str,
+    /// Whether the bootloader randomized where it loaded the kernel (`on` or
+    /// `off`, the bootloader reads it, see `KernelArgs::kaslr`). `kexec`
+    /// picks the place of the next kernel the same way.
+    pub kaslr: &'static str,
+    /// Speculative execution mitigations (`auto`, `off` or a list like
+    /// `'ibrs,ibpb,mds'`, see `arch::mitigations`).
+    pub mitigations: &'static str,
+    /// Batch size of the node-replication combiner (see `nrstats`).
+    pub nr_batch: &'static str,
+    /// Bring up the kernel's network interface at boot (`on`, `off` or `auto`
+    /// if something on the command line needs it, see
+    /// `arch::network::start`).
+    pub net: &'static str,
 }
 
 impl Default for BootloaderArguments {
     fn default() -> BootloaderArguments {
-        BootloaderArguments {
-            log_filter: "info",
-            init_binary: "init",
-            init_args: "",
-            app_args: "",
-        }
+        BootloaderArguments::DEFAULT
     }
 }
 
 impl BootloaderArguments {
-    pub const fn new(
-        log_filter: &'static str,
-        init_binary: &'static str,
-        init_args: &'static str,
-        app_args: &'static str,
-    ) -> Self {
-        BootloaderArguments {
-            log_filter,
-            init_binary,
-            init_args,
-            app_args,
-        }
-    }
+    /// The arguments if the command line doesn't set anything, usable in
+    /// `const` contexts with `BootloaderArguments { .., ..DEFAULT }`.
+    pub const DEFAULT: BootloaderArguments = BootloaderArguments {
+        log_filter: "info",
+        init_binary: "init",
+        init_args: "",
+        app_args: "",
+        init_root: "/",
+        balance: "off",
+        sched: "rr",
+        thp: "off",
+        replicas: "node",
+        init_caps: "",
+        seed: "",
+        user_log: "trace",
+        net_log: "",
+        ntp: "",
+        mgmt_key: "",
+        log_disk: "",
+        oom: "rss",
+        syscall_budget: "10",
+        kaslr: "on",
+        mitigations: "auto",
+        nr_batch: "1",
+        net: "auto",
+    };
 
     /// Parse command line argument and initialize the logging infrastructure.
     ///
@@ -131,7 +286,28 @@ impl BootloaderArguments {
                 CmdToken::KernelBinary => {
                     //assert_eq!(slice, "./kernel");
                 }
-                CmdToken::Log | CmdToken::InitBinary | CmdToken::InitArgs | CmdToken::AppArgs => {
+                CmdToken::Log
+                | CmdToken::InitBinary
+                | CmdToken::InitArgs
+                | CmdToken::AppArgs
+                | CmdToken::InitRoot
+                | CmdToken::Balance
+                | CmdToken::Sched
+                | CmdToken::Thp
+                | CmdToken::Replicas
+                | CmdToken::InitCaps
+                | CmdToken::Seed
+                | CmdToken::UserLog
+                | CmdToken::NetLog
+                | CmdToken::Ntp
+                | CmdToken::MgmtKey
+                | CmdToken::LogDisk
+                | CmdToken::Oom
+                | CmdToken::SyscallBudget
+                | CmdToken::Kaslr
+                | CmdToken::Mitigations
+                | CmdToken::NrBatch
+                | CmdToken::Net => {
                     prev = token;
                 }
                 CmdToken::Ident => match prev {
@@ -151,6 +327,78 @@ impl BootloaderArguments {
                         parsed_args.app_args = slice;
                         prev = CmdToken::Error;
                     }
+                    CmdToken::InitRoot => {
+                        parsed_args.init_root = slice;
+                        prev = CmdToken::Error;
+                    }
+                    CmdToken::Balance => {
+                        parsed_args.balance = slice;
+                        prev = CmdToken::Error;
+                    }
+                    CmdToken::Sched => {
+                        parsed_args.sched = slice;
+                        prev = CmdToken::Error;
+                    }
+                    CmdToken::Thp => {
+                        parsed_args.thp = slice;
+                        prev = CmdToken::Error;
+                    }
+                    CmdToken::Replicas => {
+                        parsed_args.replicas = slice;
+                        prev = CmdToken::Error;
+                    }
+                    CmdToken::InitCaps => {
+                        parsed_args.init_caps = slice;
+                        prev = CmdToken::Error;
+                    }
+                    CmdToken::Seed => {
+                        parsed_args.seed = slice;
+                        prev = CmdToken::Error;
+                    }
+                    CmdToken::UserLog => {
+                        parsed_args.user_log = slice;
+                        prev = CmdToken::Error;
+                    }
+                    CmdToken::NetLog => {
+                        parsed_args.net_log = slice;
+                        prev = CmdToken::Error;
+                    }
+                    CmdToken::Ntp => {
+                        parsed_args.ntp = slice;
+                        prev = CmdToken::Error;
+                    }
+                    CmdToken::MgmtKey => {
+                        parsed_args.mgmt_key = slice;
+                        prev = CmdToken::Error;
+                    }
+                    CmdToken::LogDisk => {
+                        parsed_args.log_disk = slice;
+                        prev = CmdToken::Error;
+                    }
+                    CmdToken::Oom => {
+                        parsed_args.oom = slice;
+                        prev = CmdToken::Error;
+                    }
+                    CmdToken::SyscallBudget => {
+                        parsed_args.syscall_budget = slice;
+                        prev = CmdToken::Error;
+                    }
+                    CmdToken::Kaslr => {
+                        parsed_args.kaslr = slice;
+                        prev = CmdToken::Error;
+                    }
+                    CmdToken::Mitigations => {
+                        parsed_args.mitigations = slice;
+                        prev = CmdToken::Error;
+                    }
+                    CmdToken::NrBatch => {
+                        parsed_args.nr_batch = slice;
+                        prev = CmdToken::Error;
+                    }
+                    CmdToken::Net => {
+                        parsed_args.net = slice;
+                        prev = CmdToken::Error;
+                    }
                     _ => {
                         error!("Invalid cmd arguments: {} (skipped {})", args, slice);
                         continue;
@@ -161,6 +409,24 @@ impl BootloaderArguments {
                         && prev != CmdToken::InitBinary
                         && prev != CmdToken::InitArgs
                         && prev != CmdToken::AppArgs
+                        && prev != CmdToken::InitRoot
+                        && prev != CmdToken::Balance
+                        && prev != CmdToken::Sched
+                        && prev != CmdToken::Thp
+                        && prev != CmdToken::Replicas
+                        && prev != CmdToken::InitCaps
+                        && prev != CmdToken::Seed
+                        && prev != CmdToken::UserLog
+                        && prev != CmdToken::NetLog
+                        && prev != CmdToken::Ntp
+                        && prev != CmdToken::MgmtKey
+                        && prev != CmdToken::LogDisk
+                        && prev != CmdToken::Oom
+                        && prev != CmdToken::SyscallBudget
+                        && prev != CmdToken::Kaslr
+                        && prev != CmdToken::Mitigations
+                        && prev != CmdToken::NrBatch
+                        && prev != CmdToken::Net
                     {
                         error!("Malformed args (unexpected equal sign) in {}", args);
                         continue;
@@ -185,6 +451,78 @@ impl BootloaderArguments {
                             parsed_args.app_args = &slice[1..slice.len() - 1];
                             prev = CmdToken::Error;
                         }
+                        CmdToken::InitRoot => {
+                            parsed_args.init_root = &slice[1..slice.len() - 1];
+                            prev = CmdToken::Error;
+                        }
+                        CmdToken::Balance => {
+                            parsed_args.balance = &slice[1..slice.len() - 1];
+                            prev = CmdToken::Error;
+                        }
+                        CmdToken::Sched => {
+                            parsed_args.sched = &slice[1..slice.len() - 1];
+                            prev = CmdToken::Error;
+                        }
+                        CmdToken::Thp => {
+                            parsed_args.thp = &slice[1..slice.len() - 1];
+                            prev = CmdToken::Error;
+                        }
+                        CmdToken::Replicas => {
+                            parsed_args.replicas = &slice[1..slice.len() - 1];
+                            prev = CmdToken::Error;
+                        }
+                        CmdToken::InitCaps => {
+                            parsed_args.init_caps = &slice[1..slice.len() - 1];
+                            prev = CmdToken::Error;
+                        }
+                        CmdToken::Seed => {
+                            parsed_args.seed = &slice[1..slice.len() - 1];
+                            prev = CmdToken::Error;
+                        }
+                        CmdToken::UserLog => {
+                            parsed_args.user_log = &slice[1..slice.len() - 1];
+                            prev = CmdToken::Error;
+                        }
+                        CmdToken::NetLog => {
+                            parsed_args.net_log = &slice[1..slice.len() - 1];
+                            prev = CmdToken::Error;
+                        }
+                        CmdToken::Ntp => {
+                            parsed_args.ntp = &slice[1..slice.len() - 1];
+                            prev = CmdToken::Error;
+                        }
+                        CmdToken::MgmtKey => {
+                            parsed_args.mgmt_key = &slice[1..slice.len() - 1];
+                            prev = CmdToken::Error;
+                        }
+                        CmdToken::LogDisk => {
+                            parsed_args.log_disk = &slice[1..slice.len() - 1];
+                            prev = CmdToken::Error;
+                        }
+                        CmdToken::Oom => {
+                            parsed_args.oom = &slice[1..slice.len() - 1];
+                            prev = CmdToken::Error;
+                        }
+                        CmdToken::SyscallBudget => {
+                            parsed_args.syscall_budget = &slice[1..slice.len() - 1];
+                            prev = CmdToken::Error;
+                        }
+                        CmdToken::Kaslr => {
+                            parsed_args.kaslr = &slice[1..slice.len() - 1];
+                            prev = CmdToken::Error;
+                        }
+                        CmdToken::Mitigations => {
+                            parsed_args.mitigations = &slice[1..slice.len() - 1];
+                            prev = CmdToken::Error;
+                        }
+                        CmdToken::NrBatch => {
+                            parsed_args.nr_batch = &slice[1..slice.len() - 1];
+                            prev = CmdToken::Error;
+                        }
+                        CmdToken::Net => {
+                            parsed_args.net = &slice[1..slice.len() - 1];
+                            prev = CmdToken::Error;
+                        }
                         _ => {
                             error!("Invalid cmd arguments: {} (skipped {})", args, slice);
                             continue;
@@ -251,7 +589,23 @@ where
     ///
     /// # See also
     /// - `panic.rs`
-    pub in_panic_mode: bool,
+    in_panic_mode: Cell<bool>,
+
+    /// The innermost panic domain this core is in.
+    ///
+    /// # See also
+    /// - `domain.rs`
+    pub domain: CurrentFrame,
+
+    /// How many interrupts and exceptions this core is handling (nested in
+    /// each other), 0 in system calls.
+    ///
+    /// # See also
+    /// - `domain.rs`
+    irq_depth: Cell<usize>,
+
+    /// How many [`crate::domain::Pin`]s this core holds.
+    pins: Cell<usize>,
 
     pub cmdline: BootloaderArguments,
 
@@ -272,20 +626,28 @@ where
     /// TODO(redundant): use kcb.arch.node_id
     pub node: atopology::NodeId,
 
-    pub print_buffer: Option<String>,
+    /// Line buffer for user-space prints (see `process_print`).
+    print_buffer: RefCell<Option<String>>,
 
     /// Contains a bunch of memory arenas, can be one for every NUMA node
     /// but we intialize it lazily upon calling `set_allocation_affinity`.
     pub memory_arenas: [Option<PhysicalMemoryArena>; crate::arch::MAX_NUMA_NODES],
 
     /// A handle to the node-local kernel replica.
-    pub replica: Option<(Arc<Replica<'static, KernelNode>>, ReplicaToken)>,
+    replica: Option<(Arc<Replica<'static, KernelNode>>, ReplicaToken)>,
 
     /// Measures cycles spent in TLB shootdown handler for responder.
-    pub tlb_time: u64,
+    tlb_time: Cell<u64>,
+
+    /// Number of system calls and cycles spent handling them (only measured
+    /// with the `syscall-timing` feature).
+    syscall_time: Cell<(u64, u64)>,
 
     /// Tokens to access process replicas
-    pub process_token: ArrayVec<ReplicaToken, { MAX_PROCESSES }>,
+    process_token: ArrayVec<ReplicaToken, { MAX_PROCESSES }>,
+
+    /// Kernel-internal timeouts on this core.
+    pub timers: RefCell<TimerWheel>,
 }
 
 impl<A: ArchSpecificKcb> Kcb<A> {
@@ -301,7 +663,10 @@ impl<A: ArchSpecificKcb> Kcb<A> {
         Kcb {
             arch,
             cmdline,
-            in_panic_mode: false,
+            in_panic_mode: Cell::new(false),
+            domain: Cell::new(None),
+            irq_depth: Cell::new(0),
+            pins: Cell::new(0),
             kernel_binary,
             emanager: RefCell::new(emanager),
             ezone_allocator: RefCell::new(EmergencyAllocator::empty()),
@@ -310,10 +675,12 @@ impl<A: ArchSpecificKcb> Kcb<A> {
             // Can't initialize these yet, we need basic Kcb first for
             // memory allocations (emanager):
             physical_memory: PhysicalMemoryArena::uninit_with_node(node),
-            print_buffer: None,
+            print_buffer: RefCell::new(None),
             replica: None,
-            tlb_time: 0,
+            tlb_time: Cell::new(0),
+            syscall_time: Cell::new((0, 0)),
             process_token: ArrayVec::new_const(),
+            timers: RefCell::new(TimerWheel::new()),
         }
     }
 
@@ -338,8 +705,80 @@ impl<A: ArchSpecificKcb> Kcb<A> {
         }
     }
 
-    pub fn set_panic_mode(&mut self) {
-        self.in_panic_mode = true;
+    /// Returns the node-local kernel replica and our token for it.
+    pub fn replica(&self) -> Result<(&Arc<Replica<'static, KernelNode>>, ReplicaToken), KError> {
+        self.replica
+            .as_ref()
+            .map(|(replica, token)| (replica, *token))
+            .ok_or(KError::ReplicaNotSet)
+    }
+
+    /// Returns the token to access the local replica of process `pid`.
+    pub fn process_token(&self, pid: Pid) -> ReplicaToken {
+        self.process_token[pid]
+    }
+
+    pub fn in_panic_mode(&self) -> bool {
+        self.in_panic_mode.get()
+    }
+
+    pub fn set_panic_mode(&self) {
+        self.in_panic_mode.set(true);
+    }
+
+    /// How many interrupts and exceptions the core is handling.
+    pub fn irq_depth(&self) -> usize {
+        self.irq_depth.get()
+    }
+
+    /// The core started handling an interrupt or exception, `from_user` if
+    /// it interrupted user-space (the kernel doesn't return to handlers it
+    /// left for user-space).
+    pub fn enter_irq(&self, from_user: bool) {
+        let depth = if from_user { 0 } else { self.irq_depth.get() };
+        self.irq_depth.set(depth + 1);
+    }
+
+    /// The core entered the kernel with a system call.
+    pub fn enter_syscall(&self) {
+        self.irq_depth.set(0);
+    }
+
+    /// How many [`crate::domain::Pin`]s the core holds.
+    pub fn pins(&self) -> usize {
+        self.pins.get()
+    }
+
+    pub fn pin(&self) {
+        self.pins.set(self.pins.get() + 1);
+    }
+
+    pub fn unpin(&self) {
+        self.pins.set(self.pins.get() - 1);
+    }
+
+    /// Cycles spent in the TLB shootdown handler (as a responder).
+    pub fn tlb_time(&self) -> u64 {
+        self.tlb_time.get()
+    }
+
+    pub fn add_tlb_time(&self, cycles: u64) {
+        self.tlb_time.set(self.tlb_time.get() + cycles);
+    }
+
+    /// Number of system calls handled and cycles spent in them.
+    pub fn syscall_time(&self) -> (u64, u64) {
+        self.syscall_time.get()
+    }
+
+    pub fn add_syscall_time(&self, cycles: u64) {
+        let (count, time) = self.syscall_time.get();
+        self.syscall_time.set((count + 1, time + cycles));
+    }
+
+    /// Get a reference to the print buffer (if print buffering is enabled).
+    pub fn print_buffer(&self) -> RefMut<Option<String>> {
+        self.print_buffer.borrow_mut()
     }
 
     /// Ties this KCB to the local CPU by setting the KCB's GDT and IDT.
@@ -388,7 +827,7 @@ impl<A: ArchSpecificKcb> Kcb<A> {
     }
 
     pub fn enable_print_buffering(&mut self, buffer: String) {
-        self.print_buffer = Some(buffer);
+        self.print_buffer.replace(Some(buffer));
     }
 
     /// Get a reference to the early memory manager.
@@ -418,7 +857,7 @@ impl<A: ArchSpecificKcb> Kcb<A> {
     /// Returns a reference to the core-local physical memory manager if set,
     /// otherwise returns the early physical memory manager.
     pub fn mem_manager(&self) -> RefMut<dyn MemManager> {
-        if core::intrinsics::unlikely(self.in_panic_mode) {
+        if core::intrinsics::unlikely(self.in_panic_mode()) {
             return self.emanager();
         }
 
@@ -429,7 +868,7 @@ impl<A: ArchSpecificKcb> Kcb<A> {
     }
 
     pub fn try_mem_manager(&self) -> Result<RefMut<dyn MemManager>, core::cell::BorrowMutError> {
-        if core::intrinsics::unlikely(self.in_panic_mode) {
+        if core::intrinsics::unlikely(self.in_panic_mode()) {
             return Ok(self.emanager());
         }
 
@@ -520,6 +959,138 @@ mod test {
         assert_eq!(ba.init_args, "0");
     }
 
+    #[test]
+    fn parse_args_initroot() {
+        let ba = BootloaderArguments::from_str("./kernel initroot='/scratch/init' initargs=0");
+        assert_eq!(ba.init_root, "/scratch/init");
+        assert_eq!(ba.init_args, "0");
+
+        let ba = BootloaderArguments::from_str("./kernel");
+        assert_eq!(ba.init_root, "/");
+    }
+
+    #[test]
+    fn parse_args_balance() {
+        let ba = BootloaderArguments::from_str("./kernel balance=spread log=debug");
+        assert_eq!(ba.balance, "spread");
+        assert_eq!(ba.log_filter, "debug");
+
+        let ba = BootloaderArguments::from_str("./kernel");
+        assert_eq!(ba.balance, "off");
+    }
+
+    #[test]
+    fn parse_args_sched() {
+        let ba = BootloaderArguments::from_str("./kernel sched=rtc balance=spread");
+        assert_eq!(ba.sched, "rtc");
+        assert_eq!(ba.balance, "spread");
+
+        let ba = BootloaderArguments::from_str("./kernel");
+        assert_eq!(ba.sched, "rr");
+        assert_eq!(ba.thp, "off");
+
+        let ba = BootloaderArguments::from_str("./kernel thp=on sched=rr");
+        assert_eq!(ba.thp, "on");
+    }
+
+    #[test]
+    fn parse_args_oom() {
+        let ba = BootloaderArguments::from_str("./kernel oom=prio sched=rtc");
+        assert_eq!(ba.oom, "prio");
+        assert_eq!(ba.sched, "rtc");
+
+        let ba = BootloaderArguments::from_str("./kernel");
+        assert_eq!(ba.oom, "rss");
+        assert_eq!(ba.syscall_budget, "10");
+
+        let ba = BootloaderArguments::from_str("./kernel sysbudget=50");
+        assert_eq!(ba.syscall_budget, "50");
+    }
+
+    #[test]
+    fn parse_args_replicas() {
+        let ba = BootloaderArguments::from_str("./kernel replicas=2 log=info");
+        assert_eq!(ba.replicas, "2");
+        assert_eq!(ba.log_filter, "info");
+
+        let ba = BootloaderArguments::from_str("./kernel");
+        assert_eq!(ba.replicas, "node");
+    }
+
+    #[test]
+    fn parse_args_initcaps() {
+        let ba = BootloaderArguments::from_str("./kernel initcaps=measure initargs=1");
+        assert_eq!(ba.init_caps, "measure");
+        assert_eq!(ba.init_args, "1");
+
+        let ba = BootloaderArguments::from_str("./kernel");
+        assert_eq!(ba.init_caps, "");
+    }
+
+    #[test]
+    fn parse_args_netlog() {
+        let ba = BootloaderArguments::from_str("./kernel netlog=172.31.0.20:6666 log=info");
+        assert_eq!(ba.net_log, "172.31.0.20:6666");
+        assert_eq!(ba.log_filter, "info");
+
+        let ba = BootloaderArguments::from_str("./kernel");
+        assert_eq!(ba.net_log, "");
+    }
+
+    #[test]
+    fn parse_args_ntp() {
+        let ba =
+            BootloaderArguments::from_str("./kernel ntp=172.31.0.20:6123 netlog=172.31.0.20:6666");
+        assert_eq!(ba.ntp, "172.31.0.20:6123");
+        assert_eq!(ba.net_log, "172.31.0.20:6666");
+
+        let ba = BootloaderArguments::from_str("./kernel");
+        assert_eq!(ba.ntp, "");
+    }
+
+    #[test]
+    fn parse_args_mgmtkey() {
+        let ba = BootloaderArguments::from_str("./kernel mgmtkey='s3cret key' log=info");
+        assert_eq!(ba.mgmt_key, "s3cret key");
+        assert_eq!(ba.log_filter, "info");
+
+        let ba = BootloaderArguments::from_str("./kernel");
+        assert_eq!(ba.mgmt_key, "");
+    }
+
+    #[test]
+    fn parse_args_logdisk() {
+        let ba = BootloaderArguments::from_str("./kernel logdisk=0:2048:1024 log=info");
+        assert_eq!(ba.log_disk, "0:2048:1024");
+        assert_eq!(ba.log_filter, "info");
+
+        let ba = BootloaderArguments::from_str("./kernel");
+        assert_eq!(ba.log_disk, "");
+    }
+
+    #[test]
+    fn parse_args_kaslr() {
+        let ba = BootloaderArguments::from_str("./kernel kaslr=off log=info");
+        assert_eq!(ba.kaslr, "off");
+        assert_eq!(ba.log_filter, "info");
+
+        let ba = BootloaderArguments::from_str("./kernel");
+        assert_eq!(ba.kaslr, "on");
+    }
+
+    #[test]
+    fn parse_args_mitigations() {
+        let ba = BootloaderArguments::from_str("./kernel mitigations='ibrs,mds' log=info");
+        assert_eq!(ba.mitigations, "ibrs,mds");
+        assert_eq!(ba.log_filter, "info");
+
+        let ba = BootloaderArguments::from_str("./kernel mitigations=off");
+        assert_eq!(ba.mitigations, "off");
+
+        let ba = BootloaderArguments::from_str("./kernel");
+        assert_eq!(ba.mitigations, "auto");
+    }
+
     #[test]
     fn parse_args_leveldb() {
         let args = "./kernel log=warn init=dbbench.bin initargs=3 appcmd='--threads=1 --benchmarks=fillseq,readrandom --reads=100000 --num=50000 --value_size=65535'";
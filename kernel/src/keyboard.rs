@@ -0,0 +1,270 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Keyboard input for processes.
+//!
+//! Keyboard drivers (the PS/2 controller, see `arch::ps2`) pass every byte
+//! they read to [`scancode`]. We decode scancode set 1 (US layout) into
+//! [`KeyEvent`]s and queue them for the process with the *focus*: the first
+//! process that waits for them with `Process::wait_event(Event::Keyboard)`
+//! gets the focus and keeps it until it exits. Keys typed while nobody has
+//! the focus are dropped. Polling the keyboard (`PollKind::Keyboard`) takes
+//! the focus too.
+//!
+//! There is no USB HID driver, USB keyboards work if the firmware emulates
+//! a PS/2 keyboard for them (legacy USB support).
+
+// Only the x86-64 kernel has keyboard drivers
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use arrayvec::ArrayVec;
+use kpi::io::PollEvents;
+use kpi::process::{KeyEvent, Modifiers};
+use log::{debug, info};
+
+use crate::error::KError;
+use crate::nr;
+use crate::process::Pid;
+use crate::sync::Mutex;
+
+/// How many keys we keep for the focus.
+const MAX_EVENTS: usize = 64;
+
+/// Characters of the keys of scancode set 1 (0 if there is none).
+const NORMAL: &[u8; 58] =
+    b"\0\x1b1234567890-=\x08\tqwertyuiop[]\n\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+
+/// Characters of the keys with Shift held down.
+const SHIFTED: &[u8; 58] =
+    b"\0\x1b!@#$%^&*()_+\x08\tQWERTYUIOP{}\n\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+/// Turns scancodes into key events.
+struct Decoder {
+    /// The previous byte was the `0xe0` prefix.
+    extended: bool,
+    /// Bytes left of a sequence we ignore (Pause).
+    skip: usize,
+    modifiers: Modifiers,
+}
+
+impl Decoder {
+    const fn new() -> Decoder {
+        Decoder {
+            extended: false,
+            skip: 0,
+            modifiers: Modifiers::empty(),
+        }
+    }
+
+    /// Takes the next byte from the keyboard, returns an event once it has
+    /// all bytes of a key.
+    fn feed(&mut self, byte: u8) -> Option<KeyEvent> {
+        if self.skip > 0 {
+            self.skip -= 1;
+            return None;
+        }
+        match byte {
+            0xe0 => {
+                self.extended = true;
+                return None;
+            }
+            // Pause sends 0xe1 and five more bytes
+            0xe1 => {
+                self.skip = 5;
+                return None;
+            }
+            // Errors and acknowledgements of the keyboard
+            0x00 | 0xfa | 0xfe | 0xff => return None,
+            _ => {}
+        }
+
+        let extended = core::mem::replace(&mut self.extended, false);
+        let code = byte & 0x7f;
+        let pressed = byte & 0x80 == 0;
+        match (extended, code) {
+            // Fake Shift that comes along with some extended keys
+            (true, 0x2a) | (true, 0x36) => return None,
+            (false, 0x2a) | (false, 0x36) => self.modifiers.set(Modifiers::SHIFT, pressed),
+            (_, 0x1d) => self.modifiers.set(Modifiers::CTRL, pressed),
+            (_, 0x38) => self.modifiers.set(Modifiers::ALT, pressed),
+            (false, 0x3a) if pressed => self.modifiers.toggle(Modifiers::CAPS_LOCK),
+            _ => {}
+        }
+
+        Some(KeyEvent {
+            scancode: if extended {
+                0xe000 | code as u16
+            } else {
+                code as u16
+            },
+            pressed,
+            modifiers: self.modifiers,
+            character: if pressed {
+                self.character(extended, code)
+            } else {
+                None
+            },
+        })
+    }
+
+    /// The character the key `code` produces with the current modifiers.
+    fn character(&self, extended: bool, code: u8) -> Option<char> {
+        let c = match (extended, code) {
+            (false, _) => *NORMAL.get(code as usize)?,
+            // Keypad Enter and /
+            (true, 0x1c) => b'\n',
+            (true, 0x35) => b'/',
+            _ => return None,
+        };
+
+        let mut shift = self.modifiers.contains(Modifiers::SHIFT);
+        if c.is_ascii_lowercase() && self.modifiers.contains(Modifiers::CAPS_LOCK) {
+            shift = !shift;
+        }
+        let c = if shift && !extended {
+            SHIFTED[code as usize]
+        } else {
+            c
+        };
+
+        match c {
+            0 => None,
+            c if c.is_ascii_alphabetic() && self.modifiers.contains(Modifiers::CTRL) => {
+                Some((c & 0x1f) as char)
+            }
+            c => Some(c as char),
+        }
+    }
+}
+
+static DECODER: Mutex<Decoder> = Mutex::new(Decoder::new());
+
+/// The process with the focus (and its generation).
+static FOCUS: Mutex<Option<(Pid, u64)>> = Mutex::new(None);
+
+static EVENTS: Mutex<ArrayVec<KeyEvent, MAX_EVENTS>> = Mutex::new(ArrayVec::new_const());
+
+/// Gives `pid` the focus, fails if another live process has it.
+pub fn subscribe(pid: Pid) -> Result<(), KError> {
+    let generation = nr::KernelNode::process_generation(pid)?;
+    let mut focus = FOCUS.lock();
+    match *focus {
+        Some(current) if current == (pid, generation) => Ok(()),
+        Some((other, other_generation)) if nr::KernelNode::is_alive(other, other_generation) => {
+            Err(KError::KeyboardInUse { pid: other })
+        }
+        _ => {
+            info!("Process {} has the keyboard focus", pid);
+            *focus = Some((pid, generation));
+            EVENTS.lock().clear();
+            Ok(())
+        }
+    }
+}
+
+/// Takes the oldest key the focus `pid` didn't pick up yet (as passed to
+/// the process, see `KeyEvent`).
+pub fn next_event(pid: Pid) -> Result<Option<u64>, KError> {
+    let generation = nr::KernelNode::process_generation(pid)?;
+    if *FOCUS.lock() != Some((pid, generation)) {
+        return Err(KError::NoKeyboardFocus);
+    }
+
+    let mut events = EVENTS.lock();
+    if events.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(events.remove(0).into()))
+    }
+}
+
+/// Readiness of the keyboard for `events` of a `Poll` of `pid`: `POLLIN`
+/// once a key is queued. Takes the focus like [`subscribe`], `POLLERR` if
+/// another process has it.
+pub fn poll(pid: Pid, events: PollEvents) -> Result<PollEvents, KError> {
+    match subscribe(pid) {
+        Ok(()) => {}
+        Err(KError::KeyboardInUse { .. }) => return Ok(PollEvents::POLLERR),
+        Err(e) => return Err(e),
+    }
+
+    let mut ready = PollEvents::empty();
+    ready.set(PollEvents::POLLIN, !EVENTS.lock().is_empty());
+    Ok(ready & events)
+}
+
+/// Takes the next `byte` a keyboard sent (called by the drivers), returns
+/// true if it queued a key for the focus.
+pub fn scancode(byte: u8) -> bool {
+    let key = match DECODER.lock().feed(byte) {
+        Some(key) => key,
+        None => return false,
+    };
+    let focus = *FOCUS.lock();
+    if !focus.map_or(false, |(pid, generation)| {
+        nr::KernelNode::is_alive(pid, generation)
+    }) {
+        return false;
+    }
+
+    let mut events = EVENTS.lock();
+    if events.is_full() {
+        let dropped = events.remove(0);
+        debug!("Focus didn't pick up key {:#x}", dropped.scancode);
+    }
+    events.push(key);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::String;
+
+    fn typed(decoder: &mut Decoder, bytes: &[u8]) -> String {
+        bytes
+            .iter()
+            .filter_map(|byte| decoder.feed(*byte))
+            .filter_map(|key| key.character)
+            .collect()
+    }
+
+    #[test]
+    fn decode_keys() {
+        let mut decoder = Decoder::new();
+        // Shift+H, i, Shift+1, Enter
+        let bytes = [
+            0x2a, 0x23, 0xa3, 0xaa, 0x17, 0x97, 0x36, 0x02, 0x82, 0xb6, 0x1c, 0x9c,
+        ];
+        assert_eq!(typed(&mut decoder, &bytes), "Hi!\n");
+        assert!(decoder.modifiers.is_empty());
+
+        // Caps Lock only affects letters, Shift reverts it
+        let bytes = [0x3a, 0xba, 0x1e, 0x02, 0x2a, 0x1e, 0xaa, 0x3a, 0xba, 0x1e];
+        assert_eq!(typed(&mut decoder, &bytes), "A1aa");
+
+        // Ctrl+C
+        let bytes = [0x1d, 0x2e, 0xae, 0x9d];
+        assert_eq!(typed(&mut decoder, &bytes), "\x03");
+    }
+
+    #[test]
+    fn decode_extended() {
+        let mut decoder = Decoder::new();
+        // Cursor up (with fake Shift), released
+        let keys: alloc::vec::Vec<KeyEvent> = [0xe0, 0x2a, 0xe0, 0x48, 0xe0, 0xc8]
+            .iter()
+            .filter_map(|byte| decoder.feed(*byte))
+            .collect();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].scancode, 0xe048);
+        assert!(keys[0].pressed && !keys[1].pressed);
+        assert_eq!(keys[0].character, None);
+        assert!(decoder.modifiers.is_empty());
+
+        // Pause is ignored, keypad Enter is Enter
+        let bytes = [0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5, 0xe0, 0x1c, 0xfa];
+        assert_eq!(typed(&mut decoder, &bytes), "\n");
+        assert_eq!(NORMAL.len(), SHIFTED.len());
+    }
+}
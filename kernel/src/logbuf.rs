@@ -0,0 +1,138 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Per-core staging of kernel log lines.
+//!
+//! Formatting a log record takes a while (and can log or fault itself), if
+//! every core writes the pieces straight to the console the lines of
+//! different cores interleave. Instead, a core formats the whole line into
+//! its staging buffer first ([`stage`]), the logger then writes the complete
+//! line under the console lock, which is held only for the copy.
+//!
+//! Every line gets a [`sequence`] number (taken under the console lock, so
+//! the numbers follow the console order). The copies of the log that can
+//! lose or reorder lines (netconsole, disk log) carry it, post-processing can
+//! restore the order and spot lost lines.
+
+// Only the x86-64 kernel has its own logger
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use arrayvec::ArrayString;
+
+use crate::arch::MAX_CORES;
+use crate::sync::Mutex;
+
+/// Longest log line (in bytes), longer lines are truncated.
+pub const LINE_LEN: usize = 512;
+
+/// Appended to a line that didn't fit.
+const TRUNCATED: &str = " [...]";
+
+/// A log line being formatted.
+struct Staging {
+    line: ArrayString<LINE_LEN>,
+    truncated: bool,
+}
+
+impl Staging {
+    const fn new() -> Staging {
+        Staging {
+            line: ArrayString::new_const(),
+            truncated: false,
+        }
+    }
+
+    /// Formats `args` (replaces what was staged before).
+    fn format(&mut self, args: fmt::Arguments) -> &str {
+        self.line.clear();
+        self.truncated = false;
+        let _r = self.write_fmt(args);
+        if self.truncated {
+            let mut end = LINE_LEN - TRUNCATED.len();
+            while !self.line.is_char_boundary(end) {
+                end -= 1;
+            }
+            self.line.truncate(end);
+            self.line.push_str(TRUNCATED);
+        }
+        self.line.as_str()
+    }
+}
+
+impl fmt::Write for Staging {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.truncated {
+            return Ok(());
+        }
+        let mut len = core::cmp::min(s.len(), self.line.remaining_capacity());
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.line.push_str(&s[..len]);
+        self.truncated = len < s.len();
+        Ok(())
+    }
+}
+
+/// The staging buffers of the cores.
+static STAGING: [Mutex<Staging>; MAX_CORES] = {
+    const EMPTY: Mutex<Staging> = Mutex::new(Staging::new());
+    [EMPTY; MAX_CORES]
+};
+
+/// Sequence number of the next line.
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Formats `args` as one line in the staging buffer of `core` and passes the
+/// complete line to `emit`.
+///
+/// If the buffer is busy (we log while formatting a line, e.g., from an
+/// interrupt) the line is staged on the stack instead.
+pub fn stage(core: usize, args: fmt::Arguments, emit: impl FnOnce(&str)) {
+    match STAGING.get(core).and_then(|staging| staging.try_lock()) {
+        Some(mut staging) => emit(staging.format(args)),
+        None => emit(Staging::new().format(args)),
+    }
+}
+
+/// Takes the sequence number of the next line, call it with the console
+/// locked.
+pub fn sequence() -> u64 {
+    SEQUENCE.fetch_add(1, Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::String;
+
+    #[test]
+    fn stage_lines() {
+        let mut out = String::new();
+        stage(
+            0,
+            format_args!("[{:5}] - {}: {}", "INFO", "nrk", 42),
+            |line| out.push_str(line),
+        );
+        assert_eq!(out, "[INFO ] - nrk: 42");
+
+        // Logging while the buffer is in use stages on the stack
+        stage(1, format_args!("outer"), |outer| {
+            stage(1, format_args!("inner"), |inner| assert_eq!(inner, "inner"));
+            assert_eq!(outer, "outer");
+        });
+    }
+
+    #[test]
+    fn truncate_long_lines() {
+        let long = "ä".repeat(LINE_LEN);
+        stage(2, format_args!("{}", long), |line| {
+            assert!(line.len() <= LINE_LEN);
+            assert!(line.ends_with(TRUNCATED));
+            assert!(line.starts_with("ää"));
+        });
+    }
+}
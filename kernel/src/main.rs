@@ -32,7 +32,9 @@
     const_raw_ptr_deref,
     const_maybe_uninit_as_ptr,
     const_refs_to_cell,
-    nonnull_slice_from_raw_parts
+    nonnull_slice_from_raw_parts,
+    abi_efiapi,
+    const_caller_location
 )]
 #![cfg_attr(not(target_os = "none"), feature(thread_local))]
 
@@ -54,21 +56,48 @@ pub mod arch;
 #[path = "arch/x86_64/mod.rs"]
 pub mod x86_64_arch;
 
+mod autostart;
+mod block;
+mod build_info;
+mod clock;
 mod cnrfs;
+mod cputime;
+mod devices;
+mod disklog;
+mod domain;
+mod elfcheck;
+mod entropy;
 mod error;
+mod fault;
+mod features;
 mod fs;
 mod graphviz;
+mod ioring;
+mod irqroute;
 mod kcb;
+mod keyboard;
+mod logbuf;
 mod memory;
+mod mgmt;
 mod nr;
 mod nrproc;
+mod nrstats;
+mod oom;
+mod placement;
 #[macro_use]
 mod prelude;
 mod fallible_string;
 mod mpmc;
 mod process;
 mod scheduler;
+mod services;
+mod sntp;
+mod softirq;
 mod stack;
+mod strace;
+mod sync;
+mod timer_wheel;
+mod ulog;
 
 pub mod panic;
 
@@ -105,7 +134,7 @@ pub enum ExitReason {
 #[no_mangle]
 #[cfg(not(feature = "integration-test"))]
 pub fn xmain() {
-    let ret = arch::process::spawn("init");
+    let ret = arch::process::start_init();
     if let Err(e) = ret {
         log::warn!("{}", e);
     }
@@ -19,12 +19,12 @@ use arrayvec::ArrayVec;
 use atopology::MACHINE_TOPOLOGY;
 use crossbeam_utils::CachePadded;
 use log::info;
-use spin::Mutex;
 
 use crate::arch::MAX_NUMA_NODES;
 use crate::error::KError;
 use crate::kcb;
 use crate::mpmc::Queue;
+use crate::sync::Mutex;
 
 /// Makes allocation failures are deterministic (across all replicas) when used
 /// within a replica.
@@ -0,0 +1,232 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A heap sanitizer for the kernel (a very reduced version of Linux' KASAN).
+//!
+//! With the `kasan` feature, every small allocation (the ones served by the
+//! zone allocator) is surrounded by red zones:
+//!
+//! ```text
+//! | header | front red zone | object | rear red zone |
+//! ```
+//!
+//! The header records the state of the object and the call-chains that
+//! allocated and freed it. The red zones are filled with a pattern that is
+//! checked when the object is freed, so we catch writes past either end of
+//! the object (buffer overflows).
+//!
+//! Freed objects are poisoned and put in a quarantine instead of being
+//! returned to the allocator right away. Once an object leaves the quarantine,
+//! we check that the poison is still intact (which catches writes through
+//! dangling pointers, i.e., use-after-free). Reads of freed memory are not
+//! detected.
+//!
+//! On a violation we print the allocation and free call-chains and panic.
+
+use core::alloc::Layout;
+use core::ffi::c_void;
+use core::ptr;
+
+use klogger::sprintln;
+use slabmalloc::ZoneAllocator;
+
+use crate::round_up;
+use crate::sync::Mutex;
+
+/// Size of the header and front red zone (also the maximum alignment we can
+/// support without extra padding).
+const FRONT: usize = 128;
+
+/// Minimum size of the rear red zone.
+const REAR: usize = 32;
+
+/// Number of frames we record for allocation and free.
+const BACKTRACE_DEPTH: usize = 4;
+
+/// Number of freed objects that we hold back.
+const QUARANTINE_SIZE: usize = 1024;
+
+/// Pattern of the red zones.
+const REDZONE_BYTE: u8 = 0xfc;
+
+/// Pattern of freed objects.
+const FREED_BYTE: u8 = 0xfd;
+
+/// `Header::magic` of an allocated object.
+const ALLOCATED: u64 = 0x6b61_7361_6e41_4c4c;
+
+/// `Header::magic` of a freed object.
+const FREED: u64 = 0x6b61_7361_6e46_5245;
+
+/// Book-keeping at the start of every sanitized allocation (has to be
+/// smaller than `FRONT`, the rest of it is the front red zone).
+#[repr(C)]
+struct Header {
+    magic: u64,
+    /// The size the caller requested.
+    size: usize,
+    allocated_by: [usize; BACKTRACE_DEPTH],
+    freed_by: [usize; BACKTRACE_DEPTH],
+}
+
+/// Objects that were freed but not yet returned to the allocator.
+struct Quarantine {
+    next: usize,
+    objects: [Option<(usize, Layout)>; QUARANTINE_SIZE],
+}
+
+static QUARANTINE: Mutex<Quarantine> = Mutex::new(Quarantine {
+    next: 0,
+    objects: [None; QUARANTINE_SIZE],
+});
+
+/// Returns the (bigger) layout we allocate for `layout` or `None` if we
+/// don't sanitize allocations of this layout.
+pub fn padded_layout(layout: Layout) -> Option<Layout> {
+    let size = FRONT + round_up!(layout.size(), 8) + REAR;
+    if layout.align() > FRONT || size > ZoneAllocator::MAX_ALLOC_SIZE {
+        return None;
+    }
+    Layout::from_size_align(size, layout.align()).ok()
+}
+
+/// Records the return addresses of the current call-chain.
+fn capture_backtrace() -> [usize; BACKTRACE_DEPTH] {
+    let mut frames = [0; BACKTRACE_DEPTH];
+    let mut idx = 0;
+    backtracer_core::trace(|frame| {
+        frames[idx] = frame.ip() as usize;
+        idx += 1;
+        idx < BACKTRACE_DEPTH
+    });
+    frames
+}
+
+fn print_frames(what: &str, frames: &[usize]) {
+    if frames.iter().all(|ip| *ip == 0) {
+        return;
+    }
+    sprintln!("kasan: {} by:", what);
+    for (count, ip) in frames.iter().filter(|ip| **ip != 0).enumerate() {
+        crate::panic::backtrace_ip(count + 1, *ip as *mut c_void);
+    }
+}
+
+/// Prints what we know about the object at `raw` and panics.
+fn report(what: &str, raw: *mut u8, offset: isize) -> ! {
+    // Safety: `raw` points to one of our (padded) allocations
+    let header = unsafe { &*(raw as *const Header) };
+    sprintln!(
+        "kasan: {} at offset {} of object {:p} ({} bytes)",
+        what,
+        offset,
+        unsafe { raw.add(FRONT) },
+        header.size
+    );
+    print_frames("Allocated", &header.allocated_by);
+    print_frames("Freed", &header.freed_by);
+    panic!("kasan: {}", what);
+}
+
+/// Returns the offset of the first byte in `from..to` (relative to `raw`)
+/// that isn't `pattern`.
+unsafe fn find_corruption(raw: *mut u8, from: usize, to: usize, pattern: u8) -> Option<usize> {
+    (from..to).find(|offset| ptr::read_volatile(raw.add(*offset)) != pattern)
+}
+
+/// Sets up the header and red zones of a fresh allocation `raw` (with the
+/// layout from `padded_layout(layout)`), returns the pointer to hand out.
+///
+/// # Safety
+/// `raw` has to be a valid allocation of `padded_layout(layout)`.
+pub unsafe fn on_alloc(raw: *mut u8, layout: Layout, padded: Layout) -> *mut u8 {
+    if raw.is_null() {
+        return raw;
+    }
+
+    ptr::write_bytes(raw, REDZONE_BYTE, FRONT);
+    ptr::write_bytes(
+        raw.add(FRONT + layout.size()),
+        REDZONE_BYTE,
+        padded.size() - FRONT - layout.size(),
+    );
+    ptr::write(
+        raw as *mut Header,
+        Header {
+            magic: ALLOCATED,
+            size: layout.size(),
+            allocated_by: capture_backtrace(),
+            freed_by: [0; BACKTRACE_DEPTH],
+        },
+    );
+
+    raw.add(FRONT)
+}
+
+/// Checks the red zones of the object at `ptr` and poisons it.
+///
+/// Returns the object to free now (the one that was evicted from the
+/// quarantine to make room for `ptr`) as a (pointer, padded layout) tuple.
+///
+/// # Safety
+/// `ptr` has to be an object returned from `on_alloc` with `layout`.
+pub unsafe fn on_dealloc(
+    ptr: *mut u8,
+    layout: Layout,
+    padded: Layout,
+) -> Option<(*mut u8, Layout)> {
+    let raw = ptr.sub(FRONT);
+    let header = &mut *(raw as *mut Header);
+
+    match header.magic {
+        ALLOCATED => {}
+        FREED => {
+            sprintln!("kasan: Freed again by:");
+            crate::panic::backtrace();
+            report("double free", raw, 0);
+        }
+        _ => report(
+            "invalid free (or the header was overwritten)",
+            raw,
+            -(FRONT as isize),
+        ),
+    }
+    if header.size != layout.size() {
+        report("free with wrong layout", raw, 0);
+    }
+
+    let header_end = core::mem::size_of::<Header>();
+    if let Some(offset) = find_corruption(raw, header_end, FRONT, REDZONE_BYTE) {
+        report("buffer underflow", raw, offset as isize - FRONT as isize);
+    }
+    if let Some(offset) = find_corruption(raw, FRONT + layout.size(), padded.size(), REDZONE_BYTE) {
+        report("buffer overflow", raw, (offset - FRONT) as isize);
+    }
+
+    header.magic = FREED;
+    header.freed_by = capture_backtrace();
+    ptr::write_bytes(ptr, FREED_BYTE, layout.size());
+
+    // Don't deadlock if an interrupt frees memory while we hold the lock,
+    // just free the object right away in that case.
+    let mut quarantine = match QUARANTINE.try_lock() {
+        Some(quarantine) => quarantine,
+        None => return Some((raw, padded)),
+    };
+    let next = quarantine.next;
+    let evicted = quarantine.objects[next].replace((ptr as usize, layout));
+    quarantine.next = (next + 1) % QUARANTINE_SIZE;
+    drop(quarantine);
+
+    evicted.map(|(ptr, layout)| {
+        let ptr = ptr as *mut u8;
+        let raw = ptr.sub(FRONT);
+        if let Some(offset) = find_corruption(raw, FRONT, FRONT + layout.size(), FREED_BYTE) {
+            report("use after free (write)", raw, (offset - FRONT) as isize);
+        }
+        (
+            raw,
+            padded_layout(layout).expect("Only sanitized objects are in the quarantine"),
+        )
+    })
+}
@@ -98,6 +98,10 @@ impl<const BP: usize, const LP: usize> MCache<BP, LP> {
     }
 
     fn populate(&mut self, frame: Frame, mut how_many_large_pages: usize) {
+        if super::reserved::check_allocatable(&frame).is_err() {
+            return;
+        }
+
         let base_count_before_populate = self.base_page_addresses.len();
         let large_count_before_populate = self.large_page_addresses.len();
 
@@ -130,24 +134,36 @@ impl<const BP: usize, const LP: usize> MCache<BP, LP> {
             how_many_large_pages -= 1;
         }
 
-        // Put the rest as base-pages
-        let mut lost_pages = 0;
-        for base_page in large_page_aligned_frame.into_iter() {
-            match self.base_page_addresses.try_push(base_page.base) {
-                Ok(()) => continue,
-                Err(_) => {
-                    lost_pages += 1;
-                    break;
+        // Put the rest as base-pages, or as large-pages once we run out of
+        // base-pages (rather than dropping the memory of big frames)
+        let mut lost = lost_large_pages * LARGE_PAGE_SIZE;
+        let mut rest = large_page_aligned_frame;
+        while rest != Frame::empty() {
+            let base_pages_full = self.base_page_addresses.is_full();
+            if base_pages_full && self.large_page_addresses.is_full() {
+                lost += rest.size();
+                break;
+            }
+
+            if base_pages_full && rest.is_large_page_aligned() && rest.size() >= LARGE_PAGE_SIZE {
+                let (large_page, high) = rest.split_at(LARGE_PAGE_SIZE);
+                if self.large_page_addresses.try_push(large_page.base).is_err() {
+                    lost += LARGE_PAGE_SIZE;
+                }
+                rest = high;
+            } else {
+                let (base_page, high) = rest.split_at(BASE_PAGE_SIZE);
+                if self.base_page_addresses.try_push(base_page.base).is_err() {
+                    lost += BASE_PAGE_SIZE;
                 }
+                rest = high;
             }
         }
 
-        if lost_pages > 0 || lost_large_pages > 0 {
+        if lost > 0 {
             warn!(
                 "MCache population lost {} of memory.",
-                super::DataSize::from_bytes(
-                    lost_pages * BASE_PAGE_SIZE + lost_large_pages * LARGE_PAGE_SIZE
-                )
+                super::DataSize::from_bytes(lost)
             );
         }
 
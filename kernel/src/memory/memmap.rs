@@ -0,0 +1,254 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Turns the conventional memory of the firmware's memory map into the
+//! regions the memory allocators get during boot.
+//!
+//! Some machines report all of their memory as one huge region, we split
+//! regions bigger than [`MAX_REGION_SIZE`] into chunks of that size rather
+//! than handing the allocators a region they only take part of. Memory we
+//! don't manage (below 1 MiB, reserved ranges or regions beyond
+//! [`MAX_PHYSICAL_REGIONS`]) is remembered so we can report it, and
+//! [`MemoryMap::validate`] checks that every byte of the map is either
+//! managed or reported.
+
+// Only the x86-64 kernel parses a memory map
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use arrayvec::ArrayVec;
+use log::{error, info, warn};
+
+use super::reserved;
+use super::{DataSize, Frame, MAX_PHYSICAL_REGIONS};
+
+/// Largest region we hand to the allocators in one piece (64 GiB).
+pub const MAX_REGION_SIZE: usize = 64 * 1024 * 1024 * 1024;
+
+/// We don't manage memory below this address.
+const ONE_MIB: usize = 1024 * 1024;
+
+/// How many ignored regions we remember (for the report).
+const MAX_IGNORED_REGIONS: usize = 16;
+
+/// Why we don't manage a region.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Ignored {
+    /// It's (partly) below 1 MiB.
+    LowMemory,
+    /// We ran out of regions ([`MAX_PHYSICAL_REGIONS`]).
+    TooManyRegions,
+    /// It's reserved (see `reserved`).
+    Reserved,
+}
+
+/// The conventional memory of the memory map.
+#[derive(Default)]
+pub struct MemoryMap {
+    regions: ArrayVec<Frame, MAX_PHYSICAL_REGIONS>,
+    ignored: ArrayVec<(Frame, Ignored), MAX_IGNORED_REGIONS>,
+    /// Bytes of memory we took out of `regions` (see [`MemoryMap::take`]).
+    taken: usize,
+    /// Bytes and number of ignored regions (including the ones we don't
+    /// remember).
+    ignored_bytes: usize,
+    ignored_count: usize,
+    /// Bytes of conventional memory in the map.
+    total: usize,
+}
+
+impl MemoryMap {
+    /// Adds a region of conventional memory of the map.
+    pub fn add(&mut self, frame: Frame) {
+        self.total += frame.size();
+
+        // Ignore all physical memory below 1 MiB because it's not worth the
+        // hassle of dealing with it. Some of the memory here will be used by
+        // coreboot, there we just assume the memory is free for us to use --
+        // so in case someone wants to change it have a look there first!
+        let mut rest = frame;
+        if rest.base.as_usize() < ONE_MIB {
+            let (low, high) = rest.split_at(ONE_MIB - rest.base.as_usize());
+            self.ignore(low, Ignored::LowMemory);
+            rest = high;
+        }
+
+        // Leave out reserved ranges (firmware may report them as
+        // conventional memory)
+        while let Some((below, reserved, above)) = reserved::split(rest) {
+            self.push(below);
+            self.ignore(reserved, Ignored::Reserved);
+            rest = above;
+        }
+        self.push(rest);
+    }
+
+    /// Adds `frame` to the regions (in chunks of [`MAX_REGION_SIZE`]).
+    fn push(&mut self, frame: Frame) {
+        let mut rest = frame;
+        while rest != Frame::empty() {
+            let (chunk, high) = rest.split_at(MAX_REGION_SIZE);
+            if self.regions.try_push(chunk).is_err() {
+                self.ignore(rest, Ignored::TooManyRegions);
+                return;
+            }
+            rest = high;
+        }
+    }
+
+    fn ignore(&mut self, frame: Frame, reason: Ignored) {
+        self.ignored_bytes += frame.size();
+        self.ignored_count += 1;
+        let _remembered = self.ignored.try_push((frame, reason));
+    }
+
+    /// Takes `size` bytes from the first region that is bigger than that
+    /// (e.g., for the early allocator of the BSP).
+    pub fn take(&mut self, size: usize) -> Option<Frame> {
+        let region = self.regions.iter_mut().find(|f| f.size() > size)?;
+        let (low, high) = region.split_at(size);
+        *region = high;
+        self.taken += low.size();
+        Some(low)
+    }
+
+    /// The regions for the allocators.
+    pub fn regions(&self) -> &ArrayVec<Frame, MAX_PHYSICAL_REGIONS> {
+        &self.regions
+    }
+
+    /// Bytes of conventional memory in the map.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Bytes of memory the kernel manages.
+    pub fn managed(&self) -> usize {
+        self.taken + self.regions.iter().map(|f| f.size()).sum::<usize>()
+    }
+
+    /// Bytes of memory we don't manage.
+    pub fn ignored(&self) -> usize {
+        self.ignored_bytes
+    }
+
+    /// Checks that all memory of the map is managed or ignored.
+    pub fn validate(&self) -> bool {
+        self.managed() + self.ignored() == self.total()
+    }
+
+    /// Logs how much memory we manage and the regions we ignore.
+    pub fn report(&self) {
+        info!(
+            "Memory map: {} of conventional memory, {} managed in {} regions",
+            DataSize::from_bytes(self.total()),
+            DataSize::from_bytes(self.managed()),
+            self.regions.len()
+        );
+        if self.ignored_count > 0 {
+            info!(
+                "Memory map: ignored {} in {} region(s)",
+                DataSize::from_bytes(self.ignored()),
+                self.ignored_count
+            );
+        }
+        for (frame, reason) in self.ignored.iter() {
+            match reason {
+                Ignored::LowMemory => info!("  ignored {:?} (below 1 MiB)", frame),
+                Ignored::TooManyRegions => warn!(
+                    "  ignored {:?} (more than {} regions)",
+                    frame, MAX_PHYSICAL_REGIONS
+                ),
+                Ignored::Reserved => warn!("  ignored {:?} (reserved)", frame),
+            }
+        }
+        if self.ignored_count > self.ignored.len() {
+            warn!(
+                "  ... and {} more region(s)",
+                self.ignored_count - self.ignored.len()
+            );
+        }
+        if !self.validate() {
+            error!(
+                "Memory map: {} managed and {} ignored don't add up to {}",
+                DataSize::from_bytes(self.managed()),
+                DataSize::from_bytes(self.ignored()),
+                DataSize::from_bytes(self.total())
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::PAddr;
+
+    const GIB: usize = 1024 * 1024 * 1024;
+
+    #[test]
+    fn giant_regions_are_chunked() {
+        let mut map = MemoryMap::default();
+        map.add(Frame::new(PAddr::from(4 * GIB as u64), 150 * GIB, 0));
+
+        let sizes: ArrayVec<usize, 4> = map.regions().iter().map(|f| f.size()).collect();
+        assert_eq!(sizes.as_slice(), &[64 * GIB, 64 * GIB, 22 * GIB]);
+        assert_eq!(map.regions()[1].base, PAddr::from(68 * GIB as u64));
+        assert_eq!(map.managed(), 150 * GIB);
+        assert!(map.validate());
+    }
+
+    #[test]
+    fn low_memory_is_ignored() {
+        let mut map = MemoryMap::default();
+        map.add(Frame::new(PAddr::from(0x0u64), 2 * 1024 * 1024, 0));
+
+        assert_eq!(map.regions().len(), 1);
+        assert_eq!(map.regions()[0].base, PAddr::from(ONE_MIB as u64));
+        assert_eq!(map.ignored(), ONE_MIB);
+        assert!(map.validate());
+    }
+
+    #[test]
+    fn reserved_ranges_are_left_out() {
+        let base = PAddr::from(0x00fe_0000_0000_0000u64);
+        reserved::reserve(base + 0x2000usize, 0x1000, reserved::Reserved::Module).unwrap();
+
+        let mut map = MemoryMap::default();
+        map.add(Frame::new(base, 0x8000, 0));
+        assert_eq!(map.regions()[0], Frame::new(base, 0x2000, 0));
+        assert_eq!(map.regions()[1], Frame::new(base + 0x3000usize, 0x5000, 0));
+        assert_eq!(map.ignored[0].1, Ignored::Reserved);
+        assert!(map.validate());
+    }
+
+    #[test]
+    fn ignored_regions() {
+        let mut map = MemoryMap::default();
+        map.add(Frame::new(PAddr::from(0x1000u64), 0x9f000, 0));
+        for i in 0..MAX_PHYSICAL_REGIONS + 2 {
+            map.add(Frame::new(PAddr::from(((i + 1) * GIB) as u64), 0x1000, 0));
+        }
+
+        assert_eq!(map.regions().len(), MAX_PHYSICAL_REGIONS);
+        assert_eq!(map.ignored(), 0x9f000 + 2 * 0x1000);
+        assert_eq!(map.ignored[0].1, Ignored::LowMemory);
+        assert_eq!(map.ignored[1].1, Ignored::TooManyRegions);
+        assert!(map.validate());
+    }
+
+    #[test]
+    fn take_early_memory() {
+        let mut map = MemoryMap::default();
+        map.add(Frame::new(PAddr::from(0x100000u64), 0x10000, 0));
+        map.add(Frame::new(PAddr::from(GIB as u64), GIB, 0));
+
+        let early = map.take(32 * 1024 * 1024).expect("Big enough region");
+        assert_eq!(early.base, PAddr::from(GIB as u64));
+        assert_eq!(
+            map.regions()[1].base,
+            PAddr::from((GIB + 32 * 1024 * 1024) as u64)
+        );
+        assert_eq!(map.managed(), map.total());
+        assert!(map.take(2 * GIB).is_none());
+    }
+}
@@ -22,11 +22,11 @@ use core::{fmt, ptr};
 use arrayvec::ArrayVec;
 use log::{debug, error, trace, warn};
 use slabmalloc::{Allocator, ZoneAllocator};
-use spin::Mutex;
 use x86::bits64::paging;
 
 use crate::arch::MAX_NUMA_NODES;
 use crate::prelude::*;
+use crate::sync::Mutex;
 use crate::{kcb, round_up};
 
 /// Re-export arch specific memory definitions
@@ -39,13 +39,18 @@ use vspace::MapAction;
 
 pub mod detmem;
 pub mod emem;
+#[cfg(feature = "kasan")]
+pub mod kasan;
 pub mod mcache;
+pub mod memmap;
+pub mod reserved;
 pub mod vspace;
 #[cfg(test)]
 pub mod vspace_model;
 
-/// How many initial physical memory regions we support.
-pub const MAX_PHYSICAL_REGIONS: usize = 64;
+/// How many initial physical memory regions we support (after splitting
+/// big ones, see `memmap`).
+pub const MAX_PHYSICAL_REGIONS: usize = 128;
 
 /// The global allocator in the kernel.
 //#[cfg(not(any(test, fuzzing)))]
@@ -100,7 +105,7 @@ impl KernelAllocator {
         match KernelAllocator::allocator_for(layout) {
             AllocatorType::Zone if layout.size() <= ZoneAllocator::MAX_ALLOC_SIZE => {
                 // TODO(rust): Silly code duplication follows if/else
-                if core::intrinsics::unlikely(kcb.in_panic_mode) {
+                if core::intrinsics::unlikely(kcb.in_panic_mode()) {
                     let mut zone_allocator = kcb.ezone_allocator()?;
                     zone_allocator.allocate(layout).map_err(|e| e.into())
                 } else {
@@ -273,6 +278,10 @@ impl KernelAllocator {
         needed_base_pages: usize,
         needed_large_pages: usize,
     ) -> Result<(), KError> {
+        if crate::fault::fire(crate::fault::Fault::FailAllocation) {
+            return Err(KError::OutOfMemory);
+        }
+
         let kcb = kcb::try_get_kcb().ok_or(KError::KcbUnavailable)?;
         if kcb.physical_memory.gmanager.is_none() {
             // No gmanager, can't refill then, let's hope it works anyways...
@@ -346,7 +355,7 @@ impl KernelAllocator {
 
         let mut mem_manager = kcb.try_mem_manager()?;
         // TODO(rust): Silly code duplication follows if/else
-        if core::intrinsics::unlikely(kcb.in_panic_mode) {
+        if core::intrinsics::unlikely(kcb.in_panic_mode()) {
             let mut zone = kcb.ezone_allocator()?;
             if needs_a_base_page {
                 let frame = mem_manager.allocate_base_page()?;
@@ -393,13 +402,13 @@ impl KernelAllocator {
     }
 }
 
-/// Implementation of GlobalAlloc for the kernel.
-///
-/// The algorithm in alloc/dealloc should take care of allocating kernel objects of
-/// various sizes and is responsible for balancing the memory between different
-/// allocators.
-unsafe impl GlobalAlloc for KernelAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+impl KernelAllocator {
+    /// Allocates memory for `layout`.
+    ///
+    /// The algorithm in allocate/deallocate should take care of allocating
+    /// kernel objects of various sizes and is responsible for balancing the
+    /// memory between different allocators.
+    unsafe fn allocate(&self, layout: Layout) -> *mut u8 {
         for _tries in 0..3 {
             let res = self.try_alloc(layout);
             match res {
@@ -434,7 +443,15 @@ unsafe impl GlobalAlloc for KernelAllocator {
                             break;
                         }
                         Err(_e) => {
-                            // Refilling failed, re-try allocation
+                            // Refilling failed, take what we need from the
+                            // reserve (and let the OOM killer free memory)
+                            let (needed_base_pages, needed_large_pages) =
+                                KernelAllocator::refill_amount(layout);
+                            if crate::oom::use_reserve(needed_base_pages, needed_large_pages)
+                                .is_ok()
+                            {
+                                continue;
+                            }
                             return ptr::null_mut();
                         }
                     }
@@ -445,7 +462,8 @@ unsafe impl GlobalAlloc for KernelAllocator {
         ptr::null_mut()
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    /// Returns memory from `allocate` to the allocators.
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
         crate::kcb::try_get_kcb().map_or_else(
             || {
                 unreachable!("Trying to deallocate {:p} {:?} without a KCB.", ptr, layout);
@@ -453,7 +471,7 @@ unsafe impl GlobalAlloc for KernelAllocator {
             |kcb| {
                 if layout.size() <= ZoneAllocator::MAX_ALLOC_SIZE {
                     // TODO(rust): Silly code duplication follows if/else
-                    if core::intrinsics::unlikely(kcb.in_panic_mode) {
+                    if core::intrinsics::unlikely(kcb.in_panic_mode()) {
                         let mut zone_allocator = kcb
                             .ezone_allocator()
                             .expect("Can't borrow ezone_allocator?");
@@ -527,6 +545,35 @@ unsafe impl GlobalAlloc for KernelAllocator {
             },
         );
     }
+}
+
+/// Implementation of GlobalAlloc for the kernel.
+///
+/// With the `kasan` feature, small allocations get red zones and a quarantine
+/// (see [`kasan`]).
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _pin = crate::domain::pin();
+        #[cfg(feature = "kasan")]
+        if let Some(padded) = kasan::padded_layout(layout) {
+            return kasan::on_alloc(self.allocate(padded), layout, padded);
+        }
+        self.allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _pin = crate::domain::pin();
+        #[cfg(feature = "kasan")]
+        if !ptr.is_null() {
+            if let Some(padded) = kasan::padded_layout(layout) {
+                if let Some((evicted, evicted_layout)) = kasan::on_dealloc(ptr, layout, padded) {
+                    self.deallocate(evicted, evicted_layout);
+                }
+                return;
+            }
+        }
+        self.deallocate(ptr, layout)
+    }
 
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
         crate::kcb::try_get_kcb().map_or_else(
@@ -534,7 +581,9 @@ unsafe impl GlobalAlloc for KernelAllocator {
                 unreachable!("Trying to reallocate {:p} {:?} without a KCB.", ptr, layout);
             },
             |kcb| {
-                if !kcb.in_panic_mode
+                if !kcb.in_panic_mode()
+                    // The sanitizer needs to move the rear red zone
+                    && !cfg!(feature = "kasan")
                     && layout.size() <= ZoneAllocator::MAX_ALLOC_SIZE
                     && layout.size() != BASE_PAGE_SIZE
                     && new_size <= ZoneAllocator::get_max_size(layout.size()).unwrap_or(0x0)
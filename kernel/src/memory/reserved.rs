@@ -0,0 +1,232 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Registry of physical memory ranges that belong to someone else (the
+//! kernel image, modules, ACPI tables, interrupt controllers, the real-mode
+//! bootstrap code of the app cores ...).
+//!
+//! The ranges are registered during boot. The memory allocators don't take
+//! memory that overlaps a reserved range (see `memmap` and
+//! `MCache::populate`) and identity mappings of device memory are audited with
+//! [`check`]: only the owner of a range may map it, so a bogus BAR or a
+//! process that asks for device memory (`VSpaceOperation::MapDevice`) can't
+//! map the kernel or the APIC by accident.
+
+// Only the x86-64 kernel registers ranges
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use arrayvec::ArrayVec;
+use log::{error, info, warn};
+
+use crate::error::KError;
+use crate::round_up;
+use crate::sync::Mutex;
+
+use super::{DataSize, Frame, PAddr, BASE_PAGE_SIZE};
+
+/// How many ranges we can register (adjacent ranges of the same kind are
+/// merged).
+const MAX_RESERVATIONS: usize = 64;
+
+/// What a reserved range is used for.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Reserved {
+    /// The loaded kernel ELF image.
+    KernelImage,
+    /// Page-tables, stack, arguments and memory map the bootloader set up.
+    BootData,
+    /// Binaries the bootloader passed to the kernel.
+    Module,
+    /// ACPI tables and non-volatile storage of the firmware.
+    AcpiTables,
+    /// Registers of the local APIC.
+    LocalApic,
+    /// Registers of an I/O APIC.
+    IoApic,
+    /// Registers of the HPET.
+    Hpet,
+    /// Bootstrap code for app cores (they start in real-mode).
+    RealModeBootstrap,
+}
+
+/// A reserved range of physical memory (page aligned).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Reservation {
+    pub base: PAddr,
+    pub size: usize,
+    pub kind: Reserved,
+}
+
+impl Reservation {
+    pub fn end(&self) -> PAddr {
+        self.base + self.size
+    }
+
+    fn overlaps(&self, base: PAddr, size: usize) -> bool {
+        size > 0 && base < self.end() && self.base < base + size
+    }
+}
+
+static RESERVATIONS: Mutex<ArrayVec<Reservation, MAX_RESERVATIONS>> =
+    Mutex::new(ArrayVec::new_const());
+
+/// Reserves `size` bytes at `base` (rounded to pages) for `kind`.
+pub fn reserve(base: PAddr, size: usize, kind: Reserved) -> Result<(), KError> {
+    let start = base.align_down_to_base_page();
+    let end = PAddr::from(round_up!((base + size).as_usize(), BASE_PAGE_SIZE) as u64);
+    let size = (end - start).as_usize();
+    if size == 0 {
+        return Ok(());
+    }
+
+    let mut reservations = RESERVATIONS.lock();
+    for r in reservations.iter_mut() {
+        if r.kind == kind && r.base <= end && start <= r.end() {
+            let merged_end = core::cmp::max(r.end(), end);
+            r.base = core::cmp::min(r.base, start);
+            r.size = (merged_end - r.base).as_usize();
+            return Ok(());
+        }
+    }
+    reservations
+        .try_push(Reservation {
+            base: start,
+            size,
+            kind,
+        })
+        .map_err(|_e| KError::CapacityOverflow)
+}
+
+/// The reserved range that overlaps `size` bytes at `base` and starts first.
+pub fn find(base: PAddr, size: usize) -> Option<Reservation> {
+    RESERVATIONS
+        .lock()
+        .iter()
+        .filter(|r| r.overlaps(base, size))
+        .min_by_key(|r| r.base)
+        .copied()
+}
+
+/// Checks that `size` bytes at `base` may be mapped by `owner` (`None` for
+/// memory that should not be reserved at all, e.g., device BARs).
+pub fn check(base: PAddr, size: usize, owner: Option<Reserved>) -> Result<(), KError> {
+    let reservations = RESERVATIONS.lock();
+    match reservations
+        .iter()
+        .find(|r| r.overlaps(base, size) && Some(r.kind) != owner)
+    {
+        Some(r) => {
+            warn!(
+                "Refusing to map {:#x} -- {:#x}: overlaps {:?} at {:#x} -- {:#x}",
+                base,
+                base + size,
+                r.kind,
+                r.base,
+                r.end()
+            );
+            Err(KError::ReservedMemory {
+                base: base.as_u64(),
+            })
+        }
+        None => Ok(()),
+    }
+}
+
+/// Checks that `frame` doesn't overlap any reserved range before an
+/// allocator takes it.
+pub fn check_allocatable(frame: &Frame) -> Result<(), KError> {
+    match find(frame.base, frame.size()) {
+        Some(r) => {
+            error!(
+                "Refusing to allocate {:?}: overlaps {:?} at {:#x} -- {:#x}",
+                frame,
+                r.kind,
+                r.base,
+                r.end()
+            );
+            Err(KError::ReservedMemory {
+                base: frame.base.as_u64(),
+            })
+        }
+        None => Ok(()),
+    }
+}
+
+/// Splits `frame` at the first reserved range it overlaps, returns the part
+/// below it, the overlapping part (page aligned) and the part above it.
+///
+/// Returns `None` if `frame` doesn't overlap a reserved range.
+pub fn split(frame: Frame) -> Option<(Frame, Frame, Frame)> {
+    let r = find(frame.base, frame.size())?;
+    let start = core::cmp::max(frame.base, r.base);
+    let end = core::cmp::min(frame.end(), r.end());
+    debug_assert!(start.as_usize() % BASE_PAGE_SIZE == 0 && end.as_usize() % BASE_PAGE_SIZE == 0);
+
+    let (below, rest) = frame.split_at((start - frame.base).as_usize());
+    let (reserved, above) = rest.split_at((end - start).as_usize());
+    Some((below, reserved, above))
+}
+
+/// Logs all reserved ranges.
+pub fn report() {
+    let reservations = RESERVATIONS.lock();
+    info!("Reserved {} physical memory range(s)", reservations.len());
+    for r in reservations.iter() {
+        info!(
+            "  {:#x} -- {:#x} {:?} ({})",
+            r.base,
+            r.end(),
+            r.kind,
+            DataSize::from_bytes(r.size)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The registry is global, every test uses its own addresses (above what
+    // a host allocator gives out)
+    const BASE: u64 = 0x00ff_0000_0000_0000;
+
+    #[test]
+    fn reserve_and_check() {
+        let base = PAddr::from(BASE);
+        reserve(base + 0x10usize, 0x1000, Reserved::LocalApic).unwrap();
+
+        // Rounded to pages
+        let r = find(base, 0x1000).expect("Overlaps");
+        assert_eq!(r.base, base);
+        assert_eq!(r.size, 0x2000);
+
+        assert!(check(base + 0x1000usize, 0x1000, None).is_err());
+        assert!(check(base, 0x1000, Some(Reserved::LocalApic)).is_ok());
+        assert!(check(base + 0x2000usize, 0x1000, None).is_ok());
+        assert!(find(base + 0x2000usize, 0x1000).is_none());
+    }
+
+    #[test]
+    fn adjacent_ranges_are_merged() {
+        let base = PAddr::from(BASE + 0x1000_0000);
+        reserve(base, 0x1000, Reserved::Module).unwrap();
+        reserve(base + 0x1000usize, 0x3000, Reserved::Module).unwrap();
+
+        let r = find(base, 0x1000).expect("Overlaps");
+        assert_eq!(r.size, 0x4000);
+    }
+
+    #[test]
+    fn split_frames_around_reservations() {
+        let base = PAddr::from(BASE + 0x2000_0000);
+        reserve(base + 0x3000usize, 0x2000, Reserved::AcpiTables).unwrap();
+
+        let frame = Frame::new(base, 0x10000, 0);
+        let (below, reserved, above) = split(frame).expect("Overlaps");
+        assert_eq!(below, Frame::new(base, 0x3000, 0));
+        assert_eq!(reserved, Frame::new(base + 0x3000usize, 0x2000, 0));
+        assert_eq!(above, Frame::new(base + 0x5000usize, 0xb000, 0));
+        assert!(check_allocatable(&above).is_ok());
+        assert!(check_allocatable(&frame).is_err());
+    }
+}
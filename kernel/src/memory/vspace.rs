@@ -4,10 +4,12 @@
 //! A trait defining architecture independent address spaces.
 
 use core::cmp::PartialEq;
+use core::convert::TryFrom;
 use core::fmt;
 
 use crate::error::KError;
 use bit_field::BitField;
+use kpi::process::MapFlags;
 use x86::current::paging::{PDFlags, PDPTFlags, PTFlags};
 
 use super::{Frame, PAddr, VAddr};
@@ -174,8 +176,36 @@ pub trait AddressSpace {
     /// invoked to flush the TLB.
     fn unmap(&mut self, vaddr: VAddr) -> Result<TlbFlushHandle, KError>;
 
-    // Returns an iterator of all currently mapped memory regions.
-    //fn mappings()
+    /// Returns the first mapping (base, frame and rights) that starts at or
+    /// above `vaddr`.
+    ///
+    /// Can be used to walk all mapped regions, address spaces that don't
+    /// keep track of their mappings return `None`.
+    fn next_mapping(&self, _vaddr: VAddr) -> Option<(VAddr, Frame, MapAction)> {
+        None
+    }
+
+    /// Returns the first page (base, physical address, page size and rights)
+    /// that maps memory at or above `vaddr`, the page can start below `vaddr`
+    /// if it contains it.
+    ///
+    /// Address spaces without page-tables report each mapping as one page.
+    fn next_page(&self, vaddr: VAddr) -> Option<(VAddr, PAddr, usize, MapAction)> {
+        self.next_mapping(vaddr)
+            .map(|(base, frame, rights)| (base, frame.base, frame.size(), rights))
+    }
+}
+
+/// Consecutive pages of the same size and rights that map physically
+/// contiguous memory (see [`AddressSpace::next_page`]).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MappedRange {
+    pub vaddr: VAddr,
+    pub paddr: PAddr,
+    /// Size of the range in bytes.
+    pub size: usize,
+    pub page_size: usize,
+    pub rights: MapAction,
 }
 
 /// Mapping rights to give to address translation.
@@ -192,6 +222,10 @@ pub enum MapAction {
     ReadWriteUser,
     /// Map region read-write, disable page-cache for IO regions.
     ReadWriteUserNoCache,
+    /// Map region read-write, write-through caching.
+    ReadWriteUserWriteThrough,
+    /// Map region read-write, write-combining (e.g., for frame buffers).
+    ReadWriteUserWriteCombine,
     /// Map region read-write for kernel.
     ReadWriteKernel,
     /// Map region read-executable.
@@ -205,6 +239,54 @@ pub enum MapAction {
 }
 
 impl MapAction {
+    /// Is the region accessible from user-space?
+    pub fn is_user(&self) -> bool {
+        use MapAction::*;
+        matches!(
+            self,
+            ReadUser
+                | ReadWriteUser
+                | ReadWriteUserNoCache
+                | ReadWriteUserWriteThrough
+                | ReadWriteUserWriteCombine
+                | ReadExecuteUser
+                | ReadWriteExecuteUser
+        )
+    }
+
+    /// Is the region writable?
+    pub fn is_writable(&self) -> bool {
+        use MapAction::*;
+        matches!(
+            self,
+            ReadWriteUser
+                | ReadWriteUserNoCache
+                | ReadWriteUserWriteThrough
+                | ReadWriteUserWriteCombine
+                | ReadWriteKernel
+                | ReadWriteExecuteUser
+                | ReadWriteExecuteKernel
+        )
+    }
+
+    /// Is the region cached write-back (the default)?
+    pub fn is_write_back(&self) -> bool {
+        use MapAction::*;
+        !matches!(
+            self,
+            ReadWriteUserNoCache | ReadWriteUserWriteThrough | ReadWriteUserWriteCombine
+        )
+    }
+
+    /// Is the region executable?
+    pub fn is_executable(&self) -> bool {
+        use MapAction::*;
+        matches!(
+            self,
+            ReadExecuteUser | ReadExecuteKernel | ReadWriteExecuteUser | ReadWriteExecuteKernel
+        )
+    }
+
     /// Transform MapAction into rights for 1 GiB page.
     pub fn to_pdpt_rights(self) -> PDPTFlags {
         use MapAction::*;
@@ -213,7 +295,15 @@ impl MapAction {
             ReadUser => PDPTFlags::XD | PDPTFlags::US,
             ReadKernel => PDPTFlags::XD,
             ReadWriteUser => PDPTFlags::RW | PDPTFlags::XD | PDPTFlags::US,
-            ReadWriteUserNoCache => PDPTFlags::RW | PDPTFlags::XD | PDPTFlags::US,
+            ReadWriteUserNoCache => {
+                PDPTFlags::RW | PDPTFlags::XD | PDPTFlags::US | PDPTFlags::PCD | PDPTFlags::PWT
+            }
+            ReadWriteUserWriteThrough => {
+                PDPTFlags::RW | PDPTFlags::XD | PDPTFlags::US | PDPTFlags::PWT
+            }
+            ReadWriteUserWriteCombine => {
+                PDPTFlags::RW | PDPTFlags::XD | PDPTFlags::US | PDPTFlags::PCD
+            }
             ReadWriteKernel => PDPTFlags::RW | PDPTFlags::XD,
             ReadExecuteUser => PDPTFlags::US,
             ReadExecuteKernel => PDPTFlags::empty(),
@@ -230,7 +320,11 @@ impl MapAction {
             ReadUser => PDFlags::XD | PDFlags::US,
             ReadKernel => PDFlags::XD,
             ReadWriteUser => PDFlags::RW | PDFlags::XD | PDFlags::US,
-            ReadWriteUserNoCache => PDFlags::RW | PDFlags::XD | PDFlags::US,
+            ReadWriteUserNoCache => {
+                PDFlags::RW | PDFlags::XD | PDFlags::US | PDFlags::PCD | PDFlags::PWT
+            }
+            ReadWriteUserWriteThrough => PDFlags::RW | PDFlags::XD | PDFlags::US | PDFlags::PWT,
+            ReadWriteUserWriteCombine => PDFlags::RW | PDFlags::XD | PDFlags::US | PDFlags::PCD,
             ReadWriteKernel => PDFlags::RW | PDFlags::XD,
             ReadExecuteUser => PDFlags::US,
             ReadExecuteKernel => PDFlags::empty(),
@@ -247,7 +341,11 @@ impl MapAction {
             ReadUser => PTFlags::XD | PTFlags::US,
             ReadKernel => PTFlags::XD,
             ReadWriteUser => PTFlags::RW | PTFlags::XD | PTFlags::US,
-            ReadWriteUserNoCache => PTFlags::RW | PTFlags::XD | PTFlags::US,
+            ReadWriteUserNoCache => {
+                PTFlags::RW | PTFlags::XD | PTFlags::US | PTFlags::PCD | PTFlags::PWT
+            }
+            ReadWriteUserWriteThrough => PTFlags::RW | PTFlags::XD | PTFlags::US | PTFlags::PWT,
+            ReadWriteUserWriteCombine => PTFlags::RW | PTFlags::XD | PTFlags::US | PTFlags::PCD,
             ReadWriteKernel => PTFlags::RW | PTFlags::XD,
             ReadExecuteUser => PTFlags::US,
             ReadExecuteKernel => PTFlags::empty(),
@@ -257,11 +355,67 @@ impl MapAction {
     }
 }
 
+impl TryFrom<MapFlags> for MapAction {
+    type Error = KError;
+
+    /// The rights of a mapping a process asked for.
+    ///
+    /// User mappings are always readable, only read-write mappings can have
+    /// a caching attribute other than write-back.
+    fn try_from(flags: MapFlags) -> Result<MapAction, KError> {
+        use MapAction::*;
+        let rw = MapFlags::READ_WRITE_USER;
+
+        if flags == MapFlags::READ | MapFlags::USER {
+            Ok(ReadUser)
+        } else if flags == rw {
+            Ok(ReadWriteUser)
+        } else if flags == rw | MapFlags::NOCACHE {
+            Ok(ReadWriteUserNoCache)
+        } else if flags == rw | MapFlags::WRITE_THROUGH {
+            Ok(ReadWriteUserWriteThrough)
+        } else if flags == rw | MapFlags::WRITE_COMBINE {
+            Ok(ReadWriteUserWriteCombine)
+        } else if flags == MapFlags::READ | MapFlags::EXECUTE | MapFlags::USER {
+            Ok(ReadExecuteUser)
+        } else if flags == rw | MapFlags::EXECUTE {
+            Ok(ReadWriteExecuteUser)
+        } else {
+            Err(KError::InvalidFlags)
+        }
+    }
+}
+
+impl From<MapAction> for MapFlags {
+    /// The flags that describe a mapping to user-space (kernel mappings
+    /// lack `USER`).
+    fn from(action: MapAction) -> MapFlags {
+        use MapAction::*;
+        let r = MapFlags::READ;
+        let rw = MapFlags::READ | MapFlags::WRITE;
+        let rx = MapFlags::READ | MapFlags::EXECUTE;
+
+        match action {
+            None => MapFlags::empty(),
+            ReadUser => r | MapFlags::USER,
+            ReadKernel => r,
+            ReadWriteUser => rw | MapFlags::USER,
+            ReadWriteUserNoCache => rw | MapFlags::USER | MapFlags::NOCACHE,
+            ReadWriteUserWriteThrough => rw | MapFlags::USER | MapFlags::WRITE_THROUGH,
+            ReadWriteUserWriteCombine => rw | MapFlags::USER | MapFlags::WRITE_COMBINE,
+            ReadWriteKernel => rw,
+            ReadExecuteUser => rx | MapFlags::USER,
+            ReadExecuteKernel => rx,
+            ReadWriteExecuteUser => rw | MapFlags::EXECUTE | MapFlags::USER,
+            ReadWriteExecuteKernel => rw | MapFlags::EXECUTE,
+        }
+    }
+}
+
 impl From<PTFlags> for MapAction {
     fn from(f: PTFlags) -> MapAction {
         use MapAction::*;
-        let irrelevant_bits: PTFlags =
-            PTFlags::PWT | PTFlags::A | PTFlags::D | PTFlags::G | PTFlags::PWT;
+        let irrelevant_bits: PTFlags = PTFlags::A | PTFlags::D | PTFlags::G;
 
         let mut cleaned = f;
         cleaned.remove(irrelevant_bits);
@@ -271,8 +425,14 @@ impl From<PTFlags> for MapAction {
             MapAction::ReadUser
         } else if cleaned == PTFlags::XD | PTFlags::P {
             MapAction::ReadKernel
-        } else if cleaned == PTFlags::RW | PTFlags::XD | PTFlags::US | PTFlags::P | PTFlags::PCD {
+        } else if cleaned
+            == PTFlags::RW | PTFlags::XD | PTFlags::US | PTFlags::P | PTFlags::PCD | PTFlags::PWT
+        {
             ReadWriteUserNoCache
+        } else if cleaned == PTFlags::RW | PTFlags::XD | PTFlags::US | PTFlags::P | PTFlags::PWT {
+            ReadWriteUserWriteThrough
+        } else if cleaned == PTFlags::RW | PTFlags::XD | PTFlags::US | PTFlags::P | PTFlags::PCD {
+            ReadWriteUserWriteCombine
         } else if cleaned == PTFlags::RW | PTFlags::XD | PTFlags::US | PTFlags::P {
             ReadWriteUser
         } else if cleaned == PTFlags::RW | PTFlags::XD | PTFlags::P {
@@ -295,8 +455,7 @@ impl From<PDFlags> for MapAction {
     fn from(f: PDFlags) -> MapAction {
         use MapAction::*;
 
-        let irrelevant_bits =
-            PDFlags::PWT | PDFlags::A | PDFlags::D | PDFlags::PS | PDFlags::G | PDFlags::PAT;
+        let irrelevant_bits = PDFlags::A | PDFlags::D | PDFlags::PS | PDFlags::G | PDFlags::PAT;
 
         let mut cleaned = f;
         cleaned.remove(irrelevant_bits);
@@ -306,8 +465,14 @@ impl From<PDFlags> for MapAction {
             MapAction::ReadUser
         } else if cleaned == PDFlags::XD | PDFlags::P {
             MapAction::ReadKernel
-        } else if cleaned == PDFlags::RW | PDFlags::XD | PDFlags::US | PDFlags::P | PDFlags::PCD {
+        } else if cleaned
+            == PDFlags::RW | PDFlags::XD | PDFlags::US | PDFlags::P | PDFlags::PCD | PDFlags::PWT
+        {
             ReadWriteUserNoCache
+        } else if cleaned == PDFlags::RW | PDFlags::XD | PDFlags::US | PDFlags::P | PDFlags::PWT {
+            ReadWriteUserWriteThrough
+        } else if cleaned == PDFlags::RW | PDFlags::XD | PDFlags::US | PDFlags::P | PDFlags::PCD {
+            ReadWriteUserWriteCombine
         } else if cleaned == PDFlags::RW | PDFlags::XD | PDFlags::US | PDFlags::P {
             ReadWriteUser
         } else if cleaned == PDFlags::RW | PDFlags::XD | PDFlags::P {
@@ -330,12 +495,8 @@ impl From<PDPTFlags> for MapAction {
     fn from(f: PDPTFlags) -> MapAction {
         use MapAction::*;
 
-        let irrelevant_bits: PDPTFlags = PDPTFlags::PWT
-            | PDPTFlags::A
-            | PDPTFlags::D
-            | PDPTFlags::PS
-            | PDPTFlags::G
-            | PDPTFlags::PAT;
+        let irrelevant_bits: PDPTFlags =
+            PDPTFlags::A | PDPTFlags::D | PDPTFlags::PS | PDPTFlags::G | PDPTFlags::PAT;
 
         let mut cleaned = f;
         cleaned.remove(irrelevant_bits);
@@ -346,9 +507,22 @@ impl From<PDPTFlags> for MapAction {
         } else if cleaned == PDPTFlags::XD | PDPTFlags::P {
             MapAction::ReadKernel
         } else if cleaned
-            == PDPTFlags::RW | PDPTFlags::XD | PDPTFlags::US | PDPTFlags::P | PDPTFlags::PCD
+            == PDPTFlags::RW
+                | PDPTFlags::XD
+                | PDPTFlags::US
+                | PDPTFlags::P
+                | PDPTFlags::PCD
+                | PDPTFlags::PWT
         {
             ReadWriteUserNoCache
+        } else if cleaned
+            == PDPTFlags::RW | PDPTFlags::XD | PDPTFlags::US | PDPTFlags::P | PDPTFlags::PWT
+        {
+            ReadWriteUserWriteThrough
+        } else if cleaned
+            == PDPTFlags::RW | PDPTFlags::XD | PDPTFlags::US | PDPTFlags::P | PDPTFlags::PCD
+        {
+            ReadWriteUserWriteCombine
         } else if cleaned == PDPTFlags::RW | PDPTFlags::XD | PDPTFlags::US | PDPTFlags::P {
             ReadWriteUser
         } else if cleaned == PDPTFlags::RW | PDPTFlags::XD | PDPTFlags::P {
@@ -376,6 +550,8 @@ impl fmt::Display for MapAction {
             ReadKernel => write!(f, "kR--"),
             ReadWriteUser => write!(f, "uRW-"),
             ReadWriteUserNoCache => write!(f, "uRW-IO"),
+            ReadWriteUserWriteThrough => write!(f, "uRW-WT"),
+            ReadWriteUserWriteCombine => write!(f, "uRW-WC"),
             ReadWriteKernel => write!(f, "kRW-"),
             ReadExecuteUser => write!(f, "uR-X"),
             ReadExecuteKernel => write!(f, "kR-X"),
@@ -0,0 +1,335 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A management console to inspect machines without a serial console.
+//!
+//! The kernel serves it on TCP port [`PORT`] once its network interface is
+//! up (see `arch::remote`) if the command line has a pre-shared key
+//! (`mgmtkey=<key>`). A session starts with `auth <key>`, the connection is
+//! closed after a wrong key. Every further line is one of the [`COMMANDS`].
+//!
+//! After [`MAX_FAILURES`] wrong keys in a row the console turns clients away
+//! for [`LOCKOUT`], doubled with every further wrong key up to
+//! [`MAX_LOCKOUT`] (see [`Throttle`]), so keys can't be guessed at the speed
+//! of the network.
+//!
+//! Nothing is encrypted: whoever sees the traffic sees the key and the
+//! output, so the console belongs on a trusted management network.
+//! Being trusted, `vspace` shows physical addresses that processes only get
+//! to see with the `inspect` capability.
+
+// Only the x86-64 kernel network stack serves the console
+#![cfg_attr(
+    any(not(target_os = "none"), not(feature = "smoltcp")),
+    allow(dead_code)
+)]
+
+use alloc::string::String;
+use core::fmt::Write;
+use core::time::Duration;
+
+use kpi::system::{DeviceDriver, DeviceLocation};
+use log::{info, warn};
+
+use crate::error::KError;
+use crate::memory::VAddr;
+use crate::process::{Pid, MAX_PROCESSES};
+
+/// The processes the kernel runs.
+#[cfg(target_os = "none")]
+type ArchProcess = crate::arch::process::Ring3Process;
+#[cfg(not(target_os = "none"))]
+type ArchProcess = crate::arch::process::UnixProcess;
+
+/// TCP port of the console.
+pub const PORT: u16 = 2323;
+
+/// Shortest key we accept.
+pub const MIN_KEY_LEN: usize = 8;
+
+/// Wrong keys in a row before the console turns clients away.
+pub const MAX_FAILURES: u32 = 3;
+
+/// How long the console turns clients away after [`MAX_FAILURES`] wrong keys.
+pub const LOCKOUT: Duration = Duration::from_secs(1);
+
+/// Longest the console turns clients away.
+pub const MAX_LOCKOUT: Duration = Duration::from_secs(300);
+
+/// What a client gets when it connects.
+pub const GREETING: &str = "nrk management console, authenticate with `auth <key>`\n";
+
+/// The commands (and what they print).
+pub const COMMANDS: &[(&str, &str)] = &[
+    ("help", "this list"),
+    ("uptime", "time since boot and the wall-clock time"),
+    ("ps", "live processes"),
+    ("devices", "devices and their drivers"),
+    ("vspace <pid>", "mapped ranges of a process"),
+    ("quit", "ends the session"),
+];
+
+/// Compares `a` and `b` in time that only depends on their lengths (so the
+/// time it takes doesn't tell how much of a guessed key is right).
+fn same_key(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.bytes()
+        .zip(b.bytes())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/// Counts wrong keys across sessions and tells when clients may try again.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    /// Wrong keys since the last right one.
+    failures: u32,
+    /// Time since boot until which clients are turned away.
+    until: Duration,
+}
+
+impl Throttle {
+    pub const fn new() -> Throttle {
+        Throttle {
+            failures: 0,
+            until: Duration::from_secs(0),
+        }
+    }
+
+    /// May a client authenticate at `now` (time since boot)?
+    pub fn allows(&self, now: Duration) -> bool {
+        now >= self.until
+    }
+
+    /// A client sent a wrong key at `now`.
+    pub fn failed(&mut self, now: Duration) {
+        self.failures = self.failures.saturating_add(1);
+        if self.failures >= MAX_FAILURES {
+            let doublings = core::cmp::min(self.failures - MAX_FAILURES, 16);
+            let lockout = core::cmp::min(LOCKOUT * (1 << doublings), MAX_LOCKOUT);
+            warn!(
+                "Management console: {} wrong keys, locked for {} s",
+                self.failures,
+                lockout.as_secs()
+            );
+            self.until = now + lockout;
+        }
+    }
+
+    /// A client sent the right key.
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+    }
+}
+
+/// A connection to the console.
+pub struct Session {
+    key: &'static str,
+    authenticated: bool,
+}
+
+impl Session {
+    pub fn new(key: &'static str) -> Session {
+        Session {
+            key,
+            authenticated: false,
+        }
+    }
+
+    /// Did the client send the right key?
+    pub fn authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    /// Handles the next `line` of the client and appends the answer to
+    /// `out`, returns false if the connection should be closed.
+    pub fn input(&mut self, line: &str, out: &mut String) -> bool {
+        let line = line.trim();
+        if !self.authenticated {
+            let key = line.strip_prefix("auth ").map(str::trim);
+            if !key.map_or(false, |key| same_key(key, self.key)) {
+                warn!("Management console: authentication failed");
+                out.push_str("denied\n");
+                return false;
+            }
+            info!("Management console: session authenticated");
+            self.authenticated = true;
+            out.push_str("ok\n");
+            return true;
+        }
+
+        match line {
+            "" => {}
+            "quit" => return false,
+            "help" => {
+                for (command, help) in COMMANDS {
+                    let _r = writeln!(out, "{:<12} {}", command, help);
+                }
+            }
+            command => {
+                if let Err(e) = run(command, out) {
+                    let _r = writeln!(out, "error: {}", e);
+                }
+            }
+        }
+        true
+    }
+}
+
+/// Runs one of the commands that inspect the kernel.
+fn run(command: &str, out: &mut String) -> Result<(), KError> {
+    match command {
+        "uptime" => {
+            let (up, wall) = (rawtime::duration_since_boot(), crate::clock::now());
+            let _r = writeln!(
+                out,
+                "up {}.{:03} s, Unix time {}.{:03}",
+                up.as_secs(),
+                up.subsec_millis(),
+                wall.as_secs(),
+                wall.subsec_millis()
+            );
+        }
+        "ps" => {
+            let _r = writeln!(out, " PID PARENT ARGS");
+            for (pid, _generation) in crate::nr::KernelNode::processes()? {
+                let process = crate::nr::KernelNode::process(pid)?;
+                let _r = match process.parent {
+                    Some(parent) => writeln!(out, "{:>4} {:>6} {}", pid, parent, process.args),
+                    None => writeln!(out, "{:>4} {:>6} {}", pid, "-", process.args),
+                };
+            }
+        }
+        "devices" => {
+            for device in crate::devices::list()? {
+                let _r = write!(out, "{:>3} {:<12} ", device.id, device.name);
+                let _r = match device.location {
+                    DeviceLocation::Pci {
+                        bus,
+                        device,
+                        function,
+                    } => write!(out, "pci {:02x}:{:02x}.{}", bus, device, function),
+                    DeviceLocation::Acpi { table } => write!(out, "acpi {}", table),
+                    DeviceLocation::Platform => write!(out, "platform"),
+                };
+                let _r = match device.driver {
+                    DeviceDriver::None => writeln!(out),
+                    DeviceDriver::Kernel(driver) => writeln!(out, " driven by {}", driver),
+                    DeviceDriver::Process(pid) => writeln!(out, " claimed by process {}", pid),
+                };
+            }
+        }
+        _ if command.split_whitespace().next() == Some("vspace") => {
+            let pid = match command["vspace".len()..].trim().parse::<Pid>() {
+                Ok(pid) if pid < MAX_PROCESSES => pid,
+                _ => {
+                    let _r = writeln!(out, "usage: vspace <pid>");
+                    return Ok(());
+                }
+            };
+            crate::nr::KernelNode::process(pid)?;
+
+            let mut cursor = VAddr::zero();
+            while let Some(range) =
+                crate::nrproc::NrProcess::<ArchProcess>::next_range(pid, cursor)?
+            {
+                cursor = range.vaddr + range.size;
+                let _r = writeln!(
+                    out,
+                    "{:#x} - {:#x} {:#x} -> {:#x} ({} KiB pages) {}",
+                    range.vaddr,
+                    range.vaddr + range.size,
+                    range.size,
+                    range.paddr,
+                    range.page_size / 1024,
+                    range.rights
+                );
+            }
+        }
+        _ => {
+            let _r = writeln!(out, "unknown command `{}`, try `help`", command);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authenticate() {
+        let mut out = String::new();
+        let mut session = Session::new("secret-key");
+        assert!(!session.input("help", &mut out));
+        assert_eq!(out, "denied\n");
+
+        let mut out = String::new();
+        let mut session = Session::new("secret-key");
+        assert!(!session.input("auth secret-kez", &mut out));
+        let mut session = Session::new("secret-key");
+        assert!(!session.input("auth secret-key2", &mut out));
+
+        let mut out = String::new();
+        let mut session = Session::new("secret-key");
+        assert!(session.input("auth secret-key\r", &mut out));
+        assert_eq!(out, "ok\n");
+    }
+
+    #[test]
+    fn throttle() {
+        let mut throttle = Throttle::new();
+        let now = Duration::from_secs(10);
+        for _i in 1..MAX_FAILURES {
+            throttle.failed(now);
+            assert!(throttle.allows(now));
+        }
+
+        throttle.failed(now);
+        assert!(!throttle.allows(now));
+        assert!(!throttle.allows(now + LOCKOUT - Duration::from_millis(1)));
+        assert!(throttle.allows(now + LOCKOUT));
+
+        let now = now + LOCKOUT;
+        throttle.failed(now);
+        assert!(!throttle.allows(now + LOCKOUT));
+        assert!(throttle.allows(now + LOCKOUT * 2));
+
+        for _i in 0..64 {
+            throttle.failed(now);
+        }
+        assert!(!throttle.allows(now + MAX_LOCKOUT - Duration::from_millis(1)));
+        assert!(throttle.allows(now + MAX_LOCKOUT));
+
+        throttle.succeeded();
+        throttle.failed(now + MAX_LOCKOUT);
+        assert!(throttle.allows(now + MAX_LOCKOUT));
+    }
+
+    #[test]
+    fn commands() {
+        let mut session = Session::new("secret-key");
+        let mut out = String::new();
+        assert!(session.input("auth secret-key", &mut out));
+
+        let mut out = String::new();
+        assert!(session.input("help", &mut out));
+        assert_eq!(out.lines().count(), COMMANDS.len());
+        assert!(out.starts_with("help "));
+
+        let mut out = String::new();
+        assert!(session.input("reboot", &mut out));
+        assert_eq!(out, "unknown command `reboot`, try `help`\n");
+
+        let mut out = String::new();
+        assert!(session.input("vspace init", &mut out));
+        assert_eq!(out, "usage: vspace <pid>\n");
+
+        let mut out = String::new();
+        assert!(session.input("", &mut out));
+        assert!(!session.input("quit", &mut out));
+        assert!(out.is_empty());
+    }
+}
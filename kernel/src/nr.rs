@@ -4,26 +4,56 @@
 use crate::prelude::*;
 use core::fmt::Debug;
 
+use arrayvec::ArrayVec;
 use hashbrown::HashMap;
+use kpi::process::{AffinityMask, Capabilities, OomPriority};
 use log::{error, trace};
 use node_replication::Dispatch;
 
 use crate::arch::MAX_CORES;
 use crate::error::KError;
 use crate::memory::VAddr;
-use crate::process::{Pid, MAX_PROCESSES};
+use crate::nrstats::TimedReplica;
+use crate::process::{Eid, Pid, MAX_PROCESSES};
+use crate::scheduler::policy;
 
 #[derive(PartialEq, Clone, Copy, Debug)]
 pub enum ReadOps {
     CurrentProcess(atopology::GlobalThreadId),
+    /// The cores that are assigned to a process.
+    AllocatedCores,
+    /// The generation of a (live) process.
+    ProcessGeneration(Pid),
+    /// All live processes.
+    Processes,
+    /// The capabilities of a (live) process.
+    Capabilities(Pid),
+    /// What the kernel knows about a process.
+    Process(Pid),
+    /// Exited processes nobody waits for (the kernel started them or their
+    /// parent is gone) that aren't being destroyed yet.
+    Orphans,
 }
 
 #[derive(PartialEq, Clone, Debug)]
 pub enum Op {
-    /// Allocate a new process (Pid)
-    AllocatePid,
-    /// Destroy a process
+    /// Allocate a new process (Pid), one of the first half of the free Pids
+    /// after the last allocated one, picked by the (random) hint.
+    AllocatePid(Pid),
+    /// Claim an exited process for tearing it down (only the first claim
+    /// succeeds).
+    Destroy(Pid),
+    /// Forget a destroyed process, its children become orphans.
     FreePid(Pid),
+    /// Give a process additional capabilities
+    GrantCapabilities(Pid, Capabilities),
+    /// Record that a process was spawned by the (parent) process or the
+    /// kernel (`None`), with the given arguments.
+    Spawned(Pid, Option<Pid>, &'static str),
+    /// A process exited with the given code, it loses all its cores.
+    Exit(Pid, u64),
+    /// Set how important a process is to the OOM killer.
+    SetOomPriority(Pid, OomPriority),
     /// Assign a core to a process
     SchedAllocateCore(
         Pid,
@@ -31,31 +61,88 @@ pub enum Op {
         Option<atopology::GlobalThreadId>,
         VAddr,
     ),
+    /// Assign up to `usize` cores from the mask to a process (if `bool` is
+    /// set, either all or none of the cores are assigned).
+    SchedAllocateCores(Pid, AffinityMask, usize, bool, VAddr),
+    /// Move the executor `Eid` of a process from one core to another (free)
+    /// core.
+    SchedMigrateCore(
+        Pid,
+        atopology::GlobalThreadId,
+        atopology::GlobalThreadId,
+        Eid,
+    ),
 }
 
 #[derive(Debug, Clone)]
 pub enum NodeResult {
     PidAllocated(Pid),
     PidReturned,
+    Destroying,
+    Generation(u64),
+    Processes(ArrayVec<(Pid, u64), MAX_PROCESSES>),
+    Capabilities(Capabilities),
+    CapabilitiesGranted,
     CoreInfo(CoreInfo),
     CoreAllocated(atopology::GlobalThreadId),
+    CoresAllocated(AffinityMask),
+    CoreMigrated(atopology::GlobalThreadId),
+    Process(ProcessEntry),
+    Spawned,
+    OomPrioritySet,
+    /// The cores a process had when it exited.
+    Exited(AffinityMask),
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct CoreInfo {
     pub pid: Pid,
     pub entry_point: VAddr,
+    /// Executor to resume (instead of starting a new one at `entry_point`)
+    /// if the core got it from another core.
+    pub resume: Option<Eid>,
+}
+
+/// What the kernel keeps track of for every live process.
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessEntry {
+    /// Pids are reused once a process is gone, the generation tells apart
+    /// different processes that had the same pid.
+    pub generation: u64,
+    /// Privileged operations the process may use.
+    pub capabilities: Capabilities,
+    /// The process that spawned it (`None` for processes the kernel started).
+    pub parent: Option<Pid>,
+    /// Arguments it was spawned with.
+    pub args: &'static str,
+    /// Exit code once the process exited.
+    pub exit_code: Option<u64>,
+    /// How important it is to the OOM killer.
+    pub oom_priority: OomPriority,
+    /// Somebody tears the (exited) process down.
+    pub destroying: bool,
 }
 
 pub struct KernelNode {
-    process_map: HashMap<Pid, ()>,
+    /// Live processes.
+    process_map: HashMap<Pid, ProcessEntry>,
+    /// Generation of the next process.
+    generation: u64,
+    /// Pid of the last allocated process, the next allocation starts looking
+    /// for a free pid after it (so pids aren't reused immediately).
+    last_pid: Pid,
+    /// Core the scheduling policy handed out last (see `SchedPolicy::place`).
+    last_core: atopology::GlobalThreadId,
     scheduler_map: HashMap<atopology::GlobalThreadId, CoreInfo>,
 }
 
 impl Default for KernelNode {
     fn default() -> KernelNode {
         KernelNode {
-            process_map: HashMap::new(),   // with_capacity(MAX_PROCESSES),
+            process_map: HashMap::new(), // with_capacity(MAX_PROCESSES),
+            generation: 0,
+            last_pid: MAX_PROCESSES - 1,
+            last_core: MAX_CORES - 1,
             scheduler_map: HashMap::new(), // with_capacity(MAX_CORES),
         }
     }
@@ -63,13 +150,9 @@ impl Default for KernelNode {
 
 impl KernelNode {
     pub fn synchronize() -> Result<(), KError> {
-        let kcb = super::kcb::get_kcb();
-        kcb.replica
-            .as_ref()
-            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
-                replica.sync(*token);
-                Ok(())
-            })
+        let (replica, token) = super::kcb::per_core().replica()?;
+        replica.sync(token);
+        Ok(())
     }
 
     pub fn allocate_core_to_process(
@@ -78,19 +161,229 @@ impl KernelNode {
         affinity: Option<atopology::NodeId>,
         gtid: Option<atopology::GlobalThreadId>,
     ) -> Result<atopology::GlobalThreadId, KError> {
-        let kcb = super::kcb::get_kcb();
-        kcb.replica
-            .as_ref()
-            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
-                let op = Op::SchedAllocateCore(pid, affinity, gtid, entry_point);
-                let response = replica.execute_mut(op, *token);
+        let (replica, token) = super::kcb::per_core().replica()?;
+        let op = Op::SchedAllocateCore(pid, affinity, gtid, entry_point);
+        let response = replica.execute_mut_timed(op, token);
 
-                match response {
-                    Ok(NodeResult::CoreAllocated(rgtid)) => Ok(rgtid),
-                    Err(e) => Err(e),
-                    Ok(_) => unreachable!("Got unexpected response"),
-                }
-            })
+        match response {
+            Ok(NodeResult::CoreAllocated(rgtid)) => Ok(rgtid),
+            Err(e) => Err(e),
+            Ok(_) => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Allocate up to `count` cores (out of `mask`) to the process.
+    ///
+    /// The allocation happens as a single operation on the log: For `gang`
+    /// requests this means every core will observe the assignment at the
+    /// same log position and start dispatching the process together.
+    pub fn allocate_cores_to_process(
+        pid: Pid,
+        entry_point: VAddr,
+        mask: AffinityMask,
+        count: usize,
+        gang: bool,
+    ) -> Result<AffinityMask, KError> {
+        let (replica, token) = super::kcb::per_core().replica()?;
+        let op = Op::SchedAllocateCores(pid, mask, count, gang, entry_point);
+        let response = replica.execute_mut_timed(op, token);
+
+        match response {
+            Ok(NodeResult::CoresAllocated(allocated)) => Ok(allocated),
+            Err(e) => Err(e),
+            Ok(_) => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Hands the executor `eid` of `pid` over from core `from` to core `to`.
+    ///
+    /// `to` has to be unused, it picks up the executor the next time it
+    /// enters the scheduler.
+    pub fn migrate_core(
+        pid: Pid,
+        from: atopology::GlobalThreadId,
+        to: atopology::GlobalThreadId,
+        eid: Eid,
+    ) -> Result<atopology::GlobalThreadId, KError> {
+        let (replica, token) = super::kcb::per_core().replica()?;
+        let op = Op::SchedMigrateCore(pid, from, to, eid);
+        let response = replica.execute_mut_timed(op, token);
+
+        match response {
+            Ok(NodeResult::CoreMigrated(gtid)) => Ok(gtid),
+            Err(e) => Err(e),
+            Ok(_) => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Returns the generation of the live process `pid`.
+    ///
+    /// Together with the pid it identifies a process over the lifetime of the
+    /// system.
+    pub fn process_generation(pid: Pid) -> Result<u64, KError> {
+        let (replica, token) = super::kcb::per_core().replica()?;
+        let response = replica.execute(ReadOps::ProcessGeneration(pid), token);
+
+        match response {
+            Ok(NodeResult::Generation(generation)) => Ok(generation),
+            Err(e) => Err(e),
+            Ok(_) => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Is the process `pid` of `generation` still alive?
+    pub fn is_alive(pid: Pid, generation: u64) -> bool {
+        KernelNode::process(pid).map_or(false, |entry| {
+            entry.generation == generation && entry.exit_code.is_none()
+        })
+    }
+
+    /// Adds `capabilities` to the ones `pid` already has.
+    pub fn grant_capabilities(pid: Pid, capabilities: Capabilities) -> Result<(), KError> {
+        let (replica, token) = super::kcb::per_core().replica()?;
+        let response = replica.execute_mut_timed(Op::GrantCapabilities(pid, capabilities), token);
+
+        match response {
+            Ok(NodeResult::CapabilitiesGranted) => Ok(()),
+            Err(e) => Err(e),
+            Ok(_) => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Returns the capabilities of the live process `pid`.
+    pub fn capabilities(pid: Pid) -> Result<Capabilities, KError> {
+        let (replica, token) = super::kcb::per_core().replica()?;
+        let response = replica.execute(ReadOps::Capabilities(pid), token);
+
+        match response {
+            Ok(NodeResult::Capabilities(capabilities)) => Ok(capabilities),
+            Err(e) => Err(e),
+            Ok(_) => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Returns what the kernel knows about process `pid`.
+    pub fn process(pid: Pid) -> Result<ProcessEntry, KError> {
+        let (replica, token) = super::kcb::per_core().replica()?;
+        let response = replica.execute(ReadOps::Process(pid), token);
+
+        match response {
+            Ok(NodeResult::Process(entry)) => Ok(entry),
+            Err(e) => Err(e),
+            Ok(_) => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Records that `pid` was spawned by `parent` (`None` for the kernel)
+    /// with `args`.
+    pub fn spawned(pid: Pid, parent: Option<Pid>, args: &'static str) -> Result<(), KError> {
+        let (replica, token) = super::kcb::per_core().replica()?;
+        let response = replica.execute_mut_timed(Op::Spawned(pid, parent, args), token);
+
+        match response {
+            Ok(NodeResult::Spawned) => Ok(()),
+            Err(e) => Err(e),
+            Ok(_) => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Marks `pid` as exited with `code` and takes away its cores.
+    ///
+    /// Returns the cores it had, the caller has to make them drop the
+    /// executors they still run.
+    pub fn exit(pid: Pid, code: u64) -> Result<AffinityMask, KError> {
+        let (replica, token) = super::kcb::per_core().replica()?;
+        let response = replica.execute_mut_timed(Op::Exit(pid, code), token);
+
+        match response {
+            Ok(NodeResult::Exited(released)) => Ok(released),
+            Err(e) => Err(e),
+            Ok(_) => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Sets how important `pid` is to the OOM killer.
+    pub fn set_oom_priority(pid: Pid, priority: OomPriority) -> Result<(), KError> {
+        let (replica, token) = super::kcb::per_core().replica()?;
+        let response = replica.execute_mut_timed(Op::SetOomPriority(pid, priority), token);
+
+        match response {
+            Ok(NodeResult::OomPrioritySet) => Ok(()),
+            Err(e) => Err(e),
+            Ok(_) => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Returns the pid and generation of all live processes (ordered by pid).
+    pub fn processes() -> Result<ArrayVec<(Pid, u64), MAX_PROCESSES>, KError> {
+        let (replica, token) = super::kcb::per_core().replica()?;
+        let response = replica.execute(ReadOps::Processes, token);
+
+        match response {
+            Ok(NodeResult::Processes(processes)) => Ok(processes),
+            Err(e) => Err(e),
+            Ok(_) => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Claims the exited process `pid` for tearing it down, fails if it
+    /// still runs or somebody else claimed it.
+    pub fn destroy(pid: Pid) -> Result<(), KError> {
+        let (replica, token) = super::kcb::per_core().replica()?;
+        let response = replica.execute_mut_timed(Op::Destroy(pid), token);
+
+        match response {
+            Ok(NodeResult::Destroying) => Ok(()),
+            Err(e) => Err(e),
+            Ok(_) => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Forgets the destroyed process `pid`, its pid can be reused.
+    pub fn free_pid(pid: Pid) -> Result<(), KError> {
+        let (replica, token) = super::kcb::per_core().replica()?;
+        let response = replica.execute_mut_timed(Op::FreePid(pid), token);
+
+        match response {
+            Ok(NodeResult::PidReturned) => Ok(()),
+            Err(e) => Err(e),
+            Ok(_) => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Returns the pid and generation of the exited processes nobody waits
+    /// for (ordered by pid).
+    pub fn orphans() -> Result<ArrayVec<(Pid, u64), MAX_PROCESSES>, KError> {
+        let (replica, token) = super::kcb::per_core().replica()?;
+        let response = replica.execute(ReadOps::Orphans, token);
+
+        match response {
+            Ok(NodeResult::Processes(orphans)) => Ok(orphans),
+            Err(e) => Err(e),
+            Ok(_) => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Returns the cores that currently run (or are about to run) a process.
+    pub fn allocated_cores() -> Result<AffinityMask, KError> {
+        let (replica, token) = super::kcb::per_core().replica()?;
+        let response = replica.execute(ReadOps::AllocatedCores, token);
+
+        match response {
+            Ok(NodeResult::CoresAllocated(allocated)) => Ok(allocated),
+            Err(e) => Err(e),
+            Ok(_) => unreachable!("Got unexpected response"),
+        }
+    }
+}
+
+impl KernelNode {
+    /// Fails if `pid` exited (it can't get new cores then).
+    fn check_alive(&self, pid: Pid) -> Result<(), KError> {
+        match self.process_map.get(&pid) {
+            Some(entry) if entry.exit_code.is_some() => Err(KError::NoProcessFoundForPid),
+            _ => Ok(()),
+        }
     }
 }
 
@@ -100,6 +393,7 @@ impl Dispatch for KernelNode {
     type Response = Result<NodeResult, KError>;
 
     fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
+        let _pin = crate::domain::pin();
         match op {
             ReadOps::CurrentProcess(gtid) => {
                 let core_info = self
@@ -108,33 +402,166 @@ impl Dispatch for KernelNode {
                     .ok_or(KError::NoExecutorForCore)?;
                 Ok(NodeResult::CoreInfo(*core_info))
             }
+            ReadOps::ProcessGeneration(pid) => {
+                let entry = self
+                    .process_map
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                Ok(NodeResult::Generation(entry.generation))
+            }
+            ReadOps::Processes => {
+                let mut processes = ArrayVec::new();
+                for (pid, entry) in self.process_map.iter() {
+                    if entry.exit_code.is_none() {
+                        processes.push((*pid, entry.generation));
+                    }
+                }
+                processes.sort_unstable();
+                Ok(NodeResult::Processes(processes))
+            }
+            ReadOps::Capabilities(pid) => {
+                let entry = self
+                    .process_map
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                Ok(NodeResult::Capabilities(entry.capabilities))
+            }
+            ReadOps::Process(pid) => {
+                let entry = self
+                    .process_map
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                Ok(NodeResult::Process(*entry))
+            }
+            ReadOps::Orphans => {
+                let mut orphans = ArrayVec::new();
+                for (pid, entry) in self.process_map.iter() {
+                    if entry.exit_code.is_some() && entry.parent.is_none() && !entry.destroying {
+                        orphans.push((*pid, entry.generation));
+                    }
+                }
+                orphans.sort_unstable();
+                Ok(NodeResult::Processes(orphans))
+            }
+            ReadOps::AllocatedCores => {
+                let mut allocated = AffinityMask::empty();
+                for gtid in self.scheduler_map.keys() {
+                    allocated.set(*gtid);
+                }
+                Ok(NodeResult::CoresAllocated(allocated))
+            }
         }
     }
 
     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+        let _pin = crate::domain::pin();
+        let _applying = crate::nrstats::applying();
+        crate::fault::delay_combine();
+
         match op {
-            Op::AllocatePid => {
+            Op::AllocatePid(hint) => {
+                // The hint is random (we can't draw random numbers here, all
+                // replicas have to pick the same Pid). We only pick from the
+                // first half of the free pids after `last_pid`, so recently
+                // used pids (at the end of the scan) aren't reused right away.
                 // TODO(performance): O(n) scan probably not what we really
                 // want, fine for now, MAX_PROCESSES is tiny
-                for i in 0..MAX_PROCESSES {
-                    if !self.process_map.contains_key(&i) {
-                        self.process_map.try_reserve(1)?;
-                        let r = self.process_map.insert(i, ());
-                        assert!(r.is_none(), "!contains_key");
-                        return Ok(NodeResult::PidAllocated(i));
+                let free = (1..=MAX_PROCESSES)
+                    .map(|i| (self.last_pid + i) % MAX_PROCESSES)
+                    .filter(|pid| !self.process_map.contains_key(pid));
+                let skip = hint % (free.clone().count() / 2 + 1);
+                let pid = free.clone().nth(skip).ok_or(KError::OutOfPids)?;
+
+                self.process_map.try_reserve(1)?;
+                let entry = ProcessEntry {
+                    generation: self.generation,
+                    capabilities: Capabilities::NONE,
+                    parent: None,
+                    args: "",
+                    exit_code: None,
+                    oom_priority: OomPriority::default(),
+                    destroying: false,
+                };
+                let r = self.process_map.insert(pid, entry);
+                assert!(r.is_none(), "!contains_key");
+                self.generation += 1;
+                self.last_pid = pid;
+                Ok(NodeResult::PidAllocated(pid))
+            }
+            // TODO: better impl, what about scheduler_map?
+            Op::GrantCapabilities(pid, capabilities) => {
+                let entry = self
+                    .process_map
+                    .get_mut(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                entry.capabilities |= capabilities;
+                Ok(NodeResult::CapabilitiesGranted)
+            }
+            Op::Spawned(pid, parent, args) => {
+                let entry = self
+                    .process_map
+                    .get_mut(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                entry.parent = parent;
+                entry.args = args;
+                Ok(NodeResult::Spawned)
+            }
+            Op::SetOomPriority(pid, priority) => {
+                let entry = self
+                    .process_map
+                    .get_mut(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                entry.oom_priority = priority;
+                Ok(NodeResult::OomPrioritySet)
+            }
+            Op::Exit(pid, code) => {
+                let entry = self
+                    .process_map
+                    .get_mut(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                // The first exit counts (e.g., if it's killed while exiting)
+                if entry.exit_code.is_none() {
+                    entry.exit_code = Some(code);
+                }
+
+                let mut released = AffinityMask::empty();
+                for (gtid, core_info) in self.scheduler_map.iter() {
+                    if core_info.pid == pid {
+                        released.set(*gtid);
                     }
                 }
-                Err(KError::OutOfPids)
+                for gtid in released.iter() {
+                    self.scheduler_map.remove(&gtid);
+                }
+                Ok(NodeResult::Exited(released))
+            }
+            Op::Destroy(pid) => {
+                let entry = self
+                    .process_map
+                    .get_mut(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+                if entry.exit_code.is_none() || entry.destroying {
+                    return Err(KError::NoProcessFoundForPid);
+                }
+                entry.destroying = true;
+                Ok(NodeResult::Destroying)
             }
-            // TODO: better impl, what about scheduler_map?
             Op::FreePid(pid) => match self.process_map.remove(&pid) {
-                Some(_) => Ok(NodeResult::PidReturned),
+                Some(_) => {
+                    for entry in self.process_map.values_mut() {
+                        if entry.parent == Some(pid) {
+                            entry.parent = None;
+                        }
+                    }
+                    Ok(NodeResult::PidReturned)
+                }
                 None => {
                     error!("Process not found");
                     Err(KError::NoProcessFoundForPid)
                 }
             },
             Op::SchedAllocateCore(pid, _affinity, Some(gtid), entry_point) => {
+                self.check_alive(pid)?;
                 assert!((gtid as usize) < MAX_CORES, "Invalid gtid");
 
                 match self.scheduler_map.get(&gtid) {
@@ -143,16 +570,117 @@ impl Dispatch for KernelNode {
                         trace!("Op::SchedAllocateCore pid={}, gtid={}", pid, gtid);
 
                         self.scheduler_map.try_reserve(1)?;
-                        let r = self
-                            .scheduler_map
-                            .insert(gtid, CoreInfo { pid, entry_point });
+                        let r = self.scheduler_map.insert(
+                            gtid,
+                            CoreInfo {
+                                pid,
+                                entry_point,
+                                resume: None,
+                            },
+                        );
                         assert!(r.is_none(), "get() -> None");
 
                         Ok(NodeResult::CoreAllocated(gtid))
                     }
                 }
             }
-            Op::SchedAllocateCore(_pid, _affinity, _gtid, _entry_point) => unimplemented!(),
+            Op::SchedAllocateCore(pid, affinity, None, entry_point) => {
+                self.check_alive(pid)?;
+                // Let the scheduling policy pick an unused core (on the
+                // requested NUMA node)
+                let cores: ArrayVec<policy::Core, MAX_CORES> = atopology::MACHINE_TOPOLOGY
+                    .threads()
+                    .filter(|t| t.id < MAX_CORES)
+                    .map(|t| policy::Core {
+                        gtid: t.id,
+                        node: t.node_id.unwrap_or(0),
+                        pid: self.scheduler_map.get(&t.id).map(|ci| ci.pid),
+                    })
+                    .collect();
+                let gtid = policy::current()
+                    .place(pid, affinity, &cores, self.last_core)
+                    .ok_or(KError::CoreAlreadyAllocated)?;
+                trace!("Op::SchedAllocateCore pid={}, gtid={}", pid, gtid);
+
+                self.scheduler_map.try_reserve(1)?;
+                self.last_core = gtid;
+                let r = self.scheduler_map.insert(
+                    gtid,
+                    CoreInfo {
+                        pid,
+                        entry_point,
+                        resume: None,
+                    },
+                );
+                assert!(r.is_none(), "contains_key() -> false");
+
+                Ok(NodeResult::CoreAllocated(gtid))
+            }
+            Op::SchedAllocateCores(pid, mask, count, gang, entry_point) => {
+                self.check_alive(pid)?;
+                let mut allocated = AffinityMask::empty();
+                let mut available = 0;
+                for gtid in mask.iter().filter(|gtid| *gtid < MAX_CORES) {
+                    if !self.scheduler_map.contains_key(&gtid) {
+                        if available < count {
+                            allocated.set(gtid);
+                        }
+                        available += 1;
+                    }
+                }
+
+                if gang && available < count {
+                    return Err(KError::GangRequestUnsatisfiable {
+                        requested: count,
+                        available,
+                    });
+                }
+                if allocated.is_empty() {
+                    return Err(KError::CoreAlreadyAllocated);
+                }
+
+                self.scheduler_map.try_reserve(allocated.count())?;
+                for gtid in allocated.iter() {
+                    trace!("Op::SchedAllocateCores pid={}, gtid={}", pid, gtid);
+                    let r = self.scheduler_map.insert(
+                        gtid,
+                        CoreInfo {
+                            pid,
+                            entry_point,
+                            resume: None,
+                        },
+                    );
+                    assert!(r.is_none(), "contains_key() -> false");
+                }
+
+                Ok(NodeResult::CoresAllocated(allocated))
+            }
+            Op::SchedMigrateCore(pid, from, to, eid) => {
+                if to >= MAX_CORES {
+                    return Err(KError::InvalidGlobalThreadId);
+                }
+                match self.scheduler_map.get(&from) {
+                    Some(cinfo) if cinfo.pid == pid => {}
+                    _ => return Err(KError::NoExecutorForCore),
+                }
+                if self.scheduler_map.contains_key(&to) {
+                    return Err(KError::CoreAlreadyAllocated);
+                }
+                trace!("Op::SchedMigrateCore pid={}, {} -> {}", pid, from, to);
+
+                self.scheduler_map.try_reserve(1)?;
+                let cinfo = self.scheduler_map.remove(&from).expect("get() -> Some");
+                let r = self.scheduler_map.insert(
+                    to,
+                    CoreInfo {
+                        resume: Some(eid),
+                        ..cinfo
+                    },
+                );
+                assert!(r.is_none(), "contains_key() -> false");
+
+                Ok(NodeResult::CoreMigrated(to))
+            }
         }
     }
 }
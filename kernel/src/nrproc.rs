@@ -5,17 +5,23 @@ use crate::prelude::*;
 
 use alloc::vec::Vec;
 use core::alloc::Allocator;
+use core::ops::Bound::{Excluded, Included, Unbounded};
+use core::ops::RangeBounds;
 
+use fallible_collections::btree::BTreeMap;
 use fallible_collections::vec::FallibleVec;
-use kpi::process::{FrameId, ProcessInfo};
+use fallible_collections::FallibleVecGlobal;
+use kpi::process::{FrameId, MemoryStats, ProcessInfo};
+use kpi::KERNEL_BASE;
 use node_replication::Dispatch;
 
 use crate::arch::process::PROCESS_TABLE;
 use crate::arch::Module;
 use crate::error::KError;
 use crate::memory::detmem::DA;
-use crate::memory::vspace::{AddressSpace, MapAction, TlbFlushHandle};
-use crate::memory::{Frame, PAddr, VAddr};
+use crate::memory::vspace::{AddressSpace, MapAction, MappedRange, TlbFlushHandle};
+use crate::memory::{Frame, PAddr, VAddr, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+use crate::nrstats::TimedReplica;
 use crate::process::{Eid, Executor, Pid, Process, MAX_PROCESSES};
 
 use crate::kcb::{ArchSpecificKcb, Kcb};
@@ -25,17 +31,35 @@ use crate::kcb::{ArchSpecificKcb, Kcb};
 pub enum ReadOps {
     ProcessInfo,
     MemResolve(VAddr),
+    /// Find the first mapped anonymous page at or above the given address.
+    MemAnonymous(VAddr),
+    /// Find the first region of the address-space at or above the given
+    /// address (including evicted pages).
+    MemMapping(VAddr),
+    /// The binary the process was loaded from.
+    Module,
+    /// Find the first large-page aligned range at or above the given address
+    /// that is backed by anonymous base pages (with the same rights) only.
+    MemPromotable(VAddr),
+    /// Find the first range of pages in the page-tables at or above the
+    /// given address (see `MappedRange`).
+    MemRange(VAddr),
+    /// The memory of the process that isn't replicated (see
+    /// `Process::owned_frames`), including pages that are being evicted.
+    OwnedFrames,
 }
 
 /// Mutable operations on the NrProcess.
 #[derive(PartialEq, Clone, Debug)]
 pub enum Op {
     ProcRaiseIrq,
-    Load(Pid, &'static Module, Vec<Frame>),
+    Load(Pid, &'static Module, Vec<Frame>, VAddr),
 
     /// Assign a core to a process.
     AssignExecutor(atopology::NodeId, atopology::GlobalThreadId),
 
+    /// Tear down the (exited) process so its pid can be reused, the memory
+    /// `ReadOps::OwnedFrames` reported is up to the caller.
     Destroy,
 
     /// Assign a physical frame to a process (returns a FrameId).
@@ -48,6 +72,144 @@ pub enum Op {
     MemMapFrameId(VAddr, FrameId, MapAction),
     MemAdjust,
     MemUnmap(VAddr),
+
+    /// Make room to track the given number of anonymous pages (see
+    /// `AnonymousPages`), executed before the operations that add them.
+    MemReserveAnonymous(usize),
+    /// Map zeroed memory, base pages of it can be evicted under memory
+    /// pressure (the caller refills the TCache for page-tables).
+    MemMapAnonymous(VAddr, Frame, MapAction),
+    /// Unmap an anonymous page so the reclaimer can check its contents.
+    MemEvict(VAddr),
+    /// Finish an eviction, `true` if the frame can be released, otherwise
+    /// it gets mapped again.
+    MemEvictCommit(VAddr, bool),
+    /// Back an evicted page with a new (zeroed) frame.
+    MemReload(VAddr, Frame),
+    /// Reserve a region of the address-space (base, size), it gets mapped
+    /// with the given rights once it's committed.
+    MemReserve(VAddr, usize, MapAction),
+    /// Drop the reservation that starts at the address, its committed pages
+    /// stay mapped.
+    MemUnreserve(VAddr),
+    /// Back a base page of a reserved region with a (zeroed) frame, unless
+    /// it's already mapped.
+    MemCommit(VAddr, Frame),
+    /// Unmap the anonymous base pages of a promotable range, their contents
+    /// are copied to a large page before the promotion is committed.
+    MemPromote(VAddr),
+    /// Finish a promotion by mapping the large page (or map the base pages
+    /// again if there is none).
+    MemPromoteCommit(VAddr, Option<Frame>),
+
+    /// Resume the next executor that is assigned to a core from the given
+    /// state (preferrably using the executor with the given id).
+    RestoreExecutor(Eid, Box<kpi::arch::SaveArea>),
+    /// Take the executor off a core, it moves to the given NUMA node and
+    /// resumes from the given state.
+    MigrateExecutor(
+        atopology::GlobalThreadId,
+        Eid,
+        atopology::NodeId,
+        Box<kpi::arch::SaveArea>,
+    ),
+    /// Assign a migrated executor to a core.
+    ResumeExecutor(atopology::GlobalThreadId, Eid),
+}
+
+/// State of an anonymous base page of a process.
+///
+/// Anonymous memory is zeroed when it's mapped, as long as a page still
+/// only contains zeroes the kernel can drop its frame and re-create it on
+/// the next access.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum AnonymousPage {
+    /// Mapped in the address-space and backed by the frame.
+    Mapped(Frame, MapAction),
+    /// Unmapped, the reclaimer is checking if the frame can be released.
+    Evicting(Frame, MapAction),
+    /// The frame was released, gets a new zeroed frame on the next access.
+    Evicted(MapAction),
+}
+
+/// The anonymous base pages of a process (sorted by their address).
+///
+/// Adding a page doesn't allocate if room for it was made with
+/// [`AnonymousPages::try_reserve`] (through `Op::MemReserveAnonymous`, before
+/// the operation that adds it is executed). An allocation that fails in
+/// dispatch would leave the replica tracking different pages than the others.
+#[derive(Debug, Default)]
+pub struct AnonymousPages {
+    pages: Vec<(VAddr, AnonymousPage)>,
+}
+
+impl AnonymousPages {
+    pub fn new() -> AnonymousPages {
+        AnonymousPages { pages: Vec::new() }
+    }
+
+    /// Makes room for `additional` more pages.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), KError> {
+        FallibleVec::try_reserve(&mut self.pages, additional)?;
+        Ok(())
+    }
+
+    pub fn get(&self, vaddr: &VAddr) -> Option<&AnonymousPage> {
+        let idx = self.position(vaddr).ok()?;
+        Some(&self.pages[idx].1)
+    }
+
+    pub fn get_mut(&mut self, vaddr: &VAddr) -> Option<&mut AnonymousPage> {
+        let idx = self.position(vaddr).ok()?;
+        Some(&mut self.pages[idx].1)
+    }
+
+    pub fn contains_key(&self, vaddr: &VAddr) -> bool {
+        self.position(vaddr).is_ok()
+    }
+
+    /// Adds (or replaces) the page at `vaddr`.
+    ///
+    /// Only allocates if no room was reserved for it.
+    pub fn insert(&mut self, vaddr: VAddr, page: AnonymousPage) -> Option<AnonymousPage> {
+        match self.position(&vaddr) {
+            Ok(idx) => Some(core::mem::replace(&mut self.pages[idx].1, page)),
+            Err(idx) => {
+                self.pages.insert(idx, (vaddr, page));
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, vaddr: &VAddr) -> Option<AnonymousPage> {
+        let idx = self.position(vaddr).ok()?;
+        Some(self.pages.remove(idx).1)
+    }
+
+    /// The pages with an address in `range` (in ascending order).
+    pub fn range<R: RangeBounds<VAddr>>(
+        &self,
+        range: R,
+    ) -> impl DoubleEndedIterator<Item = (&VAddr, &AnonymousPage)> {
+        let start = match range.start_bound() {
+            Included(base) => self.pages.partition_point(|(vaddr, _page)| vaddr < base),
+            Excluded(base) => self.pages.partition_point(|(vaddr, _page)| vaddr <= base),
+            Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Included(end) => self.pages.partition_point(|(vaddr, _page)| vaddr <= end),
+            Excluded(end) => self.pages.partition_point(|(vaddr, _page)| vaddr < end),
+            Unbounded => self.pages.len(),
+        };
+        self.pages[start..core::cmp::max(start, end)]
+            .iter()
+            .map(|(vaddr, page)| (vaddr, page))
+    }
+
+    fn position(&self, vaddr: &VAddr) -> Result<usize, usize> {
+        self.pages
+            .binary_search_by(|(other, _page)| other.cmp(vaddr))
+    }
 }
 
 /// Possible return values from the NrProcess.
@@ -65,15 +227,31 @@ pub enum NodeResult<E: Executor> {
     Unmapped(TlbFlushHandle),
     Resolved(PAddr, MapAction),
     FrameId(usize),
+    Anonymous(Option<(VAddr, Frame)>),
+    Reclaimed(Option<Frame>),
+    Reloaded(bool),
+    AnonymousReserved,
+    Reserved,
+    Unreserved,
+    Committed(bool),
+    Promotable(Option<(VAddr, Frame)>),
+    Promoting(TlbFlushHandle, Vec<Frame>),
+    Promoted,
+    Mapping(Option<(VAddr, Option<Frame>, MapAction)>),
+    Range(Option<MappedRange>),
+    Module(&'static Module, VAddr),
+    OwnedFrames(Vec<Frame>),
+    ExecutorRestored,
+    ExecutorMigrated,
 }
 
 /// Advances the replica of all the processes on the current NUMA node.
 pub fn advance_all() {
-    let kcb = super::kcb::get_kcb();
+    let kcb = super::kcb::per_core();
     let node = kcb.arch.node();
 
     for pid in 0..MAX_PROCESSES {
-        let _r = PROCESS_TABLE[node][pid].sync(kcb.process_token[pid]);
+        let _r = PROCESS_TABLE[node][pid].sync(kcb.process_token(pid));
     }
 }
 
@@ -81,6 +259,18 @@ pub fn advance_all() {
 pub struct NrProcess<P: Process, M: Allocator + Clone = alloc::alloc::Global> {
     /// A list of all cores where the current process is running.
     active_cores: Vec<(atopology::GlobalThreadId, Eid), M>,
+    /// Anonymous base pages that can be evicted.
+    anonymous: AnonymousPages,
+    /// Reserved regions: size and rights of the pages once they're committed
+    /// (indexed by their base address).
+    reserved: BTreeMap<VAddr, (usize, MapAction)>,
+    /// Large pages that replaced anonymous base pages (see `MemPromote`).
+    promoted: BTreeMap<VAddr, Frame>,
+    /// The binary the process was loaded from (and where).
+    module: Option<(&'static Module, VAddr)>,
+    /// How much memory the process uses (updated by every operation that
+    /// maps or unmaps memory).
+    memory: MemoryStats,
     /// The process struct itself.
     process: Box<P>,
 }
@@ -89,6 +279,11 @@ impl<P: Process> NrProcess<P> {
     pub fn new(process: Box<P>, _da: DA) -> NrProcess<P> {
         NrProcess {
             active_cores: Vec::new(),
+            anonymous: AnonymousPages::new(),
+            reserved: BTreeMap::new(),
+            promoted: BTreeMap::new(),
+            module: None,
+            memory: Default::default(),
             process,
         }
     }
@@ -99,15 +294,16 @@ impl<P: Process> NrProcess<P> {
         pid: Pid,
         module: &'static Module,
         writeable_sections: Vec<Frame>,
+        offset: VAddr,
     ) -> Result<(), KError> {
         debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
 
-        let kcb = super::kcb::get_kcb();
+        let kcb = super::kcb::per_core();
         let node = kcb.arch.node();
 
-        let response = PROCESS_TABLE[node][pid].execute_mut(
-            Op::Load(pid, module, writeable_sections),
-            kcb.process_token[pid],
+        let response = PROCESS_TABLE[node][pid].execute_mut_timed(
+            Op::Load(pid, module, writeable_sections, offset),
+            kcb.process_token(pid),
         );
         match response {
             Ok(NodeResult::Loaded) => Ok(()),
@@ -120,11 +316,11 @@ impl<P: Process> NrProcess<P> {
         debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
         debug_assert!(base.as_u64() < kpi::KERNEL_BASE, "Invalid base");
 
-        let kcb = super::kcb::get_kcb();
+        let kcb = super::kcb::per_core();
         let node = kcb.arch.node();
 
         let response =
-            PROCESS_TABLE[node][pid].execute(ReadOps::MemResolve(base), kcb.process_token[pid]);
+            PROCESS_TABLE[node][pid].execute(ReadOps::MemResolve(base), kcb.process_token(pid));
         match response {
             Ok(NodeResult::Resolved(paddr, _rights)) => Ok((paddr.as_u64(), 0x0)),
             Err(e) => Err(e),
@@ -132,12 +328,29 @@ impl<P: Process> NrProcess<P> {
         }
     }
 
+    /// Returns the access rights of the mapping that contains `base`.
+    pub fn access_rights(pid: Pid, base: VAddr) -> Result<MapAction, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+        debug_assert!(base.as_u64() < kpi::KERNEL_BASE, "Invalid base");
+
+        let kcb = super::kcb::per_core();
+        let node = kcb.arch.node();
+
+        let response =
+            PROCESS_TABLE[node][pid].execute(ReadOps::MemResolve(base), kcb.process_token(pid));
+        match response {
+            Ok(NodeResult::Resolved(_paddr, rights)) => Ok(rights),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
     pub fn synchronize(pid: Pid) {
         debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
-        let kcb = super::kcb::get_kcb();
+        let kcb = super::kcb::per_core();
         let node = kcb.arch.node();
 
-        PROCESS_TABLE[node][pid].sync(kcb.process_token[pid]);
+        PROCESS_TABLE[node][pid].sync(kcb.process_token(pid));
     }
 
     pub fn map_device_frame(
@@ -147,11 +360,11 @@ impl<P: Process> NrProcess<P> {
     ) -> Result<(u64, u64), KError> {
         debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
 
-        let kcb = super::kcb::get_kcb();
+        let kcb = super::kcb::per_core();
         let node = kcb.arch.node();
 
         let response = PROCESS_TABLE[node][pid]
-            .execute_mut(Op::MemMapDevice(frame, action), kcb.process_token[pid]);
+            .execute_mut_timed(Op::MemMapDevice(frame, action), kcb.process_token(pid));
         match response {
             Ok(NodeResult::Mapped) => Ok((frame.base.as_u64(), frame.size() as u64)),
             Err(e) => Err(e),
@@ -162,11 +375,16 @@ impl<P: Process> NrProcess<P> {
     pub fn unmap(pid: Pid, base: VAddr) -> Result<TlbFlushHandle, KError> {
         debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
 
-        let kcb = super::kcb::get_kcb();
+        let kcb = super::kcb::per_core();
         let node = kcb.arch.node();
 
+        // Unmapping part of a promoted large page splits it into base pages
+        // again, make room for them before the operation is logged
+        crate::memory::KernelAllocator::try_refill_tcache(7, 0)?;
+        Self::reserve_anonymous(pid, PROMOTE_PAGES)?;
+
         let response =
-            PROCESS_TABLE[node][pid].execute_mut(Op::MemUnmap(base), kcb.process_token[pid]);
+            PROCESS_TABLE[node][pid].execute_mut_timed(Op::MemUnmap(base), kcb.process_token(pid));
         match response {
             Ok(NodeResult::Unmapped(handle)) => Ok(handle),
             Err(e) => Err(e),
@@ -182,12 +400,12 @@ impl<P: Process> NrProcess<P> {
     ) -> Result<(PAddr, usize), KError> {
         debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
 
-        let kcb = super::kcb::get_kcb();
+        let kcb = super::kcb::per_core();
         let node = kcb.arch.node();
 
-        let response = PROCESS_TABLE[node][pid].execute_mut(
+        let response = PROCESS_TABLE[node][pid].execute_mut_timed(
             Op::MemMapFrameId(base, frame_id, action),
-            kcb.process_token[pid],
+            kcb.process_token(pid),
         );
         match response {
             Ok(NodeResult::MappedFrameId(paddr, size)) => Ok((paddr, size)),
@@ -204,14 +422,14 @@ impl<P: Process> NrProcess<P> {
     ) -> Result<(u64, u64), KError> {
         debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
 
-        let kcb = super::kcb::get_kcb();
+        let kcb = super::kcb::per_core();
         let node = kcb.arch.node();
 
         let mut virtual_offset = 0;
         for frame in frames {
-            let response = PROCESS_TABLE[node][pid].execute_mut(
+            let response = PROCESS_TABLE[node][pid].execute_mut_timed(
                 Op::MemMapFrame(base + virtual_offset, frame, action),
-                kcb.process_token[pid],
+                kcb.process_token(pid),
             );
             match response {
                 Ok(NodeResult::Mapped) => {}
@@ -230,14 +448,347 @@ impl<P: Process> NrProcess<P> {
         Ok((base.as_u64(), virtual_offset as u64))
     }
 
+    /// Maps zeroed `frames` at `base`, the base pages among them can be
+    /// reclaimed under memory pressure.
+    pub fn map_anonymous(
+        pid: Pid,
+        base: VAddr,
+        frames: Vec<Frame>,
+        action: MapAction,
+    ) -> Result<(u64, u64), KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::per_core();
+        let node = kcb.arch.node();
+
+        let pages = frames
+            .iter()
+            .filter(|frame| frame.size() == BASE_PAGE_SIZE)
+            .count();
+        Self::reserve_anonymous(pid, pages)?;
+
+        let mut virtual_offset = 0;
+        for frame in frames {
+            let response = PROCESS_TABLE[node][pid].execute_mut_timed(
+                Op::MemMapAnonymous(base + virtual_offset, frame, action),
+                kcb.process_token(pid),
+            );
+            match response {
+                Ok(NodeResult::Mapped) => {}
+                Err(e) => return Err(e),
+                _ => unreachable!("Got unexpected response"),
+            }
+
+            virtual_offset += frame.size();
+        }
+
+        Ok((base.as_u64(), virtual_offset as u64))
+    }
+
+    /// Makes room to track `pages` more anonymous pages of `pid`, so the
+    /// operations that add them don't allocate in dispatch.
+    fn reserve_anonymous(pid: Pid, pages: usize) -> Result<(), KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::per_core();
+        let node = kcb.arch.node();
+
+        let response = PROCESS_TABLE[node][pid]
+            .execute_mut_timed(Op::MemReserveAnonymous(pages), kcb.process_token(pid));
+        match response {
+            Ok(NodeResult::AnonymousReserved) => Ok(()),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Returns the binary `pid` was loaded from and the offset it was
+    /// loaded at.
+    pub fn module(pid: Pid) -> Result<(&'static Module, VAddr), KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::per_core();
+        let node = kcb.arch.node();
+
+        let response = PROCESS_TABLE[node][pid].execute(ReadOps::Module, kcb.process_token(pid));
+        match response {
+            Ok(NodeResult::Module(module, offset)) => Ok((module, offset)),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Returns the first region of the address-space at or above `base`.
+    ///
+    /// The frame is `None` for pages that were evicted (they're re-created
+    /// zeroed on access).
+    pub fn next_mapping(
+        pid: Pid,
+        base: VAddr,
+    ) -> Result<Option<(VAddr, Option<Frame>, MapAction)>, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::per_core();
+        let node = kcb.arch.node();
+
+        let response =
+            PROCESS_TABLE[node][pid].execute(ReadOps::MemMapping(base), kcb.process_token(pid));
+        match response {
+            Ok(NodeResult::Mapping(mapping)) => Ok(mapping),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Returns the first range of pages (mapped with the same page size and
+    /// rights to contiguous memory) that contains or follows `base`.
+    ///
+    /// Only covers the user part of the address-space.
+    pub fn next_range(pid: Pid, base: VAddr) -> Result<Option<MappedRange>, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::per_core();
+        let node = kcb.arch.node();
+
+        let response =
+            PROCESS_TABLE[node][pid].execute(ReadOps::MemRange(base), kcb.process_token(pid));
+        match response {
+            Ok(NodeResult::Range(range)) => Ok(range),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// The next executor of `pid` that gets assigned to a core resumes from
+    /// `state` instead of the entry point.
+    pub fn restore_executor(pid: Pid, eid: Eid, state: kpi::arch::SaveArea) -> Result<(), KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::per_core();
+        let node = kcb.arch.node();
+
+        let state = Box::try_new(state)?;
+        let response = PROCESS_TABLE[node][pid]
+            .execute_mut_timed(Op::RestoreExecutor(eid, state), kcb.process_token(pid));
+        match response {
+            Ok(NodeResult::ExecutorRestored) => Ok(()),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Returns the first mapped anonymous page at or above `base`.
+    pub fn next_anonymous(pid: Pid, base: VAddr) -> Result<Option<(VAddr, Frame)>, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::per_core();
+        let node = kcb.arch.node();
+
+        let response =
+            PROCESS_TABLE[node][pid].execute(ReadOps::MemAnonymous(base), kcb.process_token(pid));
+        match response {
+            Ok(NodeResult::Anonymous(page)) => Ok(page),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Unmaps the anonymous page at `base`, the eviction has to be finished
+    /// with [`NrProcess::evict_commit`] after the TLB shootdown.
+    pub fn evict(pid: Pid, base: VAddr) -> Result<TlbFlushHandle, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::per_core();
+        let node = kcb.arch.node();
+
+        let response =
+            PROCESS_TABLE[node][pid].execute_mut_timed(Op::MemEvict(base), kcb.process_token(pid));
+        match response {
+            Ok(NodeResult::Unmapped(handle)) => Ok(handle),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Finishes the eviction of the page at `base`, returns the frame to
+    /// release if `zero` is set, otherwise the page is mapped again.
+    pub fn evict_commit(pid: Pid, base: VAddr, zero: bool) -> Result<Option<Frame>, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::per_core();
+        let node = kcb.arch.node();
+
+        let response = PROCESS_TABLE[node][pid]
+            .execute_mut_timed(Op::MemEvictCommit(base, zero), kcb.process_token(pid));
+        match response {
+            Ok(NodeResult::Reclaimed(frame)) => Ok(frame),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Maps `frame` in place of the evicted page at `base`.
+    ///
+    /// Returns `false` if `frame` wasn't used because the page is still
+    /// mapped or in the middle of an eviction.
+    pub fn reload(pid: Pid, base: VAddr, frame: Frame) -> Result<bool, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::per_core();
+        let node = kcb.arch.node();
+
+        let response = PROCESS_TABLE[node][pid]
+            .execute_mut_timed(Op::MemReload(base, frame), kcb.process_token(pid));
+        match response {
+            Ok(NodeResult::Reloaded(used)) => Ok(used),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Reserves `size` bytes of the address-space at `base`, nothing gets
+    /// mapped until pages of it are committed (see [`NrProcess::commit`]).
+    pub fn reserve(pid: Pid, base: VAddr, size: usize, action: MapAction) -> Result<(), KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::per_core();
+        let node = kcb.arch.node();
+
+        let response = PROCESS_TABLE[node][pid]
+            .execute_mut_timed(Op::MemReserve(base, size, action), kcb.process_token(pid));
+        match response {
+            Ok(NodeResult::Reserved) => Ok(()),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Drops the reservation at `base` (see [`NrProcess::reserve`]), pages
+    /// of it that are committed stay mapped.
+    pub fn unreserve(pid: Pid, base: VAddr) -> Result<(), KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::per_core();
+        let node = kcb.arch.node();
+
+        let response = PROCESS_TABLE[node][pid]
+            .execute_mut_timed(Op::MemUnreserve(base), kcb.process_token(pid));
+        match response {
+            Ok(NodeResult::Unreserved) => Ok(()),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Maps the zeroed `frame` at `base` which has to be in a reserved
+    /// region.
+    ///
+    /// Returns `false` if `frame` wasn't used because the page is already
+    /// committed.
+    pub fn commit(pid: Pid, base: VAddr, frame: Frame) -> Result<bool, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::per_core();
+        let node = kcb.arch.node();
+
+        Self::reserve_anonymous(pid, 1)?;
+        let response = PROCESS_TABLE[node][pid]
+            .execute_mut_timed(Op::MemCommit(base, frame), kcb.process_token(pid));
+        match response {
+            Ok(NodeResult::Committed(used)) => Ok(used),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Returns the first range at or above `base` that can be promoted to a
+    /// large page and the frame of its first base page.
+    pub fn promotable(pid: Pid, base: VAddr) -> Result<Option<(VAddr, Frame)>, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::per_core();
+        let node = kcb.arch.node();
+
+        let response =
+            PROCESS_TABLE[node][pid].execute(ReadOps::MemPromotable(base), kcb.process_token(pid));
+        match response {
+            Ok(NodeResult::Promotable(range)) => Ok(range),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Unmaps the base pages of the promotable range at `base`, returns
+    /// their frames (in order). The promotion has to be finished with
+    /// [`NrProcess::promote_commit`] after the TLB shootdown.
+    pub fn promote(pid: Pid, base: VAddr) -> Result<(TlbFlushHandle, Vec<Frame>), KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::per_core();
+        let node = kcb.arch.node();
+
+        let response = PROCESS_TABLE[node][pid]
+            .execute_mut_timed(Op::MemPromote(base), kcb.process_token(pid));
+        match response {
+            Ok(NodeResult::Promoting(handle, frames)) => Ok((handle, frames)),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Maps `large` (which has the contents of the base pages) at `base`, or
+    /// maps the base pages again if `large` is `None`.
+    pub fn promote_commit(pid: Pid, base: VAddr, large: Option<Frame>) -> Result<(), KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::per_core();
+        let node = kcb.arch.node();
+
+        let response = PROCESS_TABLE[node][pid]
+            .execute_mut_timed(Op::MemPromoteCommit(base, large), kcb.process_token(pid));
+        match response {
+            Ok(NodeResult::Promoted) => Ok(()),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Tears down the exited process `pid` and returns the memory it owned,
+    /// the caller has to free it.
+    ///
+    /// Nothing may run the process or change its address space anymore.
+    pub fn destroy(pid: Pid) -> Result<Vec<Frame>, KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::per_core();
+        let node = kcb.arch.node();
+
+        // Collected before, `Op::Destroy` can't allocate (if it failed on
+        // some replicas only they would diverge)
+        let frames =
+            match PROCESS_TABLE[node][pid].execute(ReadOps::OwnedFrames, kcb.process_token(pid)) {
+                Ok(NodeResult::OwnedFrames(frames)) => frames,
+                Err(e) => return Err(e),
+                _ => unreachable!("Got unexpected response"),
+            };
+
+        let response =
+            PROCESS_TABLE[node][pid].execute_mut_timed(Op::Destroy, kcb.process_token(pid));
+        match response {
+            Ok(NodeResult::Destroyed) => Ok(frames),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
     pub fn pinfo(pid: Pid) -> Result<ProcessInfo, KError> {
         debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
 
-        let kcb = super::kcb::get_kcb();
+        let kcb = super::kcb::per_core();
         let node = kcb.arch.node();
 
         let response =
-            PROCESS_TABLE[node][pid].execute(ReadOps::ProcessInfo, kcb.process_token[pid]);
+            PROCESS_TABLE[node][pid].execute(ReadOps::ProcessInfo, kcb.process_token(pid));
         match response {
             Ok(NodeResult::ProcessInfo(pinfo)) => Ok(pinfo),
             Err(e) => Err(e),
@@ -256,7 +807,53 @@ impl<P: Process> NrProcess<P> {
         let node = kcb.arch.node();
 
         let response = kcb.arch.process_table()[node][pid]
-            .execute_mut(Op::AssignExecutor(gtid, node), kcb.process_token[pid]);
+            .execute_mut_timed(Op::AssignExecutor(gtid, node), kcb.process_token(pid));
+        match response {
+            Ok(NodeResult::Executor(executor)) => Ok(executor),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Takes executor `eid` off core `from`, it will resume from `state` on
+    /// a core of NUMA node `to_region` (see `resume_executor`).
+    pub fn migrate_executor(
+        pid: Pid,
+        from: atopology::GlobalThreadId,
+        eid: Eid,
+        to_region: atopology::NodeId,
+        state: kpi::arch::SaveArea,
+    ) -> Result<(), KError> {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let kcb = super::kcb::per_core();
+        let node = kcb.arch.node();
+
+        let state = Box::try_new(state)?;
+        let response = PROCESS_TABLE[node][pid].execute_mut_timed(
+            Op::MigrateExecutor(from, eid, to_region, state),
+            kcb.process_token(pid),
+        );
+        match response {
+            Ok(NodeResult::ExecutorMigrated) => Ok(()),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Assigns the migrated executor `eid` to the current core.
+    pub fn resume_executor<A>(kcb: &Kcb<A>, pid: Pid, eid: Eid) -> Result<Box<P::E>, KError>
+    where
+        A: ArchSpecificKcb<Process = P>,
+        P: Process + core::marker::Sync + 'static,
+    {
+        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+
+        let gtid = kcb.arch.hwthread_id();
+        let node = kcb.arch.node();
+
+        let response = kcb.arch.process_table()[node][pid]
+            .execute_mut_timed(Op::ResumeExecutor(gtid, eid), kcb.process_token(pid));
         match response {
             Ok(NodeResult::Executor(executor)) => Ok(executor),
             Err(e) => Err(e),
@@ -267,11 +864,11 @@ impl<P: Process> NrProcess<P> {
     pub fn allocate_frame_to_process(pid: Pid, frame: Frame) -> Result<FrameId, KError> {
         debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
 
-        let kcb = super::kcb::get_kcb();
+        let kcb = super::kcb::per_core();
         let node = kcb.arch.node();
 
         let response = PROCESS_TABLE[node][pid]
-            .execute_mut(Op::AllocateFrameToProcess(frame), kcb.process_token[pid]);
+            .execute_mut_timed(Op::AllocateFrameToProcess(frame), kcb.process_token(pid));
         match response {
             Ok(NodeResult::FrameId(fid)) => Ok(fid),
             Err(e) => Err(e),
@@ -282,11 +879,11 @@ impl<P: Process> NrProcess<P> {
     pub fn allocate_dispatchers(pid: Pid, frame: Frame) -> Result<usize, KError> {
         debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
 
-        let kcb = super::kcb::get_kcb();
+        let kcb = super::kcb::per_core();
         let node = kcb.arch.node();
 
         let response = PROCESS_TABLE[node][pid]
-            .execute_mut(Op::DispatcherAllocation(frame), kcb.process_token[pid]);
+            .execute_mut_timed(Op::DispatcherAllocation(frame), kcb.process_token(pid));
 
         match response {
             Ok(NodeResult::ExecutorsCreated(how_many)) => Ok(how_many),
@@ -296,6 +893,144 @@ impl<P: Process> NrProcess<P> {
     }
 }
 
+/// Base pages in a large page.
+const PROMOTE_PAGES: usize = LARGE_PAGE_SIZE / BASE_PAGE_SIZE;
+
+impl<P: Process, M: Allocator + Clone> NrProcess<P, M> {
+    /// Accounts a page of `page_size` that got mapped (or unmapped if
+    /// `mapped` is false).
+    fn account(&mut self, page_size: usize, mapped: bool) {
+        let bytes = page_size as u64;
+        let class = match page_size {
+            BASE_PAGE_SIZE => &mut self.memory.base_pages,
+            LARGE_PAGE_SIZE => &mut self.memory.large_pages,
+            _ => &mut self.memory.huge_pages,
+        };
+        if mapped {
+            *class += bytes;
+            self.memory.resident += bytes;
+        } else {
+            *class = class.saturating_sub(bytes);
+            self.memory.resident = self.memory.resident.saturating_sub(bytes);
+        }
+    }
+
+    /// Accounts the pages that are mapped in `size` bytes at `base` (after
+    /// they got mapped, the page-tables know which page sizes were used).
+    fn account_mapped(&mut self, base: VAddr, size: usize) {
+        let end = base + size;
+        let mut cursor = base;
+        while let Some((vaddr, _paddr, page_size, _rights)) =
+            self.process.vspace().next_page(cursor)
+        {
+            if vaddr >= end {
+                break;
+            }
+            self.account(page_size, true);
+            cursor = vaddr + page_size;
+        }
+    }
+
+    /// Accounts the pages the process mapped on its own (e.g., when loading
+    /// the binary or creating executors).
+    fn account_last_mapped(&mut self) {
+        let (base, size) = self.process.last_mapped();
+        self.account_mapped(base, size);
+    }
+
+    /// Is `vaddr` in a reserved region?
+    fn is_reserved(&self, vaddr: VAddr) -> bool {
+        self.reserved
+            .range((Unbounded, Included(vaddr)))
+            .next_back()
+            .map_or(false, |(start, (size, _action))| vaddr < *start + *size)
+    }
+
+    /// The memory use of the process.
+    fn memory_stats(&self) -> MemoryStats {
+        let mut stats = self.memory;
+        stats.virtual_size = stats.resident + stats.evicted + stats.reserved;
+        stats
+    }
+
+    /// The rights of the anonymous pages in the large-page aligned range at
+    /// `base` if all of them are in the given state (`Mapped` or `Evicting`).
+    fn anonymous_range(&self, base: VAddr, evicting: bool) -> Option<MapAction> {
+        let mut rights = None;
+        for i in 0..PROMOTE_PAGES {
+            let action = match (self.anonymous.get(&(base + i * BASE_PAGE_SIZE)), evicting) {
+                (Some(AnonymousPage::Mapped(_frame, action)), false) => *action,
+                (Some(AnonymousPage::Evicting(_frame, action)), true) => *action,
+                _ => return None,
+            };
+            if *rights.get_or_insert(action) != action {
+                return None;
+            }
+        }
+        rights
+    }
+
+    /// Maps the large page at `base` that replaced anonymous base pages
+    /// as base pages again (backed by the same memory).
+    ///
+    /// Room for the base pages is made before the operation is executed
+    /// (see [`NrProcess::unmap`]).
+    fn demote(&mut self, base: VAddr) -> Result<(), KError> {
+        let large = *self.promoted.get(&base).ok_or(KError::NotMapped)?;
+        let (_paddr, action) = self.process.vspace().resolve(base)?;
+
+        // Same memory, same rights: stale TLB entries for the large page
+        // don't hurt, no shootdown needed
+        let handle = self.process.vspace_mut().unmap(base)?;
+        self.promoted.remove(&base);
+        self.account(handle.frame.size(), false);
+        for i in 0..PROMOTE_PAGES {
+            let frame = Frame::new(
+                large.base + i * BASE_PAGE_SIZE,
+                BASE_PAGE_SIZE,
+                large.affinity,
+            );
+            let vaddr = base + i * BASE_PAGE_SIZE;
+            self.process.vspace_mut().map_frame(vaddr, frame, action)?;
+            self.account(BASE_PAGE_SIZE, true);
+            self.anonymous
+                .insert(vaddr, AnonymousPage::Mapped(frame, action));
+        }
+
+        Ok(())
+    }
+
+    /// Maps the base pages of an abandoned promotion at `base` again (they
+    /// are `Evicting` since `MemPromote`).
+    ///
+    /// Maps as many of them as it can and returns the first error.
+    fn unpromote(&mut self, base: VAddr) -> Result<(), KError> {
+        let mut result = Ok(());
+        for i in 0..PROMOTE_PAGES {
+            let vaddr = base + i * BASE_PAGE_SIZE;
+            if let Some(AnonymousPage::Evicting(frame, action)) =
+                self.anonymous.get(&vaddr).copied()
+            {
+                match self.process.vspace_mut().map_frame(vaddr, frame, action) {
+                    Ok(()) => {
+                        self.anonymous
+                            .insert(vaddr, AnonymousPage::Mapped(frame, action));
+                        self.account(BASE_PAGE_SIZE, true);
+                        self.memory.evicted =
+                            self.memory.evicted.saturating_sub(BASE_PAGE_SIZE as u64);
+                    }
+                    Err(e) => {
+                        if result.is_ok() {
+                            result = Err(e);
+                        }
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
 impl<P, M> Dispatch for NrProcess<P, M>
 where
     P: Process,
@@ -307,34 +1042,149 @@ where
     type Response = Result<NodeResult<P::E>, KError>;
 
     fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
+        let _pin = crate::domain::pin();
         match op {
-            ReadOps::ProcessInfo => Ok(NodeResult::ProcessInfo(*self.process.pinfo())),
+            ReadOps::ProcessInfo => {
+                let mut pinfo = *self.process.pinfo();
+                pinfo.memory = self.memory_stats();
+                Ok(NodeResult::ProcessInfo(pinfo))
+            }
             ReadOps::MemResolve(base) => {
                 let (paddr, rights) = self.process.vspace().resolve(base)?;
                 Ok(NodeResult::Resolved(paddr, rights))
             }
+            ReadOps::MemAnonymous(base) => {
+                let page =
+                    self.anonymous
+                        .range((Included(base), Unbounded))
+                        .find_map(|(vaddr, page)| match page {
+                            AnonymousPage::Mapped(frame, _action) => Some((*vaddr, *frame)),
+                            _ => None,
+                        });
+                Ok(NodeResult::Anonymous(page))
+            }
+            ReadOps::MemMapping(base) => {
+                let mapped = self
+                    .process
+                    .vspace()
+                    .next_mapping(base)
+                    .map(|(vaddr, frame, action)| (vaddr, Some(frame), action));
+                // Evicted pages are no longer in the address-space
+                let unmapped =
+                    self.anonymous
+                        .range((Included(base), Unbounded))
+                        .find_map(|(vaddr, page)| match page {
+                            AnonymousPage::Evicting(frame, action) => {
+                                Some((*vaddr, Some(*frame), *action))
+                            }
+                            AnonymousPage::Evicted(action) => Some((*vaddr, None, *action)),
+                            AnonymousPage::Mapped(_frame, _action) => None,
+                        });
+
+                let next = match (mapped, unmapped) {
+                    (Some(m), Some(u)) => Some(if u.0 < m.0 { u } else { m }),
+                    (m, u) => m.or(u),
+                };
+                Ok(NodeResult::Mapping(next))
+            }
+            ReadOps::MemRange(base) => {
+                let vspace = self.process.vspace();
+                let mut range = match vspace.next_page(base) {
+                    Some((vaddr, paddr, page_size, rights)) if vaddr.as_u64() < KERNEL_BASE => {
+                        MappedRange {
+                            vaddr,
+                            paddr,
+                            size: page_size,
+                            page_size,
+                            rights,
+                        }
+                    }
+                    _ => return Ok(NodeResult::Range(None)),
+                };
+
+                // Extend it as long as the next page continues it
+                while let Some((vaddr, paddr, page_size, rights)) =
+                    vspace.next_page(range.vaddr + range.size)
+                {
+                    if vaddr != range.vaddr + range.size
+                        || paddr != range.paddr + range.size
+                        || page_size != range.page_size
+                        || rights != range.rights
+                        || vaddr.as_u64() >= KERNEL_BASE
+                    {
+                        break;
+                    }
+                    range.size += page_size;
+                }
+                Ok(NodeResult::Range(Some(range)))
+            }
+            ReadOps::OwnedFrames => {
+                let mut frames = Vec::new();
+                self.process.owned_frames(&mut frames)?;
+                // Not mapped while the reclaimer looks at them
+                for (_vaddr, page) in self.anonymous.range(..) {
+                    if let AnonymousPage::Evicting(frame, _action) = page {
+                        frames.try_push(*frame)?;
+                    }
+                }
+                Ok(NodeResult::OwnedFrames(frames))
+            }
+            ReadOps::Module => self
+                .module
+                .map(|(module, offset)| NodeResult::Module(module, offset))
+                .ok_or(KError::NoProcessFoundForPid),
+            ReadOps::MemPromotable(base) => {
+                let candidate = self
+                    .anonymous
+                    .range((Included(base), Unbounded))
+                    .filter(|(vaddr, _page)| vaddr.is_large_page_aligned())
+                    .find(|(vaddr, _page)| self.anonymous_range(**vaddr, false).is_some());
+                Ok(NodeResult::Promotable(candidate.and_then(
+                    |(vaddr, page)| match page {
+                        AnonymousPage::Mapped(frame, _action) => Some((*vaddr, *frame)),
+                        _ => None,
+                    },
+                )))
+            }
         }
     }
 
     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+        let _pin = crate::domain::pin();
+        let _applying = crate::nrstats::applying();
+        crate::fault::delay_combine();
+
         match op {
-            Op::Destroy => unimplemented!("Destrroy"),
+            Op::Destroy => {
+                self.process.destroy();
+                self.active_cores.clear();
+                self.anonymous = AnonymousPages::new();
+                self.reserved = BTreeMap::new();
+                self.promoted = BTreeMap::new();
+                self.module = None;
+                self.memory = Default::default();
+                Ok(NodeResult::Destroyed)
+            }
             Op::ProcRaiseIrq => unimplemented!("ProcRaiseIrq"),
             Op::MemAdjust => unimplemented!("MemAdjust"),
 
-            Op::Load(pid, module, writeable_sections) => {
-                self.process.load(pid, module, writeable_sections)?;
+            Op::Load(pid, module, writeable_sections, offset) => {
+                self.process.load(pid, module, writeable_sections, offset)?;
+                self.module = Some((module, offset));
+                self.account_last_mapped();
                 Ok(NodeResult::Loaded)
             }
 
             Op::DispatcherAllocation(frame) => {
                 let how_many = self.process.allocate_executors(frame)?;
+                self.account_last_mapped();
                 Ok(NodeResult::ExecutorsCreated(how_many))
             }
 
             Op::MemMapFrame(base, frame, action) => {
                 crate::memory::KernelAllocator::try_refill_tcache(7, 0)?;
                 self.process.vspace_mut().map_frame(base, frame, action)?;
+                self.account_mapped(base, frame.size());
                 Ok(NodeResult::Mapped)
             }
 
@@ -342,6 +1192,7 @@ where
             Op::MemMapDevice(frame, action) => {
                 let base = VAddr::from(frame.base.as_u64());
                 self.process.vspace_mut().map_frame(base, frame, action)?;
+                self.account_mapped(base, frame.size());
                 Ok(NodeResult::Mapped)
             }
 
@@ -350,11 +1201,54 @@ where
                 crate::memory::KernelAllocator::try_refill_tcache(7, 0)?;
 
                 self.process.vspace_mut().map_frame(base, frame, action)?;
+                self.account_mapped(base, frame.size());
                 Ok(NodeResult::MappedFrameId(frame.base, frame.size))
             }
 
             Op::MemUnmap(vaddr) => {
-                let mut shootdown_handle = self.process.vspace_mut().unmap(vaddr)?;
+                // Unmapping (part of) a promoted large page: it used to be
+                // base pages, keep it that way
+                let large = self
+                    .promoted
+                    .range((Unbounded, Included(vaddr)))
+                    .next_back()
+                    .map(|(base, frame)| (*base, *frame));
+                if let Some((base, frame)) = large {
+                    if vaddr < base + frame.size() {
+                        self.demote(base)?;
+                    }
+                }
+
+                let mut shootdown_handle = match self.process.vspace_mut().unmap(vaddr) {
+                    Ok(handle) => handle,
+                    Err(e) => {
+                        // An evicted page has no mapping left, just forget it
+                        return match self.anonymous.get(&vaddr) {
+                            Some(AnonymousPage::Evicted(_action)) => {
+                                self.anonymous.remove(&vaddr);
+                                self.memory.evicted =
+                                    self.memory.evicted.saturating_sub(BASE_PAGE_SIZE as u64);
+                                if self.is_reserved(vaddr) {
+                                    self.memory.reserved += BASE_PAGE_SIZE as u64;
+                                }
+                                Ok(NodeResult::Unmapped(TlbFlushHandle::new(
+                                    vaddr,
+                                    Frame::empty(),
+                                )))
+                            }
+                            _ => Err(e),
+                        };
+                    }
+                };
+                self.anonymous.remove(&shootdown_handle.vaddr);
+                let size = shootdown_handle.frame.size();
+                self.account(size, false);
+                // Unmapped parts of a reserved region count as reserved
+                // again (they stay unbacked until they're committed again
+                // with `VSpaceOperation::Commit`)
+                if self.is_reserved(shootdown_handle.vaddr) {
+                    self.memory.reserved += size as u64;
+                }
                 // Figure out which cores are running our current process
                 // (this is where we send IPIs later)
                 for (gtid, _eid) in self.active_cores.iter() {
@@ -364,6 +1258,213 @@ where
                 Ok(NodeResult::Unmapped(shootdown_handle))
             }
 
+            Op::MemReserveAnonymous(pages) => {
+                self.anonymous.try_reserve(pages)?;
+                Ok(NodeResult::AnonymousReserved)
+            }
+
+            Op::MemMapAnonymous(base, frame, action) => {
+                self.process.vspace_mut().map_frame(base, frame, action)?;
+                self.account_mapped(base, frame.size());
+                if frame.size() == BASE_PAGE_SIZE {
+                    self.anonymous
+                        .insert(base, AnonymousPage::Mapped(frame, action));
+                }
+                Ok(NodeResult::Mapped)
+            }
+
+            Op::MemEvict(base) => {
+                let page = self.anonymous.get_mut(&base).ok_or(KError::NotMapped)?;
+                let (frame, action) = match *page {
+                    AnonymousPage::Mapped(frame, action) => (frame, action),
+                    _ => return Err(KError::NotMapped),
+                };
+
+                let mut shootdown_handle = self.process.vspace_mut().unmap(base)?;
+                for (gtid, _eid) in self.active_cores.iter() {
+                    shootdown_handle.add_core(*gtid);
+                }
+                *page = AnonymousPage::Evicting(frame, action);
+                self.account(BASE_PAGE_SIZE, false);
+                self.memory.evicted += BASE_PAGE_SIZE as u64;
+
+                Ok(NodeResult::Unmapped(shootdown_handle))
+            }
+
+            Op::MemEvictCommit(base, zero) => {
+                let page = self.anonymous.get_mut(&base).ok_or(KError::NotMapped)?;
+                let (frame, action) = match *page {
+                    AnonymousPage::Evicting(frame, action) => (frame, action),
+                    _ => return Err(KError::NotMapped),
+                };
+
+                if zero {
+                    *page = AnonymousPage::Evicted(action);
+                    Ok(NodeResult::Reclaimed(Some(frame)))
+                } else {
+                    // Got written to before it was unmapped, keep it
+                    self.process.vspace_mut().map_frame(base, frame, action)?;
+                    *page = AnonymousPage::Mapped(frame, action);
+                    self.account(BASE_PAGE_SIZE, true);
+                    self.memory.evicted = self.memory.evicted.saturating_sub(BASE_PAGE_SIZE as u64);
+                    Ok(NodeResult::Reclaimed(None))
+                }
+            }
+
+            Op::MemReload(base, frame) => {
+                let page = self.anonymous.get_mut(&base).ok_or(KError::NotMapped)?;
+                match *page {
+                    AnonymousPage::Evicted(action) => {
+                        self.process.vspace_mut().map_frame(base, frame, action)?;
+                        *page = AnonymousPage::Mapped(frame, action);
+                        self.account(BASE_PAGE_SIZE, true);
+                        self.memory.evicted =
+                            self.memory.evicted.saturating_sub(BASE_PAGE_SIZE as u64);
+                        Ok(NodeResult::Reloaded(true))
+                    }
+                    _ => Ok(NodeResult::Reloaded(false)),
+                }
+            }
+
+            Op::MemReserve(base, size, action) => {
+                let end = base
+                    .as_u64()
+                    .checked_add(size as u64)
+                    .map(VAddr::from)
+                    .ok_or(KError::BaseOverflow {
+                        base: base.as_u64(),
+                    })?;
+                // Overlaps with a reservation that starts below or in it?
+                if let Some((other, (other_size, _action))) =
+                    self.reserved.range((Unbounded, Excluded(end))).next_back()
+                {
+                    if *other + *other_size > base {
+                        return Err(KError::AlreadyMapped { base: *other });
+                    }
+                }
+                // ...or with pages that are mapped (or evicted)?
+                if self.process.vspace().resolve(base).is_ok() {
+                    return Err(KError::AlreadyMapped { base });
+                }
+                if let Some((vaddr, _frame, _action)) = self.process.vspace().next_mapping(base) {
+                    if vaddr < end {
+                        return Err(KError::AlreadyMapped { base: vaddr });
+                    }
+                }
+                if let Some((vaddr, _page)) =
+                    self.anonymous.range((Included(base), Excluded(end))).next()
+                {
+                    return Err(KError::AlreadyMapped { base: *vaddr });
+                }
+
+                self.reserved.try_insert(base, (size, action))?;
+                self.memory.reserved += size as u64;
+                Ok(NodeResult::Reserved)
+            }
+
+            Op::MemUnreserve(base) => {
+                let (size, _action) = self
+                    .reserved
+                    .remove(&base)
+                    .ok_or(KError::NotReserved { base })?;
+                // Only the pages that aren't backed count as reserved
+                let end = base + size;
+                let anonymous = self
+                    .anonymous
+                    .range((Included(base), Excluded(end)))
+                    .count()
+                    * BASE_PAGE_SIZE;
+                let promoted: usize = self
+                    .promoted
+                    .range((Included(base), Excluded(end)))
+                    .map(|(_base, frame)| frame.size())
+                    .sum();
+                let unbacked = size.saturating_sub(anonymous + promoted);
+                self.memory.reserved = self.memory.reserved.saturating_sub(unbacked as u64);
+                Ok(NodeResult::Unreserved)
+            }
+
+            Op::MemCommit(base, frame) => {
+                let action = match self.reserved.range((Unbounded, Included(base))).next_back() {
+                    Some((start, (size, action))) if base + frame.size() <= *start + *size => {
+                        *action
+                    }
+                    _ => return Err(KError::NotReserved { base }),
+                };
+                if self.process.vspace().resolve(base).is_ok() || self.anonymous.contains_key(&base)
+                {
+                    return Ok(NodeResult::Committed(false));
+                }
+
+                self.process.vspace_mut().map_frame(base, frame, action)?;
+                self.account_mapped(base, frame.size());
+                self.memory.reserved = self.memory.reserved.saturating_sub(frame.size() as u64);
+                self.anonymous
+                    .insert(base, AnonymousPage::Mapped(frame, action));
+                Ok(NodeResult::Committed(true))
+            }
+
+            Op::MemPromote(base) => {
+                if self.anonymous_range(base, false).is_none() {
+                    return Err(KError::NotMapped);
+                }
+
+                let mut frames = Vec::try_with_capacity(PROMOTE_PAGES)?;
+                for i in 0..PROMOTE_PAGES {
+                    let vaddr = base + i * BASE_PAGE_SIZE;
+                    if let Some(AnonymousPage::Mapped(frame, action)) =
+                        self.anonymous.get(&vaddr).copied()
+                    {
+                        if let Err(e) = self.process.vspace_mut().unmap(vaddr) {
+                            // Give back the pages that were already unmapped
+                            let _r = self.unpromote(base);
+                            return Err(e);
+                        }
+                        self.anonymous
+                            .insert(vaddr, AnonymousPage::Evicting(frame, action));
+                        frames.push(frame);
+                        self.account(BASE_PAGE_SIZE, false);
+                        self.memory.evicted += BASE_PAGE_SIZE as u64;
+                    }
+                }
+
+                let mut shootdown_handle =
+                    TlbFlushHandle::new(base, Frame::new(PAddr::zero(), LARGE_PAGE_SIZE, 0));
+                for (gtid, _eid) in self.active_cores.iter() {
+                    shootdown_handle.add_core(*gtid);
+                }
+                Ok(NodeResult::Promoting(shootdown_handle, frames))
+            }
+
+            Op::MemPromoteCommit(base, large) => {
+                let action = self.anonymous_range(base, true).ok_or(KError::NotMapped)?;
+
+                match large {
+                    Some(frame) => {
+                        // Nothing changed yet if this fails, the promotion can
+                        // still be abandoned
+                        self.promoted.try_insert(base, frame)?;
+                        if let Err(e) = self.process.vspace_mut().map_frame(base, frame, action) {
+                            self.promoted.remove(&base);
+                            return Err(e);
+                        }
+                        self.account_mapped(base, frame.size());
+                        for i in 0..PROMOTE_PAGES {
+                            self.anonymous.remove(&(base + i * BASE_PAGE_SIZE));
+                        }
+                        self.memory.evicted =
+                            self.memory.evicted.saturating_sub(LARGE_PAGE_SIZE as u64);
+                    }
+                    None => self.unpromote(base)?,
+                }
+                Ok(NodeResult::Promoted)
+            }
+
+            Op::RestoreExecutor(eid, state) => {
+                self.process.restore_executor(eid, *state)?;
+                Ok(NodeResult::ExecutorRestored)
+            }
+
             Op::AssignExecutor(gtid, region) => {
                 let executor = self.process.get_executor(region)?;
                 let eid = executor.id();
@@ -371,6 +1472,20 @@ where
                 Ok(NodeResult::Executor(executor))
             }
 
+            Op::MigrateExecutor(from, eid, to_region, state) => {
+                self.process.migrate_executor(eid, to_region, *state)?;
+                // The old core no longer needs TLB shootdowns for us
+                self.active_cores
+                    .retain(|(gtid, active)| *gtid != from || *active != eid);
+                Ok(NodeResult::ExecutorMigrated)
+            }
+
+            Op::ResumeExecutor(gtid, eid) => {
+                let executor = self.process.resume_executor(eid)?;
+                self.active_cores.try_push((gtid, eid))?;
+                Ok(NodeResult::Executor(executor))
+            }
+
             Op::AllocateFrameToProcess(frame) => {
                 let fid = self.process.add_frame(frame)?;
                 Ok(NodeResult::FrameId(fid))
@@ -378,3 +1493,261 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::arch::process::UnixProcess;
+
+    fn page(i: usize) -> (VAddr, AnonymousPage) {
+        let frame = Frame::new(
+            PAddr::from(0x10_0000 + i * BASE_PAGE_SIZE),
+            BASE_PAGE_SIZE,
+            0,
+        );
+        (
+            VAddr::from(0x4000_0000 + i * BASE_PAGE_SIZE),
+            AnonymousPage::Mapped(frame, MapAction::ReadWriteUser),
+        )
+    }
+
+    /// Adding reserved pages doesn't allocate.
+    #[test]
+    fn reserved_pages_fit() {
+        let mut pages = AnonymousPages::new();
+        pages.try_reserve(4).expect("Can't reserve");
+        let capacity = pages.pages.capacity();
+        assert!(capacity >= 4);
+
+        for i in 0..4 {
+            let (vaddr, p) = page(i);
+            assert_eq!(pages.insert(vaddr, p), None);
+        }
+        assert_eq!(pages.pages.len(), 4);
+        assert_eq!(pages.pages.capacity(), capacity);
+
+        // Replacing a page takes no room
+        let (vaddr, _p) = page(2);
+        let evicted = AnonymousPage::Evicted(MapAction::ReadWriteUser);
+        assert_eq!(pages.insert(vaddr, evicted), Some(page(2).1));
+        assert_eq!(pages.pages.len(), 4);
+        assert_eq!(pages.pages.capacity(), capacity);
+        assert_eq!(pages.get(&vaddr), Some(&evicted));
+    }
+
+    /// Pages stay sorted by their address, no matter in which order they're
+    /// added.
+    #[test]
+    fn sorted_by_address() {
+        let mut pages = AnonymousPages::new();
+        pages.try_reserve(5).expect("Can't reserve");
+        for i in [3, 0, 4, 1, 2].iter() {
+            let (vaddr, p) = page(*i);
+            pages.insert(vaddr, p);
+        }
+
+        let order: Vec<VAddr> = pages.range(..).map(|(vaddr, _page)| *vaddr).collect();
+        let expected: Vec<VAddr> = (0..5).map(|i| page(i).0).collect();
+        assert_eq!(order, expected);
+
+        assert_eq!(pages.remove(&page(1).0), Some(page(1).1));
+        assert_eq!(pages.remove(&page(1).0), None);
+        assert!(!pages.contains_key(&page(1).0));
+        assert!(pages.contains_key(&page(2).0));
+    }
+
+    #[test]
+    fn range_bounds() {
+        let mut pages = AnonymousPages::new();
+        pages.try_reserve(4).expect("Can't reserve");
+        for i in 0..4 {
+            let (vaddr, p) = page(i * 2);
+            pages.insert(vaddr, p);
+        }
+
+        let first = |r: Option<(&VAddr, &AnonymousPage)>| r.map(|(vaddr, _page)| *vaddr);
+        assert_eq!(
+            first(pages.range((Included(page(1).0), Unbounded)).next()),
+            Some(page(2).0)
+        );
+        assert_eq!(
+            first(pages.range((Included(page(2).0), Unbounded)).next()),
+            Some(page(2).0)
+        );
+        assert_eq!(
+            first(pages.range((Excluded(page(2).0), Unbounded)).next()),
+            Some(page(4).0)
+        );
+        assert_eq!(
+            pages
+                .range((Included(page(2).0), Excluded(page(6).0)))
+                .count(),
+            2
+        );
+        assert_eq!(
+            pages
+                .range((Included(page(2).0), Included(page(6).0)))
+                .count(),
+            3
+        );
+        assert_eq!(pages.range((Included(page(7).0), Unbounded)).count(), 0);
+        assert_eq!(
+            pages
+                .range((Included(page(5).0), Excluded(page(1).0)))
+                .count(),
+            0
+        );
+        assert_eq!(
+            first(pages.range((Unbounded, Included(page(5).0))).next_back()),
+            Some(page(4).0)
+        );
+    }
+
+    /// A process with a promotable range of anonymous base pages at `base`
+    /// (the unix address-space maps them in the test process, so every test
+    /// uses its own range).
+    fn promotable_process(base: VAddr) -> NrProcess<UnixProcess> {
+        let da = DA::new().expect("Can't create DA");
+        let mut p = NrProcess::new(Box::new(UnixProcess::default()), da);
+        p.dispatch_mut(Op::MemReserveAnonymous(PROMOTE_PAGES))
+            .expect("Can't reserve");
+        for i in 0..PROMOTE_PAGES {
+            let frame = Frame::new(
+                PAddr::from(0x4000_0000 + i * BASE_PAGE_SIZE),
+                BASE_PAGE_SIZE,
+                0,
+            );
+            p.dispatch_mut(Op::MemMapAnonymous(
+                base + i * BASE_PAGE_SIZE,
+                frame,
+                MapAction::ReadWriteUser,
+            ))
+            .expect("Can't map");
+        }
+        p
+    }
+
+    fn resolve(p: &NrProcess<UnixProcess>, vaddr: VAddr) -> Option<PAddr> {
+        p.process
+            .vspace()
+            .resolve(vaddr)
+            .ok()
+            .map(|(paddr, _rights)| paddr)
+    }
+
+    /// Promoting replaces the base pages with a large page, unmapping one of
+    /// its base pages demotes it again.
+    #[test]
+    fn promote_and_demote() {
+        let base = VAddr::from(0x2000_0000_0000u64);
+        let mut p = promotable_process(base);
+        assert_eq!(p.memory.base_pages, LARGE_PAGE_SIZE as u64);
+
+        let frames = match p.dispatch_mut(Op::MemPromote(base)) {
+            Ok(NodeResult::Promoting(_handle, frames)) => frames,
+            r => panic!("Unexpected result {:?}", r),
+        };
+        assert_eq!(frames.len(), PROMOTE_PAGES);
+        assert_eq!(frames[1].base, PAddr::from(0x4000_0000 + BASE_PAGE_SIZE));
+        assert_eq!(resolve(&p, base), None);
+        assert_eq!(p.memory.evicted, LARGE_PAGE_SIZE as u64);
+
+        let large = Frame::new(PAddr::from(0x8000_0000u64), LARGE_PAGE_SIZE, 0);
+        assert!(p
+            .dispatch_mut(Op::MemPromoteCommit(base, Some(large)))
+            .is_ok());
+        assert_eq!(p.promoted.get(&base), Some(&large));
+        assert!(!p.anonymous.contains_key(&base));
+        assert_eq!(p.memory.large_pages, LARGE_PAGE_SIZE as u64);
+        assert_eq!(p.memory.base_pages, 0);
+        assert_eq!(p.memory.evicted, 0);
+
+        // Unmapping the second base page demotes the large page
+        let vaddr = base + BASE_PAGE_SIZE;
+        assert!(p.dispatch_mut(Op::MemUnmap(vaddr)).is_ok());
+        assert_eq!(p.promoted.get(&base), None);
+        assert_eq!(p.memory.large_pages, 0);
+        assert_eq!(
+            p.memory.base_pages,
+            ((PROMOTE_PAGES - 1) * BASE_PAGE_SIZE) as u64
+        );
+        assert!(!p.anonymous.contains_key(&vaddr));
+        assert_eq!(resolve(&p, vaddr), None);
+        assert_eq!(resolve(&p, base), Some(large.base));
+        assert_eq!(
+            resolve(&p, base + 2 * BASE_PAGE_SIZE),
+            Some(large.base + 2 * BASE_PAGE_SIZE)
+        );
+        assert_eq!(
+            p.anonymous.get(&base),
+            Some(&AnonymousPage::Mapped(
+                Frame::new(large.base, BASE_PAGE_SIZE, 0),
+                MapAction::ReadWriteUser
+            ))
+        );
+    }
+
+    /// An abandoned promotion maps the base pages again.
+    #[test]
+    fn promote_abandoned() {
+        let base = VAddr::from(0x2000_0020_0000u64);
+        let mut p = promotable_process(base);
+
+        assert!(matches!(
+            p.dispatch_mut(Op::MemPromote(base)),
+            Ok(NodeResult::Promoting(..))
+        ));
+        assert_eq!(
+            p.anonymous_range(base, true),
+            Some(MapAction::ReadWriteUser)
+        );
+
+        assert!(p.dispatch_mut(Op::MemPromoteCommit(base, None)).is_ok());
+        assert_eq!(
+            p.anonymous_range(base, false),
+            Some(MapAction::ReadWriteUser)
+        );
+        assert_eq!(p.promoted.get(&base), None);
+        assert_eq!(resolve(&p, base), Some(PAddr::from(0x4000_0000u64)));
+        assert_eq!(p.memory.base_pages, LARGE_PAGE_SIZE as u64);
+        assert_eq!(p.memory.evicted, 0);
+    }
+
+    /// Destroying a process reports all its memory (pages being evicted
+    /// too) and leaves nothing behind.
+    #[test]
+    fn destroy_returns_memory() {
+        let base = VAddr::from(0x2000_0060_0000u64);
+        let mut p = promotable_process(base);
+        assert!(p.dispatch_mut(Op::MemEvict(base)).is_ok());
+
+        let frames = match p.dispatch(ReadOps::OwnedFrames) {
+            Ok(NodeResult::OwnedFrames(frames)) => frames,
+            r => panic!("Unexpected result {:?}", r),
+        };
+        assert_eq!(frames.len(), PROMOTE_PAGES);
+        assert!(frames.contains(&Frame::new(PAddr::from(0x4000_0000u64), BASE_PAGE_SIZE, 0)));
+
+        assert!(matches!(
+            p.dispatch_mut(Op::Destroy),
+            Ok(NodeResult::Destroyed)
+        ));
+        assert_eq!(resolve(&p, base + BASE_PAGE_SIZE), None);
+        assert!(p.anonymous.range(..).next().is_none());
+        assert_eq!(p.memory.base_pages, 0);
+        assert_eq!(p.memory.evicted, 0);
+        assert!(matches!(
+            p.dispatch(ReadOps::OwnedFrames),
+            Ok(NodeResult::OwnedFrames(frames)) if frames.is_empty()
+        ));
+    }
+
+    /// A large page that isn't a promotion can't be demoted.
+    #[test]
+    fn demote_needs_promotion() {
+        let base = VAddr::from(0x2000_0040_0000u64);
+        let mut p = promotable_process(base);
+        assert!(matches!(p.demote(base), Err(KError::NotMapped)));
+        assert_eq!(resolve(&p, base), Some(PAddr::from(0x4000_0000u64)));
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Latency of node-replicated operations (of the kernel and of processes).
+//!
+//! Every core records the time it spends in `execute_mut`
+//! ([`TimedReplica::execute_mut_timed`]) and splits it into the time it
+//! applied operations as the combiner (`dispatch_mut`, see [`applying`]) and
+//! the rest: waiting for the combiner lock or for another core to combine
+//! its operation, and appending to the log. When a core applied operations
+//! during its own `execute_mut` it was the combiner, the number of operations
+//! it applied then is the size of the batch it combined.
+//!
+//! The stats system call prints the numbers for all cores ([`report`]).
+//!
+//! The combiner applies the operations that are posted when it takes the
+//! combiner lock. With `nrbatch=<n>` (see [`init`]) a core that wants to
+//! execute an operation first waits (for at most [`BATCH_WINDOW`] cycles)
+//! until `n` cores execute operations on the same replica, so they are
+//! posted together and combined in one batch. With the default of 1 cores
+//! don't wait.
+
+// Only the x86-64 kernel sets the batch size and reports the numbers
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use log::{info, warn};
+use node_replication::{Dispatch, Replica, ReplicaToken};
+
+use crate::arch::MAX_CORES;
+
+struct CoreStats {
+    /// Operations this core executed.
+    ops: AtomicU64,
+    /// Cycles spent in `execute_mut`.
+    cycles: AtomicU64,
+    /// Cycles spent in `execute_mut` without applying operations.
+    wait_cycles: AtomicU64,
+    /// Operations this core applied to its replica.
+    applied: AtomicU64,
+    /// Cycles spent applying operations.
+    apply_cycles: AtomicU64,
+    /// Times the core was the combiner for its own operation.
+    combines: AtomicU64,
+    /// Operations applied while combining for its own operation.
+    combined: AtomicU64,
+}
+
+impl CoreStats {
+    const fn new() -> Self {
+        CoreStats {
+            ops: AtomicU64::new(0),
+            cycles: AtomicU64::new(0),
+            wait_cycles: AtomicU64::new(0),
+            applied: AtomicU64::new(0),
+            apply_cycles: AtomicU64::new(0),
+            combines: AtomicU64::new(0),
+            combined: AtomicU64::new(0),
+        }
+    }
+}
+
+static STATS: [CoreStats; MAX_CORES] = {
+    const INIT: CoreStats = CoreStats::new();
+    [INIT; MAX_CORES]
+};
+
+/// Operations the combiner should apply at once (`nrbatch=`).
+static BATCH: AtomicUsize = AtomicUsize::new(1);
+
+/// Longest a core waits for a batch to fill up (in cycles).
+pub const BATCH_WINDOW: u64 = 50_000;
+
+/// Number of counters in [`PENDING`].
+const PENDING_SLOTS: usize = 64;
+
+/// Cores that execute an operation on a replica, indexed by the address of
+/// the replica (replicas that share a counter wait for each other).
+static PENDING: [AtomicUsize; PENDING_SLOTS] = {
+    const INIT: AtomicUsize = AtomicUsize::new(0);
+    [INIT; PENDING_SLOTS]
+};
+
+/// Sets the batch size of the combiner from the `nrbatch` argument (a number
+/// of operations between 1 and `MAX_CORES`).
+pub fn init(batch: &str) {
+    match batch.parse::<usize>() {
+        Ok(batch) if batch >= 1 && batch <= MAX_CORES => {
+            BATCH.store(batch, Ordering::Relaxed);
+            info!("NR combiner batch: {} ops", batch);
+        }
+        _ => warn!("Invalid nrbatch={} ignored", batch),
+    }
+}
+
+/// Waits until `batch` cores (including the current one) execute an
+/// operation on the replica of `pending` or [`BATCH_WINDOW`] is over.
+fn gather(pending: &AtomicUsize, batch: usize) {
+    let start = x86::time::rdtsc();
+    while pending.load(Ordering::Relaxed) < batch && x86::time::rdtsc() - start < BATCH_WINDOW {
+        core::hint::spin_loop();
+    }
+}
+
+/// Adds `n` to a counter only the current core writes.
+fn add(counter: &AtomicU64, n: u64) {
+    counter.store(counter.load(Ordering::Relaxed) + n, Ordering::Relaxed);
+}
+
+fn current_core() -> Option<usize> {
+    crate::kcb::try_get_kcb().map(|kcb| kcb.arch.hwthread_id())
+}
+
+/// Records the time spent in `dispatch_mut` until it is dropped.
+pub struct Applying {
+    core: Option<usize>,
+    start: u64,
+}
+
+/// Call at the start of `dispatch_mut`.
+pub fn applying() -> Applying {
+    Applying {
+        core: current_core(),
+        start: x86::time::rdtsc(),
+    }
+}
+
+impl Drop for Applying {
+    fn drop(&mut self) {
+        if let Some(core) = self.core {
+            let stats = &STATS[core];
+            add(&stats.applied, 1);
+            add(&stats.apply_cycles, x86::time::rdtsc() - self.start);
+        }
+    }
+}
+
+/// `Replica::execute_mut` that records how long it took.
+pub trait TimedReplica<D: Dispatch> {
+    fn execute_mut_timed(&self, op: D::WriteOperation, token: ReplicaToken) -> D::Response;
+}
+
+impl<'a, D> TimedReplica<D> for Replica<'a, D>
+where
+    D: Dispatch + Sized + Sync,
+{
+    fn execute_mut_timed(&self, op: D::WriteOperation, token: ReplicaToken) -> D::Response {
+        let core = match current_core() {
+            Some(core) => core,
+            None => return self.execute_mut(op, token),
+        };
+
+        let stats = &STATS[core];
+        let applied = stats.applied.load(Ordering::Relaxed);
+        let apply_cycles = stats.apply_cycles.load(Ordering::Relaxed);
+        let start = x86::time::rdtsc();
+        let batch = BATCH.load(Ordering::Relaxed);
+        let response = if batch > 1 {
+            let pending = &PENDING[(self as *const Self as usize >> 6) % PENDING_SLOTS];
+            if pending.fetch_add(1, Ordering::Relaxed) + 1 < batch {
+                gather(pending, batch);
+            }
+            let response = self.execute_mut(op, token);
+            pending.fetch_sub(1, Ordering::Relaxed);
+            response
+        } else {
+            self.execute_mut(op, token)
+        };
+        let cycles = x86::time::rdtsc() - start;
+        add(&stats.ops, 1);
+        add(&stats.cycles, cycles);
+        add(
+            &stats.wait_cycles,
+            cycles.saturating_sub(stats.apply_cycles.load(Ordering::Relaxed) - apply_cycles),
+        );
+
+        let combined = stats.applied.load(Ordering::Relaxed) - applied;
+        if combined > 0 {
+            add(&stats.combines, 1);
+            add(&stats.combined, combined);
+        }
+
+        response
+    }
+}
+
+/// Prints the stats of every core that executed operations.
+pub fn report() {
+    for (core, stats) in STATS.iter().enumerate() {
+        let ops = stats.ops.load(Ordering::Relaxed);
+        if ops == 0 {
+            continue;
+        }
+
+        let cycles = stats.cycles.load(Ordering::Relaxed);
+        let wait_cycles = stats.wait_cycles.load(Ordering::Relaxed);
+        let applied = stats.applied.load(Ordering::Relaxed);
+        let apply_cycles = stats.apply_cycles.load(Ordering::Relaxed);
+        let combines = stats.combines.load(Ordering::Relaxed);
+        let combined = stats.combined.load(Ordering::Relaxed);
+        info!(
+            "NR core {}: {} ops in {} cycles/op (waiting {} cycles/op), applied {} ops in {} cycles/op, combined {} times (batch {})",
+            core,
+            ops,
+            cycles / ops,
+            wait_cycles / ops,
+            applied,
+            apply_cycles / core::cmp::max(applied, 1),
+            combines,
+            combined / core::cmp::max(combines, 1),
+        );
+    }
+}
@@ -0,0 +1,344 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! What the kernel does when it runs out of memory (*OOM*).
+//!
+//! Every NUMA node keeps a small reserve of pages. When the kernel allocator
+//! can't refill the TCache of a core (even after the retries in
+//! `KernelAllocator::allocate`) it takes the pages from the reserve
+//! ([`use_reserve`]) instead of failing the allocation (which would panic the
+//! kernel), and raises `SoftIrq::Oom`. The soft IRQ runs the OOM killer: it
+//! picks a victim with the [`OomPolicy`] selected by `oom=<name>` on the
+//! kernel command-line, terminates it, gives its memory back and refills the
+//! reserves:
+//!
+//! - `rss` ([`LargestRss`], default): the process with the largest resident
+//!   set.
+//! - `prio` ([`LowestPriority`]): the least important process
+//!   (`Process::set_oom_priority`), the largest one of them.
+//! - `panic` ([`Panic`]): nobody, the kernel panics once the reserve is gone
+//!   (the behavior before there was an OOM killer).
+//!
+//! A process with the `supervise` capability can subscribe to the kills with
+//! `Process::wait_event` (the *supervisor*, e.g., to restart what was
+//! killed), the OOM killer never picks it.
+
+// The OOM killer only runs in the x86-64 kernel
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use arrayvec::ArrayVec;
+use kpi::process::OomPriority;
+use log::{info, warn};
+
+use crate::arch::MAX_NUMA_NODES;
+use crate::error::KError;
+use crate::memory::{Frame, GrowBackend, PhysicalPageProvider};
+use crate::nr;
+use crate::process::{Pid, MAX_PROCESSES};
+use crate::softirq::{self, SoftIrq};
+use crate::sync::Mutex;
+
+/// Base pages in the reserve of every NUMA node.
+pub const RESERVE_BASE_PAGES: usize = 64;
+
+/// Large pages in the reserve of every NUMA node.
+pub const RESERVE_LARGE_PAGES: usize = 2;
+
+/// How many kills we keep for the supervisor.
+const MAX_EVENTS: usize = 16;
+
+/// A process as seen by [`OomPolicy::select`].
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Candidate {
+    pub pid: Pid,
+    /// Bytes of memory mapped in its address-space.
+    pub resident: u64,
+    pub priority: OomPriority,
+}
+
+pub trait OomPolicy: Sync {
+    /// Name of the policy on the command-line.
+    fn name(&self) -> &'static str;
+
+    /// Picks the process to kill out of `candidates` (all live processes
+    /// except the supervisor), `None` to kill nobody.
+    fn select(&self, candidates: &[Candidate]) -> Option<Pid>;
+}
+
+/// Kills the process that frees the most memory.
+pub struct LargestRss;
+
+impl OomPolicy for LargestRss {
+    fn name(&self) -> &'static str {
+        "rss"
+    }
+
+    fn select(&self, candidates: &[Candidate]) -> Option<Pid> {
+        // The first one on ties
+        candidates
+            .iter()
+            .rev()
+            .max_by_key(|c| c.resident)
+            .map(|c| c.pid)
+    }
+}
+
+/// Kills the least important process (the largest one if there are
+/// several).
+pub struct LowestPriority;
+
+impl OomPolicy for LowestPriority {
+    fn name(&self) -> &'static str {
+        "prio"
+    }
+
+    fn select(&self, candidates: &[Candidate]) -> Option<Pid> {
+        let lowest = candidates.iter().map(|c| c.priority).min()?;
+        let eligible: ArrayVec<Candidate, MAX_PROCESSES> = candidates
+            .iter()
+            .filter(|c| c.priority == lowest)
+            .copied()
+            .collect();
+        LargestRss.select(&eligible)
+    }
+}
+
+/// Never kills anything.
+pub struct Panic;
+
+impl OomPolicy for Panic {
+    fn name(&self) -> &'static str {
+        "panic"
+    }
+
+    fn select(&self, _candidates: &[Candidate]) -> Option<Pid> {
+        None
+    }
+}
+
+/// All policies that can be selected on the command-line.
+static POLICIES: [&dyn OomPolicy; 3] = [&LargestRss, &LowestPriority, &Panic];
+
+static POLICY: spin::Once<&'static dyn OomPolicy> = spin::Once::new();
+
+/// Selects the policy with the given `name` (falls back to [`LargestRss`]
+/// if there is none).
+pub fn init(name: &str) {
+    let policy = POLICIES
+        .iter()
+        .find(|p| p.name() == name)
+        .copied()
+        .unwrap_or_else(|| {
+            warn!("Unknown oom={} ignored", name);
+            &LargestRss
+        });
+    info!("OOM policy: {}", policy.name());
+    POLICY.call_once(|| policy);
+}
+
+/// The policy in use.
+pub fn current() -> &'static dyn OomPolicy {
+    POLICY.get().copied().unwrap_or(&LargestRss)
+}
+
+/// Pages put aside for when a NUMA node runs out of memory.
+struct Reserve {
+    base: ArrayVec<Frame, RESERVE_BASE_PAGES>,
+    large: ArrayVec<Frame, RESERVE_LARGE_PAGES>,
+}
+
+impl Reserve {
+    const fn new() -> Reserve {
+        Reserve {
+            base: ArrayVec::new_const(),
+            large: ArrayVec::new_const(),
+        }
+    }
+}
+
+static RESERVES: [Mutex<Reserve>; MAX_NUMA_NODES] = {
+    const EMPTY: Mutex<Reserve> = Mutex::new(Reserve::new());
+    [EMPTY; MAX_NUMA_NODES]
+};
+
+/// How often the kernel allocator had to use a reserve.
+static RESERVE_USED: AtomicU64 = AtomicU64::new(0);
+
+/// How many processes the OOM killer killed.
+static KILLED: AtomicU64 = AtomicU64::new(0);
+
+/// The process that gets the kills (pid and generation).
+static SUPERVISOR: Mutex<Option<(Pid, u64)>> = Mutex::new(None);
+
+/// Kills the supervisor didn't pick up yet (oldest first).
+static EVENTS: Mutex<ArrayVec<Pid, MAX_EVENTS>> = Mutex::new(ArrayVec::new_const());
+
+/// Returns how often a reserve was used and how many processes were killed.
+pub fn stats() -> (u64, u64) {
+    (
+        RESERVE_USED.load(Ordering::Relaxed),
+        KILLED.load(Ordering::Relaxed),
+    )
+}
+
+/// Tops up the reserves of all NUMA nodes from their NCaches.
+///
+/// Returns `true` if all of them are full.
+pub fn fill_reserves() -> bool {
+    let gmanager = match crate::kcb::try_get_kcb().and_then(|kcb| kcb.physical_memory.gmanager) {
+        Some(gmanager) => gmanager,
+        None => return false,
+    };
+
+    let mut full = true;
+    for (reserve, ncache) in RESERVES.iter().zip(gmanager.node_caches.iter()) {
+        let mut reserve = reserve.lock();
+        let mut ncache = ncache.lock();
+        while !reserve.base.is_full() {
+            match ncache.allocate_base_page() {
+                Ok(frame) => reserve.base.push(frame),
+                Err(_e) => break,
+            }
+        }
+        while !reserve.large.is_full() {
+            match ncache.allocate_large_page() {
+                Ok(frame) => reserve.large.push(frame),
+                Err(_e) => break,
+            }
+        }
+        full &= reserve.base.is_full() && reserve.large.is_full();
+    }
+    full
+}
+
+/// Moves pages from the reserve of the core's NUMA node to its TCache and
+/// raises `SoftIrq::Oom` to free memory.
+///
+/// The kernel allocator calls this when it can't refill the TCache
+/// otherwise, fails if the reserve doesn't have enough pages left.
+pub fn use_reserve(base_pages: usize, large_pages: usize) -> Result<(), KError> {
+    let kcb = crate::kcb::try_get_kcb().ok_or(KError::KcbUnavailable)?;
+    softirq::raise(SoftIrq::Oom);
+
+    let node = kcb.physical_memory.affinity as usize;
+    let mut reserve = RESERVES.get(node).ok_or(KError::OutOfMemory)?.lock();
+    if reserve.base.len() < base_pages || reserve.large.len() < large_pages {
+        return Err(KError::OutOfMemory);
+    }
+
+    let mut mem_manager = kcb.try_mem_manager()?;
+    // Make sure we don't overflow the TCache
+    let base_pages = core::cmp::min(mem_manager.spare_base_page_capacity(), base_pages);
+    let large_pages = core::cmp::min(mem_manager.spare_large_page_capacity(), large_pages);
+    for frame in reserve.base.drain(..base_pages) {
+        mem_manager
+            .grow_base_pages(&[frame])
+            .expect("We ensure to not overfill the TCache above.");
+    }
+    for frame in reserve.large.drain(..large_pages) {
+        mem_manager
+            .grow_large_pages(&[frame])
+            .expect("We ensure to not overfill the TCache above.");
+    }
+    drop(mem_manager);
+    drop(reserve);
+
+    RESERVE_USED.fetch_add(1, Ordering::Relaxed);
+    warn!("Out of memory on node {}, using the reserve", node);
+    Ok(())
+}
+
+/// Makes `pid` the supervisor, fails if another live process already is.
+pub fn subscribe(pid: Pid) -> Result<(), KError> {
+    let generation = nr::KernelNode::process_generation(pid)?;
+    let mut supervisor = SUPERVISOR.lock();
+    match *supervisor {
+        Some(current) if current == (pid, generation) => Ok(()),
+        Some((other, other_generation)) if nr::KernelNode::is_alive(other, other_generation) => {
+            Err(KError::SupervisorExists { pid: other })
+        }
+        _ => {
+            info!("Process {} supervises the OOM killer", pid);
+            *supervisor = Some((pid, generation));
+            EVENTS.lock().clear();
+            Ok(())
+        }
+    }
+}
+
+/// The supervisor (if it is still alive).
+pub fn supervisor() -> Option<Pid> {
+    let supervisor = *SUPERVISOR.lock();
+    supervisor
+        .filter(|(pid, generation)| nr::KernelNode::is_alive(*pid, *generation))
+        .map(|(pid, _generation)| pid)
+}
+
+/// Takes the oldest kill the supervisor `pid` didn't pick up yet.
+pub fn next_event(pid: Pid) -> Result<Option<Pid>, KError> {
+    let generation = nr::KernelNode::process_generation(pid)?;
+    if *SUPERVISOR.lock() != Some((pid, generation)) {
+        return Err(KError::NotSupervisor);
+    }
+
+    let mut events = EVENTS.lock();
+    if events.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(events.remove(0)))
+    }
+}
+
+/// Records that the OOM killer killed `victim` (for the supervisor).
+pub fn killed(victim: Pid) {
+    KILLED.fetch_add(1, Ordering::Relaxed);
+    if supervisor().is_none() {
+        return;
+    }
+
+    let mut events = EVENTS.lock();
+    if events.is_full() {
+        let dropped = events.remove(0);
+        warn!("Supervisor didn't pick up the OOM kill of {}", dropped);
+    }
+    events.push(victim);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn candidate(pid: Pid, resident: u64, priority: OomPriority) -> Candidate {
+        Candidate {
+            pid,
+            resident,
+            priority,
+        }
+    }
+
+    #[test]
+    fn largest_rss() {
+        let candidates = [
+            candidate(1, 10, OomPriority::Normal),
+            candidate(2, 30, OomPriority::Important),
+            candidate(3, 30, OomPriority::Normal),
+        ];
+        assert_eq!(LargestRss.select(&candidates), Some(2));
+        assert_eq!(LargestRss.select(&[]), None);
+    }
+
+    #[test]
+    fn lowest_priority() {
+        let candidates = [
+            candidate(1, 10, OomPriority::Normal),
+            candidate(2, 30, OomPriority::Important),
+            candidate(3, 5, OomPriority::Expendable),
+            candidate(4, 20, OomPriority::Expendable),
+        ];
+        assert_eq!(LowestPriority.select(&candidates), Some(4));
+        assert_eq!(LowestPriority.select(&candidates[..2]), Some(1));
+        assert_eq!(Panic.select(&candidates), None);
+    }
+}
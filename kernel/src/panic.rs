@@ -11,15 +11,30 @@ use crate::arch;
 use crate::kcb;
 #[cfg(target_os = "none")]
 use crate::ExitReason;
+#[cfg(feature = "addr2line")]
 use addr2line::{gimli, Context};
+#[cfg(feature = "addr2line")]
 use alloc::rc::Rc;
+use core::ffi::c_void;
 use klogger::{sprint, sprintln};
 
 //pub type EndianRcSlice<gimli::Endian> = gimli::EndianReader<gimli::Endian, Rc<[u8]>>;
 
-fn new_ctxt(
-    file: &elfloader::ElfBinary,
-) -> Option<Context<gimli::EndianRcSlice<gimli::RunTimeEndian>>> {
+/// Debug information of a binary, symbolizes the frames of a backtrace.
+#[cfg(feature = "addr2line")]
+pub(crate) type DebugContext = Context<gimli::EndianRcSlice<gimli::RunTimeEndian>>;
+
+/// Without `addr2line` frames aren't symbolized, there is never a context.
+#[cfg(not(feature = "addr2line"))]
+pub(crate) enum DebugContext {}
+
+#[cfg(not(feature = "addr2line"))]
+pub(crate) fn new_ctxt(_file: &elfloader::ElfBinary) -> Option<DebugContext> {
+    None
+}
+
+#[cfg(feature = "addr2line")]
+pub(crate) fn new_ctxt(file: &elfloader::ElfBinary) -> Option<DebugContext> {
     let endian = gimli::RunTimeEndian::Little;
 
     fn load_section<S, Endian>(elf: &elfloader::ElfBinary, endian: Endian) -> S
@@ -64,14 +79,24 @@ fn new_ctxt(
 }
 
 fn backtrace_format(
-    context: Option<&Context<gimli::EndianRcSlice<gimli::RunTimeEndian>>>,
+    context: Option<&DebugContext>,
     relocated_offset: u64,
     count: usize,
     frame: &backtracer_core::Frame,
 ) -> bool {
-    let ip = frame.ip();
+    backtrace_format_ip(context, relocated_offset, count, frame.ip())
+}
+
+fn backtrace_format_ip(
+    context: Option<&DebugContext>,
+    relocated_offset: u64,
+    count: usize,
+    ip: *mut c_void,
+) -> bool {
     sprint!("frame #{:<2} - {:#02$x}", count, ip as usize, 20);
     let mut resolved = false;
+    #[cfg(not(feature = "addr2line"))]
+    let context = context.map(|c| match *c {});
 
     let _r = backtracer_core::resolve(context, relocated_offset, ip, |symbol| {
         if !resolved {
@@ -173,6 +198,80 @@ pub fn backtrace() {
     }
 }
 
+/// Most frames we print for a user-space backtrace.
+#[cfg(target_os = "none")]
+const MAX_USER_FRAMES: usize = 64;
+
+/// Prints a backtrace of process `pid` that stopped at `rip` (with frame
+/// pointer `rbp`), symbolized with the debug info of the ELF `binary` it
+/// was loaded from at `offset`.
+///
+/// Follows the frame pointers on the user stack, so it's only complete if
+/// the process was compiled with them. Every frame is validated before it
+/// is read, a corrupted stack ends the backtrace early.
+#[cfg(target_os = "none")]
+pub fn backtrace_user(pid: crate::process::Pid, binary: &[u8], offset: u64, rbp: u64, rip: u64) {
+    use crate::arch::process::validate_user_range;
+    use crate::arch::usercopy::read_user;
+
+    sprintln!("User backtrace (pid {}):", pid);
+    let elf_binary = elfloader::ElfBinary::new(binary).ok();
+    let context = elf_binary.as_ref().and_then(new_ctxt);
+
+    let mut count = 1;
+    backtrace_format_ip(context.as_ref(), offset, count, rip as *mut c_void);
+
+    let mut fp = rbp;
+    while count < MAX_USER_FRAMES {
+        // A frame is the caller's frame pointer followed by the return address
+        if fp == 0 || fp % 8 != 0 || validate_user_range(pid, fp, 16, false).is_err() {
+            break;
+        }
+        let (next, ret) = match (read_user::<u64>(fp), read_user::<u64>(fp + 8)) {
+            (Ok(next), Ok(ret)) => (next, ret),
+            _ => break,
+        };
+        if ret == 0 {
+            break;
+        }
+
+        count += 1;
+        backtrace_format_ip(context.as_ref(), offset, count, ret as *mut c_void);
+        // The stack grows down, callers have frames at higher addresses
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+}
+
+/// Prints a single (previously recorded) backtrace frame at address `ip`.
+#[allow(unused)]
+pub fn backtrace_ip(count: usize, ip: *mut c_void) {
+    let kernel_info = kcb::try_get_kcb().map(|k| {
+        (
+            k.kernel_binary(),
+            k.arch.kernel_args().kernel_elf_offset.as_u64(),
+        )
+    });
+
+    match kernel_info {
+        Some((elf_data, relocated_offset)) => match elfloader::ElfBinary::new(elf_data) {
+            Ok(elf_binary) => {
+                let context = new_ctxt(&elf_binary);
+                backtrace_format_ip(context.as_ref(), relocated_offset, count, ip);
+                core::mem::forget(context);
+            }
+            Err(_e) => {
+                backtrace_format_ip(None, relocated_offset, count, ip);
+            }
+        },
+        None => {
+            backtrace_format_ip(None, 0x0, count, ip);
+        }
+    }
+}
+
 #[allow(unused)]
 #[inline(always)]
 pub fn backtrace_no_context() {
@@ -191,6 +290,10 @@ pub fn backtrace_no_context() {
 #[cfg_attr(target_os = "none", panic_handler)]
 #[no_mangle]
 pub fn panic_impl(info: &PanicInfo) -> ! {
+    // Doesn't return if a subsystem that can fail on its own panicked
+    crate::domain::recover(info);
+
+    arch::splash::fail();
     sprint!(
         "System panic encountered (On H/W thread {})",
         atopology::MACHINE_TOPOLOGY.current_thread().id
@@ -204,11 +307,14 @@ pub fn panic_impl(info: &PanicInfo) -> ! {
     } else {
         sprintln!("");
     }
+    sprintln!("Kernel build: {}", crate::build_info::BUILD_ID);
+    // Only the log gets to the disk, not the serial console (see `shutdown`)
+    crate::disklog::mirror(format_args!("System panic: {}\n", info));
 
     // We need memory allocation for a backtrace, can't do that without a KCB
     kcb::try_get_kcb().map(|k| {
         // If we're already panicking, it usually doesn't help to panic more
-        if !k.in_panic_mode {
+        if !k.in_panic_mode() {
             // Make sure we use the e{early, emergency} memory allocator for backtracing
             // (if we have a panic with the memory manager already borrowed
             // we can't use it because it will just trigger another panic)
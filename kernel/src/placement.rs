@@ -0,0 +1,115 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Placement of the kernel and file-system replicas on the NUMA nodes.
+//!
+//! By default every NUMA node (as reported by the ACPI SRAT table) gets its
+//! own replica. `replicas=<n>` on the kernel command-line asks for `n`
+//! replicas instead (`replicas=one` for a single, global replica), the nodes
+//! are then split into `n` groups of neighbouring nodes that share a replica.
+//! We never create more replicas than there are nodes.
+
+// Only the x86-64 kernel boots more than one core
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use log::warn;
+
+/// How many replicas the system should have.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ReplicaPlacement {
+    /// One replica per NUMA node.
+    PerNode,
+    /// A fixed number of replicas.
+    Fixed(usize),
+}
+
+impl ReplicaPlacement {
+    /// Parses the `replicas` argument of the kernel command-line.
+    pub fn from_arg(arg: &str) -> ReplicaPlacement {
+        match arg {
+            "node" => ReplicaPlacement::PerNode,
+            "one" => ReplicaPlacement::Fixed(1),
+            n => match n.parse::<usize>() {
+                Ok(n) if n > 0 => ReplicaPlacement::Fixed(n),
+                _ => {
+                    warn!("Invalid replicas={} ignored", arg);
+                    ReplicaPlacement::PerNode
+                }
+            },
+        }
+    }
+
+    /// Number of replicas on a machine with `nodes` NUMA nodes.
+    pub fn replicas(&self, nodes: usize) -> usize {
+        let nodes = core::cmp::max(1, nodes);
+        match *self {
+            ReplicaPlacement::PerNode => nodes,
+            ReplicaPlacement::Fixed(n) => core::cmp::min(n, nodes),
+        }
+    }
+}
+
+/// The replica that cores of `node` use (with `replicas` replicas on
+/// `nodes` NUMA nodes).
+pub fn replica_of(node: usize, nodes: usize, replicas: usize) -> usize {
+    let nodes = core::cmp::max(1, nodes);
+    core::cmp::min(node, nodes - 1) * replicas / nodes
+}
+
+/// The NUMA node that holds the memory of `replica` (the first node of its
+/// group).
+pub fn home_node(replica: usize, nodes: usize, replicas: usize) -> usize {
+    let nodes = core::cmp::max(1, nodes);
+    (replica * nodes + replicas - 1) / replicas
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_placement() {
+        assert_eq!(
+            ReplicaPlacement::from_arg("node"),
+            ReplicaPlacement::PerNode
+        );
+        assert_eq!(
+            ReplicaPlacement::from_arg("one"),
+            ReplicaPlacement::Fixed(1)
+        );
+        assert_eq!(ReplicaPlacement::from_arg("2"), ReplicaPlacement::Fixed(2));
+        assert_eq!(ReplicaPlacement::from_arg("0"), ReplicaPlacement::PerNode);
+        assert_eq!(ReplicaPlacement::from_arg("x"), ReplicaPlacement::PerNode);
+    }
+
+    #[test]
+    fn replica_count() {
+        assert_eq!(ReplicaPlacement::PerNode.replicas(0), 1);
+        assert_eq!(ReplicaPlacement::PerNode.replicas(4), 4);
+        assert_eq!(ReplicaPlacement::Fixed(2).replicas(4), 2);
+        assert_eq!(ReplicaPlacement::Fixed(8).replicas(4), 4);
+    }
+
+    #[test]
+    fn groups_of_nodes() {
+        // Every node and replica maps to itself by default
+        for node in 0..4 {
+            assert_eq!(replica_of(node, 4, 4), node);
+            assert_eq!(home_node(node, 4, 4), node);
+        }
+
+        // Neighbouring nodes share a replica, which lives on the first one
+        let replicas: [usize; 8] = [0, 0, 0, 1, 1, 1, 2, 2];
+        for (node, replica) in replicas.iter().enumerate() {
+            assert_eq!(replica_of(node, 8, 3), *replica);
+        }
+        assert_eq!(home_node(0, 8, 3), 0);
+        assert_eq!(home_node(1, 8, 3), 3);
+        assert_eq!(home_node(2, 8, 3), 6);
+
+        for node in 0..4 {
+            assert_eq!(replica_of(node, 4, 1), 0);
+        }
+        assert_eq!(replica_of(0, 0, 1), 0);
+    }
+}
@@ -10,23 +10,26 @@ use core::convert::{TryFrom, TryInto};
 use core::fmt::Debug;
 
 use arrayvec::ArrayVec;
-use cstr_core::CStr;
 use fallible_collections::vec::FallibleVecGlobal;
 use fallible_collections::vec::TryCollect;
+use fallible_collections::FallibleVec;
 use fallible_collections::TryReserveError;
-use kpi::process::{FrameId, ELF_OFFSET};
+use kpi::process::{FrameId, ELF_OFFSET, EXECUTOR_OFFSET};
 use log::{debug, info, trace};
 
-use crate::arch::memory::{paddr_to_kernel_vaddr, LARGE_PAGE_SIZE};
-use crate::arch::process::UserPtr;
+use crate::arch::memory::{paddr_to_kernel_vaddr, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+use crate::arch::process::validate_user_range;
+use crate::arch::usercopy::{copy_from_user, read_user};
 use crate::arch::{Module, MAX_CORES, MAX_NUMA_NODES};
 use crate::error::KError;
 use crate::fallible_string::TryString;
 use crate::fs::Fd;
 use crate::memory::vspace::AddressSpace;
 use crate::memory::{Frame, KernelAllocator, PhysicalPageProvider, VAddr};
+use crate::nrstats::TimedReplica;
 use crate::prelude::overlaps;
-use crate::{cnrfs, kcb, nr, nrproc, round_up};
+use crate::sync::Mutex;
+use crate::{cnrfs, cputime, elfcheck, entropy, kcb, nr, nrproc, round_up, strace, ulog};
 
 /// How many (concurrent) processes the systems supports.
 pub const MAX_PROCESSES: usize = 12;
@@ -37,6 +40,13 @@ pub const MAX_FRAMES_PER_PROCESS: usize = MAX_CORES;
 /// How many writable sections a process can have (part of the ELF file).
 pub const MAX_WRITEABLE_SECTIONS_PER_PROCESS: usize = 4;
 
+/// Maximum size of a buffer a process can pass to a system call.
+pub const MAX_USER_BUFFER_LEN: usize = 32 * 1024 * 1024;
+
+/// Maximum length of a (NUL-terminated) string a process can pass to a
+/// system call (e.g., a path).
+pub const MAX_USER_STR_LEN: usize = 4096;
+
 /// This struct is used to copy the user buffer into kernel space, so that the
 /// user-application doesn't have any reference to any log operation in kernel space.
 #[derive(PartialEq, Clone, Debug)]
@@ -45,32 +55,49 @@ pub struct KernSlice {
 }
 
 impl KernSlice {
-    pub fn new(base: u64, len: usize) -> KernSlice {
+    pub fn new(base: u64, len: usize) -> Result<KernSlice, KError> {
         let buffer = Arc::<[u8]>::new_uninit_slice(len);
         let mut buffer = unsafe { buffer.assume_init() };
-
-        let mut user_ptr = VAddr::from(base);
-        let slice_ptr = UserPtr::new(&mut user_ptr);
-        let user_slice: &mut [u8] =
-            unsafe { core::slice::from_raw_parts_mut(slice_ptr.as_mut_ptr(), len) };
-        unsafe { Arc::get_mut_unchecked(&mut buffer).copy_from_slice(&user_slice[0..len]) };
-        KernSlice { buffer }
+        copy_from_user(unsafe { Arc::get_mut_unchecked(&mut buffer) }, base)?;
+        Ok(KernSlice { buffer })
     }
 }
 
-pub fn userptr_to_str(useraddr: u64) -> Result<String, KError> {
-    let mut user_ptr = VAddr::from(useraddr);
-    let str_ptr = UserPtr::new(&mut user_ptr);
-    unsafe {
-        match CStr::from_ptr(str_ptr.as_ptr()).to_str() {
-            Ok(path) => {
-                if !path.is_ascii() || path.is_empty() {
-                    return Err(KError::NotSupported);
-                }
-                Ok(TryString::try_from(path)?.into())
+/// Copies a NUL-terminated string from the address space of `pid` into
+/// kernel memory.
+///
+/// Every page the string touches is checked to be mapped for user-space
+/// before we read it, the string can be at most `MAX_USER_STR_LEN` bytes.
+pub fn userptr_to_str(pid: Pid, useraddr: u64) -> Result<String, KError> {
+    let mut bytes: Vec<u8> = Vec::try_with_capacity(MAX_USER_STR_LEN)?;
+    loop {
+        let addr = useraddr
+            .checked_add(bytes.len() as u64)
+            .ok_or(KError::BadAddress)?;
+        if bytes.is_empty() || addr % BASE_PAGE_SIZE as u64 == 0 {
+            validate_user_range(pid, addr, 1, false)?;
+        }
+
+        let byte: u8 = read_user(addr)?;
+        if byte == 0 {
+            break;
+        }
+        if bytes.len() == MAX_USER_STR_LEN {
+            return Err(KError::UserBufferTooLarge {
+                len: MAX_USER_STR_LEN + 1,
+            });
+        }
+        bytes.push(byte);
+    }
+
+    match core::str::from_utf8(bytes.as_slice()) {
+        Ok(path) => {
+            if !path.is_ascii() || path.is_empty() {
+                return Err(KError::NotSupported);
             }
-            Err(_) => Err(KError::NotSupported),
+            Ok(TryString::try_from(path)?.into())
         }
+        Err(_) => Err(KError::NotSupported),
     }
 }
 
@@ -85,11 +112,13 @@ pub trait Process {
     type E: Executor + Copy + Sync + Send + Debug + PartialEq;
     type A: AddressSpace;
 
+    /// Loads the ELF binary `module` at `offset` (zero for non-PIE binaries).
     fn load(
         &mut self,
         pid: Pid,
         module: &Module,
         writable_sections: Vec<Frame>,
+        offset: VAddr,
     ) -> Result<(), KError>
     where
         Self: core::marker::Sized;
@@ -101,12 +130,32 @@ pub trait Process {
     ) -> Result<(), alloc::collections::TryReserveError>;
     fn allocate_executors(&mut self, frame: Frame) -> Result<usize, KError>;
 
+    /// The region (base and size in bytes) the last call to
+    /// [`Process::load`] or [`Process::allocate_executors`] mapped.
+    fn last_mapped(&self) -> (VAddr, usize);
+
     fn vspace_mut(&mut self) -> &mut Self::A;
 
     fn vspace(&self) -> &Self::A;
 
     fn get_executor(&mut self, for_region: atopology::NodeId) -> Result<Box<Self::E>, KError>;
 
+    /// Makes the next executor returned by `get_executor` resume from
+    /// `state` (preferrably the one with id `eid`).
+    fn restore_executor(&mut self, eid: Eid, state: kpi::arch::SaveArea) -> Result<(), KError>;
+
+    /// Moves the (running) executor `eid` to the NUMA node `to_region`, it
+    /// resumes from `state` once `resume_executor` hands it to a core there.
+    fn migrate_executor(
+        &mut self,
+        eid: Eid,
+        to_region: atopology::NodeId,
+        state: kpi::arch::SaveArea,
+    ) -> Result<(), KError>;
+
+    /// Returns the executor `eid` (after it was migrated).
+    fn resume_executor(&mut self, eid: Eid) -> Result<Box<Self::E>, KError>;
+
     fn allocate_fd(&mut self) -> Option<(u64, &mut Fd)>;
 
     fn deallocate_fd(&mut self, fd: usize) -> Result<usize, KError>;
@@ -118,6 +167,17 @@ pub trait Process {
     fn add_frame(&mut self, frame: Frame) -> Result<FrameId, KError>;
     fn get_frame(&mut self, frame_id: FrameId) -> Result<Frame, KError>;
     fn deallocate_frame(&mut self, fid: FrameId) -> Result<Frame, KError>;
+
+    /// Adds the memory the process owns and shares with its other replicas
+    /// (what it maps and the frames registered to it) to `frames`.
+    fn owned_frames(&self, frames: &mut Vec<Frame>) -> Result<(), KError>;
+
+    /// Drops the address space, executors and file descriptors of the
+    /// process so its pid can be reused.
+    ///
+    /// Memory the replica allocated for itself is freed, what
+    /// [`Process::owned_frames`] returned is up to the caller.
+    fn destroy(&mut self);
 }
 
 /// ResumeHandle is the HW specific logic that switches the CPU
@@ -193,7 +253,7 @@ impl elfloader::ElfLoader for DataSecAllocator {
                 let large_pages = size_page / LARGE_PAGE_SIZE;
                 KernelAllocator::try_refill_tcache(0, large_pages).expect("Refill didn't work");
 
-                let kcb = crate::kcb::get_kcb();
+                let kcb = crate::kcb::per_core();
                 let mut pmanager = kcb.mem_manager();
                 for i in 0..large_pages {
                     let frame = pmanager
@@ -331,28 +391,162 @@ impl elfloader::ElfLoader for DataSecAllocator {
     }
 }
 
+/// How far up from `ELF_OFFSET` we (randomly) move PIE binaries.
+const ELF_SLIDE_MAX: usize = 0x8000_0000;
+// Leave at least as much room for the binary itself.
+static_assertions::const_assert!(ELF_OFFSET + 2 * ELF_SLIDE_MAX <= EXECUTOR_OFFSET);
+
+/// A random, large-page aligned offset for the ELF binary (ASLR).
+///
+/// Has to be large-page aligned since we map the binary with large pages.
+fn elf_slide() -> usize {
+    (entropy::next_u64() as usize % (ELF_SLIDE_MAX / LARGE_PAGE_SIZE)) * LARGE_PAGE_SIZE
+}
+
+/// The binary that replaces a module (see [`replace_module`]).
+pub enum Binary {
+    /// Another module (modules stay mapped in the kernel address space).
+    Module(&'static Module),
+    /// An ELF file that was read into kernel memory.
+    File(Vec<u8>),
+}
+
+impl Binary {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            // Safe: Modules stay mapped in the kernel address space
+            Binary::Module(module) => unsafe { module.as_slice() },
+            Binary::File(image) => image.as_slice(),
+        }
+    }
+}
+
+/// A module that was replaced at run-time.
+struct Replacement {
+    /// Describes `binary` (handed out by [`find_module`]).
+    module: Box<Module>,
+    binary: Binary,
+    /// Undone by [`restore_module`].
+    restored: bool,
+}
+
+/// Binaries that replaced modules at run-time (see [`replace_module`]).
+///
+/// A replacement is dropped once a newer one (or the original) is used for
+/// its name again and no process runs it anymore
+/// ([`release_replaced`]).
+static REPLACED: Mutex<Vec<Replacement>> = Mutex::new(Vec::new());
+
+/// The binary called `name`: the module the bootloader passed us unless it
+/// was replaced (by the latest replacement).
+pub fn find_module(name: &str) -> Option<&'static Module> {
+    let replaced = REPLACED
+        .lock()
+        .iter()
+        .rev()
+        .find(|r| !r.restored && r.module.name() == name)
+        // Safe: Replacements are only dropped when no process uses them
+        .map(|r| unsafe { &*(&*r.module as *const Module) });
+    replaced.or_else(|| {
+        kcb::per_core()
+            .arch
+            .kernel_args()
+            .modules
+            .iter()
+            .rev()
+            .find(|module| module.name() == name)
+    })
+}
+
+/// Makes the ELF binary `binary` the binary called `name` for all
+/// processes created from now on (`ProcessOperation::Reload`).
+///
+/// Processes that ran the old binary still refer to it, it's kept until
+/// they're gone (see [`release_replaced`]).
+pub fn replace_module(name: &str, binary: Binary) -> Result<(), KError> {
+    let image = binary.as_slice();
+    elfcheck::verify(name, image)?;
+    elfloader::ElfBinary::new(image).map_err(|_e| KError::UnableToParseElf)?;
+
+    // The contents of `binary` don't move along with it
+    let vaddr = VAddr::from(image.as_ptr() as u64);
+    let module = Box::try_new(Module::new(
+        name,
+        vaddr,
+        crate::arch::memory::kernel_vaddr_to_paddr(vaddr),
+        image.len(),
+    ))?;
+    let mut replaced = REPLACED.lock();
+    FallibleVec::try_reserve(&mut *replaced, 1)?;
+    replaced.push(Replacement {
+        module,
+        binary,
+        restored: false,
+    });
+    Ok(())
+}
+
+/// Undoes the latest [`replace_module`] of `name`, e.g., if the new binary
+/// can't be started.
+pub fn restore_module(name: &str) {
+    let mut replaced = REPLACED.lock();
+    if let Some(r) = replaced
+        .iter_mut()
+        .rev()
+        .find(|r| !r.restored && r.module.name() == name)
+    {
+        r.restored = true;
+    }
+}
+
+/// Drops the replaced binaries that aren't used anymore: they were replaced
+/// again (or restored) and none of the live processes runs them.
+pub fn release_replaced<P: Process>() {
+    let processes = match nr::KernelNode::processes() {
+        Ok(processes) => processes,
+        Err(_e) => return,
+    };
+
+    let mut replaced = REPLACED.lock();
+    let mut idx = 0;
+    while idx < replaced.len() {
+        let r = &replaced[idx];
+        let superseded = r.restored
+            || replaced[idx + 1..]
+                .iter()
+                .any(|newer| !newer.restored && newer.module.name() == r.module.name());
+        let running = processes.iter().any(|(pid, _generation)| {
+            nrproc::NrProcess::<P>::module(*pid)
+                .map_or(false, |(module, _offset)| core::ptr::eq(module, &*r.module))
+        });
+
+        if superseded && !running {
+            debug!("Releasing replaced binary {}", r.module.name());
+            replaced.remove(idx);
+        } else {
+            idx += 1;
+        }
+    }
+}
+
 /// Create a new process
 ///
 /// Parse & relocate ELF
 /// Create an initial VSpace
-pub fn make_process<P: Process>(binary: &'static str) -> Result<Pid, KError> {
+/// Confine the process to the file-system sub-tree at `root`
+pub fn make_process<P: Process>(binary: &'static str, root: &str) -> Result<Pid, KError> {
     KernelAllocator::try_refill_tcache(7, 1)?;
-    let kcb = kcb::get_kcb();
+    let kcb = kcb::per_core();
 
     // Lookup binary of the process
-    let mut mod_file = None;
-    for module in &kcb.arch.kernel_args().modules {
-        if module.name() == binary {
-            mod_file = Some(module);
-        }
-    }
-
-    let mod_file = mod_file.ok_or(KError::BinaryNotFound { binary })?;
+    let mod_file = find_module(binary).ok_or(KError::BinaryNotFound { binary })?;
     info!(
         "binary={} cmdline={} module={:?}",
         binary, kcb.cmdline.init_args, mod_file
     );
 
+    // Don't hand a truncated or foreign binary to elfloader
+    elfcheck::verify(binary, unsafe { mod_file.as_slice() })?;
     let elf_module = unsafe {
         elfloader::ElfBinary::new(mod_file.as_slice()).map_err(|_e| KError::UnableToParseElf)?
     };
@@ -361,7 +555,7 @@ pub fn make_process<P: Process>(binary: &'static str) -> Result<Pid, KError> {
     let offset = if !elf_module.is_pie() {
         VAddr::zero()
     } else {
-        VAddr::from(ELF_OFFSET)
+        VAddr::from(ELF_OFFSET + elf_slide())
     };
 
     let mut data_sec_loader = DataSecAllocator {
@@ -378,20 +572,20 @@ pub fn make_process<P: Process>(binary: &'static str) -> Result<Pid, KError> {
     );
 
     // Allocate a new process
-    kcb.replica
-        .as_ref()
-        .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
-            let response = replica.execute_mut(nr::Op::AllocatePid, *token)?;
-            if let nr::NodeResult::PidAllocated(pid) = response {
-                cnrfs::MlnrKernelNode::add_process(pid)
-                    .expect("TODO(error-handling): revert state");
-                crate::nrproc::NrProcess::<P>::load(pid, mod_file, data_frames)
-                    .expect("TODO(error-handling): revert state properly");
-                Ok(pid)
-            } else {
-                Err(KError::ProcessLoadingFailed)
-            }
-        })
+    let (replica, token) = kcb.replica()?;
+    let response =
+        replica.execute_mut_timed(nr::Op::AllocatePid(entropy::next_u64() as Pid), token)?;
+    if let nr::NodeResult::PidAllocated(pid) = response {
+        cputime::reset(pid);
+        ulog::reset(pid);
+        strace::reset(pid);
+        cnrfs::MlnrKernelNode::add_process(pid, root).expect("TODO(error-handling): revert state");
+        crate::nrproc::NrProcess::<P>::load(pid, mod_file, data_frames, offset)
+            .expect("TODO(error-handling): revert state properly");
+        Ok(pid)
+    } else {
+        Err(KError::ProcessLoadingFailed)
+    }
 }
 
 /// Create dispatchers for a given Pid to run on all cores.
@@ -419,7 +613,7 @@ pub fn allocate_dispatchers<P: Process>(pid: Pid) -> Result<(), KError> {
         while dispatchers_created < to_create {
             KernelAllocator::try_refill_tcache(20, 1)?;
             let mut frame = {
-                let kcb = crate::kcb::get_kcb();
+                let kcb = crate::kcb::per_core();
                 kcb.physical_memory.gmanager.unwrap().node_caches[affinity as usize]
                     .lock()
                     .allocate_large_page()?
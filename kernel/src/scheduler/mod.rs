@@ -5,17 +5,53 @@
 
 use core::intrinsics::unlikely;
 
+use kpi::process::SchedulingClass;
+
 use crate::error::KError;
 use crate::kcb::{self, ArchSpecificKcb};
 use crate::nr;
 use crate::nrproc::NrProcess;
 use crate::process::{Executor, ResumeHandle};
 
-use crate::arch::timer;
+use crate::arch::traits::{ContextSwitch, Timer};
+use crate::arch::Platform;
+
+pub mod policy;
+
+/// Period (in rdtsc ticks) of the replica advancement timer on cores that run
+/// a `SchedulingClass::LowLatency` executor without a deadline hint.
+pub const LOW_LATENCY_TIMER_DEADLINE: u64 = Platform::DEFAULT_DEADLINE / 10;
+
+/// Shortest period (in rdtsc ticks) a deadline hint can ask for, shorter
+/// ones would keep the core busy with timer interrupts.
+pub const MIN_TIMER_DEADLINE: u64 = Platform::DEFAULT_DEADLINE / 1000;
+
+/// Figure out when to raise the next periodic timer on a core that runs an
+/// executor of the given scheduling `class`.
+///
+/// Low-latency executors get a shorter period (bounded by their `deadline`
+/// hint) since they do less work per timer interrupt (see `timer_handler`)
+/// and want to observe updates to their own process replica early. The
+/// hint is clamped to `MIN_TIMER_DEADLINE..=DEFAULT_DEADLINE`.
+pub fn timer_deadline(class: SchedulingClass, deadline: Option<u64>) -> u64 {
+    match class {
+        SchedulingClass::LowLatency => deadline
+            .unwrap_or(LOW_LATENCY_TIMER_DEADLINE)
+            .clamp(MIN_TIMER_DEADLINE, Platform::DEFAULT_DEADLINE),
+        SchedulingClass::Normal | SchedulingClass::Unknown => Platform::DEFAULT_DEADLINE,
+    }
+}
 
 /// Runs the process allocated to the given core.
 pub fn schedule() -> ! {
-    let kcb = kcb::get_kcb();
+    // Safety: `schedule` is the last thing a core does when it (re-)enters
+    // the scheduler, there are no other KCB references alive.
+    let mut core = unsafe { kcb::LocalCore::new() };
+    let kcb = kcb::per_core();
+
+    // Deferred work of interrupt handlers goes first, nobody waits for the
+    // core right now
+    while crate::softirq::run_pending() {}
 
     // Are we the master/first thread in that replica?
     // Then we should set timer to periodically advance the state
@@ -34,29 +70,46 @@ pub fn schedule() -> ! {
     #[cfg(not(target_os = "none"))]
     let is_replica_main_thread = false;
 
+    // The load-balancer runs from the timer on every core with an executor
+    #[cfg(target_os = "none")]
+    let balancing = crate::arch::migrate::balancing();
+    #[cfg(not(target_os = "none"))]
+    let balancing = false;
+
     // No process assigned to core? Figure out if there is one now:
     if unlikely(kcb.arch.current_executor().is_err()) {
-        if let Some((replica, token)) = kcb.replica.as_ref() {
+        if let Ok((replica, token)) = kcb.replica() {
             loop {
                 let response =
-                    replica.execute(nr::ReadOps::CurrentProcess(kcb.arch.hwthread_id()), *token);
+                    replica.execute(nr::ReadOps::CurrentProcess(kcb.arch.hwthread_id()), token);
 
                 match response {
                     Ok(nr::NodeResult::CoreInfo(ci)) => {
-                        let executor =
-                            NrProcess::allocate_executor(kcb, ci.pid).expect("This should work");
-                        unsafe {
-                            (*executor.vcpu_kernel()).resume_with_upcall = ci.entry_point;
-                        }
+                        let executor = match ci.resume {
+                            // Migrated from another core, continues where it left off
+                            Some(eid) => NrProcess::resume_executor(kcb, ci.pid, eid)
+                                .expect("This should work"),
+                            None => {
+                                let executor = NrProcess::allocate_executor(kcb, ci.pid)
+                                    .expect("This should work");
+                                unsafe {
+                                    *executor.vcpu_kernel() =
+                                        kpi::arch::VirtualCpu::new(ci.entry_point);
+                                }
+                                executor
+                            }
+                        };
 
                         // info!("Start execution of {} on gtid {}", executor.eid, gtid);
-                        let no = kcb::get_kcb().arch.swap_current_executor(executor);
+                        let (class, deadline) = (executor.sched_class, executor.sched_deadline);
+                        let no = core.kcb_mut().arch.swap_current_executor(executor);
                         assert!(no.is_none(), "Handle the case where we replace a process.");
-                        if is_replica_main_thread {
-                            // Make sure we periodically try and advance the replica on main-thread
-                            // even if we're running something (e.g., if everything polls in
-                            // user-space we can livelock)
-                            timer::set(timer::DEFAULT_TIMER_DEADLINE);
+                        // Make sure we periodically try and advance the replica on main-thread
+                        // even if we're running something (e.g., if everything polls in
+                        // user-space we can livelock), unless the policy says otherwise
+                        let housekeeping = is_replica_main_thread || balancing;
+                        if let Some(tick) = policy::current().tick(class, deadline, housekeeping) {
+                            Platform::set(tick);
                         }
                         break;
                     }
@@ -78,10 +131,17 @@ pub fn schedule() -> ! {
                             }
                             continue;
                         } else {
+                            // Nothing to run, use the time to promote user
+                            // memory to large pages (if enabled)
+                            #[cfg(target_os = "none")]
+                            if crate::arch::thp::enabled() && crate::arch::thp::promote_next() {
+                                continue;
+                            }
+
                             // There is no process, set a timer and go to sleep
-                            timer::set(timer::DEFAULT_TIMER_DEADLINE);
+                            Platform::set(Platform::DEFAULT_DEADLINE);
                         }
-                        crate::arch::halt();
+                        Platform::halt();
                     }
                     other => {
                         unreachable!(
@@ -100,7 +160,7 @@ pub fn schedule() -> ! {
 
     // If we come here, we have a new process, dispatch it:
     unsafe {
-        let rh = kcb::get_kcb().arch.current_executor().map(|p| p.start());
+        let rh = kcb.arch.current_executor().map(|p| p.start());
         rh.unwrap().resume()
     }
 }
@@ -0,0 +1,341 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Scheduling policies.
+//!
+//! The kernel space-partitions cores: a core runs the executor of (at most)
+//! one process until the process gives it up. What is left to decide is
+//! which core a process gets when it asks for any core ([`SchedPolicy::place`])
+//! and how often the kernel interrupts an executor for its own housekeeping
+//! ([`SchedPolicy::tick`]). A [`SchedPolicy`] makes these decisions, the one
+//! in use is picked with `sched=<name>` on the kernel command-line:
+//!
+//! - `rr` ([`RoundRobin`], default): cores are handed out in turn (in
+//!   topology order, starting after the core handed out last), cores that
+//!   advance replicas or balance load get a periodic tick.
+//! - `prio` ([`Priority`]): like `rr`, but `SchedulingClass::LowLatency`
+//!   executors are never interrupted for housekeeping (only to honor their
+//!   deadline hint).
+//! - `rtc` ([`RunToCompletion`]): a process gets its cores on as few NUMA
+//!   nodes as possible and its executors run without periodic ticks (except
+//!   for a rare housekeeping tick).
+
+// The policy is only selected by the x86-64 kernel
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use atopology::{GlobalThreadId, NodeId};
+use kpi::process::SchedulingClass;
+use log::{info, warn};
+
+use crate::process::Pid;
+
+use super::timer_deadline;
+use crate::arch::traits::Timer as _;
+use crate::arch::Platform;
+
+/// Period (in rdtsc ticks) of the housekeeping tick of [`RunToCompletion`],
+/// without it a core that advances the replicas of its node would never do
+/// so while it runs an executor (and cores waiting for the log to make room
+/// would spin forever).
+pub const RTC_HOUSEKEEPING_DEADLINE: u64 = Platform::DEFAULT_DEADLINE * 10;
+
+/// A core as seen by [`SchedPolicy::place`].
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Core {
+    pub gtid: GlobalThreadId,
+    pub node: NodeId,
+    /// The process that has the core (if any).
+    pub pid: Option<Pid>,
+}
+
+impl Core {
+    /// Can the core be given to a process that asked for one on `affinity`?
+    pub fn is_eligible(&self, affinity: Option<NodeId>) -> bool {
+        self.pid.is_none() && affinity.map_or(true, |node| self.node == node)
+    }
+}
+
+pub trait SchedPolicy: Sync {
+    /// Name of the policy on the command-line.
+    fn name(&self) -> &'static str;
+
+    /// Picks the core for `pid` which asked for any core (on the `affinity`
+    /// NUMA node, if set) out of `cores` (all cores of the machine in
+    /// topology order). `last` is the core a policy handed out last.
+    ///
+    /// This runs as part of an NR operation: the decision must only depend
+    /// on the arguments (every replica has to come to the same result).
+    fn place(
+        &self,
+        pid: Pid,
+        affinity: Option<NodeId>,
+        cores: &[Core],
+        last: GlobalThreadId,
+    ) -> Option<GlobalThreadId>;
+
+    /// Period (in rdtsc ticks) of the timer on a core that runs an executor
+    /// of `class` with the (optional) `deadline` hint, `None` if the core
+    /// doesn't need a periodic timer.
+    ///
+    /// `housekeeping` is set if the core is expected to periodically advance
+    /// the replicas of its node or balance load (see `migrate`).
+    fn tick(
+        &self,
+        class: SchedulingClass,
+        deadline: Option<u64>,
+        housekeeping: bool,
+    ) -> Option<u64>;
+}
+
+/// The first eligible core in topology order.
+fn first_fit(affinity: Option<NodeId>, cores: &[Core]) -> Option<GlobalThreadId> {
+    cores
+        .iter()
+        .find(|core| core.is_eligible(affinity))
+        .map(|core| core.gtid)
+}
+
+/// The first eligible core in topology order after `last` (wraps around).
+fn next_fit(
+    affinity: Option<NodeId>,
+    cores: &[Core],
+    last: GlobalThreadId,
+) -> Option<GlobalThreadId> {
+    let start = cores
+        .iter()
+        .position(|core| core.gtid == last)
+        .map_or(0, |idx| idx + 1);
+    first_fit(affinity, &cores[start..]).or_else(|| first_fit(affinity, &cores[..start]))
+}
+
+/// Cores are handed out in turn and every executor gets the same time
+/// between housekeeping ticks (shorter for low-latency executors, see
+/// `timer_deadline`).
+pub struct RoundRobin;
+
+impl SchedPolicy for RoundRobin {
+    fn name(&self) -> &'static str {
+        "rr"
+    }
+
+    fn place(
+        &self,
+        _pid: Pid,
+        affinity: Option<NodeId>,
+        cores: &[Core],
+        last: GlobalThreadId,
+    ) -> Option<GlobalThreadId> {
+        next_fit(affinity, cores, last)
+    }
+
+    fn tick(
+        &self,
+        class: SchedulingClass,
+        deadline: Option<u64>,
+        housekeeping: bool,
+    ) -> Option<u64> {
+        if housekeeping {
+            Some(timer_deadline(class, deadline))
+        } else {
+            None
+        }
+    }
+}
+
+/// Low-latency executors take precedence over kernel housekeeping: their
+/// cores leave it to cores that run normal executors (or idle cores).
+pub struct Priority;
+
+impl SchedPolicy for Priority {
+    fn name(&self) -> &'static str {
+        "prio"
+    }
+
+    fn place(
+        &self,
+        _pid: Pid,
+        affinity: Option<NodeId>,
+        cores: &[Core],
+        last: GlobalThreadId,
+    ) -> Option<GlobalThreadId> {
+        next_fit(affinity, cores, last)
+    }
+
+    fn tick(
+        &self,
+        class: SchedulingClass,
+        deadline: Option<u64>,
+        housekeeping: bool,
+    ) -> Option<u64> {
+        match class {
+            SchedulingClass::LowLatency => deadline.map(|d| timer_deadline(class, Some(d))),
+            SchedulingClass::Normal | SchedulingClass::Unknown => {
+                RoundRobin.tick(class, deadline, housekeeping)
+            }
+        }
+    }
+}
+
+/// Core-partitioned run-to-completion: a process gets its cores packed on
+/// the NUMA node it already runs on and its executors are only interrupted
+/// by device interrupts or their own system calls.
+///
+/// Replicas then mostly advance on idle cores and with system calls, a
+/// core that does housekeeping only gets a tick every
+/// [`RTC_HOUSEKEEPING_DEADLINE`]. `balance=spread` has no effect.
+pub struct RunToCompletion;
+
+impl SchedPolicy for RunToCompletion {
+    fn name(&self) -> &'static str {
+        "rtc"
+    }
+
+    fn place(
+        &self,
+        pid: Pid,
+        affinity: Option<NodeId>,
+        cores: &[Core],
+        _last: GlobalThreadId,
+    ) -> Option<GlobalThreadId> {
+        if affinity.is_some() {
+            return first_fit(affinity, cores);
+        }
+
+        // Prefer the node the process has the most cores on, then the node
+        // with the most free cores (the first one on ties)
+        let owned = |node: NodeId| {
+            cores
+                .iter()
+                .filter(|c| c.node == node && c.pid == Some(pid))
+                .count()
+        };
+        let free = |node: NodeId| {
+            cores
+                .iter()
+                .filter(|c| c.node == node && c.pid.is_none())
+                .count()
+        };
+        let mut best: Option<(NodeId, (usize, usize))> = None;
+        for core in cores.iter().filter(|c| c.pid.is_none()) {
+            let rank = (owned(core.node), free(core.node));
+            if best.map_or(true, |(_node, best_rank)| rank > best_rank) {
+                best = Some((core.node, rank));
+            }
+        }
+
+        best.and_then(|(node, _rank)| first_fit(Some(node), cores))
+    }
+
+    fn tick(
+        &self,
+        _class: SchedulingClass,
+        _deadline: Option<u64>,
+        housekeeping: bool,
+    ) -> Option<u64> {
+        if housekeeping {
+            Some(RTC_HOUSEKEEPING_DEADLINE)
+        } else {
+            None
+        }
+    }
+}
+
+/// All policies that can be selected on the command-line.
+static POLICIES: [&dyn SchedPolicy; 3] = [&RoundRobin, &Priority, &RunToCompletion];
+
+static POLICY: spin::Once<&'static dyn SchedPolicy> = spin::Once::new();
+
+/// Selects the policy with the given `name` (falls back to [`RoundRobin`]
+/// if there is none).
+///
+/// Has to happen before the first process is created, later calls have no
+/// effect.
+pub fn init(name: &str) {
+    let policy = POLICIES
+        .iter()
+        .find(|p| p.name() == name)
+        .copied()
+        .unwrap_or_else(|| {
+            warn!("Unknown sched={} ignored", name);
+            &RoundRobin
+        });
+    info!("Scheduling policy: {}", policy.name());
+    POLICY.call_once(|| policy);
+}
+
+/// The policy in use.
+pub fn current() -> &'static dyn SchedPolicy {
+    POLICY.get().copied().unwrap_or(&RoundRobin)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::arch::traits::Timer;
+    use crate::scheduler::MIN_TIMER_DEADLINE;
+
+    fn core(gtid: GlobalThreadId, node: NodeId, pid: Option<Pid>) -> Core {
+        Core { gtid, node, pid }
+    }
+
+    #[test]
+    fn round_robin_placement() {
+        let cores = [
+            core(0, 0, Some(1)),
+            core(1, 0, None),
+            core(2, 1, None),
+            core(3, 1, None),
+        ];
+        assert_eq!(RoundRobin.place(2, None, &cores, 3), Some(1));
+        // Continues after the core handed out last
+        assert_eq!(RoundRobin.place(2, None, &cores, 1), Some(2));
+        assert_eq!(RoundRobin.place(2, None, &cores, 2), Some(3));
+        assert_eq!(RoundRobin.place(2, Some(0), &cores, 1), Some(1));
+        assert_eq!(RoundRobin.place(2, Some(1), &cores, 0), Some(2));
+        assert_eq!(RoundRobin.place(2, Some(2), &cores, 0), None);
+        assert_eq!(Priority.place(2, None, &cores, 2), Some(3));
+    }
+
+    #[test]
+    fn run_to_completion_packs_nodes() {
+        let cores = [
+            core(0, 0, None),
+            core(1, 0, Some(2)),
+            core(2, 1, Some(1)),
+            core(3, 1, None),
+            core(4, 1, None),
+        ];
+        // Stays on the node it already runs on
+        assert_eq!(RunToCompletion.place(1, None, &cores, 0), Some(3));
+        // Otherwise goes to the node with the most free cores
+        assert_eq!(RunToCompletion.place(3, None, &cores, 0), Some(3));
+        assert_eq!(RunToCompletion.place(2, None, &cores, 0), Some(0));
+
+        let full = [core(0, 0, Some(1))];
+        assert_eq!(RunToCompletion.place(1, None, &full, 0), None);
+    }
+
+    #[test]
+    fn ticks() {
+        let ll = SchedulingClass::LowLatency;
+        let hint = 2 * MIN_TIMER_DEADLINE;
+        assert_eq!(RoundRobin.tick(ll, Some(hint), false), None);
+        assert_eq!(RoundRobin.tick(ll, Some(hint), true), Some(hint));
+        assert_eq!(Priority.tick(ll, None, true), None);
+        assert_eq!(Priority.tick(ll, Some(hint), false), Some(hint));
+        // Hints outside the allowed range are clamped
+        assert_eq!(Priority.tick(ll, Some(10), false), Some(MIN_TIMER_DEADLINE));
+        assert_eq!(
+            Priority.tick(ll, Some(u64::MAX), false),
+            Some(Platform::DEFAULT_DEADLINE)
+        );
+        assert_eq!(
+            RunToCompletion.tick(SchedulingClass::Normal, None, true),
+            Some(RTC_HOUSEKEEPING_DEADLINE)
+        );
+        assert_eq!(
+            RunToCompletion.tick(SchedulingClass::Normal, None, false),
+            None
+        );
+    }
+}
@@ -0,0 +1,359 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Restarts processes the kernel started at boot when they fail.
+//!
+//! A process of `init.toml` (see `autostart`) with a [`RestartPolicy`] other
+//! than `never` is a *service*. When a service exits (or crashes, or is
+//! killed) and its policy asks for it, the kernel starts the program again
+//! instead of shutting down the system. Restarts back off exponentially:
+//! [`MIN_BACKOFF`] after the first failure, twice as long after each
+//! further one, at most [`MAX_BACKOFF`]. A service that ran for [`STABLE`]
+//! before it failed starts over at [`MIN_BACKOFF`]. `System::stats` reports
+//! how often every service failed.
+//!
+//! The kernel destroys exited services, so restarts reuse their pid and
+//! memory. A restart that fails (e.g., no free core) is tried again after
+//! the next backoff.
+
+// Only the x86-64 kernel restarts processes
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use core::time::Duration;
+
+use arrayvec::ArrayVec;
+use log::{info, warn};
+
+use crate::arch::traits::Timer as _;
+use crate::arch::Platform;
+use crate::autostart::Program;
+use crate::domain::Pinned;
+use crate::error::KError;
+use crate::nr;
+use crate::process::{Pid, MAX_PROCESSES};
+use crate::sync::{Mutex, MutexGuard};
+use crate::timer_wheel;
+
+/// Wait before the first restart.
+pub const MIN_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Longest wait between restarts.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A service that ran for this long is considered healthy again.
+pub const STABLE: Duration = Duration::from_secs(10);
+
+/// When to restart a service (`restart` in `init.toml`).
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum RestartPolicy {
+    /// Never (the default), the system shuts down when it exits.
+    Never,
+    /// If it exits with a code other than 0, crashes or is killed.
+    OnFailure,
+    /// Whenever it exits.
+    Always,
+}
+
+impl RestartPolicy {
+    /// The policy called `name` in `init.toml`.
+    pub fn from_name(name: &str) -> Option<RestartPolicy> {
+        match name {
+            "never" => Some(RestartPolicy::Never),
+            "on-failure" => Some(RestartPolicy::OnFailure),
+            "always" => Some(RestartPolicy::Always),
+            _ => None,
+        }
+    }
+
+    /// Does a process that exited with `code` get restarted?
+    pub fn restarts(self, code: u64) -> bool {
+        match self {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => code != 0,
+            RestartPolicy::Always => true,
+        }
+    }
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy::Never
+    }
+}
+
+/// A supervised program.
+struct Service {
+    program: Program<'static>,
+    /// The running instance (`None` while we wait to restart it).
+    pid: Option<Pid>,
+    /// When the instance was started (in ns).
+    started: u64,
+    /// Failures in a row (since it last ran for `STABLE`).
+    failures: u32,
+    /// Exits that led to a restart.
+    restarts: u64,
+    /// Restarts that failed (e.g., no free core).
+    lost: u64,
+}
+
+/// The services of the system.
+struct Services {
+    services: ArrayVec<Service, MAX_PROCESSES>,
+}
+
+impl Services {
+    const fn new() -> Services {
+        Services {
+            services: ArrayVec::new_const(),
+        }
+    }
+
+    fn register(&mut self, program: &Program<'static>, pid: Pid, now: u64) -> Result<(), KError> {
+        self.services
+            .try_push(Service {
+                program: program.clone(),
+                pid: Some(pid),
+                started: now,
+                failures: 0,
+                restarts: 0,
+                lost: 0,
+            })
+            .map_err(|_e| KError::TooManyProcesses)
+    }
+
+    fn running(&self, pid: Pid) -> Option<usize> {
+        self.services.iter().position(|s| s.pid == Some(pid))
+    }
+
+    /// Does `pid` get restarted if it exits with `code`?
+    fn restarts(&self, pid: Pid, code: u64) -> bool {
+        self.running(pid).map_or(false, |idx| {
+            self.services[idx].program.restart.restarts(code)
+        })
+    }
+
+    /// Takes note that `pid` exited with `code`, returns the service and
+    /// when to restart it (if it's a service that gets restarted).
+    fn exited(&mut self, pid: Pid, code: u64, now: u64) -> Option<(usize, Duration)> {
+        let idx = self.running(pid)?;
+        let service = &mut self.services[idx];
+        service.pid = None;
+        if !service.program.restart.restarts(code) {
+            return None;
+        }
+
+        let ran = Duration::from_nanos(now.saturating_sub(service.started));
+        if ran >= STABLE {
+            service.failures = 0;
+        }
+        service.failures += 1;
+        service.restarts += 1;
+        Some((idx, backoff(service.failures)))
+    }
+
+    /// Stops supervising `pid` without restarting it.
+    fn detach(&mut self, pid: Pid) -> Option<(usize, Program<'static>)> {
+        let idx = self.running(pid)?;
+        self.services[idx].pid = None;
+        Some((idx, self.services[idx].program.clone()))
+    }
+
+    /// Takes note that service `idx` couldn't be started, returns when to
+    /// try again.
+    fn failed(&mut self, idx: usize) -> Duration {
+        match self.services.get_mut(idx) {
+            Some(service) => {
+                service.pid = None;
+                service.lost += 1;
+                service.failures += 1;
+                backoff(service.failures)
+            }
+            None => MAX_BACKOFF,
+        }
+    }
+
+    fn restarted(&mut self, idx: usize, pid: Option<Pid>, now: u64) {
+        if let Some(service) = self.services.get_mut(idx) {
+            service.pid = pid;
+            service.started = now;
+            if pid.is_none() {
+                service.lost += 1;
+            }
+        }
+    }
+}
+
+static SERVICES: Mutex<Services> = Mutex::new(Services::new());
+
+/// Locks [`SERVICES`] (pinned, see `domain::Pin`).
+fn services() -> Pinned<MutexGuard<'static, Services>> {
+    Pinned::new(SERVICES.lock())
+}
+
+/// How long to wait before restarting a service after `failures` failures
+/// in a row.
+fn backoff(failures: u32) -> Duration {
+    let shift = failures.saturating_sub(1).min(16);
+    (MIN_BACKOFF * (1 << shift)).min(MAX_BACKOFF)
+}
+
+fn now() -> u64 {
+    timer_wheel::cycles_to_ns(Platform::now())
+}
+
+/// Supervises `pid`, the running instance of `program`.
+pub fn register(program: &Program<'static>, pid: Pid) -> Result<(), KError> {
+    services().register(program, pid, now())
+}
+
+/// Does `pid` get restarted if it exits with `code`? The system keeps
+/// running then.
+pub fn restarts(pid: Pid, code: u64) -> bool {
+    services().restarts(pid, code)
+}
+
+/// Takes note that `pid` exited with `code`.
+///
+/// Returns the service and when to restart it if `pid` is a service that
+/// gets restarted, the caller starts it again with [`restart`].
+pub fn exited(pid: Pid, code: u64) -> Option<(usize, Duration)> {
+    let mut services = services();
+    let (idx, backoff) = services.exited(pid, code, now())?;
+    let service = &services.services[idx];
+    warn!(
+        "Service {} ({}) exited with {} ({} failure(s) in a row), restarting in {} ms",
+        service.program.binary,
+        pid,
+        code,
+        service.failures,
+        backoff.as_millis()
+    );
+    Some((idx, backoff))
+}
+
+/// Starts service `idx` again with `spawn`.
+///
+/// Returns when to try again if the service didn't come up (or already
+/// exited again).
+pub fn restart(
+    idx: usize,
+    spawn: fn(&Program<'static>) -> Result<Pid, KError>,
+) -> Option<Duration> {
+    let program = services().services.get(idx)?.program.clone();
+
+    // Spawn without holding the lock (it loads the binary and talks to
+    // other cores), the new instance may exit before we supervise it
+    match spawn(&program) {
+        Ok(pid) => {
+            info!("Restarted {} ({}) as {}", program.binary, program.args, pid);
+            services().restarted(idx, Some(pid), now());
+
+            // If it exited before we supervised it, nobody restarts it
+            let code = match nr::KernelNode::process(pid) {
+                Ok(entry) => entry.exit_code,
+                Err(_e) => Some(kpi::process::CRASHED),
+            };
+            code.and_then(|code| exited(pid, code))
+                .map(|(_idx, backoff)| backoff)
+        }
+        Err(e) => {
+            let backoff = failed(idx);
+            warn!(
+                "Can't restart {}: {}, trying again in {} ms",
+                program.binary,
+                e,
+                backoff.as_millis()
+            );
+            Some(backoff)
+        }
+    }
+}
+
+/// Stops supervising `pid` (it's about to be replaced by a new instance),
+/// returns the service and its program.
+pub fn detach(pid: Pid) -> Option<(usize, Program<'static>)> {
+    services().detach(pid)
+}
+
+/// Supervises `pid` as the new instance of service `idx`.
+pub fn attach(idx: usize, pid: Pid) {
+    services().restarted(idx, Some(pid), now());
+}
+
+/// Takes note that service `idx` couldn't be started, returns when to try
+/// again (with [`restart`]).
+pub fn failed(idx: usize) -> Duration {
+    services().failed(idx)
+}
+
+/// Logs the failures of all services (`System::stats`).
+pub fn report() {
+    let services = services();
+    for service in services.services.iter() {
+        info!(
+            "Service {}: {} restart(s) ({} failed), running as {:?}",
+            service.program.binary, service.restarts, service.lost, service.pid
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles() {
+        assert_eq!(backoff(1), MIN_BACKOFF);
+        assert_eq!(backoff(2), MIN_BACKOFF * 2);
+        assert_eq!(backoff(4), MIN_BACKOFF * 8);
+        assert_eq!(backoff(20), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn restart_policies() {
+        let mut program = Program::new("redis.bin");
+        program.restart = RestartPolicy::OnFailure;
+        let mut services = Services::new();
+        services.register(&program, 3, 0).expect("Has room");
+
+        assert!(!services.restarts(3, 0));
+        assert!(services.restarts(3, kpi::process::CRASHED));
+        assert!(!services.restarts(4, 1));
+
+        // Failures in a row back off
+        assert_eq!(services.exited(3, 1, 1_000), Some((0, MIN_BACKOFF)));
+        services.restarted(0, Some(4), 2_000);
+        assert_eq!(services.exited(4, 1, 3_000), Some((0, MIN_BACKOFF * 2)));
+        services.restarted(0, Some(5), 4_000);
+
+        // It ran long enough to start over
+        let later = 4_000 + STABLE.as_nanos() as u64;
+        assert_eq!(services.exited(5, 1, later), Some((0, MIN_BACKOFF)));
+        assert_eq!(services.services[0].restarts, 3);
+
+        // Success is final
+        services.restarted(0, Some(6), later);
+        assert_eq!(services.exited(6, 0, later), None);
+        assert_eq!(services.exited(6, 1, later), None);
+        services.restarted(0, None, later);
+        assert_eq!(services.services[0].lost, 1);
+
+        // Starts that fail back off as well
+        assert_eq!(services.failed(0), MIN_BACKOFF * 2);
+        assert_eq!(services.services[0].lost, 2);
+        assert_eq!(services.failed(1), MAX_BACKOFF);
+
+        // Replaced instances aren't restarted
+        services.restarted(0, Some(7), later);
+        assert_eq!(services.detach(7).map(|(idx, _)| idx), Some(0));
+        assert!(!services.restarts(7, 1));
+
+        assert_eq!(
+            RestartPolicy::from_name("always"),
+            Some(RestartPolicy::Always)
+        );
+        assert_eq!(RestartPolicy::from_name("sometimes"), None);
+        assert!(RestartPolicy::Always.restarts(0));
+    }
+}
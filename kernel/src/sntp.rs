@@ -0,0 +1,197 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Messages of the Simple Network Time Protocol (SNTPv4, RFC 4330).
+//!
+//! A client sends a [`request`] with its time in the transmit timestamp, the
+//! server answers with the time it received the request and the time it sent
+//! the reply. With the time the reply arrived we know [`offset`] (how far
+//! our clock is behind the server's) and the round trip [`delay`] (the
+//! offset is off by at most half of it).
+
+// Only the x86-64 kernel network stack synchronizes the clock
+#![cfg_attr(
+    any(not(target_os = "none"), not(feature = "smoltcp")),
+    allow(dead_code)
+)]
+
+use core::time::Duration;
+
+/// UDP port of the servers.
+pub const PORT: u16 = 123;
+
+/// Size of a message (without authentication).
+pub const PACKET_SIZE: usize = 48;
+
+/// Seconds from the NTP epoch (1900) to the Unix epoch (1970).
+const UNIX_EPOCH: u64 = 2_208_988_800;
+
+/// Mode of a request.
+const MODE_CLIENT: u8 = 3;
+
+/// Mode of a reply.
+const MODE_SERVER: u8 = 4;
+
+/// Converts a time since the Unix epoch into an NTP timestamp (seconds since
+/// 1900 in 32.32 fixed point).
+pub fn timestamp(time: Duration) -> u64 {
+    let seconds = time.as_secs().wrapping_add(UNIX_EPOCH) as u32;
+    let fraction = (time.subsec_nanos() as u64) << 32;
+    ((seconds as u64) << 32) | (fraction / 1_000_000_000)
+}
+
+/// Converts an NTP timestamp into a time since the Unix epoch (in era 0,
+/// which ends in 2036).
+pub fn unix_time(timestamp: u64) -> Duration {
+    let seconds = (timestamp >> 32).saturating_sub(UNIX_EPOCH);
+    let nanos = ((timestamp & 0xffff_ffff) * 1_000_000_000) >> 32;
+    Duration::new(seconds, nanos as u32)
+}
+
+/// A request of a client whose clock says `now` (the server returns the
+/// transmit timestamp as originate timestamp of its reply).
+pub fn request(now: Duration) -> [u8; PACKET_SIZE] {
+    let mut packet = [0u8; PACKET_SIZE];
+    // No leap second warning, version 4
+    packet[0] = (4 << 3) | MODE_CLIENT;
+    packet[40..48].copy_from_slice(&timestamp(now).to_be_bytes());
+    packet
+}
+
+/// The timestamps of a reply.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Reply {
+    /// Stratum of the server (1 for a reference clock).
+    pub stratum: u8,
+    /// Transmit timestamp of our request.
+    pub originate: u64,
+    /// When the server received the request.
+    pub receive: u64,
+    /// When the server sent the reply.
+    pub transmit: u64,
+}
+
+/// Parses the reply of a server, `None` if it isn't one or if the server has
+/// no time for us (a kiss-o'-death packet or an unsynchronized server).
+pub fn parse(packet: &[u8]) -> Option<Reply> {
+    if packet.len() < PACKET_SIZE {
+        return None;
+    }
+    let field = |offset: usize| {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&packet[offset..offset + 8]);
+        u64::from_be_bytes(bytes)
+    };
+    let leap = packet[0] >> 6;
+    let stratum = packet[1];
+    let reply = Reply {
+        stratum,
+        originate: field(24),
+        receive: field(32),
+        transmit: field(40),
+    };
+
+    let unsynchronized = leap == 3 || stratum == 0 || stratum > 15;
+    if packet[0] & 0x7 != MODE_SERVER || unsynchronized || reply.transmit == 0 {
+        return None;
+    }
+    Some(reply)
+}
+
+/// Difference of two NTP timestamps `a - b` in ns.
+fn difference(a: u64, b: u64) -> i64 {
+    // Fixed point: 2^32 units per second
+    ((a.wrapping_sub(b) as i64 as i128 * 1_000_000_000) >> 32) as i64
+}
+
+/// Nanoseconds our clock is behind the server's, for a `reply` that arrived
+/// at `arrival` (NTP timestamp of our clock).
+pub fn offset(reply: &Reply, arrival: u64) -> i64 {
+    (difference(reply.receive, reply.originate) + difference(reply.transmit, arrival)) / 2
+}
+
+/// Nanoseconds the request and the reply spent in the network.
+pub fn delay(reply: &Reply, arrival: u64) -> i64 {
+    difference(arrival, reply.originate) - difference(reply.transmit, reply.receive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(originate: Duration, receive: Duration, transmit: Duration) -> [u8; PACKET_SIZE] {
+        let mut packet = [0u8; PACKET_SIZE];
+        packet[0] = (4 << 3) | MODE_SERVER;
+        packet[1] = 2;
+        packet[24..32].copy_from_slice(&timestamp(originate).to_be_bytes());
+        packet[32..40].copy_from_slice(&timestamp(receive).to_be_bytes());
+        packet[40..48].copy_from_slice(&timestamp(transmit).to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn timestamps() {
+        let time = Duration::new(1_600_000_000, 250_000_000);
+        assert_eq!(timestamp(time) >> 32, 1_600_000_000 + UNIX_EPOCH);
+        assert_eq!(timestamp(time) & 0xffff_ffff, 1 << 30);
+        // Fractions of a second are rounded down (to ~0.23 ns)
+        let time = Duration::new(1_600_000_000, 123_456_789);
+        let back = unix_time(timestamp(time));
+        assert!(time - back <= Duration::from_nanos(1));
+
+        let packet = request(time);
+        assert_eq!(packet[0], 0x23);
+        assert_eq!(parse(&packet), None);
+    }
+
+    #[test]
+    fn offset_and_delay() {
+        // We are 2 s behind, the packets take 10 ms each way and the server
+        // takes 1 ms to answer
+        let t1 = Duration::from_secs(1_600_000_000);
+        let packet = reply(
+            t1,
+            t1 + Duration::from_millis(2010),
+            t1 + Duration::from_millis(2011),
+        );
+        let behind = parse(&packet).expect("Valid reply");
+        assert_eq!(behind.stratum, 2);
+        assert_eq!(behind.originate, timestamp(t1));
+
+        let arrival = timestamp(t1 + Duration::from_millis(21));
+        let (ns, rtt) = (offset(&behind, arrival), delay(&behind, arrival));
+        assert!((ns - 2_000_000_000).abs() < 10, "offset {}", ns);
+        assert!((rtt - 20_000_000).abs() < 10, "delay {}", rtt);
+
+        // 2 s ahead of the server
+        let packet = reply(
+            t1,
+            t1 - Duration::from_millis(1990),
+            t1 - Duration::from_millis(1989),
+        );
+        let ahead = parse(&packet).expect("Valid reply");
+        let ns = offset(&ahead, arrival);
+        assert!((ns + 2_000_000_000).abs() < 10, "offset {}", ns);
+    }
+
+    #[test]
+    fn reject_bad_replies() {
+        let t = Duration::from_secs(1_600_000_000);
+        let good = reply(t, t, t);
+        assert!(parse(&good).is_some());
+        assert_eq!(parse(&good[..40]), None);
+
+        // Kiss-o'-death
+        let mut packet = good;
+        packet[1] = 0;
+        assert_eq!(parse(&packet), None);
+        // Unsynchronized server
+        let mut packet = good;
+        packet[0] |= 3 << 6;
+        assert_eq!(parse(&packet), None);
+        // No transmit timestamp
+        let mut packet = good;
+        packet[40..48].copy_from_slice(&[0; 8]);
+        assert_eq!(parse(&packet), None);
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Deferred work of interrupt handlers (*soft IRQs*).
+//!
+//! An interrupt handler only does what can't wait (acknowledge the device,
+//! take note of the work) and [`raise`]s a soft IRQ for the rest. Pending
+//! soft IRQs of a core run from [`run_pending`] before the core returns to
+//! user-space or looks for something to run, after the interrupt was
+//! acknowledged. This gives them priority over user execution.
+//!
+//! Soft IRQs run with interrupts enabled (see `IrqControl::with_interrupts`).
+//! Interrupts don't nest in nrk (an interrupt in kernel mode would overwrite
+//! the save area of the core), so an interrupt that arrives while a soft IRQ
+//! runs is held back until the handler returns, [`run_pending`] then leaves
+//! the other soft IRQs for later and the core handles the interrupt when it
+//! leaves the kernel. [`run_pending`] also stops once it used up
+//! [`BUDGET_US`]. Deferred soft IRQs run the next time, [`RETRY_DEADLINE`]
+//! later at the latest.
+
+// Handlers are only registered by the x86-64 kernel
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use log::trace;
+
+use crate::arch::traits::{IrqControl, Timer as _};
+use crate::arch::{Platform, MAX_CORES};
+
+/// Time [`run_pending`] may spend on soft IRQs before it defers the rest.
+pub const BUDGET_US: u64 = 500;
+
+/// When to come back for deferred soft IRQs (in rdtsc ticks).
+pub const RETRY_DEADLINE: u64 = 1_000_000;
+
+/// The kinds of deferred work, in the order they run.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u32)]
+pub enum SoftIrq {
+    /// Run the expired timers of the core's timer wheel.
+    Timer = 0,
+    /// Advance the replicas of the core.
+    Replication = 1,
+    /// Advance the RCU epoch and drop what the core retired.
+    Rcu = 2,
+    /// Kill a process when the kernel ran out of memory (see `oom`).
+    Oom = 3,
+    /// Receive packets and run the timers of the kernel's network stack.
+    NetRx = 4,
+    /// Write back and read ahead the disks of disk mounts (see `fs::image`).
+    Flush = 5,
+}
+
+impl SoftIrq {
+    pub const ALL: [SoftIrq; 6] = [
+        SoftIrq::Timer,
+        SoftIrq::Replication,
+        SoftIrq::Rcu,
+        SoftIrq::Oom,
+        SoftIrq::NetRx,
+        SoftIrq::Flush,
+    ];
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// Does the work of a soft IRQ on the current core.
+pub type Handler = fn();
+
+static HANDLERS: [spin::Once<Handler>; SoftIrq::ALL.len()] = {
+    const NONE: spin::Once<Handler> = spin::Once::new();
+    [NONE; SoftIrq::ALL.len()]
+};
+
+/// Raised soft IRQs of every core (one bit per `SoftIrq`).
+static PENDING: [AtomicU32; MAX_CORES] = {
+    const NONE: AtomicU32 = AtomicU32::new(0);
+    [NONE; MAX_CORES]
+};
+
+/// How often `run_pending` ran out of budget or got interrupted.
+static DEFERRED: AtomicU64 = AtomicU64::new(0);
+
+/// Sets the function that does the work of `irq` (once, at boot).
+pub fn register(irq: SoftIrq, handler: Handler) {
+    HANDLERS[irq as usize].call_once(|| handler);
+}
+
+/// Raises `irq` on the current core.
+pub fn raise(irq: SoftIrq) {
+    if let Some(kcb) = crate::kcb::try_get_kcb() {
+        raise_on(kcb.arch.hwthread_id(), irq);
+    }
+}
+
+/// Raises `irq` on `core`, it runs the next time the core leaves the
+/// kernel.
+pub fn raise_on(core: usize, irq: SoftIrq) {
+    PENDING[core].fetch_or(irq.bit(), Ordering::Release);
+}
+
+/// How often we ran out of budget (or got interrupted) with soft IRQs still
+/// pending.
+pub fn deferred() -> u64 {
+    DEFERRED.load(Ordering::Relaxed)
+}
+
+/// Runs the pending soft IRQs of the current core (until the budget is used
+/// up or an interrupt arrives).
+///
+/// Returns true if soft IRQs are still pending, the caller makes sure the
+/// core comes back for them (e.g., with a timer in [`RETRY_DEADLINE`]).
+pub fn run_pending() -> bool {
+    match crate::kcb::try_get_kcb() {
+        Some(kcb) => run_on(kcb.arch.hwthread_id(), Platform::with_interrupts),
+        None => false,
+    }
+}
+
+/// Runs the pending soft IRQs of `core` (the current one), `run` calls a
+/// handler and returns true if an interrupt arrived.
+fn run_on(core: usize, run: fn(Handler) -> bool) -> bool {
+    let start = Platform::now();
+    loop {
+        let pending = PENDING[core].swap(0, Ordering::Acquire);
+        if pending == 0 {
+            return false;
+        }
+        for irq in SoftIrq::ALL.iter().filter(|irq| pending & irq.bit() != 0) {
+            trace!("Run soft IRQ {:?} on core {}", irq, core);
+            let interrupted = match HANDLERS[*irq as usize].get() {
+                Some(handler) => run(*handler),
+                None => false,
+            };
+            if interrupted {
+                // The ones we didn't get to yet stay pending
+                let done = (irq.bit() << 1) - 1;
+                PENDING[core].fetch_or(pending & !done, Ordering::Release);
+                DEFERRED.fetch_add(1, Ordering::Relaxed);
+                return true;
+            }
+        }
+
+        let elapsed = crate::timer_wheel::cycles_to_us(Platform::now() - start);
+        if elapsed >= BUDGET_US && PENDING[core].load(Ordering::Relaxed) != 0 {
+            DEFERRED.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static REPLICATION_RUNS: AtomicU64 = AtomicU64::new(0);
+    static OOM_RUNS: AtomicU64 = AtomicU64::new(0);
+    static NET_RX_RUNS: AtomicU64 = AtomicU64::new(0);
+
+    fn register_handlers() {
+        register(SoftIrq::Replication, || {
+            REPLICATION_RUNS.fetch_add(1, Ordering::Relaxed);
+        });
+        register(SoftIrq::Oom, || {
+            OOM_RUNS.fetch_add(1, Ordering::Relaxed);
+        });
+        register(SoftIrq::NetRx, || {
+            NET_RX_RUNS.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    fn run(handler: Handler) -> bool {
+        handler();
+        false
+    }
+
+    fn run_interrupted(handler: Handler) -> bool {
+        handler();
+        true
+    }
+
+    /// Raised soft IRQs run once.
+    #[test]
+    fn runs_raised() {
+        register_handlers();
+        let core = MAX_CORES - 1;
+
+        raise_on(core, SoftIrq::Replication);
+        raise_on(core, SoftIrq::Replication);
+        assert!(!run_on(core, run));
+        assert_eq!(REPLICATION_RUNS.load(Ordering::Relaxed), 1);
+        assert_eq!(PENDING[core].load(Ordering::Relaxed), 0);
+
+        // Nothing pending, nothing runs
+        assert!(!run_on(core, run));
+        assert_eq!(REPLICATION_RUNS.load(Ordering::Relaxed), 1);
+    }
+
+    /// An interrupt leaves the soft IRQs we didn't get to yet pending.
+    #[test]
+    fn interrupted_stay_pending() {
+        register_handlers();
+        let core = MAX_CORES - 2;
+        let deferred_before = deferred();
+
+        raise_on(core, SoftIrq::Oom);
+        raise_on(core, SoftIrq::NetRx);
+        assert!(run_on(core, run_interrupted));
+        assert!(deferred() > deferred_before);
+        assert_eq!(OOM_RUNS.load(Ordering::Relaxed), 1);
+        assert_eq!(PENDING[core].load(Ordering::Relaxed), SoftIrq::NetRx.bit());
+
+        assert!(!run_on(core, run));
+        assert_eq!(OOM_RUNS.load(Ordering::Relaxed), 1);
+        assert_eq!(NET_RX_RUNS.load(Ordering::Relaxed), 1);
+        assert_eq!(PENDING[core].load(Ordering::Relaxed), 0);
+    }
+}
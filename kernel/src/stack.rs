@@ -10,12 +10,46 @@ use alloc::alloc::alloc;
 use alloc::boxed::Box;
 
 use core::alloc::Layout;
-use core::slice;
+use core::{ptr, slice};
 
 use x86::bits64::paging::BASE_PAGE_SIZE;
 
 pub const STACK_ALIGNMENT: usize = 16;
 
+/// Unused parts of an [`OwnedStack`] are filled with this pattern (to find
+/// out how deep the stack got, see [`max_used`]).
+const STACK_PAINT: u64 = 0x5354_4b50_4149_4e54;
+
+/// The lowest bytes of an [`OwnedStack`] are filled with this pattern, if it
+/// changes the stack overflowed (see [`canary_intact`]).
+const STACK_CANARY: u64 = 0xdead_57ac_dead_57ac;
+
+/// Size of the canary at the limit of an [`OwnedStack`].
+pub const CANARY_SIZE: usize = 512;
+
+/// Checks the canary of a stack that was allocated by [`OwnedStack::new`].
+///
+/// # Safety
+/// `limit` has to be the limit of an `OwnedStack` that is still allocated.
+pub unsafe fn canary_intact(limit: *const u8) -> bool {
+    let canary = limit as *const u64;
+    (0..CANARY_SIZE / 8).all(|i| ptr::read_volatile(canary.add(i)) == STACK_CANARY)
+}
+
+/// Returns how many bytes of a stack that was allocated by
+/// [`OwnedStack::new`] were used so far.
+///
+/// # Safety
+/// `limit` and `size` have to describe an `OwnedStack` that is still
+/// allocated. The stack can be in use (by another core) while we look at it.
+pub unsafe fn max_used(limit: *const u8, size: usize) -> usize {
+    let words = limit.add(CANARY_SIZE) as *const u64;
+    let unused = (0..(size - CANARY_SIZE) / 8)
+        .take_while(|i| ptr::read_volatile(words.add(*i)) == STACK_PAINT)
+        .count();
+    size - CANARY_SIZE - unused * 8
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct StackPointer(*mut usize);
 
@@ -107,6 +141,10 @@ unsafe impl<'a> Stack for SliceStack<'a> {
 }
 
 /// OwnedStack holds a non-guarded, heap-allocated stack.
+///
+/// The lowest [`CANARY_SIZE`] bytes of the stack are a canary that tells us
+/// if the stack overflowed, the rest is painted to measure how much of the
+/// stack is used.
 #[derive(Debug)]
 pub struct OwnedStack(Box<[u8]>);
 
@@ -116,13 +154,40 @@ impl OwnedStack {
     pub fn new(size: usize) -> OwnedStack {
         unsafe {
             let aligned_size = size & !(STACK_ALIGNMENT - 1);
+            assert!(aligned_size > CANARY_SIZE, "Stack too small");
             let ptr = alloc(Layout::from_size_align_unchecked(
                 aligned_size,
                 STACK_ALIGNMENT,
             ));
+
+            let words = ptr as *mut u64;
+            for i in 0..aligned_size / 8 {
+                let pattern = if i < CANARY_SIZE / 8 {
+                    STACK_CANARY
+                } else {
+                    STACK_PAINT
+                };
+                ptr::write_volatile(words.add(i), pattern);
+            }
+
             OwnedStack(Box::from_raw(slice::from_raw_parts_mut(ptr, aligned_size)))
         }
     }
+
+    /// Size of the stack (in bytes).
+    pub fn size(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns false if the stack overflowed.
+    pub fn canary_intact(&self) -> bool {
+        unsafe { canary_intact(self.limit()) }
+    }
+
+    /// Returns how many bytes of the stack were used so far.
+    pub fn max_used(&self) -> usize {
+        unsafe { max_used(self.limit(), self.size()) }
+    }
 }
 
 unsafe impl Stack for OwnedStack {
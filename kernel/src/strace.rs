@@ -0,0 +1,145 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Tracing of the system calls a process makes (`ProcessOperation::Trace`).
+//!
+//! A process can turn tracing on or off for itself or one of its children at
+//! any time. The kernel then logs every system call of the traced process
+//! (once on entry and once with its result) and keeps the last [`RECORDS`]
+//! completed calls in a ring buffer that `ProcessOperation::ReadTrace`
+//! drains. If nobody traces anything, the system call path only pays for
+//! one atomic load.
+
+// `enter`, `exit` etc. are only called by the x86-64 kernel
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use kpi::process::SyscallRecord;
+use log::info;
+
+use crate::process::{Pid, MAX_PROCESSES};
+use crate::sync::Mutex;
+
+/// How many system calls we keep per traced process (the oldest get
+/// overwritten).
+pub const RECORDS: usize = 64;
+
+const EMPTY: SyscallRecord = SyscallRecord {
+    function: 0,
+    operation: 0,
+    args: [0; 4],
+    error: 0,
+    ret: [0; 2],
+    core: 0,
+};
+
+struct Trace {
+    records: [SyscallRecord; RECORDS],
+    /// Index of the oldest record.
+    head: usize,
+    /// Number of valid records.
+    len: usize,
+}
+
+impl Trace {
+    const fn new() -> Self {
+        Trace {
+            records: [EMPTY; RECORDS],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    fn push(&mut self, record: SyscallRecord) {
+        let tail = (self.head + self.len) % RECORDS;
+        self.records[tail] = record;
+        if self.len < RECORDS {
+            self.len += 1;
+        } else {
+            self.head = (self.head + 1) % RECORDS;
+        }
+    }
+
+    fn pop(&mut self) -> Option<SyscallRecord> {
+        if self.len == 0 {
+            return None;
+        }
+        let record = self.records[self.head];
+        self.head = (self.head + 1) % RECORDS;
+        self.len -= 1;
+        Some(record)
+    }
+}
+
+/// Number of processes that are currently traced.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Which processes are traced.
+static TRACED: [AtomicBool; MAX_PROCESSES] = {
+    const OFF: AtomicBool = AtomicBool::new(false);
+    [OFF; MAX_PROCESSES]
+};
+
+/// The recorded system calls of every process.
+static TRACES: [Mutex<Trace>; MAX_PROCESSES] = {
+    const TRACE: Mutex<Trace> = Mutex::new(Trace::new());
+    [TRACE; MAX_PROCESSES]
+};
+
+/// Is any process traced?
+#[inline]
+pub fn active() -> bool {
+    ACTIVE.load(Ordering::Relaxed) > 0
+}
+
+/// Is `pid` traced?
+pub fn is_traced(pid: Pid) -> bool {
+    TRACED
+        .get(pid)
+        .map_or(false, |traced| traced.load(Ordering::Relaxed))
+}
+
+/// Turns tracing of `pid` on or off, clears what was recorded so far.
+pub fn set(pid: Pid, enable: bool) {
+    if let Some(traced) = TRACED.get(pid) {
+        TRACES[pid].lock().clear();
+        match (traced.swap(enable, Ordering::Relaxed), enable) {
+            (false, true) => {
+                ACTIVE.fetch_add(1, Ordering::Relaxed);
+            }
+            (true, false) => {
+                ACTIVE.fetch_sub(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Stops tracing `pid` (it gets reused for a new process).
+pub fn reset(pid: Pid) {
+    set(pid, false);
+}
+
+/// `pid` entered the kernel with the system call in `record`.
+pub fn enter(pid: Pid, record: &SyscallRecord) {
+    info!("[pid {}] {} ...", pid, record.call());
+}
+
+/// The system call of `pid` in `record` completed.
+pub fn exit(pid: Pid, record: SyscallRecord) {
+    info!("[pid {}] {}", pid, record);
+    if is_traced(pid) {
+        TRACES[pid].lock().push(record);
+    }
+}
+
+/// Removes the oldest recorded system call of `pid`.
+pub fn pop(pid: Pid) -> Option<SyscallRecord> {
+    TRACES.get(pid).and_then(|trace| trace.lock().pop())
+}
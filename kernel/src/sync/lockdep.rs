@@ -0,0 +1,422 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A lock-ordering checker for kernel spinlocks (a very reduced version of
+//! Linux' lockdep).
+//!
+//! Every [`Mutex`] belongs to a lock class, which is identified by the source
+//! location that created the lock (so all the per-NUMA node caches created in
+//! the same loop share a class). [`Mutex::new`] is a `const fn` so statics can
+//! use it too, the class is looked up when the lock is first acquired.
+//! Whenever a lock of class B is acquired while a lock of class A is held, we
+//! remember the edge A -> B. If we later find a core acquiring A while holding
+//! B, the two code paths can deadlock and we panic, printing the backtraces of
+//! both acquisitions.
+//!
+//! In addition, every lock tracks the core that currently owns it (to catch
+//! recursive locking which would otherwise just hang the core) and every class
+//! keeps statistics about how long it is held (in rdtsc cycles).
+//!
+//! None of the bookkeeping here is allowed to allocate memory since the memory
+//! allocator itself is protected by these locks.
+
+use core::cell::UnsafeCell;
+use core::ffi::c_void;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+use klogger::sprintln;
+use log::info;
+
+use crate::kcb;
+
+/// Maximum number of distinct lock classes we can track.
+const MAX_CLASSES: usize = 128;
+
+/// Maximum number of cores that can hold locks.
+const MAX_CORES: usize = 256;
+
+/// Maximum number of locks a single core can hold at the same time.
+const MAX_HELD: usize = 8;
+
+/// Number of frames we record for each acquisition.
+const BACKTRACE_DEPTH: usize = 6;
+
+/// Marks a lock as not owned by any core.
+const NO_OWNER: usize = usize::MAX;
+
+/// Marks a lock whose class wasn't looked up yet.
+const NO_CLASS: usize = usize::MAX;
+
+/// Statistics we keep for every lock class.
+struct LockClass {
+    /// Where locks of this class are created.
+    site: AtomicPtr<Location<'static>>,
+    /// How many times locks in this class were acquired.
+    acquisitions: AtomicU64,
+    /// How many times a core had to spin on a lock in this class.
+    contended: AtomicU64,
+    /// Sum of all hold times (in cycles).
+    total_hold: AtomicU64,
+    /// Longest hold time observed (in cycles).
+    max_hold: AtomicU64,
+}
+
+impl LockClass {
+    const fn new() -> Self {
+        LockClass {
+            site: AtomicPtr::new(ptr::null_mut()),
+            acquisitions: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            total_hold: AtomicU64::new(0),
+            max_hold: AtomicU64::new(0),
+        }
+    }
+}
+
+/// A lock that is currently held by a core.
+#[derive(Copy, Clone)]
+struct HeldLock {
+    /// Address of the lock (to find it again on release).
+    lock: usize,
+    /// Class index of the lock.
+    class: usize,
+    /// Where the lock was acquired.
+    site: &'static Location<'static>,
+    /// Return addresses of the acquiring call-chain.
+    backtrace: [usize; BACKTRACE_DEPTH],
+}
+
+/// The stack of locks held by a single core.
+///
+/// Only ever accessed by the core that owns it.
+struct HeldLocks {
+    depth: UnsafeCell<usize>,
+    locks: UnsafeCell<[Option<HeldLock>; MAX_HELD]>,
+}
+
+unsafe impl Sync for HeldLocks {}
+
+impl HeldLocks {
+    const fn new() -> Self {
+        HeldLocks {
+            depth: UnsafeCell::new(0),
+            locks: UnsafeCell::new([None; MAX_HELD]),
+        }
+    }
+}
+
+const CLASS_INIT: LockClass = LockClass::new();
+static CLASSES: [LockClass; MAX_CLASSES] = [CLASS_INIT; MAX_CLASSES];
+
+const EDGE_INIT: AtomicPtr<Location<'static>> = AtomicPtr::new(ptr::null_mut());
+const EDGE_ROW_INIT: [AtomicPtr<Location<'static>>; MAX_CLASSES] = [EDGE_INIT; MAX_CLASSES];
+/// `ORDER[a][b]` is the location where we first acquired class `b` while
+/// holding class `a` (or null if that never happened).
+static ORDER: [[AtomicPtr<Location<'static>>; MAX_CLASSES]; MAX_CLASSES] =
+    [EDGE_ROW_INIT; MAX_CLASSES];
+
+const HELD_INIT: HeldLocks = HeldLocks::new();
+static HELD: [HeldLocks; MAX_CORES] = [HELD_INIT; MAX_CORES];
+
+/// Returns the id of the current core.
+///
+/// Locks can be taken before the KCB is installed, at that point we're
+/// still only running on the BSP.
+fn core_id() -> usize {
+    kcb::try_get_kcb().map_or(0, |k| k.arch.id())
+}
+
+/// Finds (or allocates) the class for locks created at `site`.
+fn class_for(site: &'static Location<'static>) -> usize {
+    let site_ptr = site as *const Location<'static> as *mut Location<'static>;
+    for (idx, class) in CLASSES.iter().enumerate() {
+        let existing = class.site.load(Ordering::Acquire);
+        if existing == site_ptr {
+            return idx;
+        }
+        if existing.is_null() {
+            match class.site.compare_exchange(
+                ptr::null_mut(),
+                site_ptr,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return idx,
+                Err(other) if other == site_ptr => return idx,
+                Err(_) => continue,
+            }
+        }
+    }
+    panic!("lockdep: out of lock classes, increase MAX_CLASSES");
+}
+
+/// Returns the location where locks of `class` are created.
+fn class_site(class: usize) -> &'static Location<'static> {
+    let site = CLASSES[class].site.load(Ordering::Acquire);
+    debug_assert!(!site.is_null());
+    // Safety: We only store `&'static Location` in CLASSES.
+    unsafe { &*site }
+}
+
+/// Records the return addresses of the current call-chain.
+fn capture_backtrace() -> [usize; BACKTRACE_DEPTH] {
+    let mut frames = [0; BACKTRACE_DEPTH];
+    let mut idx = 0;
+    backtracer_core::trace(|frame| {
+        frames[idx] = frame.ip() as usize;
+        idx += 1;
+        idx < BACKTRACE_DEPTH
+    });
+    frames
+}
+
+fn print_acquisition(what: &str, site: &'static Location<'static>, frames: &[usize]) {
+    sprintln!("{} acquired at {}:{}", what, site.file(), site.line());
+    for (count, ip) in frames.iter().filter(|ip| **ip != 0).enumerate() {
+        crate::panic::backtrace_ip(count + 1, *ip as *mut c_void);
+    }
+}
+
+/// Verifies that acquiring a lock of `class` is consistent with the order in
+/// which the current core already holds its locks, then pushes it on the
+/// held stack of the core.
+fn acquire(lock: usize, class: usize, site: &'static Location<'static>) {
+    let held = &HELD[core_id()];
+    // Safety: The held stack is only accessed by the core that owns it
+    // (interrupt handlers on this core acquire and release in LIFO order).
+    let (depth, locks) = unsafe { (&mut *held.depth.get(), &mut *held.locks.get()) };
+    let backtrace = capture_backtrace();
+
+    for h in locks[..*depth].iter().flatten() {
+        if h.class == class {
+            // Nested locks from the same class (e.g., two NCaches) are allowed.
+            continue;
+        }
+
+        let reverse = ORDER[class][h.class].load(Ordering::Acquire);
+        if !reverse.is_null() {
+            // Safety: We only store `&'static Location` in ORDER.
+            let reverse: &'static Location<'static> = unsafe { &*reverse };
+            sprintln!(
+                "lockdep: lock order violation, acquiring {}:{} while holding {}:{}",
+                site.file(),
+                site.line(),
+                h.site.file(),
+                h.site.line()
+            );
+            sprintln!(
+                "lockdep: the opposite order was established at {}:{}",
+                reverse.file(),
+                reverse.line()
+            );
+            print_acquisition("Held lock", h.site, &h.backtrace);
+            print_acquisition("New lock", site, &backtrace);
+            panic!("lockdep: possible deadlock detected");
+        }
+
+        let _r = ORDER[h.class][class].compare_exchange(
+            ptr::null_mut(),
+            site as *const Location<'static> as *mut Location<'static>,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+    }
+
+    assert!(*depth < MAX_HELD, "lockdep: too many locks held");
+    locks[*depth] = Some(HeldLock {
+        lock,
+        class,
+        site,
+        backtrace,
+    });
+    *depth += 1;
+}
+
+/// Removes the lock at address `lock` from the held stack of the current core.
+fn release(lock: usize) {
+    let held = &HELD[core_id()];
+    // Safety: See `acquire`.
+    let (depth, locks) = unsafe { (&mut *held.depth.get(), &mut *held.locks.get()) };
+
+    // Guards don't have to be dropped in LIFO order, so search the stack.
+    if let Some(pos) = locks[..*depth]
+        .iter()
+        .rposition(|h| h.map_or(false, |h| h.lock == lock))
+    {
+        locks.copy_within(pos + 1..*depth, pos);
+        *depth -= 1;
+        locks[*depth] = None;
+    }
+}
+
+/// Prints the hold-time statistics for every lock class.
+#[allow(unused)]
+pub fn dump_stats() {
+    for (idx, class) in CLASSES.iter().enumerate() {
+        if class.site.load(Ordering::Acquire).is_null() {
+            break;
+        }
+        let site = class_site(idx);
+        let acquisitions = class.acquisitions.load(Ordering::Relaxed);
+        let total = class.total_hold.load(Ordering::Relaxed);
+        info!(
+            "lockdep class {} ({}:{}): acquired {} contended {} avg-hold {} max-hold {} cycles",
+            idx,
+            site.file(),
+            site.line(),
+            acquisitions,
+            class.contended.load(Ordering::Relaxed),
+            total.checked_div(acquisitions).unwrap_or(0),
+            class.max_hold.load(Ordering::Relaxed),
+        );
+    }
+}
+
+/// A spinlock with owner tracking and lock-order checking.
+pub struct Mutex<T: ?Sized> {
+    /// Where the lock was created.
+    site: &'static Location<'static>,
+    /// Class index of this lock (or `NO_CLASS` before the first acquisition).
+    class: AtomicUsize,
+    /// Core that currently holds the lock (or `NO_OWNER`).
+    owner: AtomicUsize,
+    /// rdtsc timestamp of the last acquisition.
+    locked_at: AtomicU64,
+    inner: spin::Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    /// Creates a new lock, the class is determined by the caller location.
+    #[track_caller]
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            site: Location::caller(),
+            class: AtomicUsize::new(NO_CLASS),
+            owner: AtomicUsize::new(NO_OWNER),
+            locked_at: AtomicU64::new(0),
+            inner: spin::Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Class index of this lock, looked up on first use.
+    fn class(&self) -> usize {
+        match self.class.load(Ordering::Relaxed) {
+            NO_CLASS => {
+                let class = class_for(self.site);
+                self.class.store(class, Ordering::Relaxed);
+                class
+            }
+            class => class,
+        }
+    }
+
+    /// Acquires the lock, spinning until it is available.
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<T> {
+        let me = core_id();
+        if self.owner.load(Ordering::Relaxed) == me {
+            panic!(
+                "lockdep: recursive locking of lock created at {}:{}",
+                self.site.file(),
+                self.site.line()
+            );
+        }
+
+        let class = self.class();
+        let lock = self as *const Self as *const () as usize;
+        acquire(lock, class, Location::caller());
+
+        let inner = match self.inner.try_lock() {
+            Some(guard) => guard,
+            None => {
+                CLASSES[class].contended.fetch_add(1, Ordering::Relaxed);
+                self.inner.lock()
+            }
+        };
+
+        self.owner.store(me, Ordering::Relaxed);
+        self.locked_at
+            .store(unsafe { x86::time::rdtsc() }, Ordering::Relaxed);
+        CLASSES[class].acquisitions.fetch_add(1, Ordering::Relaxed);
+
+        MutexGuard {
+            lock: self,
+            inner: Some(inner),
+        }
+    }
+
+    /// Tries to acquire the lock without spinning.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        let inner = self.inner.try_lock()?;
+        let class = self.class();
+        let lock = self as *const Self as *const () as usize;
+        acquire(lock, class, Location::caller());
+
+        self.owner.store(core_id(), Ordering::Relaxed);
+        self.locked_at
+            .store(unsafe { x86::time::rdtsc() }, Ordering::Relaxed);
+        CLASSES[class].acquisitions.fetch_add(1, Ordering::Relaxed);
+
+        Some(MutexGuard {
+            lock: self,
+            inner: Some(inner),
+        })
+    }
+
+    /// Returns true if the lock is currently held.
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Core that currently holds the lock.
+    pub fn owner(&self) -> Option<usize> {
+        match self.owner.load(Ordering::Relaxed) {
+            NO_OWNER => None,
+            core => Some(core),
+        }
+    }
+}
+
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+
+/// The guard returned by [`Mutex::lock`].
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+    lock: &'a Mutex<T>,
+    inner: Option<spin::MutexGuard<'a, T>>,
+}
+
+impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.inner.as_ref().unwrap()
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.inner.as_mut().unwrap()
+    }
+}
+
+impl<'a, T: ?Sized> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        let held_for = unsafe { x86::time::rdtsc() }
+            .saturating_sub(self.lock.locked_at.load(Ordering::Relaxed));
+        let class = &CLASSES[self.lock.class()];
+        class.total_hold.fetch_add(held_for, Ordering::Relaxed);
+        class.max_hold.fetch_max(held_for, Ordering::Relaxed);
+
+        self.lock.owner.store(NO_OWNER, Ordering::Relaxed);
+        release(self.lock as *const Mutex<T> as *const () as usize);
+        // Release the spinlock last, after we're done with the bookkeeping.
+        drop(self.inner.take());
+    }
+}
@@ -0,0 +1,22 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Locking primitives used inside the kernel.
+//!
+//! By default these are just the spinlocks from the `spin` crate. When the
+//! kernel is compiled with the `lockdep` feature, the locks are replaced with
+//! wrappers that track owners, collect hold-time statistics and verify that
+//! locks are always acquired in a consistent order (see [`lockdep`]).
+//!
+//! Read-mostly data can be protected with [`rcu`] instead, readers don't
+//! take any locks.
+
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+pub mod rcu;
+
+#[cfg(feature = "lockdep")]
+pub use lockdep::{Mutex, MutexGuard};
+
+#[cfg(not(feature = "lockdep"))]
+pub use spin::{Mutex, MutexGuard};
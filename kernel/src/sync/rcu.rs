@@ -0,0 +1,343 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Epoch-based reclamation for read-mostly kernel data (RCU-style).
+//!
+//! Readers enter a read-side critical section with [`read_lock`], which
+//! only publishes the current global epoch for their core (no locks, no
+//! shared cache-lines written). Writers publish a new version of the data
+//! (e.g., with [`Rcu::replace`]) and hand the old one to [`defer`]: it is
+//! only dropped once every core went through a quiescent state, i.e., left
+//! the read-side critical section it may have seen the old version in.
+//!
+//! The global epoch advances once all cores in a read-side critical section
+//! entered it in the current epoch. Things retired in epoch `e` are dropped
+//! once the global epoch reached `e + 2`. Cores that aren't in a read-side
+//! critical section (running user-space, idle, or anywhere else in the
+//! kernel) don't hold up the epoch. The timer raises the
+//! [`SoftIrq::Rcu`](crate::softirq::SoftIrq) soft IRQ on every core which
+//! advances the epoch and drops what the core retired ([`reclaim`]).
+//!
+//! If the epoch doesn't advance for [`STALL_US`], we report the cores that
+//! hold it up together with the location of their [`read_lock`] call.
+
+// `reclaim` is only registered by the x86-64 kernel
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::hint::spin_loop;
+use core::marker::PhantomData;
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+use crossbeam_utils::CachePadded;
+use log::warn;
+
+use crate::arch::traits::Timer as _;
+use crate::arch::{Platform, MAX_CORES};
+use crate::error::KError;
+use crate::sync::Mutex;
+
+/// Report a stall if the epoch didn't advance for this long.
+pub const STALL_US: u64 = 1_000_000;
+
+/// `Local::state` of a core outside a read-side critical section (otherwise
+/// it's the epoch the core saw when it entered it).
+const QUIESCENT: u64 = u64::MAX;
+
+/// The global epoch.
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// When the epoch last advanced (`Platform::global_now`).
+static ADVANCED: AtomicU64 = AtomicU64::new(0);
+
+/// The epoch we last reported a stall for.
+static STALL_REPORTED: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Number of stalls we reported.
+static STALLS: AtomicUsize = AtomicUsize::new(0);
+
+/// Read-side state of a core.
+struct Local {
+    state: AtomicU64,
+    /// Nesting depth of read-side critical sections (only the core itself
+    /// touches it).
+    depth: AtomicUsize,
+    /// Where the outermost read-side critical section started.
+    site: AtomicPtr<Location<'static>>,
+}
+
+static LOCALS: [CachePadded<Local>; MAX_CORES] = {
+    const LOCAL: CachePadded<Local> = CachePadded::new(Local {
+        state: AtomicU64::new(QUIESCENT),
+        depth: AtomicUsize::new(0),
+        site: AtomicPtr::new(ptr::null_mut()),
+    });
+    [LOCAL; MAX_CORES]
+};
+
+/// Something retired in `epoch`, `callback` drops it.
+struct Retired {
+    epoch: u64,
+    callback: Box<dyn FnOnce() + Send>,
+}
+
+/// What every core retired and didn't drop yet.
+static RETIRED: [Mutex<Vec<Retired>>; MAX_CORES] = {
+    const EMPTY: Mutex<Vec<Retired>> = Mutex::new(Vec::new());
+    [EMPTY; MAX_CORES]
+};
+
+fn core_id() -> usize {
+    crate::kcb::try_get_kcb().map_or(0, |kcb| kcb.arch.hwthread_id())
+}
+
+/// A read-side critical section, ends when dropped.
+///
+/// References to RCU-protected data can't outlive the guard. The guard
+/// can't be moved to another core.
+pub struct ReadGuard {
+    core: usize,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for ReadGuard {
+    fn drop(&mut self) {
+        unpin(self.core);
+    }
+}
+
+/// Enters a read-side critical section on the current core.
+#[track_caller]
+pub fn read_lock() -> ReadGuard {
+    let core = core_id();
+    pin(core, Location::caller());
+    ReadGuard {
+        core,
+        _not_send: PhantomData,
+    }
+}
+
+fn pin(core: usize, site: &'static Location<'static>) {
+    let local = &LOCALS[core];
+    if local.depth.fetch_add(1, Ordering::Relaxed) == 0 {
+        local
+            .site
+            .store(site as *const _ as *mut _, Ordering::Relaxed);
+        local
+            .state
+            .store(EPOCH.load(Ordering::Relaxed), Ordering::Relaxed);
+        // Publish the epoch before we read any protected data
+        fence(Ordering::SeqCst);
+    }
+}
+
+fn unpin(core: usize) {
+    let local = &LOCALS[core];
+    if local.depth.fetch_sub(1, Ordering::Relaxed) == 1 {
+        local.state.store(QUIESCENT, Ordering::Release);
+    }
+}
+
+/// Advances the global epoch if every core in a read-side critical section
+/// saw the current one.
+///
+/// Returns the (possibly new) global epoch.
+fn try_advance() -> u64 {
+    let epoch = EPOCH.load(Ordering::Relaxed);
+    fence(Ordering::SeqCst);
+    let lagging = LOCALS.iter().any(|local| {
+        let state = local.state.load(Ordering::Relaxed);
+        state != QUIESCENT && state != epoch
+    });
+    if lagging {
+        return epoch;
+    }
+
+    fence(Ordering::Acquire);
+    match EPOCH.compare_exchange(epoch, epoch + 1, Ordering::Release, Ordering::Relaxed) {
+        Ok(_) => {
+            ADVANCED.store(Platform::global_now(), Ordering::Relaxed);
+            epoch + 1
+        }
+        Err(current) => current,
+    }
+}
+
+/// Drops `t` once no core can be in a read-side critical section that saw
+/// it.
+pub fn defer_drop<T: Send + 'static>(t: T) -> Result<(), KError> {
+    defer(move || drop(t))
+}
+
+/// Calls `callback` on the current core once all read-side critical
+/// sections that are active right now ended.
+pub fn defer<F: FnOnce() + Send + 'static>(callback: F) -> Result<(), KError> {
+    let callback: Box<dyn FnOnce() + Send> = Box::try_new(callback)?;
+    let mut retired = RETIRED[core_id()].lock();
+    retired.try_reserve(1)?;
+    retired.push(Retired {
+        epoch: EPOCH.load(Ordering::Relaxed),
+        callback,
+    });
+    Ok(())
+}
+
+/// Waits until all read-side critical sections that are active right now
+/// ended (must not be called from inside one).
+pub fn synchronize() {
+    debug_assert_eq!(
+        LOCALS[core_id()].depth.load(Ordering::Relaxed),
+        0,
+        "synchronize in a read-side critical section"
+    );
+    let target = EPOCH.load(Ordering::Relaxed) + 2;
+    while try_advance() < target {
+        report_stall();
+        spin_loop();
+    }
+}
+
+/// Advances the epoch and drops what the current core retired and no
+/// reader can see anymore (the RCU soft IRQ).
+pub fn reclaim() {
+    let epoch = try_advance();
+    report_stall();
+
+    let ready: Vec<Retired> = {
+        let mut retired = match RETIRED[core_id()].try_lock() {
+            Some(retired) => retired,
+            None => return,
+        };
+        // The list is in epoch order
+        let ready = retired
+            .iter()
+            .position(|r| r.epoch + 2 > epoch)
+            .unwrap_or(retired.len());
+        if ready == 0 {
+            return;
+        }
+        retired.drain(..ready).collect()
+    };
+    for retired in ready {
+        (retired.callback)();
+    }
+}
+
+/// Reports the cores that keep the epoch from advancing (once per epoch) if
+/// it didn't advance for [`STALL_US`].
+fn report_stall() {
+    let epoch = EPOCH.load(Ordering::Relaxed);
+    let since = ADVANCED.load(Ordering::Relaxed);
+    let stalled_us = crate::timer_wheel::cycles_to_us(Platform::global_now().saturating_sub(since));
+    if stalled_us < STALL_US || STALL_REPORTED.swap(epoch, Ordering::Relaxed) == epoch {
+        return;
+    }
+
+    STALLS.fetch_add(1, Ordering::Relaxed);
+    warn!(
+        "RCU stall: epoch {} didn't advance for {} ms",
+        epoch,
+        stalled_us / 1000
+    );
+    for (core, local) in LOCALS.iter().enumerate() {
+        let state = local.state.load(Ordering::Relaxed);
+        if state != QUIESCENT && state != epoch {
+            let site = local.site.load(Ordering::Relaxed);
+            match unsafe { site.as_ref() } {
+                Some(site) => warn!(
+                    "  core {} in read-side critical section since epoch {} ({})",
+                    core, state, site
+                ),
+                None => warn!(
+                    "  core {} in read-side critical section since epoch {}",
+                    core, state
+                ),
+            }
+        }
+    }
+}
+
+/// The current global epoch.
+pub fn epoch() -> u64 {
+    EPOCH.load(Ordering::Relaxed)
+}
+
+/// How many stalls we reported.
+pub fn stalls() -> usize {
+    STALLS.load(Ordering::Relaxed)
+}
+
+/// A pointer to RCU-protected data.
+///
+/// Readers get a reference that is valid for their read-side critical
+/// section, writers replace the data as a whole. Writers have to be
+/// serialized by the caller (e.g., with a lock).
+pub struct Rcu<T: Send + Sync + 'static> {
+    ptr: AtomicPtr<T>,
+}
+
+/// The old value of a [`Rcu::replace`] (we drop it on another core maybe).
+struct Retiree<T>(*mut T);
+
+unsafe impl<T: Send> Send for Retiree<T> {}
+
+impl<T: Send + Sync + 'static> Rcu<T> {
+    pub fn new(value: Box<T>) -> Rcu<T> {
+        Rcu {
+            ptr: AtomicPtr::new(Box::into_raw(value)),
+        }
+    }
+
+    /// The current value.
+    pub fn read<'g>(&self, _guard: &'g ReadGuard) -> &'g T {
+        unsafe { &*self.ptr.load(Ordering::Acquire) }
+    }
+
+    /// Publishes `value`, the old value is dropped once no reader can see
+    /// it anymore.
+    pub fn replace(&self, value: Box<T>) {
+        let old = self.ptr.swap(Box::into_raw(value), Ordering::AcqRel);
+        let retiree = Retiree(old);
+        if defer(move || drop(unsafe { Box::from_raw(retiree.0) })).is_err() {
+            // Can't allocate the entry, wait for the readers instead
+            synchronize();
+            drop(unsafe { Box::from_raw(old) });
+        }
+    }
+}
+
+impl<T: Send + Sync + 'static> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        // Nobody can read it anymore: readers borrow `self`
+        drop(unsafe { Box::from_raw(*self.ptr.get_mut()) });
+    }
+}
+
+unsafe impl<T: Send + Sync + 'static> Send for Rcu<T> {}
+unsafe impl<T: Send + Sync + 'static> Sync for Rcu<T> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn epoch_waits_for_readers() {
+        let site = Location::caller();
+        let start = try_advance();
+
+        // A reader pinned in the current epoch lets it advance once
+        pin(1, site);
+        pin(1, site);
+        assert_eq!(try_advance(), start + 1);
+        assert_eq!(try_advance(), start + 1);
+
+        // Nested sections only end with the outermost one
+        unpin(1);
+        assert_eq!(try_advance(), start + 1);
+        unpin(1);
+        assert_eq!(try_advance(), start + 2);
+    }
+}
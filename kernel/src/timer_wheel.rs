@@ -0,0 +1,421 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A hierarchical timer wheel for kernel-internal timeouts.
+//!
+//! Every core has its own wheel (`Kcb::timers`), timers run on the core that
+//! added them from the soft IRQ its timer interrupt raises ([`run_expired`],
+//! see `softirq`). The wheel makes sure the per-core deadline timer
+//! (`Timer::set`) fires in time for its earliest timer.
+//!
+//! The wheel has [`LEVELS`] levels of [`SLOTS`] slots. A slot on level 0
+//! covers one tick ([`TICK_SHIFT`]), a slot on level `n` covers the range of
+//! the whole level `n - 1`. Timers are put in the level that matches how far
+//! in the future they expire and move down one level whenever the slot they
+//! are in comes up (*cascading*). Adding and removing a timer is cheap,
+//! timers that expire later than the wheel covers wait on an overflow list.
+//!
+//! A timer can be moved to another core with [`migrate`]. It is put in the
+//! inbox of that core and added to its wheel on the next timer interrupt of
+//! the core.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use fallible_collections::FallibleVec;
+use log::trace;
+
+use crate::arch::traits::Timer as _;
+use crate::arch::{Platform, MAX_CORES};
+use crate::error::KError;
+use crate::sync::Mutex;
+
+/// Bits of the tick per level.
+const LEVEL_BITS: u64 = 6;
+
+/// Slots per level.
+pub const SLOTS: usize = 1 << LEVEL_BITS;
+
+/// Number of levels.
+pub const LEVELS: usize = 4;
+
+/// A tick of the wheel is 2^TICK_SHIFT TSC cycles (~0.3-0.5 ms).
+pub const TICK_SHIFT: u64 = 20;
+
+/// Identifies a timer (unique in the system).
+pub type TimerId = u64;
+
+/// Function to call when a timer expires, with the argument given to [`add`].
+pub type Callback = fn(u64);
+
+/// A pending timer.
+#[derive(Debug, Clone, Copy)]
+pub struct Timer {
+    pub id: TimerId,
+    /// Tick at which the timer expires.
+    expires: u64,
+    callback: Callback,
+    arg: u64,
+}
+
+impl Timer {
+    fn fire(self) {
+        trace!("Timer {} expired", self.id);
+        (self.callback)(self.arg);
+    }
+}
+
+/// The timers of a core.
+pub struct TimerWheel {
+    /// The last tick we processed.
+    now: u64,
+    slots: [Vec<Timer>; LEVELS * SLOTS],
+    /// Timers beyond the range of the wheel.
+    overflow: Vec<Timer>,
+    len: usize,
+}
+
+impl TimerWheel {
+    pub const fn new() -> TimerWheel {
+        const EMPTY: Vec<Timer> = Vec::new();
+        TimerWheel {
+            now: 0,
+            slots: [EMPTY; LEVELS * SLOTS],
+            overflow: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// Number of pending timers.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The slot for a timer that expires at tick `expires` (`None` for the
+    /// overflow list).
+    fn slot(&self, expires: u64) -> Option<usize> {
+        // Timers that are already due fire on the next tick
+        let expires = expires.max(self.now + 1);
+        let delta = expires - self.now;
+        (0..LEVELS).find_map(|level| {
+            let shift = LEVEL_BITS * level as u64;
+            if delta < 1 << (shift + LEVEL_BITS) {
+                Some(level * SLOTS + ((expires >> shift) as usize & (SLOTS - 1)))
+            } else {
+                None
+            }
+        })
+    }
+
+    fn list(&mut self, slot: Option<usize>) -> &mut Vec<Timer> {
+        match slot {
+            Some(slot) => &mut self.slots[slot],
+            None => &mut self.overflow,
+        }
+    }
+
+    fn insert(&mut self, timer: Timer) -> Result<(), KError> {
+        let slot = self.slot(timer.expires);
+        self.list(slot).try_push(timer)?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Removes the timer `id`.
+    pub fn remove(&mut self, id: TimerId) -> Option<Timer> {
+        for list in self
+            .slots
+            .iter_mut()
+            .chain(core::iter::once(&mut self.overflow))
+        {
+            if let Some(idx) = list.iter().position(|t| t.id == id) {
+                self.len -= 1;
+                return Some(list.swap_remove(idx));
+            }
+        }
+        None
+    }
+
+    /// The tick at which the earliest timer expires.
+    pub fn next_expiry(&self) -> Option<u64> {
+        self.slots
+            .iter()
+            .chain(core::iter::once(&self.overflow))
+            .flat_map(|list| list.iter())
+            .map(|t| t.expires)
+            .min()
+    }
+
+    /// Moves the timers in `slot` to where they belong now.
+    ///
+    /// Timers we can't move (out of memory) stay in `slot`, we try again the
+    /// next time it comes up.
+    fn cascade(&mut self, slot: Option<usize>) {
+        let mut timers = core::mem::take(self.list(slot));
+        timers.retain(|timer| {
+            let to = self.slot(timer.expires);
+            to == slot || self.list(to).try_push(*timer).is_err()
+        });
+        *self.list(slot) = timers;
+    }
+
+    /// Advances the wheel to tick `now`, appends the timers that expired to
+    /// `expired`.
+    pub fn advance(&mut self, now: u64, expired: &mut Vec<Timer>) -> Result<(), KError> {
+        while self.now < now {
+            if self.is_empty() {
+                self.now = now;
+                break;
+            }
+
+            let tick = self.now + 1;
+            self.now = tick;
+            for level in (1..=LEVELS).rev() {
+                let shift = LEVEL_BITS * level as u64;
+                if tick & ((1 << shift) - 1) == 0 {
+                    if level == LEVELS {
+                        self.cascade(None);
+                    } else {
+                        let slot = (tick >> shift) as usize & (SLOTS - 1);
+                        self.cascade(Some(level * SLOTS + slot));
+                    }
+                }
+            }
+
+            let list = &mut self.slots[tick as usize & (SLOTS - 1)];
+            expired.try_reserve(list.len())?;
+            self.len -= list.len();
+            expired.extend(list.drain(..));
+        }
+        Ok(())
+    }
+}
+
+/// TSC cycles per microsecond (set by [`calibrate`] or
+/// [`set_cycles_per_us`]).
+static CYCLES_PER_US: AtomicU64 = AtomicU64::new(2_000);
+
+/// Allocates [`TimerId`]s.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Timers migrated to a core that haven't been added to its wheel yet.
+static INBOXES: [Mutex<Vec<Timer>>; MAX_CORES] = {
+    const EMPTY: Mutex<Vec<Timer>> = Mutex::new(Vec::new());
+    [EMPTY; MAX_CORES]
+};
+
+/// Measures how many cycles the TSC advances per microsecond (takes 1 ms),
+/// call once during boot.
+#[cfg_attr(target_os = "none", allow(dead_code))]
+pub fn calibrate() {
+    let start = rawtime::Instant::now();
+    let start_cycles = Platform::now();
+    while start.elapsed() < Duration::from_millis(1) {
+        core::hint::spin_loop();
+    }
+    let cycles = Platform::now() - start_cycles;
+    let us = start.elapsed().as_micros() as u64;
+    CYCLES_PER_US.store(core::cmp::max(cycles / us, 1), Ordering::Relaxed);
+}
+
+/// Sets the TSC frequency, for architectures that measure it against a
+/// reference clock instead of calling [`calibrate`].
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn set_cycles_per_us(cycles: u64) {
+    CYCLES_PER_US.store(core::cmp::max(cycles, 1), Ordering::Relaxed);
+}
+
+/// Converts TSC `cycles` to microseconds.
+pub fn cycles_to_us(cycles: u64) -> u64 {
+    cycles / CYCLES_PER_US.load(Ordering::Relaxed)
+}
+
+/// Converts nanoseconds to TSC cycles (rounded up).
+pub fn ns_to_cycles(ns: u64) -> u64 {
+    let cycles = (ns as u128 * CYCLES_PER_US.load(Ordering::Relaxed) as u128 + 999) / 1000;
+    core::cmp::min(cycles, u64::MAX as u128) as u64
+}
+
+/// Converts TSC `cycles` to nanoseconds.
+pub fn cycles_to_ns(cycles: u64) -> u64 {
+    let ns = cycles as u128 * 1000 / CYCLES_PER_US.load(Ordering::Relaxed) as u128;
+    core::cmp::min(ns, u64::MAX as u128) as u64
+}
+
+fn ticks(after: Duration) -> u64 {
+    let cycles = after.as_micros() as u64 * CYCLES_PER_US.load(Ordering::Relaxed);
+    // Round up, timers never fire early
+    (cycles + (1 << TICK_SHIFT) - 1) >> TICK_SHIFT
+}
+
+fn current_tick() -> u64 {
+    Platform::now() >> TICK_SHIFT
+}
+
+/// Cycles until the earliest timer on the current core expires (or `None`
+/// if the core has no timers or its wheel is in use).
+pub fn next_deadline() -> Option<u64> {
+    let kcb = crate::kcb::try_get_kcb()?;
+    let wheel = kcb.timers.try_borrow().ok()?;
+    let expires = wheel.next_expiry()? << TICK_SHIFT;
+    Some(expires.saturating_sub(Platform::now()))
+}
+
+/// Arms the deadline timer for the earliest timer on the current core.
+fn arm() {
+    if let Some(deadline) = next_deadline() {
+        Platform::set(deadline);
+    }
+}
+
+/// Calls `callback(arg)` on the current core once `after` elapsed.
+pub fn add(after: Duration, callback: Callback, arg: u64) -> Result<TimerId, KError> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let timer = Timer {
+        id,
+        // +1: we're already somewhere into the current tick
+        expires: current_tick() + ticks(after) + 1,
+        callback,
+        arg,
+    };
+
+    let kcb = crate::kcb::per_core();
+    {
+        let mut wheel = kcb.timers.try_borrow_mut()?;
+        if wheel.is_empty() {
+            wheel.now = current_tick();
+        }
+        wheel.insert(timer)?;
+    }
+    arm();
+    Ok(id)
+}
+
+/// Removes the timer `id` from the current core, returns false if it isn't
+/// there (it expired, never existed or runs on another core).
+pub fn cancel(id: TimerId) -> bool {
+    let kcb = crate::kcb::per_core();
+    let removed = kcb
+        .timers
+        .try_borrow_mut()
+        .map(|mut wheel| wheel.remove(id).is_some())
+        .unwrap_or(false);
+    removed
+}
+
+/// Moves the timer `id` from the current core to core `to`.
+///
+/// The timer keeps its expiry time, it is picked up by `to` on its next
+/// timer interrupt.
+pub fn migrate(id: TimerId, to: usize) -> Result<(), KError> {
+    let inbox = INBOXES.get(to).ok_or(KError::InvalidGlobalThreadId)?;
+    let kcb = crate::kcb::per_core();
+    let timer = kcb
+        .timers
+        .try_borrow_mut()?
+        .remove(id)
+        .ok_or(KError::NotSupported)?;
+
+    let mut inbox = inbox.lock();
+    if let Err(e) = inbox.try_push(timer) {
+        drop(inbox);
+        // Keep it here rather than losing it
+        let _r = kcb.timers.try_borrow_mut().map(|mut w| w.insert(timer));
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Adds timers migrated to the current core and runs all timers that
+/// expired, called from the timer interrupt.
+pub fn run_expired() {
+    let kcb = match crate::kcb::try_get_kcb() {
+        Some(kcb) => kcb,
+        None => return,
+    };
+    let mut expired = Vec::new();
+    {
+        let mut wheel = match kcb.timers.try_borrow_mut() {
+            Ok(wheel) => wheel,
+            Err(_) => return,
+        };
+        if let Some(mut inbox) = INBOXES[kcb.arch.hwthread_id()].try_lock() {
+            if wheel.is_empty() {
+                wheel.now = current_tick();
+            }
+            while let Some(timer) = inbox.pop() {
+                if wheel.insert(timer).is_err() {
+                    let _r = inbox.try_push(timer);
+                    break;
+                }
+            }
+        }
+        if let Err(e) = wheel.advance(current_tick(), &mut expired) {
+            trace!("Can't advance timer wheel: {:?}", e);
+        }
+    }
+
+    for timer in expired {
+        timer.fire();
+    }
+    arm();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn nop(_arg: u64) {}
+
+    fn timer(id: TimerId, expires: u64) -> Timer {
+        Timer {
+            id,
+            expires,
+            callback: nop,
+            arg: 0,
+        }
+    }
+
+    fn expired_at(wheel: &mut TimerWheel, now: u64) -> Vec<TimerId> {
+        let mut expired = Vec::new();
+        wheel.advance(now, &mut expired).unwrap();
+        expired.iter().map(|t| t.id).collect()
+    }
+
+    #[test]
+    fn expires_in_order_across_levels() {
+        let mut wheel = TimerWheel::new();
+        let deadlines = [1, 63, 64, 65, 4095, 4096, 300_000, 20_000_000];
+        for (id, &expires) in deadlines.iter().enumerate() {
+            wheel.insert(timer(id as TimerId, expires)).unwrap();
+        }
+        assert_eq!(wheel.next_expiry(), Some(1));
+
+        for (id, &expires) in deadlines.iter().enumerate() {
+            assert!(expired_at(&mut wheel, expires - 1).is_empty());
+            assert_eq!(expired_at(&mut wheel, expires), [id as TimerId]);
+        }
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn remove_and_late_insert() {
+        let mut wheel = TimerWheel::new();
+        wheel.insert(timer(1, 100)).unwrap();
+        wheel.insert(timer(2, 100)).unwrap();
+        assert_eq!(wheel.remove(1).map(|t| t.id), Some(1));
+        assert!(wheel.remove(1).is_none());
+
+        assert!(expired_at(&mut wheel, 50).is_empty());
+        // Already due, fires on the next tick
+        wheel.insert(timer(3, 10)).unwrap();
+        assert_eq!(expired_at(&mut wheel, 51), [3]);
+        assert_eq!(expired_at(&mut wheel, 200), [2]);
+        assert_eq!(wheel.len(), 0);
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Filtering and rate limiting of the messages processes log
+//! (`ProcessOperation::Log`).
+//!
+//! Every process has a log level (`ulog=<level>` on the command line sets it
+//! for new processes, `Process::set_log_level` changes it), messages that are
+//! less severe are discarded. All output of a process (including plain
+//! prints) is rate limited with a token bucket of [`BURST`] bytes that
+//! refills with [`RATE`] bytes per second, so a process that floods the
+//! console can't starve everyone else who wants to print. Messages that
+//! don't fit are dropped and counted, the count is printed once the process
+//! can log again.
+
+// `init` is only called by the x86-64 kernel
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use kpi::process::LogLevel;
+use log::warn;
+
+use crate::process::{Pid, MAX_PROCESSES};
+use crate::sync::Mutex;
+
+/// Bytes per second a process can log (on average).
+pub const RATE: u64 = 64 * 1024;
+
+/// Bytes a process can log at once.
+pub const BURST: u64 = 256 * 1024;
+
+/// What to do with a message.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Verdict {
+    /// Print it, `dropped` messages were dropped before it.
+    Print { dropped: u64 },
+    /// Discard it, it's below the log level of the process.
+    Filtered,
+    /// Discard it, the process logged too much.
+    Dropped,
+}
+
+struct Bucket {
+    level: LogLevel,
+    /// Bytes the process can log right now.
+    tokens: u64,
+    /// When we last refilled `tokens` (TSC).
+    refilled: u64,
+    /// Messages dropped since the last one we printed.
+    dropped: u64,
+}
+
+impl Bucket {
+    const fn new() -> Self {
+        Bucket {
+            level: LogLevel::Trace,
+            tokens: BURST,
+            refilled: 0,
+            dropped: 0,
+        }
+    }
+
+    fn refill(&mut self, now: u64) {
+        let elapsed_us = crate::timer_wheel::cycles_to_us(now.saturating_sub(self.refilled));
+        let tokens = elapsed_us.saturating_mul(RATE) / 1_000_000;
+        // Don't move `refilled` until we earned a token, otherwise frequent
+        // calls would never refill anything
+        if tokens > 0 {
+            self.tokens = core::cmp::min(BURST, self.tokens.saturating_add(tokens));
+            self.refilled = now;
+        }
+    }
+}
+
+/// Log level of new processes (a `LogLevel` value).
+static DEFAULT_LEVEL: AtomicU64 = AtomicU64::new(LogLevel::Trace as u64);
+
+static PROCESSES: [Mutex<Bucket>; MAX_PROCESSES] = {
+    const BUCKET: Mutex<Bucket> = Mutex::new(Bucket::new());
+    [BUCKET; MAX_PROCESSES]
+};
+
+/// Sets the log level of new processes (`ulog=<level>` on the command line).
+pub fn init(level: &str) {
+    match LogLevel::from_name(level) {
+        Some(level) => DEFAULT_LEVEL.store(level as u64, Ordering::Relaxed),
+        None => warn!("Invalid ulog={} ignored", level),
+    }
+}
+
+/// Starts with a full bucket and the default log level for a new process
+/// `pid`.
+pub fn reset(pid: Pid) {
+    let level = LogLevel::ALL[DEFAULT_LEVEL.load(Ordering::Relaxed) as usize];
+    *PROCESSES[pid].lock() = Bucket {
+        level,
+        tokens: BURST,
+        refilled: unsafe { x86::time::rdtsc() },
+        dropped: 0,
+    };
+}
+
+/// Sets the log level of process `pid`.
+pub fn set_level(pid: Pid, level: LogLevel) {
+    PROCESSES[pid].lock().level = level;
+}
+
+/// Decides whether process `pid` can log a message of `len` bytes with
+/// severity `level`.
+pub fn admit(pid: Pid, level: LogLevel, len: usize) -> Verdict {
+    let mut bucket = PROCESSES[pid].lock();
+    if level > bucket.level {
+        return Verdict::Filtered;
+    }
+
+    bucket.refill(unsafe { x86::time::rdtsc() });
+    // A message bigger than the bucket gets through once it is full
+    let cost = core::cmp::min(len as u64, BURST);
+    if cost > bucket.tokens {
+        bucket.dropped += 1;
+        return Verdict::Dropped;
+    }
+
+    bucket.tokens -= cost;
+    let dropped = core::mem::replace(&mut bucket.dropped, 0);
+    Verdict::Print { dropped }
+}
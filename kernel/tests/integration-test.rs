@@ -284,6 +284,10 @@ struct RunnerArgs<'a> {
     cmd: Option<&'a str>,
     /// Which user-space modules to include.
     mods: Vec<&'a str>,
+    /// Directory to populate the file-system from.
+    rootfs: Option<&'a str>,
+    /// `init.toml` with the processes to start.
+    init_config: Option<&'a str>,
     /// Should we compile in release mode?
     release: bool,
     /// If true don't run, just compile.
@@ -300,6 +304,12 @@ struct RunnerArgs<'a> {
     prealloc: bool,
     /// Use large-pages for host memory
     large_pages: bool,
+    /// Hide the x2APIC from the guest
+    no_x2apic: bool,
+    /// Hide the TSC-deadline timer from the guest
+    no_tsc_deadline: bool,
+    /// Hide the invariant TSC from the guest
+    no_invtsc: bool,
 }
 
 #[allow(unused)]
@@ -315,6 +325,8 @@ impl<'a> RunnerArgs<'a> {
             pmem: 0,
             cmd: None,
             mods: Vec::new(),
+            rootfs: None,
+            init_config: None,
             release: false,
             norun: false,
             qemu_args: Vec::new(),
@@ -323,6 +335,9 @@ impl<'a> RunnerArgs<'a> {
             setaffinity: false,
             prealloc: false,
             large_pages: false,
+            no_x2apic: false,
+            no_tsc_deadline: false,
+            no_invtsc: false,
         };
 
         if cfg!(feature = "prealloc") {
@@ -426,6 +441,18 @@ impl<'a> RunnerArgs<'a> {
         self
     }
 
+    /// Ships the contents of `directory` in the file-system.
+    fn rootfs(mut self, directory: &'a str) -> RunnerArgs<'a> {
+        self.rootfs = Some(directory);
+        self
+    }
+
+    /// Starts the processes of the `init.toml` at `path`.
+    fn init_config(mut self, path: &'a str) -> RunnerArgs<'a> {
+        self.init_config = Some(path);
+        self
+    }
+
     /// Do a release build.
     fn release(mut self) -> RunnerArgs<'a> {
         self.release = true;
@@ -475,6 +502,24 @@ impl<'a> RunnerArgs<'a> {
         self
     }
 
+    /// Run on a CPU without x2APIC.
+    fn no_x2apic(mut self) -> RunnerArgs<'a> {
+        self.no_x2apic = true;
+        self
+    }
+
+    /// Run on a CPU without TSC-deadline timer.
+    fn no_tsc_deadline(mut self) -> RunnerArgs<'a> {
+        self.no_tsc_deadline = true;
+        self
+    }
+
+    /// Run on a CPU without an invariant TSC.
+    fn no_invtsc(mut self) -> RunnerArgs<'a> {
+        self.no_invtsc = true;
+        self
+    }
+
     /// Converts the RunnerArgs to a run.py command line invocation.
     fn as_cmd(&'a self) -> Vec<String> {
         use std::ops::Add;
@@ -506,6 +551,16 @@ impl<'a> RunnerArgs<'a> {
             cmd.push(self.mods.join(" "));
         }
 
+        if let Some(rootfs) = self.rootfs {
+            cmd.push(String::from("--rootfs"));
+            cmd.push(String::from(rootfs));
+        }
+
+        if let Some(init_config) = self.init_config {
+            cmd.push(String::from("--init-config"));
+            cmd.push(String::from(init_config));
+        }
+
         match self.user_features.is_empty() {
             false => {
                 cmd.push(String::from("--ufeatures"));
@@ -546,6 +601,15 @@ impl<'a> RunnerArgs<'a> {
                     // sudo bash -c "echo 0 > /sys/kernel/mm/hugepages/hugepages-2048kB/nr_hugepages"
                     cmd.push(String::from("--qemu-large-pages"));
                 }
+                if self.no_x2apic {
+                    cmd.push(String::from("--qemu-no-x2apic"));
+                }
+                if self.no_tsc_deadline {
+                    cmd.push(String::from("--qemu-no-tsc-deadline"));
+                }
+                if self.no_invtsc {
+                    cmd.push(String::from("--qemu-no-invtsc"));
+                }
 
                 // Form arguments for QEMU
                 let mut qemu_args: Vec<String> =
@@ -742,6 +806,11 @@ fn s00_exit() {
     let mut qemu_run = || -> Result<WaitStatus> {
         let mut p = spawn_nrk(&cmdline)?;
         p.exp_string("Started")?;
+        p.exp_string("Kernel build: nrk ")?;
+        p.exp_regex(r#"Memory map: .* managed in \d+ regions"#)?;
+        p.exp_regex(r#"Reserved \d+ physical memory range"#)?;
+        p.exp_regex(r#"CPU: .* microcode 0x[0-9a-f]+\)"#)?;
+        p.exp_regex(r#"PCI: \d+ devices"#)?;
         output = p.exp_eof()?;
         p.process.exit()
     };
@@ -792,6 +861,25 @@ fn s01_pfault() {
     check_for_exit(ExitStatus::PageFault, &cmdline, qemu_run(), output);
 }
 
+/// Make sure the kernel image is write-protected after initialization (the
+/// self-check passes and writing to the kernel text faults).
+#[test]
+fn s01_wx() {
+    let cmdline = RunnerArgs::new("test-wx");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        p.exp_regex(r#"Kernel image write-protected: \d+ pages checked"#)?;
+        p.exp_string("Writing to kernel text at")?;
+        p.exp_string("[IRQ] Page Fault")?;
+        output = p.exp_eof()?;
+        p.process.exit()
+    };
+
+    check_for_exit(ExitStatus::PageFault, &cmdline, qemu_run(), output);
+}
+
 /// Make sure the general-protection-fault handler works as expected  -- even if
 /// we're early on in initialization.
 /// In essence a trap should be raised but we can't get a backtrace yet
@@ -879,6 +967,26 @@ fn s01_alloc() {
     check_for_successful_exit(&cmdline, qemu_run(), output);
 }
 
+/// Make sure the heap sanitizer catches writes past the end of an allocation.
+#[test]
+fn s01_kasan() {
+    let cmdline = RunnerArgs::new("test-kasan");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("overflowing heap object at")?.as_str();
+        output += p
+            .exp_string("kasan: buffer overflow at offset 20")?
+            .as_str();
+        output += p.exp_string("kasan: Allocated by:")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_exit(ExitStatus::KernelPanic, &cmdline, qemu_run(), output);
+}
+
 /// Test that makes use of SSE in kernel-space and see if it works.AsMut
 ///
 /// Tests that we have correctly set-up the hardware to deal with floating
@@ -930,6 +1038,129 @@ fn s01_timer() {
     check_for_successful_exit(&cmdline, qemu_run(), output);
 }
 
+/// Test the timer interrupt on a CPU without TSC-deadline timer (the APIC
+/// timer in one-shot mode, calibrated against the HPET).
+#[cfg(not(feature = "baremetal"))]
+#[test]
+fn s01_timer_apic_one_shot() {
+    let cmdline = RunnerArgs::new("test-timer").no_tsc_deadline();
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p
+            .exp_string("Timer: APIC one-shot (calibrated with the HPET")?
+            .as_str();
+        output += p.exp_string("Setting the timer")?.as_str();
+        output += p.exp_string("Got a timer interrupt")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Boot on a CPU without an invariant TSC, the kernel keeps time with the
+/// HPET and uses the APIC timer in one-shot mode.
+#[test]
+fn s01_timer_hpet_clock() {
+    let cmdline = RunnerArgs::new("test-timer").no_invtsc();
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p
+            .exp_string("Timer: APIC one-shot (calibrated with the HPET")?
+            .as_str();
+        output += p
+            .exp_string("Clock: HPET (the TSC isn't invariant)")?
+            .as_str();
+        output += p.exp_string("Setting the timer")?.as_str();
+        output += p.exp_string("Got a timer interrupt")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Make sure the bootloader loads the kernel at a random place (unless we
+/// boot with `kaslr=off`).
+///
+/// That backtraces account for where the kernel is is checked by
+/// `s01_gpfault`.
+#[test]
+fn s01_kaslr() {
+    let mut offsets = Vec::new();
+    for _boot in 0..3 {
+        let cmdline = RunnerArgs::new("test-timer");
+        let mut output = String::new();
+
+        let mut qemu_run = || -> Result<WaitStatus> {
+            let mut p = spawn_nrk(&cmdline)?;
+            let (before, matched) =
+                p.exp_regex(r"Kernel binary at 0x[0-9a-f]+ \(KASLR: one of \d+ places")?;
+            output += before.as_str();
+            output += matched.as_str();
+            offsets.push(matched.split_whitespace().nth(3).unwrap_or("").to_string());
+            output += p.exp_eof()?.as_str();
+            p.process.exit()
+        };
+
+        check_for_successful_exit(&cmdline, qemu_run(), output);
+    }
+    // Three boots at the same place would be a (very) unlikely coincidence
+    offsets.sort();
+    offsets.dedup();
+    assert!(offsets.len() > 1, "Kernel always at {:?}", offsets);
+
+    let cmdline = RunnerArgs::new("test-timer").cmd("kaslr=off");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("(KASLR disabled)")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that the kernel reports the speculative execution mitigations and
+/// that `mitigations=off` turns them off.
+#[test]
+fn s01_mitigations() {
+    let cmdline = RunnerArgs::new("test-timer");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p
+            .exp_regex(r#"Mitigations: ibrs=(true|false) ibpb=(true|false) mds=(true|false)"#)?
+            .0
+            .as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+
+    let cmdline = RunnerArgs::new("test-timer").cmd("mitigations=off");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p
+            .exp_string("Mitigations: ibrs=false ibpb=false mds=false")?
+            .as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
 /// Test that we can initialize the ACPI subsystem and figure out the machine topology.
 #[cfg(not(feature = "baremetal"))]
 #[test]
@@ -997,6 +1228,27 @@ fn s02_coreboot_smoke() {
     check_for_successful_exit(&cmdline, qemu_run(), output);
 }
 
+/// Test that we can boot (and bring up an additional core) on a CPU without
+/// x2APIC, using the xAPIC (MMIO) instead.
+#[cfg(not(feature = "baremetal"))]
+#[test]
+fn s02_coreboot_xapic() {
+    let cmdline = RunnerArgs::new("test-coreboot-smoke").cores(2).no_x2apic();
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("xAPIC id: 0")?.as_str();
+        output += p.exp_string("ACPI Initialized")?.as_str();
+        output += p.exp_string("Hello from the other side")?.as_str();
+        output += p.exp_string("Core has started")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
 /// Test that we can multiple cores and use the node-replication log to communicate.
 #[cfg(not(feature = "baremetal"))] // TODO: can be ported to baremetal
 #[test]
@@ -1045,21 +1297,588 @@ fn s02_nvdimm_discover() {
 /// Test that we boot up all cores in the system.
 #[cfg(not(feature = "baremetal"))] // TODO: can be ported to baremetal
 #[test]
-fn s03_coreboot() {
-    let cmdline = &RunnerArgs::new("test-coreboot")
-        .cores(32)
-        .nodes(4)
-        .memory(4096);
+fn s03_coreboot() {
+    let cmdline = &RunnerArgs::new("test-coreboot")
+        .cores(32)
+        .nodes(4)
+        .memory(4096);
+    let mut output = String::new();
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline).expect("Can't spawn QEMU instance");
+
+        for i in 1..32 {
+            // Check that we see all 32 cores booting up
+            let expected_output = format!("Core #{} initialized", i);
+            output += p.exp_string(expected_output.as_str())?.as_str();
+        }
+        output += p
+            .exp_string("TSC: synchronized 31 cores to core 0")?
+            .as_str();
+
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that basic user-space support is functional.
+///
+/// This tests various user-space components such as:
+///  * process loading
+///  * system calls (printing, mem. mgmt.)
+///  * user-space scheduling and upcalls
+///  * BSD libOS in user-space
+#[test]
+fn s03_userspace_smoke() {
+    let cmdline = RunnerArgs::new("test-userspace").user_features(&[
+        "test-print",
+        "test-map",
+        "test-alloc",
+        "test-upcall",
+        "test-scheduler",
+    ]);
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+
+        output += p.exp_string("print_test OK")?.as_str();
+        output += p.exp_string("upcall_test OK")?.as_str();
+        output += p.exp_string("critical_section_test OK")?.as_str();
+        output += p.exp_string("map_test OK")?.as_str();
+        output += p.exp_string("alloc_test OK")?.as_str();
+        output += p.exp_string("scheduler_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that booting with `seed=` makes random numbers (and load addresses)
+/// reproducible.
+#[test]
+fn s03_userspace_random_seed() {
+    let cmdline = RunnerArgs::new("test-userspace")
+        .user_feature("test-random")
+        .cmd("seed=42");
+
+    let mut runs = Vec::new();
+    for _run in 0..2 {
+        let mut output = String::new();
+        let mut random = String::new();
+        let mut qemu_run = || -> Result<WaitStatus> {
+            let mut p = spawn_nrk(&cmdline)?;
+
+            let (before, loaded) = p.exp_regex(r#"Binary loaded at address: 0x[0-9a-f]+"#)?;
+            output += before.as_str();
+            output += loaded.as_str();
+            let (before, bytes) = p.exp_regex(r#"random_test bytes: \[[0-9a-f, ]+\]"#)?;
+            output += before.as_str();
+            output += bytes.as_str();
+            random = format!("{} {}", loaded, bytes);
+            output += p.exp_string("random_test OK")?.as_str();
+            output += p.exp_eof()?.as_str();
+            p.process.exit()
+        };
+
+        check_for_successful_exit(&cmdline, qemu_run(), output);
+        runs.push(random);
+    }
+
+    assert_eq!(runs[0], runs[1], "Same seed but different random numbers");
+}
+
+/// Tests that the kernel filters user-space log messages by the log level of
+/// the process and drops messages of a process that floods the console.
+#[test]
+fn s03_userspace_log_limits() {
+    let cmdline = RunnerArgs::new("test-userspace").user_feature("test-log");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        let (before, _) = p.exp_regex(r#"\[WARN\] - init: log_test not filtered"#)?;
+        assert!(
+            !before.contains("log_test filtered"),
+            "Message below the log level was printed"
+        );
+        output += before.as_str();
+        output += p.exp_regex(r#"\[pid \d+\] \d+ log messages dropped"#)?.0.as_str();
+        output += p.exp_string("log_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that `Process::sleep` and the timeout of barrier waits don't wake
+/// up early.
+#[test]
+fn s03_userspace_sleep() {
+    let cmdline = RunnerArgs::new("test-userspace").user_feature("test-sleep");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p
+            .exp_regex(r#"sleep_test slept [0-9.]+m?s for 100ms"#)?
+            .0
+            .as_str();
+        output += p.exp_string("sleep_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that a process can set its OOM priority and wait for OOM kills (as
+/// the supervisor).
+#[test]
+fn s03_userspace_oom() {
+    let cmdline = RunnerArgs::new("test-userspace")
+        .user_feature("test-oom")
+        .cmd("initcaps=supervise");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("OOM policy: rss")?.as_str();
+        output += p
+            .exp_regex(r#"Process \d+ supervises the OOM killer"#)?
+            .0
+            .as_str();
+        output += p.exp_string("oom_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that a process gets the keys typed on the PS/2 keyboard (we type
+/// with `sendkey` on the QEMU monitor).
+#[test]
+fn s03_userspace_keyboard() {
+    let cmdline = RunnerArgs::new("test-userspace")
+        .user_feature("test-keyboard")
+        .qemu_args(&["-monitor", "telnet:127.0.0.1:55557,server,nowait"]);
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("PS/2 keyboard polled every 10 ms")?.as_str();
+        output += p
+            .exp_regex(r#"Process \d+ has the keyboard focus"#)?
+            .0
+            .as_str();
+        output += p.exp_string("keyboard_test ready")?.as_str();
+
+        let mut monitor = std::net::TcpStream::connect("127.0.0.1:55557")
+            .expect("Can't connect to the QEMU monitor");
+        for key in &["shift-h", "i", "shift-1", "ret"] {
+            writeln!(monitor, "sendkey {}", key).expect("Can't type");
+        }
+
+        output += p.exp_string("keyboard_test typed Hi!")?.as_str();
+        output += p.exp_string("keyboard_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that the kernel lists the devices it found and that a process can
+/// claim a device without a driver.
+#[test]
+fn s03_userspace_devices() {
+    let cmdline = RunnerArgs::new("test-userspace")
+        .user_feature("test-devices")
+        .cmd("initcaps=devices");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_regex(r#"Found \d+ devices"#)?.0.as_str();
+        output += p.exp_regex(r#"Device \d+ driven by serial"#)?.0.as_str();
+        output += p.exp_regex(r#"Process \d+ drives device \d+"#)?.0.as_str();
+        output += p.exp_string("devices_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that the AHCI driver finds a SATA disk (an empty image on the AHCI
+/// controller of QEMU).
+#[test]
+fn s03_ahci_disk() {
+    let image = std::env::temp_dir().join("nrk-ahci-disk.img");
+    File::create(&image)
+        .and_then(|f| f.set_len(16 * 1024 * 1024))
+        .expect("Can't create disk image");
+    let drive = format!("id=disk,file={},format=raw,if=none", image.display());
+    let cmdline = RunnerArgs::new("test-userspace").qemu_args(&[
+        "-device",
+        "ahci,id=ahci",
+        "-drive",
+        drive.as_str(),
+        "-device",
+        "ide-hd,drive=disk,bus=ahci.0",
+    ]);
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("Disk 0: ich9-ahci port 0 (16 MiB)")?.as_str();
+        output += p.exp_regex(r#"Device \d+ driven by ahci"#)?.0.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that the kernel keeps its log on a disk (`logdisk`) and that
+/// `scripts/disklog.py` gets it back after the kernel exited.
+#[test]
+fn s03_disk_log() {
+    let image = std::env::temp_dir().join("nrk-disk-log.img");
+    File::create(&image)
+        .and_then(|f| f.set_len(16 * 1024 * 1024))
+        .expect("Can't create disk image");
+    let drive = format!("id=disk,file={},format=raw,if=none", image.display());
+    let cmdline = RunnerArgs::new("test-userspace")
+        .cmd("logdisk=0:2048:256")
+        .qemu_args(&[
+            "-device",
+            "ahci,id=ahci",
+            "-drive",
+            drive.as_str(),
+            "-device",
+            "ide-hd,drive=disk,bus=ahci.0",
+        ]);
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p
+            .exp_string("Kernel log on disk 0 sectors 2048..2304 (from record 0)")?
+            .as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+
+    // What was logged before the disk was up is in there as well
+    let mut log = spawn(
+        format!("python3 ../scripts/disklog.py {} 2048 256", image.display()).as_str(),
+        Some(10_000),
+    )
+    .expect("Can't run disklog.py");
+    log.exp_string("Disk 0: ich9-ahci port 0 (16 MiB)")
+        .expect("Disk isn't in the log");
+    log.exp_string("Kernel log on disk 0")
+        .expect("Disk log isn't in the log");
+    log.exp_eof().expect("disklog.py didn't exit");
+}
+
+/// Tests that the file-system is populated from the `rootfs` module at boot.
+#[test]
+fn s03_userspace_rootfs() {
+    let cmdline = RunnerArgs::new("test-userspace")
+        .user_feature("test-rootfs")
+        .rootfs("tests/rootfs");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p
+            .exp_string("Root file-system: 1 file(s) (16 bytes) and 1 directory(ies)")?
+            .as_str();
+        output += p.exp_string("rootfs_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that the kernel starts the processes of `init.toml`.
+#[test]
+fn s03_userspace_autostart() {
+    let cmdline = RunnerArgs::new("test-userspace-smp")
+        .user_feature("test-autostart")
+        .cores(2)
+        .init_config("tests/init.toml");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p
+            .exp_regex(r#"Started init \(autostart\) as \d+"#)?
+            .0
+            .as_str();
+        output += p.exp_string("autostart_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that the kernel restarts a failing service (with back-off) until
+/// it succeeds.
+#[test]
+fn s03_userspace_restart() {
+    let cmdline = RunnerArgs::new("test-userspace")
+        .user_feature("test-services")
+        .init_config("tests/services.toml");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p
+            .exp_regex(r#"Service init \(\d+\) exited with 1 \(1 failure\(s\) in a row\), restarting in 100 ms"#)?
+            .0
+            .as_str();
+        output += p
+            .exp_regex(r#"Restarted init \(service\) as \d+"#)?
+            .0
+            .as_str();
+        output += p
+            .exp_regex(r#"Service init \(\d+\) exited with 1 \(2 failure\(s\) in a row\), restarting in 200 ms"#)?
+            .0
+            .as_str();
+        output += p.exp_string("service_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that a process with the `reload` capability can replace a running
+/// service.
+#[test]
+fn s03_userspace_reload() {
+    let cmdline = RunnerArgs::new("test-userspace-smp")
+        .user_feature("test-reload")
+        .cores(2)
+        .init_config("tests/reload.toml");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p
+            .exp_regex(r#"Replaced init \(\d+\) with init as \d+"#)?
+            .0
+            .as_str();
+        output += p.exp_string("reload_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that a spawned child gets the file descriptors its parent passes
+/// (but not `O_CLOEXEC` ones) and the descriptor limit of the parent.
+#[test]
+fn s03_userspace_fd_inherit() {
+    let cmdline = RunnerArgs::new("test-userspace-smp")
+        .user_feature("test-fd-inherit")
+        .cores(2);
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("fd_inherit_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that a process with the `checkpoint` capability can save itself and
+/// restore the checkpoint as a new process (and others can't).
+#[test]
+fn s03_userspace_checkpoint() {
+    let cmdline = RunnerArgs::new("test-userspace-smp")
+        .user_feature("test-checkpoint")
+        .cmd("initcaps=checkpoint")
+        .cores(2);
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("checkpoint_test restored")?.as_str();
+        output += p.exp_string("checkpoint_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that the kernel logs and records the system calls of a traced
+/// process.
+#[test]
+fn s03_userspace_trace() {
+    let cmdline = RunnerArgs::new("test-userspace").user_feature("test-trace");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p
+            .exp_regex(r#"\[pid \d+\] System::GetRandom\(0x[0-9a-f]+, 0x10, 0x0, 0x0\) \.\.\."#)?
+            .0
+            .as_str();
+        output += p
+            .exp_regex(r#"\[pid \d+\] System::GetRandom\(0x[0-9a-f]+, 0x10, 0x0, 0x0\) = Ok"#)?
+            .0
+            .as_str();
+        output += p.exp_string("trace_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that the kernel prints a user backtrace and writes a core dump of a
+/// process that crashes (if it asked for one).
+#[test]
+fn s03_userspace_core_dump() {
+    let cmdline = RunnerArgs::new("test-userspace").user_feature("test-core-dump");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("core_dump_test crashing")?.as_str();
+        output += p.exp_regex(r#"User backtrace \(pid \d+\):"#)?.0.as_str();
+        output += p.exp_regex(r#"frame #1 +- 0x[0-9a-f]+"#)?.0.as_str();
+        output += p
+            .exp_regex(r#"Wrote core dump of process \d+ to /core.\d+ \(\d+ bytes\)"#)?
+            .0
+            .as_str();
+        output += p.exp_string("[IRQ] Page Fault")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_exit(ExitStatus::PageFault, &cmdline, qemu_run(), output);
+}
+
+/// Tests the user-space Mutex, RwLock, Condvar and channels of vibrio with
+/// threads that park and wake each other.
+#[test]
+fn s03_userspace_sync() {
+    let cmdline = RunnerArgs::new("test-userspace").user_feature("test-sync");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("sync_test OK")?.as_str();
+        output += p.exp_string("mpsc_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that faults injected with the `Test` system calls fire (and can be
+/// disarmed again), and that system calls on a core that lost its save area
+/// or its process kill the caller or fail without taking the kernel down.
+#[test]
+fn s03_userspace_fault_injection() {
+    let cmdline = RunnerArgs::new("test-userspace-smp")
+        .kernel_feature("fault-injection")
+        .user_feature("test-fault-injection")
+        .cmd("initcaps=fault-injection")
+        .cores(2);
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p
+            .exp_string("without a valid save area, killing process")?
+            .as_str();
+        output += p
+            .exp_string("System call returned with error: ProcessNotSet")?
+            .as_str();
+        output += p.exp_string("fault_injection_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that malformed system calls fail with an error (on the first core
+/// and on a core that was just added to the process).
+#[cfg(not(feature = "baremetal"))]
+#[test]
+fn s03_userspace_syscall_fuzz() {
+    let cmdline = RunnerArgs::new("test-userspace-smp")
+        .user_feature("test-syscall-fuzz")
+        .cores(2)
+        .timeout(25_000);
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("calls on core 1")?.as_str();
+        output += p.exp_string("syscall_fuzz_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that a process with the `power` capability can set the power
+/// policy and read the energy counters.
+#[test]
+fn s03_userspace_power() {
+    let cmdline = RunnerArgs::new("test-userspace")
+        .user_feature("test-power")
+        .cmd("initcaps=power");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p
+            .exp_string("Power policy: governor Firmware, idle Poll")?
+            .as_str();
+        output += p.exp_string("power_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that processes can send each other datagrams over 127.0.0.1 without
+/// a NIC.
+#[test]
+fn s03_userspace_udp() {
+    let cmdline = RunnerArgs::new("test-userspace")
+        .kernel_feature("smoltcp")
+        .user_feature("test-udp");
     let mut output = String::new();
-    let mut qemu_run = || -> Result<WaitStatus> {
-        let mut p = spawn_nrk(&cmdline).expect("Can't spawn QEMU instance");
-
-        for i in 1..32 {
-            // Check that we see all 32 cores booting up
-            let expected_output = format!("Core #{} initialized", i);
-            output += p.exp_string(expected_output.as_str())?.as_str();
-        }
 
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("Network on the loopback interface")?.as_str();
+        output += p.exp_string("udp_test OK")?.as_str();
         output += p.exp_eof()?.as_str();
         p.process.exit()
     };
@@ -1067,32 +1886,24 @@ fn s03_coreboot() {
     check_for_successful_exit(&cmdline, qemu_run(), output);
 }
 
-/// Tests that basic user-space support is functional.
-///
-/// This tests various user-space components such as:
-///  * process loading
-///  * system calls (printing, mem. mgmt.)
-///  * user-space scheduling and upcalls
-///  * BSD libOS in user-space
+/// Tests that init runs its tests concurrently in spawned processes and
+/// collects their results.
+#[cfg(not(feature = "baremetal"))]
 #[test]
-fn s03_userspace_smoke() {
-    let cmdline = RunnerArgs::new("test-userspace").user_features(&[
-        "test-print",
-        "test-map",
-        "test-alloc",
-        "test-upcall",
-        "test-scheduler",
-    ]);
+fn s03_userspace_test_runner() {
+    let cmdline = RunnerArgs::new("test-userspace-smp")
+        .user_feature("test-runner")
+        .cores(4)
+        .memory(1024)
+        .timeout(60_000);
     let mut output = String::new();
 
     let mut qemu_run = || -> Result<WaitStatus> {
         let mut p = spawn_nrk(&cmdline)?;
-
-        output += p.exp_string("print_test OK")?.as_str();
-        output += p.exp_string("upcall_test OK")?.as_str();
-        output += p.exp_string("map_test OK")?.as_str();
-        output += p.exp_string("alloc_test OK")?.as_str();
-        output += p.exp_string("scheduler_test OK")?.as_str();
+        output += p.exp_string("test=map status=passed")?.as_str();
+        output += p
+            .exp_string("test_runner: 5 passed, 0 failed, 0 timed out")?
+            .as_str();
         output += p.exp_eof()?.as_str();
         p.process.exit()
     };
@@ -1182,6 +1993,11 @@ fn s03_vmxnet3_smoltcp() {
         let mut p = spawn_nrk(&cmdline)?;
         output += p.exp_string("About to serve sockets!")?.as_str();
 
+        // The kernel answers pings (and the ARP request before them)
+        let mut ping = spawn("ping -c 3 172.31.0.10", Some(10_000))?;
+        output += ping.exp_string("3 received")?.as_str();
+        ping.process.exit()?;
+
         let mut client = spawn_socat(6970)?;
         for i in 0..12 {
             println!("sending pkt = {}", i);
@@ -1189,8 +2005,223 @@ fn s03_vmxnet3_smoltcp() {
             output += client.exp_string(RANDOM_PAYLOAD)?.as_str();
         }
         client.process.exit()?;
+        output += p.exp_string("Neighbors:")?.as_str();
+        output += p.exp_string("172.31.0.20 at")?.as_str();
+        output += p.exp_eof()?.as_str();
+
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests TCP on the loopback interface of the kernel network stack (works
+/// without a NIC).
+#[cfg(not(feature = "baremetal"))]
+#[test]
+fn s02_net_loopback() {
+    let cmdline = RunnerArgs::new("test-net-loopback");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("Network on the loopback interface")?.as_str();
+        output += p.exp_string("Echo on 127.0.0.1 received")?.as_str();
+        output += p.exp_string("inet 127.0.0.1/8")?.as_str();
+        output += p.exp_string("tcp 127.0.0.1:6970 127.0.0.1:49152")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that a panic in the network stack disables it but the kernel keeps
+/// running.
+#[test]
+fn s02_panic_domain() {
+    let cmdline = RunnerArgs::new("test-panic-domain");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("Subsystem network panicked")?.as_str();
+        output += p.exp_string("Test panic in the network stack")?.as_str();
+        output += p
+            .exp_string("Kernel survived, network is disabled")?
+            .as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that the kernel can soft-reboot into itself without going through
+/// the firmware.
+#[test]
+fn s02_kexec() {
+    let cmdline = RunnerArgs::new("test-kexec");
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("Soft-rebooting into a new kernel")?.as_str();
+        output += p.exp_string("Modules loaded from Kexec")?.as_str();
+        output += p.exp_string("Soft-reboot done")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that the e1000 driver works with the smoltcp network stack (the
+/// kernel answers pings).
+#[cfg(not(feature = "baremetal"))]
+#[test]
+fn s03_e1000_smoltcp() {
+    let cmdline = RunnerArgs::new("test-vmxnet-smoltcp")
+        .timeout(30_000)
+        .use_e1000();
+
+    let mut output = String::new();
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_regex(r#"Device \d+ driven by e1000"#)?.0.as_str();
+        output += p.exp_string("e1000 link up")?.as_str();
+        output += p.exp_string("About to serve sockets!")?.as_str();
+
+        let mut ping = spawn("ping -c 3 172.31.0.10", Some(10_000))?;
+        output += ping.exp_string("3 received")?.as_str();
+        ping.process.exit()?;
+
+        output += p.exp_string("Neighbors:")?.as_str();
+        output += p.exp_string("172.31.0.20 at")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that the kernel mirrors its console output (including what it
+/// logged before the network was up) to a UDP endpoint with `netlog`.
+#[cfg(not(feature = "baremetal"))]
+#[test]
+fn s04_netconsole() {
+    let cmdline = RunnerArgs::new("test-vmxnet-smoltcp")
+        .timeout(30_000)
+        .use_vmxnet3()
+        .cmd("netlog=172.31.0.20:6666");
+
+    let mut output = String::new();
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut receiver = spawn("socat UDP-LISTEN:6666 stdout", Some(20_000))?;
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("About to serve sockets!")?.as_str();
+
+        output += receiver.exp_string("Started at")?.as_str();
+        output += receiver.exp_string("About to serve sockets!")?.as_str();
+        receiver.process.kill(SIGTERM)?;
+
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that the kernel synchronizes its wall clock with an SNTP server
+/// (`ntp`), the server runs an hour ahead of the host.
+#[cfg(not(feature = "baremetal"))]
+#[test]
+fn s04_sntp() {
+    use std::net::UdpSocket;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    let cmdline = RunnerArgs::new("test-vmxnet-smoltcp")
+        .timeout(30_000)
+        .use_vmxnet3()
+        .cmd("ntp=172.31.0.20:6123");
+
+    let server = UdpSocket::bind("0.0.0.0:6123").expect("Can't bind SNTP port");
+    server
+        .set_read_timeout(Some(Duration::from_secs(25)))
+        .expect("Can't set timeout");
+    // Answers until nobody asked for 25 s
+    std::thread::spawn(move || {
+        let mut request = [0u8; 48];
+        while let Ok((_len, client)) = server.recv_from(&mut request) {
+            let now =
+                SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + Duration::from_secs(3600);
+            let seconds = now.as_secs() + 2_208_988_800;
+            let fraction = ((now.subsec_nanos() as u64) << 32) / 1_000_000_000;
+            let timestamp = ((seconds << 32) | fraction).to_be_bytes();
+
+            let mut reply = [0u8; 48];
+            // Version 4, server, stratum 1
+            reply[0] = 0x24;
+            reply[1] = 1;
+            reply[24..32].copy_from_slice(&request[40..48]);
+            reply[32..40].copy_from_slice(&timestamp);
+            reply[40..48].copy_from_slice(&timestamp);
+            let _r = server.send_to(&reply, client);
+        }
+    });
+
+    let mut output = String::new();
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("About to serve sockets!")?.as_str();
+        // An hour plus the RTC's fraction of a second
+        output += p
+            .exp_regex(r#"Wall clock synchronized with 172.31.0.20:6123: offset 3[56]\d{8} us"#)?
+            .0
+            .as_str();
         output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests the management console (`mgmtkey`): a wrong key closes the
+/// connection, with the right one the commands work.
+#[cfg(not(feature = "baremetal"))]
+#[test]
+fn s04_mgmt_console() {
+    let cmdline = RunnerArgs::new("test-vmxnet-smoltcp")
+        .timeout(30_000)
+        .use_vmxnet3()
+        .cmd("mgmtkey=s3cret-rack-key");
+
+    let mut output = String::new();
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("About to serve sockets!")?.as_str();
 
+        let mut console = spawn("socat - TCP:172.31.0.10:2323", Some(10_000))?;
+        output += console.exp_string("nrk management console")?.as_str();
+        console.send_line("auth guessed-key")?;
+        output += console.exp_string("denied")?.as_str();
+        output += console.exp_eof()?.as_str();
+        output += p
+            .exp_string("Management console: authentication failed")?
+            .as_str();
+
+        let mut console = spawn("socat - TCP:172.31.0.10:2323", Some(10_000))?;
+        output += console.exp_string("nrk management console")?.as_str();
+        console.send_line("auth s3cret-rack-key")?;
+        output += console.exp_string("ok")?.as_str();
+        console.send_line("uptime")?;
+        output += console.exp_regex(r#"up \d+\.\d{3} s"#)?.0.as_str();
+        console.send_line("devices")?;
+        output += console.exp_string("vmxnet3")?.as_str();
+        console.send_line("quit")?;
+        output += console.exp_eof()?.as_str();
+
+        output += p.exp_eof()?.as_str();
         p.process.exit()
     };
 
@@ -1227,19 +2258,43 @@ fn s04_userspace_multicore() {
     wait_for_sigterm(&cmdline, qemu_run(), output);
 }
 
+/// Tests the vibrio barrier with one thread on every core.
+#[cfg(not(feature = "baremetal"))]
+#[test]
+fn s04_userspace_barrier() {
+    let machine = Machine::determine();
+    let num_cores: usize = machine.max_cores();
+    let cmdline = RunnerArgs::new("test-userspace-smp")
+        .user_features(&["test-barrier"])
+        .cores(num_cores)
+        .memory(2048)
+        .timeout(28_000);
+
+    let mut output = String::new();
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("barrier_test OK")?.as_str();
+        p.process.kill(SIGTERM)
+    };
+
+    wait_for_sigterm(&cmdline, qemu_run(), output);
+}
+
 /// Tests that user-space networking is functional.
 ///
 /// This tests various user-space components such as:
 ///  * BSD libOS network stack
 ///  * PCI/user-space drivers
 ///  * Interrupt registration and upcalls
+///  * Interrupt moderation (masking the NIC interrupt while polling)
 #[cfg(not(feature = "baremetal"))]
 #[test]
 fn s04_userspace_rumprt_net() {
     let cmdline = RunnerArgs::new("test-userspace")
         .user_feature("test-rump-net")
         .user_feature("rumprt")
-        .timeout(20_000);
+        .cmd("initcaps=devices,tune")
+        .timeout(35_000);
 
     let mut output = String::new();
     let mut qemu_run = || -> Result<WaitStatus> {
@@ -1264,6 +2319,9 @@ fn s04_userspace_rumprt_net() {
             ping.exp_regex(r#"64 bytes from 172.31.0.10: icmp_seq=(\d+) ttl=255 time=(.*?ms)"#)?;
         }
 
+        // Test that the IRQ thread takes fewer interrupts while polling:
+        output += p.exp_string("IRQ moderation OK")?.as_str();
+
         ping.process.kill(SIGTERM)?;
         dhcp_server.send_control('c')?;
         receiver.process.kill(SIGTERM)?;
@@ -1284,6 +2342,7 @@ fn s04_userspace_rumprt_fs() {
     let cmdline = &RunnerArgs::new("test-userspace")
         .user_feature("test-rump-tmpfs")
         .user_feature("rumprt")
+        .cmd("initcaps=devices")
         .timeout(20_000);
     let mut output = String::new();
 
@@ -1357,7 +2416,7 @@ fn s05_redis_smoke() {
     let cmdline = RunnerArgs::new("test-userspace")
         .module("rkapps")
         .user_feature("rkapps:redis")
-        .cmd("init=redis.bin")
+        .cmd("init=redis.bin initcaps=devices")
         .timeout(20_000);
 
     let mut output = String::new();
@@ -1490,7 +2549,7 @@ fn s06_redis_benchmark_virtio() {
     let cmdline = RunnerArgs::new("test-userspace")
         .module("rkapps")
         .user_feature("rkapps:redis")
-        .cmd("init=redis.bin")
+        .cmd("init=redis.bin initcaps=devices")
         .use_virtio()
         .release()
         .timeout(45_000);
@@ -1523,7 +2582,7 @@ fn s06_redis_benchmark_e1000() {
     let cmdline = RunnerArgs::new("test-userspace")
         .module("rkapps")
         .user_feature("rkapps:redis")
-        .cmd("init=redis.bin")
+        .cmd("init=redis.bin initcaps=devices")
         .release()
         .timeout(45_000);
 
@@ -1857,6 +2916,152 @@ fn s06_vmops_unmaplat_latency_benchmark() {
     }
 }
 
+#[test]
+fn s06_syscall_latency_benchmark() {
+    let file_name = "syscall_benchmark_latency.csv";
+    let _r = std::fs::remove_file(file_name);
+
+    let mut cmdline = RunnerArgs::new("test-userspace")
+        .module("init")
+        .kernel_feature("syscall-timing")
+        .user_feature("bench-syscall")
+        .timeout(60_000)
+        .release();
+    if cfg!(feature = "smoke") {
+        cmdline = cmdline.user_feature("smoke");
+    }
+
+    let mut output = String::new();
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+
+        let write_headers = !Path::new(file_name).exists();
+        let mut csv_file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(file_name)
+            .expect("Can't open file");
+        if write_headers {
+            let row = "git_rev,benchmark,ncores,memsize,p1,p25,p50,p75,p99,p999,p100\n";
+            let r = csv_file.write(row.as_bytes());
+            assert!(r.is_ok());
+        }
+
+        // Parse lines like:
+        // "init::syscallbench: Latency percentiles: null,1,0,412,420,424,432,560,1220,30120"
+        // and writes them to a CSV file
+        for _syscall in &["null", "log", "map", "unmap", "identify"] {
+            let (prev, matched) = p.exp_regex(
+                r#"init::syscallbench: Latency percentiles: (.*),(\d+),(\d+),(\d+),(\d+),(\d+),(\d+),(\d+),(\d+),(\d+)"#,
+            )?;
+            output += prev.as_str();
+            output += matched.as_str();
+
+            let parts: Vec<&str> = matched
+                .split("init::syscallbench: Latency percentiles: ")
+                .collect();
+            assert!(parts.len() >= 2);
+            let r = csv_file.write(format!("{},", env!("GIT_HASH")).as_bytes());
+            assert!(r.is_ok());
+            let r = csv_file.write(parts[1].as_bytes());
+            assert!(r.is_ok());
+            let r = csv_file.write("\n".as_bytes());
+            assert!(r.is_ok());
+        }
+
+        output += p.exp_string("System calls: ")?.as_str();
+        output += p.exp_string("syscall_bench OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Measures what the speculative execution mitigations cost: runs the
+/// system call benchmark without mitigations, with the default ones and with
+/// all of them (as far as the CPU supports them).
+#[test]
+fn s06_mitigations_benchmark() {
+    let file_name = "mitigations_benchmark.csv";
+    let _r = std::fs::remove_file(file_name);
+
+    for mitigations in &["off", "auto", "'ibrs,ibpb,mds'"] {
+        let kernel_cmdline = format!("mitigations={}", mitigations);
+        let mut cmdline = RunnerArgs::new("test-userspace")
+            .module("init")
+            .user_feature("bench-syscall")
+            .timeout(60_000)
+            .cmd(kernel_cmdline.as_str())
+            .release();
+        if cfg!(feature = "smoke") {
+            cmdline = cmdline.user_feature("smoke");
+        }
+
+        let mut output = String::new();
+        let mut qemu_run = || -> Result<WaitStatus> {
+            let mut p = spawn_nrk(&cmdline)?;
+
+            let write_headers = !Path::new(file_name).exists();
+            let mut csv_file = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(file_name)
+                .expect("Can't open file");
+            if write_headers {
+                let row = "git_rev,mitigations,ibrs,ibpb,mds,benchmark,ncores,memsize,p1,p25,p50,p75,p99,p999,p100\n";
+                let r = csv_file.write(row.as_bytes());
+                assert!(r.is_ok());
+            }
+
+            // What we actually got (the CPU might not support all of them)
+            let (prev, matched) = p.exp_regex(
+                r#"init::syscallbench: Mitigations: ibrs=(true|false) ibpb=(true|false) mds=(true|false)"#,
+            )?;
+            output += prev.as_str();
+            output += matched.as_str();
+            let active: Vec<&str> = matched
+                .split(' ')
+                .filter_map(|kv| kv.split('=').nth(1))
+                .collect();
+            assert_eq!(active.len(), 3);
+
+            for _syscall in &["null", "log", "map", "unmap", "identify"] {
+                let (prev, matched) = p.exp_regex(
+                    r#"init::syscallbench: Latency percentiles: (.*),(\d+),(\d+),(\d+),(\d+),(\d+),(\d+),(\d+),(\d+),(\d+)"#,
+                )?;
+                output += prev.as_str();
+                output += matched.as_str();
+
+                let parts: Vec<&str> = matched
+                    .split("init::syscallbench: Latency percentiles: ")
+                    .collect();
+                assert!(parts.len() >= 2);
+                let r = csv_file.write(
+                    format!(
+                        "{},{},{},",
+                        env!("GIT_HASH"),
+                        mitigations.trim_matches('\'').replace(',', "+"),
+                        active.join(",")
+                    )
+                    .as_bytes(),
+                );
+                assert!(r.is_ok());
+                let r = csv_file.write(parts[1].as_bytes());
+                assert!(r.is_ok());
+                let r = csv_file.write("\n".as_bytes());
+                assert!(r.is_ok());
+            }
+
+            output += p.exp_string("syscall_bench OK")?.as_str();
+            output += p.exp_eof()?.as_str();
+            p.process.exit()
+        };
+
+        check_for_successful_exit(&cmdline, qemu_run(), output);
+    }
+}
+
 #[test]
 fn s06_fxmark_benchmark() {
     // benchmark naming convention = nameXwrite - mixX10 is - mix benchmark for 10% writes.
@@ -2149,7 +3354,8 @@ fn s06_memcached_benchmark() {
 
     for nic in &["virtio", "e1000"] {
         for thread in threads.iter() {
-            let kernel_cmdline = format!("init=memcached.bin initargs={}", *thread);
+            let kernel_cmdline =
+                format!("init=memcached.bin initcaps=devices initargs={}", *thread);
             let cmdline = RunnerArgs::new("test-userspace-smp")
                 .module("rkapps")
                 .user_feature("rkapps:memcached")
@@ -2211,7 +3417,7 @@ fn s06_leveldb_benchmark() {
 
     for thread in threads.iter() {
         let kernel_cmdline = format!(
-            r#"init=dbbench.bin initargs={} appcmd='--threads={} --benchmarks=fillseq,readrandom --reads={} --num={} --value_size={}'"#,
+            r#"init=dbbench.bin initcaps=devices initargs={} appcmd='--threads={} --benchmarks=fillseq,readrandom --reads={} --num={} --value_size={}'"#,
             *thread, *thread, reads, num, val_size
         );
         let mut cmdline = RunnerArgs::new("test-userspace-smp")
@@ -4,6 +4,7 @@
 #![no_std]
 #![feature(core_intrinsics)]
 
+use driverkit::{DriverControl, DriverState};
 use x86::apic::{ApicId, Icr};
 
 pub const TSC_TIMER_VECTOR: u8 = 252;
@@ -59,3 +60,137 @@ pub trait ApicDriver {
     /// to the wrong core, or the wrong vector etc.
     unsafe fn send_ipi(&mut self, icr: Icr);
 }
+
+/// The local APIC of a core, driven either through MSRs (x2APIC mode) or
+/// through its memory-mapped registers (xAPIC mode).
+///
+/// Which one to use is decided at runtime (not every machine supports the
+/// x2APIC), the rest of the kernel goes through [`ApicDriver`].
+#[derive(Debug)]
+pub enum LocalApic {
+    X2Apic(x2apic::X2APICDriver),
+    XApic(xapic::XAPICDriver),
+}
+
+impl LocalApic {
+    /// Is the APIC in x2APIC mode?
+    pub fn is_x2apic(&self) -> bool {
+        matches!(self, LocalApic::X2Apic(_))
+    }
+}
+
+impl ApicDriver for LocalApic {
+    fn bsp(&self) -> bool {
+        match self {
+            LocalApic::X2Apic(apic) => apic.bsp(),
+            LocalApic::XApic(apic) => apic.bsp(),
+        }
+    }
+
+    fn id(&self) -> u32 {
+        match self {
+            LocalApic::X2Apic(apic) => apic.id(),
+            LocalApic::XApic(apic) => apic.id(),
+        }
+    }
+
+    fn logical_id(&self) -> u32 {
+        match self {
+            LocalApic::X2Apic(apic) => apic.logical_id(),
+            LocalApic::XApic(apic) => apic.logical_id(),
+        }
+    }
+
+    fn version(&self) -> u32 {
+        match self {
+            LocalApic::X2Apic(apic) => apic.version(),
+            LocalApic::XApic(apic) => apic.version(),
+        }
+    }
+
+    fn eoi(&mut self) {
+        match self {
+            LocalApic::X2Apic(apic) => apic.eoi(),
+            LocalApic::XApic(apic) => apic.eoi(),
+        }
+    }
+
+    fn tsc_enable(&mut self) {
+        match self {
+            LocalApic::X2Apic(apic) => apic.tsc_enable(),
+            LocalApic::XApic(apic) => apic.tsc_enable(),
+        }
+    }
+
+    fn tsc_set(&self, value: u64) {
+        match self {
+            LocalApic::X2Apic(apic) => apic.tsc_set(value),
+            LocalApic::XApic(apic) => apic.tsc_set(value),
+        }
+    }
+
+    unsafe fn ipi_init(&mut self, core: ApicId) {
+        match self {
+            LocalApic::X2Apic(apic) => apic.ipi_init(core),
+            LocalApic::XApic(apic) => apic.ipi_init(core),
+        }
+    }
+
+    unsafe fn ipi_init_deassert(&mut self) {
+        match self {
+            LocalApic::X2Apic(apic) => apic.ipi_init_deassert(),
+            LocalApic::XApic(apic) => apic.ipi_init_deassert(),
+        }
+    }
+
+    unsafe fn ipi_startup(&mut self, core: ApicId, start_page: u8) {
+        match self {
+            LocalApic::X2Apic(apic) => apic.ipi_startup(core, start_page),
+            LocalApic::XApic(apic) => apic.ipi_startup(core, start_page),
+        }
+    }
+
+    unsafe fn send_ipi(&mut self, icr: Icr) {
+        match self {
+            LocalApic::X2Apic(apic) => apic.send_ipi(icr),
+            LocalApic::XApic(apic) => apic.send_ipi(icr),
+        }
+    }
+}
+
+impl DriverControl for LocalApic {
+    fn attach(&mut self) {
+        match self {
+            LocalApic::X2Apic(apic) => apic.attach(),
+            LocalApic::XApic(apic) => apic.attach(),
+        }
+    }
+
+    fn detach(&mut self) {
+        match self {
+            LocalApic::X2Apic(apic) => apic.detach(),
+            LocalApic::XApic(apic) => apic.detach(),
+        }
+    }
+
+    fn destroy(self) {
+        match self {
+            LocalApic::X2Apic(apic) => apic.destroy(),
+            LocalApic::XApic(apic) => apic.destroy(),
+        }
+    }
+
+    fn state(&self) -> DriverState {
+        match self {
+            LocalApic::X2Apic(apic) => apic.state(),
+            LocalApic::XApic(apic) => apic.state(),
+        }
+    }
+
+    fn set_state(&mut self, st: DriverState) {
+        match self {
+            LocalApic::X2Apic(apic) => apic.set_state(st),
+            LocalApic::XApic(apic) => apic.set_state(st),
+        }
+    }
+}
@@ -17,6 +17,29 @@ extern crate alloc;
 
 use alloc::vec::Vec;
 
+pub mod splash;
+
+// UEFI memory types of the regions the bootloader allocates for the kernel
+// (they show up in the memory map the kernel gets).
+
+/// UEFI memory region type for ELF data allocation.
+pub const KERNEL_ELF: u32 = 0x80000001;
+
+/// UEFI memory region type for kernel page-tables.
+pub const KERNEL_PT: u32 = 0x80000002;
+
+/// UEFI memory region type for the kernel stack.
+pub const KERNEL_STACK: u32 = 0x80000003;
+
+/// UEFI memory region type for the memory map.
+pub const UEFI_MEMORY_MAP: u32 = 0x80000004;
+
+/// UEFI memory region type for arguments passed to the kernel.
+pub const KERNEL_ARGS: u32 = 0x80000005;
+
+/// UEFI memory region type for the modules (binaries) passed to the kernel.
+pub const MODULE: u32 = 0x80000006;
+
 /// Describes an ELF binary we loaded from the UEFI image into memory.
 #[derive(Eq, PartialEq, Clone)]
 pub struct Module {
@@ -30,6 +53,8 @@ pub struct Module {
     pub binary_paddr: x86::bits64::paging::PAddr,
     /// How big the binary is (in bytes)
     pub binary_size: usize,
+    /// SHA-256 of the binary (if the bootloader verified the modules).
+    pub sha256: Option<[u8; 32]>,
 }
 
 impl Module {
@@ -54,6 +79,7 @@ impl Module {
             binary_vaddr,
             binary_paddr,
             binary_size,
+            sha256: None,
         }
     }
 
@@ -107,6 +133,46 @@ impl core::fmt::Debug for Module {
     }
 }
 
+/// Where the bootloader found the kernel and modules.
+#[repr(C)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ModuleSource {
+    /// The (ESP) file systems of the machine.
+    FileSystem,
+    /// A TFTP server (the one we were PXE booted from).
+    Tftp { server: [u8; 4] },
+    /// The previous kernel (a soft-reboot, the kernel binary is the one it
+    /// got, the other modules are the ones of the first boot).
+    Kexec,
+}
+
+/// Where the bootloader got the randomness to place the kernel.
+#[repr(C)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum RandomSource {
+    /// The firmware's `EFI_RNG_PROTOCOL`.
+    UefiRng,
+    /// The RDRAND instruction.
+    Rdrand,
+    /// The random number generator of the kernel that loaded us with kexec.
+    Kernel,
+}
+
+/// How the bootloader chose where to load the kernel (kernel address space
+/// layout randomization).
+#[repr(C)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Kaslr {
+    /// Turned off on the command line (`kaslr=off`).
+    Disabled,
+    /// There was no source of randomness, the kernel is where the firmware
+    /// allocated memory for it.
+    Unavailable,
+    /// The kernel is at one of `slots` places, picked with randomness from
+    /// `source`.
+    Randomized { source: RandomSource, slots: u64 },
+}
+
 /// Arguments that are passed on to the kernel by the bootloader.
 #[repr(C)]
 #[derive(Debug)]
@@ -135,17 +201,38 @@ pub struct KernelArgs {
     pub stack: (x86::bits64::paging::PAddr, usize),
 
     /// The offset where the elfloader placed the kernel
+    ///
+    /// Addresses in the kernel binary are relative to it, the bootloader
+    /// relocated the kernel by this much (it changes every boot, see `kaslr`).
     pub kernel_elf_offset: x86::bits64::paging::VAddr,
 
+    /// How the bootloader picked `kernel_elf_offset`.
+    pub kaslr: Kaslr,
+
     /// The physical address of the ACPIv1 RSDP (Root System Description Pointer)
     pub acpi1_rsdp: x86::bits64::paging::PAddr,
 
     /// The physical address of the ACPIv2 RSDP (Root System Description Pointer)
     pub acpi2_rsdp: x86::bits64::paging::PAddr,
 
+    /// The physical address of the SMBIOS (32-bit) entry point structure
+    pub smbios_entry: x86::bits64::paging::PAddr,
+
+    /// The physical address of the SMBIOS 3 (64-bit) entry point structure
+    pub smbios3_entry: x86::bits64::paging::PAddr,
+
+    /// The physical address of the UEFI runtime services table.
+    ///
+    /// The runtime services are relocated to the kernel address space (at
+    /// `KERNEL_OFFSET`), this is zero if the relocation failed.
+    pub runtime_services: x86::bits64::paging::PAddr,
+
     /// Modules (ELF binaries found in the UEFI partition) passed to the kernel
     /// modules[0] is the kernel binary
     pub modules: arrayvec::ArrayVec<Module, { KernelArgs::MAX_MODULES }>,
+
+    /// Where the modules were loaded from (for diagnostics).
+    pub module_source: ModuleSource,
 }
 
 impl KernelArgs {
@@ -159,9 +246,14 @@ impl KernelArgs {
             pml4: x86::bits64::paging::PAddr(0),
             stack: (x86::bits64::paging::PAddr(0), 0),
             kernel_elf_offset: x86::bits64::paging::VAddr(0),
+            kaslr: Kaslr::Disabled,
             acpi1_rsdp: x86::bits64::paging::PAddr(0),
             acpi2_rsdp: x86::bits64::paging::PAddr(0),
+            smbios_entry: x86::bits64::paging::PAddr(0),
+            smbios3_entry: x86::bits64::paging::PAddr(0),
+            runtime_services: x86::bits64::paging::PAddr(0),
             modules: arrayvec::ArrayVec::new_const(),
+            module_source: ModuleSource::FileSystem,
         }
     }
 }
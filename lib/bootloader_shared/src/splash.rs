@@ -0,0 +1,153 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A boot progress bar on the GOP framebuffer.
+//!
+//! The bootloader and the early kernel fill one segment of the bar per
+//! [`BootStage`] they complete (every stage has its own color). On machines
+//! without a serial console this tells us how far we got if the boot hangs.
+//! The remaining segments turn red if the kernel panics.
+
+use uefi::proto::console::gop::{ModeInfo, PixelFormat};
+
+/// The stages of the boot process (in order).
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BootStage {
+    /// The bootloader started.
+    Bootloader = 0,
+    /// The bootloader found (and verified) the kernel and modules.
+    ModulesLoaded = 1,
+    /// The bootloader loaded the kernel ELF into its address space.
+    KernelLoaded = 2,
+    /// The kernel started (before serial and memory initialization).
+    KernelEntry = 3,
+    /// The kernel parsed ACPI and the machine topology.
+    Topology = 4,
+    /// The kernel initialized physical memory management (needs the
+    /// topology for the NUMA affinity of memory).
+    MemoryInit = 5,
+    /// The kernel started all application cores.
+    CoresStarted = 6,
+    /// Initialization is done, the kernel enters its main function.
+    Running = 7,
+}
+
+impl BootStage {
+    /// Number of stages (segments of the progress bar).
+    pub const COUNT: usize = 8;
+
+    /// Color (RGB) of the segment.
+    fn color(&self) -> [u8; 3] {
+        match self {
+            BootStage::Bootloader => [0x60, 0x60, 0x60],
+            BootStage::ModulesLoaded => [0x80, 0x80, 0x80],
+            BootStage::KernelLoaded => [0xa0, 0xa0, 0xa0],
+            BootStage::KernelEntry => [0x20, 0x60, 0xc0],
+            BootStage::Topology => [0x20, 0x80, 0xc0],
+            BootStage::MemoryInit => [0x20, 0xa0, 0xa0],
+            BootStage::CoresStarted => [0x20, 0xa0, 0x60],
+            BootStage::Running => [0x20, 0xc0, 0x20],
+        }
+    }
+}
+
+/// Color (RGB) of the segments after a failure.
+const FAILED: [u8; 3] = [0xd0, 0x20, 0x20];
+
+/// Draws the progress bar into the framebuffer.
+pub struct Splash<'a> {
+    frame_buffer: &'a mut [u8],
+    width: usize,
+    height: usize,
+    stride: usize,
+    bgr: bool,
+    /// The last stage we completed.
+    stage: Option<BootStage>,
+}
+
+impl<'a> Splash<'a> {
+    /// Returns `None` if we can't draw into the framebuffer (i.e., it only
+    /// supports `Blt`).
+    pub fn new(frame_buffer: &'a mut [u8], mode: &ModeInfo) -> Option<Splash<'a>> {
+        let bgr = match mode.pixel_format() {
+            PixelFormat::Rgb => false,
+            PixelFormat::Bgr => true,
+            _ => return None,
+        };
+        let (width, height) = mode.resolution();
+        if frame_buffer.len() < mode.stride() * height * 4 {
+            return None;
+        }
+
+        Some(Splash {
+            frame_buffer,
+            width,
+            height,
+            stride: mode.stride(),
+            bgr,
+            stage: None,
+        })
+    }
+
+    /// Marks `stage` as completed (and all stages before it).
+    pub fn progress(&mut self, stage: BootStage) {
+        for idx in 0..=stage as usize {
+            self.segment(idx, Self::stage(idx).color());
+        }
+        self.stage = Some(stage);
+    }
+
+    /// Turns all segments after the last completed stage red.
+    pub fn fail(&mut self) {
+        let first = self.stage.map(|s| s as usize + 1).unwrap_or(0);
+        for idx in first..BootStage::COUNT {
+            self.segment(idx, FAILED);
+        }
+    }
+
+    fn stage(idx: usize) -> BootStage {
+        match idx {
+            0 => BootStage::Bootloader,
+            1 => BootStage::ModulesLoaded,
+            2 => BootStage::KernelLoaded,
+            3 => BootStage::KernelEntry,
+            4 => BootStage::Topology,
+            5 => BootStage::MemoryInit,
+            6 => BootStage::CoresStarted,
+            _ => BootStage::Running,
+        }
+    }
+
+    /// Fills segment `idx` of the bar (centered, in the lower quarter of the
+    /// screen).
+    fn segment(&mut self, idx: usize, color: [u8; 3]) {
+        let bar_width = self.width * 3 / 5;
+        let segment_width = bar_width / BootStage::COUNT;
+        let height = core::cmp::max(self.height / 60, 4);
+
+        let x = (self.width - bar_width) / 2 + idx * segment_width;
+        let y = self.height * 3 / 4;
+        // Leave a gap between segments
+        self.fill(x + 1, y, segment_width.saturating_sub(2), height, color);
+    }
+
+    fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, color: [u8; 3]) {
+        let pixel = if self.bgr {
+            [color[2], color[1], color[0], 0]
+        } else {
+            [color[0], color[1], color[2], 0]
+        };
+
+        for row in y..core::cmp::min(y + height, self.height) {
+            for col in x..core::cmp::min(x + width, self.width) {
+                let offset = (row * self.stride + col) * 4;
+                for (i, byte) in pixel.iter().enumerate() {
+                    // The framebuffer is MMIO
+                    unsafe {
+                        core::ptr::write_volatile(&mut self.frame_buffer[offset + i], *byte);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Feeds random register values to the system call decoding logic.
+
+#![no_main]
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+use kpi::decode;
+use kpi::io::{FileFlags, FileModes};
+use kpi::process::{CoreRequestFlags, SchedulingClass};
+use kpi::{
+    FileOperation, NetOperation, ProcessOperation, SystemCall, SystemCallError, SystemOperation,
+    VSpaceOperation,
+};
+
+/// The registers user-space controls on a system call.
+#[derive(Arbitrary, Debug)]
+struct Registers {
+    function: u64,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    arg5: u64,
+}
+
+fuzz_target!(|r: Registers| {
+    let _ = SystemCall::new(r.function);
+    let _ = SystemOperation::from(r.arg1);
+    let _ = ProcessOperation::from(r.arg1);
+    let _ = VSpaceOperation::from(r.arg1);
+    let _ = FileOperation::from(r.arg1);
+    let _ = NetOperation::from(r.arg1);
+    let _ = SystemCallError::from(r.arg1);
+
+    if let Ok(op) = decode::operation(r.function, r.arg1) {
+        // A successfully decoded operation must round-trip
+        assert_eq!(SystemCall::new(r.function) as u64, r.function);
+        match op {
+            decode::Operation::System(op) => assert_eq!(op as u64, r.arg1),
+            decode::Operation::Process(op) => assert_eq!(op as u64, r.arg1),
+            decode::Operation::VSpace(op) => assert_eq!(op as u64, r.arg1),
+            decode::Operation::FileIO(op) => assert_eq!(op as u64, r.arg1),
+            decode::Operation::Test(op) => assert_eq!(op as u64, r.arg1),
+            decode::Operation::Net(op) => assert_eq!(op as u64, r.arg1),
+        }
+
+        // Accepted arguments only describe buffers in user-space
+        let args = [r.arg2, r.arg3, r.arg4, r.arg5];
+        if decode::arguments(op, args).is_ok() {
+            let signature = kpi::signature::signature(op).unwrap();
+            for (idx, kind) in signature.args.iter().enumerate() {
+                if *kind == kpi::signature::Arg::Address {
+                    assert!(args[idx] < kpi::KERNEL_BASE);
+                }
+            }
+        }
+    }
+
+    // Buffers are passed as (base, len) in consecutive arguments
+    for (base, size) in [(r.arg2, r.arg3), (r.arg3, r.arg4), (r.arg4, r.arg5)] {
+        if let Ok(range) = decode::user_range(base, size) {
+            assert!(range.start <= range.end);
+            assert!(range.end < kpi::KERNEL_BASE);
+        }
+    }
+
+    for raw in [r.arg2, r.arg3, r.arg4, r.arg5] {
+        let _ = FileFlags::from(raw);
+        let _ = FileModes::from(raw);
+        let _ = CoreRequestFlags::from(raw);
+        let _ = SchedulingClass::from(raw);
+
+        if let Ok(flags) = decode::file_flags(raw) {
+            assert_eq!(u64::from(flags), raw);
+        }
+        if let Ok(modes) = decode::file_modes(raw) {
+            assert_eq!(u64::from(modes), raw);
+        }
+        if let Ok(flags) = decode::core_request_flags(raw) {
+            assert_eq!(flags.bits(), raw);
+        }
+        let _ = decode::scheduling_class(raw);
+    }
+});
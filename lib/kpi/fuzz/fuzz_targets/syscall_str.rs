@@ -0,0 +1,18 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Feeds random strings to the `From<&str>` conversions of the system call
+//! enums (used by tools that parse syscall traces).
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use kpi::{FileOperation, ProcessOperation, SystemCall, SystemOperation, VSpaceOperation};
+
+fuzz_target!(|s: &str| {
+    let _ = SystemCall::from(s);
+    let _ = SystemOperation::from(s);
+    let _ = ProcessOperation::from(s);
+    let _ = VSpaceOperation::from(s);
+    let _ = FileOperation::from(s);
+});
@@ -0,0 +1,186 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Decoding of the raw register values passed to a system call.
+//!
+//! This is the first thing the kernel does on a system call entry, it doesn't
+//! depend on any kernel state so it can be compiled (and fuzzed, see
+//! `lib/kpi/fuzz`) on the host.
+
+use core::ops::Range;
+
+use crate::io::{FileFlags, FileModes};
+use crate::process::{CoreRequestFlags, MapFlags, SchedulingClass};
+use crate::signature::{signature, Arg};
+use crate::*;
+
+/// A system call with its operation decoded.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Operation {
+    System(SystemOperation),
+    Process(ProcessOperation),
+    VSpace(VSpaceOperation),
+    FileIO(FileOperation),
+    Test(TestOperation),
+    Net(NetOperation),
+}
+
+/// Decode `function` (%rdi) and `arg1` (%rsi) into the requested operation.
+pub fn operation(function: u64, arg1: u64) -> Result<Operation, SystemCallError> {
+    match SystemCall::new(function) {
+        SystemCall::System => match SystemOperation::from(arg1) {
+            SystemOperation::Unknown => Err(SystemCallError::NotSupported),
+            op => Ok(Operation::System(op)),
+        },
+        SystemCall::Process => match ProcessOperation::from(arg1) {
+            ProcessOperation::Unknown => Err(SystemCallError::NotSupported),
+            op => Ok(Operation::Process(op)),
+        },
+        SystemCall::VSpace => match VSpaceOperation::from(arg1) {
+            VSpaceOperation::Unknown => Err(SystemCallError::NotSupported),
+            op => Ok(Operation::VSpace(op)),
+        },
+        SystemCall::FileIO => match FileOperation::from(arg1) {
+            FileOperation::Unknown => Err(SystemCallError::NotSupported),
+            op => Ok(Operation::FileIO(op)),
+        },
+        SystemCall::Test => match TestOperation::from(arg1) {
+            TestOperation::Unknown => Err(SystemCallError::NotSupported),
+            op => Ok(Operation::Test(op)),
+        },
+        SystemCall::Net => match NetOperation::from(arg1) {
+            NetOperation::Unknown => Err(SystemCallError::NotSupported),
+            op => Ok(Operation::Net(op)),
+        },
+        SystemCall::Unknown => Err(SystemCallError::NotSupported),
+    }
+}
+
+/// Check that a user-space buffer of `size` bytes at `base` lies entirely
+/// below the kernel address space (and doesn't wrap around).
+///
+/// Returns the address range covered by the buffer.
+pub fn user_range(base: u64, size: u64) -> Result<Range<u64>, SystemCallError> {
+    let end = base.checked_add(size).ok_or(SystemCallError::BadAddress)?;
+    if end < KERNEL_BASE {
+        Ok(base..end)
+    } else {
+        Err(SystemCallError::BadAddress)
+    }
+}
+
+/// Check the arguments (`arg2` to `arg5`) of `op` against its signature.
+///
+/// Buffers (an [`Arg::Address`] followed by an [`Arg::Length`]) and
+/// addresses have to lie in user-space, file descriptors have to fit in 32
+/// bits. Registers that aren't part of the signature are ignored.
+pub fn arguments(op: Operation, args: [u64; 4]) -> Result<(), SystemCallError> {
+    let signature = signature(op).ok_or(SystemCallError::NotSupported)?;
+
+    for (idx, kind) in signature.args.iter().enumerate() {
+        match kind {
+            Arg::Address => {
+                let len = match signature.args.get(idx + 1) {
+                    Some(Arg::Length) => args[idx + 1],
+                    _ => 0,
+                };
+                user_range(args[idx], len)?;
+            }
+            Arg::Fd => {
+                if args[idx] > u32::MAX as u64 {
+                    return Err(SystemCallError::BadFileDescriptor);
+                }
+            }
+            Arg::Value | Arg::Length | Arg::Flags => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Decode file flags, rejecting unknown bits.
+pub fn file_flags(raw: u64) -> Result<FileFlags, SystemCallError> {
+    FileFlags::from_bits(raw).ok_or(SystemCallError::BadFlags)
+}
+
+/// Decode file modes, rejecting unknown bits.
+pub fn file_modes(raw: u64) -> Result<FileModes, SystemCallError> {
+    FileModes::from_bits(raw).ok_or(SystemCallError::BadFlags)
+}
+
+/// Decode the flags of a core request, rejecting unknown bits.
+pub fn core_request_flags(raw: u64) -> Result<CoreRequestFlags, SystemCallError> {
+    CoreRequestFlags::from_bits(raw).ok_or(SystemCallError::BadFlags)
+}
+
+/// Decode the flags of a mapping, rejecting unknown bits and more than one
+/// caching attribute.
+pub fn map_flags(raw: u64) -> Result<MapFlags, SystemCallError> {
+    let flags = MapFlags::from_bits(raw).ok_or(SystemCallError::BadFlags)?;
+    let caching = flags & (MapFlags::NOCACHE | MapFlags::WRITE_THROUGH | MapFlags::WRITE_COMBINE);
+    if caching.bits().count_ones() > 1 {
+        return Err(SystemCallError::BadFlags);
+    }
+    Ok(flags)
+}
+
+/// Decode a scheduling class.
+pub fn scheduling_class(raw: u64) -> Result<SchedulingClass, SystemCallError> {
+    match SchedulingClass::from(raw) {
+        SchedulingClass::Unknown => Err(SystemCallError::NotSupported),
+        class => Ok(class),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn user_range_rejects_overflow() {
+    assert_eq!(user_range(0x1000, 0x1000), Ok(0x1000..0x2000));
+    assert_eq!(
+        user_range(u64::MAX - 1, 2),
+        Err(SystemCallError::BadAddress)
+    );
+    assert_eq!(
+        user_range(KERNEL_BASE - 1, 1),
+        Err(SystemCallError::BadAddress)
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn arguments_check_buffers() {
+    let log = Operation::Process(ProcessOperation::Log);
+    assert_eq!(arguments(log, [0x1000, 0x10, 0, 0]), Ok(()));
+    assert_eq!(
+        arguments(log, [KERNEL_BASE, 0x10, 0, 0]),
+        Err(SystemCallError::BadAddress)
+    );
+
+    let close = Operation::FileIO(FileOperation::Close);
+    assert_eq!(
+        arguments(close, [u64::MAX, 0, 0, 0]),
+        Err(SystemCallError::BadFileDescriptor)
+    );
+
+    let create = Operation::FileIO(FileOperation::Create);
+    assert_eq!(
+        arguments(create, [0, 0, 0, 0]),
+        Err(SystemCallError::NotSupported)
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn map_flags_single_caching_attribute() {
+    let rw = MapFlags::READ_WRITE_USER;
+    assert_eq!(map_flags(rw.bits()), Ok(rw));
+    assert_eq!(
+        map_flags((rw | MapFlags::WRITE_COMBINE).bits()),
+        Ok(rw | MapFlags::WRITE_COMBINE)
+    );
+    assert_eq!(
+        map_flags((rw | MapFlags::NOCACHE | MapFlags::WRITE_COMBINE).bits()),
+        Err(SystemCallError::BadFlags)
+    );
+    assert_eq!(map_flags(1 << 40), Err(SystemCallError::BadFlags));
+}
@@ -8,9 +8,13 @@ use bitflags::*;
 pub struct FileInfo {
     pub ftype: u64,
     pub fsize: u64,
+    /// Bytes stored in the mount the file belongs to.
+    pub mount_used: u64,
+    /// Capacity of the mount the file belongs to (`u64::MAX` if unlimited).
+    pub mount_limit: u64,
 }
 
-/// Each file-node can be of two types: directory or a file.
+/// Each file-node is a directory, a file or a symbolic link.
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 #[repr(u64)]
 pub enum FileType {
@@ -18,6 +22,8 @@ pub enum FileType {
     Directory = 1,
     /// The mnode is of regular type
     File = 2,
+    /// The mnode is a symbolic link to another path
+    Symlink = 3,
 }
 
 impl From<FileType> for u64 {
@@ -25,6 +31,33 @@ impl From<FileType> for u64 {
         match ft {
             FileType::Directory => 1,
             FileType::File => 2,
+            FileType::Symlink => 3,
+        }
+    }
+}
+
+/// What a mount does once it reaches its size limit.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[repr(u64)]
+pub enum MountKind {
+    /// Writes that would exceed the limit fail with `NoSpace`.
+    Tmpfs = 0,
+    /// The least recently written files of the mount are deleted to make space.
+    Cache = 1,
+    /// The files are kept on a disk: they're loaded when it's mounted and
+    /// written back on `Fsync`. Writes that would exceed the size of the
+    /// disk fail with `NoSpace`.
+    Disk = 2,
+    Unknown,
+}
+
+impl From<u64> for MountKind {
+    fn from(kind: u64) -> MountKind {
+        match kind {
+            0 => MountKind::Tmpfs,
+            1 => MountKind::Cache,
+            2 => MountKind::Disk,
+            _ => MountKind::Unknown,
         }
     }
 }
@@ -38,7 +71,9 @@ bitflags! {
         const O_RDWR = 0x0003; /* open for reading and writing */
         const O_CREAT = 0x0200; /* create if nonexistant */
         const O_TRUNC = 0x0400; /* truncate to zero length */
+        const O_EXCL = 0x0800; /* with O_CREAT: fail if the file exists */
         const O_APPEND = 0x02000; /* append at the EOF */
+        const O_CLOEXEC = 0x400000; /* never inherited by spawned children */
     }
 }
 
@@ -82,9 +117,144 @@ impl FileFlags {
         (*self & FileFlags::O_TRUNC) == FileFlags::O_TRUNC
     }
 
+    pub fn is_exclusive(&self) -> bool {
+        (*self & FileFlags::O_EXCL) == FileFlags::O_EXCL
+    }
+
     pub fn is_append(&self) -> bool {
         (*self & FileFlags::O_APPEND) == FileFlags::O_APPEND
     }
+
+    pub fn is_cloexec(&self) -> bool {
+        (*self & FileFlags::O_CLOEXEC) == FileFlags::O_CLOEXEC
+    }
+}
+
+bitflags! {
+    /// Readiness events of a file descriptor (see [`PollFd`]).
+    pub struct PollEvents: u64 {
+        const POLLIN = 0x0001; /* data can be read */
+        const POLLOUT = 0x0004; /* data can be written */
+        const POLLERR = 0x0008; /* error condition (output only) */
+        const POLLNVAL = 0x0020; /* invalid file descriptor (output only) */
+    }
+}
+
+/// What the `fd` of a [`PollFd`] refers to.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+#[repr(u64)]
+pub enum PollKind {
+    /// A file descriptor, ready for what it was opened for.
+    File = 0,
+    /// A UDP socket (see `Net::udp_bind`), ready for `POLLIN` once a
+    /// datagram arrived and for `POLLOUT` while its send queue has space.
+    Socket = 1,
+    /// The keyboard (`fd` is ignored), ready for `POLLIN` once a key is
+    /// queued for us. Polling it takes the focus like waiting for
+    /// `Event::Keyboard` does, `POLLERR` if another process has it.
+    Keyboard = 2,
+    /// The (4-byte aligned) futex word at address `fd`, ready for `POLLIN`
+    /// once it isn't `arg` anymore. `FutexWake` wakes up polls on it.
+    Futex = 3,
+    Unknown,
+}
+
+impl From<u64> for PollKind {
+    fn from(kind: u64) -> PollKind {
+        match kind {
+            0 => PollKind::File,
+            1 => PollKind::Socket,
+            2 => PollKind::Keyboard,
+            3 => PollKind::Futex,
+            _ => PollKind::Unknown,
+        }
+    }
+}
+
+/// Timeout of the `Poll` system call that waits until an entry is ready.
+pub const POLL_FOREVER: u64 = u64::MAX;
+
+/// An entry of the array passed to the `Poll` system call.
+///
+/// The kernel reads `fd`, `events`, `kind` and `arg` and sets `revents` to
+/// the subset of `events` the entry is ready for (`POLLERR` and `POLLNVAL`
+/// are always reported).
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct PollFd {
+    pub fd: u64,
+    pub events: u64,
+    pub revents: u64,
+    /// What `fd` refers to (a [`PollKind`]).
+    pub kind: u64,
+    /// The value a [`PollKind::Futex`] word waits to change from.
+    pub arg: u64,
+}
+
+impl PollFd {
+    /// An entry for the file descriptor `fd`.
+    pub fn new(fd: u64, events: PollEvents) -> PollFd {
+        PollFd {
+            fd,
+            events: events.bits(),
+            ..Default::default()
+        }
+    }
+
+    /// An entry for the UDP socket `socket`.
+    pub fn socket(socket: u64, events: PollEvents) -> PollFd {
+        PollFd {
+            kind: PollKind::Socket as u64,
+            ..PollFd::new(socket, events)
+        }
+    }
+
+    /// An entry for the keyboard (waits for keys).
+    pub fn keyboard() -> PollFd {
+        PollFd {
+            kind: PollKind::Keyboard as u64,
+            ..PollFd::new(0, PollEvents::POLLIN)
+        }
+    }
+
+    /// An entry for the futex word at address `word` that is ready once it
+    /// isn't `expected` anymore.
+    pub fn futex(word: u64, expected: u32) -> PollFd {
+        PollFd {
+            kind: PollKind::Futex as u64,
+            arg: expected as u64,
+            ..PollFd::new(word, PollEvents::POLLIN)
+        }
+    }
+
+    pub fn kind(&self) -> PollKind {
+        PollKind::from(self.kind)
+    }
+
+    pub fn events(&self) -> PollEvents {
+        PollEvents::from_bits_truncate(self.events)
+    }
+
+    pub fn revents(&self) -> PollEvents {
+        PollEvents::from_bits_truncate(self.revents)
+    }
+}
+
+/// The maximum number of buffers in a `ReadV`/`WriteV` request.
+pub const IOV_MAX: usize = 1024;
+
+/// A buffer of a vectored (scatter-gather) read or write.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct IoVec {
+    pub base: u64,
+    pub len: u64,
+}
+
+impl IoVec {
+    pub fn new(base: u64, len: u64) -> IoVec {
+        IoVec { base, len }
+    }
 }
 
 bitflags! {
@@ -126,3 +296,130 @@ impl FileModes {
         (*self & FileModes::S_IXUSR) == FileModes::S_IXUSR
     }
 }
+
+/// Number of entries in the submission and completion queue of an [`IoRing`].
+pub const RING_ENTRIES: usize = 64;
+
+/// Operations that can be submitted to an [`IoRing`].
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u64)]
+pub enum RingOperation {
+    /// Does nothing, completes immediately.
+    Nop = 0,
+    /// Read from a file (at the current position).
+    Read = 1,
+    /// Write to a file (at the current position).
+    Write = 2,
+    /// Read from a file at the given offset.
+    ReadAt = 3,
+    /// Write to a file at the given offset.
+    WriteAt = 4,
+    /// Send on a socket.
+    Send = 5,
+    /// Receive from a socket.
+    Recv = 6,
+    Unknown,
+}
+
+impl From<u64> for RingOperation {
+    /// Construct a RingOperation enum based on a 64-bit value.
+    fn from(op: u64) -> RingOperation {
+        match op {
+            0 => RingOperation::Nop,
+            1 => RingOperation::Read,
+            2 => RingOperation::Write,
+            3 => RingOperation::ReadAt,
+            4 => RingOperation::WriteAt,
+            5 => RingOperation::Send,
+            6 => RingOperation::Recv,
+            _ => RingOperation::Unknown,
+        }
+    }
+}
+
+/// A request posted by user-space to the submission queue.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct SubmissionEntry {
+    /// The [`RingOperation`].
+    pub op: u64,
+    pub fd: u64,
+    pub buf: u64,
+    pub len: u64,
+    /// Only used by `ReadAt` and `WriteAt`.
+    pub offset: i64,
+    /// Passed through unmodified to the [`CompletionEntry`].
+    pub user_data: u64,
+}
+
+/// The result of a request, posted by the kernel to the completion queue.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct CompletionEntry {
+    pub user_data: u64,
+    /// Number of bytes transferred.
+    pub result: u64,
+    /// A [`crate::SystemCallError`] (0 on success).
+    pub error: u64,
+}
+
+/// Producer/consumer positions of the queues of an [`IoRing`].
+///
+/// The positions only ever increase, an entry lives at index
+/// `position % RING_ENTRIES`. User-space owns `sq_tail` and `cq_head`, the
+/// kernel owns `sq_head` and `cq_tail`.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct RingHeader {
+    pub sq_head: u64,
+    pub sq_tail: u64,
+    pub cq_head: u64,
+    pub cq_tail: u64,
+}
+
+/// Memory shared between user-space and the kernel for asynchronous I/O.
+///
+/// User-space posts requests to `sq` and calls `RingEnter`, the kernel
+/// processes them and posts the results to `cq`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct IoRing {
+    pub header: RingHeader,
+    pub sq: [SubmissionEntry; RING_ENTRIES],
+    pub cq: [CompletionEntry; RING_ENTRIES],
+}
+
+impl Default for IoRing {
+    fn default() -> IoRing {
+        IoRing::new()
+    }
+}
+
+impl IoRing {
+    pub const SQ_HEAD_OFFSET: u64 = 0;
+    pub const CQ_TAIL_OFFSET: u64 = 3 * core::mem::size_of::<u64>() as u64;
+
+    pub fn new() -> IoRing {
+        IoRing {
+            header: Default::default(),
+            sq: [Default::default(); RING_ENTRIES],
+            cq: [Default::default(); RING_ENTRIES],
+        }
+    }
+
+    /// Offset (from the start of the ring) of the submission entry at
+    /// `position`.
+    pub fn sq_offset(position: u64) -> u64 {
+        let idx = (position % RING_ENTRIES as u64) as usize;
+        (core::mem::size_of::<RingHeader>() + idx * core::mem::size_of::<SubmissionEntry>()) as u64
+    }
+
+    /// Offset (from the start of the ring) of the completion entry at
+    /// `position`.
+    pub fn cq_offset(position: u64) -> u64 {
+        let idx = (position % RING_ENTRIES as u64) as usize;
+        (core::mem::size_of::<RingHeader>()
+            + RING_ENTRIES * core::mem::size_of::<SubmissionEntry>()
+            + idx * core::mem::size_of::<CompletionEntry>()) as u64
+    }
+}
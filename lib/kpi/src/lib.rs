@@ -4,13 +4,17 @@
 //! Defines the public kernel interface (i.e., system call interface)
 //! and associated data-types.
 #![no_std]
-#![feature(llvm_asm)]
+#![feature(llvm_asm, thread_local)]
 
 #[cfg(not(target_os = "none"))]
 extern crate alloc;
 
+pub mod decode;
 pub mod io;
+pub mod net;
 pub mod process;
+#[macro_use]
+pub mod signature;
 pub mod system;
 pub mod upcall;
 pub mod x86_64;
@@ -54,6 +58,20 @@ pub enum SystemCallError {
     PermissionError = 9,
     /// Bad offset
     OffsetError = 10,
+    /// The requested core(s) are already allocated to a process.
+    CoreUnavailable = 11,
+    /// A gang-scheduling request couldn't be satisfied as a whole.
+    GangUnsatisfiable = 12,
+    /// The file-system (mount) has no space left.
+    NoSpace = 13,
+    /// The file doesn't contain a (usable) process checkpoint.
+    InvalidCheckpoint = 14,
+    /// The process has as many files open as its limit allows.
+    OpenFileLimit = 15,
+    /// Resolving a path followed too many symbolic links.
+    SymlinkLoop = 16,
+    /// A name (e.g., of a binary) isn't valid UTF-8.
+    InvalidName = 17,
     /// Placeholder for an invalid, unknown error code.
     Unknown,
 }
@@ -72,6 +90,13 @@ impl From<u64> for SystemCallError {
             8 => SystemCallError::BadFlags,
             9 => SystemCallError::PermissionError,
             10 => SystemCallError::OffsetError,
+            11 => SystemCallError::CoreUnavailable,
+            12 => SystemCallError::GangUnsatisfiable,
+            13 => SystemCallError::NoSpace,
+            14 => SystemCallError::InvalidCheckpoint,
+            15 => SystemCallError::OpenFileLimit,
+            16 => SystemCallError::SymlinkLoop,
+            17 => SystemCallError::InvalidName,
             _ => SystemCallError::Unknown,
         }
     }
@@ -83,14 +108,14 @@ impl From<u64> for SystemCallError {
 pub enum ProcessOperation {
     /// Exit the process.
     Exit = 1,
-    /// Log to console.
+    /// Log to console (with a severity level and target).
     Log = 2,
     /// Sets the process control and save area for trap/IRQ forwarding
     /// to user-space for this process and CPU.
     GetVCpuArea = 3,
     /// Allocate a device interrupt vector.
     AllocateVector = 4,
-    /// Subscribe to a trap and/or interrupt events.
+    /// Wait for a kernel event the process subscribes to (e.g., an OOM kill).
     SubscribeEvent = 5,
     /// Query info about the current process.
     GetProcessInfo = 6,
@@ -98,6 +123,48 @@ pub enum ProcessOperation {
     RequestCore = 7,
     /// Allocate a physical memory page as a mem object to the process.
     AllocatePhysical = 8,
+    /// Request a set of cores (constrained by an affinity mask) for the process.
+    RequestCores = 9,
+    /// Set the scheduling class for the executor on the current core.
+    SetSchedulingClass = 10,
+    /// Save the state of the current process in a file.
+    Checkpoint = 11,
+    /// Create a new process from a checkpoint file.
+    Restore = 12,
+    /// Set the log level of the current process.
+    SetLogLevel = 13,
+    /// Wait on the current core until a barrier word changes.
+    BarrierWait = 14,
+    /// Deliver the upcall that arrived while the vCPU was disabled.
+    DeliverUpcall = 15,
+    /// Start a new (child) process from a binary module.
+    Spawn = 16,
+    /// Query whether a child process exited (and its exit code).
+    WaitPid = 17,
+    /// Terminate a child process.
+    Kill = 18,
+    /// Turn system call tracing of a (child) process on or off.
+    Trace = 19,
+    /// Read the system calls recorded for a traced process.
+    ReadTrace = 20,
+    /// Write a core dump if the process crashes (or don't).
+    SetCoreDump = 21,
+    /// Sleep for a number of nanoseconds.
+    Sleep = 22,
+    /// Set how important a process is to the OOM killer.
+    SetOomPriority = 23,
+    /// Replace a service with an instance of a new binary.
+    Reload = 24,
+    /// Drive a device from user-space.
+    ClaimDevice = 25,
+    /// Lower the number of files the process can have open.
+    SetFdLimit = 26,
+    /// Wait on the current core until another core wakes up a futex word.
+    FutexWait = 27,
+    /// Wake up the cores that wait on a futex word.
+    FutexWake = 28,
+    /// Mask or unmask an interrupt routed to the process.
+    MaskVector = 29,
     Unknown,
 }
 
@@ -113,6 +180,27 @@ impl From<u64> for ProcessOperation {
             6 => ProcessOperation::GetProcessInfo,
             7 => ProcessOperation::RequestCore,
             8 => ProcessOperation::AllocatePhysical,
+            9 => ProcessOperation::RequestCores,
+            10 => ProcessOperation::SetSchedulingClass,
+            11 => ProcessOperation::Checkpoint,
+            12 => ProcessOperation::Restore,
+            13 => ProcessOperation::SetLogLevel,
+            14 => ProcessOperation::BarrierWait,
+            15 => ProcessOperation::DeliverUpcall,
+            16 => ProcessOperation::Spawn,
+            17 => ProcessOperation::WaitPid,
+            18 => ProcessOperation::Kill,
+            19 => ProcessOperation::Trace,
+            20 => ProcessOperation::ReadTrace,
+            21 => ProcessOperation::SetCoreDump,
+            22 => ProcessOperation::Sleep,
+            23 => ProcessOperation::SetOomPriority,
+            24 => ProcessOperation::Reload,
+            25 => ProcessOperation::ClaimDevice,
+            26 => ProcessOperation::SetFdLimit,
+            27 => ProcessOperation::FutexWait,
+            28 => ProcessOperation::FutexWake,
+            29 => ProcessOperation::MaskVector,
             _ => ProcessOperation::Unknown,
         }
     }
@@ -130,6 +218,27 @@ impl From<&str> for ProcessOperation {
             "GetProcessInfo" => ProcessOperation::GetProcessInfo,
             "RequestCore" => ProcessOperation::RequestCore,
             "AllocatePhysical" => ProcessOperation::AllocatePhysical,
+            "RequestCores" => ProcessOperation::RequestCores,
+            "SetSchedulingClass" => ProcessOperation::SetSchedulingClass,
+            "Checkpoint" => ProcessOperation::Checkpoint,
+            "Restore" => ProcessOperation::Restore,
+            "SetLogLevel" => ProcessOperation::SetLogLevel,
+            "BarrierWait" => ProcessOperation::BarrierWait,
+            "DeliverUpcall" => ProcessOperation::DeliverUpcall,
+            "Spawn" => ProcessOperation::Spawn,
+            "WaitPid" => ProcessOperation::WaitPid,
+            "Kill" => ProcessOperation::Kill,
+            "Trace" => ProcessOperation::Trace,
+            "ReadTrace" => ProcessOperation::ReadTrace,
+            "SetCoreDump" => ProcessOperation::SetCoreDump,
+            "Sleep" => ProcessOperation::Sleep,
+            "SetOomPriority" => ProcessOperation::SetOomPriority,
+            "Reload" => ProcessOperation::Reload,
+            "ClaimDevice" => ProcessOperation::ClaimDevice,
+            "SetFdLimit" => ProcessOperation::SetFdLimit,
+            "FutexWait" => ProcessOperation::FutexWait,
+            "FutexWake" => ProcessOperation::FutexWake,
+            "MaskVector" => ProcessOperation::MaskVector,
             _ => ProcessOperation::Unknown,
         }
     }
@@ -149,6 +258,14 @@ pub enum VSpaceOperation {
     MapFrame = 4,
     /// Resolve a virtual to a physical address
     Identify = 5,
+    /// Reserve a region of the address-space without backing it
+    Reserve = 6,
+    /// Back (part of) a reserved region with memory
+    Commit = 7,
+    /// Describe the mapped regions of the address-space
+    Dump = 8,
+    /// Drop a reservation (its committed pages stay mapped)
+    Unreserve = 9,
     Unknown,
 }
 
@@ -161,6 +278,10 @@ impl From<u64> for VSpaceOperation {
             3 => VSpaceOperation::MapDevice,
             4 => VSpaceOperation::MapFrame,
             5 => VSpaceOperation::Identify,
+            6 => VSpaceOperation::Reserve,
+            7 => VSpaceOperation::Commit,
+            8 => VSpaceOperation::Dump,
+            9 => VSpaceOperation::Unreserve,
             _ => VSpaceOperation::Unknown,
         }
     }
@@ -175,6 +296,10 @@ impl From<&str> for VSpaceOperation {
             "MapDevice" => VSpaceOperation::MapDevice,
             "MapFrame" => VSpaceOperation::MapFrame,
             "Identify" => VSpaceOperation::Identify,
+            "Reserve" => VSpaceOperation::Reserve,
+            "Commit" => VSpaceOperation::Commit,
+            "Dump" => VSpaceOperation::Dump,
+            "Unreserve" => VSpaceOperation::Unreserve,
             _ => VSpaceOperation::Unknown,
         }
     }
@@ -208,6 +333,22 @@ pub enum FileOperation {
     FileRename = 11,
     /// Create a directory.
     MkDir = 12,
+    /// Query the readiness of a set of file descriptors.
+    Poll = 13,
+    /// Process the requests in an I/O ring.
+    RingEnter = 14,
+    /// Mount a size-limited file-system on a directory.
+    Mount = 15,
+    /// Write the modified data of a file back to its storage.
+    Fsync = 16,
+    /// Read from a file into a list of buffers.
+    ReadV = 17,
+    /// Write a list of buffers to a file.
+    WriteV = 18,
+    /// Create a symbolic link.
+    Symlink = 19,
+    /// Read the target of a symbolic link.
+    ReadLink = 20,
     Unknown,
 }
 
@@ -227,6 +368,14 @@ impl From<u64> for FileOperation {
             10 => FileOperation::WriteDirect,
             11 => FileOperation::FileRename,
             12 => FileOperation::MkDir,
+            13 => FileOperation::Poll,
+            14 => FileOperation::RingEnter,
+            15 => FileOperation::Mount,
+            16 => FileOperation::Fsync,
+            17 => FileOperation::ReadV,
+            18 => FileOperation::WriteV,
+            19 => FileOperation::Symlink,
+            20 => FileOperation::ReadLink,
             _ => FileOperation::Unknown,
         }
     }
@@ -248,6 +397,14 @@ impl From<&str> for FileOperation {
             "WriteDirect" => FileOperation::WriteDirect,
             "Rename" => FileOperation::FileRename,
             "MkDir" => FileOperation::MkDir,
+            "Poll" => FileOperation::Poll,
+            "RingEnter" => FileOperation::RingEnter,
+            "Mount" => FileOperation::Mount,
+            "Fsync" => FileOperation::Fsync,
+            "ReadV" => FileOperation::ReadV,
+            "WriteV" => FileOperation::WriteV,
+            "Symlink" => FileOperation::Symlink,
+            "ReadLink" => FileOperation::ReadLink,
             _ => FileOperation::Unknown,
         }
     }
@@ -263,6 +420,29 @@ pub enum SystemOperation {
     Stats = 2,
     /// Get the core id for the current thread.
     GetCoreID = 3,
+    /// Move the current thread to a different core.
+    MigrateCore = 4,
+    /// Read a model-specific register (needs `Capabilities::MEASURE`).
+    ReadMsr = 5,
+    /// Query a CPUID leaf on the current core (needs `Capabilities::MEASURE`).
+    Cpuid = 6,
+    /// Get or set the interrupt moderation tunables of the network drivers.
+    NetModeration = 7,
+    /// Query how much of a kernel stack of a core was used.
+    StackUsage = 8,
+    /// Fill a buffer with random bytes.
+    GetRandom = 9,
+    /// Start, stop or dump the kernel profiler (needs `Capabilities::MEASURE`).
+    Profile = 10,
+    /// Query the build information of the kernel.
+    GetInfo = 11,
+    /// Query how many interrupts of a vector a core took.
+    IrqCount = 12,
+    /// Soft-reboot into a new kernel (needs `Capabilities::KEXEC`).
+    Kexec = 13,
+    /// Set the frequency governor and idle state of the cores (needs
+    /// `Capabilities::POWER`).
+    SetPowerPolicy = 14,
     Unknown,
 }
 
@@ -273,6 +453,17 @@ impl From<u64> for SystemOperation {
             1 => SystemOperation::GetHardwareThreads,
             2 => SystemOperation::Stats,
             3 => SystemOperation::GetCoreID,
+            4 => SystemOperation::MigrateCore,
+            5 => SystemOperation::ReadMsr,
+            6 => SystemOperation::Cpuid,
+            7 => SystemOperation::NetModeration,
+            8 => SystemOperation::StackUsage,
+            9 => SystemOperation::GetRandom,
+            10 => SystemOperation::Profile,
+            11 => SystemOperation::GetInfo,
+            12 => SystemOperation::IrqCount,
+            13 => SystemOperation::Kexec,
+            14 => SystemOperation::SetPowerPolicy,
             _ => SystemOperation::Unknown,
         }
     }
@@ -285,11 +476,116 @@ impl From<&str> for SystemOperation {
             "GetHardwareThreads" => SystemOperation::GetHardwareThreads,
             "Stats" => SystemOperation::Stats,
             "GetCoreID" => SystemOperation::GetCoreID,
+            "MigrateCore" => SystemOperation::MigrateCore,
+            "ReadMsr" => SystemOperation::ReadMsr,
+            "Cpuid" => SystemOperation::Cpuid,
+            "NetModeration" => SystemOperation::NetModeration,
+            "StackUsage" => SystemOperation::StackUsage,
+            "GetRandom" => SystemOperation::GetRandom,
+            "Profile" => SystemOperation::Profile,
+            "GetInfo" => SystemOperation::GetInfo,
+            "IrqCount" => SystemOperation::IrqCount,
+            "Kexec" => SystemOperation::Kexec,
+            "SetPowerPolicy" => SystemOperation::SetPowerPolicy,
             _ => SystemOperation::Unknown,
         }
     }
 }
 
+/// Operations to inject faults in the kernel (for tests, needs a kernel
+/// built with `fault-injection` and `Capabilities::FAULT_INJECTION`).
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u64)]
+pub enum TestOperation {
+    /// Fail the next N physical memory allocations of system calls.
+    FailAllocations = 1,
+    /// Drop the next N packets the NIC receives.
+    DropPackets = 2,
+    /// Delay the next N operations of the kernel's NR combiner.
+    DelayCombine = 3,
+    /// Disarm all faults.
+    Reset = 4,
+    /// The core loses its save area once this call returns, the next system
+    /// call of the caller finds none.
+    LoseSaveArea = 5,
+    /// The next system call on the core is handled as if the core had no
+    /// process.
+    LoseProcess = 6,
+    Unknown,
+}
+
+impl From<u64> for TestOperation {
+    /// Construct a TestOperation enum based on a 64-bit value.
+    fn from(op: u64) -> TestOperation {
+        match op {
+            1 => TestOperation::FailAllocations,
+            2 => TestOperation::DropPackets,
+            3 => TestOperation::DelayCombine,
+            4 => TestOperation::Reset,
+            5 => TestOperation::LoseSaveArea,
+            6 => TestOperation::LoseProcess,
+            _ => TestOperation::Unknown,
+        }
+    }
+}
+
+impl From<&str> for TestOperation {
+    /// Construct a TestOperation enum based on a str.
+    fn from(op: &str) -> TestOperation {
+        match op {
+            "FailAllocations" => TestOperation::FailAllocations,
+            "DropPackets" => TestOperation::DropPackets,
+            "DelayCombine" => TestOperation::DelayCombine,
+            "Reset" => TestOperation::Reset,
+            "LoseSaveArea" => TestOperation::LoseSaveArea,
+            "LoseProcess" => TestOperation::LoseProcess,
+            _ => TestOperation::Unknown,
+        }
+    }
+}
+
+/// Operations on the UDP sockets of a process on the loopback interface
+/// (127.0.0.1, needs a kernel built with `smoltcp`).
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u64)]
+pub enum NetOperation {
+    /// Open a socket bound to a port (an unused one for port 0).
+    UdpBind = 1,
+    /// Send a datagram to an endpoint on the loopback interface.
+    UdpSendTo = 2,
+    /// Take the next received datagram (doesn't block).
+    UdpRecvFrom = 3,
+    /// Close a socket.
+    UdpClose = 4,
+    Unknown,
+}
+
+impl From<u64> for NetOperation {
+    /// Construct a NetOperation enum based on a 64-bit value.
+    fn from(op: u64) -> NetOperation {
+        match op {
+            1 => NetOperation::UdpBind,
+            2 => NetOperation::UdpSendTo,
+            3 => NetOperation::UdpRecvFrom,
+            4 => NetOperation::UdpClose,
+            _ => NetOperation::Unknown,
+        }
+    }
+}
+
+impl From<&str> for NetOperation {
+    /// Construct a NetOperation enum based on a str.
+    fn from(op: &str) -> NetOperation {
+        match op {
+            "UdpBind" => NetOperation::UdpBind,
+            "UdpSendTo" => NetOperation::UdpSendTo,
+            "UdpRecvFrom" => NetOperation::UdpRecvFrom,
+            "UdpClose" => NetOperation::UdpClose,
+            _ => NetOperation::Unknown,
+        }
+    }
+}
+
 /// SystemCall is the type of call we are invoking.
 ///
 /// It is passed to the kernel in the %rdi register.
@@ -300,6 +596,8 @@ pub enum SystemCall {
     Process = 2,
     VSpace = 3,
     FileIO = 4,
+    Test = 5,
+    Net = 6,
     Unknown,
 }
 
@@ -311,6 +609,8 @@ impl SystemCall {
             2 => SystemCall::Process,
             3 => SystemCall::VSpace,
             4 => SystemCall::FileIO,
+            5 => SystemCall::Test,
+            6 => SystemCall::Net,
             _ => SystemCall::Unknown,
         }
     }
@@ -324,6 +624,8 @@ impl From<&str> for SystemCall {
             "Process" => SystemCall::Process,
             "VSpace" => SystemCall::VSpace,
             "FileIO" => SystemCall::FileIO,
+            "Test" => SystemCall::Test,
+            "Net" => SystemCall::Net,
             _ => SystemCall::Unknown,
         }
     }
@@ -0,0 +1,49 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Definitions for the UDP sockets of processes (`SystemCall::Net`).
+
+/// Loopback address the sockets of processes use.
+pub const LOCALHOST: [u8; 4] = [127, 0, 0, 1];
+
+/// Largest datagram a socket sends or receives (in bytes).
+pub const MAX_DATAGRAM: usize = 1472;
+
+/// An IPv4 address and a port.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Endpoint {
+    pub addr: [u8; 4],
+    pub port: u16,
+}
+
+impl Endpoint {
+    pub const fn new(addr: [u8; 4], port: u16) -> Endpoint {
+        Endpoint { addr, port }
+    }
+}
+
+impl From<Endpoint> for u64 {
+    /// Packs the endpoint into one register (the address in bits 16..48, the
+    /// port in bits 0..16).
+    fn from(endpoint: Endpoint) -> u64 {
+        (u64::from(u32::from_be_bytes(endpoint.addr)) << 16) | u64::from(endpoint.port)
+    }
+}
+
+impl From<u64> for Endpoint {
+    /// Unpacks an endpoint packed by `u64::from` (ignores bits 48..64).
+    fn from(raw: u64) -> Endpoint {
+        Endpoint {
+            addr: ((raw >> 16) as u32).to_be_bytes(),
+            port: raw as u16,
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn endpoint_round_trip() {
+    let endpoint = Endpoint::new(LOCALHOST, 7);
+    assert_eq!(u64::from(endpoint), 0x7f00_0001_0007);
+    assert_eq!(Endpoint::from(u64::from(endpoint)), endpoint);
+}
@@ -2,14 +2,23 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use core::convert::TryInto;
+use core::fmt;
 
+use bitflags::*;
 use serde::{Deserialize, Serialize};
 use x86::bits64::paging::PML4_SLOT_SIZE;
 
+use crate::system::GlobalThreadId;
+use crate::{
+    FileOperation, NetOperation, ProcessOperation, SystemCall, SystemCallError, SystemOperation,
+    TestOperation, VSpaceOperation,
+};
+
 /// Max number of cores supported by the process allocator.
 pub const MAX_CORES: usize = 96;
 
-/// Offset in address-space for ELF binary relocation.
+/// Offset in address-space for ELF binary relocation (the kernel moves PIE
+/// binaries up by a random amount from here).
 pub const ELF_OFFSET: usize = 0x20_0000_0000;
 
 /// Memory region space for shared executor region is allocated.
@@ -44,6 +53,511 @@ impl CoreToken {
     }
 }
 
+/// A set of hardware threads (identified by their [`GlobalThreadId`]).
+///
+/// Used to constrain on which cores the kernel may dispatch a process.
+///
+/// # Important
+/// This struct is read from and written to by the kernel during a
+/// `RequestCores` system call, don't change the layout without adjusting
+/// the kernel.
+#[repr(C)]
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct AffinityMask {
+    bits: [u64; AffinityMask::WORDS],
+}
+
+impl AffinityMask {
+    /// How many 64-bit words we use to represent the mask.
+    const WORDS: usize = 4;
+
+    /// Max. number of hardware threads that can be represented by the mask.
+    pub const MAX_THREADS: usize = AffinityMask::WORDS * 64;
+
+    /// A mask that doesn't contain any hardware thread.
+    pub const fn empty() -> AffinityMask {
+        AffinityMask {
+            bits: [0; AffinityMask::WORDS],
+        }
+    }
+
+    /// A mask that contains every hardware thread.
+    pub const fn all() -> AffinityMask {
+        AffinityMask {
+            bits: [u64::MAX; AffinityMask::WORDS],
+        }
+    }
+
+    /// Add `gtid` to the mask.
+    pub fn set(&mut self, gtid: GlobalThreadId) {
+        assert!(gtid < AffinityMask::MAX_THREADS, "gtid out of range");
+        self.bits[gtid / 64] |= 1 << (gtid % 64);
+    }
+
+    /// Remove `gtid` from the mask.
+    pub fn clear(&mut self, gtid: GlobalThreadId) {
+        assert!(gtid < AffinityMask::MAX_THREADS, "gtid out of range");
+        self.bits[gtid / 64] &= !(1 << (gtid % 64));
+    }
+
+    /// Is `gtid` part of the mask?
+    pub fn contains(&self, gtid: GlobalThreadId) -> bool {
+        gtid < AffinityMask::MAX_THREADS && self.bits[gtid / 64] & (1 << (gtid % 64)) > 0
+    }
+
+    /// Number of hardware threads in the mask.
+    pub fn count(&self) -> usize {
+        self.bits.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count() == 0
+    }
+
+    /// Iterate over all hardware threads in the mask (in ascending order).
+    pub fn iter(&self) -> impl Iterator<Item = GlobalThreadId> + '_ {
+        (0..AffinityMask::MAX_THREADS).filter(move |gtid| self.contains(*gtid))
+    }
+}
+
+bitflags! {
+    /// Scheduling hints passed along with a `RequestCores` system call.
+    pub struct CoreRequestFlags: u64 {
+        const NONE = 0x0;
+        /// Gang-schedule: all requested cores have to be allocated at once
+        /// (and start running at the same time) or none at all.
+        const GANG = 0x1;
+    }
+}
+
+/// Convert u64 to CoreRequestFlags.
+impl From<u64> for CoreRequestFlags {
+    fn from(flags: u64) -> CoreRequestFlags {
+        CoreRequestFlags::from_bits_truncate(flags)
+    }
+}
+
+bitflags! {
+    /// Access rights and caching attributes of memory mapped with the
+    /// `VSpace` system calls.
+    ///
+    /// At most one of `NOCACHE`, `WRITE_THROUGH` and `WRITE_COMBINE` can be
+    /// set (only to map device memory), without any of them memory is cached
+    /// (write-back).
+    pub struct MapFlags: u64 {
+        const READ = 0x1;
+        const WRITE = 0x2;
+        const EXECUTE = 0x4;
+        /// Accessible from user-space (required for all mappings of a process).
+        const USER = 0x8;
+        /// Uncached, e.g., for device registers.
+        const NOCACHE = 0x10;
+        /// Write-through caching.
+        const WRITE_THROUGH = 0x20;
+        /// Write-combining, e.g., for frame buffers.
+        const WRITE_COMBINE = 0x40;
+        /// What `VSpace::map` and `VSpace::map_device` use.
+        const READ_WRITE_USER = Self::READ.bits | Self::WRITE.bits | Self::USER.bits;
+    }
+}
+
+/// Convert u64 to MapFlags.
+impl From<u64> for MapFlags {
+    fn from(flags: u64) -> MapFlags {
+        MapFlags::from_bits_truncate(flags)
+    }
+}
+
+/// A range of the address-space that is mapped with pages of the same size
+/// and flags to physically contiguous memory.
+///
+/// # Important
+/// This struct is written to user-space by the kernel in a `VSpace(Dump)`
+/// system call, don't change the layout without adjusting the kernel.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct MappingRange {
+    /// First virtual address of the range.
+    pub start: u64,
+    /// First virtual address after the range.
+    pub end: u64,
+    /// Physical address `start` is mapped to (0 without the
+    /// [`Capabilities::INSPECT`] capability).
+    pub paddr: u64,
+    /// Size of the pages in the range (4 KiB, 2 MiB or 1 GiB).
+    pub page_size: u64,
+    /// Access rights and caching attribute (see [`MapFlags`]).
+    pub flags: u64,
+}
+
+impl MappingRange {
+    /// Size of the range in bytes.
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn flags(&self) -> MapFlags {
+        MapFlags::from_bits_truncate(self.flags)
+    }
+}
+
+bitflags! {
+    /// Privileges of a process beyond what every process is allowed to do.
+    pub struct Capabilities: u64 {
+        const NONE = 0x0;
+        /// Read (allow-listed) model-specific registers and CPUID leaves,
+        /// e.g., for energy and frequency measurements.
+        const MEASURE = 0x1;
+        /// Inject faults in the kernel (`SystemCall::Test`).
+        const FAULT_INJECTION = 0x2;
+        /// Replace services with a new binary (`Process::reload`).
+        const RELOAD = 0x4;
+        /// Drive devices from user-space (`Process::claim_device`).
+        const DEVICES = 0x8;
+        /// Soft-reboot the machine into a new kernel (`System::kexec`).
+        const KEXEC = 0x10;
+        /// Set the frequency and idle states of the cores
+        /// (`System::set_power_policy`).
+        const POWER = 0x20;
+        /// Save processes to files and start processes from them
+        /// (`Process::checkpoint` and `Process::restore`).
+        const CHECKPOINT = 0x40;
+        /// Change system-wide tunables (`System::set_net_moderation`).
+        const TUNE = 0x80;
+        /// Supervise the OOM killer (`Process::wait_event` with
+        /// `Event::OutOfMemory`), the OOM killer never picks the supervisor.
+        const SUPERVISE = 0x100;
+        /// See the physical addresses of its own mappings (`VSpace::dump`).
+        const INSPECT = 0x200;
+    }
+}
+
+impl Capabilities {
+    /// Parses a comma separated list of capability names (e.g., `measure`),
+    /// unknown names are ignored.
+    pub fn from_names(names: &str) -> Capabilities {
+        names
+            .split(',')
+            .fold(Capabilities::NONE, |caps, name| match name {
+                "measure" => caps | Capabilities::MEASURE,
+                "fault-injection" => caps | Capabilities::FAULT_INJECTION,
+                "reload" => caps | Capabilities::RELOAD,
+                "devices" => caps | Capabilities::DEVICES,
+                "kexec" => caps | Capabilities::KEXEC,
+                "power" => caps | Capabilities::POWER,
+                "checkpoint" => caps | Capabilities::CHECKPOINT,
+                "tune" => caps | Capabilities::TUNE,
+                "supervise" => caps | Capabilities::SUPERVISE,
+                "inspect" => caps | Capabilities::INSPECT,
+                _ => caps,
+            })
+    }
+}
+
+/// A system call of a traced process (see `Process::trace`).
+///
+/// # Important
+/// This struct is written to user-space by the kernel in a `ReadTrace`
+/// system call, don't change the layout without adjusting the kernel.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct SyscallRecord {
+    /// The system call domain (a [`SystemCall`] value).
+    pub function: u64,
+    /// The operation within the domain (e.g., a [`ProcessOperation`] value).
+    pub operation: u64,
+    /// The arguments after the operation.
+    pub args: [u64; 4],
+    /// The error code (a [`SystemCallError`] value, 0 on success).
+    pub error: u64,
+    /// The two values the system call returned (if it succeeded).
+    pub ret: [u64; 2],
+    /// Core the system call ran on.
+    pub core: u64,
+}
+
+impl SyscallRecord {
+    /// Formats the call without its result, like
+    /// `VSpace::Map(0x1000, 0x2000, 0xb, 0x0)`.
+    pub fn call(&self) -> impl fmt::Display + '_ {
+        struct Call<'a>(&'a SyscallRecord);
+
+        impl fmt::Display for Call<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                let (call, op) = (SystemCall::new(self.0.function), self.0.operation);
+                write!(f, "{:?}::", call)?;
+                match call {
+                    SystemCall::System => write!(f, "{:?}", SystemOperation::from(op))?,
+                    SystemCall::Process => write!(f, "{:?}", ProcessOperation::from(op))?,
+                    SystemCall::VSpace => write!(f, "{:?}", VSpaceOperation::from(op))?,
+                    SystemCall::FileIO => write!(f, "{:?}", FileOperation::from(op))?,
+                    SystemCall::Test => write!(f, "{:?}", TestOperation::from(op))?,
+                    SystemCall::Net => write!(f, "{:?}", NetOperation::from(op))?,
+                    SystemCall::Unknown => write!(f, "{}", op)?,
+                }
+                let args = &self.0.args;
+                write!(
+                    f,
+                    "({:#x}, {:#x}, {:#x}, {:#x})",
+                    args[0], args[1], args[2], args[3]
+                )
+            }
+        }
+
+        Call(self)
+    }
+}
+
+impl fmt::Display for SyscallRecord {
+    /// Formats the call followed by its result.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.call())?;
+        match self.error {
+            0 => write!(f, " = Ok({:#x}, {:#x})", self.ret[0], self.ret[1]),
+            e => write!(f, " = Err({:?})", SystemCallError::from(e)),
+        }
+    }
+}
+
+/// Exit code of a process that was terminated with `Process::kill` (or by
+/// the OOM killer).
+pub const KILLED: u64 = 137;
+
+/// Exit code of a (child) process that crashed.
+pub const CRASHED: u64 = 139;
+
+/// A spawned child can inherit the file descriptors below this (see
+/// `Process::spawn_with_fds`).
+pub const INHERITABLE_FDS: u64 = 64;
+
+/// How important a process is when the kernel runs out of memory and has to
+/// kill one (see `Process::set_oom_priority`).
+///
+/// With `oom=prio` on the kernel command-line the least important process is
+/// killed first.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
+#[repr(u64)]
+pub enum OomPriority {
+    /// Killed before anything else (e.g., caches that can be rebuilt).
+    Expendable = 0,
+    Normal = 1,
+    /// Killed only if there is nothing else.
+    Important = 2,
+}
+
+impl OomPriority {
+    /// All priorities (indexed by their value).
+    pub const ALL: [OomPriority; 3] = [
+        OomPriority::Expendable,
+        OomPriority::Normal,
+        OomPriority::Important,
+    ];
+}
+
+impl Default for OomPriority {
+    fn default() -> OomPriority {
+        OomPriority::Normal
+    }
+}
+
+/// Kernel events a process can wait for (`Process::wait_event`).
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u64)]
+pub enum Event {
+    /// The kernel ran out of memory and killed a process, the event carries
+    /// its pid.
+    ///
+    /// Only one process (the *supervisor*, it needs the `supervise`
+    /// capability) can subscribe to it, the OOM killer never picks the
+    /// supervisor.
+    OutOfMemory = 1,
+    /// A key was pressed or released, the event carries a [`KeyEvent`]
+    /// (`KeyEvent::from(value)`).
+    ///
+    /// Only one process (the one with the *focus*) can subscribe to it, the
+    /// first one that does keeps it until it exits.
+    Keyboard = 2,
+}
+
+bitflags! {
+    /// Modifier keys that were held down (or locked) during a [`KeyEvent`].
+    pub struct Modifiers: u8 {
+        const SHIFT = 0x1;
+        const CTRL = 0x2;
+        const ALT = 0x4;
+        const CAPS_LOCK = 0x8;
+    }
+}
+
+/// A key press or release (see [`Event::Keyboard`]).
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct KeyEvent {
+    /// Scancode (set 1) of the key, `0xe0xx` for extended keys (e.g., the
+    /// cursor keys).
+    pub scancode: u16,
+    pub pressed: bool,
+    pub modifiers: Modifiers,
+    /// The character a press produces (US layout, with the modifiers
+    /// applied, e.g., `'\x03'` for Ctrl+C), if any.
+    pub character: Option<char>,
+}
+
+/// How a `KeyEvent` is passed in a register.
+impl From<KeyEvent> for u64 {
+    fn from(key: KeyEvent) -> u64 {
+        key.scancode as u64
+            | (key.pressed as u64) << 16
+            | (key.modifiers.bits() as u64) << 24
+            | key.character.map_or(0, |c| c as u64) << 32
+    }
+}
+
+impl From<u64> for KeyEvent {
+    fn from(value: u64) -> KeyEvent {
+        KeyEvent {
+            scancode: value as u16,
+            pressed: (value >> 16) & 0x1 == 1,
+            modifiers: Modifiers::from_bits_truncate((value >> 24) as u8),
+            character: core::char::from_u32((value >> 32) as u32).filter(|c| *c != '\0'),
+        }
+    }
+}
+
+/// Convert u64 to Capabilities.
+impl From<u64> for Capabilities {
+    fn from(caps: u64) -> Capabilities {
+        Capabilities::from_bits_truncate(caps)
+    }
+}
+
+/// Scheduling classes an executor (a process' thread on a core) can be in.
+///
+/// The threads of lineup (the user-level scheduler) use the same classes for
+/// their run-queues.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u64)]
+pub enum SchedulingClass {
+    /// Round-robin, the core takes part in the kernel's periodic
+    /// housekeeping (e.g., advancing replicas).
+    Normal = 0,
+    /// Latency sensitive: kernel housekeeping on the core is kept to a
+    /// minimum and bounded by an (optional) deadline hint.
+    LowLatency = 1,
+    Unknown,
+}
+
+impl Default for SchedulingClass {
+    fn default() -> SchedulingClass {
+        SchedulingClass::Normal
+    }
+}
+
+impl From<u64> for SchedulingClass {
+    /// Construct a SchedulingClass enum based on a 64-bit value.
+    fn from(class: u64) -> SchedulingClass {
+        match class {
+            0 => SchedulingClass::Normal,
+            1 => SchedulingClass::LowLatency,
+            _ => SchedulingClass::Unknown,
+        }
+    }
+}
+
+/// Severity of a message a process logs (see `Process::log`).
+///
+/// Every process also has a log level: the kernel discards messages that are
+/// less severe (have a higher value) than it.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
+#[repr(u64)]
+pub enum LogLevel {
+    /// Plain console output (`Process::print`), it passes every log level.
+    /// As a process' log level it discards all other messages.
+    Print = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl LogLevel {
+    /// All log levels (indexed by their value).
+    pub const ALL: [LogLevel; 6] = [
+        LogLevel::Print,
+        LogLevel::Error,
+        LogLevel::Warn,
+        LogLevel::Info,
+        LogLevel::Debug,
+        LogLevel::Trace,
+    ];
+
+    /// Parses a log level name (`off` for [`LogLevel::Print`], `error`,
+    /// `warn`, `info`, `debug` or `trace`).
+    pub fn from_name(name: &str) -> Option<LogLevel> {
+        match name {
+            "off" => Some(LogLevel::Print),
+            "error" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            "trace" => Some(LogLevel::Trace),
+            _ => None,
+        }
+    }
+
+    /// Name of the level in log messages (same as the `log` crate).
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Print => "PRINT",
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Trace => "TRACE",
+        }
+    }
+}
+
+impl From<log::Level> for LogLevel {
+    fn from(level: log::Level) -> LogLevel {
+        match level {
+            log::Level::Error => LogLevel::Error,
+            log::Level::Warn => LogLevel::Warn,
+            log::Level::Info => LogLevel::Info,
+            log::Level::Debug => LogLevel::Debug,
+            log::Level::Trace => LogLevel::Trace,
+        }
+    }
+}
+
+/// CPU time used (similar to `struct rusage`).
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct CpuTime {
+    /// Time spent in user space (in microseconds).
+    pub user_us: u64,
+    /// Time the kernel spent on behalf of the process (in microseconds).
+    pub kernel_us: u64,
+}
+
+/// Memory use of a process (in bytes).
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct MemoryStats {
+    /// Virtual size: all of the address-space that is mapped, evicted or
+    /// reserved.
+    pub virtual_size: u64,
+    /// Resident size: memory that is mapped (the sum of the three below).
+    pub resident: u64,
+    /// Resident memory mapped with base (4 KiB), large (2 MiB) and huge
+    /// (1 GiB) pages.
+    pub base_pages: u64,
+    pub large_pages: u64,
+    pub huge_pages: u64,
+    /// Anonymous memory that was evicted (or is being evicted).
+    pub evicted: u64,
+    /// Reserved memory that wasn't committed (touched) yet.
+    pub reserved: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, Eq, PartialEq)]
 pub struct ProcessInfo {
     pub has_tls: bool,
@@ -60,6 +574,16 @@ pub struct ProcessInfo {
     /// App specific command line argument, for example: benchmarks, reads,
     /// value_size for leveldb (passed to the rump init function).
     pub app_cmdline: &'static str,
+    /// Id of the process.
+    pub pid: u64,
+    /// Distinguishes the process from earlier processes with the same pid.
+    pub generation: u64,
+    /// CPU time used by the process (all its cores) so far.
+    pub cpu_time: CpuTime,
+    /// CPU time used by the calling core (the executor on it) so far.
+    pub thread_cpu_time: CpuTime,
+    /// Memory the process uses.
+    pub memory: MemoryStats,
 }
 
 #[cfg(test)]
@@ -76,6 +600,22 @@ fn serialize() {
         alignment: 3,
         cmdline: "test",
         app_cmdline: "app_cmdline",
+        pid: 1,
+        generation: 7,
+        cpu_time: CpuTime {
+            user_us: 1000,
+            kernel_us: 10,
+        },
+        thread_cpu_time: Default::default(),
+        memory: MemoryStats {
+            virtual_size: 8192,
+            resident: 4096,
+            base_pages: 4096,
+            large_pages: 0,
+            huge_pages: 0,
+            evicted: 0,
+            reserved: 4096,
+        },
     };
 
     let serialized: &'static [u8] = Vec::leak(serde_cbor::to_vec(&point).unwrap());
@@ -83,3 +623,72 @@ fn serialize() {
     log::info!("serialized.len = {}", serialized.len());
     log::info!("deserialized = {:?}", deserialized);
 }
+
+#[cfg(test)]
+#[test]
+fn affinity_mask() {
+    let mut mask = AffinityMask::empty();
+    assert!(mask.is_empty());
+
+    mask.set(0);
+    mask.set(63);
+    mask.set(64);
+    mask.set(AffinityMask::MAX_THREADS - 1);
+    assert_eq!(mask.count(), 4);
+    assert!(mask.contains(63) && mask.contains(64));
+    assert!(!mask.contains(1));
+    assert!(!mask.contains(AffinityMask::MAX_THREADS));
+
+    mask.clear(63);
+    let gtids: alloc::vec::Vec<GlobalThreadId> = mask.iter().collect();
+    assert_eq!(gtids, [0, 64, AffinityMask::MAX_THREADS - 1]);
+    assert_eq!(AffinityMask::all().count(), AffinityMask::MAX_THREADS);
+}
+
+#[cfg(test)]
+#[test]
+fn syscall_record() {
+    use alloc::format;
+
+    let record = SyscallRecord {
+        function: SystemCall::VSpace as u64,
+        operation: VSpaceOperation::Map as u64,
+        args: [0x1000, 0x2000, MapFlags::READ_WRITE_USER.bits(), 0],
+        error: 0,
+        ret: [0x1000, 0x2000],
+        core: 1,
+    };
+    assert_eq!(
+        format!("{}", record),
+        "VSpace::Map(0x1000, 0x2000, 0xb, 0x0) = Ok(0x1000, 0x2000)"
+    );
+
+    let record = SyscallRecord {
+        error: SystemCallError::BadAddress as u64,
+        ..record
+    };
+    assert_eq!(
+        format!("{}", record),
+        "VSpace::Map(0x1000, 0x2000, 0xb, 0x0) = Err(BadAddress)"
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn key_event() {
+    let key = KeyEvent {
+        scancode: 0xe048,
+        pressed: true,
+        modifiers: Modifiers::SHIFT | Modifiers::CAPS_LOCK,
+        character: None,
+    };
+    assert_eq!(KeyEvent::from(u64::from(key)), key);
+
+    let key = KeyEvent {
+        scancode: 0x2e,
+        modifiers: Modifiers::CTRL,
+        character: Some('\x03'),
+        ..key
+    };
+    assert_eq!(KeyEvent::from(u64::from(key)), key);
+}
@@ -0,0 +1,250 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Argument signatures of the system calls.
+//!
+//! Every system call is described exactly once, in `syscall_table!`. The
+//! table is expanded into the [`signature`] lookup the kernel uses to
+//! validate arguments (see [`crate::decode::arguments`]) and into the raw
+//! stubs the user-space wrappers in `syscalls` are built on. Adding an
+//! operation to the table updates both sides.
+
+use crate::decode::Operation;
+use crate::*;
+
+/// What a system call argument is used for.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Arg {
+    /// A plain value, not interpreted by the decoder.
+    Value,
+    /// A user-space address, if the next argument is a [`Arg::Length`] the
+    /// pair describes a buffer.
+    Address,
+    /// The size of a buffer in bytes.
+    Length,
+    /// A set of flags (checked by the handler of the operation).
+    Flags,
+    /// A file descriptor.
+    Fd,
+}
+
+/// The arguments (passed after the operation in %rdx, %r10, %r8, %r9)
+/// and the number of return values (including the error code) of a
+/// system call.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Signature {
+    pub args: &'static [Arg],
+    pub returns: usize,
+}
+
+/// The system call table.
+///
+/// Invokes `$m!` with one entry per system call in the form
+/// `Domain(Operation::Name) fn stub_name(arg: Kind, ...) -> returns;`.
+macro_rules! syscall_table {
+    ($m:ident) => {
+        $m! {
+            System(SystemOperation::GetHardwareThreads)
+                fn system_get_hardware_threads(buf: Address, len: Length) -> 2;
+            System(SystemOperation::Stats)
+                fn system_stats() -> 1;
+            System(SystemOperation::GetCoreID)
+                fn system_get_core_id() -> 2;
+            System(SystemOperation::MigrateCore)
+                fn system_migrate_core(core_id: Value) -> 1;
+            System(SystemOperation::ReadMsr)
+                fn system_read_msr(msr: Value) -> 2;
+            System(SystemOperation::Cpuid)
+                fn system_cpuid(leaf: Value, subleaf: Value) -> 3;
+            System(SystemOperation::NetModeration)
+                fn system_net_moderation(poll_threshold: Value, idle_polls: Value) -> 3;
+            System(SystemOperation::StackUsage)
+                fn system_stack_usage(core: Value, stack: Value) -> 3;
+            System(SystemOperation::GetRandom)
+                fn system_get_random(buf: Address, len: Length) -> 1;
+            System(SystemOperation::Profile)
+                fn system_profile(command: Value, period: Value) -> 1;
+            System(SystemOperation::GetInfo)
+                fn system_get_info(buf: Address, len: Length, kind: Value) -> 2;
+            System(SystemOperation::IrqCount)
+                fn system_irq_count(core: Value, vector: Value) -> 2;
+            System(SystemOperation::Kexec)
+                fn system_kexec(binary: Address, len: Length, cmdline: Address, cmd_len: Length) -> 1;
+            System(SystemOperation::SetPowerPolicy)
+                fn system_set_power_policy(governor: Value, ratio: Value, idle: Value) -> 1;
+
+            Process(ProcessOperation::Exit)
+                fn process_exit(code: Value) -> 1;
+            Process(ProcessOperation::Log)
+                fn process_log(buf: Address, len: Length, target: Address, meta: Value) -> 1;
+            Process(ProcessOperation::GetVCpuArea)
+                fn process_get_vcpu_area() -> 2;
+            Process(ProcessOperation::AllocateVector)
+                fn process_allocate_vector(vector: Value, core: Value) -> 3;
+            Process(ProcessOperation::SubscribeEvent)
+                fn process_subscribe_event(event: Value, timeout: Value) -> 3;
+            Process(ProcessOperation::GetProcessInfo)
+                fn process_get_process_info(buf: Address, len: Length) -> 2;
+            Process(ProcessOperation::RequestCore)
+                fn process_request_core(core_id: Value, entry_point: Address) -> 3;
+            Process(ProcessOperation::AllocatePhysical)
+                fn process_allocate_physical(page_size: Value) -> 3;
+            Process(ProcessOperation::RequestCores)
+                fn process_request_cores(
+                    mask: Address,
+                    count: Value,
+                    flags: Flags,
+                    entry_point: Address
+                ) -> 2;
+            Process(ProcessOperation::SetSchedulingClass)
+                fn process_set_scheduling_class(class: Value, deadline: Value) -> 1;
+            Process(ProcessOperation::Checkpoint)
+                fn process_checkpoint(pathname: Address) -> 3;
+            Process(ProcessOperation::Restore)
+                fn process_restore(pathname: Address) -> 2;
+            Process(ProcessOperation::SetLogLevel)
+                fn process_set_log_level(level: Value) -> 1;
+            Process(ProcessOperation::BarrierWait)
+                fn process_barrier_wait(word: Address, expected: Value, timeout: Value) -> 3;
+            Process(ProcessOperation::DeliverUpcall)
+                fn process_deliver_upcall() -> 1;
+            Process(ProcessOperation::Spawn)
+                fn process_spawn(
+                    cmdline: Address,
+                    len: Length,
+                    capabilities: Flags,
+                    inherit: Value
+                ) -> 2;
+            Process(ProcessOperation::WaitPid)
+                fn process_wait_pid(pid: Value, timeout: Value) -> 3;
+            Process(ProcessOperation::Kill)
+                fn process_kill(pid: Value) -> 1;
+            Process(ProcessOperation::Trace)
+                fn process_trace(pid: Value, enable: Value) -> 1;
+            Process(ProcessOperation::ReadTrace)
+                fn process_read_trace(pid: Value, buf: Address, len: Length) -> 2;
+            Process(ProcessOperation::SetCoreDump)
+                fn process_set_core_dump(enable: Value) -> 1;
+            Process(ProcessOperation::Sleep)
+                fn process_sleep(ns: Value) -> 2;
+            Process(ProcessOperation::SetOomPriority)
+                fn process_set_oom_priority(pid: Value, priority: Value) -> 1;
+            Process(ProcessOperation::Reload)
+                fn process_reload(pid: Value, binary: Address, len: Length) -> 2;
+            Process(ProcessOperation::ClaimDevice)
+                fn process_claim_device(id: Value) -> 1;
+            Process(ProcessOperation::SetFdLimit)
+                fn process_set_fd_limit(limit: Value) -> 1;
+            Process(ProcessOperation::FutexWait)
+                fn process_futex_wait(word: Address, expected: Value, timeout: Value) -> 3;
+            Process(ProcessOperation::FutexWake)
+                fn process_futex_wake(word: Address, count: Value) -> 2;
+            Process(ProcessOperation::MaskVector)
+                fn process_mask_vector(vector: Value, masked: Value) -> 1;
+
+            VSpace(VSpaceOperation::Map)
+                fn vspace_map(base: Address, size: Length, flags: Flags) -> 3;
+            VSpace(VSpaceOperation::Unmap)
+                fn vspace_unmap(base: Address, size: Length) -> 3;
+            VSpace(VSpaceOperation::MapDevice)
+                fn vspace_map_device(paddr: Value, size: Length, flags: Flags) -> 3;
+            VSpace(VSpaceOperation::MapFrame)
+                fn vspace_map_frame(base: Address, frame_id: Value) -> 3;
+            VSpace(VSpaceOperation::Identify)
+                fn vspace_identify(base: Address, size: Length) -> 3;
+            VSpace(VSpaceOperation::Reserve)
+                fn vspace_reserve(base: Address, size: Length, flags: Flags) -> 3;
+            VSpace(VSpaceOperation::Commit)
+                fn vspace_commit(base: Address, size: Length) -> 3;
+            VSpace(VSpaceOperation::Dump)
+                fn vspace_dump(buf: Address, len: Length, console: Value) -> 2;
+            VSpace(VSpaceOperation::Unreserve)
+                fn vspace_unreserve(base: Address) -> 1;
+
+            FileIO(FileOperation::Open)
+                fn file_open(pathname: Address, flags: Flags, modes: Flags) -> 2;
+            FileIO(FileOperation::Read)
+                fn file_read(fd: Fd, buf: Address, len: Length) -> 2;
+            FileIO(FileOperation::ReadAt)
+                fn file_read_at(fd: Fd, buf: Address, len: Length, offset: Value) -> 2;
+            FileIO(FileOperation::Write)
+                fn file_write(fd: Fd, buf: Address, len: Length) -> 2;
+            FileIO(FileOperation::WriteAt)
+                fn file_write_at(fd: Fd, buf: Address, len: Length, offset: Value) -> 2;
+            FileIO(FileOperation::Close)
+                fn file_close(fd: Fd) -> 1;
+            FileIO(FileOperation::GetInfo)
+                fn file_get_info(name: Address, info: Address) -> 1;
+            FileIO(FileOperation::Delete)
+                fn file_delete(name: Address) -> 2;
+            FileIO(FileOperation::WriteDirect)
+                fn file_write_direct(
+                    buf: Address,
+                    len: Length,
+                    offset: Value,
+                    is_offset: Value
+                ) -> 2;
+            FileIO(FileOperation::FileRename)
+                fn file_rename(old_name: Address, new_name: Address) -> 1;
+            FileIO(FileOperation::MkDir)
+                fn file_mkdir(pathname: Address, modes: Flags) -> 1;
+            FileIO(FileOperation::Poll)
+                fn file_poll(fds: Address, len: Length, timeout: Value) -> 3;
+            FileIO(FileOperation::RingEnter)
+                fn file_ring_enter(ring: Address, len: Length) -> 2;
+            FileIO(FileOperation::Mount)
+                fn file_mount(pathname: Address, kind: Flags, limit: Value) -> 1;
+            FileIO(FileOperation::Fsync)
+                fn file_fsync(fd: Fd) -> 1;
+            FileIO(FileOperation::ReadV)
+                fn file_readv(fd: Fd, iov: Address, len: Length) -> 2;
+            FileIO(FileOperation::WriteV)
+                fn file_writev(fd: Fd, iov: Address, len: Length) -> 2;
+            FileIO(FileOperation::Symlink)
+                fn file_symlink(target: Address, linkpath: Address) -> 1;
+            FileIO(FileOperation::ReadLink)
+                fn file_readlink(pathname: Address, buf: Address, len: Length) -> 2;
+
+            Test(TestOperation::FailAllocations)
+                fn test_fail_allocations(count: Value) -> 1;
+            Test(TestOperation::DropPackets)
+                fn test_drop_packets(count: Value) -> 1;
+            Test(TestOperation::DelayCombine)
+                fn test_delay_combine(count: Value, cycles: Value) -> 1;
+            Test(TestOperation::Reset)
+                fn test_reset() -> 1;
+            Test(TestOperation::LoseSaveArea)
+                fn test_lose_save_area() -> 1;
+            Test(TestOperation::LoseProcess)
+                fn test_lose_process() -> 1;
+
+            Net(NetOperation::UdpBind)
+                fn net_udp_bind(port: Value) -> 2;
+            Net(NetOperation::UdpSendTo)
+                fn net_udp_send_to(socket: Value, buf: Address, len: Length, endpoint: Value) -> 2;
+            Net(NetOperation::UdpRecvFrom)
+                fn net_udp_recv_from(socket: Value, buf: Address, len: Length) -> 3;
+            Net(NetOperation::UdpClose)
+                fn net_udp_close(socket: Value) -> 1;
+        }
+    };
+}
+
+macro_rules! signatures {
+    ($($call:ident($ops:ident::$op:ident)
+        fn $name:ident($($arg:ident: $kind:ident),*) -> $ret:tt;)*) => {
+        /// Returns the signature of `op` or `None` if user-space can't
+        /// invoke the operation.
+        pub fn signature(op: Operation) -> Option<Signature> {
+            match op {
+                $(Operation::$call($ops::$op) => Some(Signature {
+                    args: &[$(Arg::$kind),*],
+                    returns: $ret,
+                }),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+syscall_table!(signatures);
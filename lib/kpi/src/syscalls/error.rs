@@ -0,0 +1,50 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! The error of the last failed system call of a thread.
+//!
+//! Every wrapper returns a `Result<T, SystemCallError>`, but C code (and the
+//! compatibility shims that emulate it) expect an `errno` that outlives the
+//! call. The raw stubs record the error code of every failed system call in
+//! a thread-local slot, successful calls leave it as it is (like `errno`).
+
+use crate::SystemCallError;
+
+#[cfg(target_os = "nrk")]
+#[thread_local]
+static LAST_ERROR: core::cell::Cell<u64> = core::cell::Cell::new(0);
+
+/// Do we have a TLS area yet? The runtime sets it up with system calls, so
+/// the first few of them run without one.
+#[cfg(target_os = "nrk")]
+fn has_tls() -> bool {
+    unsafe { x86::bits64::segmentation::rdfsbase() != 0 }
+}
+
+/// Remembers the error code `err` (if it's one) for [`last_error`].
+#[inline(always)]
+pub(crate) fn record(err: u64) {
+    #[cfg(target_os = "nrk")]
+    if err != 0 && has_tls() {
+        LAST_ERROR.set(err);
+    }
+    #[cfg(not(target_os = "nrk"))]
+    let _ = err;
+}
+
+/// The error of the last system call of the current thread that failed.
+pub fn last_error() -> Option<SystemCallError> {
+    #[cfg(target_os = "nrk")]
+    if has_tls() && LAST_ERROR.get() != 0 {
+        return Some(SystemCallError::from(LAST_ERROR.get()));
+    }
+    None
+}
+
+/// Forgets the error of the last failed system call of the current thread.
+pub fn clear_last_error() {
+    #[cfg(target_os = "nrk")]
+    if has_tls() {
+        LAST_ERROR.set(0);
+    }
+}
@@ -3,26 +3,26 @@
 
 //! Abstraction for system calls to access the global file-system and control interrupts.
 
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::AtomicU32;
+use core::time::Duration;
+
 use crate::io::*;
 use crate::*;
 
-use crate::syscall;
+use super::raw;
 
 /// System calls related to interrupt routing.
 pub struct Irq;
 
 impl Irq {
-    /// Manipulate the CPU interrupt alloction table.
+    /// Routes the interrupt `vec` (a GSI, e.g., the interrupt line of a
+    /// PCI device) to `core`, it arrives with vector `vec + 32`.
+    ///
+    /// The route goes away when the process exits.
     pub fn irqalloc(vec: u64, core: u64) -> Result<(), SystemCallError> {
-        let (r, retvec, retcore) = unsafe {
-            syscall!(
-                SystemCall::Process as u64,
-                ProcessOperation::AllocateVector as u64,
-                vec,
-                core,
-                3
-            )
-        };
+        let (r, retvec, retcore) = unsafe { raw::process_allocate_vector(vec, core) };
 
         assert_eq!(vec, retvec);
         assert_eq!(core, retcore);
@@ -33,6 +33,27 @@ impl Irq {
             Err(SystemCallError::from(r))
         }
     }
+
+    /// Stops delivering the interrupt `vec` (a GSI routed to the process
+    /// with [`Irq::irqalloc`]) until [`Irq::unmask`] is called, e.g., while
+    /// a driver polls its device.
+    pub fn mask(vec: u64) -> Result<(), SystemCallError> {
+        Irq::set_masked(vec, true)
+    }
+
+    /// Delivers the interrupt `vec` again after [`Irq::mask`].
+    pub fn unmask(vec: u64) -> Result<(), SystemCallError> {
+        Irq::set_masked(vec, false)
+    }
+
+    fn set_masked(vec: u64, masked: bool) -> Result<(), SystemCallError> {
+        let r = unsafe { raw::process_mask_vector(vec, masked as u64) };
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
 }
 
 /// System calls related to file-systems.
@@ -48,16 +69,7 @@ impl Fs {
 
     /// Open a file. Return `fd` if successful; error otherwise.
     pub fn open(pathname: u64, flags: u64, modes: u64) -> Result<u64, SystemCallError> {
-        let (r, fd) = unsafe {
-            syscall!(
-                SystemCall::FileIO as u64,
-                FileOperation::Open as u64,
-                pathname,
-                flags,
-                modes,
-                2
-            )
-        };
+        let (r, fd) = unsafe { raw::file_open(pathname, flags, modes) };
 
         if r == 0 {
             Ok(fd)
@@ -69,7 +81,7 @@ impl Fs {
     /// Close a file. This function will remove the file descriptor from the process.
     /// It doesn't do anything to the file.
     pub fn close(fd: u64) -> Result<u64, SystemCallError> {
-        let r = unsafe { syscall!(SystemCall::FileIO as u64, FileOperation::Close, fd, 1) };
+        let r = unsafe { raw::file_close(fd) };
 
         if r == 0 {
             Ok(r)
@@ -86,14 +98,49 @@ impl Fs {
         Fs::fileio(FileOperation::Write, fd, buffer, len)
     }
 
+    /// Read from the current offset of `fd` into the buffers of `iov` (in
+    /// order), returns the number of bytes read.
+    pub fn readv(fd: u64, iov: &[IoVec]) -> Result<u64, SystemCallError> {
+        Fs::fileio_vectored(FileOperation::ReadV, fd, iov)
+    }
+
+    /// Write the buffers of `iov` (in order) at the current offset of `fd`,
+    /// returns the number of bytes written.
+    pub fn writev(fd: u64, iov: &[IoVec]) -> Result<u64, SystemCallError> {
+        Fs::fileio_vectored(FileOperation::WriteV, fd, iov)
+    }
+
+    fn fileio_vectored(op: FileOperation, fd: u64, iov: &[IoVec]) -> Result<u64, SystemCallError> {
+        let base = iov.as_ptr() as u64;
+        let len = (iov.len() * core::mem::size_of::<IoVec>()) as u64;
+        let (r, len) = unsafe {
+            match op {
+                FileOperation::ReadV => raw::file_readv(fd, base, len),
+                FileOperation::WriteV => raw::file_writev(fd, base, len),
+                _ => unreachable!("fileio_vectored received unexpected op"),
+            }
+        };
+
+        if r == 0 {
+            Ok(len)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
     /// Read or write an opened file. `fd` is the file descriptor for the opened file.
     fn fileio(op: FileOperation, fd: u64, buffer: u64, len: u64) -> Result<u64, SystemCallError> {
         if len == 0 {
             return Err(SystemCallError::BadFileDescriptor);
         }
 
-        let (r, len) =
-            unsafe { syscall!(SystemCall::FileIO as u64, op as u64, fd, buffer, len, 2) };
+        let (r, len) = unsafe {
+            match op {
+                FileOperation::Read => raw::file_read(fd, buffer, len),
+                FileOperation::Write => raw::file_write(fd, buffer, len),
+                _ => unreachable!("fileio received unexpected op"),
+            }
+        };
 
         if r == 0 {
             Ok(len)
@@ -132,15 +179,11 @@ impl Fs {
 
         // If read or write is performed at the specific offset.
         let (r, len) = unsafe {
-            syscall!(
-                SystemCall::FileIO as u64,
-                op as u64,
-                fd,
-                buffer,
-                len,
-                offset as u64,
-                2
-            )
+            match op {
+                FileOperation::ReadAt => raw::file_read_at(fd, buffer, len, offset as u64),
+                FileOperation::WriteAt => raw::file_write_at(fd, buffer, len, offset as u64),
+                _ => unreachable!("fileio_at received unexpected op"),
+            }
         };
 
         if r == 0 {
@@ -153,15 +196,7 @@ impl Fs {
     /// Retrieve information about a file.
     pub fn getinfo(name: u64) -> Result<FileInfo, SystemCallError> {
         let fileinfo: FileInfo = Default::default();
-        let r = unsafe {
-            syscall!(
-                SystemCall::FileIO as u64,
-                FileOperation::GetInfo,
-                name as u64,
-                &fileinfo as *const FileInfo as u64,
-                1
-            )
-        };
+        let r = unsafe { raw::file_get_info(name, &fileinfo as *const FileInfo as u64) };
 
         if r == 0 {
             Ok(fileinfo)
@@ -172,14 +207,7 @@ impl Fs {
 
     /// Delete a file given by `name`.
     pub fn delete(name: u64) -> Result<bool, SystemCallError> {
-        let (r, is_deleted) = unsafe {
-            syscall!(
-                SystemCall::FileIO as u64,
-                FileOperation::Delete as u64,
-                name,
-                2
-            )
-        };
+        let (r, is_deleted) = unsafe { raw::file_delete(name) };
 
         if r == 0 && is_deleted == 0 {
             Ok(true)
@@ -198,17 +226,8 @@ impl Fs {
         }
 
         // If read or write is performed at the specific offset.
-        let (r, len) = unsafe {
-            syscall!(
-                SystemCall::FileIO as u64,
-                FileOperation::WriteDirect as u64,
-                buffer,
-                len,
-                offset as u64,
-                is_offset,
-                2
-            )
-        };
+        let (r, len) =
+            unsafe { raw::file_write_direct(buffer, len, offset as u64, is_offset as u64) };
 
         if r == 0 {
             Ok(len)
@@ -218,15 +237,7 @@ impl Fs {
     }
 
     pub fn rename(old_name: u64, new_name: u64) -> Result<u64, SystemCallError> {
-        let r = unsafe {
-            syscall!(
-                SystemCall::FileIO as u64,
-                FileOperation::FileRename,
-                old_name,
-                new_name,
-                1
-            )
-        };
+        let r = unsafe { raw::file_rename(old_name, new_name) };
 
         if r == 0 {
             Ok(0)
@@ -236,20 +247,266 @@ impl Fs {
     }
 
     pub fn mkdir_simple(pathname: u64, modes: u64) -> Result<u64, SystemCallError> {
-        let r = unsafe {
-            syscall!(
-                SystemCall::FileIO as u64,
-                FileOperation::MkDir,
-                pathname,
-                modes,
-                1
+        let r = unsafe { raw::file_mkdir(pathname, modes) };
+
+        if r == 0 {
+            Ok(0)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Create a symbolic link at `linkpath` that points to `target`.
+    ///
+    /// The target doesn't have to exist; relative targets are resolved from
+    /// the directory of the link.
+    pub fn symlink(target: u64, linkpath: u64) -> Result<(), SystemCallError> {
+        let r = unsafe { raw::file_symlink(target, linkpath) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Read the target of the symbolic link `pathname` into `buf`.
+    ///
+    /// Returns the length of the target; like POSIX `readlink` it's cut off
+    /// at the end of `buf` and not NUL-terminated.
+    pub fn readlink(pathname: u64, buf: &mut [u8]) -> Result<usize, SystemCallError> {
+        let (r, len) =
+            unsafe { raw::file_readlink(pathname, buf.as_mut_ptr() as u64, buf.len() as u64) };
+
+        if r == 0 {
+            Ok(len as usize)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Wait until all writes to `fd` have reached the storage of the file.
+    ///
+    /// Files on a disk mount are written to the disk (with all other files of
+    /// the mount), other files only live in memory. The kernel writes files
+    /// on a disk mount back shortly after they change anyway, this waits
+    /// until the disk has them.
+    pub fn fsync(fd: u64) -> Result<(), SystemCallError> {
+        let r = unsafe { raw::file_fsync(fd) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Mount a file-system of `kind` that can hold at most `limit` bytes
+    /// on the (existing) directory `pathname`.
+    ///
+    /// Files already below `pathname` are moved to the new mount. For
+    /// [`MountKind::Disk`] `limit` is the index of the disk instead (the
+    /// limit is its size) and `pathname` has to be empty, see
+    /// [`Fs::mount_disk`].
+    pub fn mount(pathname: u64, kind: MountKind, limit: u64) -> Result<(), SystemCallError> {
+        let r = unsafe { raw::file_mount(pathname, kind as u64, limit) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Mount disk `disk` on the (existing, empty) directory `pathname`.
+    ///
+    /// The files on the disk show up below `pathname`, [`Fs::fsync`] of any
+    /// file below it writes all of them back to the disk.
+    pub fn mount_disk(pathname: u64, disk: u64) -> Result<(), SystemCallError> {
+        Fs::mount(pathname, MountKind::Disk, disk)
+    }
+
+    /// Update `revents` of every entry in `fds` with its readiness. This
+    /// doesn't block, see [`Fs::poll_wait`] and [`PollSet`] for that.
+    ///
+    /// Returns the number of entries with a non-empty `revents`.
+    pub fn poll(fds: &mut [PollFd]) -> Result<usize, SystemCallError> {
+        Fs::poll_wait(fds, 0).map(|(ready, _left)| ready)
+    }
+
+    /// Like [`Fs::poll`] but if no entry is ready the kernel halts the core
+    /// until one might be (a datagram, key or `FutexWake` arrived), an
+    /// interrupt arrives or `timeout` (in ns, [`POLL_FOREVER`] for none)
+    /// is up.
+    ///
+    /// Returns the number of ready entries and the nanoseconds left of
+    /// `timeout`.
+    pub fn poll_wait(fds: &mut [PollFd], timeout: u64) -> Result<(usize, u64), SystemCallError> {
+        let (r, ready, left) = unsafe {
+            raw::file_poll(
+                fds.as_mut_ptr() as u64,
+                (fds.len() * core::mem::size_of::<PollFd>()) as u64,
+                timeout,
             )
         };
 
         if r == 0 {
-            Ok(0)
+            Ok((ready as usize, left))
         } else {
             Err(SystemCallError::from(r))
         }
     }
 }
+
+/// A set of file descriptors, sockets, futex words and the keyboard to wait
+/// on until any of them becomes ready.
+#[derive(Debug, Default)]
+pub struct PollSet {
+    fds: Vec<PollFd>,
+}
+
+impl PollSet {
+    pub fn new() -> PollSet {
+        Default::default()
+    }
+
+    /// Wait for `events` on the file descriptor `fd` (replaces the events
+    /// if `fd` is already part of the set).
+    pub fn add(&mut self, fd: u64, events: PollEvents) {
+        self.insert(PollFd::new(fd, events));
+    }
+
+    /// Wait for `events` on the UDP socket `socket`.
+    pub fn add_socket(&mut self, socket: u64, events: PollEvents) {
+        self.insert(PollFd::socket(socket, events));
+    }
+
+    /// Wait for keys (takes the keyboard focus, see [`PollKind::Keyboard`]).
+    pub fn add_keyboard(&mut self) {
+        self.insert(PollFd::keyboard());
+    }
+
+    /// Wait until `word` isn't `expected` anymore.
+    pub fn add_futex(&mut self, word: &AtomicU32, expected: u32) {
+        self.insert(PollFd::futex(word as *const AtomicU32 as u64, expected));
+    }
+
+    fn insert(&mut self, entry: PollFd) {
+        match self
+            .fds
+            .iter_mut()
+            .find(|pfd| pfd.kind == entry.kind && pfd.fd == entry.fd)
+        {
+            Some(pfd) => *pfd = entry,
+            None => self.fds.push(entry),
+        }
+    }
+
+    /// Stop waiting on the file descriptor `fd`.
+    pub fn remove(&mut self, fd: u64) {
+        self.remove_entry(PollKind::File, fd);
+    }
+
+    /// Stop waiting on `fd` of `kind` (the address of a futex word, 0 for
+    /// the keyboard).
+    pub fn remove_entry(&mut self, kind: PollKind, fd: u64) {
+        self.fds.retain(|pfd| pfd.kind() != kind || pfd.fd != fd);
+    }
+
+    /// Wait until at least one entry in the set is ready or `timeout`
+    /// expired, `None` waits forever.
+    ///
+    /// The kernel halts the core in between (see [`Fs::poll_wait`]), we
+    /// call it again after every interrupt until the timeout is up.
+    ///
+    /// Returns the ready entries and their events (empty on timeout).
+    pub fn wait(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<(PollKind, u64, PollEvents)>, SystemCallError> {
+        let mut left = timeout.map_or(POLL_FOREVER, |timeout| {
+            core::cmp::min(timeout.as_nanos(), (POLL_FOREVER - 1) as u128) as u64
+        });
+        loop {
+            let (ready, remaining) = Fs::poll_wait(self.fds.as_mut_slice(), left)?;
+            if ready > 0 {
+                return Ok(self
+                    .fds
+                    .iter()
+                    .filter(|pfd| !pfd.revents().is_empty())
+                    .map(|pfd| (pfd.kind(), pfd.fd, pfd.revents()))
+                    .collect());
+            }
+            if left == 0 || remaining == 0 {
+                return Ok(Vec::new());
+            }
+            left = remaining;
+        }
+    }
+}
+
+/// An asynchronous I/O ring shared with the kernel.
+///
+/// Requests are queued with [`Ring::submit`] and processed by the kernel on
+/// [`Ring::enter`], their results can be collected with [`Ring::complete`].
+pub struct Ring {
+    ring: Box<IoRing>,
+}
+
+impl Default for Ring {
+    fn default() -> Ring {
+        Ring::new()
+    }
+}
+
+impl Ring {
+    pub fn new() -> Ring {
+        Ring {
+            ring: Box::new(IoRing::new()),
+        }
+    }
+
+    /// Queue `entry`, hands it back if the submission queue is full.
+    pub fn submit(&mut self, entry: SubmissionEntry) -> Result<(), SubmissionEntry> {
+        let header = &mut self.ring.header;
+        if header.sq_tail - header.sq_head == RING_ENTRIES as u64 {
+            return Err(entry);
+        }
+
+        let idx = (header.sq_tail % RING_ENTRIES as u64) as usize;
+        self.ring.sq[idx] = entry;
+        self.ring.header.sq_tail += 1;
+        Ok(())
+    }
+
+    /// Let the kernel process the queued requests.
+    ///
+    /// Returns the number of requests that completed, requests that don't
+    /// fit in the completion queue stay queued for the next call.
+    pub fn enter(&mut self) -> Result<usize, SystemCallError> {
+        let (r, completed) = unsafe {
+            raw::file_ring_enter(
+                &mut *self.ring as *mut IoRing as u64,
+                core::mem::size_of::<IoRing>() as u64,
+            )
+        };
+
+        if r == 0 {
+            Ok(completed as usize)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Take the oldest completion from the completion queue.
+    pub fn complete(&mut self) -> Option<CompletionEntry> {
+        let header = &mut self.ring.header;
+        if header.cq_head == header.cq_tail {
+            return None;
+        }
+
+        let idx = (header.cq_head % RING_ENTRIES as u64) as usize;
+        header.cq_head += 1;
+        Some(self.ring.cq[idx])
+    }
+}
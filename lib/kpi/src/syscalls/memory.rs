@@ -5,10 +5,10 @@
 
 use core::convert::TryInto;
 
-use crate::process::FrameId;
+use crate::process::{FrameId, MapFlags, MappingRange};
 use crate::*;
 
-use crate::syscall;
+use super::raw;
 
 use x86::bits64::paging::{PAddr, VAddr};
 
@@ -21,7 +21,20 @@ impl VSpace {
     /// # Safety
     /// Manipulates address space of process.
     pub unsafe fn map(base: u64, bound: u64) -> Result<(VAddr, PAddr), SystemCallError> {
-        VSpace::vspace(VSpaceOperation::Map, base, bound)
+        VSpace::vspace(VSpaceOperation::Map, base, bound, MapFlags::READ_WRITE_USER)
+    }
+
+    /// Back a region of memory with DRAM, mapped with the given access
+    /// rights (DRAM is always cached, caching attributes are rejected).
+    ///
+    /// # Safety
+    /// Manipulates address space of process.
+    pub unsafe fn map_with_flags(
+        base: u64,
+        bound: u64,
+        flags: MapFlags,
+    ) -> Result<(VAddr, PAddr), SystemCallError> {
+        VSpace::vspace(VSpaceOperation::Map, base, bound, flags)
     }
 
     /// Unmap region of virtual memory.
@@ -29,7 +42,7 @@ impl VSpace {
     /// # Safety
     /// Manipulates address space of process.
     pub unsafe fn unmap(base: u64, bound: u64) -> Result<(VAddr, PAddr), SystemCallError> {
-        VSpace::vspace(VSpaceOperation::Unmap, base, bound)
+        VSpace::vspace(VSpaceOperation::Unmap, base, bound, MapFlags::empty())
     }
 
     /// Maps device memory (identity mapped with physical mem).
@@ -37,7 +50,26 @@ impl VSpace {
     /// # Safety
     /// Manipulates address space of process.
     pub unsafe fn map_device(base: u64, bound: u64) -> Result<(VAddr, PAddr), SystemCallError> {
-        VSpace::vspace(VSpaceOperation::MapDevice, base, bound)
+        VSpace::vspace(
+            VSpaceOperation::MapDevice,
+            base,
+            bound,
+            MapFlags::READ_WRITE_USER,
+        )
+    }
+
+    /// Maps device memory (identity mapped with physical mem) with the given
+    /// access rights and caching attribute, e.g., `MapFlags::NOCACHE` for
+    /// registers or `MapFlags::WRITE_COMBINE` for a frame buffer.
+    ///
+    /// # Safety
+    /// Manipulates address space of process.
+    pub unsafe fn map_device_with_flags(
+        base: u64,
+        bound: u64,
+        flags: MapFlags,
+    ) -> Result<(VAddr, PAddr), SystemCallError> {
+        VSpace::vspace(VSpaceOperation::MapDevice, base, bound, flags)
     }
 
     /// Maps a registered frame.
@@ -49,13 +81,7 @@ impl VSpace {
         base: u64,
     ) -> Result<(VAddr, PAddr), SystemCallError> {
         let frame_id: u64 = frame_id.try_into().unwrap();
-        let (err, paddr, _size) = syscall!(
-            SystemCall::VSpace as u64,
-            VSpaceOperation::MapFrame as u64,
-            base,
-            frame_id,
-            3
-        );
+        let (err, paddr, _size) = raw::vspace_map_frame(base, frame_id);
 
         if err == 0 {
             Ok((VAddr::from(base), PAddr::from(paddr)))
@@ -64,8 +90,84 @@ impl VSpace {
         }
     }
 
+    /// Reserves `bound` bytes of the address-space at `base` without backing
+    /// them with memory. The region is mapped with `flags` once pages of it
+    /// are committed ([`VSpace::commit`]).
+    ///
+    /// # Safety
+    /// Manipulates address space of process.
+    pub unsafe fn reserve(base: u64, bound: u64, flags: MapFlags) -> Result<(), SystemCallError> {
+        let (err, _base, _size) = raw::vspace_reserve(base, bound, flags.bits());
+
+        if err == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(err))
+        }
+    }
+
+    /// Drops the reservation at `base` ([`VSpace::reserve`]), the address
+    /// space of its uncommitted pages is free again. Committed pages stay
+    /// mapped until they're unmapped.
+    ///
+    /// # Safety
+    /// Manipulates address space of process.
+    pub unsafe fn unreserve(base: u64) -> Result<(), SystemCallError> {
+        let err = raw::vspace_unreserve(base);
+
+        if err == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(err))
+        }
+    }
+
+    /// Backs the pages of a reserved region from `base` to `base + bound`
+    /// with zeroed memory (pages that are already backed are left as is).
+    ///
+    /// Returns the number of bytes that got backed.
+    ///
+    /// # Safety
+    /// Manipulates address space of process.
+    pub unsafe fn commit(base: u64, bound: u64) -> Result<u64, SystemCallError> {
+        let (err, _base, committed) = raw::vspace_commit(base, bound);
+
+        if err == 0 {
+            Ok(committed)
+        } else {
+            Err(SystemCallError::from(err))
+        }
+    }
+
+    /// Describes the mapped regions of the address-space in `ranges`
+    /// (sorted by address, kernel memory is left out).
+    ///
+    /// Returns the number of ranges there are, which can be more than fit
+    /// in `ranges` (only the first ones are written then).
+    pub fn dump(ranges: &mut [MappingRange]) -> Result<usize, SystemCallError> {
+        let len = ranges.len() * core::mem::size_of::<MappingRange>();
+        let (err, count) = unsafe { raw::vspace_dump(ranges.as_mut_ptr() as u64, len as u64, 0) };
+
+        if err == 0 {
+            Ok(count as usize)
+        } else {
+            Err(SystemCallError::from(err))
+        }
+    }
+
+    /// Prints the mapped regions of the address-space on the kernel console.
+    pub fn dump_to_console() -> Result<usize, SystemCallError> {
+        let (err, count) = unsafe { raw::vspace_dump(0, 0, 1) };
+
+        if err == 0 {
+            Ok(count as usize)
+        } else {
+            Err(SystemCallError::from(err))
+        }
+    }
+
     pub fn identify(base: u64) -> Result<(VAddr, PAddr), SystemCallError> {
-        unsafe { VSpace::vspace(VSpaceOperation::Identify, base, 0) }
+        unsafe { VSpace::vspace(VSpaceOperation::Identify, base, 0, MapFlags::empty()) }
     }
 
     /// Manipulate the virtual address space.
@@ -73,8 +175,15 @@ impl VSpace {
         op: VSpaceOperation,
         base: u64,
         bound: u64,
+        flags: MapFlags,
     ) -> Result<(VAddr, PAddr), SystemCallError> {
-        let (err, paddr, size) = syscall!(SystemCall::VSpace as u64, op as u64, base, bound, 3);
+        let (err, paddr, size) = match op {
+            VSpaceOperation::Map => raw::vspace_map(base, bound, flags.bits()),
+            VSpaceOperation::Unmap => raw::vspace_unmap(base, bound),
+            VSpaceOperation::MapDevice => raw::vspace_map_device(base, bound, flags.bits()),
+            VSpaceOperation::Identify => raw::vspace_identify(base, bound),
+            _ => unreachable!("vspace received unexpected op"),
+        };
 
         log::trace!(
             "OP={:?} {:#x} -- {:#x} --> {:#x} -- {:#x}",
@@ -103,12 +212,8 @@ pub struct PhysicalMemory;
 impl PhysicalMemory {
     pub fn allocate_base_page() -> Result<(FrameId, PAddr), SystemCallError> {
         unsafe {
-            let (err, frame_id, paddr) = syscall!(
-                SystemCall::Process as u64,
-                ProcessOperation::AllocatePhysical as u64,
-                x86::current::paging::BASE_PAGE_SIZE,
-                3
-            );
+            let (err, frame_id, paddr) =
+                raw::process_allocate_physical(x86::current::paging::BASE_PAGE_SIZE as u64);
 
             if err == 0 {
                 debug_assert!(paddr > 0, "Valid PAddr");
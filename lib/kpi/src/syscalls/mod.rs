@@ -5,13 +5,20 @@
 //!
 //! Code in this module is not linked into the kernel.
 
+mod error;
 mod io;
 mod macros;
 mod memory;
+mod net;
 mod process;
+mod raw;
 mod system;
+mod test;
 
-pub use io::{Fs, Irq};
+pub use error::{clear_last_error, last_error};
+pub use io::{Fs, Irq, PollSet, Ring};
 pub use memory::{PhysicalMemory, VSpace};
+pub use net::Net;
 pub use process::Process;
 pub use system::System;
+pub use test::Test;
@@ -0,0 +1,72 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! System calls for UDP sockets on the loopback interface (127.0.0.1).
+//!
+//! Processes use them to talk to each other without a NIC. They fail with
+//! `SystemCallError::NotSupported` on a kernel built without `smoltcp`.
+
+use crate::net::Endpoint;
+use crate::*;
+
+use super::raw;
+
+pub struct Net;
+
+impl Net {
+    /// Opens a socket bound to `port` (an unused one if `port` is 0),
+    /// returns the socket.
+    ///
+    /// Fails with `PermissionError` if the port is in use and with
+    /// `OpenFileLimit` if the process has too many sockets.
+    pub fn udp_bind(port: u16) -> Result<u64, SystemCallError> {
+        let (r, socket) = unsafe { raw::net_udp_bind(port as u64) };
+
+        if r == 0 {
+            Ok(socket)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Sends `buf` as one datagram from `socket` to `to` (an address in
+    /// 127.0.0.0/8), returns the bytes sent.
+    pub fn udp_send_to(socket: u64, buf: &[u8], to: Endpoint) -> Result<usize, SystemCallError> {
+        let (r, sent) = unsafe {
+            raw::net_udp_send_to(socket, buf.as_ptr() as u64, buf.len() as u64, u64::from(to))
+        };
+
+        if r == 0 {
+            Ok(sent as usize)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Takes the next datagram `socket` received, returns its length (cut to
+    /// `buf.len()`) and sender, or `None` if nothing arrived.
+    pub fn udp_recv_from(
+        socket: u64,
+        buf: &mut [u8],
+    ) -> Result<Option<(usize, Endpoint)>, SystemCallError> {
+        let (r, len, from) =
+            unsafe { raw::net_udp_recv_from(socket, buf.as_mut_ptr() as u64, buf.len() as u64) };
+
+        match r {
+            0 if from == 0 => Ok(None),
+            0 => Ok(Some((len as usize, Endpoint::from(from)))),
+            _ => Err(SystemCallError::from(r)),
+        }
+    }
+
+    /// Closes `socket`.
+    pub fn udp_close(socket: u64) -> Result<(), SystemCallError> {
+        let r = unsafe { raw::net_udp_close(socket) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+}
@@ -3,32 +3,361 @@
 
 //! Abstraction for system calls to do control the current process.
 
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+
 use crate::*;
 
-use crate::process::{CoreToken, ProcessInfo};
-use crate::syscall;
+use super::raw;
+use crate::process::{
+    AffinityMask, Capabilities, CoreRequestFlags, CoreToken, Event, LogLevel, OomPriority,
+    ProcessInfo, SchedulingClass, SyscallRecord, INHERITABLE_FDS,
+};
+use crate::system::DeviceId;
 use crate::x86_64::VirtualCpu;
 
 use x86::bits64::paging::VAddr;
 
 pub struct Process;
 
+/// `duration` in nanoseconds (as much as fits in a system call argument).
+fn nanos(duration: Duration) -> u64 {
+    core::cmp::min(duration.as_nanos(), u64::MAX as u128) as u64
+}
+
 impl Process {
     /// Request to run on `core_id` starting at `entry_point`.
     pub fn request_core(core_id: usize, entry_point: VAddr) -> Result<CoreToken, SystemCallError> {
-        let (r, gtid, _eid) = unsafe {
-            syscall!(
-                SystemCall::Process as u64,
-                ProcessOperation::RequestCore as u64,
-                core_id as u64,
+        let (r, gtid, _eid) =
+            unsafe { raw::process_request_core(core_id as u64, entry_point.as_u64()) };
+
+        if r == 0 {
+            debug_assert_eq!(gtid as usize, core_id, "Should this hold?");
+            Ok(CoreToken::from(gtid))
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Request up to `count` cores from the set of hardware threads in `mask`,
+    /// the new cores start executing at `entry_point`.
+    ///
+    /// With [`CoreRequestFlags::GANG`] the kernel either allocates all `count`
+    /// cores at once (so they get dispatched simultaneously) or fails with
+    /// [`SystemCallError::GangUnsatisfiable`] without allocating anything.
+    ///
+    /// Returns the set of cores that were allocated to the process.
+    pub fn request_cores(
+        mask: AffinityMask,
+        count: usize,
+        flags: CoreRequestFlags,
+        entry_point: VAddr,
+    ) -> Result<AffinityMask, SystemCallError> {
+        let mut mask = mask;
+        let (r, allocated) = unsafe {
+            raw::process_request_cores(
+                &mut mask as *mut AffinityMask as u64,
+                count as u64,
+                flags.bits(),
                 entry_point.as_u64(),
-                3
             )
         };
 
         if r == 0 {
-            debug_assert_eq!(gtid as usize, core_id, "Should this hold?");
-            Ok(CoreToken::from(gtid))
+            debug_assert_eq!(allocated as usize, mask.count());
+            Ok(mask)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Save the current process (its writable memory, the registers of the
+    /// calling core and the open files) in the file at `pathname`.
+    ///
+    /// Like `fork`, this returns twice: `false` in the calling process once
+    /// the checkpoint is written and `true` when a process restored from the
+    /// checkpoint (see [`Process::restore`]) starts running. Needs
+    /// `Capabilities::CHECKPOINT`.
+    pub fn checkpoint(pathname: u64) -> Result<bool, SystemCallError> {
+        let (r, _len, restored) = unsafe { raw::process_checkpoint(pathname) };
+
+        if r == 0 {
+            Ok(restored == 1)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Start a new process from the checkpoint in the file at `pathname`,
+    /// returns the pid of the new process (a child of the caller). Needs
+    /// `Capabilities::CHECKPOINT`.
+    pub fn restore(pathname: u64) -> Result<u64, SystemCallError> {
+        let (r, pid) = unsafe { raw::process_restore(pathname) };
+
+        if r == 0 {
+            Ok(pid)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Start a new process, a child of the calling process, on a free core.
+    ///
+    /// `cmdline` is the name of the binary (a module the kernel was booted
+    /// with) optionally followed by a space and the arguments for the child
+    /// (its `ProcessInfo::cmdline`). The child gets `capabilities`, which
+    /// the caller must have itself.
+    ///
+    /// The child doesn't inherit any file descriptors, see
+    /// [`Process::spawn_with_fds`].
+    ///
+    /// Returns the pid of the child.
+    pub fn spawn(cmdline: &str, capabilities: Capabilities) -> Result<u64, SystemCallError> {
+        Process::spawn_with_fds(cmdline, capabilities, &[])
+    }
+
+    /// Like [`Process::spawn`], the child also inherits the file
+    /// descriptors `fds` (below [`INHERITABLE_FDS`]) of the caller.
+    ///
+    /// The child gets them with the same numbers, the same flags and the
+    /// current offset of the caller (which both advance on their own from
+    /// there). Descriptors opened with `O_CLOEXEC` are never inherited. The
+    /// child also inherits the file descriptor limit of the caller (see
+    /// [`Process::set_fd_limit`]).
+    pub fn spawn_with_fds(
+        cmdline: &str,
+        capabilities: Capabilities,
+        fds: &[u64],
+    ) -> Result<u64, SystemCallError> {
+        let mut inherit = 0u64;
+        for fd in fds {
+            if *fd >= INHERITABLE_FDS {
+                return Err(SystemCallError::BadFileDescriptor);
+            }
+            inherit |= 1 << fd;
+        }
+
+        let (r, pid) = unsafe {
+            raw::process_spawn(
+                cmdline.as_ptr() as u64,
+                cmdline.len() as u64,
+                capabilities.bits(),
+                inherit,
+            )
+        };
+
+        if r == 0 {
+            Ok(pid)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Returns the exit code of the child `pid` if it exited, `None` if it
+    /// still runs.
+    ///
+    /// This doesn't block, poll it to wait for a child.
+    pub fn wait_pid(pid: u64) -> Result<Option<u64>, SystemCallError> {
+        let (r, exited, code) = unsafe { raw::process_wait_pid(pid, 0) };
+
+        if r == 0 {
+            Ok(if exited == 1 { Some(code) } else { None })
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Waits (without spinning) until the child `pid` exits and returns its
+    /// exit code, or `None` if it still runs after `timeout`.
+    ///
+    /// The kernel checks on the child about every millisecond.
+    pub fn wait_pid_timeout(pid: u64, timeout: Duration) -> Result<Option<u64>, SystemCallError> {
+        let mut left = nanos(timeout);
+        loop {
+            let (r, exited, code) = unsafe { raw::process_wait_pid(pid, left) };
+            if r != 0 {
+                return Err(SystemCallError::from(r));
+            }
+            if exited == 1 {
+                return Ok(Some(code));
+            }
+            // Without exit code we get the time left
+            left = code;
+            if left == 0 {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Sleeps for (at least) `duration`.
+    ///
+    /// The kernel halts the core until its timer fires, the sleep ends a
+    /// few microseconds late at most (more in a VM) and never early.
+    ///
+    /// Nothing else runs on the core in the meantime, including the other
+    /// user-level threads scheduled on it. Threads should use lineup's
+    /// `Environment::thread().sleep()` instead, which only suspends the
+    /// calling thread.
+    pub fn sleep(duration: Duration) -> Result<(), SystemCallError> {
+        let mut left = nanos(duration);
+        while left > 0 {
+            // Returns early to handle interrupts
+            let (r, remaining) = unsafe { raw::process_sleep(left) };
+            if r != 0 {
+                return Err(SystemCallError::from(r));
+            }
+            left = remaining;
+        }
+        Ok(())
+    }
+
+    /// Terminates the child `pid` on all its cores, it exits with
+    /// [`crate::process::KILLED`].
+    pub fn kill(pid: u64) -> Result<(), SystemCallError> {
+        let r = unsafe { raw::process_kill(pid) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Sets how important `pid` (the current process or a child) is to the
+    /// OOM killer (see [`OomPriority`]).
+    pub fn set_oom_priority(pid: u64, priority: OomPriority) -> Result<(), SystemCallError> {
+        let r = unsafe { raw::process_set_oom_priority(pid, priority as u64) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Replaces the service `pid` (a process of `init.toml` the kernel
+    /// restarts) with an instance of `binary`, needs the `RELOAD`
+    /// capability.
+    ///
+    /// `binary` is the path of an ELF file (e.g., one uploaded over the
+    /// network) or the name of a module. It replaces the binary of the
+    /// service for good. The new instance gets the arguments, file-system
+    /// root, cores and capabilities of the old one, which is terminated.
+    ///
+    /// Returns the pid of the new instance.
+    pub fn reload(pid: u64, binary: &str) -> Result<u64, SystemCallError> {
+        let (r, new_pid) =
+            unsafe { raw::process_reload(pid, binary.as_ptr() as u64, binary.len() as u64) };
+
+        if r == 0 {
+            Ok(new_pid)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Makes the current process the driver of device `id` (see
+    /// `System::devices`), needs the `DEVICES` capability.
+    ///
+    /// Only the driver can map the memory of a device (`VSpace::map_device`)
+    /// once it's claimed. The device is free again when the process exits.
+    /// Devices that have a driver in the kernel can't be claimed.
+    pub fn claim_device(id: DeviceId) -> Result<(), SystemCallError> {
+        let r = unsafe { raw::process_claim_device(id) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Lowers the number of files the current process can have open at the
+    /// same time to `limit` (file descriptors are below `limit`).
+    ///
+    /// The limit can't be raised again, children inherit it. Descriptors
+    /// that are already open stay open.
+    pub fn set_fd_limit(limit: u64) -> Result<(), SystemCallError> {
+        let r = unsafe { raw::process_set_fd_limit(limit) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Subscribes to `event` and waits (without spinning) until it happens,
+    /// returns what the event carries (see [`Event`]) or `None` if nothing
+    /// happened in `timeout`.
+    ///
+    /// Events that happen after the first call are queued until they are
+    /// picked up.
+    pub fn wait_event(event: Event, timeout: Duration) -> Result<Option<u64>, SystemCallError> {
+        let mut left = nanos(timeout);
+        loop {
+            let (r, happened, value) = unsafe { raw::process_subscribe_event(event as u64, left) };
+            if r != 0 {
+                return Err(SystemCallError::from(r));
+            }
+            if happened == 1 {
+                return Ok(Some(value));
+            }
+            // Otherwise we get the time left
+            left = value;
+            if left == 0 {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Turns tracing of the system calls of `pid` (the current process or
+    /// a child) on or off.
+    ///
+    /// The kernel logs every system call of a traced process on its console
+    /// and keeps the most recent ones, [`Process::read_trace`] retrieves
+    /// them.
+    pub fn trace(pid: u64, enable: bool) -> Result<(), SystemCallError> {
+        let r = unsafe { raw::process_trace(pid, enable as u64) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Moves the system calls recorded for `pid` (oldest first) to
+    /// `records`, returns how many were written.
+    pub fn read_trace(pid: u64, records: &mut [SyscallRecord]) -> Result<usize, SystemCallError> {
+        let len = records.len() * core::mem::size_of::<SyscallRecord>();
+        let (r, count) =
+            unsafe { raw::process_read_trace(pid, records.as_mut_ptr() as u64, len as u64) };
+
+        if r == 0 {
+            Ok(count as usize)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Set the scheduling `class` for the executor on the current core.
+    ///
+    /// `deadline` is an optional hint (in rdtsc cycles) for how long
+    /// [`SchedulingClass::LowLatency`] executors can tolerate being kept
+    /// from running by kernel work.
+    ///
+    /// This is a hint for the whole core, the threads of the user-level
+    /// scheduler have classes of their own (lineup's
+    /// `ThreadControlBlock::set_scheduling_class`).
+    pub fn set_scheduling_class(
+        class: SchedulingClass,
+        deadline: Option<u64>,
+    ) -> Result<(), SystemCallError> {
+        let r = unsafe { raw::process_set_scheduling_class(class as u64, deadline.unwrap_or(0)) };
+
+        if r == 0 {
+            Ok(())
         } else {
             Err(SystemCallError::from(r))
         }
@@ -37,12 +366,37 @@ impl Process {
     /// Print `buffer` on the console.
     pub fn print(buffer: &str) -> Result<(), SystemCallError> {
         let r = unsafe {
-            syscall!(
-                SystemCall::Process as u64,
-                ProcessOperation::Log as u64,
+            raw::process_log(
                 buffer.as_ptr() as u64,
-                buffer.len(),
-                1
+                buffer.len() as u64,
+                0,
+                LogLevel::Print as u64,
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Log the line `message` from `target` (e.g., the module) with severity
+    /// `level` on the console.
+    ///
+    /// The kernel discards the message if `level` is less severe than the log
+    /// level of the process. Like [`Process::print`] it may also drop it if
+    /// the process logs too much.
+    pub fn log(level: LogLevel, target: &str, message: &str) -> Result<(), SystemCallError> {
+        // The level goes in the low, the length of the target in the high
+        // 32 bits
+        let meta = level as u64 | (target.len() as u64) << 32;
+        let r = unsafe {
+            raw::process_log(
+                message.as_ptr() as u64,
+                message.len() as u64,
+                target.as_ptr() as u64,
+                meta,
             )
         };
 
@@ -53,24 +407,163 @@ impl Process {
         }
     }
 
+    /// Set the log level of the current process, messages that are less
+    /// severe are discarded (see [`Process::log`]).
+    pub fn set_log_level(level: LogLevel) -> Result<(), SystemCallError> {
+        let r = unsafe { raw::process_set_log_level(level as u64) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Makes the kernel write an ELF core dump of the current process to
+    /// `/core.<pid>` if it crashes (or stops it from doing so, the default).
+    pub fn set_core_dump(enable: bool) -> Result<(), SystemCallError> {
+        let r = unsafe { raw::process_set_core_dump(enable as u64) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Waits on the current core until the value of `word` is no longer
+    /// `expected` (the kernel halts the core until `word` gets written if
+    /// the CPU supports it).
+    ///
+    /// Returns false if the kernel returned before that (e.g., to handle an
+    /// interrupt), the caller should check `word` and call us again.
+    pub fn barrier_wait(word: &AtomicU64, expected: u64) -> Result<bool, SystemCallError> {
+        let (r, changed, _left) =
+            unsafe { raw::process_barrier_wait(word as *const AtomicU64 as u64, expected, 0) };
+
+        if r == 0 {
+            Ok(changed != 0)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Like [`Process::barrier_wait`] but waits until `word` changes or
+    /// `timeout` is up (handling interrupts in between).
+    ///
+    /// Returns false if `timeout` elapsed before `word` changed.
+    pub fn barrier_wait_timeout(
+        word: &AtomicU64,
+        expected: u64,
+        timeout: Duration,
+    ) -> Result<bool, SystemCallError> {
+        let mut left = nanos(timeout);
+        while left > 0 {
+            let (r, changed, remaining) = unsafe {
+                raw::process_barrier_wait(word as *const AtomicU64 as u64, expected, left)
+            };
+            if r != 0 {
+                return Err(SystemCallError::from(r));
+            }
+            if changed != 0 {
+                return Ok(true);
+            }
+            left = remaining;
+        }
+        Ok(word.load(Ordering::Acquire) != expected)
+    }
+
+    /// Waits on the current core until another core wakes up `word` with
+    /// [`Process::futex_wake`], unless `word` isn't `expected` (anymore).
+    ///
+    /// Returns false if the kernel returned without a wake-up (e.g., the word
+    /// changed or an interrupt arrived), the caller should check `word` and
+    /// call us again.
+    pub fn futex_wait(word: &AtomicU32, expected: u32) -> Result<bool, SystemCallError> {
+        let (r, woken, _left) =
+            unsafe { raw::process_futex_wait(word as *const AtomicU32 as u64, expected as u64, 0) };
+
+        if r == 0 {
+            Ok(woken != 0)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Like [`Process::futex_wait`] but waits until we get woken up, `word`
+    /// changes or `timeout` is up (handling interrupts in between).
+    ///
+    /// Returns false if `timeout` elapsed first.
+    pub fn futex_wait_timeout(
+        word: &AtomicU32,
+        expected: u32,
+        timeout: Duration,
+    ) -> Result<bool, SystemCallError> {
+        let mut left = nanos(timeout);
+        while left > 0 && word.load(Ordering::Acquire) == expected {
+            let (r, woken, remaining) = unsafe {
+                raw::process_futex_wait(word as *const AtomicU32 as u64, expected as u64, left)
+            };
+            if r != 0 {
+                return Err(SystemCallError::from(r));
+            }
+            if woken != 0 {
+                return Ok(true);
+            }
+            left = remaining;
+        }
+        Ok(word.load(Ordering::Acquire) != expected)
+    }
+
+    /// Wakes up at most `count` cores of the process that wait on `word`,
+    /// returns how many we woke up.
+    pub fn futex_wake(word: &AtomicU32, count: u64) -> Result<u64, SystemCallError> {
+        let (r, woken) = unsafe { raw::process_futex_wake(word as *const AtomicU32 as u64, count) };
+
+        if r == 0 {
+            Ok(woken)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Leaves a critical section (re-enables upcalls on the current core).
+    ///
+    /// In case an upcall arrived while upcalls were disabled, the kernel
+    /// delivers it now (and this returns once the upcall handler resumes the
+    /// interrupted state).
+    pub fn enable_upcalls(vcpu: &mut VirtualCpu) -> Result<(), SystemCallError> {
+        vcpu.enable_upcalls();
+        if !vcpu.has_pending_upcall() {
+            return Ok(());
+        }
+
+        let r = unsafe { raw::process_deliver_upcall() };
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
     /// Gets the VCPU memory location for the current core of the thread.
     ///
     /// This is allocated and controlled by the kernel, it doesn't move and
     /// will be valid as long as the current CPU is allocated to the process.
     pub fn vcpu_control_area() -> Result<&'static mut VirtualCpu, SystemCallError> {
-        let (r, control) = unsafe {
-            syscall!(
-                SystemCall::Process as u64,
-                ProcessOperation::GetVCpuArea as u64,
-                2
-            )
-        };
+        let (r, control) = unsafe { raw::process_get_vcpu_area() };
 
         if r == 0 {
             let vaddr = VAddr::from(control);
             assert!(vaddr.is_base_page_aligned());
-            let vcpu_ctl: *mut VirtualCpu = vaddr.as_mut_ptr::<VirtualCpu>();
-            unsafe { Ok(&mut *vcpu_ctl) }
+            let vcpu_ctl: &'static mut VirtualCpu =
+                unsafe { &mut *vaddr.as_mut_ptr::<VirtualCpu>() };
+            assert_eq!(
+                vcpu_ctl.version(),
+                VirtualCpu::VERSION,
+                "Kernel and user-space disagree on the vCPU area layout"
+            );
+            Ok(vcpu_ctl)
         } else {
             Err(SystemCallError::from(r))
         }
@@ -78,38 +571,36 @@ impl Process {
 
     /// Query process specific information.
     pub fn process_info() -> Result<ProcessInfo, SystemCallError> {
-        let mut buf = alloc::vec![0; 256];
-        let (r, len) = unsafe {
-            syscall!(
-                SystemCall::Process as u64,
-                ProcessOperation::GetProcessInfo as u64,
-                buf.as_mut_ptr() as u64,
-                buf.len() as u64,
-                2
-            )
-        };
+        let mut buf = alloc::vec![0; 1024];
+        loop {
+            let (r, len) =
+                unsafe { raw::process_get_process_info(buf.as_mut_ptr() as u64, buf.len() as u64) };
+            if r != 0 {
+                return Err(SystemCallError::from(r));
+            }
 
-        if r == 0 {
+            // The kernel only copies it if it fits
             let len = len as usize;
-            debug_assert!(len <= buf.len());
-            buf.resize(len, 0);
+            if len > buf.len() {
+                buf.resize(len, 0);
+                continue;
+            }
+            buf.truncate(len);
             let static_buf = alloc::vec::Vec::leak(buf);
             let deserialized: ProcessInfo = serde_cbor::from_slice(static_buf).unwrap();
-            Ok(deserialized)
-        } else {
-            Err(SystemCallError::from(r))
+            return Ok(deserialized);
         }
     }
 
     /// Exit the process (pass an error `code` to exit).
+    ///
+    /// If the process was started by another process (see
+    /// [`Process::spawn`]) the parent can query `code` with
+    /// [`Process::wait_pid`]. Otherwise (i.e., for init) the system shuts
+    /// down.
     pub fn exit(code: u64) -> ! {
         unsafe {
-            syscall!(
-                SystemCall::Process as u64,
-                ProcessOperation::Exit as u64,
-                code,
-                1
-            );
+            raw::process_exit(code);
 
             // This stops the process and never returns:
             unreachable!()
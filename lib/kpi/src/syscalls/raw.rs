@@ -0,0 +1,64 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Raw system call stubs, generated from the system call table (see
+//! `signature.rs`).
+//!
+//! Each stub takes the arguments listed in the table and returns as many
+//! registers as the table specifies (the first one is the error code).
+//! Errors are recorded for [`super::last_error`].
+
+use crate::syscall;
+use crate::*;
+
+macro_rules! returns {
+    (1) => {
+        u64
+    };
+    (2) => {
+        (u64, u64)
+    };
+    (3) => {
+        (u64, u64, u64)
+    };
+}
+
+/// The error code (first register) of what a stub returns.
+trait ErrorCode {
+    fn error_code(&self) -> u64;
+}
+
+impl ErrorCode for u64 {
+    fn error_code(&self) -> u64 {
+        *self
+    }
+}
+
+impl ErrorCode for (u64, u64) {
+    fn error_code(&self) -> u64 {
+        self.0
+    }
+}
+
+impl ErrorCode for (u64, u64, u64) {
+    fn error_code(&self) -> u64 {
+        self.0
+    }
+}
+
+macro_rules! stubs {
+    ($($call:ident($ops:ident::$op:ident)
+        fn $name:ident($($arg:ident: $kind:ident),*) -> $ret:tt;)*) => {
+        $(
+            #[inline(always)]
+            pub(crate) unsafe fn $name($($arg: u64),*) -> returns!($ret) {
+                let r: returns!($ret) =
+                    syscall!(SystemCall::$call as u64, $ops::$op as u64, $($arg,)* $ret);
+                super::error::record(r.error_code());
+                r
+            }
+        )*
+    };
+}
+
+syscall_table!(stubs);
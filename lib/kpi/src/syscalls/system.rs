@@ -6,9 +6,15 @@
 
 use alloc::vec::Vec;
 
-use crate::{syscall, *};
+use crate::*;
 
-use crate::system::{CoreId, CpuThread};
+use super::raw;
+
+use crate::system::{
+    BuildInfo, ClockInfo, CoreId, CpuThread, CpuidResult, DeviceInfo, Governor, HardwareInfo,
+    IdleState, InfoKind, KernelStack, MitigationInfo, NetModeration, PowerInfo, ProfileCommand,
+    StackUsage,
+};
 
 pub struct System;
 
@@ -16,15 +22,8 @@ impl System {
     /// Query information about available hardware threads.
     pub fn threads() -> Result<Vec<CpuThread>, SystemCallError> {
         let mut buf = alloc::vec![0; 5*4096];
-        let (r, len) = unsafe {
-            syscall!(
-                SystemCall::System as u64,
-                SystemOperation::GetHardwareThreads as u64,
-                buf.as_mut_ptr() as u64,
-                buf.len() as u64,
-                2
-            )
-        };
+        let (r, len) =
+            unsafe { raw::system_get_hardware_threads(buf.as_mut_ptr() as u64, buf.len() as u64) };
 
         if r == 0 {
             let len = len as usize;
@@ -39,7 +38,7 @@ impl System {
 
     /// Prints some stats for the core.
     pub fn stats() -> Result<(), SystemCallError> {
-        let r = unsafe { syscall!(SystemCall::System as u64, SystemOperation::Stats as u64, 1) };
+        let r = unsafe { raw::system_stats() };
 
         if r == 0 {
             Ok(())
@@ -50,13 +49,7 @@ impl System {
 
     /// Get the core id for the current running thread.
     pub fn core_id() -> Result<CoreId, SystemCallError> {
-        let (r, id) = unsafe {
-            syscall!(
-                SystemCall::System as u64,
-                SystemOperation::GetCoreID as u64,
-                2
-            )
-        };
+        let (r, id) = unsafe { raw::system_get_core_id() };
 
         if r == 0 {
             Ok(id as usize)
@@ -64,4 +57,248 @@ impl System {
             Err(SystemCallError::from(r))
         }
     }
+
+    /// Move the current thread to the (unused) hardware thread `core`.
+    ///
+    /// The thread keeps its registers, stacks and vCPU area, it returns from
+    /// this call running on `core`.
+    pub fn migrate_core(core: CoreId) -> Result<(), SystemCallError> {
+        let r = unsafe { raw::system_migrate_core(core as u64) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Reads the model-specific register `msr` on the current core.
+    ///
+    /// Only a few registers useful for measurements (APERF/MPERF, RAPL
+    /// energy counters etc.) can be read and the process needs the
+    /// `MEASURE` capability.
+    pub fn read_msr(msr: u32) -> Result<u64, SystemCallError> {
+        let (r, value) = unsafe { raw::system_read_msr(msr as u64) };
+
+        if r == 0 {
+            Ok(value)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Queries CPUID `leaf` (and `subleaf`) on the current core.
+    ///
+    /// Like [`System::read_msr`] this is limited to a few leaves and needs
+    /// the `MEASURE` capability.
+    pub fn cpuid(leaf: u32, subleaf: u32) -> Result<CpuidResult, SystemCallError> {
+        let (r, eax_ebx, ecx_edx) = unsafe { raw::system_cpuid(leaf as u64, subleaf as u64) };
+
+        if r == 0 {
+            Ok(CpuidResult {
+                eax: eax_ebx as u32,
+                ebx: (eax_ebx >> 32) as u32,
+                ecx: ecx_edx as u32,
+                edx: (ecx_edx >> 32) as u32,
+            })
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Returns the current interrupt moderation tunables of the network
+    /// drivers.
+    pub fn net_moderation() -> Result<NetModeration, SystemCallError> {
+        let (r, poll_threshold, idle_polls) = unsafe { raw::system_net_moderation(0, 0) };
+
+        if r == 0 {
+            Ok(NetModeration {
+                poll_threshold,
+                idle_polls,
+            })
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Updates the interrupt moderation tunables of the network drivers.
+    ///
+    /// Fields that are 0 are left unchanged. Drivers pick up the new values
+    /// when they next measure the interrupt rate or switch back to
+    /// interrupts. Needs the `tune` capability.
+    pub fn set_net_moderation(moderation: NetModeration) -> Result<(), SystemCallError> {
+        let (r, _, _) =
+            unsafe { raw::system_net_moderation(moderation.poll_threshold, moderation.idle_polls) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Returns how much of the kernel `stack` of hardware thread `core` was
+    /// used so far.
+    ///
+    /// The usage is only known once the core handled its first timer
+    /// interrupt (before that the size is reported as 0).
+    pub fn stack_usage(core: usize, stack: KernelStack) -> Result<StackUsage, SystemCallError> {
+        let (r, max_used, size) = unsafe { raw::system_stack_usage(core as u64, stack as u64) };
+
+        if r == 0 {
+            Ok(StackUsage { max_used, size })
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Fills `buf` with random bytes from the kernel.
+    ///
+    /// The numbers are not cryptographically secure. They are the same on
+    /// every run if the kernel was booted with `seed=<n>`.
+    pub fn get_random(buf: &mut [u8]) -> Result<(), SystemCallError> {
+        let r = unsafe { raw::system_get_random(buf.as_mut_ptr() as u64, buf.len() as u64) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Starts the kernel profiler, all cores take a sample every `period`
+    /// cycles (needs the `measure` capability).
+    pub fn profile_start(period: u64) -> Result<(), SystemCallError> {
+        Self::profile(ProfileCommand::Start, period)
+    }
+
+    /// Stops the kernel profiler.
+    pub fn profile_stop() -> Result<(), SystemCallError> {
+        Self::profile(ProfileCommand::Stop, 0)
+    }
+
+    /// Prints the samples of the kernel profiler on the serial console (in
+    /// the collapsed stack format for flamegraphs) and clears them.
+    pub fn profile_dump() -> Result<(), SystemCallError> {
+        Self::profile(ProfileCommand::Dump, 0)
+    }
+
+    fn profile(command: ProfileCommand, period: u64) -> Result<(), SystemCallError> {
+        let r = unsafe { raw::system_profile(command as u64, period) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Returns the build information (git revision, profile, features and
+    /// compiler) of the running kernel.
+    pub fn build_info() -> Result<BuildInfo, SystemCallError> {
+        System::info(InfoKind::Build)
+    }
+
+    /// Returns the hardware inventory (machine, CPU model, microcode, caches,
+    /// NUMA nodes, memory and PCI devices) the kernel took at boot.
+    pub fn hardware_info() -> Result<HardwareInfo, SystemCallError> {
+        System::info(InfoKind::Hardware)
+    }
+
+    /// Returns the TSC offsets of all cores, [`ClockInfo::correct`] turns
+    /// timestamps of different cores into comparable ones. The offset of
+    /// the wall clock (see `ntp` on the kernel command line) makes wall-clock
+    /// times of different machines comparable.
+    pub fn clock_info() -> Result<ClockInfo, SystemCallError> {
+        System::info(InfoKind::Clock)
+    }
+
+    /// Returns the devices the kernel found and who drives them (see
+    /// `Process::claim_device`).
+    pub fn devices() -> Result<Vec<DeviceInfo>, SystemCallError> {
+        System::info(InfoKind::Devices)
+    }
+
+    /// Returns the speculative execution mitigations the kernel uses (see
+    /// `mitigations` on the kernel command line).
+    pub fn mitigations() -> Result<MitigationInfo, SystemCallError> {
+        System::info(InfoKind::Mitigations)
+    }
+
+    /// Returns the power management settings of the kernel and the energy
+    /// every package used since boot.
+    pub fn power() -> Result<PowerInfo, SystemCallError> {
+        System::info(InfoKind::Power)
+    }
+
+    /// Sets the frequency `governor` and the `idle` state of all cores, needs
+    /// the `POWER` capability.
+    ///
+    /// Fails with `NotSupported` if the CPU can't do it (e.g., in a VM that
+    /// doesn't expose the MSRs) or the ratio of `Governor::Fixed` is out of
+    /// range (see `System::power`).
+    pub fn set_power_policy(governor: Governor, idle: IdleState) -> Result<(), SystemCallError> {
+        let (kind, ratio) = governor.to_args();
+        let r = unsafe { raw::system_set_power_policy(kind, ratio, idle as u64) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    fn info<T: serde::de::DeserializeOwned>(kind: InfoKind) -> Result<T, SystemCallError> {
+        let mut buf = alloc::vec![0; 4096];
+        loop {
+            let (r, len) = unsafe {
+                raw::system_get_info(buf.as_mut_ptr() as u64, buf.len() as u64, kind as u64)
+            };
+            if r != 0 {
+                return Err(SystemCallError::from(r));
+            }
+
+            // The kernel only copies it if it fits
+            let len = len as usize;
+            if len > buf.len() {
+                buf.resize(len, 0);
+                continue;
+            }
+            buf.truncate(len);
+            let deserialized: T = serde_cbor::from_slice(&buf).unwrap();
+            return Ok(deserialized);
+        }
+    }
+
+    /// Returns how many interrupts (or exceptions) `vector` hardware thread
+    /// `core` took since it booted.
+    pub fn irq_count(core: usize, vector: u8) -> Result<u64, SystemCallError> {
+        let (r, count) = unsafe { raw::system_irq_count(core as u64, vector as u64) };
+
+        if r == 0 {
+            Ok(count)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Soft-reboots the machine into the kernel `binary` with
+    /// `command_line`, without going through the firmware. Needs the `KEXEC`
+    /// capability.
+    ///
+    /// `binary` is the path of an ELF file (e.g., one uploaded over the
+    /// network) or the name of a module. Only returns if the kernel can't
+    /// load it, the running kernel (and this process) keep going then.
+    pub fn kexec(binary: &str, command_line: &str) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            raw::system_kexec(
+                binary.as_ptr() as u64,
+                binary.len() as u64,
+                command_line.as_ptr() as u64,
+                command_line.len() as u64,
+            )
+        };
+
+        Err(SystemCallError::from(r))
+    }
 }
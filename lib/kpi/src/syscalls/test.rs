@@ -0,0 +1,103 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! System calls to inject faults in the kernel (for tests).
+//!
+//! They only work with a kernel built with the `fault-injection` feature and
+//! for processes with `Capabilities::FAULT_INJECTION`, otherwise they fail
+//! with `SystemCallError::NotSupported` or `SystemCallError::PermissionError`.
+
+use crate::*;
+
+use super::raw;
+
+pub struct Test;
+
+impl Test {
+    /// Fail the next `count` physical memory allocations of system calls
+    /// (with `SystemCallError::OutOfMemory`).
+    pub fn fail_allocations(count: u64) -> Result<(), SystemCallError> {
+        let r = unsafe { raw::test_fail_allocations(count) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Drop the next `count` packets the NIC receives.
+    pub fn drop_packets(count: u64) -> Result<(), SystemCallError> {
+        let r = unsafe { raw::test_drop_packets(count) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Delay the next `count` operations of the kernel's NR combiner by
+    /// `cycles` (rdtsc ticks) each.
+    pub fn delay_combine(count: u64, cycles: u64) -> Result<(), SystemCallError> {
+        let r = unsafe { raw::test_delay_combine(count, cycles) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Issue a system call with arbitrary registers (`function` in `%rdi`,
+    /// `op` in `%rsi` and `args` in the remaining argument registers), to
+    /// check how the kernel copes with malformed calls. Returns the first
+    /// return value.
+    ///
+    /// # Safety
+    /// The kernel executes whatever the registers decode to.
+    pub unsafe fn raw(function: u64, op: u64, args: [u64; 4]) -> Result<u64, SystemCallError> {
+        let (r, ret) = syscall!(function, op, args[0], args[1], args[2], args[3], 2);
+
+        if r == 0 {
+            Ok(ret)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Disarm all faults that didn't fire yet.
+    pub fn reset() -> Result<(), SystemCallError> {
+        let r = unsafe { raw::test_reset() };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// The core loses its save area once this call returns, the kernel kills
+    /// the caller at its next system call (the call returns with interrupts
+    /// disabled, so nothing else enters the kernel before).
+    pub fn lose_save_area() -> Result<(), SystemCallError> {
+        let r = unsafe { raw::test_lose_save_area() };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// The next system call on the core fails as if the core had no process.
+    pub fn lose_process() -> Result<(), SystemCallError> {
+        let r = unsafe { raw::test_lose_process() };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+}
@@ -3,6 +3,9 @@
 
 //! Data structures to exchange system-wide information between kernel and user-space.
 
+use alloc::string::String;
+use alloc::vec::Vec;
+
 use serde::{Deserialize, Serialize};
 
 /// A system global ID for a CPU hardware thread.
@@ -20,6 +23,97 @@ pub type PackageId = usize;
 /// Affinity region, a NUMA node (consists of a bunch of threads/core/packages and memory regions).
 pub type NodeId = usize;
 
+/// The registers returned by a CPUID leaf.
+#[derive(Eq, PartialEq, Debug, Default, Clone, Copy)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// Interrupt moderation tunables for the network drivers.
+///
+/// A driver handles its interrupts one by one until they arrive faster than
+/// `poll_threshold` per second, it then masks the interrupt and polls the
+/// device instead. After `idle_polls` polls in a row that found no work it
+/// unmasks the interrupt and goes back to waiting for it.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct NetModeration {
+    /// Interrupts per second above which a driver switches to polling.
+    pub poll_threshold: u64,
+    /// Consecutive empty polls before a driver switches back to interrupts.
+    pub idle_polls: u64,
+}
+
+impl NetModeration {
+    /// Values the kernel starts with.
+    pub const DEFAULT: NetModeration = NetModeration {
+        poll_threshold: 10_000,
+        idle_polls: 64,
+    };
+}
+
+impl Default for NetModeration {
+    fn default() -> Self {
+        NetModeration::DEFAULT
+    }
+}
+
+/// The kernel stacks of a core.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+#[repr(u64)]
+pub enum KernelStack {
+    /// Used while handling system calls.
+    Syscall = 0,
+    /// Used while handling interrupts and exceptions.
+    Interrupt = 1,
+    /// Used for unrecoverable faults (double faults etc.).
+    Fault = 2,
+    /// Used while handling NMIs (profiler samples).
+    Nmi = 3,
+}
+
+impl KernelStack {
+    /// All kernel stacks of a core.
+    pub const ALL: [KernelStack; 4] = [
+        KernelStack::Syscall,
+        KernelStack::Interrupt,
+        KernelStack::Fault,
+        KernelStack::Nmi,
+    ];
+}
+
+/// Commands for the kernel profiler.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+#[repr(u64)]
+pub enum ProfileCommand {
+    /// Start sampling on all cores.
+    Start = 0,
+    /// Stop sampling (keeps the samples).
+    Stop = 1,
+    /// Print the samples on the serial console and clear them.
+    Dump = 2,
+}
+
+impl ProfileCommand {
+    /// All profiler commands.
+    pub const ALL: [ProfileCommand; 3] = [
+        ProfileCommand::Start,
+        ProfileCommand::Stop,
+        ProfileCommand::Dump,
+    ];
+}
+
+/// How much of a kernel stack was used.
+#[derive(Eq, PartialEq, Debug, Default, Clone, Copy)]
+pub struct StackUsage {
+    /// Deepest the stack got so far (in bytes).
+    pub max_used: u64,
+    /// Size of the stack (in bytes).
+    pub size: u64,
+}
+
 #[derive(Serialize, Deserialize, Ord, PartialOrd, Eq, PartialEq, Debug)]
 pub struct CpuThread {
     /// ID the thread, global within a system.
@@ -33,3 +127,308 @@ pub struct CpuThread {
     /// ID of the thread (relative to the core (usually either 0 or 1)).
     pub thread_id: ThreadId,
 }
+
+/// Identifies the build of the running kernel.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+pub struct BuildInfo {
+    /// Git revision (ends with `-dirty` if the tree had uncommitted changes).
+    pub git_version: String,
+    /// Cargo build profile (`debug` or `release`).
+    pub build: String,
+    /// Kernel profile (`tiny`, `full`, `benchmark` or `custom`).
+    pub profile: String,
+    /// Enabled cargo features.
+    pub features: Vec<String>,
+    /// Version of the compiler.
+    pub rustc: String,
+}
+
+/// What `SystemOperation::GetInfo` describes.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+#[repr(u64)]
+pub enum InfoKind {
+    /// The build of the kernel ([`BuildInfo`]).
+    Build = 0,
+    /// The machine the kernel runs on ([`HardwareInfo`]).
+    Hardware = 1,
+    /// The TSC offsets of the cores ([`ClockInfo`]).
+    Clock = 2,
+    /// The devices the kernel found (a `Vec` of [`DeviceInfo`]).
+    Devices = 3,
+    /// The speculative execution mitigations ([`MitigationInfo`]).
+    Mitigations = 4,
+    /// Power management and energy readings ([`PowerInfo`]).
+    Power = 5,
+}
+
+impl InfoKind {
+    /// All kinds of information.
+    pub const ALL: [InfoKind; 6] = [
+        InfoKind::Build,
+        InfoKind::Hardware,
+        InfoKind::Clock,
+        InfoKind::Devices,
+        InfoKind::Mitigations,
+        InfoKind::Power,
+    ];
+}
+
+/// A set of speculative execution mitigations.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone, Copy, Default)]
+pub struct MitigationSet {
+    /// Indirect branch restricted speculation (IBRS, with STIBP) on all
+    /// cores, in the kernel and in user-space (Spectre v2).
+    pub ibrs: bool,
+    /// Indirect branch predictor barrier (IBPB) when a core switches to
+    /// another address space (Spectre v2 across processes).
+    pub ibpb: bool,
+    /// Clear the CPU buffers (VERW) on every return to user-space (MDS).
+    pub mds: bool,
+}
+
+/// The speculative execution mitigations of the kernel (`mitigations` on
+/// the command line).
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone, Copy)]
+pub struct MitigationInfo {
+    /// What the kernel does.
+    pub active: MitigationSet,
+    /// What the CPU supports (`mds` is false if the CPU isn't affected).
+    pub available: MitigationSet,
+    /// The CPU has enhanced IBRS (IBRS that is cheap to leave on).
+    pub enhanced_ibrs: bool,
+}
+
+/// How the kernel sets the frequency of the cores (P-states, see
+/// `System::set_power_policy`).
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone, Copy)]
+pub enum Governor {
+    /// The kernel leaves the frequency to the firmware and the CPU
+    /// (default).
+    Firmware,
+    /// The highest frequency (with turbo if the CPU has it).
+    Performance,
+    /// The lowest frequency.
+    Powersave,
+    /// A fixed ratio, the frequency is the ratio times the bus clock
+    /// (usually 100 MHz).
+    Fixed(u8),
+}
+
+impl Governor {
+    /// The governor as system call arguments (kind, ratio).
+    pub fn to_args(self) -> (u64, u64) {
+        match self {
+            Governor::Firmware => (0, 0),
+            Governor::Performance => (1, 0),
+            Governor::Powersave => (2, 0),
+            Governor::Fixed(ratio) => (3, ratio as u64),
+        }
+    }
+
+    /// Parses the system call arguments of [`Governor::to_args`].
+    pub fn from_args(kind: u64, ratio: u64) -> Option<Governor> {
+        match (kind, ratio) {
+            (0, _) => Some(Governor::Firmware),
+            (1, _) => Some(Governor::Performance),
+            (2, _) => Some(Governor::Powersave),
+            (3, ratio) if ratio <= u8::MAX as u64 => Some(Governor::Fixed(ratio as u8)),
+            _ => None,
+        }
+    }
+}
+
+/// What cores do when they have nothing to run (C-states, see
+/// `System::set_power_policy`).
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone, Copy)]
+#[repr(u64)]
+pub enum IdleState {
+    /// Spin, wakes up the fastest but doesn't save energy.
+    Poll = 0,
+    /// Halt the core (`hlt`, C1), the default.
+    Halt = 1,
+    /// The deepest C-state the CPU has (`mwait`).
+    Mwait = 2,
+}
+
+impl IdleState {
+    /// All idle states, from the shallowest to the deepest.
+    pub const ALL: [IdleState; 3] = [IdleState::Poll, IdleState::Halt, IdleState::Mwait];
+}
+
+/// Energy a package used since boot (measured with RAPL).
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone, Copy)]
+pub struct PackageEnergy {
+    pub package: PackageId,
+    /// Energy of the package (cores, caches, uncore) in microjoules.
+    pub package_uj: u64,
+    /// Energy of the DRAM of the package in microjoules (not every CPU
+    /// measures it).
+    pub dram_uj: Option<u64>,
+}
+
+/// The power management settings of the kernel and the energy the machine
+/// used.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+pub struct PowerInfo {
+    pub governor: Governor,
+    pub idle: IdleState,
+    /// Lowest and highest (non-turbo) ratio of the cores, 0 if the kernel
+    /// can't set the frequency.
+    pub min_ratio: u8,
+    pub max_ratio: u8,
+    /// Highest turbo ratio (`max_ratio` without turbo).
+    pub turbo_ratio: u8,
+    /// Energy of every package, empty if the CPU doesn't have RAPL.
+    pub energy: Vec<PackageEnergy>,
+}
+
+/// A CPU cache (as reported by CPUID).
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+pub struct CacheInfo {
+    /// Cache level (1 for L1 etc.).
+    pub level: u8,
+    /// `data`, `instruction` or `unified`.
+    pub kind: String,
+    /// Size in bytes.
+    pub size: u64,
+    /// Maximum number of hardware threads that share it.
+    pub shared_by: u64,
+}
+
+/// A NUMA node.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    /// Number of hardware threads.
+    pub threads: u64,
+    /// Physical memory (in bytes) the kernel manages on the node.
+    pub memory: u64,
+}
+
+/// A PCI function.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    /// Class and sub-class code.
+    pub class: u8,
+    pub subclass: u8,
+}
+
+/// Identifies a device (see [`DeviceInfo`]).
+pub type DeviceId = u64;
+
+/// Where the kernel found a device.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+pub enum DeviceLocation {
+    /// A PCI function.
+    Pci { bus: u8, device: u8, function: u8 },
+    /// Described by an ACPI table.
+    Acpi { table: String },
+    /// A legacy device of the platform that can't be enumerated.
+    Platform,
+}
+
+/// Memory or I/O ports a device decodes.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone, Copy)]
+pub enum DeviceResource {
+    /// Physical memory (e.g., a PCI memory BAR).
+    Memory {
+        base: u64,
+        size: u64,
+    },
+    IoPorts {
+        base: u16,
+        count: u16,
+    },
+}
+
+/// Who drives a device.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+pub enum DeviceDriver {
+    /// Nobody, a process can claim it.
+    None,
+    /// A driver of the kernel (its name).
+    Kernel(String),
+    /// The process with this pid (`Process::claim_device`).
+    Process(u64),
+}
+
+/// A device the kernel found.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+pub struct DeviceInfo {
+    pub id: DeviceId,
+    /// What it is (e.g., `vmxnet3`, `i8042`), a PCI vendor and device id
+    /// (`8086:100e`) if we don't know.
+    pub name: String,
+    pub location: DeviceLocation,
+    /// PCI vendor, device and class code (0 for other devices).
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub resources: Vec<DeviceResource>,
+    pub driver: DeviceDriver,
+}
+
+/// Describes the machine the kernel runs on.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone, Default)]
+pub struct HardwareInfo {
+    /// Vendor and product name of the machine (from SMBIOS, empty if there
+    /// is no SMBIOS table).
+    pub system: String,
+    /// Vendor and version of the firmware (from SMBIOS).
+    pub firmware: String,
+    /// CPU model (CPUID brand string).
+    pub cpu_model: String,
+    /// CPUID family, model and stepping of the CPU.
+    pub cpu_family: u32,
+    pub cpu_model_id: u32,
+    pub cpu_stepping: u32,
+    /// Microcode revision of the boot core.
+    pub microcode: u64,
+    pub caches: Vec<CacheInfo>,
+    /// NUMA nodes (one node without NUMA information).
+    pub nodes: Vec<NodeInfo>,
+    /// Physical memory (in bytes) the kernel manages.
+    pub memory: u64,
+    pub pci_devices: Vec<PciDevice>,
+}
+
+/// The TSC offset of a core.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+pub struct CoreClock {
+    pub core: CoreId,
+    /// How many cycles the TSC of the core is ahead of the reference core.
+    pub offset: i64,
+    /// How far off `offset` may be (in cycles).
+    pub uncertainty: u64,
+}
+
+/// The TSC offsets the kernel measured at boot (and the correction of its
+/// wall clock).
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone, Default)]
+pub struct ClockInfo {
+    /// The core whose TSC the others are measured against.
+    pub reference: CoreId,
+    pub cores: Vec<CoreClock>,
+    /// Nanoseconds the kernel's wall clock is ahead of the real-time clock
+    /// (0 unless it was synchronized with an SNTP server).
+    pub wall_offset: i64,
+}
+
+impl ClockInfo {
+    /// Corrects the timestamp `tsc` taken on `core` to the TSC of the
+    /// reference core, so timestamps of different cores can be compared.
+    pub fn correct(&self, core: CoreId, tsc: u64) -> u64 {
+        let offset = self
+            .cores
+            .iter()
+            .find(|c| c.core == core)
+            .map_or(0, |c| c.offset);
+        (tsc as i64).wrapping_sub(offset) as u64
+    }
+}
@@ -14,31 +14,100 @@ use x86::bits64::rflags::RFlags;
 /// that facilitates IRQ/trap delivery and emulation of critical sections
 /// for a user-space scheduler.
 ///
+/// The kernel initializes the area before a core starts executing the process
+/// (including [`VirtualCpu::VERSION`], which user-space checks to make sure
+/// both sides agree on the layout). Both sides only access it through the
+/// methods below.
+///
 /// # Important
 /// This struct is referenced by several assembly code pieces through the kernel
 /// and in [vibrio]. Care must be taken to adjust them after any changes to
-/// this struct.
+/// this struct (and to bump [`VirtualCpu::VERSION`]).
 #[repr(C, packed)]
 #[derive(Debug)]
 pub struct VirtualCpu {
     /// CPU state if interrupted while not disabled
-    pub enabled_state: SaveArea,
+    enabled_state: SaveArea,
     /// PC critical region
-    pub pc_disabled: (VAddr, VAddr),
+    pc_disabled: (VAddr, VAddr),
     /// Function pointer to the entry point for upcalls.
-    pub resume_with_upcall: VAddr,
+    resume_with_upcall: VAddr,
     /// Are we in a critical section?
-    pub is_disabled: bool,
+    is_disabled: bool,
     /// An upcall needs to be executed.
-    pub has_pending_upcall: bool,
+    has_pending_upcall: bool,
+    /// Layout version of this struct.
+    version: u64,
+    /// Vector of the upcall that arrived while disabled.
+    pending_vector: u64,
+    /// Exception (error code) of the upcall that arrived while disabled.
+    pending_exception: u64,
+    /// How often an upcall was held back for longer than the kernel allows.
+    overdue_upcalls: u64,
 }
 
 impl VirtualCpu {
+    /// Version of the layout, bumped with every change to it (or to
+    /// [`SaveArea`]).
+    pub const VERSION: u64 = 2;
+
+    /// An area for a core that starts executing at `upcall_entry`.
+    pub const fn new(upcall_entry: VAddr) -> VirtualCpu {
+        VirtualCpu {
+            enabled_state: SaveArea::empty(),
+            pc_disabled: (VAddr(0), VAddr(0)),
+            resume_with_upcall: upcall_entry,
+            is_disabled: false,
+            has_pending_upcall: false,
+            version: VirtualCpu::VERSION,
+            pending_vector: 0,
+            pending_exception: 0,
+            overdue_upcalls: 0,
+        }
+    }
+
+    /// Layout version the kernel initialized the area with.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// CPU state if we were interrupted while upcalls were enabled.
+    pub fn enabled_state(&self) -> &SaveArea {
+        &self.enabled_state
+    }
+
+    pub fn set_enabled_state(&mut self, state: &SaveArea) {
+        self.enabled_state = *state;
+    }
+
+    /// Where the kernel delivers upcalls to.
+    pub fn upcall_entry(&self) -> VAddr {
+        self.resume_with_upcall
+    }
+
+    pub fn set_upcall_entry(&mut self, entry: VAddr) {
+        self.resume_with_upcall = entry;
+    }
+
+    /// Code between `start` and `end` (inclusive) runs with upcalls disabled.
+    pub fn critical_region(&self) -> (VAddr, VAddr) {
+        self.pc_disabled
+    }
+
+    pub fn set_critical_region(&mut self, start: VAddr, end: VAddr) {
+        self.pc_disabled = (start, end);
+    }
+
     /// Is the vCPU currently disabled or executing in a critical section?
     pub fn upcalls_disabled(&self, rip: VAddr) -> bool {
         self.is_disabled || self.pc_disabled.0 <= rip && rip <= self.pc_disabled.1
     }
 
+    /// Is the vCPU disabled (regardless of the critical region)?
+    pub fn is_disabled(&self) -> bool {
+        self.is_disabled
+    }
+
     pub fn enable_upcalls(&mut self) {
         self.is_disabled = false;
     }
@@ -46,6 +115,45 @@ impl VirtualCpu {
     pub fn disable_upcalls(&mut self) {
         self.is_disabled = true;
     }
+
+    /// Does an upcall wait to be delivered?
+    pub fn has_pending_upcall(&self) -> bool {
+        self.has_pending_upcall
+    }
+
+    pub fn set_pending_upcall(&mut self, pending: bool) {
+        self.has_pending_upcall = pending;
+    }
+
+    /// Notes an upcall that arrived while disabled (only the first one is
+    /// kept until it is delivered).
+    pub fn defer_upcall(&mut self, vector: u64, exception: u64) {
+        if !self.has_pending_upcall {
+            self.pending_vector = vector;
+            self.pending_exception = exception;
+            self.has_pending_upcall = true;
+        }
+    }
+
+    /// Removes the deferred upcall, returns its `(vector, exception)`.
+    pub fn take_pending_upcall(&mut self) -> Option<(u64, u64)> {
+        if self.has_pending_upcall {
+            self.has_pending_upcall = false;
+            Some((self.pending_vector, self.pending_exception))
+        } else {
+            None
+        }
+    }
+
+    /// How often the vCPU stayed disabled for too long while an upcall was
+    /// pending (the kernel still waits for it to enable upcalls).
+    pub fn overdue_upcalls(&self) -> u64 {
+        self.overdue_upcalls
+    }
+
+    pub fn count_overdue_upcall(&mut self) {
+        self.overdue_upcalls += 1;
+    }
 }
 
 /// Memory area that is used by a CPU/scheduler to capture and save
@@ -57,7 +165,7 @@ impl VirtualCpu {
 /// this struct.
 /// Grep for SaveArea to find all occurences.
 #[repr(C, packed)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub struct SaveArea {
     /// 0: ret val, not preserved, holds 1st ret arg (error code)
     /// for syscalls
@@ -162,6 +270,21 @@ impl SaveArea {
     pub fn set_syscall_ret2(&mut self, val: u64) {
         self.rsi = val;
     }
+
+    /// The error code of a system call (see [`SaveArea::set_syscall_error_code`]).
+    pub fn syscall_error_code(&self) -> u64 {
+        self.rax
+    }
+
+    /// The 1st return argument of a system call.
+    pub fn syscall_ret1(&self) -> u64 {
+        self.rdi
+    }
+
+    /// The 2nd return argument of a system call.
+    pub fn syscall_ret2(&self) -> u64 {
+        self.rsi
+    }
 }
 
 impl fmt::Debug for SaveArea {
@@ -195,3 +318,59 @@ rip = {:>#18x} rflags = {:?}",
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Offset of `field` in `base` (in bytes).
+    fn offset<T, F>(base: &T, field: *const F) -> usize {
+        field as usize - base as *const T as usize
+    }
+
+    /// The assembly in the kernel (`exec.S`, `isr.S`) and in vibrio
+    /// (`upcalls::resume`) uses these offsets.
+    #[test]
+    fn save_area_layout() {
+        let sa = SaveArea::empty();
+        assert_eq!(offset(&sa, core::ptr::addr_of!(sa.rax)), 0 * 8);
+        assert_eq!(offset(&sa, core::ptr::addr_of!(sa.rsi)), 4 * 8);
+        assert_eq!(offset(&sa, core::ptr::addr_of!(sa.rdi)), 5 * 8);
+        assert_eq!(offset(&sa, core::ptr::addr_of!(sa.rsp)), 7 * 8);
+        assert_eq!(offset(&sa, core::ptr::addr_of!(sa.r15)), 15 * 8);
+        assert_eq!(offset(&sa, core::ptr::addr_of!(sa.rip)), 16 * 8);
+        assert_eq!(offset(&sa, core::ptr::addr_of!(sa.rflags)), 17 * 8);
+        assert_eq!(offset(&sa, core::ptr::addr_of!(sa.gs)), 18 * 8);
+        assert_eq!(offset(&sa, core::ptr::addr_of!(sa.fs)), 19 * 8);
+        assert_eq!(offset(&sa, core::ptr::addr_of!(sa.fxsave)), 24 * 8);
+        assert_eq!(core::mem::size_of::<SaveArea>(), 24 * 8 + 512);
+    }
+
+    /// Changing any of these requires a new `VirtualCpu::VERSION`.
+    #[test]
+    fn vcpu_layout() {
+        let vcpu = VirtualCpu::new(VAddr(0x1000));
+        assert_eq!(vcpu.version(), VirtualCpu::VERSION);
+        assert_eq!(offset(&vcpu, core::ptr::addr_of!(vcpu.enabled_state)), 0);
+        assert_eq!(offset(&vcpu, core::ptr::addr_of!(vcpu.pc_disabled)), 704);
+        assert_eq!(
+            offset(&vcpu, core::ptr::addr_of!(vcpu.resume_with_upcall)),
+            720
+        );
+        assert_eq!(offset(&vcpu, core::ptr::addr_of!(vcpu.is_disabled)), 728);
+        assert_eq!(
+            offset(&vcpu, core::ptr::addr_of!(vcpu.has_pending_upcall)),
+            729
+        );
+        assert_eq!(offset(&vcpu, core::ptr::addr_of!(vcpu.version)), 730);
+        assert_eq!(offset(&vcpu, core::ptr::addr_of!(vcpu.pending_vector)), 738);
+        assert_eq!(
+            offset(&vcpu, core::ptr::addr_of!(vcpu.pending_exception)),
+            746
+        );
+        assert_eq!(
+            offset(&vcpu, core::ptr::addr_of!(vcpu.overdue_upcalls)),
+            754
+        );
+    }
+}
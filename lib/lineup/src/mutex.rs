@@ -1,13 +1,13 @@
 // Copyright © 2021 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-use core::cell::Cell;
+use core::cell::{Cell, RefCell};
 use core::hint::spin_loop;
 use core::ptr;
 use core::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::threads::ThreadId;
-use crate::tls2::{Environment, ThreadControlBlock};
+use crate::tls2::{Environment, NoPreempt, ThreadControlBlock};
 
 use crossbeam_queue::ArrayQueue;
 use crossbeam_utils::CachePadded;
@@ -47,6 +47,7 @@ impl Mutex {
                 is_spin,
                 waitlist: ArrayQueue::new(64),
                 counter: CachePadded::new(AtomicUsize::new(0)),
+                no_preempt: RefCell::new(None),
             },
         }
     }
@@ -96,6 +97,10 @@ struct MutexInner {
     /// A value of 1: The mutex is locked, no waiters.
     /// A value of >1: The mutex is locked and has (or will have) waiters in waitlist.
     counter: CachePadded<AtomicUsize>,
+
+    /// Keeps the holder of a spinning lock from being preempted (a
+    /// high-priority thread on the same core would spin forever).
+    no_preempt: RefCell<Option<NoPreempt>>,
 }
 
 impl MutexInner {
@@ -106,6 +111,7 @@ impl MutexInner {
             "Locking mutex against itself."
         );
 
+        let no_preempt = NoPreempt::new();
         let counter = self.counter.load(Ordering::Relaxed);
         loop {
             if counter != 0 {
@@ -133,6 +139,9 @@ impl MutexInner {
         self.owner.replace(Some(tid));
         self.lwp_ptr
             .replace(thread_state.rump_lwp.load(Ordering::SeqCst));
+        if self.is_spin {
+            self.no_preempt.replace(Some(no_preempt));
+        }
         true
     }
 
@@ -146,6 +155,8 @@ impl MutexInner {
         }
 
         assert!(self.is_kmutex);
+        // The holder spins in `exit` until we're in the waitlist
+        let _no_preempt = NoPreempt::new();
         if self.counter.fetch_add(1, Ordering::SeqCst) != 0 {
             // Didn't get the lock:
             let mut rid = 0;
@@ -181,6 +192,7 @@ impl MutexInner {
     }
 
     fn enter_nowrap(&self) {
+        let no_preempt = NoPreempt::new();
         loop {
             // Wait till lock is free (counter is 0):
             #[cfg(feature = "latency")]
@@ -209,6 +221,7 @@ impl MutexInner {
         self.owner.replace(Some(tid));
         self.lwp_ptr
             .replace(thread_state.rump_lwp.load(Ordering::SeqCst));
+        self.no_preempt.replace(Some(no_preempt));
     }
 
     fn exit(&self) {
@@ -216,6 +229,8 @@ impl MutexInner {
 
         let _prev = self.owner.replace(None);
         let _prev_ptr = self.lwp_ptr.replace(ptr::null());
+        // Preemptible again once the lock is released
+        let _no_preempt = self.no_preempt.take();
 
         let v = self.counter.fetch_sub(1, Ordering::SeqCst);
         if v < 1 {
@@ -6,6 +6,8 @@
 //! Has the following properties:
 //! * Cooperative scheduling (threads can yield voluntarily)
 //! * Round robin scheduling (per-core)
+//! * High-priority threads (`SchedulingClass::LowLatency`) run before the
+//!   others, upcalls for their interrupts preempt normal threads
 //! * Per core run and wait lists
 //! * Thread affinity can be defined upon thread creation (currently no migration)
 //! * Waitlist is sorted according to thread wake-up times.
@@ -17,6 +19,7 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 
 use arr_macro::arr;
 use fringe::generator::Generator;
+use kpi::process::SchedulingClass;
 use log::{error, trace};
 use rawtime::Instant;
 
@@ -33,6 +36,10 @@ use crate::{CoreId, IrqVector};
 /// In case we need to lock across multiple `SchedulerCoreState`
 /// lower `core_id` should be locked first.
 struct SchedulerCoreState {
+    /// Per-core list of runnable high-priority threads, they run before the
+    /// threads in `runnable`.
+    urgent: spin::Mutex<VecDeque<ThreadId>>,
+
     /// Per-core list of runnable threads.
     ///
     /// Protected by a mutex since anyone could put threads here.
@@ -47,6 +54,7 @@ struct SchedulerCoreState {
 impl SchedulerCoreState {
     fn new() -> Self {
         SchedulerCoreState {
+            urgent: spin::Mutex::new(VecDeque::new()),
             runnable: spin::Mutex::new(VecDeque::with_capacity(SmpScheduler::MAX_THREADS)),
             waiting: spin::Mutex::new(Vec::with_capacity(SmpScheduler::MAX_THREADS)),
         }
@@ -185,9 +193,17 @@ impl<'a> SmpScheduler<'a> {
     }
 
     /// Marks a thread as sunnable by inserting it into
-    /// `runnable`.
+    /// `runnable` (or `urgent` for high-priority threads).
     fn mark_runnable(&self, tid: ThreadId, affinity: CoreId) {
-        self.per_core[affinity].runnable.lock().push_back(tid);
+        let class = self
+            .threads
+            .lock()
+            .get(&tid)
+            .map_or(SchedulingClass::Normal, |thread| thread.class);
+        match class {
+            SchedulingClass::LowLatency => self.per_core[affinity].urgent.lock().push_back(tid),
+            _ => self.per_core[affinity].runnable.lock().push_back(tid),
+        }
     }
 
     /// Make a thread no longer runnable.
@@ -196,6 +212,10 @@ impl<'a> SmpScheduler<'a> {
     /// This is O(n) but it happens rarely(?); only
     /// call it if tid is different from current thread.
     fn mark_unrunnable(&self, tid: ThreadId, affinity: CoreId) {
+        self.per_core[affinity]
+            .urgent
+            .lock()
+            .retain(|&ltid| ltid != tid);
         let mut runnable = self.per_core[affinity].runnable.lock();
         runnable.retain(|&ltid| ltid != tid);
     }
@@ -344,6 +364,21 @@ impl<'a> SmpScheduler<'a> {
                     None => YieldResume::Completed,
                 }
             }
+            Some(YieldRequest::SetClass(class)) => {
+                trace!("Thread {} is now in {:?}", tid, class);
+                let vector = {
+                    let mut threads = self.threads.lock();
+                    let thread = threads.get_mut(&tid).expect("Can't find thread");
+                    thread.class = class;
+                    thread.interrupt_vector
+                };
+                // Upcalls for its IRQ preempt normal threads now
+                if let Some(vector) = vector {
+                    tls2::Environment::scheduler()
+                        .set_urgent_irq(vector, class == SchedulingClass::LowLatency);
+                }
+                YieldResume::Completed
+            }
             Some(YieldRequest::Spawn(function, arg, affinity, irq_vector)) => {
                 trace!("self.spawn {:?} {:p}", function, arg);
                 let tid = self
@@ -440,7 +475,11 @@ impl<'a> SmpScheduler<'a> {
             self.check_wakeups(core_id);
 
             // The next thread ID we want to run
-            let next_tid = self.per_core[core_id].runnable.lock().pop_front();
+            let next_tid = self.per_core[core_id]
+                .urgent
+                .lock()
+                .pop_front()
+                .or_else(|| self.per_core[core_id].runnable.lock().pop_front());
             match next_tid {
                 Some(tid) => {
                     let mut generator = self
@@ -938,4 +977,97 @@ mod tests {
         assert!(t2_duration >= t2_waittime);
         assert!(t2_duration <= t2_waittime + Duration::from_millis(1));
     }
+
+    /// Checks that high-priority threads run before the normal threads of
+    /// their core.
+    #[test]
+    fn high_priority_threads_run_first() {
+        let s: Arc<SmpScheduler> = Default::default();
+        let order: Arc<ArrayQueue<char>> = Arc::new(ArrayQueue::new(6));
+        let order1 = order.clone();
+        let order2 = order.clone();
+
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                for _i in 0..3 {
+                    let _r = order1.push('n');
+                    Environment::thread().relinquish();
+                }
+            },
+            ptr::null_mut(),
+            0,
+            None,
+        );
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                Environment::thread().set_scheduling_class(SchedulingClass::LowLatency);
+                for _i in 0..3 {
+                    let _r = order2.push('u');
+                    // Goes back to the front of the line
+                    Environment::thread().relinquish();
+                }
+            },
+            ptr::null_mut(),
+            0,
+            None,
+        );
+
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+        s.run(&scb);
+
+        let order: Vec<char> = core::iter::from_fn(|| order.pop()).collect();
+        assert_eq!(order, ['n', 'u', 'u', 'u', 'n', 'n']);
+    }
+
+    /// Checks which threads an upcall for an IRQ preempts.
+    #[test]
+    fn upcalls_preempt_normal_threads() {
+        const URGENT_IRQ: IrqVector = 0x2a;
+        const OTHER_IRQ: IrqVector = 0x24;
+
+        let s: Arc<SmpScheduler> = Default::default();
+        let preempts: Arc<ArrayQueue<bool>> = Arc::new(ArrayQueue::new(6));
+        let preempts1 = preempts.clone();
+
+        // A high-priority IRQ thread
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                Environment::thread().set_scheduling_class(SchedulingClass::LowLatency);
+                Environment::thread().block();
+            },
+            ptr::null_mut(),
+            0,
+            Some(URGENT_IRQ),
+        );
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| unsafe {
+                let scb = Environment::scheduler();
+                let me = Environment::thread() as *const ThreadControlBlock;
+                let _r = preempts1.push(scb.preempts(URGENT_IRQ, me));
+                let _r = preempts1.push(scb.preempts(OTHER_IRQ, me));
+                let _r = preempts1.push(scb.preempts(URGENT_IRQ, ptr::null()));
+                {
+                    let _no_preempt = tls2::NoPreempt::new();
+                    let _r = preempts1.push(scb.preempts(URGENT_IRQ, me));
+                }
+                let _r = preempts1.push(scb.preempts(URGENT_IRQ, me));
+
+                Environment::thread().set_scheduling_class(SchedulingClass::LowLatency);
+                let _r = preempts1.push(scb.preempts(URGENT_IRQ, me));
+            },
+            ptr::null_mut(),
+            0,
+            None,
+        );
+
+        let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+        s.run(&scb);
+
+        let preempts: Vec<bool> = core::iter::from_fn(|| preempts.pop()).collect();
+        assert_eq!(preempts, [true, false, false, false, true, false]);
+    }
 }
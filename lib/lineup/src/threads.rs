@@ -3,9 +3,11 @@
 
 use alloc::vec::Vec;
 use core::hash::{Hash, Hasher};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use core::{fmt, mem, ptr};
 
 use fringe::generator::{Generator, Yielder};
+use kpi::process::SchedulingClass;
 use rawtime::Instant;
 
 use crate::stack::LineupStack;
@@ -44,7 +46,10 @@ pub(crate) struct Thread {
     pub(crate) return_with: Option<YieldResume>,
 
     /// If thread is registered to wake up for the specific interrupt vector.
-    pub(crate) _interrupt_vector: Option<IrqVector>,
+    pub(crate) interrupt_vector: Option<IrqVector>,
+
+    /// Which run-queue the thread goes to when it becomes runnable.
+    pub(crate) class: SchedulingClass,
 
     /// Threads currently waiting (join, blocked) on us to exit.
     pub(crate) joinlist: Vec<(ThreadId, CoreId)>,
@@ -84,7 +89,7 @@ impl Thread {
         f: F,
         arg: *mut u8,
         upcalls: Upcalls,
-        _interrupt_vector: Option<IrqVector>,
+        interrupt_vector: Option<IrqVector>,
         tcb: *mut ThreadControlBlock<'static>,
     ) -> (
         Thread,
@@ -97,12 +102,16 @@ impl Thread {
         (*tcb).tid = tid;
         (*tcb).current_core = affinity;
         (*tcb).upcalls = upcalls;
+        (*tcb).class = SchedulingClass::Normal;
+        // Can't be preempted until it runs
+        (*tcb).preempt_off = AtomicUsize::new(1);
 
         let thread = Thread {
             id: tid,
             affinity,
             return_with: None,
-            _interrupt_vector,
+            interrupt_vector,
+            class: SchedulingClass::Normal,
             joinlist: Vec::with_capacity(crate::scheduler::SmpScheduler::MAX_THREADS),
             state: tcb,
         };
@@ -112,6 +121,9 @@ impl Thread {
                 &Yielder<YieldResume, YieldRequest>,
                 &'static Yielder<YieldResume, YieldRequest>,
             >(yielder));
+            tls2::Environment::thread()
+                .preempt_off
+                .fetch_sub(1, Ordering::SeqCst);
 
             // rump lwp switchproc stuff here
             let r = f(arg);
@@ -145,6 +157,8 @@ pub(crate) enum YieldRequest {
     RunnableList(Vec<ThreadId>),
     /// Wait until the thread with given ID is finished.
     JoinOn(ThreadId),
+    /// Move the thread to the run-queue of the given class.
+    SetClass(SchedulingClass),
     /// Spawn a new thread that runs the provided function and argument.
     Spawn(
         Option<unsafe extern "C" fn(arg1: *mut u8) -> *mut u8>,
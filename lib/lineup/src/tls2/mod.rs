@@ -18,12 +18,13 @@
 use alloc::vec::Vec;
 
 use core::ops::Add;
-use core::sync::atomic::{AtomicPtr, Ordering};
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
 use core::{mem, ptr};
 
 use fringe::generator::Yielder;
 
 use crossbeam_queue::ArrayQueue;
+use kpi::process::SchedulingClass;
 use rawtime::{Duration, Instant};
 
 use crate::stack::LineupStack;
@@ -91,6 +92,11 @@ pub struct ThreadControlBlock<'a> {
 
     /// The current errno variable (for libc compatibility).
     pub errno: i32,
+
+    /// Scheduling class of the thread (see `set_scheduling_class`).
+    pub(crate) class: SchedulingClass,
+    /// The thread can be preempted if this is 0 (see [`NoPreempt`]).
+    pub(crate) preempt_off: AtomicUsize,
 }
 
 impl<'a> ThreadControlBlock<'a> {
@@ -106,6 +112,8 @@ impl<'a> ThreadControlBlock<'a> {
             upcalls: Default::default(),
             rump_lwp: AtomicPtr::new(ptr::null_mut()),
             rumprun_lwp: ptr::null_mut(),
+            class: SchedulingClass::Normal,
+            preempt_off: AtomicUsize::new(1),
         };
 
         let (initial_tdata, tls_layout) = arch::get_tls_info();
@@ -153,7 +161,7 @@ impl<'a> ThreadControlBlock<'a> {
         tcb: *mut ThreadControlBlock<'static>,
     ) -> Option<ThreadId> {
         let request = YieldRequest::SpawnWithArgs(s, f, arg, core_id, irq_vector, tcb);
-        match self.yielder().suspend(request) {
+        match self.request(request) {
             YieldResume::Spawned(tid) => Some(tid),
             _ => None,
         }
@@ -166,7 +174,7 @@ impl<'a> ThreadControlBlock<'a> {
         core_id: CoreId,
     ) -> Option<ThreadId> {
         let request = YieldRequest::Spawn(f, arg, core_id, None);
-        match self.yielder().suspend(request) {
+        match self.request(request) {
             YieldResume::Spawned(tid) => Some(tid),
             _ => None,
         }
@@ -180,7 +188,7 @@ impl<'a> ThreadControlBlock<'a> {
         irq_vector: IrqVector,
     ) -> Option<ThreadId> {
         let request = YieldRequest::Spawn(f, arg, core_id, Some(irq_vector));
-        match self.yielder().suspend(request) {
+        match self.request(request) {
             YieldResume::Spawned(tid) => Some(tid),
             _ => None,
         }
@@ -192,7 +200,7 @@ impl<'a> ThreadControlBlock<'a> {
         arg: *mut u8,
     ) -> Option<ThreadId> {
         let request = YieldRequest::Spawn(f, arg, self.current_core, None);
-        match self.yielder().suspend(request) {
+        match self.request(request) {
             YieldResume::Spawned(tid) => Some(tid),
             _ => None,
         }
@@ -200,36 +208,60 @@ impl<'a> ThreadControlBlock<'a> {
 
     pub fn sleep(&self, d: Duration) {
         let request = YieldRequest::Timeout(Instant::now().add(d));
-        self.yielder().suspend(request);
+        self.request(request);
     }
 
     pub fn block(&self) {
         let request = YieldRequest::Unrunnable(Environment::tid());
-        self.yielder().suspend(request);
+        self.request(request);
     }
 
     pub fn make_runnable(&self, tid: ThreadId) {
         let request = YieldRequest::Runnable(tid);
-        self.yielder().suspend(request);
+        self.request(request);
     }
 
     pub fn make_all_runnable(&self, tids: Vec<ThreadId>) {
         let request = YieldRequest::RunnableList(tids);
-        self.yielder().suspend(request);
+        self.request(request);
     }
 
     pub fn make_unrunnable(&self, tid: ThreadId) {
         let request = YieldRequest::Unrunnable(tid);
-        self.yielder().suspend(request);
+        self.request(request);
     }
 
     pub fn join(&self, tid: ThreadId) {
         let request = YieldRequest::JoinOn(tid);
-        self.yielder().suspend(request);
+        self.request(request);
     }
 
     pub(crate) fn suspend(&self, request: YieldRequest) {
-        self.yielder().suspend(request);
+        self.request(request);
+    }
+
+    /// Hands `request` to the scheduler. The thread can't be preempted
+    /// while it switches to the scheduler and back.
+    fn request(&self, request: YieldRequest) -> YieldResume {
+        let _no_preempt = NoPreempt::of(self);
+        self.yielder().suspend(request)
+    }
+
+    /// Moves the thread to the run-queue of `class`.
+    ///
+    /// [`SchedulingClass::LowLatency`] threads run before all the normal
+    /// threads of their core. If one of them waits for an interrupt, the
+    /// upcall for it preempts the normal thread that runs (see
+    /// [`SchedulerControlBlock::preempts`]). This is independent of the
+    /// class of the core's executor in the kernel (see
+    /// `Process::set_scheduling_class`).
+    pub fn set_scheduling_class(&mut self, class: SchedulingClass) {
+        self.class = class;
+        self.request(YieldRequest::SetClass(class));
+    }
+
+    pub fn scheduling_class(&self) -> SchedulingClass {
+        self.class
     }
 
     pub fn relinquish(&self) {
@@ -237,6 +269,45 @@ impl<'a> ThreadControlBlock<'a> {
     }
 }
 
+/// Keeps the current thread from being preempted by an upcall while it's
+/// alive, e.g., while it holds a spinlock that a high-priority thread on the
+/// same core could spin on forever.
+///
+/// Doesn't do anything if we don't run on a lineup thread.
+#[derive(Debug)]
+pub struct NoPreempt {
+    tcb: *const ThreadControlBlock<'static>,
+}
+
+impl NoPreempt {
+    pub fn new() -> NoPreempt {
+        NoPreempt::of(unsafe { arch::get_tcb() })
+    }
+
+    fn of(tcb: *const ThreadControlBlock) -> NoPreempt {
+        if !tcb.is_null() {
+            unsafe { (*tcb).preempt_off.fetch_add(1, Ordering::SeqCst) };
+        }
+        NoPreempt {
+            tcb: tcb as *const ThreadControlBlock<'static>,
+        }
+    }
+}
+
+impl Default for NoPreempt {
+    fn default() -> Self {
+        NoPreempt::new()
+    }
+}
+
+impl Drop for NoPreempt {
+    fn drop(&mut self) {
+        if !self.tcb.is_null() {
+            unsafe { (*self.tcb).preempt_off.fetch_sub(1, Ordering::SeqCst) };
+        }
+    }
+}
+
 /// This is global scheduler-state. Every thread (and also non-threaded upcall handlers)
 /// can access this (ideally through the `gs` register).
 ///
@@ -257,6 +328,10 @@ pub struct SchedulerControlBlock {
 
     /// Core identifier of this scheduler state
     pub core_id: usize,
+
+    /// IRQ vectors handled by high-priority threads of the core (a bit per
+    /// vector).
+    urgent_irqs: [AtomicU64; 4],
 }
 
 impl SchedulerControlBlock {
@@ -267,8 +342,53 @@ impl SchedulerControlBlock {
             pending_irqs: ArrayQueue::new(4),
             rump_upcalls: AtomicPtr::new(ptr::null_mut()),
             core_id,
+            urgent_irqs: Default::default(),
         }
     }
+
+    /// Records whether the thread for IRQ `vector` is a high-priority
+    /// thread.
+    pub(crate) fn set_urgent_irq(&self, vector: IrqVector, urgent: bool) {
+        if let Some(word) = self.urgent_irqs.get(vector as usize / 64) {
+            let bit = 1 << (vector % 64);
+            if urgent {
+                word.fetch_or(bit, Ordering::Relaxed);
+            } else {
+                word.fetch_and(!bit, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn is_urgent_irq(&self, vector: IrqVector) -> bool {
+        self.urgent_irqs
+            .get(vector as usize / 64)
+            .map_or(false, |word| {
+                word.load(Ordering::Relaxed) & (1 << (vector % 64)) != 0
+            })
+    }
+
+    /// Should an upcall for IRQ `vector` preempt the thread it interrupted
+    /// (with the TCB `interrupted`, null if the scheduler itself ran)?
+    ///
+    /// Only IRQs of high-priority threads preempt, and only normal threads
+    /// that aren't in a [`NoPreempt`] section. The preempted thread should
+    /// give up the core (`relinquish`) once the upcall is done, it goes back
+    /// to the end of its run-queue.
+    ///
+    /// # Safety
+    /// `interrupted` has to be null or point to the TCB of a thread of this
+    /// core.
+    pub unsafe fn preempts(
+        &self,
+        vector: IrqVector,
+        interrupted: *const ThreadControlBlock,
+    ) -> bool {
+        self.is_urgent_irq(vector)
+            && !interrupted.is_null()
+            && (*interrupted).yielder.is_some()
+            && (*interrupted).class != SchedulingClass::LowLatency
+            && (*interrupted).preempt_off.load(Ordering::SeqCst) == 0
+    }
 }
 
 impl SchedulerControlBlock {
@@ -329,6 +449,14 @@ impl Environment {
         }
     }
 
+    /// Do we run on a thread of a lineup scheduler (which can block)?
+    pub fn has_thread() -> bool {
+        unsafe {
+            let tcb = arch::get_tcb() as *const ThreadControlBlock;
+            !tcb.is_null() && (*tcb).yielder.is_some()
+        }
+    }
+
     // TODO(correctness): this needs some hardending to avoid aliasing of ThreadState!
     pub fn scheduler<'a>() -> &'a SchedulerControlBlock {
         unsafe {
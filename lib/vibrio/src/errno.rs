@@ -0,0 +1,50 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! POSIX `errno` values for system call errors.
+//!
+//! The system call wrappers return a [`SystemCallError`] and also remember
+//! it per thread ([`last_error`]). The C compatibility layers (e.g., the rump
+//! runtime) need it as an `errno` value, numbered like NetBSD's `errno.h`.
+
+use kpi::SystemCallError;
+use lineup::tls2::Environment;
+
+pub use kpi::syscalls::{clear_last_error, last_error};
+
+/// Translates a system call error to the closest `errno` value.
+pub fn to_errno(error: SystemCallError) -> i32 {
+    match error {
+        SystemCallError::Ok => 0,
+        SystemCallError::PermissionError => 1,      // EPERM
+        SystemCallError::NotLogged => 5,            // EIO
+        SystemCallError::InternalError => 5,        // EIO
+        SystemCallError::InvalidCheckpoint => 8,    // ENOEXEC
+        SystemCallError::BadFileDescriptor => 9,    // EBADF
+        SystemCallError::OutOfMemory => 12,         // ENOMEM
+        SystemCallError::BadAddress => 14,          // EFAULT
+        SystemCallError::CoreUnavailable => 16,     // EBUSY
+        SystemCallError::VSpaceAlreadyMapped => 17, // EEXIST
+        SystemCallError::BadFlags => 22,            // EINVAL
+        SystemCallError::OffsetError => 22,         // EINVAL
+        SystemCallError::InvalidName => 22,         // EINVAL
+        SystemCallError::OpenFileLimit => 24,       // EMFILE
+        SystemCallError::NoSpace => 28,             // ENOSPC
+        SystemCallError::GangUnsatisfiable => 35,   // EAGAIN
+        SystemCallError::SymlinkLoop => 62,         // ELOOP
+        SystemCallError::NotSupported => 86,        // ENOTSUP
+        SystemCallError::Unknown => 5,              // EIO
+    }
+}
+
+/// The `errno` value of the last failed system call of the current thread
+/// (0 if none failed since [`clear_last_error`]).
+pub fn last_errno() -> i32 {
+    last_error().map_or(0, to_errno)
+}
+
+/// Sets the `errno` of the current thread (what C code reads through
+/// `__errno`) to the value of `error`.
+pub fn set_errno(error: SystemCallError) {
+    Environment::thread().errno = to_errno(error);
+}
@@ -0,0 +1,102 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! File I/O definitions of the [kpi] crate and a builder for opening files.
+
+use alloc::vec::Vec;
+
+pub use kpi::io::*;
+
+use crate::syscalls::Fs;
+use crate::SystemCallError;
+
+/// How to open a file (flags and the modes of a new file), like
+/// `std::fs::OpenOptions`:
+///
+/// ```ignore
+/// let fd = OpenOptions::new().append(true).create(true).open("/log")?;
+/// ```
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct OpenOptions {
+    flags: FileFlags,
+    modes: FileModes,
+}
+
+impl Default for OpenOptions {
+    fn default() -> OpenOptions {
+        OpenOptions::new()
+    }
+}
+
+impl OpenOptions {
+    /// Nothing set, new files are readable and writable.
+    pub fn new() -> OpenOptions {
+        OpenOptions {
+            flags: FileFlags::O_NONE,
+            modes: FileModes::S_IRUSR | FileModes::S_IWUSR,
+        }
+    }
+
+    fn set(&mut self, flag: FileFlags, on: bool) -> &mut OpenOptions {
+        self.flags.set(flag, on);
+        self
+    }
+
+    /// Open for reading (needs `S_IRUSR` on the file).
+    pub fn read(&mut self, read: bool) -> &mut OpenOptions {
+        self.set(FileFlags::O_RDONLY, read)
+    }
+
+    /// Open for writing (needs `S_IWUSR` on the file).
+    pub fn write(&mut self, write: bool) -> &mut OpenOptions {
+        self.set(FileFlags::O_WRONLY, write)
+    }
+
+    /// Open for writing, every write (without an offset) goes to the end of
+    /// the file, also if others write to it at the same time.
+    pub fn append(&mut self, append: bool) -> &mut OpenOptions {
+        self.set(FileFlags::O_WRONLY | FileFlags::O_APPEND, append)
+    }
+
+    /// Cut the file to length 0 when it's opened (needs `S_IWUSR`).
+    pub fn truncate(&mut self, truncate: bool) -> &mut OpenOptions {
+        self.set(FileFlags::O_TRUNC, truncate)
+    }
+
+    /// Create the file if it doesn't exist.
+    pub fn create(&mut self, create: bool) -> &mut OpenOptions {
+        self.set(FileFlags::O_CREAT, create)
+    }
+
+    /// Create the file, fail with `AlreadyPresent` if it exists.
+    pub fn create_new(&mut self, create_new: bool) -> &mut OpenOptions {
+        self.set(FileFlags::O_CREAT | FileFlags::O_EXCL, create_new)
+    }
+
+    /// Don't pass the descriptor to spawned children.
+    pub fn cloexec(&mut self, cloexec: bool) -> &mut OpenOptions {
+        self.set(FileFlags::O_CLOEXEC, cloexec)
+    }
+
+    /// The permissions of a file created by this open.
+    pub fn modes(&mut self, modes: FileModes) -> &mut OpenOptions {
+        self.modes = modes;
+        self
+    }
+
+    pub fn flags(&self) -> FileFlags {
+        self.flags
+    }
+
+    /// Opens `path`, returns the file descriptor.
+    pub fn open(&self, path: &str) -> Result<u64, SystemCallError> {
+        let mut pathname: Vec<u8> = Vec::with_capacity(path.len() + 1);
+        pathname.extend_from_slice(path.as_bytes());
+        pathname.push(0);
+        Fs::open(
+            pathname.as_ptr() as u64,
+            u64::from(self.flags),
+            u64::from(self.modes),
+        )
+    }
+}
@@ -12,6 +12,7 @@
     c_variadic,
     ptr_internals,
     llvm_asm,
+    global_asm,
     lang_items,
     thread_local
 )]
@@ -23,7 +24,10 @@ pub use kpi::*;
 extern crate arrayvec;
 extern crate lazy_static;
 
+pub mod errno;
+pub mod io;
 pub mod mem;
+pub mod sync;
 pub mod upcalls;
 pub mod vconsole;
 pub mod writer;
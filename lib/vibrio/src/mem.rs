@@ -20,7 +20,7 @@ use kpi::SystemCallError;
 
 use slabmalloc::*;
 
-use lineup::tls2::Environment;
+use lineup::tls2::{Environment, NoPreempt};
 
 use crossbeam_utils::CachePadded;
 
@@ -301,19 +301,24 @@ impl PerCoreAllocator {
     }
 }
 
+// The allocators are behind spinlocks, threads that hold one can't be
+// preempted (a high-priority thread on the same core could spin on it forever).
 unsafe impl GlobalAlloc for PerCoreAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _no_preempt = NoPreempt::new();
         // Get the core_id we are currently running on. Then return alloc of that SafeZoneAllocator.
         let sza = &PER_CORE_MEM_ALLOCATOR[Environment::core_id()];
         sza.alloc(layout)
     }
 
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _no_preempt = NoPreempt::new();
         let sza = &PER_CORE_MEM_ALLOCATOR[Environment::core_id()];
         sza.realloc(ptr, layout, new_size)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _no_preempt = NoPreempt::new();
         let sza = &PER_CORE_MEM_ALLOCATOR[Environment::core_id()];
         sza.dealloc(ptr, layout)
     }
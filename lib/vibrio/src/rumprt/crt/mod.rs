@@ -126,8 +126,9 @@ pub unsafe fn netbsd_userlevel_init() {
 pub fn install_vcpu_area() {
     use x86::bits64::paging::VAddr;
     let ctl = crate::syscalls::Process::vcpu_control_area().expect("Can't read vcpu control area.");
-    ctl.resume_with_upcall =
-        VAddr::from(crate::upcalls::upcall_while_enabled as *const fn() as u64);
+    ctl.set_upcall_entry(VAddr::from(
+        crate::upcalls::upcall_while_enabled as *const fn() as u64,
+    ));
 
     let upcall_begin_rip = crate::upcalls::resume as *const fn() as u64;
     extern "C" {
@@ -152,7 +153,7 @@ pub fn install_vcpu_area() {
         upcall_end_rip - upcall_begin_rip < 0x1000,
         "Unusually large code footprint of resume()?"
     );
-    ctl.pc_disabled = (VAddr::from(upcall_begin_rip), VAddr::from(upcall_end_rip));
+    ctl.set_critical_region(VAddr::from(upcall_begin_rip), VAddr::from(upcall_end_rip));
 }
 
 /// Entry point for libc.
@@ -4,10 +4,14 @@
 use super::{c_int, c_uint, c_ulong, c_void};
 
 use core::alloc::Layout;
+use core::convert::TryFrom;
+use core::time::Duration;
 use core::{fmt, ptr};
 
 use hashbrown::HashMap;
-use lineup::tls2::Environment;
+use kpi::process::{MapFlags, SchedulingClass};
+use kpi::system::NetModeration;
+use lineup::tls2::{Environment, NoPreempt};
 use log::{error, info, trace, warn};
 use spin::Mutex;
 use x86::current::paging::{PAddr, VAddr};
@@ -16,6 +20,8 @@ use x86::io;
 static PCI_CONF_ADDR: u16 = 0xcf8;
 static PCI_CONF_DATA: u16 = 0xcfc;
 
+// Threads that hold these can't be preempted (see `NoPreempt`), the IRQ
+// thread takes them too.
 static CONFSPACE_LOCK: Mutex<()> = Mutex::new(());
 static PADDR_CACHE: Mutex<Option<HashMap<VAddr, PAddr>>> = Mutex::new(None);
 
@@ -28,6 +34,7 @@ fn pci_bus_address(bus: u32, dev: u32, fun: u32, reg: i32) -> u32 {
 
 #[no_mangle]
 pub unsafe extern "C" fn rumpcomp_pci_iospace_init() -> c_int {
+    let _no_preempt = NoPreempt::new();
     PADDR_CACHE.lock().replace(HashMap::new());
     0
 }
@@ -42,6 +49,7 @@ pub unsafe extern "C" fn rumpcomp_pci_confread(
 ) -> c_int {
     let addr = pci_bus_address(bus, dev, fun, reg);
 
+    let _no_preempt = NoPreempt::new();
     let _l = CONFSPACE_LOCK.lock();
     io::outl(PCI_CONF_ADDR, addr);
     *value = io::inl(PCI_CONF_DATA);
@@ -75,6 +83,7 @@ pub unsafe extern "C" fn rumpcomp_pci_confwrite(
     );
 
     let addr = pci_bus_address(bus, dev, fun, reg);
+    let _no_preempt = NoPreempt::new();
     let _l = CONFSPACE_LOCK.lock();
     io::outl(PCI_CONF_ADDR, addr);
     io::outl(PCI_CONF_DATA, value);
@@ -147,9 +156,12 @@ pub unsafe extern "C" fn irq_handler(_arg1: *mut u8) -> *mut u8 {
     trace!("irq_handler");
 
     let thread = lineup::tls2::Environment::thread();
+    // Runs ahead of the application threads, its interrupts preempt them
+    thread.set_scheduling_class(SchedulingClass::LowLatency);
     thread.block(); // Wake up on next IRQ
 
     let mut nlock: i32 = 1;
+    let mut moderation = Moderation::new(IRQS[0].vector as u64);
     loop {
         let start = rawtime::Instant::now();
         super::rumpkern_sched(&nlock, None);
@@ -158,7 +170,101 @@ pub unsafe extern "C" fn irq_handler(_arg1: *mut u8) -> *mut u8 {
         super::rumpkern_unsched(&mut nlock, None);
 
         let thread = lineup::tls2::Environment::thread();
-        thread.block(); // Wake up on next IRQ
+        if moderation.poll_again(r != 0) {
+            thread.relinquish(); // Poll again once other threads had a go
+        } else {
+            thread.block(); // Wake up on next IRQ
+        }
+    }
+}
+
+/// The vector the device interrupt arrives with (e.g., for
+/// `System::irq_count`), `None` until the driver established its handler.
+pub fn irq_vector() -> Option<u8> {
+    let irq = unsafe { IRQS[0] };
+    irq.handler.and(u8::try_from(irq.vector + 32).ok())
+}
+
+/// Interval over which the interrupt rate is measured.
+const RATE_WINDOW: Duration = Duration::from_millis(10);
+
+/// NAPI-style interrupt moderation for a device (see
+/// [`NetModeration`] for the tunables).
+///
+/// At low load the IRQ thread blocks until the next interrupt. Once
+/// interrupts arrive faster than `poll_threshold` per second it masks the
+/// interrupt and keeps calling the handler (yielding in between) until it
+/// found no work `idle_polls` times in a row, then unmasks it again.
+struct Moderation {
+    /// The GSI of the device.
+    gsi: u64,
+    tunables: NetModeration,
+    polling: bool,
+    window_start: rawtime::Instant,
+    irqs: u64,
+    idle_polls: u64,
+}
+
+impl Moderation {
+    fn new(gsi: u64) -> Moderation {
+        Moderation {
+            gsi,
+            tunables: crate::syscalls::System::net_moderation().unwrap_or_default(),
+            polling: false,
+            window_start: rawtime::Instant::now(),
+            irqs: 0,
+            idle_polls: 0,
+        }
+    }
+
+    /// Records a run of the handler, `handled` is true if it found work.
+    ///
+    /// Returns true if the device should be polled again instead of waiting
+    /// for the next interrupt.
+    fn poll_again(&mut self, handled: bool) -> bool {
+        if self.polling {
+            self.idle_polls = if handled { 0 } else { self.idle_polls + 1 };
+            if self.idle_polls >= self.tunables.idle_polls {
+                trace!("IRQ thread: back to interrupts");
+                if let Err(e) = crate::syscalls::Irq::unmask(self.gsi) {
+                    warn!("Can't unmask GSI {}: {:?}", self.gsi, e);
+                }
+                Environment::thread().set_scheduling_class(SchedulingClass::LowLatency);
+                self.polling = false;
+                self.new_window();
+            }
+        } else {
+            if self.window_start.elapsed() > RATE_WINDOW {
+                self.new_window();
+            }
+            self.irqs += 1;
+
+            let windows_per_sec =
+                (Duration::from_secs(1).as_nanos() / RATE_WINDOW.as_nanos()) as u64;
+            if self.irqs * windows_per_sec > self.tunables.poll_threshold {
+                trace!("IRQ thread: switch to polling");
+                // The handler runs anyways, the interrupts would only wake
+                // us up in vain
+                if let Err(e) = crate::syscalls::Irq::mask(self.gsi) {
+                    warn!("Can't mask GSI {}: {:?}", self.gsi, e);
+                }
+                // Take turns with the other threads while polling, a
+                // high-priority thread that polls would starve them
+                Environment::thread().set_scheduling_class(SchedulingClass::Normal);
+                self.polling = true;
+                self.idle_polls = 0;
+            }
+        }
+
+        self.polling
+    }
+
+    /// Starts measuring the interrupt rate again.
+    fn new_window(&mut self) {
+        self.irqs = 0;
+        self.window_start = rawtime::Instant::now();
+        // Pick up changes to the tunables
+        self.tunables = crate::syscalls::System::net_moderation().unwrap_or(self.tunables);
     }
 }
 
@@ -176,13 +282,47 @@ pub unsafe extern "C" fn rumpcomp_pci_irq_establish(
     &mut IRQS[0] as *mut _ as *mut c_void
 }
 
+/// Makes us the driver of the device with the BAR at `addr` (the kernel
+/// only lets the driver map it, claiming needs the `devices` capability).
+fn claim_device(addr: u64) {
+    use kpi::system::DeviceResource;
+
+    let devices = match crate::syscalls::System::devices() {
+        Ok(devices) => devices,
+        Err(e) => {
+            warn!("Can't list devices: {:?}", e);
+            return;
+        }
+    };
+    let device = devices.iter().find(|device| {
+        device.resources.iter().any(|resource| match resource {
+            DeviceResource::Memory { base, size } => (*base..base + size).contains(&addr),
+            DeviceResource::IoPorts { .. } => false,
+        })
+    });
+    if let Some(device) = device {
+        if let Err(e) = crate::syscalls::Process::claim_device(device.id) {
+            warn!(
+                "Can't claim device {} ({}): {:?}",
+                device.id, device.name, e
+            );
+        }
+    }
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn rumpcomp_pci_map(addr: c_ulong, len: c_ulong) -> *mut c_void {
     error!("rumpcomp_pci_map {:#x} {:#x}", addr, len);
 
     let start = PAddr::from(addr);
+    claim_device(start.as_u64());
 
-    let r = crate::syscalls::VSpace::map_device(start.as_u64(), len as u64);
+    // BARs are device registers, they must not be cached
+    let r = crate::syscalls::VSpace::map_device_with_flags(
+        start.as_u64(),
+        len as u64,
+        MapFlags::READ_WRITE_USER | MapFlags::NOCACHE,
+    );
 
     match r {
         Ok((vaddr, _paddr)) => vaddr.as_u64() as *mut c_void,
@@ -210,6 +350,7 @@ pub unsafe extern "C" fn rumpcomp_pci_virt_to_mach(vaddr: *mut c_void) -> c_ulon
         PAddr::from(paddr_aligned)
     }
 
+    let _no_preempt = NoPreempt::new();
     PADDR_CACHE
         .lock()
         .as_mut()
@@ -248,6 +389,7 @@ pub unsafe extern "C" fn rumpcomp_pci_dmalloc(
 
     let layout = Layout::from_size_align_unchecked(size, size);
 
+    let _no_preempt = NoPreempt::new();
     let r = {
         let mut p = crate::mem::PAGER[Environment::core_id()].lock();
         (*p).allocate(layout)
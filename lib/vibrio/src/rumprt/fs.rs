@@ -10,7 +10,6 @@ use kpi::io::*;
 use kpi::FileOperation;
 
 use bitflags::*;
-use log::*;
 
 use crate::syscalls::Fs;
 
@@ -50,7 +49,7 @@ pub unsafe extern "C" fn rumpuser_open(name: *const i8, mode: c_int, fdp: *mut c
         flags = flags | FileFlags::O_CREAT;
     }
     if ((mode_mode & RumpFileFlags::RUMPUSER_OPEN_EXCL) == RumpFileFlags::RUMPUSER_OPEN_EXCL) {
-        error!("NRK does not support O_EXCL\n");
+        flags = flags | FileFlags::O_EXCL;
     }
 
     // Rump documentation says the 'hypervisor' sets the permissions of all opened files.
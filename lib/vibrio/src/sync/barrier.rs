@@ -0,0 +1,90 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use core::fmt;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crossbeam_utils::CachePadded;
+use lineup::tls2::Environment;
+
+use super::SPIN_LIMIT;
+use crate::syscalls::Process;
+
+/// A barrier for threads on different cores, see `std::sync::Barrier`.
+///
+/// Threads that arrive spin for a short while and then wait in the kernel
+/// (`Process::barrier_wait`), which halts the core until the last thread
+/// releases the barrier by bumping the generation. That keeps the
+/// latency low for threads pinned to their own core (e.g., in parallel
+/// benchmarks). Whenever the kernel returns early, we yield to the other
+/// threads on the core.
+pub struct Barrier {
+    /// Incremented every time the barrier is released, the kernel waits
+    /// for it to change.
+    generation: CachePadded<AtomicU64>,
+    /// Threads that arrived in the current generation.
+    count: CachePadded<AtomicUsize>,
+    n: usize,
+}
+
+/// Returned by [`Barrier::wait`], one thread per generation is the leader.
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
+
+impl fmt::Debug for BarrierWaitResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BarrierWaitResult")
+            .field("is_leader", &self.is_leader())
+            .finish()
+    }
+}
+
+impl Barrier {
+    /// Creates a barrier that releases `n` threads at once.
+    pub const fn new(n: usize) -> Barrier {
+        Barrier {
+            generation: CachePadded::new(AtomicU64::new(0)),
+            count: CachePadded::new(AtomicUsize::new(0)),
+            n,
+        }
+    }
+
+    /// Waits until `n` threads called `wait`, the last one to arrive is the
+    /// leader.
+    pub fn wait(&self) -> BarrierWaitResult {
+        let generation = self.generation.load(Ordering::Acquire);
+        if self.count.fetch_add(1, Ordering::AcqRel) + 1 >= self.n {
+            // Reset before the release so the next generation starts at 0
+            self.count.store(0, Ordering::Relaxed);
+            self.generation.store(generation + 1, Ordering::Release);
+            return BarrierWaitResult(true);
+        }
+
+        for _i in 0..SPIN_LIMIT {
+            if self.generation.load(Ordering::Acquire) != generation {
+                return BarrierWaitResult(false);
+            }
+            spin_loop();
+        }
+
+        while self.generation.load(Ordering::Acquire) == generation {
+            match Process::barrier_wait(&self.generation, generation) {
+                Ok(true) => {}
+                Ok(false) | Err(_) => Environment::thread().relinquish(),
+            }
+        }
+        BarrierWaitResult(false)
+    }
+}
+
+impl fmt::Debug for Barrier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Barrier").field("n", &self.n).finish()
+    }
+}
@@ -0,0 +1,266 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Synchronization primitives with the API of `std::sync`.
+//!
+//! [`Mutex`], [`RwLock`] and [`Condvar`] spin for a short while if they
+//! can't make progress and then park the calling thread in the user-level
+//! scheduler (lineup) until another thread wakes it up, much like a futex:
+//! every primitive has a [`WaitQueue`] next to its state word. Parking and
+//! waking happen in user space, other threads on the same core keep
+//! running while a thread waits. [`mpsc`] has channels built on top of
+//! them.
+//!
+//! Code that doesn't run on a lineup thread (e.g., a core without a
+//! scheduler) can't park, it waits in the kernel with the futex system calls
+//! (`Process::futex_wait` and `Process::futex_wake`) instead, which halt the
+//! core until it gets woken up. [`Barrier`] is meant for threads on
+//! different cores, it always waits in the kernel.
+//!
+//! Waking up a parked thread has to run on a lineup thread, everything else
+//! works everywhere.
+//!
+//! Panics abort the process in vibrio, so a lock can't be held by a thread
+//! that panicked and the primitives are never poisoned. The result types
+//! ([`LockResult`], [`PoisonError`] etc.) match std's nevertheless, so code
+//! written against `std::sync` compiles unchanged.
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+use lineup::threads::ThreadId;
+use lineup::tls2::{Environment, NoPreempt};
+
+use crate::syscalls::Process;
+
+mod barrier;
+mod condvar;
+mod mutex;
+mod rwlock;
+
+pub mod mpsc;
+
+pub use barrier::{Barrier, BarrierWaitResult};
+pub use condvar::{Condvar, WaitTimeoutResult};
+pub use mutex::{Mutex, MutexGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// How often we retry before we park a thread.
+const SPIN_LIMIT: usize = 100;
+
+/// A lock was poisoned (never happens in vibrio, see the module
+/// documentation).
+pub struct PoisonError<T> {
+    guard: T,
+}
+
+impl<T> PoisonError<T> {
+    pub fn new(guard: T) -> PoisonError<T> {
+        PoisonError { guard }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.guard
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.guard
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> fmt::Debug for PoisonError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoisonError").finish()
+    }
+}
+
+impl<T> fmt::Display for PoisonError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "poisoned lock: another task failed inside".fmt(f)
+    }
+}
+
+/// Why a `try_lock` (or `try_read`, `try_write`) failed.
+pub enum TryLockError<T> {
+    Poisoned(PoisonError<T>),
+    WouldBlock,
+}
+
+impl<T> From<PoisonError<T>> for TryLockError<T> {
+    fn from(err: PoisonError<T>) -> TryLockError<T> {
+        TryLockError::Poisoned(err)
+    }
+}
+
+impl<T> fmt::Debug for TryLockError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryLockError::Poisoned(..) => "Poisoned(..)".fmt(f),
+            TryLockError::WouldBlock => "WouldBlock".fmt(f),
+        }
+    }
+}
+
+impl<T> fmt::Display for TryLockError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryLockError::Poisoned(..) => "poisoned lock: another task failed inside",
+            TryLockError::WouldBlock => "try_lock failed because the operation would block",
+        }
+        .fmt(f)
+    }
+}
+
+pub type LockResult<Guard> = Result<Guard, PoisonError<Guard>>;
+
+pub type TryLockResult<Guard> = Result<Guard, TryLockError<Guard>>;
+
+/// Threads parked on a synchronization primitive.
+///
+/// Like with a futex, a thread only parks if a condition on the state of
+/// the primitive still holds once the queue is locked. Whoever changes the
+/// state has to wake the queue after the change, so no wake-up gets lost.
+/// Parked threads can wake up spuriously and have to check the state again.
+///
+/// Waiters that aren't lineup threads wait in the kernel on `futex`, which
+/// is bumped (with the queue locked) whenever such a waiter gets woken up.
+///
+/// Threads aren't preempted while they hold the queue's lock, a
+/// high-priority thread on the same core would spin on it forever.
+pub(crate) struct WaitQueue {
+    waiters: spin::Mutex<Vec<ThreadId>>,
+    /// Futex word for the waiters in the kernel.
+    futex: AtomicU32,
+    /// Number of waiters in the kernel.
+    sleepers: AtomicU32,
+}
+
+impl WaitQueue {
+    pub(crate) const fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: spin::Mutex::new(Vec::new()),
+            futex: AtomicU32::new(0),
+            sleepers: AtomicU32::new(0),
+        }
+    }
+
+    /// Parks the current thread if `condition` holds, until it's woken up.
+    pub(crate) fn wait<F: Fn() -> bool>(&self, condition: F) {
+        let _timed_out = self.wait_timeout(condition, None);
+    }
+
+    /// Parks the current thread if `condition` holds, until it's woken up
+    /// or `timeout` passed.
+    ///
+    /// Returns true if we timed out.
+    pub(crate) fn wait_timeout<F: Fn() -> bool>(
+        &self,
+        condition: F,
+        timeout: Option<Duration>,
+    ) -> bool {
+        if !Environment::has_thread() {
+            return self.wait_in_kernel(condition, timeout);
+        }
+
+        let tid = Environment::tid();
+        {
+            let _no_preempt = NoPreempt::new();
+            let mut waiters = self.waiters.lock();
+            if !condition() {
+                return false;
+            }
+            waiters.push(tid);
+        }
+
+        // If someone wakes us before we're parked, the scheduler already
+        // put us back in the run-queue and we return right away
+        let thread = Environment::thread();
+        match timeout {
+            Some(timeout) => thread.sleep(timeout),
+            None => thread.block(),
+        }
+
+        // Still in the queue: nobody woke us up
+        let _no_preempt = NoPreempt::new();
+        let mut waiters = self.waiters.lock();
+        let queued = waiters.len();
+        waiters.retain(|waiter| *waiter != tid);
+        timeout.is_some() && waiters.len() != queued
+    }
+
+    /// Like [`WaitQueue::wait_timeout`] for callers that aren't lineup
+    /// threads, halts the core until it's woken up.
+    fn wait_in_kernel<F: Fn() -> bool>(&self, condition: F, timeout: Option<Duration>) -> bool {
+        let seen = {
+            let _no_preempt = NoPreempt::new();
+            let _waiters = self.waiters.lock();
+            if !condition() {
+                return false;
+            }
+            self.sleepers.fetch_add(1, Ordering::Relaxed);
+            self.futex.load(Ordering::Relaxed)
+        };
+
+        // Returns false if we timed out
+        let woken = match timeout {
+            Some(timeout) => Process::futex_wait_timeout(&self.futex, seen, timeout),
+            None => loop {
+                if self.futex.load(Ordering::Acquire) != seen {
+                    break Ok(true);
+                }
+                if let Err(e) = Process::futex_wait(&self.futex, seen) {
+                    break Err(e);
+                }
+            },
+        }
+        .expect("FutexWait syscall failed");
+
+        self.sleepers.fetch_sub(1, Ordering::Relaxed);
+        !woken
+    }
+
+    /// Wakes up `count` waiters in the kernel (if there are any), expects
+    /// the queue to be locked.
+    fn wake_sleepers(&self, count: u64) -> bool {
+        if self.sleepers.load(Ordering::Relaxed) == 0 {
+            return false;
+        }
+        self.futex.fetch_add(1, Ordering::Release);
+        Process::futex_wake(&self.futex, count).expect("FutexWake syscall failed");
+        true
+    }
+
+    /// Wakes up the thread that waits the longest, returns false if nobody
+    /// was waiting.
+    pub(crate) fn wake_one(&self) -> bool {
+        let waiter = {
+            let _no_preempt = NoPreempt::new();
+            let mut waiters = self.waiters.lock();
+            if waiters.is_empty() {
+                return self.wake_sleepers(1);
+            }
+            waiters.remove(0)
+        };
+        Environment::thread().make_runnable(waiter);
+        true
+    }
+
+    /// Wakes up all waiting threads.
+    pub(crate) fn wake_all(&self) {
+        let waiters: Vec<ThreadId> = {
+            let _no_preempt = NoPreempt::new();
+            let mut waiters = self.waiters.lock();
+            self.wake_sleepers(u64::MAX);
+            waiters.drain(..).collect()
+        };
+        if !waiters.is_empty() {
+            Environment::thread().make_all_runnable(waiters);
+        }
+    }
+}
@@ -0,0 +1,451 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Multi-producer, single-consumer channels, see `std::sync::mpsc`.
+//!
+//! A channel is a queue protected by a [`Mutex`] with a [`Condvar`] for
+//! each direction, so a receiver waiting for a message (or a sender waiting
+//! for space in a bounded channel) parks in the user-level scheduler.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::fmt;
+use core::time::Duration;
+
+use super::{Condvar, Mutex, MutexGuard};
+
+struct State<T> {
+    queue: VecDeque<T>,
+    /// Capacity of a bounded channel (0 means every send waits for its
+    /// message to be received).
+    bound: Option<usize>,
+    /// Messages sent and received so far.
+    sent: u64,
+    received: u64,
+    senders: usize,
+    receiver: bool,
+}
+
+struct Channel<T> {
+    state: Mutex<State<T>>,
+    /// Signaled when a message was sent or the last sender is gone.
+    not_empty: Condvar,
+    /// Signaled when a message was received or the receiver is gone.
+    not_full: Condvar,
+}
+
+impl<T> Channel<T> {
+    fn new(bound: Option<usize>) -> Arc<Channel<T>> {
+        Arc::new(Channel {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                bound,
+                sent: 0,
+                received: 0,
+                senders: 1,
+                receiver: true,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn is_full(state: &State<T>) -> bool {
+        match state.bound {
+            Some(bound) => state.queue.len() >= core::cmp::max(bound, 1),
+            None => false,
+        }
+    }
+
+    fn send(&self, t: T, block: bool) -> Result<(), TrySendError<T>> {
+        let mut state = self.lock();
+        while state.receiver && Channel::is_full(&state) {
+            if !block {
+                return Err(TrySendError::Full(t));
+            }
+            state = self.not_full.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        if !state.receiver {
+            return Err(TrySendError::Disconnected(t));
+        }
+
+        state.queue.push_back(t);
+        state.sent += 1;
+        let seq = state.sent;
+        self.not_empty.notify_one();
+
+        // A rendezvous channel returns once the message was received
+        if state.bound == Some(0) && block {
+            while state.receiver && state.received < seq {
+                state = self.not_full.wait(state).unwrap_or_else(|e| e.into_inner());
+            }
+        }
+        Ok(())
+    }
+
+    fn recv(&self, timeout: Option<Duration>) -> Result<T, RecvTimeoutError> {
+        let start = rawtime::Instant::now();
+        let mut state = self.lock();
+        loop {
+            if let Some(t) = state.queue.pop_front() {
+                state.received += 1;
+                self.not_full.notify_all();
+                return Ok(t);
+            }
+            if state.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            state = match timeout {
+                Some(timeout) => {
+                    let remaining = timeout
+                        .checked_sub(start.elapsed())
+                        .ok_or(RecvTimeoutError::Timeout)?;
+                    self.not_empty
+                        .wait_timeout(state, remaining)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                None => self
+                    .not_empty
+                    .wait(state)
+                    .unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut state = self.lock();
+        match state.queue.pop_front() {
+            Some(t) => {
+                state.received += 1;
+                self.not_full.notify_all();
+                Ok(t)
+            }
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    fn add_sender(&self) {
+        self.lock().senders += 1;
+    }
+
+    fn drop_sender(&self) {
+        let mut state = self.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.not_empty.notify_all();
+        }
+    }
+}
+
+/// Creates an unbounded channel: sending never blocks.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let channel = Channel::new(None);
+    (
+        Sender {
+            channel: channel.clone(),
+        },
+        Receiver { channel },
+    )
+}
+
+/// Creates a channel that holds at most `bound` messages, sending blocks
+/// while it's full. With a `bound` of 0 every send blocks until the message
+/// is received.
+pub fn sync_channel<T>(bound: usize) -> (SyncSender<T>, Receiver<T>) {
+    let channel = Channel::new(Some(bound));
+    (
+        SyncSender {
+            channel: channel.clone(),
+        },
+        Receiver { channel },
+    )
+}
+
+/// The sending half of a [`channel`].
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+unsafe impl<T: Send> Send for Sender<T> {}
+
+impl<T> Sender<T> {
+    /// Sends `t`, fails if the receiver is gone.
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        self.channel.send(t, true).map_err(|e| match e {
+            TrySendError::Full(t) | TrySendError::Disconnected(t) => SendError(t),
+        })
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.channel.add_sender();
+        Sender {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.channel.drop_sender();
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender").finish()
+    }
+}
+
+/// The sending half of a [`sync_channel`].
+pub struct SyncSender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+unsafe impl<T: Send> Send for SyncSender<T> {}
+
+impl<T> SyncSender<T> {
+    /// Sends `t`, waits while the channel is full; fails if the receiver is
+    /// gone.
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        self.channel.send(t, true).map_err(|e| match e {
+            TrySendError::Full(t) | TrySendError::Disconnected(t) => SendError(t),
+        })
+    }
+
+    /// Sends `t` if the channel isn't full.
+    pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        self.channel.send(t, false)
+    }
+}
+
+impl<T> Clone for SyncSender<T> {
+    fn clone(&self) -> SyncSender<T> {
+        self.channel.add_sender();
+        SyncSender {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for SyncSender<T> {
+    fn drop(&mut self) {
+        self.channel.drop_sender();
+    }
+}
+
+impl<T> fmt::Debug for SyncSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SyncSender").finish()
+    }
+}
+
+/// The receiving half of a channel.
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+unsafe impl<T: Send> Send for Receiver<T> {}
+
+impl<T> Receiver<T> {
+    /// Waits for a message, fails once all senders are gone and the channel
+    /// is empty.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.channel.recv(None).map_err(|_e| RecvError)
+    }
+
+    /// Returns a message if there is one.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.channel.try_recv()
+    }
+
+    /// Waits at most `timeout` for a message.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.channel.recv(Some(timeout))
+    }
+
+    /// Iterates over the messages until all senders are gone.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter { rx: self }
+    }
+
+    /// Iterates over the messages that are in the channel right now.
+    pub fn try_iter(&self) -> TryIter<'_, T> {
+        TryIter { rx: self }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.lock().receiver = false;
+        self.channel.not_full.notify_all();
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver").finish()
+    }
+}
+
+pub struct Iter<'a, T: 'a> {
+    rx: &'a Receiver<T>,
+}
+
+impl<T> Iterator for Iter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+pub struct TryIter<'a, T: 'a> {
+    rx: &'a Receiver<T>,
+}
+
+impl<T> Iterator for TryIter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.try_recv().ok()
+    }
+}
+
+pub struct IntoIter<T> {
+    rx: Receiver<T>,
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+impl<T> IntoIterator for Receiver<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { rx: self }
+    }
+}
+
+/// The receiver is gone, returns the message that couldn't be sent.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct SendError<T>(pub T);
+
+/// The channel is empty and all senders are gone.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct RecvError;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum RecvTimeoutError {
+    Timeout,
+    Disconnected,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum TrySendError<T> {
+    Full(T),
+    Disconnected(T),
+}
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendError").finish()
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "sending on a closed channel".fmt(f)
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "receiving on a closed channel".fmt(f)
+    }
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            TryRecvError::Empty => "receiving on an empty channel",
+            TryRecvError::Disconnected => "receiving on a closed channel",
+        }
+        .fmt(f)
+    }
+}
+
+impl fmt::Display for RecvTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            RecvTimeoutError::Timeout => "timed out waiting on channel",
+            RecvTimeoutError::Disconnected => "channel is empty and sending half is closed",
+        }
+        .fmt(f)
+    }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            TrySendError::Full(..) => "Full(..)".fmt(f),
+            TrySendError::Disconnected(..) => "Disconnected(..)".fmt(f),
+        }
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            TrySendError::Full(..) => "sending on a full channel",
+            TrySendError::Disconnected(..) => "sending on a closed channel",
+        }
+        .fmt(f)
+    }
+}
+
+impl<T> From<SendError<T>> for TrySendError<T> {
+    fn from(err: SendError<T>) -> TrySendError<T> {
+        TrySendError::Disconnected(err.0)
+    }
+}
+
+impl From<RecvError> for TryRecvError {
+    fn from(_err: RecvError) -> TryRecvError {
+        TryRecvError::Disconnected
+    }
+}
+
+impl From<RecvError> for RecvTimeoutError {
+    fn from(_err: RecvError) -> RecvTimeoutError {
+        RecvTimeoutError::Disconnected
+    }
+}
@@ -17,6 +17,17 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use log::trace;
 
+global_asm!(include_str!("preempt.S"), options(att_syntax));
+
+extern "C" {
+    /// Entry point of a preempted thread, see `preempt.S`.
+    fn vibrio_preempted();
+}
+
+/// Size of the red zone below the stack pointer that functions can use
+/// without adjusting it (System V ABI).
+const RED_ZONE: u64 = 128;
+
 pub static CORES_ONLINE: AtomicUsize = AtomicUsize::new(1);
 
 lazy_static! {
@@ -85,6 +96,12 @@ pub fn upcall_while_enabled(control: &mut kpi::arch::VirtualCpu, cmd: u64, arg:
         let scheduler = lineup::tls2::Environment::scheduler();
         log::info!("got interrupt cmd={} arg={}", cmd, arg);
         assert!(scheduler.pending_irqs.push(cmd).is_ok());
+
+        // Make room for the high-priority thread of the interrupt
+        let interrupted = control.enabled_state().fs as *const lineup::tls2::ThreadControlBlock;
+        if unsafe { scheduler.preempts(cmd, interrupted) } {
+            unsafe { preempt(control) };
+        }
     } else {
         log::error!("got unknown interrupt... {}", cmd);
     }
@@ -93,6 +110,60 @@ pub fn upcall_while_enabled(control: &mut kpi::arch::VirtualCpu, cmd: u64, arg:
     unsafe { resume(control) }
 }
 
+/// Makes the thread that the upcall interrupted give up the core once it's
+/// resumed: it continues in `vibrio_preempted` which lets the scheduler run
+/// the high-priority thread and returns to where the thread was interrupted
+/// when it gets to run again.
+unsafe fn preempt(control: &mut kpi::arch::VirtualCpu) {
+    let mut state = *control.enabled_state();
+    // Don't clobber the red zone of the interrupted function
+    let rsp = state.rsp - RED_ZONE - 8;
+    *(rsp as *mut u64) = state.rip;
+    state.rsp = rsp;
+    state.rip = vibrio_preempted as u64;
+    control.set_enabled_state(&state);
+}
+
+/// Called on the stack of a preempted thread (see `preempt.S`).
+#[no_mangle]
+extern "C" fn vibrio_relinquish() {
+    lineup::tls2::Environment::thread().relinquish();
+}
+
+/// A critical section on the current core: upcalls (traps and interrupts
+/// for the process) that arrive while it's held are deferred by the kernel
+/// and delivered when it's dropped.
+///
+/// Critical sections should be short: the kernel counts the ones that hold
+/// back an upcall for too long (see
+/// [`kpi::arch::VirtualCpu::overdue_upcalls`]).
+///
+/// Critical sections nest, only the outermost one enables upcalls again.
+pub struct CriticalSection {
+    vcpu: &'static mut kpi::arch::VirtualCpu,
+    was_disabled: bool,
+}
+
+impl CriticalSection {
+    /// Disables upcalls on the current core.
+    pub fn enter() -> CriticalSection {
+        let vcpu =
+            crate::syscalls::Process::vcpu_control_area().expect("Can't read vcpu control area.");
+        let was_disabled = vcpu.is_disabled();
+        vcpu.disable_upcalls();
+        CriticalSection { vcpu, was_disabled }
+    }
+}
+
+impl Drop for CriticalSection {
+    fn drop(&mut self) {
+        if !self.was_disabled {
+            crate::syscalls::Process::enable_upcalls(self.vcpu)
+                .expect("Can't deliver deferred upcall");
+        }
+    }
+}
+
 /// A trap (exception or fault) happened while disabled, this is bad and
 /// shouldn't happen (i.e., it means there is a bug) in the user-space
 /// scheduler logic or upcall handling.
@@ -105,8 +176,12 @@ pub unsafe fn resume(control: &mut kpi::arch::VirtualCpu) -> ! {
     // Enable upcalls (Note: we will remain disabled while the instruction pointer
     // is in this function (i.e., between the `resume` and `resume_end`
     // symbol (see asm! below))
+    //
+    // An upcall that was deferred in the meantime is delivered by the kernel
+    // once we're out of here (we can't ask for it here since it would
+    // overwrite the state we're about to restore).
     control.enable_upcalls();
-    //debug!("resume enabled_state {:p}", &control.enabled_state);
+    //debug!("resume enabled_state {:p}", control.enabled_state());
 
     llvm_asm! {"
             // Restore gs
@@ -159,7 +234,7 @@ pub unsafe fn resume(control: &mut kpi::arch::VirtualCpu) -> ! {
             resume_end:"
     : /* No output */
     :
-      "{rsi}" (&control.enabled_state)
+      "{rsi}" (control.enabled_state())
     :
     :
     };
@@ -5,7 +5,7 @@
 //!
 //! Needs to be a proper serial driver.
 
-static COM1_IRQ: u64 = 4 + 32;
+static COM1_IRQ: u64 = 4;
 
 pub fn init() {
     crate::syscalls::Irq::irqalloc(COM1_IRQ, 0).ok();
@@ -6,6 +6,7 @@
 
 use core::{fmt, ops};
 
+use arrayvec::ArrayString;
 use log::{Level, Metadata, Record};
 
 /// println macro that uses the logging syscall.
@@ -76,6 +77,20 @@ impl fmt::Write for WriterNoDrop {
     }
 }
 
+/// A log message, truncated if it doesn't fit.
+struct Message(ArrayString<1024>);
+
+impl fmt::Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut end = core::cmp::min(s.len(), self.0.remaining_capacity());
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.0.push_str(&s[..end]);
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct ULogger;
 
@@ -88,11 +103,14 @@ impl log::Log for ULogger {
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            sys_println!(
-                "[{}] - {}: {}",
-                record.level(),
+            use core::fmt::Write;
+            let mut message = Message(ArrayString::new());
+            let _r = write!(&mut message, "{}", record.args());
+            // The kernel adds the level and target, and filters by level
+            let _r = crate::syscalls::Process::log(
+                record.level().into(),
                 record.target(),
-                record.args(),
+                message.0.as_str(),
             );
         }
     }
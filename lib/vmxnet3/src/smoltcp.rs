@@ -2,7 +2,9 @@
 // SPDX-License-Identifier: BSD-2-Clause
 
 use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
 use core::alloc::Layout;
+use core::cell::{Cell, RefCell};
 use core::pin::Pin;
 
 use driverkit::iomem::{IOBuf, IOBufChain, IOBufPool};
@@ -10,6 +12,8 @@ use driverkit::{devq::DevQueue, iomem::IOMemError};
 
 use smoltcp::phy::{self, Device, DeviceCapabilities};
 use smoltcp::time::Instant;
+use smoltcp::wire::{ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol};
+use smoltcp::wire::{IpProtocol, Ipv4Address, Ipv4Packet};
 use smoltcp::Result;
 
 use crate::vmx::VMXNet3;
@@ -17,11 +21,74 @@ use crate::vmx::VMXNet3;
 /// define the maximum packet size supported
 const MAX_PACKET_SZ: usize = 2048;
 
+/// Counters and the neighbors seen by a [`DevQueuePhy`] (for debugging).
+#[derive(Default)]
+pub struct PhyStats {
+    pub rx_packets: Cell<u64>,
+    pub rx_bytes: Cell<u64>,
+    pub tx_packets: Cell<u64>,
+    pub tx_bytes: Cell<u64>,
+    /// Received ARP packets.
+    pub rx_arp: Cell<u64>,
+    /// Received ICMP packets.
+    pub rx_icmp: Cell<u64>,
+    /// Senders of the ARP packets we received.
+    ///
+    /// This mirrors the neighbor cache of the interface which smoltcp doesn't
+    /// let us inspect.
+    pub neighbors: RefCell<BTreeMap<Ipv4Address, EthernetAddress>>,
+}
+
+impl PhyStats {
+    fn add(counter: &Cell<u64>, n: u64) {
+        counter.set(counter.get() + n);
+    }
+
+    /// Records a received frame.
+    pub fn rx(&self, frame: &[u8]) {
+        PhyStats::add(&self.rx_packets, 1);
+        PhyStats::add(&self.rx_bytes, frame.len() as u64);
+
+        let frame = match EthernetFrame::new_checked(frame) {
+            Ok(frame) => frame,
+            Err(_) => return,
+        };
+        match frame.ethertype() {
+            EthernetProtocol::Arp => {
+                PhyStats::add(&self.rx_arp, 1);
+                let arp = ArpPacket::new_checked(frame.payload()).and_then(|p| ArpRepr::parse(&p));
+                if let Ok(ArpRepr::EthernetIpv4 {
+                    source_hardware_addr,
+                    source_protocol_addr,
+                    ..
+                }) = arp
+                {
+                    self.neighbors
+                        .borrow_mut()
+                        .insert(source_protocol_addr, source_hardware_addr);
+                }
+            }
+            EthernetProtocol::Ipv4 => match Ipv4Packet::new_checked(frame.payload()) {
+                Ok(ip) if ip.protocol() == IpProtocol::Icmp => PhyStats::add(&self.rx_icmp, 1),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    /// Records a sent frame.
+    pub fn tx(&self, len: usize) {
+        PhyStats::add(&self.tx_packets, 1);
+        PhyStats::add(&self.tx_bytes, len as u64);
+    }
+}
+
 /// a smoltcp phy implementation wrapping a DevQueue
 pub struct DevQueuePhy {
     device: Pin<Box<VMXNet3>>,
     pool_tx: IOBufPool,
     pool_rx: IOBufPool,
+    stats: PhyStats,
 }
 
 impl DevQueuePhy {
@@ -50,8 +117,19 @@ impl DevQueuePhy {
             device,
             pool_tx,
             pool_rx,
+            stats: Default::default(),
         })
     }
+
+    /// Packet counters and neighbors of the device.
+    pub fn stats(&self) -> &PhyStats {
+        &self.stats
+    }
+
+    /// Are there received packets waiting to be processed?
+    pub fn rx_pending(&mut self) -> bool {
+        self.device.rxq[0].can_dequeue(false) > 0
+    }
 }
 
 impl<'a> Device<'a> for DevQueuePhy {
@@ -78,7 +156,7 @@ impl<'a> Device<'a> for DevQueuePhy {
             assert!(self.device.rxq[0].enqueue(bufs).is_ok());
 
             // construct the RX token
-            let rx_token = RxPacket::new(packet, &mut self.pool_rx);
+            let rx_token = RxPacket::new(packet, &mut self.pool_rx, &self.stats);
 
             // get an empty TX token from the pool...
             // TODO: make sure we can actually send something!
@@ -99,7 +177,12 @@ impl<'a> Device<'a> for DevQueuePhy {
             // let mut iobuf = IOBufChain::new(0, 1).expect("Can't make chain?");
             // let buf2 = self.pool_tx.get_buf().expect("Can't get buffer?");
             // iobuf.append(buf2);
-            let tx_token = TxPacket::new(iobuf, &mut self.device.txq[0], &mut self.pool_tx);
+            let tx_token = TxPacket::new(
+                iobuf,
+                &mut self.device.txq[0],
+                &mut self.pool_tx,
+                &self.stats,
+            );
 
             Some((rx_token, tx_token))
         } else {
@@ -128,6 +211,7 @@ impl<'a> Device<'a> for DevQueuePhy {
             packet,
             &mut self.device.txq[0],
             &mut self.pool_tx,
+            &self.stats,
         ))
     }
 
@@ -148,11 +232,12 @@ impl<'a> Device<'a> for DevQueuePhy {
 pub struct RxPacket<'a> {
     iobuf: IOBufChain,
     pool: &'a mut IOBufPool,
+    stats: &'a PhyStats,
 }
 
 impl<'a> RxPacket<'a> {
-    fn new(iobuf: IOBufChain, pool: &'a mut IOBufPool) -> RxPacket<'a> {
-        RxPacket { iobuf, pool }
+    fn new(iobuf: IOBufChain, pool: &'a mut IOBufPool, stats: &'a PhyStats) -> RxPacket<'a> {
+        RxPacket { iobuf, pool, stats }
     }
 }
 
@@ -161,8 +246,10 @@ impl<'a> phy::RxToken for RxPacket<'a> {
     where
         F: FnOnce(&mut [u8]) -> Result<R>,
     {
+        let buf = self.iobuf.segments[0].as_mut_slice();
+        self.stats.rx(buf);
         // XXX: not sure here if the buffer actually needs to be copied here...
-        let result = f(&mut self.iobuf.segments[0].as_mut_slice());
+        let result = f(buf);
         // info!("RxToken::consume n segments:{}", self.iobuf.segments.len());
 
         // we can drop the IOBufChain here.
@@ -179,14 +266,21 @@ pub struct TxPacket<'a> {
     iobuf: Option<IOBufChain>,
     txq: &'a mut dyn DevQueue,
     pool: &'a mut IOBufPool,
+    stats: &'a PhyStats,
 }
 
 impl<'a> TxPacket<'a> {
-    fn new(iobuf: IOBufChain, txq: &'a mut dyn DevQueue, pool: &'a mut IOBufPool) -> TxPacket<'a> {
+    fn new(
+        iobuf: IOBufChain,
+        txq: &'a mut dyn DevQueue,
+        pool: &'a mut IOBufPool,
+        stats: &'a PhyStats,
+    ) -> TxPacket<'a> {
         TxPacket {
             iobuf: Some(iobuf),
             txq,
             pool,
+            stats,
         }
     }
 }
@@ -204,6 +298,7 @@ impl<'a> phy::TxToken for TxPacket<'a> {
         iobuf.segments[0].expand();
         iobuf.segments[0].truncate(len);
         let result = f(&mut iobuf.segments[0].as_mut_slice());
+        self.stats.tx(len);
 
         // TODO: send packet out, this passes ownership of the IOBufChain to the device
         // XXX: can we guarantee that there is space in the queue?
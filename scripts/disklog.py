@@ -0,0 +1,64 @@
+#!/usr/bin/python3
+
+# Copyright © 2021 VMware, Inc. All Rights Reserved.
+# SPDX-License-Identifier: Apache-2.0 OR MIT
+
+"""
+Prints the kernel log nrk kept on a disk (`logdisk=<disk>:<first>:<sectors>`
+on the kernel command line, see `kernel/src/disklog.rs`), e.g., of a disk
+image or of the disk of a lab machine:
+
+    python3 scripts/disklog.py /dev/sdb 2048 1024
+
+The sectors hold records with a sequence number, the valid ones are printed
+ordered by it (records that fail their CRC are skipped).
+"""
+
+import argparse
+import struct
+import sys
+import zlib
+
+SECTOR_SIZE = 512
+MAGIC = b"NRKLOG01"
+HEADER = struct.Struct("<8sQHH I")
+
+
+def records(disk, first, sectors):
+    "Yields (sequence number, text) of the valid records."
+    disk.seek(first * SECTOR_SIZE)
+    for _ in range(sectors):
+        sector = disk.read(SECTOR_SIZE)
+        if len(sector) < SECTOR_SIZE:
+            break
+        magic, seq, length, _reserved, crc = HEADER.unpack_from(sector)
+        if magic != MAGIC or length > SECTOR_SIZE - HEADER.size:
+            continue
+        text = sector[HEADER.size:HEADER.size + length]
+        if zlib.crc32(text, zlib.crc32(sector[:20])) != crc:
+            continue
+        yield seq, text
+
+
+def main():
+    parser = argparse.ArgumentParser(
+        description="Prints the kernel log nrk kept on a disk.")
+    parser.add_argument("disk", help="Disk (image) with the log.")
+    parser.add_argument("first", type=int,
+                        help="First sector of the log (as in `logdisk`).")
+    parser.add_argument("sectors", type=int,
+                        help="Number of sectors of the log (as in `logdisk`).")
+    args = parser.parse_args()
+
+    with open(args.disk, "rb") as disk:
+        log = sorted(records(disk, args.first, args.sectors))
+    if not log:
+        print("No log in sectors {}..{} of {}".format(
+            args.first, args.first + args.sectors, args.disk), file=sys.stderr)
+        sys.exit(1)
+    for _seq, text in log:
+        sys.stdout.buffer.write(text)
+
+
+if __name__ == "__main__":
+    main()
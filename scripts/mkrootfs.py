@@ -0,0 +1,96 @@
+#!/usr/bin/python3
+
+# Copyright © 2021 VMware, Inc. All Rights Reserved.
+# SPDX-License-Identifier: Apache-2.0 OR MIT
+
+"""
+Assembles the archive nrk populates its file-system from at boot (the
+`rootfs` module) out of a directory, e.g.:
+
+    python3 scripts/mkrootfs.py rootfs/ target/rootfs
+
+Writes a cpio archive in the "new ASCII" format (like `cpio -H newc`) or a
+ustar archive with `--format tar`. Regular files and directories are added,
+everything else (symlinks, devices, ...) is skipped since nrk ignores it.
+"""
+
+import argparse
+import os
+import pathlib
+import stat
+import sys
+import tarfile
+
+CPIO_MAGIC = b"070701"
+CPIO_TRAILER = "TRAILER!!!"
+
+
+def entries(root):
+    "Yields (path in the archive, path on disk) sorted, parents first."
+    yield ".", root
+    for dirpath, dirnames, filenames in os.walk(root):
+        dirnames.sort()
+        for name in sorted(dirnames + filenames):
+            path = pathlib.Path(dirpath) / name
+            yield "./" + path.relative_to(root).as_posix(), path
+
+
+def pad4(out):
+    out.write(b"\0" * (-out.tell() % 4))
+
+
+def cpio_entry(out, ino, name, mode, data):
+    name = name.encode() + b"\0"
+    fields = [ino, mode, 0, 0, 1, 0, len(data), 0, 0, 0, 0, len(name), 0]
+    out.write(CPIO_MAGIC)
+    out.write("".join("{:08x}".format(f) for f in fields).encode())
+    out.write(name)
+    pad4(out)
+    out.write(data)
+    pad4(out)
+
+
+def write_cpio(root, output):
+    with open(output, "wb") as out:
+        for ino, (name, path) in enumerate(entries(root), start=1):
+            st = path.lstat()
+            if stat.S_ISDIR(st.st_mode):
+                cpio_entry(out, ino, name, st.st_mode, b"")
+            elif stat.S_ISREG(st.st_mode):
+                cpio_entry(out, ino, name, st.st_mode, path.read_bytes())
+            else:
+                print("Skipping {}".format(path), file=sys.stderr)
+        cpio_entry(out, 0, CPIO_TRAILER, 0, b"")
+
+
+def write_tar(root, output):
+    with tarfile.open(output, "w", format=tarfile.USTAR_FORMAT) as tar:
+        for name, path in entries(root):
+            st = path.lstat()
+            if stat.S_ISDIR(st.st_mode) or stat.S_ISREG(st.st_mode):
+                tar.add(path, arcname=name, recursive=False)
+            else:
+                print("Skipping {}".format(path), file=sys.stderr)
+
+
+def main():
+    parser = argparse.ArgumentParser(
+        description="Builds the root file-system archive for nrk.")
+    parser.add_argument("directory", type=pathlib.Path,
+                        help="Directory with the contents of the file-system.")
+    parser.add_argument("output", type=pathlib.Path,
+                        help="Where to write the archive.")
+    parser.add_argument("--format", choices=["cpio", "tar"], default="cpio",
+                        help="Archive format (defaults to cpio).")
+    args = parser.parse_args()
+
+    if not args.directory.is_dir():
+        parser.error("{} is not a directory".format(args.directory))
+    if args.format == "cpio":
+        write_cpio(args.directory, args.output)
+    else:
+        write_tar(args.directory, args.output)
+
+
+if __name__ == "__main__":
+    main()
@@ -317,7 +317,9 @@ impl ModelFIO {
         if let Some(mnode) = self.lookup(&path) {
             if flags.is_create() {
                 trace!("open() - create flag specified for file that already exists");
-                //Err(SystemCallError::InternalError)
+                if flags.is_exclusive() {
+                    return Err(SystemCallError::InternalError);
+                }
             }
 
             let size = self.file_size(mnode);
@@ -327,6 +329,12 @@ impl ModelFIO {
             {
                 modes = *old_modes;
             }
+            if (flags.is_read() && !modes.is_readable())
+                || ((flags.is_write() || flags.is_truncate()) && !modes.is_writable())
+            {
+                trace!("open() - modes of the file don't allow the flags");
+                return Err(SystemCallError::InternalError);
+            }
             let (fid, fd) = self.fds.allocate_fd()?;
             fd.update_fd(mnode, flags);
 
@@ -1077,8 +1085,8 @@ fn test_file_info() {
     vibrio::syscalls::Fs::close(fd).unwrap();
 
     // Get file info
-    let ret = vibrio::syscalls::Fs::getinfo("test_file_info.txt\0".as_ptr() as u64);
-    assert_eq!(ret, Ok(FileInfo { ftype: 2, fsize: 0 }));
+    let ret = vibrio::syscalls::Fs::getinfo("test_file_info.txt\0".as_ptr() as u64).unwrap();
+    assert_eq!((ret.ftype, ret.fsize), (2, 0));
 }
 
 /// Test file deletion.
@@ -1339,9 +1347,323 @@ fn test_file_position() {
     assert_eq!(rdata[3], 2);
     assert_eq!(rdata[9], 2);
 
+    // Neither `write_at` nor `read_at` moved the cursor (still at 10).
+    assert_eq!(
+        vibrio::syscalls::Fs::read(fd, rdata.as_mut_ptr() as u64, 10),
+        Ok(5)
+    );
+    assert_eq!(rdata[..5], [2u8; 5]);
+    assert_eq!(
+        vibrio::syscalls::Fs::read_at(fd, rdata.as_mut_ptr() as u64, 10, -1),
+        Err(SystemCallError::OffsetError)
+    );
+
+    vibrio::syscalls::Fs::close(fd).unwrap();
+}
+
+/// Poll a read-only, a write-only and an invalid file descriptor.
+fn test_file_poll() {
+    let rfd = vibrio::syscalls::Fs::open(
+        "test_file_poll_read.txt\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDONLY | FileFlags::O_CREAT),
+        FileModes::S_IRWXU.into(),
+    )
+    .unwrap();
+    let wfd = vibrio::syscalls::Fs::open(
+        "test_file_poll_write.txt\0".as_ptr() as u64,
+        u64::from(FileFlags::O_WRONLY | FileFlags::O_CREAT),
+        FileModes::S_IRWXU.into(),
+    )
+    .unwrap();
+
+    let both = PollEvents::POLLIN | PollEvents::POLLOUT;
+    let mut fds = [
+        PollFd::new(rfd, both),
+        PollFd::new(wfd, both),
+        PollFd::new(MAX_FILES_PER_PROCESS as u64 + 1, both),
+    ];
+    assert_eq!(vibrio::syscalls::Fs::poll(&mut fds), Ok(3));
+    assert_eq!(fds[0].revents(), PollEvents::POLLIN);
+    assert_eq!(fds[1].revents(), PollEvents::POLLOUT);
+    assert_eq!(fds[2].revents(), PollEvents::POLLNVAL);
+
+    let mut set = vibrio::syscalls::PollSet::new();
+    set.add(wfd, PollEvents::POLLIN);
+    assert_eq!(
+        set.wait(Some(core::time::Duration::from_millis(1))),
+        Ok(Vec::new())
+    );
+    set.add(rfd, PollEvents::POLLIN);
+    assert_eq!(
+        set.wait(None),
+        Ok(alloc::vec![(PollKind::File, rfd, PollEvents::POLLIN)])
+    );
+
+    vibrio::syscalls::Fs::close(rfd).unwrap();
+    vibrio::syscalls::Fs::close(wfd).unwrap();
+}
+
+fn test_file_ring() {
+    let fd = vibrio::syscalls::Fs::open(
+        "test_file_ring.txt\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+        FileModes::S_IRWXU.into(),
+    )
+    .unwrap();
+
+    let wbuf: [u8; 64] = [0xb; 64];
+    let mut rbuf: [u8; 64] = [0; 64];
+    let mut ring = vibrio::syscalls::Ring::new();
+    ring.submit(SubmissionEntry {
+        op: RingOperation::WriteAt as u64,
+        fd,
+        buf: wbuf.as_ptr() as u64,
+        len: wbuf.len() as u64,
+        offset: 0,
+        user_data: 1,
+    })
+    .unwrap();
+    ring.submit(SubmissionEntry {
+        op: RingOperation::ReadAt as u64,
+        fd,
+        buf: rbuf.as_mut_ptr() as u64,
+        len: rbuf.len() as u64,
+        offset: 0,
+        user_data: 2,
+    })
+    .unwrap();
+    ring.submit(SubmissionEntry {
+        op: RingOperation::Send as u64,
+        fd,
+        buf: 0,
+        len: 0,
+        offset: 0,
+        user_data: 3,
+    })
+    .unwrap();
+    assert_eq!(ring.enter(), Ok(3));
+
+    let cqe = ring.complete().unwrap();
+    assert_eq!((cqe.user_data, cqe.result, cqe.error), (1, 64, 0));
+    let cqe = ring.complete().unwrap();
+    assert_eq!((cqe.user_data, cqe.result, cqe.error), (2, 64, 0));
+    let cqe = ring.complete().unwrap();
+    assert_eq!(cqe.user_data, 3);
+    assert_eq!(
+        SystemCallError::from(cqe.error),
+        SystemCallError::NotSupported
+    );
+    assert!(ring.complete().is_none());
+    assert_eq!(wbuf, rbuf);
+
+    vibrio::syscalls::Fs::close(fd).unwrap();
+}
+
+fn test_file_mount() {
+    vibrio::syscalls::Fs::mkdir_simple(
+        "/test_file_mount\0".as_ptr() as u64,
+        FileModes::S_IRWXU.into(),
+    )
+    .unwrap();
+    vibrio::syscalls::Fs::mount("/test_file_mount\0".as_ptr() as u64, MountKind::Tmpfs, 4096)
+        .unwrap();
+
+    let fd = vibrio::syscalls::Fs::open(
+        "/test_file_mount/file.txt\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+        FileModes::S_IRWXU.into(),
+    )
+    .unwrap();
+    let wbuf: [u8; 4096] = [0xb; 4096];
+    assert_eq!(
+        vibrio::syscalls::Fs::write_at(fd, wbuf.as_ptr() as u64, 4096, 0),
+        Ok(4096)
+    );
+    assert_eq!(
+        vibrio::syscalls::Fs::write_at(fd, wbuf.as_ptr() as u64, 1, 4096),
+        Err(SystemCallError::NoSpace)
+    );
+
+    let info =
+        vibrio::syscalls::Fs::getinfo("/test_file_mount/file.txt\0".as_ptr() as u64).unwrap();
+    assert_eq!((info.mount_used, info.mount_limit), (4096, 4096));
+    assert_eq!(vibrio::syscalls::Fs::fsync(fd), Ok(()));
+
+    vibrio::syscalls::Fs::close(fd).unwrap();
+    assert!(vibrio::syscalls::Fs::fsync(fd).is_err());
+}
+
+fn test_file_vectored() {
+    let fd = vibrio::syscalls::Fs::open(
+        "test_file_vectored.txt\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+        FileModes::S_IRWXU.into(),
+    )
+    .unwrap();
+
+    let head: [u8; 16] = [0xa; 16];
+    let tail: [u8; 48] = [0xb; 48];
+    let iov = [
+        IoVec::new(head.as_ptr() as u64, head.len() as u64),
+        IoVec::new(0, 0),
+        IoVec::new(tail.as_ptr() as u64, tail.len() as u64),
+    ];
+    assert_eq!(vibrio::syscalls::Fs::writev(fd, &iov), Ok(64));
+
+    // Scatter into differently sized buffers, the last one is only
+    // partially filled.
+    let rfd = vibrio::syscalls::Fs::open(
+        "test_file_vectored.txt\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDONLY),
+        FileModes::S_IRWXU.into(),
+    )
+    .unwrap();
+    let mut first: [u8; 20] = [0; 20];
+    let mut second: [u8; 64] = [0; 64];
+    let iov = [
+        IoVec::new(first.as_mut_ptr() as u64, first.len() as u64),
+        IoVec::new(second.as_mut_ptr() as u64, second.len() as u64),
+    ];
+    assert_eq!(vibrio::syscalls::Fs::readv(rfd, &iov), Ok(64));
+    assert_eq!(first[..16], [0xa; 16]);
+    assert_eq!(first[16..], [0xb; 4]);
+    assert_eq!(second[..44], [0xb; 44]);
+    assert_eq!(second[44..], [0; 20]);
+
+    vibrio::syscalls::Fs::close(rfd).unwrap();
     vibrio::syscalls::Fs::close(fd).unwrap();
 }
 
+/// Open files with `OpenOptions`: exclusive create, appends, truncate and the
+/// modes checked on open.
+fn test_file_open_options() {
+    let path = "test_file_open_options.txt";
+    let fd = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .modes(FileModes::S_IWUSR)
+        .open(path)
+        .unwrap();
+    assert_eq!(
+        OpenOptions::new().write(true).create_new(true).open(path),
+        Err(SystemCallError::InternalError)
+    );
+    // The file can't be read, so it can't be opened for reading either.
+    assert_eq!(
+        OpenOptions::new().read(true).open(path),
+        Err(SystemCallError::InternalError)
+    );
+
+    let wdata = [0xau8; 8];
+    assert_eq!(
+        vibrio::syscalls::Fs::write_at(fd, wdata.as_ptr() as u64, 8, 4),
+        Ok(8)
+    );
+    let afd = OpenOptions::new().append(true).open(path).unwrap();
+    assert_eq!(
+        vibrio::syscalls::Fs::write(afd, wdata.as_ptr() as u64, 4),
+        Ok(4)
+    );
+    assert_eq!(
+        vibrio::syscalls::Fs::getinfo("test_file_open_options.txt\0".as_ptr() as u64)
+            .unwrap()
+            .fsize,
+        16
+    );
+
+    let tfd = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(path)
+        .unwrap();
+    assert_eq!(
+        vibrio::syscalls::Fs::getinfo("test_file_open_options.txt\0".as_ptr() as u64)
+            .unwrap()
+            .fsize,
+        0
+    );
+
+    vibrio::syscalls::Fs::close(tfd).unwrap();
+    vibrio::syscalls::Fs::close(afd).unwrap();
+    vibrio::syscalls::Fs::close(fd).unwrap();
+}
+
+/// Create symbolic links, resolve paths through them and read them back.
+fn test_file_symlink() {
+    vibrio::syscalls::Fs::mkdir_simple(
+        "/test_file_symlink\0".as_ptr() as u64,
+        FileModes::S_IRWXU.into(),
+    )
+    .unwrap();
+    let fd = vibrio::syscalls::Fs::open(
+        "/test_file_symlink/./data.txt\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+        FileModes::S_IRWXU.into(),
+    )
+    .unwrap();
+    let wdata = [0xau8; 10];
+    assert_eq!(
+        vibrio::syscalls::Fs::write(fd, wdata.as_ptr() as u64, 10),
+        Ok(10)
+    );
+    vibrio::syscalls::Fs::close(fd).unwrap();
+
+    vibrio::syscalls::Fs::symlink(
+        "data.txt\0".as_ptr() as u64,
+        "/test_file_symlink/link\0".as_ptr() as u64,
+    )
+    .unwrap();
+    vibrio::syscalls::Fs::symlink(
+        "/test_file_symlink\0".as_ptr() as u64,
+        "/test_file_symlink_dir\0".as_ptr() as u64,
+    )
+    .unwrap();
+
+    // Both links lead to the same file (`..` goes to the parent of the target).
+    let info = vibrio::syscalls::Fs::getinfo(
+        "/test_file_symlink_dir/../test_file_symlink//link\0".as_ptr() as u64,
+    )
+    .unwrap();
+    assert_eq!(info.fsize, 10);
+    assert_eq!(info.ftype, FileType::File.into());
+
+    let mut target = [0u8; 32];
+    assert_eq!(
+        vibrio::syscalls::Fs::readlink(
+            "/test_file_symlink_dir/link\0".as_ptr() as u64,
+            &mut target
+        ),
+        Ok(8)
+    );
+    assert_eq!(&target[..8], b"data.txt");
+    assert_eq!(
+        vibrio::syscalls::Fs::readlink(
+            "/test_file_symlink_dir/link\0".as_ptr() as u64,
+            &mut target[..4]
+        ),
+        Ok(4)
+    );
+    assert!(vibrio::syscalls::Fs::readlink(
+        "/test_file_symlink/data.txt\0".as_ptr() as u64,
+        &mut target
+    )
+    .is_err());
+
+    vibrio::syscalls::Fs::symlink(
+        "loop\0".as_ptr() as u64,
+        "/test_file_symlink/loop\0".as_ptr() as u64,
+    )
+    .unwrap();
+    assert_eq!(
+        vibrio::syscalls::Fs::getinfo("/test_file_symlink/loop\0".as_ptr() as u64),
+        Err(SystemCallError::SymlinkLoop)
+    );
+
+    // Deleting a link removes the link, not its target.
+    vibrio::syscalls::Fs::delete("/test_file_symlink/link\0".as_ptr() as u64).unwrap();
+    assert!(vibrio::syscalls::Fs::getinfo("/test_file_symlink/data.txt\0".as_ptr() as u64).is_ok());
+}
+
 pub fn run_fio_syscall_tests() {
     test_file_read_permission_error();
     test_file_write_permission_error();
@@ -1362,4 +1684,10 @@ pub fn run_fio_syscall_tests() {
     test_file_rename_nonexistent_file();
     test_file_rename_to_existent_file();
     test_file_position();
+    test_file_poll();
+    test_file_ring();
+    test_file_mount();
+    test_file_vectored();
+    test_file_open_options();
+    test_file_symlink();
 }
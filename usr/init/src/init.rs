@@ -44,9 +44,17 @@ mod fs;
 #[cfg(feature = "fxmark")]
 mod fxmark;
 mod histogram;
+#[cfg(feature = "test-runner")]
+mod runner;
+#[cfg(feature = "bench-syscall")]
+mod syscallbench;
 
 use crate::fs::{run_fio_syscall_proptests, run_fio_syscall_tests};
 
+/// Sampling period of the kernel profiler (in cycles).
+#[cfg(feature = "profile")]
+const PROFILE_PERIOD: u64 = 1_000_000;
+
 #[thread_local]
 pub static mut TLS_TEST: [&str; 2] = ["abcd", "efgh"];
 
@@ -56,6 +64,10 @@ fn print_test() {
 }
 
 fn map_test() {
+    let before = vibrio::syscalls::Process::process_info()
+        .expect("Can't get pinfo")
+        .memory;
+
     let base: u64 = 0xff000;
     let size: u64 = 0x1000 * 64;
     unsafe {
@@ -68,9 +80,543 @@ fn map_test() {
         assert_eq!(slice[99], 0xb);
     }
 
+    // Reserve a large region, back only a few pages of it
+    let base: u64 = 0x80_0000_0000;
+    let size: u64 = 1 << 30;
+    unsafe {
+        use vibrio::process::MapFlags;
+        use vibrio::syscalls::VSpace;
+
+        VSpace::reserve(base, size, MapFlags::READ_WRITE_USER).expect("Reserve syscall failed");
+        assert!(VSpace::reserve(base + 0x1000, 0x1000, MapFlags::READ_WRITE_USER).is_err());
+        // DRAM is always cached
+        let wc = MapFlags::READ_WRITE_USER | MapFlags::WRITE_COMBINE;
+        assert!(VSpace::reserve(base + size, 0x1000, wc).is_err());
+        assert!(VSpace::map_with_flags(base + size, 0x1000, wc).is_err());
+
+        let page = base + size / 2;
+        assert_eq!(VSpace::commit(page, 0x2000), Ok(0x2000));
+        assert_eq!(VSpace::commit(page, 0x3000), Ok(0x1000));
+        assert!(VSpace::commit(base + size, 0x1000).is_err());
+        assert_eq!(
+            vibrio::syscalls::last_error(),
+            Some(vibrio::SystemCallError::BadAddress)
+        );
+        assert_eq!(vibrio::errno::last_errno(), 14);
+        vibrio::errno::clear_last_error();
+
+        let slice: &mut [u8] = from_raw_parts_mut(page as *mut u8, 0x3000);
+        assert_eq!(slice[0x2fff], 0);
+        slice[0x2fff] = 0xb;
+
+        // The committed pages stay, the rest can be reserved again
+        VSpace::unreserve(base).expect("Unreserve syscall failed");
+        assert!(VSpace::unreserve(base).is_err());
+        assert!(VSpace::commit(base, 0x1000).is_err());
+        VSpace::reserve(base, size / 2, MapFlags::READ_WRITE_USER).expect("Reserve syscall failed");
+        VSpace::unreserve(base).expect("Unreserve syscall failed");
+        assert_eq!(slice[0x2fff], 0xb);
+
+        // Regions that wrap around the end of the address-space
+        assert!(VSpace::reserve(!0xfff, 0x2000, MapFlags::READ_WRITE_USER).is_err());
+        assert!(VSpace::commit(!0xfff, 0x2000).is_err());
+        vibrio::errno::clear_last_error();
+    }
+
+    // The page-tables should have both regions, sorted by address
+    {
+        use vibrio::process::{MapFlags, MappingRange};
+        use vibrio::syscalls::VSpace;
+
+        let mut ranges = [MappingRange::default(); 32];
+        let count = VSpace::dump(&mut ranges).expect("Dump syscall failed");
+        let ranges = &ranges[..core::cmp::min(count, ranges.len())];
+        assert!(ranges.windows(2).all(|w| w[0].end <= w[1].start));
+
+        let mapped = 0xff000..0xff000 + 0x1000 * 64;
+        let pages: u64 = ranges
+            .iter()
+            .filter(|r| mapped.contains(&r.start))
+            .inspect(|r| assert_eq!(r.flags(), MapFlags::READ_WRITE_USER))
+            .map(|r| r.size())
+            .sum();
+        assert_eq!(pages, 0x1000 * 64);
+        assert!(ranges.iter().any(|r| r.start == base + size / 2));
+        // Without the inspect capability
+        assert!(ranges.iter().all(|r| r.paddr == 0));
+
+        VSpace::dump_to_console().expect("Dump syscall failed");
+    }
+
+    // The kernel accounts for both regions
+    {
+        let memory = vibrio::syscalls::Process::process_info()
+            .expect("Can't get pinfo")
+            .memory;
+        assert!(memory.resident >= before.resident + 0x1000 * 64 + 0x3000);
+        assert!(memory.base_pages >= before.base_pages + 0x1000 * 64 + 0x3000);
+        assert_eq!(memory.reserved, before.reserved + size - 0x3000);
+        assert_eq!(
+            memory.virtual_size,
+            memory.resident + memory.evicted + memory.reserved
+        );
+        info!(
+            "VSS {} RSS {} reserved {}",
+            memory.virtual_size, memory.resident, memory.reserved
+        );
+    }
+
     info!("map_test OK");
 }
 
+fn random_test() {
+    let mut buf = [0u8; 16];
+    vibrio::syscalls::System::get_random(&mut buf).expect("GetRandom syscall failed");
+    info!("random_test bytes: {:x?}", buf);
+    info!("random_test OK");
+}
+
+fn sleep_test() {
+    use core::sync::atomic::AtomicU64;
+    use core::time::Duration;
+    use vibrio::syscalls::Process;
+
+    // The kernel and rawtime calibrate the TSC separately, allow for 1%
+    let at_least = |duration: Duration| duration * 99 / 100;
+
+    for ms in [1, 10, 100].iter() {
+        let duration = Duration::from_millis(*ms);
+        let start = rawtime::Instant::now();
+        Process::sleep(duration).expect("Sleep syscall failed");
+        let slept = start.elapsed();
+        assert!(slept >= at_least(duration), "Woke up early: {:?}", slept);
+        info!("sleep_test slept {:?} for {:?}", slept, duration);
+    }
+
+    // Nobody writes the word, the wait times out
+    let word = AtomicU64::new(0);
+    let timeout = Duration::from_millis(10);
+    let start = rawtime::Instant::now();
+    let changed =
+        Process::barrier_wait_timeout(&word, 0, timeout).expect("BarrierWait syscall failed");
+    assert!(!changed, "Nobody changed the word");
+    assert!(start.elapsed() >= at_least(timeout));
+
+    word.store(1, Ordering::Relaxed);
+    let changed =
+        Process::barrier_wait_timeout(&word, 0, timeout).expect("BarrierWait syscall failed");
+    assert!(changed, "The word isn't 0 anymore");
+    info!("sleep_test OK");
+}
+
+fn log_test() {
+    use log::warn;
+    use vibrio::process::LogLevel;
+    use vibrio::syscalls::Process;
+
+    Process::set_log_level(LogLevel::Warn).expect("SetLogLevel syscall failed");
+    info!("log_test filtered");
+    warn!("log_test not filtered");
+    Process::set_log_level(LogLevel::Trace).expect("SetLogLevel syscall failed");
+
+    // Log more than the kernel lets us, it drops the rest
+    let line = "x".repeat(1023) + "\n";
+    for _i in 0..1024 {
+        Process::print(line.as_str()).expect("Log syscall failed");
+    }
+
+    // Once we can log again, the kernel reports how much it dropped
+    Process::sleep(core::time::Duration::from_millis(100)).expect("Sleep syscall failed");
+    info!("log_test OK");
+}
+
+fn trace_test() {
+    use vibrio::process::SyscallRecord;
+    use vibrio::syscalls::{Process, System};
+    use vibrio::SystemCall;
+
+    let pid = Process::process_info()
+        .expect("Can't read process info")
+        .pid;
+    Process::trace(pid, true).expect("Trace syscall failed");
+    let mut buf = [0u8; 16];
+    System::get_random(&mut buf).expect("GetRandom syscall failed");
+    Process::trace(pid, false).expect("Trace syscall failed");
+
+    // Turning tracing off discards the records, read them while it's on
+    Process::trace(pid, true).expect("Trace syscall failed");
+    System::get_random(&mut buf).expect("GetRandom syscall failed");
+    let mut records = [SyscallRecord::default(); 4];
+    let n = Process::read_trace(pid, &mut records).expect("ReadTrace syscall failed");
+    Process::trace(pid, false).expect("Trace syscall failed");
+
+    assert_eq!(n, 1, "Only GetRandom completed before ReadTrace");
+    assert_eq!(records[0].function, SystemCall::System as u64);
+    assert_eq!(records[0].args[0], buf.as_ptr() as u64);
+    assert_eq!(records[0].error, 0);
+    info!("trace_test {}", records[0]);
+    info!("trace_test OK");
+}
+
+fn oom_test() {
+    use core::time::Duration;
+    use vibrio::process::{Event, OomPriority};
+    use vibrio::syscalls::Process;
+
+    let pid = Process::process_info()
+        .expect("Can't read process info")
+        .pid;
+    Process::set_oom_priority(pid, OomPriority::Expendable).expect("SetOomPriority failed");
+    Process::set_oom_priority(pid, OomPriority::Important).expect("SetOomPriority failed");
+
+    // We're the supervisor now, nothing runs out of memory
+    let timeout = Duration::from_millis(10);
+    let start = rawtime::Instant::now();
+    let killed = Process::wait_event(Event::OutOfMemory, timeout).expect("SubscribeEvent failed");
+    assert_eq!(killed, None, "Nothing was killed");
+    assert!(start.elapsed() >= timeout * 99 / 100, "Woke up early");
+    info!("oom_test OK");
+}
+
+fn keyboard_test() {
+    use core::time::Duration;
+    use vibrio::process::{Event, KeyEvent};
+    use vibrio::syscalls::Process;
+
+    // Takes the focus, the integration test types once it sees this
+    let nothing = Process::wait_event(Event::Keyboard, Duration::from_millis(1))
+        .expect("SubscribeEvent failed");
+    assert_eq!(nothing, None, "Nobody typed yet");
+    info!("keyboard_test ready");
+
+    let mut line = alloc::string::String::new();
+    loop {
+        let key = Process::wait_event(Event::Keyboard, Duration::from_secs(10))
+            .expect("SubscribeEvent failed")
+            .map(KeyEvent::from)
+            .expect("Nobody typed");
+        match key.character {
+            Some('\n') => break,
+            Some(c) => line.push(c),
+            None => {}
+        }
+    }
+    info!("keyboard_test typed {}", line);
+    assert_eq!(line, "Hi!");
+    info!("keyboard_test OK");
+}
+
+fn devices_test() {
+    use vibrio::syscalls::{Process, System};
+    use vibrio::system::{DeviceDriver, DeviceLocation};
+    use vibrio::SystemCallError;
+
+    let pid = Process::process_info()
+        .expect("Can't read process info")
+        .pid;
+    let devices = System::devices().expect("Can't list devices");
+    for device in devices.iter() {
+        info!(
+            "devices_test {} {} {:?} {:?}",
+            device.id, device.name, device.location, device.driver
+        );
+    }
+
+    // The kernel drives the serial console
+    let com1 = devices
+        .iter()
+        .find(|d| d.name == "com1")
+        .expect("No serial port");
+    assert_eq!(com1.driver, DeviceDriver::Kernel("serial".into()));
+    assert_eq!(
+        Process::claim_device(com1.id),
+        Err(SystemCallError::PermissionError)
+    );
+    assert_eq!(
+        Process::claim_device(u64::MAX),
+        Err(SystemCallError::NotSupported)
+    );
+
+    // Nobody drives the NIC (the network stack of a process would)
+    let nic = devices
+        .iter()
+        .find(|d| d.name == "e1000")
+        .expect("No e1000 NIC");
+    assert!(matches!(nic.location, DeviceLocation::Pci { .. }));
+    assert_eq!(nic.driver, DeviceDriver::None);
+    Process::claim_device(nic.id).expect("ClaimDevice failed");
+
+    let devices = System::devices().expect("Can't list devices");
+    assert_eq!(devices[nic.id as usize].driver, DeviceDriver::Process(pid));
+    info!("devices_test OK");
+}
+
+fn power_test() {
+    use vibrio::syscalls::System;
+    use vibrio::system::{Governor, IdleState};
+    use vibrio::SystemCallError;
+
+    let power = System::power().expect("Can't read power info");
+    info!("power_test {:?}", power);
+    assert_eq!(power.governor, Governor::Firmware);
+    assert_eq!(power.idle, IdleState::Halt);
+
+    // Idle cores spin, then halt again
+    System::set_power_policy(Governor::Firmware, IdleState::Poll).expect("SetPowerPolicy failed");
+    assert_eq!(
+        System::power().expect("Can't read power info").idle,
+        IdleState::Poll
+    );
+    System::set_power_policy(Governor::Firmware, IdleState::Halt).expect("SetPowerPolicy failed");
+
+    // Ratio 0 is never valid, QEMU usually doesn't let us set the frequency
+    assert_eq!(
+        System::set_power_policy(Governor::Fixed(0), IdleState::Halt),
+        Err(SystemCallError::NotSupported)
+    );
+    match System::set_power_policy(Governor::Powersave, IdleState::Halt) {
+        Ok(()) => System::set_power_policy(Governor::Firmware, IdleState::Halt)
+            .expect("SetPowerPolicy failed"),
+        Err(e) => assert_eq!(e, SystemCallError::NotSupported),
+    }
+    info!("power_test OK");
+}
+
+fn udp_test() {
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use core::sync::atomic::AtomicU32;
+    use core::time::Duration;
+    use vibrio::io::{PollEvents, PollKind};
+    use vibrio::net::{Endpoint, LOCALHOST};
+    use vibrio::syscalls::Net;
+    use vibrio::SystemCallError;
+
+    let server = Net::udp_bind(7070).expect("Can't bind port 7070");
+    assert_eq!(Net::udp_bind(7070), Err(SystemCallError::PermissionError));
+    let client = Net::udp_bind(0).expect("Can't bind an unused port");
+
+    let mut buf = [0u8; 64];
+    assert_eq!(Net::udp_recv_from(server, &mut buf), Ok(None));
+
+    // Nothing arrived and the futex word didn't change: the poll times out
+    let word = AtomicU32::new(1);
+    let mut set = vibrio::syscalls::PollSet::new();
+    set.add_socket(server, PollEvents::POLLIN);
+    set.add_futex(&word, 1);
+    assert_eq!(set.wait(Some(Duration::from_millis(5))), Ok(Vec::new()));
+    word.store(2, Ordering::Release);
+    assert_eq!(
+        set.wait(None),
+        Ok(vec![(
+            PollKind::Futex,
+            &word as *const AtomicU32 as u64,
+            PollEvents::POLLIN
+        )])
+    );
+    set.remove_entry(PollKind::Futex, &word as *const AtomicU32 as u64);
+
+    let sent = Net::udp_send_to(client, b"ping", Endpoint::new(LOCALHOST, 7070))
+        .expect("Can't send to 127.0.0.1:7070");
+    assert_eq!(sent, 4);
+    assert_eq!(
+        set.wait(None),
+        Ok(vec![(PollKind::Socket, server, PollEvents::POLLIN)])
+    );
+
+    let (len, from) = Net::udp_recv_from(server, &mut buf)
+        .expect("Can't receive")
+        .expect("Nothing arrived on 127.0.0.1:7070");
+    assert_eq!(&buf[..len], b"ping");
+    assert_eq!(from.addr, LOCALHOST);
+    Net::udp_send_to(server, b"pong", from).expect("Can't reply");
+    let (len, _from) = Net::udp_recv_from(client, &mut buf)
+        .expect("Can't receive")
+        .expect("No reply arrived");
+    assert_eq!(&buf[..len], b"pong");
+
+    // Only the loopback interface, only our own sockets
+    assert_eq!(
+        Net::udp_send_to(client, b"ping", Endpoint::new([172, 31, 0, 20], 7070)),
+        Err(SystemCallError::NotSupported)
+    );
+    Net::udp_close(server).expect("Can't close");
+    assert_eq!(
+        Net::udp_recv_from(server, &mut buf),
+        Err(SystemCallError::BadFileDescriptor)
+    );
+    assert_eq!(
+        set.wait(None),
+        Ok(vec![(PollKind::Socket, server, PollEvents::POLLNVAL)])
+    );
+    Net::udp_close(client).expect("Can't close");
+    info!("udp_test OK");
+}
+
+fn core_dump_test() {
+    use vibrio::syscalls::Process;
+
+    Process::set_core_dump(true).expect("SetCoreDump syscall failed");
+    info!("core_dump_test crashing");
+    // Nothing is mapped at 0x10 (the kernel dumps us and aborts)
+    unsafe { core::ptr::write_volatile(0x10 as *mut u64, 0xdead) };
+    unreachable!("core_dump_test survived a page-fault");
+}
+
+fn fault_injection_test() {
+    use core::time::Duration;
+    use vibrio::process::{Capabilities, CRASHED};
+    use vibrio::syscalls::{PhysicalMemory, Process, Test};
+    use vibrio::SystemCallError;
+
+    let pinfo = Process::process_info().expect("Can't read process info");
+    if pinfo.cmdline == "lose-save-area-child" {
+        Test::lose_save_area().expect("LoseSaveArea syscall failed");
+        // The kernel kills us here
+        let _r = Process::process_info();
+        Process::exit(0);
+    }
+
+    // The next allocation fails, the one after works again
+    Test::fail_allocations(1).expect("FailAllocations syscall failed");
+    assert_eq!(
+        PhysicalMemory::allocate_base_page().err(),
+        Some(SystemCallError::OutOfMemory)
+    );
+    PhysicalMemory::allocate_base_page().expect("Allocation still fails");
+
+    // A slow combiner only makes the operations slower
+    Test::delay_combine(4, 1_000_000).expect("DelayCombine syscall failed");
+    for _i in 0..4 {
+        PhysicalMemory::allocate_base_page().expect("Allocation with slow combiner failed");
+    }
+
+    Test::fail_allocations(8).expect("FailAllocations syscall failed");
+    Test::reset().expect("Reset syscall failed");
+    PhysicalMemory::allocate_base_page().expect("Reset didn't disarm faults");
+
+    // A call on a core without a save area kills the caller, the kernel
+    // keeps running
+    let child = Process::spawn("init lose-save-area-child", Capabilities::FAULT_INJECTION)
+        .expect("Spawn syscall failed");
+    let code =
+        Process::wait_pid_timeout(child, Duration::from_secs(10)).expect("WaitPid syscall failed");
+    assert_eq!(code, Some(CRASHED), "Child survived without a save area");
+
+    // A call on a core without a process fails (with `ProcessNotSet`)
+    Test::lose_process().expect("LoseProcess syscall failed");
+    assert_eq!(
+        Process::process_info().err(),
+        Some(SystemCallError::BadAddress)
+    );
+    Process::process_info().expect("Core didn't get its process back");
+
+    info!("fault_injection_test OK");
+}
+
+/// A xorshift generator for `fuzz_syscalls` (deterministic, we want to be
+/// able to replay a failure).
+struct Xorshift(u64);
+
+impl Xorshift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A register value that is likely to upset the kernel.
+    fn register(&mut self) -> u64 {
+        match self.next() % 4 {
+            0 => self.next() % 64,
+            1 => vibrio::KERNEL_BASE + (self.next() >> 20),
+            2 => u64::MAX - self.next() % 4096,
+            _ => self.next(),
+        }
+    }
+}
+
+/// Issues `rounds` system calls with malformed registers (unknown calls and
+/// operations, arguments the decoder rejects) and checks that each one
+/// fails with the error the decoder gives instead of taking the kernel
+/// down. Returns how many calls were made.
+///
+/// Registers that decode to a valid call are skipped, they could do
+/// anything.
+fn fuzz_syscalls(seed: u64, rounds: usize) -> usize {
+    use vibrio::decode;
+    use vibrio::syscalls::Test;
+
+    let mut rng = Xorshift(seed);
+    let mut calls = 0;
+    for _round in 0..rounds {
+        let function = match rng.next() % 8 {
+            0 => rng.next(),
+            _ => 1 + rng.next() % 5,
+        };
+        let op = match rng.next() % 4 {
+            0 => rng.next(),
+            _ => rng.next() % 64,
+        };
+        let args = [
+            rng.register(),
+            rng.register(),
+            rng.register(),
+            rng.register(),
+        ];
+
+        let expected = decode::operation(function, op).and_then(|op| decode::arguments(op, args));
+        if expected.is_ok() {
+            continue;
+        }
+        let r = unsafe { Test::raw(function, op, args) };
+        assert_eq!(
+            r.err(),
+            expected.err(),
+            "syscall({:#x}, {:#x}, {:x?})",
+            function,
+            op,
+            args
+        );
+        calls += 1;
+    }
+    calls
+}
+
+/// Set by the core `syscall_fuzz_test` requests once it is done.
+static FUZZ_CORE_DONE: AtomicBool = AtomicBool::new(false);
+
+/// Entry point of the core `syscall_fuzz_test` requests: fuzzes right away,
+/// before the core runs a scheduler (or anything else of ours).
+fn fuzz_core_entry(_control: &mut vibrio::arch::VirtualCpu, _cmd: u64, core_id: u64) -> ! {
+    let calls = fuzz_syscalls(0x5eed_f00d ^ core_id, 500);
+    info!("syscall_fuzz_test: {} calls on core {}", calls, core_id);
+    FUZZ_CORE_DONE.store(true, Ordering::SeqCst);
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+fn syscall_fuzz_test() {
+    let calls = fuzz_syscalls(0x5eed_f00d, 2000);
+    info!("syscall_fuzz_test: {} calls on core 0", calls);
+
+    // Once more on a fresh core
+    let threads = vibrio::syscalls::System::threads().expect("Can't get system topology");
+    if let Some(thread) = threads.iter().find(|t| t.id != 0) {
+        vibrio::syscalls::Process::request_core(
+            thread.id,
+            VAddr::from(fuzz_core_entry as *const fn() as u64),
+        )
+        .expect("Can't request core");
+        while !FUZZ_CORE_DONE.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+    }
+    info!("syscall_fuzz_test OK");
+}
+
 fn alloc_test() {
     use alloc::vec::Vec;
     let mut v: Vec<u16> = Vec::with_capacity(256);
@@ -131,6 +677,61 @@ fn scheduler_smp_test() {
     }
 }
 
+fn barrier_test() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicUsize;
+    use vibrio::sync::Barrier;
+
+    const PHASES: usize = 100;
+    let s = &vibrio::upcalls::PROCESS_SCHEDULER;
+
+    let threads = vibrio::syscalls::System::threads().expect("Can't get system topology");
+    for thread in threads.iter() {
+        if thread.id != 0 {
+            vibrio::syscalls::Process::request_core(
+                thread.id,
+                VAddr::from(vibrio::upcalls::upcall_while_enabled as *const fn() as u64),
+            )
+            .expect("Can't request core");
+        }
+    }
+
+    let barrier = Arc::new(Barrier::new(threads.len()));
+    let phases = Arc::new(
+        threads
+            .iter()
+            .map(|_t| AtomicUsize::new(0))
+            .collect::<alloc::vec::Vec<AtomicUsize>>(),
+    );
+    for (idx, thread) in threads.iter().enumerate() {
+        let barrier = barrier.clone();
+        let phases = phases.clone();
+        s.spawn(
+            32 * 4096,
+            move |_| {
+                for phase in 1..=PHASES {
+                    phases[idx].store(phase, Ordering::Relaxed);
+                    barrier.wait();
+                    // Nobody can be in the next phase before everyone
+                    // finished this one
+                    assert!(phases.iter().all(|p| p.load(Ordering::Relaxed) == phase));
+                    if barrier.wait().is_leader() && phase == PHASES {
+                        info!("barrier_test OK");
+                    }
+                }
+            },
+            ptr::null_mut(),
+            thread.id,
+            None,
+        );
+    }
+
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    loop {
+        s.run(&scb);
+    }
+}
+
 fn scheduler_test() {
     use lineup::threads::ThreadId;
     let mut s: lineup::scheduler::SmpScheduler = Default::default();
@@ -178,6 +779,127 @@ fn scheduler_test() {
     info!("scheduler_test OK");
 }
 
+fn sync_test() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicU32;
+    use core::time::Duration;
+    use lineup::tls2::Environment;
+    use vibrio::sync::{Condvar, Mutex, RwLock};
+    use vibrio::syscalls::Process;
+
+    const THREADS: usize = 4;
+    const ITERATIONS: usize = 100;
+
+    let mut s: lineup::scheduler::SmpScheduler = Default::default();
+    let counter = Arc::new((Mutex::new(0usize), Condvar::new()));
+    let table = Arc::new(RwLock::new([0usize; THREADS]));
+
+    for i in 0..THREADS {
+        let counter = counter.clone();
+        let table = table.clone();
+        s.spawn(
+            32 * 4096,
+            move |_| {
+                for _j in 0..ITERATIONS {
+                    // Yield with the lock held, so the others have to park
+                    let mut count = counter.0.lock().unwrap();
+                    Environment::thread().relinquish();
+                    *count += 1;
+                    drop(count);
+                    counter.1.notify_all();
+
+                    table.write().unwrap()[i] += 1;
+                    let _sum: usize = table.read().unwrap().iter().sum();
+                }
+            },
+            ptr::null_mut(),
+            0,
+            None,
+        );
+    }
+
+    let waiter = counter.clone();
+    s.spawn(
+        32 * 4096,
+        move |_| {
+            let (lock, cvar) = &*waiter;
+            let count = cvar
+                .wait_while(lock.lock().unwrap(), |count| *count < THREADS * ITERATIONS)
+                .unwrap();
+            assert_eq!(*count, THREADS * ITERATIONS);
+        },
+        ptr::null_mut(),
+        0,
+        None,
+    );
+
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    s.run(&scb);
+
+    assert_eq!(*counter.0.lock().unwrap(), THREADS * ITERATIONS);
+    assert!(table.read().unwrap().iter().all(|n| *n == ITERATIONS));
+
+    // We're not on a lineup thread anymore, so we wait in the kernel
+    let word = AtomicU32::new(1);
+    let woken = Process::futex_wait(&word, 0).expect("FutexWait syscall failed");
+    assert!(!woken, "The word isn't 0");
+    let woken = Process::futex_wake(&word, 1).expect("FutexWake syscall failed");
+    assert_eq!(woken, 0, "Nobody waits");
+    let (lock, cvar) = &*counter;
+    let (_count, result) = cvar
+        .wait_timeout(lock.lock().unwrap(), Duration::from_millis(10))
+        .unwrap();
+    assert!(result.timed_out(), "Nobody notifies the condvar");
+    info!("sync_test OK");
+}
+
+fn mpsc_test() {
+    use vibrio::sync::mpsc;
+
+    const SENDERS: usize = 4;
+    const MESSAGES: usize = 100;
+
+    let mut s: lineup::scheduler::SmpScheduler = Default::default();
+    let (results_tx, results_rx) = mpsc::channel();
+    // A small bound makes the senders wait for the receiver
+    let (tx, rx) = mpsc::sync_channel(2);
+
+    for i in 0..SENDERS {
+        let tx = tx.clone();
+        s.spawn(
+            32 * 4096,
+            move |_| {
+                for j in 0..MESSAGES {
+                    tx.send(i * MESSAGES + j).unwrap();
+                }
+            },
+            ptr::null_mut(),
+            0,
+            None,
+        );
+    }
+    drop(tx);
+
+    s.spawn(
+        32 * 4096,
+        move |_| {
+            // Ends once all senders are gone
+            let sum: usize = rx.iter().sum();
+            results_tx.send(sum).unwrap();
+        },
+        ptr::null_mut(),
+        0,
+        None,
+    );
+
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    s.run(&scb);
+
+    let n = SENDERS * MESSAGES;
+    assert_eq!(results_rx.try_recv(), Ok(n * (n - 1) / 2));
+    info!("mpsc_test OK");
+}
+
 #[cfg(feature = "rumprt")]
 fn test_rump_tmpfs() {
     use cstr_core::CStr;
@@ -283,6 +1005,8 @@ extern "C" fn ready() {
 #[cfg(feature = "rumprt")]
 pub fn test_rump_net() {
     use cstr_core::CStr;
+    use vibrio::syscalls::System;
+    use vibrio::system::NetModeration;
 
     #[repr(C)]
     struct sockaddr_in {
@@ -407,6 +1131,54 @@ pub fn test_rump_net() {
                 nanosleep(&sleep_dur as *const timespec_t, ptr::null_mut());
             }
 
+            // Under load the IRQ thread should mask the interrupt and poll
+            // the NIC instead of taking an interrupt per packet
+            const BURST: usize = 200;
+            let vector = rumprt::dev::irq_vector().expect("NIC has an interrupt");
+            let settle = timespec_t {
+                tv_sec: 0,
+                tv_nsec: 100_000_000,
+            };
+            let irqs_for_burst = |poll_threshold: u64| -> u64 {
+                System::set_net_moderation(NetModeration {
+                    poll_threshold,
+                    idle_polls: NetModeration::DEFAULT.idle_polls,
+                })
+                .expect("SetNetModeration failed (needs initcaps=tune)");
+                // Let the IRQ thread go back to interrupts and pick up the
+                // tunables
+                nanosleep(&settle as *const timespec_t, ptr::null_mut());
+
+                let before = System::irq_count(0, vector).expect("IrqCount syscall failed");
+                let buf = b"burst\n\0";
+                for _i in 0..BURST {
+                    // The queue may be full, we only care about the interrupts
+                    let _r = sendto(
+                        sockfd,
+                        buf.as_ptr() as *const i8,
+                        buf.len(),
+                        MSG_DONTWAIT,
+                        &addr as *const sockaddr_in,
+                        core::mem::size_of::<sockaddr_in>(),
+                    );
+                }
+                nanosleep(&settle as *const timespec_t, ptr::null_mut());
+                System::irq_count(0, vector).expect("IrqCount syscall failed") - before
+            };
+
+            let unmoderated = irqs_for_burst(u64::MAX);
+            let moderated = irqs_for_burst(1);
+            info!(
+                "IRQs for {} packets: {} without moderation, {} with moderation",
+                BURST, unmoderated, moderated
+            );
+            assert!(
+                moderated < unmoderated,
+                "Polling should take fewer interrupts"
+            );
+            System::set_net_moderation(NetModeration::DEFAULT).expect("SetNetModeration failed");
+            info!("IRQ moderation OK");
+
             info!("test_rump_net OK");
 
             let r = close(sockfd);
@@ -493,6 +1265,265 @@ fn test_fs_invalid_addresses() {
     assert_eq!(ret, 0);
 }
 
+fn autostart_test() {
+    use vibrio::syscalls::{Process, System};
+
+    // Started by kernel/tests/init.toml
+    let pinfo = Process::process_info().expect("Can't read process info");
+    assert_eq!(pinfo.cmdline, "autostart");
+    assert_eq!(System::core_id().expect("GetCoreID syscall failed"), 1);
+
+    info!("autostart_test OK");
+}
+
+fn service_test() {
+    use vibrio::io::*;
+    use vibrio::syscalls::{Fs, Process};
+
+    // Started by kernel/tests/services.toml, the file-system outlives us
+    let pinfo = Process::process_info().expect("Can't read process info");
+    assert_eq!(pinfo.cmdline, "service");
+    let fd = Fs::open(
+        "/service-runs\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+        u64::from(FileModes::S_IRWXU),
+    )
+    .expect("FileOpen syscall failed");
+    let runs = Fs::getinfo("/service-runs\0".as_ptr() as u64)
+        .expect("FileGetInfo syscall failed")
+        .fsize;
+    let run = [runs as u8];
+    Fs::write_at(fd, run.as_ptr() as u64, 1, runs as i64).expect("FileWriteAt syscall failed");
+    Fs::close(fd).expect("FileClose syscall failed");
+
+    if runs < 2 {
+        info!("service_test fails in run {}", runs);
+        Process::exit(1);
+    }
+    info!("service_test OK");
+}
+
+fn reload_test() {
+    use core::time::Duration;
+    use vibrio::io::*;
+    use vibrio::syscalls::{Fs, Process};
+
+    // Started by kernel/tests/reload.toml, the service tells us its pid
+    // through a file
+    const PID_FILE: &str = "/service-pid\0";
+    let read_pid = || -> Option<u64> {
+        let info = Fs::getinfo(PID_FILE.as_ptr() as u64).ok()?;
+        if info.fsize != 8 {
+            return None;
+        }
+        let fd = Fs::open(PID_FILE.as_ptr() as u64, u64::from(FileFlags::O_RDONLY), 0).ok()?;
+        let mut pid = [0u8; 8];
+        let len = Fs::read_at(fd, pid.as_mut_ptr() as u64, 8, 0).ok()?;
+        Fs::close(fd).expect("FileClose syscall failed");
+        if len == 8 {
+            Some(u64::from_le_bytes(pid))
+        } else {
+            None
+        }
+    };
+    let wait_for_pid = |other_than: Option<u64>| loop {
+        match read_pid() {
+            Some(pid) if Some(pid) != other_than => return pid,
+            _ => Process::sleep(Duration::from_millis(10)).expect("Sleep syscall failed"),
+        }
+    };
+
+    let pinfo = Process::process_info().expect("Can't read process info");
+    if pinfo.cmdline == "reload-service" {
+        let fd = Fs::open(
+            PID_FILE.as_ptr() as u64,
+            u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+            u64::from(FileModes::S_IRWXU),
+        )
+        .expect("FileOpen syscall failed");
+        let pid = pinfo.pid.to_le_bytes();
+        Fs::write_at(fd, pid.as_ptr() as u64, 8, 0).expect("FileWriteAt syscall failed");
+        Fs::close(fd).expect("FileClose syscall failed");
+        info!("reload_test: service runs as {}", pinfo.pid);
+        loop {
+            Process::sleep(Duration::from_millis(100)).expect("Sleep syscall failed");
+        }
+    }
+
+    assert_eq!(pinfo.cmdline, "reloader");
+    let old = wait_for_pid(None);
+    assert!(Process::reload(pinfo.pid, "init").is_err());
+    assert!(Process::reload(old, "/no-such-binary").is_err());
+    let new = Process::reload(old, "init").expect("Reload syscall failed");
+    assert_ne!(new, old);
+    assert_eq!(wait_for_pid(Some(old)), new);
+
+    info!("reload_test OK");
+}
+
+fn fd_inherit_test() {
+    use core::time::Duration;
+    use vibrio::io::*;
+    use vibrio::process::Capabilities;
+    use vibrio::syscalls::{Fs, Process};
+    use vibrio::SystemCallError;
+
+    const FILE: &str = "/fd-inherit\0";
+    const LIMIT: u64 = 8;
+    let open = |flags: FileFlags| {
+        Fs::open(
+            FILE.as_ptr() as u64,
+            u64::from(flags | FileFlags::O_RDWR | FileFlags::O_CREAT),
+            u64::from(FileModes::S_IRWXU),
+        )
+    };
+
+    let pinfo = Process::process_info().expect("Can't read process info");
+    if let Some(fds) = pinfo.cmdline.strip_prefix("fd-child ") {
+        let (shared, private) = fds.split_once(' ').expect("Two descriptors");
+        let (shared, private): (u64, u64) = (shared.parse().unwrap(), private.parse().unwrap());
+
+        let mut buf = [0u8; 9];
+        let len = Fs::read(shared, buf.as_mut_ptr() as u64, buf.len() as u64)
+            .expect("Inherited descriptor doesn't work");
+        assert_eq!(&buf[..len as usize], b"inherited");
+        assert!(Fs::read(private, buf.as_mut_ptr() as u64, 1).is_err());
+
+        // The limit of the parent applies
+        assert_eq!(
+            Process::set_fd_limit(LIMIT + 1),
+            Err(SystemCallError::PermissionError)
+        );
+        let mut last = shared;
+        loop {
+            match open(FileFlags::O_NONE) {
+                Ok(fd) => last = fd,
+                Err(e) => {
+                    assert_eq!(e, SystemCallError::OpenFileLimit);
+                    break;
+                }
+            }
+        }
+        assert_eq!(last, LIMIT - 1);
+        Process::exit(0);
+    }
+
+    let shared = open(FileFlags::O_NONE).expect("FileOpen syscall failed");
+    let private = open(FileFlags::O_CLOEXEC).expect("FileOpen syscall failed");
+    Fs::write_at(shared, b"inherited".as_ptr() as u64, 9, 0).expect("FileWriteAt syscall failed");
+    Process::set_fd_limit(LIMIT).expect("SetFdLimit syscall failed");
+
+    let cmdline = alloc::format!("init fd-child {} {}", shared, private);
+    let child = Process::spawn_with_fds(&cmdline, Capabilities::NONE, &[shared, private])
+        .expect("Spawn syscall failed");
+    let code =
+        Process::wait_pid_timeout(child, Duration::from_secs(10)).expect("WaitPid syscall failed");
+    assert_eq!(code, Some(0), "Child failed");
+
+    info!("fd_inherit_test OK");
+}
+
+fn checkpoint_test() {
+    use core::time::Duration;
+    use vibrio::io::*;
+    use vibrio::process::Capabilities;
+    use vibrio::syscalls::{Fs, Process};
+    use vibrio::SystemCallError;
+
+    const IMAGE: &str = "/checkpoint\0";
+
+    let pinfo = Process::process_info().expect("Can't read process info");
+    if pinfo.cmdline == "checkpoint-child" {
+        // Needs the capability
+        assert_eq!(
+            Process::checkpoint(IMAGE.as_ptr() as u64),
+            Err(SystemCallError::PermissionError)
+        );
+        assert_eq!(
+            Process::restore(IMAGE.as_ptr() as u64),
+            Err(SystemCallError::PermissionError)
+        );
+        Process::exit(0);
+    }
+
+    let child =
+        Process::spawn("init checkpoint-child", Capabilities::NONE).expect("Spawn syscall failed");
+    let code =
+        Process::wait_pid_timeout(child, Duration::from_secs(10)).expect("WaitPid syscall failed");
+    assert_eq!(code, Some(0), "Child failed");
+
+    // Spans pages, comes back in the restored process
+    let mut memory = alloc::vec![0u8; 3 * 4096];
+    memory[4096] = 0xab;
+    memory[2 * 4096 + 1] = 0xcd;
+
+    let restored = Process::checkpoint(IMAGE.as_ptr() as u64).expect("Checkpoint syscall failed");
+    if restored {
+        assert_eq!(memory[4096], 0xab);
+        assert_eq!(memory[2 * 4096 + 1], 0xcd);
+        info!("checkpoint_test restored");
+        Process::exit(0);
+    }
+
+    let restored = Process::restore(IMAGE.as_ptr() as u64).expect("Restore syscall failed");
+    let code = Process::wait_pid_timeout(restored, Duration::from_secs(10))
+        .expect("WaitPid syscall failed");
+    assert_eq!(code, Some(0), "Restored process failed");
+
+    // A cut-off image fails after the new process was created (which is
+    // removed again)
+    const TRUNCATED: &str = "/truncated\0";
+    let mut head = alloc::vec![0u8; 4096];
+    let image = Fs::open(IMAGE.as_ptr() as u64, u64::from(FileFlags::O_RDONLY), 0)
+        .expect("FileOpen syscall failed");
+    Fs::read_at(image, head.as_mut_ptr() as u64, head.len() as u64, 0)
+        .expect("FileReadAt syscall failed");
+    Fs::close(image).expect("FileClose syscall failed");
+    let truncated = Fs::open(
+        TRUNCATED.as_ptr() as u64,
+        u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+        u64::from(FileModes::S_IRWXU),
+    )
+    .expect("FileOpen syscall failed");
+    Fs::write_at(truncated, head.as_ptr() as u64, head.len() as u64, 0)
+        .expect("FileWriteAt syscall failed");
+    Fs::close(truncated).expect("FileClose syscall failed");
+    assert_eq!(
+        Process::restore(TRUNCATED.as_ptr() as u64),
+        Err(SystemCallError::InvalidCheckpoint)
+    );
+    info!("checkpoint_test OK");
+}
+
+fn rootfs_test() {
+    use vibrio::io::*;
+
+    // Shipped in the `rootfs` module (see kernel/tests/rootfs)
+    const MOTD: &[u8] = b"Welcome to nrk!\n";
+
+    let dirinfo = vibrio::syscalls::Fs::getinfo("/etc\0".as_ptr() as u64)
+        .expect("FileGetInfo syscall failed");
+    assert_eq!(dirinfo.ftype, FileType::Directory.into());
+    let fileinfo = vibrio::syscalls::Fs::getinfo("/etc/motd\0".as_ptr() as u64)
+        .expect("FileGetInfo syscall failed");
+    assert_eq!(fileinfo.ftype, FileType::File.into());
+    assert_eq!(fileinfo.fsize, MOTD.len() as u64);
+
+    let fd = vibrio::syscalls::Fs::open(
+        "/etc/motd\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDONLY),
+        0,
+    )
+    .expect("FileOpen syscall failed");
+    let mut buf = [0u8; 64];
+    let len = vibrio::syscalls::Fs::read(fd, buf.as_mut_ptr() as u64, buf.len() as u64)
+        .expect("FileRead syscall failed");
+    assert_eq!(&buf[..len as usize], MOTD);
+    vibrio::syscalls::Fs::close(fd).expect("FileClose syscall failed");
+
+    info!("rootfs_test OK");
+}
+
 fn fs_test() {
     use vibrio::io::*;
     let base: u64 = 0xff000;
@@ -605,14 +1636,26 @@ fn fs_write_test() {
 pub fn install_vcpu_area() {
     let ctl =
         vibrio::syscalls::Process::vcpu_control_area().expect("Can't read vcpu control area.");
-    ctl.resume_with_upcall =
-        VAddr::from(vibrio::upcalls::upcall_while_enabled as *const fn() as u64);
+    ctl.set_upcall_entry(VAddr::from(
+        vibrio::upcalls::upcall_while_enabled as *const fn() as u64,
+    ));
 }
 
 pub fn upcall_test() {
     sys_println!("causing a debug exception");
     unsafe { x86::int!(3) };
     info!("upcall_test OK");
+
+    let vcpu =
+        vibrio::syscalls::Process::vcpu_control_area().expect("Can't read vcpu control area.");
+    let cs = vibrio::upcalls::CriticalSection::enter();
+    sys_println!("causing a debug exception in a critical section");
+    unsafe { x86::int!(3) };
+    assert!(vcpu.has_pending_upcall(), "Upcall wasn't deferred?");
+    drop(cs);
+    assert!(!vcpu.has_pending_upcall(), "Upcall wasn't delivered?");
+    assert!(!vcpu.is_disabled());
+    info!("critical_section_test OK");
 }
 
 #[no_mangle]
@@ -642,12 +1685,28 @@ pub extern "C" fn _start() -> ! {
         Err(_) => unreachable!(),
     };
 
+    // Children of the runner run their test and exit, the runner exits with
+    // the result of all tests
+    #[cfg(feature = "test-runner")]
+    if !runner::run_child(pinfo.cmdline) {
+        let failed = runner::run();
+        vibrio::syscalls::Process::exit(if failed == 0 { 0 } else { 1 });
+    }
+
+    #[cfg(feature = "profile")]
+    if let Err(e) = vibrio::syscalls::System::profile_start(PROFILE_PERIOD) {
+        error!("Can't start the kernel profiler: {:?}", e);
+    }
+
     #[cfg(feature = "bench-vmops")]
     vmops::bench(ncores);
 
     #[cfg(feature = "bench-vmops-unmaplat")]
     vmops::unmaplat::bench(ncores);
 
+    #[cfg(feature = "bench-syscall")]
+    syscallbench::bench();
+
     #[cfg(feature = "test-print")]
     print_test();
 
@@ -660,12 +1719,75 @@ pub extern "C" fn _start() -> ! {
     #[cfg(feature = "test-alloc")]
     alloc_test();
 
+    #[cfg(feature = "test-random")]
+    random_test();
+
+    #[cfg(feature = "test-log")]
+    log_test();
+
+    #[cfg(feature = "test-sleep")]
+    sleep_test();
+
+    #[cfg(feature = "test-trace")]
+    trace_test();
+
+    #[cfg(feature = "test-oom")]
+    oom_test();
+
+    #[cfg(feature = "test-keyboard")]
+    keyboard_test();
+
+    #[cfg(feature = "test-devices")]
+    devices_test();
+
+    #[cfg(feature = "test-rootfs")]
+    rootfs_test();
+
+    #[cfg(feature = "test-autostart")]
+    autostart_test();
+
+    #[cfg(feature = "test-services")]
+    service_test();
+
+    #[cfg(feature = "test-reload")]
+    reload_test();
+
+    #[cfg(feature = "test-fd-inherit")]
+    fd_inherit_test();
+
+    #[cfg(feature = "test-checkpoint")]
+    checkpoint_test();
+
+    #[cfg(feature = "test-core-dump")]
+    core_dump_test();
+
+    #[cfg(feature = "test-power")]
+    power_test();
+
+    #[cfg(feature = "test-udp")]
+    udp_test();
+
+    #[cfg(feature = "test-fault-injection")]
+    fault_injection_test();
+
+    #[cfg(feature = "test-syscall-fuzz")]
+    syscall_fuzz_test();
+
     #[cfg(feature = "test-scheduler")]
     scheduler_test();
 
     #[cfg(feature = "test-scheduler-smp")]
     scheduler_smp_test();
 
+    #[cfg(feature = "test-barrier")]
+    barrier_test();
+
+    #[cfg(feature = "test-sync")]
+    {
+        sync_test();
+        mpsc_test();
+    }
+
     #[cfg(feature = "rumprt")]
     {
         // Run either, test-rump-net or test-rump-tmpfs
@@ -689,6 +1811,9 @@ pub extern "C" fn _start() -> ! {
     #[cfg(feature = "fxmark")]
     fxmark::bench(ncores, open_files, benchmark, write_ratio);
 
+    #[cfg(feature = "profile")]
+    let _r = vibrio::syscalls::System::profile_dump();
+
     vibrio::vconsole::init();
 
     debug!("Done with init tests, if we came here probably everything is good.");
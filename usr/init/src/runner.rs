@@ -0,0 +1,191 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Runs the tests of init concurrently, each one in its own process.
+//!
+//! The runner spawns a child (`init test=<name>`) for every test, waits for
+//! them with `WaitPid` (a millisecond per child) and kills those that take
+//! longer than their timeout. It prints one line per test and a summary in
+//! the end:
+//!
+//! ```text
+//! test_runner: test=map status=passed code=0 ms=12
+//! test_runner: 4 passed, 0 failed, 0 timed out
+//! ```
+//!
+//! A test fails if its process exits with a code other than 0 (a panic
+//! exits with 99).
+
+use alloc::format;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use log::{error, info, warn};
+use vibrio::process::{Capabilities, KILLED};
+use vibrio::syscalls::Process;
+use vibrio::SystemCallError;
+
+/// Prefix of the cmdline of a child that runs a test.
+const TEST_ARG: &str = "test=";
+
+/// How long we wait for a child before we check the next one.
+const WAIT_SLICE: Duration = Duration::from_millis(1);
+
+struct Test {
+    name: &'static str,
+    run: fn(),
+    timeout: Duration,
+}
+
+/// The tests the runner starts (the ones that don't need kernel features or
+/// a special machine).
+static TESTS: [Test; 5] = [
+    Test {
+        name: "print",
+        run: crate::print_test,
+        timeout: Duration::from_secs(10),
+    },
+    Test {
+        name: "map",
+        run: crate::map_test,
+        timeout: Duration::from_secs(30),
+    },
+    Test {
+        name: "alloc",
+        run: crate::alloc_test,
+        timeout: Duration::from_secs(30),
+    },
+    Test {
+        name: "random",
+        run: crate::random_test,
+        timeout: Duration::from_secs(10),
+    },
+    Test {
+        name: "sleep",
+        run: crate::sleep_test,
+        timeout: Duration::from_secs(10),
+    },
+];
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum Status {
+    Running,
+    Passed,
+    Failed,
+    TimedOut,
+}
+
+struct Child {
+    test: &'static Test,
+    pid: u64,
+    start: rawtime::Instant,
+    status: Status,
+    code: u64,
+}
+
+/// Runs the test given on the `cmdline` of a child, returns `false` if
+/// `cmdline` doesn't name a test (i.e., we're the runner).
+pub fn run_child(cmdline: &str) -> bool {
+    let name = match cmdline.strip_prefix(TEST_ARG) {
+        Some(name) => name,
+        None => return false,
+    };
+
+    match TESTS.iter().find(|t| t.name == name) {
+        Some(test) => {
+            (test.run)();
+            Process::exit(0);
+        }
+        None => {
+            error!("Unknown test {}", name);
+            Process::exit(1);
+        }
+    }
+}
+
+/// Spawns the child for `test`, waits for a core if there is none left.
+fn spawn(test: &'static Test) -> Result<u64, SystemCallError> {
+    let cmdline = format!("init {}{}", TEST_ARG, test.name);
+    loop {
+        match Process::spawn(cmdline.as_str(), Capabilities::NONE) {
+            Err(SystemCallError::CoreUnavailable) => core::hint::spin_loop(),
+            r => return r,
+        }
+    }
+}
+
+/// Runs all tests, returns the number of tests that didn't pass.
+pub fn run() -> usize {
+    let mut children: Vec<Child> = Vec::with_capacity(TESTS.len());
+    let mut failed_to_start = 0;
+    for test in TESTS.iter() {
+        match spawn(test) {
+            Ok(pid) => children.push(Child {
+                test,
+                pid,
+                start: rawtime::Instant::now(),
+                status: Status::Running,
+                code: 0,
+            }),
+            Err(e) => {
+                error!("test_runner: test={} can't spawn: {:?}", test.name, e);
+                failed_to_start += 1;
+            }
+        }
+    }
+
+    while children.iter().any(|c| c.status == Status::Running) {
+        for child in children.iter_mut().filter(|c| c.status == Status::Running) {
+            match Process::wait_pid_timeout(child.pid, WAIT_SLICE) {
+                Ok(Some(code)) => {
+                    child.code = code;
+                    child.status = if code == 0 {
+                        Status::Passed
+                    } else {
+                        Status::Failed
+                    };
+                }
+                Ok(None) if child.start.elapsed() > child.test.timeout => {
+                    if let Err(e) = Process::kill(child.pid) {
+                        warn!("test_runner: can't kill {}: {:?}", child.pid, e);
+                    }
+                    child.code = KILLED;
+                    child.status = Status::TimedOut;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!("test_runner: WaitPid({}) failed: {:?}", child.pid, e);
+                    child.status = Status::Failed;
+                }
+            }
+
+            if child.status != Status::Running {
+                info!(
+                    "test_runner: test={} status={} code={} ms={}",
+                    child.test.name,
+                    match child.status {
+                        Status::Passed => "passed",
+                        Status::Failed => "failed",
+                        Status::TimedOut => "timeout",
+                        Status::Running => unreachable!(),
+                    },
+                    child.code,
+                    child.start.elapsed().as_millis()
+                );
+            }
+        }
+    }
+
+    let count = |status| children.iter().filter(|c| c.status == status).count();
+    let (passed, failed, timed_out) = (
+        count(Status::Passed),
+        count(Status::Failed) + failed_to_start,
+        count(Status::TimedOut),
+    );
+    info!(
+        "test_runner: {} passed, {} failed, {} timed out",
+        passed, failed, timed_out
+    );
+
+    failed + timed_out
+}
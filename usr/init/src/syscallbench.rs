@@ -0,0 +1,98 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Measures the round-trip latency (in cycles) of basic system calls.
+//!
+//! Run the kernel with the `syscall-timing` feature to also get the time
+//! spent inside the kernel (printed by `System::stats` at the end).
+//!
+//! The cost of entering and leaving the kernel depends on the speculative
+//! execution mitigations (`mitigations` on the kernel command line), we
+//! print which ones are on first.
+
+use log::info;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+use vibrio::syscalls::{Process, System, VSpace};
+
+use crate::histogram;
+
+/// Measurements per system call.
+const ITERATIONS: usize = if cfg!(feature = "smoke") {
+    1_000
+} else {
+    100_000
+};
+
+/// Address used for the Map/Unmap/Identify measurements.
+const BASE: u64 = 0x0520_0000_0000;
+
+fn report(name: &str, memsize: usize, h: &histogram::Histogram) {
+    // Don't adjust this line without changing `s06_syscall_latency_benchmark`
+    info!(
+        "Latency percentiles: {},{},{},{},{},{},{},{},{},{}",
+        name,
+        1,
+        memsize,
+        h.percentile(1.0).unwrap(),
+        h.percentile(25.0).unwrap(),
+        h.percentile(50.0).unwrap(),
+        h.percentile(75.0).unwrap(),
+        h.percentile(99.0).unwrap(),
+        h.percentile(99.9).unwrap(),
+        h.percentile(100.0).unwrap(),
+    );
+}
+
+fn measure<F: FnMut()>(name: &str, memsize: usize, mut syscall: F) {
+    let mut h = histogram::Histogram::new();
+    for _i in 0..ITERATIONS {
+        let start = unsafe { x86::time::rdtsc() };
+        syscall();
+        let end = unsafe { x86::time::rdtsc() };
+        h.increment(end - start).expect("Can't record latency");
+    }
+    report(name, memsize, &h);
+}
+
+pub fn bench() {
+    // What the numbers depend on (see `s06_mitigations_benchmark`)
+    let mitigations = System::mitigations().expect("Can't get mitigations");
+    info!(
+        "Mitigations: ibrs={} ibpb={} mds={}",
+        mitigations.active.ibrs, mitigations.active.ibpb, mitigations.active.mds
+    );
+    info!("benchmark,ncores,memsize,p1,p25,p50,p75,p99,p99.9,p100");
+
+    measure("null", 0, || {
+        System::core_id().expect("GetCoreID syscall failed");
+    });
+    measure("log", 0, || {
+        Process::print("").expect("Log syscall failed");
+    });
+
+    let mut map = histogram::Histogram::new();
+    let mut unmap = histogram::Histogram::new();
+    for _i in 0..ITERATIONS {
+        unsafe {
+            let start = x86::time::rdtsc();
+            VSpace::map(BASE, BASE_PAGE_SIZE as u64).expect("Map syscall failed");
+            let mapped = x86::time::rdtsc();
+            VSpace::unmap(BASE, BASE_PAGE_SIZE as u64).expect("Unmap syscall failed");
+            let end = x86::time::rdtsc();
+            map.increment(mapped - start).expect("Can't record latency");
+            unmap.increment(end - mapped).expect("Can't record latency");
+        }
+    }
+    report("map", BASE_PAGE_SIZE, &map);
+    report("unmap", BASE_PAGE_SIZE, &unmap);
+
+    unsafe { VSpace::map(BASE, BASE_PAGE_SIZE as u64).expect("Map syscall failed") };
+    measure("identify", BASE_PAGE_SIZE, || {
+        VSpace::identify(BASE).expect("Identify syscall failed");
+    });
+    unsafe { VSpace::unmap(BASE, BASE_PAGE_SIZE as u64).expect("Unmap syscall failed") };
+
+    let _r = System::stats();
+    info!("syscall_bench OK");
+}